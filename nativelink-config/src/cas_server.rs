@@ -87,6 +87,17 @@ pub struct AcStoreConfig {
     /// it is only possible to read from the Action Cache.
     #[serde(default)]
     pub read_only: bool,
+
+    /// If set, action results written through this instance expire this
+    /// many seconds after they were written. A periodic sweep removes
+    /// expired results from `ac_store`, which must support removing keys
+    /// (e.g. the memory store), otherwise the configuration is rejected.
+    /// Expiration times are only tracked in memory, so results written
+    /// before a restart are never swept.
+    ///
+    /// Default: 0 (action results never expire)
+    #[serde(default)]
+    pub action_result_ttl_s: u64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -96,6 +107,35 @@ pub struct CasStoreConfig {
     /// This store name referenced here may be reused multiple times.
     #[serde(deserialize_with = "convert_string_with_shellexpand")]
     pub cas_store: StoreRefName,
+    /// If set, uploads to this instance are rejected with `PermissionDenied`
+    /// while reads are still served. Useful for instances that serve a
+    /// shared base cache in multi-tenant deployments. The `bytestream`
+    /// service also rejects writes to an instance of the same name.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub read_only: bool,
+    /// Digest functions clients are allowed to use with this instance,
+    /// mapped to the store that holds blobs hashed with that function. The
+    /// mapped store is used instead of `cas_store`, which lets blobs of a
+    /// digest function that is being migrated away from live in their own
+    /// store. Requests using a digest function that is not listed are
+    /// rejected. Requests that do not name a digest function use the
+    /// server's default digest function. The `bytestream` service applies
+    /// the same routing to an instance of the same name.
+    ///
+    /// For example, a value of:
+    /// ```json
+    /// { "sha256": "LEGACY_CAS_STORE", "blake3": "CAS_MAIN_STORE" }
+    /// ```
+    /// Will accept sha256 and blake3 digests and reject all others.
+    ///
+    /// Legacy digest functions such as sha1 and md5 are not supported, so
+    /// clients using them can not be migrated through this mapping.
+    ///
+    /// Default: {} (all supported digest functions use `cas_store`)
+    #[serde(default)]
+    pub digest_function_stores: HashMap<ConfigDigestHashFunction, StoreRefName>,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -127,6 +167,29 @@ pub struct ExecutionConfig {
     /// The scheduler name referenced in the `schedulers` map in the main config.
     #[serde(deserialize_with = "convert_string_with_shellexpand")]
     pub scheduler: SchedulerRefName,
+
+    /// If set, execute requests whose `Action` and `Command` only differ in
+    /// the order of repeated fields that have no meaning (environment
+    /// variables, output paths and platform properties) are merged: a
+    /// request joins an unfinished action whose canonical form, with these
+    /// fields sorted, is the same. The canonical form is only used to find
+    /// equivalent actions. A request that joins an action is reported under
+    /// the operation of that action, and its result is written to `ac_store`
+    /// under the digest the client sent. Requires `ac_store` to be set.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub canonicalize_actions: bool,
+
+    /// The store name referenced in the `stores` map in the main config.
+    /// Successful results of actions that were joined by an equivalent
+    /// action (see `canonicalize_actions`) are written to this store under
+    /// the digest of the joining action. This value must be an AC store
+    /// reference.
+    ///
+    /// Default: None
+    #[serde(default, deserialize_with = "convert_optional_string_with_shellexpand")]
+    pub ac_store: Option<StoreRefName>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -151,6 +214,43 @@ pub struct ByteStreamConfig {
     /// Defaults: 10 (seconds)
     #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
     pub persist_stream_on_disconnect_timeout: usize,
+
+    /// If set, read offsets are rounded down (and read limits rounded up) to
+    /// a multiple of this many bytes before being sent to the store, and the
+    /// extra bytes are trimmed before responding. Setting this to the block
+    /// size of an underlying store (e.g. the `block_size` of a compression
+    /// store) lets that store serve reads from whole blocks.
+    ///
+    /// Default: 0 (disabled)
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub read_alignment: usize,
+    /// Maximum length in bytes of a resource name in read, write and
+    /// query_write_status requests. Longer resource names are rejected with
+    /// `InvalidArgument` before they are parsed.
+    ///
+    /// Default: 4096
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub max_resource_name_length: usize,
+    /// If set, a write only reads the next message from the client once
+    /// fewer than this many bytes that were passed to the store are still
+    /// waiting to be read by the store. If the store stalls, the client is
+    /// slowed down by gRPC flow control instead of the server buffering the
+    /// data.
+    ///
+    /// Default: 0 (only bounded by the internal channel, which holds up to
+    /// two messages)
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub write_flow_control_window: usize,
+    /// Maximum size in bytes of a blob that may be written or read. Writes
+    /// declaring a larger size in their resource name are rejected with
+    /// `InvalidArgument` before any data is read from the client, so a
+    /// client can not make the store buffer an arbitrarily large blob.
+    /// Reads that would return more bytes, after applying `read_offset`
+    /// and `read_limit`, are rejected the same way.
+    ///
+    /// Default: None (blob size is not limited)
+    #[serde(default)]
+    pub max_blob_size: Option<usize>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -553,6 +653,14 @@ pub struct UploadActionResultConfig {
     /// Default: "" (no message)
     #[serde(default, deserialize_with = "convert_string_with_shellexpand")]
     pub failure_message_template: String,
+
+    /// Maximum number of outputs (files, directories and symlinks) an action
+    /// may produce. An action that produces more outputs fails with an error
+    /// instead of completing, so its result is never cached.
+    ///
+    /// Default: 0 (no limit)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_output_count: usize,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -711,6 +819,15 @@ pub struct GlobalConfig {
     /// Default: 1024*1024 (1MiB)
     #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
     pub default_digest_size_health_check: usize,
+    /// Stores normally answer requests for the empty digest without
+    /// touching the backend: it always exists, uploads of it are dropped
+    /// and reads of it return no data. Set this to true to send these
+    /// requests to the backend like any other digest, for example when a
+    /// backend needs to observe every request.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub disable_empty_digest_short_circuit: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -720,6 +837,17 @@ pub struct CasConfig {
     /// The keys can be used in other configs when needing to reference a store.
     pub stores: HashMap<StoreRefName, StoreConfig>,
 
+    /// Stores from `stores` to self-test when the process starts. Each
+    /// listed store gets a small sentinel blob written, read back and
+    /// verified, and startup fails if this round trip fails. This catches
+    /// misconfiguration such as bad credentials or missing permissions
+    /// before any traffic is served. Only list stores that accept arbitrary
+    /// blobs (e.g. CAS stores, not stores that expect action results).
+    ///
+    /// Default: [] (no store is tested)
+    #[serde(default)]
+    pub startup_self_test_stores: Vec<StoreRefName>,
+
     /// Worker configurations used to execute jobs.
     pub workers: Option<Vec<WorkerConfig>>,
 
@@ -16,13 +16,16 @@ use std::collections::HashMap;
 
 use serde::Deserialize;
 
-use crate::serde_utils::{convert_duration_with_shellexpand, convert_numeric_with_shellexpand};
+use crate::serde_utils::{
+    convert_duration_with_shellexpand, convert_numeric_with_shellexpand,
+    convert_string_with_shellexpand,
+};
 use crate::stores::{GrpcEndpoint, Retry, StoreRefName};
 
 #[allow(non_camel_case_types)]
 #[derive(Deserialize, Debug)]
 pub enum SchedulerConfig {
-    simple(SimpleScheduler),
+    simple(Box<SimpleScheduler>),
     grpc(GrpcScheduler),
     cache_lookup(CacheLookupScheduler),
     property_modifier(PropertyModifierScheduler),
@@ -63,6 +66,47 @@ pub enum WorkerAllocationStrategy {
     least_recently_used,
     /// Prefer workers that have been most recently used to run a job.
     most_recently_used,
+    /// Prefer workers that are running the fewest jobs, so work is spread
+    /// across workers instead of queueing up on a few of them. Workers
+    /// running the same number of jobs are chosen least recently used first.
+    least_loaded,
+}
+
+/// The order in which queued actions are offered to workers.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum ActionAssignmentPolicy {
+    /// Assign actions strictly by priority, then by the time they were
+    /// queued.
+    #[default]
+    priority,
+    /// Take turns between instance names, assigning the next action of each
+    /// instance (by priority) in round robin order. Prevents an instance
+    /// that floods the queue from starving other instances that share the
+    /// same workers.
+    instance_round_robin,
+}
+
+/// What the scheduler does with results that workers report for actions
+/// it is not tracking, eg. actions that were running when the scheduler
+/// restarted.
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Debug, Default, Clone)]
+pub enum OrphanedResultPolicy {
+    /// Log the result and return an error to the worker.
+    #[default]
+    ignore,
+    /// Write successful results of cacheable actions into the action cache,
+    /// so the completed work is not lost.
+    salvage(SalvageOrphanedResultsConfig),
+}
+
+/// Where orphaned results are written to.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SalvageOrphanedResultsConfig {
+    /// The action cache store the results are written to.
+    pub ac_store: StoreRefName,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -106,6 +150,33 @@ pub struct SimpleScheduler {
     #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
     pub worker_timeout_s: u64,
 
+    /// If set, newly connected workers are not given any work for this many
+    /// seconds, so they can warm up (eg. pull images or prime caches). A
+    /// worker can end its warmup early through the admin API's
+    /// `set_worker_ready` endpoint.
+    ///
+    /// Default: 0 (workers are given work as soon as they connect)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub worker_warmup_s: u64,
+
+    /// Maximum number of workers the scheduler will track at once. Once
+    /// this many workers are connected, new workers are rejected with
+    /// `ResourceExhausted` until an existing worker disconnects. Workers
+    /// that are already connected are not affected.
+    ///
+    /// Default: 0 (unlimited)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_workers: usize,
+
+    /// If set, actions that request a platform property which is not in
+    /// `supported_platform_properties` are rejected with
+    /// `FailedPrecondition` when they are added, instead of being queued
+    /// forever because no worker can ever run them.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub reject_unsupported_platform_properties: bool,
+
     /// If a job returns an internal error or times out this many times when
     /// attempting to run on a worker the scheduler will return the last error
     /// to the client. Jobs will be retried and this configuration is to help
@@ -119,6 +190,231 @@ pub struct SimpleScheduler {
     /// The strategy used to assign workers jobs.
     #[serde(default)]
     pub allocation_strategy: WorkerAllocationStrategy,
+
+    /// The order in which queued actions are assigned to workers.
+    /// Default: priority
+    #[serde(default)]
+    pub action_assignment_policy: ActionAssignmentPolicy,
+
+    /// If set, workers are split into isolated pools and actions will only
+    /// be matched to workers in the pool assigned to the action's instance
+    /// name. Useful in multi-tenant setups where one instance's actions must
+    /// never run on workers dedicated to another instance.
+    /// Default: None (all workers are shared by all instances)
+    #[serde(default)]
+    pub worker_pool_isolation: Option<WorkerPoolIsolation>,
+
+    /// If set, the scheduler will kill and reschedule actions that have been
+    /// running on a worker for longer than the action's timeout multiplied by
+    /// the first entry matching the worker's platform properties (or 1.0 if
+    /// no entry matches). This allows slower worker classes to be given
+    /// longer deadlines and enforces deadlines for workers that do not
+    /// enforce timeouts themselves (see `timeout_handled_externally`).
+    /// Actions without a timeout are never rescheduled.
+    ///
+    /// For example, a value of:
+    /// ```json
+    /// [{ "property": "worker_class", "value": "slow", "multiplier": 3.0 }]
+    /// ```
+    /// Will give actions running on workers with "worker_class" = "slow"
+    /// three times their requested timeout.
+    ///
+    /// Default: [] (the scheduler does not enforce action timeouts)
+    #[serde(default)]
+    pub action_timeout_multipliers: Vec<ActionTimeoutMultiplier>,
+
+    /// Maximum time in seconds an action may wait in the queue before it is
+    /// first assigned to a worker. Actions that exceed it are failed with
+    /// `DeadlineExceeded` without ever being started. Actions that are
+    /// queued again after a retry are not affected.
+    ///
+    /// Default: 0 (actions may wait in the queue forever)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub max_queue_wait_s: u64,
+
+    /// Maximum time in seconds an action may execute on a worker. Actions
+    /// that exceed it are killed and failed with `DeadlineExceeded` instead
+    /// of being retried. Unlike `action_timeout_multipliers`, this applies
+    /// regardless of the timeout requested by the action.
+    ///
+    /// Default: 0 (the execution time is not limited by the scheduler)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub max_execution_time_s: u64,
+
+    /// Number of the most recent failed action results to keep in memory,
+    /// including their errors and the worker they ran on. They can be
+    /// inspected through the admin API at
+    /// `{admin_path}/scheduler/{instance_name}/failed_actions`. Unlike
+    /// `retain_completed_for_s`, results are only dropped once newer
+    /// failures push them out.
+    ///
+    /// Default: 0 (failed action results are not retained)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_failed_action_results: usize,
+
+    /// If set, the queued and active actions are periodically written to a
+    /// store and restored from it when the scheduler starts, so a restart
+    /// does not drop in flight work. Actions that were running when the
+    /// scheduler stopped are queued again.
+    /// Default: None (scheduler state is only kept in memory)
+    #[serde(default)]
+    pub checkpoint: Option<SchedulerCheckpointConfig>,
+
+    /// If set, the state of every completed action is also written to this
+    /// store, and looked up there when a client asks for an action that is
+    /// not in memory. This lets clients reconnect to actions that completed
+    /// before the scheduler restarted, instead of running them again.
+    /// Entries are only returned while they are younger than
+    /// `retain_completed_for_s`, expired entries are left to the store's
+    /// own eviction policy.
+    ///
+    /// Default: None (completed actions are only kept in memory)
+    #[serde(default)]
+    pub completed_actions_store: Option<StoreRefName>,
+
+    /// What to do with results that workers report for actions the
+    /// scheduler is not tracking.
+    ///
+    /// For example, a value of:
+    /// ```json
+    /// { "salvage": { "ac_store": "AC_MAIN_STORE" } }
+    /// ```
+    /// Will write the results into the `AC_MAIN_STORE` store.
+    ///
+    /// Default: "ignore"
+    #[serde(default)]
+    pub orphaned_result_policy: OrphanedResultPolicy,
+
+    /// Names of worker platform properties that are used as tags to group
+    /// worker metrics. When set, worker metrics are aggregated for each
+    /// unique combination of these property values and labeled with them,
+    /// instead of being published per `worker_id`. This keeps the number of
+    /// metric series low on clusters with many workers.
+    ///
+    /// For example, a value of:
+    /// ```json
+    /// ["region", "instance_type"]
+    /// ```
+    /// Will publish one set of worker metrics per region and instance type.
+    ///
+    /// Default: [] (worker metrics are labeled by `worker_id`)
+    #[serde(default)]
+    pub worker_metrics_tags: Vec<String>,
+    /// If set, execution metadata timestamps reported by workers are
+    /// validated before the result is stored. Timestamps more than this many
+    /// seconds in the future are clamped to the current time, and phase
+    /// timestamps that are out of order are raised to the timestamp of the
+    /// phase before them. Corrections are logged and counted in the
+    /// `execution_metadata_timestamps_corrected` metric.
+    ///
+    /// Default: 0 (timestamps are passed through unchanged)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub execution_metadata_max_clock_skew_s: u64,
+
+    /// Map of instance name to the maximum number of actions of that
+    /// instance that may run at the same time. Once an instance reaches its
+    /// limit, its other queued actions wait even if workers are free, while
+    /// actions of other instances keep being scheduled. This prevents one
+    /// instance from using all worker capacity.
+    ///
+    /// For example, a value of:
+    /// ```json
+    /// { "main": 100, "experimental": 10 }
+    /// ```
+    /// Will run at most 10 actions of the "experimental" instance at once.
+    ///
+    /// Default: {} (instances are not limited)
+    #[serde(default)]
+    pub max_concurrent_actions_per_instance: HashMap<String, usize>,
+
+    /// Maximum number of actions that may wait in the queue. Once reached,
+    /// new actions are rejected with `ResourceExhausted` so that a storm of
+    /// actions can not grow the queue without bound. Actions that join an
+    /// identical action that is already queued or running are still
+    /// accepted.
+    ///
+    /// Default: None (the queue is not limited)
+    #[serde(default)]
+    pub max_queued_actions: Option<usize>,
+
+    /// Number of input roots of the most recent actions sent to each worker
+    /// that the scheduler remembers. When set, an action is given to a
+    /// worker that recently ran an action with the same `input_root_digest`
+    /// if one is available, since that worker likely still has the inputs
+    /// staged. Otherwise `allocation_strategy` picks the worker as usual.
+    ///
+    /// Default: 0 (input roots are not used to pick workers)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub worker_input_root_history_size: usize,
+
+    /// Time in milliseconds the matching engine waits after each run before
+    /// it may run again. This lets other tasks waiting on the scheduler lock
+    /// make progress between runs. Lower values assign actions sooner under
+    /// high churn at the cost of lock fairness; 0 disables the wait.
+    ///
+    /// Default: None (1 millisecond)
+    #[serde(default)]
+    pub match_cooldown_ms: Option<u64>,
+}
+
+/// Where and how often the scheduler state is persisted.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulerCheckpointConfig {
+    /// The store to write the checkpoint to. This should be a store that
+    /// keeps data across restarts, like a `redis_store`.
+    pub store: StoreRefName,
+
+    /// The key the checkpoint is stored under.
+    /// Default: "scheduler_checkpoint"
+    #[serde(default, deserialize_with = "convert_string_with_shellexpand")]
+    pub key: String,
+
+    /// How often the scheduler state is written to the store.
+    /// Default: 10 (seconds)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub interval_s: u64,
+}
+
+/// Scales the deadline of actions running on workers with a matching
+/// platform property.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ActionTimeoutMultiplier {
+    /// The worker platform property to match on.
+    pub property: String,
+
+    /// The value the worker's platform property must equal.
+    pub value: String,
+
+    /// Amount the action's timeout is multiplied by on matching workers.
+    pub multiplier: f32,
+}
+
+/// Configuration used to partition workers into pools based on a platform
+/// property the worker publishes when it joins the scheduler.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WorkerPoolIsolation {
+    /// The worker platform property whose value names the pool the worker
+    /// belongs to. This property must also be listed in
+    /// `supported_platform_properties` (usually as "priority", so it does not
+    /// otherwise restrict matching).
+    ///
+    /// Workers that do not publish this property are not part of any pool.
+    pub worker_property: String,
+
+    /// Map of instance name to the pool that runs its actions.
+    ///
+    /// For example, a value of:
+    /// ```json
+    /// { "tenant_a": "pool_a", "tenant_b": "pool_b" }
+    /// ```
+    /// Will result in actions for "tenant_a" only being sent to workers with
+    /// `worker_property` set to "pool_a". Actions for instance names not in
+    /// this map will only be sent to workers that are not part of any pool.
+    #[serde(default)]
+    pub instance_pools: HashMap<String, String>,
 }
 
 /// A scheduler that simply forwards requests to an upstream scheduler.  This
@@ -157,6 +453,15 @@ pub struct CacheLookupScheduler {
 
     /// The nested scheduler to use if cache lookup fails.
     pub scheduler: Box<SchedulerConfig>,
+
+    /// If true, errors from the action cache are treated as cache misses and
+    /// the action is forwarded to the nested scheduler to be executed. This
+    /// keeps builds running (slower) while the action cache is unavailable.
+    /// If false, such errors fail the action. Entries that are missing or
+    /// can not be decoded are always treated as cache misses.
+    /// Default: true
+    #[serde(default)]
+    pub ac_fail_open: Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
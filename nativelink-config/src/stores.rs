@@ -16,8 +16,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::serde_utils::{
     convert_data_size_with_shellexpand, convert_duration_with_shellexpand,
-    convert_numeric_with_shellexpand, convert_optional_string_with_shellexpand,
-    convert_string_with_shellexpand, convert_vec_string_with_shellexpand,
+    convert_numeric_with_shellexpand, convert_optional_numeric_with_shellexpand,
+    convert_optional_string_with_shellexpand, convert_string_with_shellexpand,
+    convert_vec_string_with_shellexpand,
 };
 
 /// Name of the store. This type will be used when referencing a store
@@ -25,7 +26,7 @@ use crate::serde_utils::{
 pub type StoreRefName = String;
 
 #[allow(non_camel_case_types)]
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ConfigDigestHashFunction {
     /// Use the sha256 hash function.
     /// <https://en.wikipedia.org/wiki/SHA-2>
@@ -78,6 +79,26 @@ pub enum StoreConfig {
     ///
     experimental_s3_store(S3Store),
 
+    /// GCS store will use Google Cloud Storage as a backend to store the
+    /// files. Like the S3 store, this configuration can be used to share
+    /// files across multiple instances and will never delete files.
+    ///
+    /// **Example JSON Config:**
+    /// ```json
+    /// "gcs_store": {
+    ///   "bucket": "crossplane-bucket-af79aeca9",
+    ///   "key_prefix": "test-prefix-index/",
+    ///   "retry": {
+    ///     "max_retries": 6,
+    ///     "delay": 0.3,
+    ///     "jitter": 0.5
+    ///   },
+    ///   "resumable_chunk_size": "8mb"
+    /// }
+    /// ```
+    ///
+    gcs_store(GcsStore),
+
     /// Verify store is used to apply verifications to an underlying
     /// store implementation. It is strongly encouraged to validate
     /// as much data as you can before accepting data from a client,
@@ -179,9 +200,11 @@ pub enum StoreConfig {
     /// the `content_store`, but never put DedupStore as the backend of
     /// CompressionStore as it will negate all the gains.
     ///
-    /// Note: When running `.has()` on this store, it will only check
-    /// to see if the entry exists in the `index_store` and not check
-    /// if the individual chunks exist in the `content_store`.
+    /// Note: When running `.has()` on this store, it will read the entry
+    /// from the `index_store` and then check that every chunk it
+    /// references exists in the `content_store`, using a single batched
+    /// `.has()` call per entry. An entry with any missing chunk is
+    /// reported as not found.
     ///
     /// **Example JSON Config:**
     /// ```json
@@ -250,6 +273,94 @@ pub enum StoreConfig {
     ///
     existence_cache(Box<ExistenceCacheStore>),
 
+    /// Single flight store will wrap around another store and collapse
+    /// concurrent uploads of the same digest into a single write to the
+    /// backend. Since the content is identified by its digest, any upload
+    /// that arrives while another upload of the same digest is in progress
+    /// will drain its data and wait for the first upload to finish instead
+    /// of writing it again. Likewise, concurrent reads of a whole blob are
+    /// collapsed into a single read from the backend.
+    /// Note: This store should only be used on CAS stores.
+    ///
+    /// **Example JSON Config:**
+    /// ```json
+    /// "single_flight": {
+    ///     "backend": {
+    ///       "ref_store": {
+    ///         "name": "CAS_MAIN_STORE"
+    ///       }
+    ///     }
+    ///   }
+    /// ```
+    ///
+    single_flight(Box<SingleFlightStore>),
+
+    /// Key limit store will wrap around another store and track the number
+    /// of distinct keys written through it. Once `max_keys` distinct keys
+    /// have been written, uploads of new keys are rejected with
+    /// `ResourceExhausted`, while keys that were already written can still
+    /// be overwritten. This is a safety valve for backends that do not
+    /// evict on their own.
+    ///
+    /// **Example JSON Config:**
+    /// ```json
+    /// "key_limit": {
+    ///     "backend": {
+    ///       "ref_store": {
+    ///         "name": "CAS_MAIN_STORE"
+    ///       }
+    ///     },
+    ///     "max_keys": 1000000
+    ///   }
+    /// ```
+    ///
+    key_limit(Box<KeyLimitStore>),
+
+    /// Prefetch store will wrap around another store and, after every read
+    /// of a bounded range, read the following `prefetch_window` bytes of the
+    /// same key from the backend in the background. Later reads that fall
+    /// inside the prefetched range are served from memory. This speeds up
+    /// sequential access patterns, such as reading a large blob in chunks.
+    ///
+    /// **Example JSON Config:**
+    /// ```json
+    /// "prefetch": {
+    ///     "backend": {
+    ///       "ref_store": {
+    ///         "name": "CAS_MAIN_STORE"
+    ///       }
+    ///     },
+    ///     "prefetch_window": "4mb"
+    ///   }
+    /// ```
+    ///
+    prefetch(Box<PrefetchStore>),
+
+    /// Alignment store will wrap around another store and store every blob
+    /// padded to a multiple of `alignment` bytes, with the true size of the
+    /// blob recorded at the end of the padding. Reads of the backend always
+    /// start and end on an alignment boundary and are trimmed to the range
+    /// that was requested. This is useful for backends on block devices,
+    /// where unaligned reads are slow.
+    ///
+    /// **Example JSON Config:**
+    /// ```json
+    /// "alignment": {
+    ///     "backend": {
+    ///       "filesystem": {
+    ///         "content_path": "/mnt/block_device/content",
+    ///         "temp_path": "/mnt/block_device/tmp",
+    ///         "eviction_policy": {
+    ///           "max_bytes": 10000000000
+    ///         }
+    ///       }
+    ///     },
+    ///     "alignment": "4kb"
+    ///   }
+    /// ```
+    ///
+    alignment(Box<AlignmentStore>),
+
     /// FastSlow store will first try to fetch the data from the `fast`
     /// store and then if it does not exist try the `slow` store.
     /// When the object does exist in the `slow` store, it will copy
@@ -457,14 +568,16 @@ pub struct ShardStore {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct SizePartitioningStore {
-    /// Size to partition the data on.
+    /// Size to partition the data on. Objects are routed by the size in
+    /// their digest, so uploads of unknown size go to the same store their
+    /// reads are served from.
     #[serde(deserialize_with = "convert_data_size_with_shellexpand")]
     pub size: u64,
 
     /// Store to send data when object is < (less than) size.
     pub lower_store: StoreConfig,
 
-    /// Store to send data when object is >= (less than eq) size.
+    /// Store to send data when object is >= (greater than or equal) size.
     pub upper_store: StoreConfig,
 }
 
@@ -524,6 +637,26 @@ pub struct FastSlowStore {
     /// If the object does not exist in the `fast` store it will try to
     /// get it from this store.
     pub slow: StoreConfig,
+
+    /// Objects larger than this many bytes that are not already in the
+    /// `fast` store will be read directly from the `slow` store without
+    /// being copied into the `fast` store. This keeps large, rarely re-read
+    /// objects from evicting smaller, frequently read objects out of the
+    /// `fast` store. Explicit requests to populate the `fast` store (for
+    /// example by workers) ignore this limit.
+    /// Default: 0. Zero means always populate the `fast` store.
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub fast_store_max_populate_size: usize,
+
+    /// Same as `fast_store_max_populate_size`, but unset instead of zero
+    /// means always populate the `fast` store. Must not be set together
+    /// with a non-zero `fast_store_max_populate_size`.
+    /// Default: None
+    #[serde(
+        default,
+        deserialize_with = "convert_optional_numeric_with_shellexpand"
+    )]
+    pub fast_store_max_populate_bytes: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -533,6 +666,48 @@ pub struct MemoryStore {
     /// value will cause items to never be removed from the store causing
     /// infinite memory usage.
     pub eviction_policy: Option<EvictionPolicy>,
+
+    /// If set, the store will periodically read the system memory pressure
+    /// signal and evict least recently used entries while the pressure is
+    /// over the configured threshold, regardless of `eviction_policy`. This
+    /// helps avoid the process being OOM-killed when the rest of the
+    /// machine needs memory.
+    /// Default: None (only `eviction_policy` triggers evictions)
+    #[serde(default)]
+    pub memory_pressure_eviction: Option<MemoryPressureEviction>,
+}
+
+/// Configuration for evicting entries from a `MemoryStore` based on the
+/// Linux pressure stall information (PSI) of the memory resource.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryPressureEviction {
+    /// Path to read the memory pressure stall information from.
+    /// Default: "/proc/pressure/memory"
+    #[serde(default, deserialize_with = "convert_optional_string_with_shellexpand")]
+    pub psi_path: Option<String>,
+
+    /// Eviction is triggered when the "some avg10" value of the pressure
+    /// file (the percentage of the last 10 seconds in which at least one
+    /// task was stalled waiting on memory) is at or above this value.
+    pub threshold: f32,
+
+    /// Percentage of the bytes held by the store to evict each time memory
+    /// pressure is detected.
+    /// Default: 10
+    #[serde(
+        default,
+        deserialize_with = "convert_optional_numeric_with_shellexpand"
+    )]
+    pub evict_percent: Option<u8>,
+
+    /// How often the pressure signal is checked. Must be greater than 0.
+    /// Default: 1 (seconds)
+    #[serde(
+        default,
+        deserialize_with = "convert_optional_numeric_with_shellexpand"
+    )]
+    pub check_interval_s: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -604,6 +779,98 @@ pub struct ExistenceCacheStore {
     /// value will cause items to never be removed from the store causing
     /// infinite memory usage.
     pub eviction_policy: Option<EvictionPolicy>,
+
+    /// Number of seconds to remember digests the backend reported as
+    /// missing, so repeated lookups of missing blobs during a build do not
+    /// reach the backend. A remembered digest is forgotten once it is
+    /// uploaded or read through this store. Blobs uploaded by other means
+    /// may be reported missing for up to this long. Besides causing redundant
+    /// uploads, this makes a `completeness_checking` store above this store
+    /// report action results that reference such blobs as missing, so action
+    /// cache lookups miss and the actions are run again. Only enable this if
+    /// all writes to the backend go through this store. The `max_count` of
+    /// `eviction_policy` also applies.
+    ///
+    /// Default: 0 (missing digests are not remembered)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub cache_missing_for_s: u32,
+
+    /// Maximum number of digests to look up in the backend with a single
+    /// call. Larger lookups are split into batches that are sent
+    /// concurrently.
+    ///
+    /// Default: 0 (no limit)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_backend_batch_size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SingleFlightStore {
+    /// The underlying store to wrap around. Only the first of any concurrent
+    /// uploads of the same digest will be forwarded to this store.
+    pub backend: StoreConfig,
+
+    /// Amount of time in milliseconds after a read of a blob started during
+    /// which other reads of the same blob are served from its result, even
+    /// if they arrive after it finished. This smooths out bursts of reads
+    /// that are close together but not perfectly concurrent. At most 256MiB
+    /// of finished reads are kept at a time.
+    ///
+    /// Default: 0. Zero means only concurrent reads are collapsed.
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub read_coalescing_window_millis: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyLimitStore {
+    /// The underlying store to wrap around. Uploads that do not exceed the
+    /// key limit are forwarded to this store.
+    pub backend: StoreConfig,
+
+    /// Maximum number of distinct keys that may be written through this
+    /// store. Keys written before the store was started are not counted.
+    #[serde(deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_keys: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PrefetchStore {
+    /// The underlying store to wrap around. All reads and writes are
+    /// forwarded to this store.
+    pub backend: StoreConfig,
+
+    /// Number of bytes read ahead after each read of a bounded range.
+    ///
+    /// Default: 1mb
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub prefetch_window: usize,
+
+    /// Maximum number of keys with prefetched data held in memory at once.
+    /// When exceeded, the oldest prefetched range is dropped.
+    ///
+    /// Default: 16
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_prefetched_keys: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AlignmentStore {
+    /// The underlying store to wrap around. Blobs are stored in this store
+    /// padded to a multiple of `alignment`.
+    pub backend: StoreConfig,
+
+    /// Blobs are padded to a multiple of this many bytes and reads of the
+    /// backend are aligned to it. Usually the block size of the device the
+    /// backend is stored on. Must be at least 8 bytes, the size of the
+    /// trailer that records the true size of each blob.
+    ///
+    /// Default: 4kb
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub alignment: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -630,6 +897,38 @@ pub struct VerifyStore {
     /// This should be set to None for AC, but hashing function like `sha256` for CAS stores.
     #[serde(default)]
     pub verify_hash: bool,
+
+    /// Number of recently verified digests to remember when `verify_hash`
+    /// is set. Uploads of a remembered digest that the backend still has
+    /// are read and discarded without hashing, so the verified copy is
+    /// never overwritten. Their size is still verified when `verify_size`
+    /// is set. A digest is forgotten as soon as the backend reports it as
+    /// not found, so data that went missing is hashed again when it is
+    /// uploaded again.
+    ///
+    /// Default: 0 (every upload is hashed)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub verified_digest_cache_size: usize,
+
+    /// Fraction of reads, between 0.0 and 1.0, whose data is hashed and
+    /// checked against the digest as it is served. This catches data that
+    /// was corrupted after it was stored without paying for hashing every
+    /// read. Only reads of a whole blob can be checked. Mismatches are
+    /// logged, counted and fail the read with `DataLoss`.
+    ///
+    /// Default: 0.0 (reads are not verified)
+    #[serde(default)]
+    pub read_verification_sample_rate: f32,
+
+    /// Name of the content policy that decides whether uploaded data may be
+    /// stored, for example to reject secrets. Content policies are
+    /// registered in code with `StoreManager::add_content_policy()` before
+    /// the stores are created. Creating the store fails if no policy is
+    /// registered under this name.
+    ///
+    /// Default: None (all data is accepted)
+    #[serde(default, deserialize_with = "convert_optional_string_with_shellexpand")]
+    pub content_policy: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -641,6 +940,41 @@ pub struct CompletenessCheckingStore {
     /// When a request is made, the results are decoded and all output digests/files are verified
     /// to exist in this CAS store before returning success.
     pub cas_store: StoreConfig,
+
+    /// Maximum amount of time in milliseconds a read will wait for the
+    /// completeness check to finish before applying
+    /// `completeness_check_timeout_behavior`. This only applies to reads;
+    /// `.has()` calls always wait for the check to complete.
+    ///
+    /// Default: 0. Zero means wait until the check completes.
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub completeness_check_timeout_millis: u64,
+
+    /// What to do when the completeness check of a read does not finish
+    /// within `completeness_check_timeout_millis`.
+    ///
+    /// Default: fail_closed
+    #[serde(default)]
+    pub completeness_check_timeout_behavior: CompletenessCheckTimeoutBehavior,
+    /// Maximum number of action results fetched from `backend` at the same
+    /// time while checking completeness. Checking many action results at
+    /// once can otherwise overwhelm the backend.
+    ///
+    /// Default: 0. Zero means no limit.
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_concurrent_action_result_fetches: usize,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum CompletenessCheckTimeoutBehavior {
+    /// Serve the action result from the backend even though it was not
+    /// verified that all of its outputs exist in the CAS.
+    fail_open,
+
+    /// Return NotFound, as if the action result was incomplete.
+    #[default]
+    fail_closed,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
@@ -666,6 +1000,32 @@ pub struct Lz4Config {
     pub max_decode_block_size: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct ZstdConfig {
+    /// Compression level to use, from 1 (fastest) to 22 (smallest output).
+    /// Negative values trade even more compression ratio for speed.
+    ///
+    /// Default: 0 (zstd's default level, currently 3).
+    #[serde(default)]
+    pub compression_level: i32,
+
+    /// Size of the blocks to compress.
+    /// Higher values require more ram, but yield better compression
+    /// ratios, as zstd can find matches across a larger window.
+    ///
+    /// Default: 65536 (64k).
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub block_size: u32,
+
+    /// Maximum size allowed to attempt to deserialize data into.
+    /// See `Lz4Config::max_decode_block_size`.
+    ///
+    /// Default: value in `block_size`.
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub max_decode_block_size: u32,
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum CompressionAlgorithm {
@@ -677,6 +1037,14 @@ pub enum CompressionAlgorithm {
     ///
     /// see: <https://lz4.github.io/lz4/>
     lz4(Lz4Config),
+
+    /// Zstandard compression algorithm is slower than LZ4, but yields much
+    /// better compression ratios, especially on text like source code.
+    /// Data compressed with either algorithm can always be read back,
+    /// regardless of which algorithm is currently configured.
+    ///
+    /// see: <https://facebook.github.io/zstd/>
+    zstd(ZstdConfig),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -691,6 +1059,47 @@ pub struct CompressionStore {
 
     /// The compression algorithm to use.
     pub compression_algorithm: CompressionAlgorithm,
+
+    /// Blobs with a known size smaller than this many bytes are stored
+    /// uncompressed, since compressing them adds framing overhead without
+    /// any benefit.
+    /// Default: 0. Zero means always compress.
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub min_compress_size: usize,
+
+    /// Number of blocks to fetch from the backend and decompress at the
+    /// same time when reading. If greater than one, a read first loads the
+    /// block index from the footer of the stored data, then fetches only
+    /// the blocks overlapping the requested range, this many at a time.
+    /// This costs a few extra requests to the backend, but speeds up large
+    /// reads from backends with high latency.
+    /// Default: 0. Zero or one streams and decompresses the data in order.
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub read_concurrency: usize,
+
+    /// Picks the compression block size of each upload from the size of the
+    /// blob. The rule with the largest `min_blob_size` that is not greater
+    /// than the upload size is used. Uploads smaller than every rule use the
+    /// `block_size` of the compression algorithm. The block size is stored
+    /// with the data, so changing these rules does not affect reading data
+    /// that was already stored.
+    /// Default: empty. Always use the `block_size` of the compression algorithm.
+    #[serde(default)]
+    pub block_size_by_blob_size: Vec<CompressionBlockSizeRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionBlockSizeRule {
+    /// Smallest blob size in bytes this rule applies to.
+    #[serde(deserialize_with = "convert_data_size_with_shellexpand")]
+    pub min_blob_size: usize,
+
+    /// Size of the blocks to compress blobs of at least `min_blob_size`
+    /// bytes with. Must not be larger than `max_decode_block_size` if it
+    /// is set.
+    #[serde(deserialize_with = "convert_data_size_with_shellexpand")]
+    pub block_size: u32,
 }
 
 /// Eviction policy always works on LRU (Least Recently Used). Any time an entry
@@ -750,11 +1159,80 @@ pub struct S3Store {
     /// Default: 5MB.
     pub max_retry_buffer_per_request: Option<usize>,
 
+    /// Maximum number of tokens in the retry budget. Every retry of an S3
+    /// request withdraws one token and retries are suppressed while the
+    /// budget is empty, preventing retry storms during partial outages.
+    /// All S3 stores configured with the same budget values share a single
+    /// budget. Setting this to zero disables the retry budget.
+    ///
+    /// Default: 0 (disabled)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub retry_budget_tokens: u64,
+
+    /// Number of tokens added back to the retry budget every second, up to
+    /// `retry_budget_tokens`.
+    ///
+    /// Default: 0 (budget never refills)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub retry_budget_refill_per_s: u64,
+
     /// Maximum number of concurrent UploadPart requests per MultipartUpload.
     ///
     /// Default: 10.
     pub multipart_max_concurrent_uploads: Option<usize>,
 
+    /// Maximum number of concurrent UploadPart requests across all
+    /// MultipartUploads of all S3 stores. There is one limit per process,
+    /// set by the first S3 store that configures a non-zero value; stores
+    /// that configure a different value share it and log a warning.
+    /// Setting this to zero leaves the store out of the limit.
+    ///
+    /// Default: 0 (unlimited)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub global_max_concurrent_uploads: usize,
+
+    /// Maximum number of multipart uploads this store runs at once. Each
+    /// multipart upload holds an upload id and buffers for its parts until
+    /// it completes, so this bounds the memory and S3 resources used by
+    /// many large uploads arriving together. Additional uploads wait until
+    /// a running one finishes. Unlike `multipart_max_concurrent_uploads`
+    /// this limits whole uploads, not the parts of each one.
+    ///
+    /// Default: 0 (unlimited)
+    #[serde(default, deserialize_with = "convert_numeric_with_shellexpand")]
+    pub max_concurrent_multipart_uploads: usize,
+
+    /// Size in bytes of each part of a multipart upload. Values below the
+    /// S3 minimum of 5MB are raised to 5MB, and the size is raised further
+    /// for uploads that would otherwise need more than 10,000 parts. Must
+    /// not be larger than the S3 maximum part size of 5GB.
+    ///
+    /// Default: Derived from the size of each upload.
+    pub multipart_part_size: Option<usize>,
+
+    /// Multipart uploads under `key_prefix` that were started more than this
+    /// many seconds ago are aborted when the store starts. These are left
+    /// behind if the process stops in the middle of an upload, and S3 keeps
+    /// billing for their parts until they are aborted. This should be longer
+    /// than the longest upload, since uploads started by other instances
+    /// sharing the bucket are aborted as well.
+    ///
+    /// Default: 0 (disabled)
+    #[serde(default, deserialize_with = "convert_duration_with_shellexpand")]
+    pub abort_orphaned_multipart_uploads_after_s: u64,
+
+    /// If set, every read first issues a HEAD request to confirm the object
+    /// exists and to learn its size, then issues the ranged GET with bounds
+    /// validated against that size. Reads starting past the end of the
+    /// object fail with `OutOfRange` without issuing a GET. This costs an
+    /// extra round trip per read, but avoids relying on the errors of ranged
+    /// GETs on missing objects, which are ambiguous on some S3 compatible
+    /// backends.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub head_before_get: bool,
+
     /// Allow unencrypted HTTP connections. Only use this for local testing.
     ///
     /// Default: false
@@ -772,6 +1250,52 @@ pub struct S3Store {
     pub disable_http2: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GcsStore {
+    /// Bucket name to use as the backend.
+    #[serde(default, deserialize_with = "convert_string_with_shellexpand")]
+    pub bucket: String,
+
+    /// If you wish to prefix the location in the bucket. If None, no prefix
+    /// will be used.
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+
+    /// Retry configuration to use when a network request fails.
+    #[serde(default)]
+    pub retry: Retry,
+
+    /// Base URL of the GCS JSON API. Useful for GCS emulators.
+    ///
+    /// Default: <https://storage.googleapis.com>
+    #[serde(default, deserialize_with = "convert_optional_string_with_shellexpand")]
+    pub endpoint: Option<String>,
+
+    /// Uploads with an unknown size, or a size larger than this, use a
+    /// resumable upload session and are sent in chunks of this size. Each
+    /// chunk is kept in memory so it can be resent on a retryable error.
+    /// Must be a multiple of 256KiB.
+    ///
+    /// Default: 8MiB.
+    #[serde(default, deserialize_with = "convert_data_size_with_shellexpand")]
+    pub resumable_chunk_size: usize,
+
+    /// Send requests without credentials. Only use this for public buckets
+    /// or local testing. Otherwise an access token of the default service
+    /// account is fetched from the GCE metadata server.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub anonymous_access: bool,
+
+    /// Allow unencrypted HTTP connections. Only use this for local testing.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub insecure_allow_http: bool,
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum StoreType {
@@ -909,6 +1433,13 @@ pub struct RedisStore {
     /// Default: standard,
     #[serde(default)]
     pub mode: RedisMode,
+    /// Retry configuration used while establishing the initial connection.
+    /// This lets the store come up when Redis becomes reachable shortly
+    /// after the server starts instead of failing every request.
+    ///
+    /// Default: (no retries)
+    #[serde(default)]
+    pub connection_retry: Retry,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -44,6 +44,14 @@ pub trait ActionScheduler: Sync + Send + Unpin {
         unique_qualifier: &ActionInfoHashKey,
     ) -> Option<watch::Receiver<Arc<ActionState>>>;
 
+    /// Sets the priority of an already queued action, so it is matched to
+    /// a worker in its new position. Fails if the action is already running.
+    async fn set_action_priority(
+        &self,
+        unique_qualifier: &ActionInfoHashKey,
+        priority: i32,
+    ) -> Result<(), Error>;
+
     /// Cleans up the cache of recently completed actions.
     async fn clean_recently_completed_actions(&self);
 
@@ -17,7 +17,7 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::stream::StreamExt;
-use nativelink_error::Error;
+use nativelink_error::{Code, Error, ResultExt};
 use nativelink_proto::build::bazel::remote::execution::v2::{
     ActionResult as ProtoActionResult, GetActionResultRequest,
 };
@@ -55,6 +55,8 @@ pub struct CacheLookupScheduler {
     action_scheduler: Arc<dyn ActionScheduler>,
     /// Actions that are currently performing a CacheCheck.
     cache_check_actions: Arc<Mutex<CheckActions>>,
+    /// Treat action cache errors as cache misses instead of failing the action.
+    ac_fail_open: bool,
 }
 
 async fn get_action_from_store(
@@ -62,26 +64,32 @@ async fn get_action_from_store(
     action_digest: DigestInfo,
     instance_name: String,
     digest_function: DigestHasherFunc,
-) -> Option<ProtoActionResult> {
+) -> Result<Option<ProtoActionResult>, Error> {
     // If we are a GrpcStore we shortcut here, as this is a special store.
-    if let Some(grpc_store) = ac_store.downcast_ref::<GrpcStore>(Some(action_digest.into())) {
-        let action_result_request = GetActionResultRequest {
-            instance_name,
-            action_digest: Some(action_digest.into()),
-            inline_stdout: false,
-            inline_stderr: false,
-            inline_output_files: Vec::new(),
-            digest_function: digest_function.proto_digest_func().into(),
+    let result =
+        if let Some(grpc_store) = ac_store.downcast_ref::<GrpcStore>(Some(action_digest.into())) {
+            let action_result_request = GetActionResultRequest {
+                instance_name,
+                action_digest: Some(action_digest.into()),
+                inline_stdout: false,
+                inline_stderr: false,
+                inline_output_files: Vec::new(),
+                digest_function: digest_function.proto_digest_func().into(),
+            };
+            grpc_store
+                .get_action_result(Request::new(action_result_request))
+                .await
+                .map(|response| response.into_inner())
+        } else {
+            get_and_decode_digest::<ProtoActionResult>(ac_store, action_digest.into()).await
         };
-        grpc_store
-            .get_action_result(Request::new(action_result_request))
-            .await
-            .map(|response| response.into_inner())
-            .ok()
-    } else {
-        get_and_decode_digest::<ProtoActionResult>(ac_store, action_digest.into())
-            .await
-            .ok()
+    match result {
+        Ok(action_result) => Ok(Some(action_result)),
+        // A missing entry is a normal cache miss, not an action cache failure.
+        // Entries that fail to decode are reported as `NotFound` too, so a
+        // corrupt entry is never served and the action runs again.
+        Err(err) if err.code == Code::NotFound => Ok(None),
+        Err(err) => Err(err).err_tip(|| "In CacheLookupScheduler::get_action_from_store"),
     }
 }
 
@@ -101,11 +109,16 @@ fn subscribe_to_existing_action(
 }
 
 impl CacheLookupScheduler {
-    pub fn new(ac_store: Store, action_scheduler: Arc<dyn ActionScheduler>) -> Result<Self, Error> {
+    pub fn new(
+        ac_store: Store,
+        action_scheduler: Arc<dyn ActionScheduler>,
+        ac_fail_open: bool,
+    ) -> Result<Self, Error> {
         Ok(Self {
             ac_store,
             action_scheduler,
             cache_check_actions: Default::default(),
+            ac_fail_open,
         })
     }
 }
@@ -156,6 +169,7 @@ impl ActionScheduler for CacheLookupScheduler {
 
         let ac_store = self.ac_store.clone();
         let action_scheduler = self.action_scheduler.clone();
+        let ac_fail_open = self.ac_fail_open;
         // We need this spawn because we are returning a stream and this spawn will populate the stream's data.
         background_spawn!("cache_lookup_scheduler_add_action", async move {
             // If our spawn ever dies, we will remove the action from the cache_check_actions map.
@@ -164,7 +178,7 @@ impl ActionScheduler for CacheLookupScheduler {
             // Perform cache check.
             let action_digest = current_state.action_digest();
             let instance_name = action_info.instance_name().clone();
-            if let Some(action_result) = get_action_from_store(
+            let lookup_result = match get_action_from_store(
                 &ac_store,
                 *action_digest,
                 instance_name,
@@ -172,21 +186,37 @@ impl ActionScheduler for CacheLookupScheduler {
             )
             .await
             {
-                match ac_store.has(*action_digest).await {
-                    Ok(Some(_)) => {
-                        Arc::make_mut(&mut current_state).stage =
-                            ActionStage::CompletedFromCache(action_result);
-                        let _ = tx.send(current_state);
-                        return;
-                    }
-                    Err(err) => {
-                        event!(
-                            Level::WARN,
-                            ?err,
-                            "Error while calling `has` on `ac_store` in `CacheLookupScheduler`'s `add_action` function"
-                        );
-                    }
-                    _ => {}
+                Ok(Some(action_result)) => ac_store
+                    .has(*action_digest)
+                    .await
+                    .err_tip(|| "Error while calling `has` on `ac_store` in `CacheLookupScheduler`'s `add_action` function")
+                    .map(|maybe_size| maybe_size.map(|_| action_result)),
+                Ok(None) => Ok(None),
+                Err(err) => Err(err),
+            };
+            match lookup_result {
+                Ok(Some(action_result)) => {
+                    Arc::make_mut(&mut current_state).stage =
+                        ActionStage::CompletedFromCache(action_result);
+                    let _ = tx.send(current_state);
+                    return;
+                }
+                Ok(None) => {}
+                Err(err) if ac_fail_open => {
+                    event!(
+                        Level::WARN,
+                        ?err,
+                        "Action cache lookup failed, treating as a cache miss because ac_fail_open is set"
+                    );
+                }
+                Err(err) => {
+                    Arc::make_mut(&mut current_state).stage =
+                        ActionStage::Completed(ActionResult {
+                            error: Some(err),
+                            ..Default::default()
+                        });
+                    let _ = tx.send(current_state);
+                    return;
                 }
             }
             // Not in cache, forward to upstream and proxy state.
@@ -235,5 +265,16 @@ impl ActionScheduler for CacheLookupScheduler {
             .await
     }
 
+    async fn set_action_priority(
+        &self,
+        unique_qualifier: &ActionInfoHashKey,
+        priority: i32,
+    ) -> Result<(), Error> {
+        // Actions still being looked up in the cache are not queued yet.
+        self.action_scheduler
+            .set_action_priority(unique_qualifier, priority)
+            .await
+    }
+
     async fn clean_recently_completed_actions(&self) {}
 }
@@ -16,20 +16,28 @@ use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
-use nativelink_config::schedulers::SchedulerConfig;
-use nativelink_error::{Error, ResultExt};
+use nativelink_config::schedulers::{
+    OrphanedResultPolicy, SchedulerCheckpointConfig, SchedulerConfig,
+};
+use nativelink_error::{Code, Error, ResultExt};
 use nativelink_store::store_manager::StoreManager;
 use nativelink_util::background_spawn;
 use nativelink_util::metrics_utils::Registry;
+use nativelink_util::store_trait::{Store, StoreKey, StoreLike};
 use tokio::time::interval;
+use tracing::{event, Level};
 
 use crate::action_scheduler::ActionScheduler;
 use crate::cache_lookup_scheduler::CacheLookupScheduler;
 use crate::grpc_scheduler::GrpcScheduler;
 use crate::property_modifier_scheduler::PropertyModifierScheduler;
+use crate::scheduler_state::checkpoint::SchedulerCheckpoint;
 use crate::simple_scheduler::SimpleScheduler;
 use crate::worker_scheduler::WorkerScheduler;
 
+const DEFAULT_CHECKPOINT_KEY: &str = "scheduler_checkpoint";
+const DEFAULT_CHECKPOINT_INTERVAL_S: u64 = 10;
+
 pub type SchedulerFactoryResults = (
     Option<Arc<dyn ActionScheduler>>,
     Option<Arc<dyn WorkerScheduler>>,
@@ -57,7 +65,36 @@ fn inner_scheduler_factory(
 ) -> Result<SchedulerFactoryResults, Error> {
     let scheduler: SchedulerFactoryResults = match scheduler_type_cfg {
         SchedulerConfig::simple(config) => {
-            let scheduler = Arc::new(SimpleScheduler::new(config));
+            let mut scheduler = SimpleScheduler::new(config);
+            if let OrphanedResultPolicy::salvage(salvage_config) = &config.orphaned_result_policy {
+                let ac_store = store_manager
+                    .get_store(&salvage_config.ac_store)
+                    .err_tip(|| {
+                        format!(
+                            "'orphaned_result_policy.salvage.ac_store': '{}' does not exist",
+                            salvage_config.ac_store
+                        )
+                    })?;
+                scheduler = scheduler.with_orphaned_result_ac_store(ac_store);
+            }
+            if let Some(store_name) = &config.completed_actions_store {
+                let store = store_manager.get_store(store_name).err_tip(|| {
+                    format!("'completed_actions_store': '{store_name}' does not exist")
+                })?;
+                scheduler = scheduler.with_completed_actions_store(store);
+            }
+            let scheduler = Arc::new(scheduler);
+            if let Some(checkpoint_config) = &config.checkpoint {
+                let store = store_manager
+                    .get_store(&checkpoint_config.store)
+                    .err_tip(|| {
+                        format!(
+                            "'checkpoint.store': '{}' does not exist",
+                            checkpoint_config.store
+                        )
+                    })?;
+                start_checkpoint_timer(&scheduler, store, checkpoint_config);
+            }
             (Some(scheduler.clone()), Some(scheduler))
         }
         SchedulerConfig::grpc(config) => (Some(Arc::new(GrpcScheduler::new(config)?)), None),
@@ -71,6 +108,7 @@ fn inner_scheduler_factory(
             let cache_lookup_scheduler = Arc::new(CacheLookupScheduler::new(
                 ac_store,
                 action_scheduler.err_tip(|| "Nested scheduler is not an action scheduler")?,
+                config.ac_fail_open.unwrap_or(true),
             )?);
             (Some(cache_lookup_scheduler), worker_scheduler)
         }
@@ -130,3 +168,64 @@ fn start_cleanup_timer(action_scheduler: &Arc<dyn ActionScheduler>) {
         }
     });
 }
+
+/// Restores the scheduler state from the checkpoint in `store` (if any) and
+/// then periodically writes a new checkpoint to it.
+fn start_checkpoint_timer(
+    scheduler: &Arc<SimpleScheduler>,
+    store: Store,
+    config: &SchedulerCheckpointConfig,
+) {
+    let weak_scheduler = Arc::downgrade(scheduler);
+    let key = if config.key.is_empty() {
+        DEFAULT_CHECKPOINT_KEY.to_string()
+    } else {
+        config.key.clone()
+    };
+    let interval_s = if config.interval_s == 0 {
+        DEFAULT_CHECKPOINT_INTERVAL_S
+    } else {
+        config.interval_s
+    };
+    background_spawn!("default_scheduler_factory_checkpoint_timer", async move {
+        // Restore before writing the first checkpoint, otherwise the previous
+        // checkpoint would be overwritten with an empty state.
+        match store
+            .get_part_unchunked(StoreKey::from(key.as_str()), 0, None)
+            .await
+        {
+            Ok(data) => match serde_json::from_slice::<SchedulerCheckpoint>(&data) {
+                Ok(checkpoint) => match weak_scheduler.upgrade() {
+                    Some(scheduler) => scheduler.restore_checkpoint(checkpoint).await,
+                    None => return,
+                },
+                Err(err) => event!(Level::ERROR, ?err, "Failed to decode scheduler checkpoint"),
+            },
+            Err(err) if err.code == Code::NotFound => {}
+            Err(err) => event!(Level::ERROR, ?err, "Failed to read scheduler checkpoint"),
+        }
+        let mut ticker = interval(Duration::from_secs(interval_s));
+        loop {
+            ticker.tick().await;
+            // If we fail to upgrade, our service is probably destroyed, so return.
+            let Some(scheduler) = weak_scheduler.upgrade() else {
+                return;
+            };
+            let checkpoint = scheduler.checkpoint().await;
+            drop(scheduler);
+            let data = match serde_json::to_vec(&checkpoint) {
+                Ok(data) => data,
+                Err(err) => {
+                    event!(Level::ERROR, ?err, "Failed to encode scheduler checkpoint");
+                    continue;
+                }
+            };
+            if let Err(err) = store
+                .update_oneshot(StoreKey::from(key.as_str()), data.into())
+                .await
+            {
+                event!(Level::WARN, ?err, "Failed to write scheduler checkpoint");
+            }
+        }
+    });
+}
@@ -291,5 +291,16 @@ impl ActionScheduler for GrpcScheduler {
         }
     }
 
+    async fn set_action_priority(
+        &self,
+        _unique_qualifier: &ActionInfoHashKey,
+        _priority: i32,
+    ) -> Result<(), Error> {
+        Err(make_err!(
+            Code::Unimplemented,
+            "Changing the priority of actions is not supported by GrpcScheduler"
+        ))
+    }
+
     async fn clean_recently_completed_actions(&self) {}
 }
@@ -24,7 +24,7 @@ use nativelink_util::action_messages::{
     ActionInfo, ActionInfoHashKey, ActionStage, ActionState, OperationId, WorkerId,
 };
 use nativelink_util::common::DigestInfo;
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
 
 bitflags! {
     #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -97,6 +97,57 @@ pub struct OrderBy {
 
 pub type ActionStateResultStream = Pin<Box<dyn Stream<Item = Arc<dyn ActionStateResult>> + Send>>;
 
+/// Policy hook that decides whether a new action may be queued. This lets
+/// operators reject actions based on custom rules (e.g. a command allowlist
+/// or resource ceilings) before they reach the queue.
+#[async_trait]
+pub trait AdmissionController: Send + Sync + 'static {
+    /// Returns an error if the action should be rejected. The error is
+    /// returned to the client unchanged.
+    async fn admit(&self, action_info: &ActionInfo) -> Result<(), Error>;
+}
+
+/// A change of the stage of an action in the scheduler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionStageEvent {
+    /// The operation whose stage changed.
+    pub operation_id: OperationId,
+    /// The stage before the change.
+    pub old_stage: ActionStage,
+    /// The stage after the change.
+    pub new_stage: ActionStage,
+    /// When the change happened.
+    pub timestamp: SystemTime,
+}
+
+/// Hook that is told about every stage change of the actions in the
+/// scheduler, e.g. to export an execution timeline to an external
+/// observability pipeline.
+pub trait ActionEventListener: Send + Sync + 'static {
+    /// Called while the scheduler holds its lock, so this must never block.
+    /// Implementations should drop events they can not handle immediately.
+    fn on_stage_change(&self, event: ActionStageEvent);
+}
+
+/// Forwards events into the channel, dropping them if the channel is full
+/// or closed.
+impl ActionEventListener for mpsc::Sender<ActionStageEvent> {
+    fn on_stage_change(&self, event: ActionStageEvent) {
+        let _ = self.try_send(event);
+    }
+}
+
+/// The default `AdmissionController`, which admits every action.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AdmitAllController;
+
+#[async_trait]
+impl AdmissionController for AdmitAllController {
+    async fn admit(&self, _action_info: &ActionInfo) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
 #[async_trait]
 pub trait ClientStateManager {
     /// Add a new action to the queue or joins an existing action.
@@ -145,5 +196,5 @@ pub trait MatchingEngineStateManager {
     /// Remove an operation from the state manager.
     /// It is important to use this function to remove operations
     /// that are no longer needed to prevent memory leaks.
-    async fn remove_operation(&self, operation_id: OperationId) -> Result<(), Error>;
+    async fn remove_operation(&mut self, operation_id: OperationId) -> Result<(), Error>;
 }
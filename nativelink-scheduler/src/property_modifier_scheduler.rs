@@ -121,6 +121,16 @@ impl ActionScheduler for PropertyModifierScheduler {
         self.scheduler.find_existing_action(unique_qualifier).await
     }
 
+    async fn set_action_priority(
+        &self,
+        unique_qualifier: &ActionInfoHashKey,
+        priority: i32,
+    ) -> Result<(), Error> {
+        self.scheduler
+            .set_action_priority(unique_qualifier, priority)
+            .await
+    }
+
     async fn clean_recently_completed_actions(&self) {
         self.scheduler.clean_recently_completed_actions().await
     }
@@ -459,7 +459,7 @@ impl MatchingEngineStateManager for RedisStateManager {
             .await
     }
 
-    async fn remove_operation(&self, operation_id: OperationId) -> Result<(), Error> {
+    async fn remove_operation(&mut self, operation_id: OperationId) -> Result<(), Error> {
         self.inner_remove_operation(operation_id).await
     }
 }
@@ -0,0 +1,40 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use nativelink_util::action_messages::{ActionInfo, WorkerId};
+use serde::{Deserialize, Serialize};
+
+/// Serializable copy of the scheduler state that is needed to resume
+/// queued and in flight work after the scheduler restarts.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct SchedulerCheckpoint {
+    /// Actions waiting for a worker, highest priority first.
+    pub queued_actions: Vec<CheckpointedAction>,
+
+    /// Actions that were assigned to a worker when the checkpoint was taken.
+    pub active_actions: Vec<CheckpointedAction>,
+}
+
+/// An action and its scheduling progress at the time of the checkpoint.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CheckpointedAction {
+    /// The action that was queued or running.
+    pub action_info: ActionInfo,
+
+    /// Number of attempts the action has been tried.
+    pub attempts: usize,
+
+    /// Worker the action was assigned to, None if it was queued.
+    pub worker_id: Option<WorkerId>,
+}
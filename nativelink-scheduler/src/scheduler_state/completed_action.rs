@@ -15,10 +15,14 @@
 use std::borrow::Borrow;
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use nativelink_error::{make_input_err, Error, ResultExt};
+use nativelink_proto::google::longrunning::Operation;
 use nativelink_util::action_messages::{ActionInfoHashKey, ActionState, OperationId};
 use nativelink_util::metrics_utils::{CollectorState, MetricsComponent};
+use nativelink_util::store_trait::StoreKey;
+use prost::Message;
 
 /// A completed action that has no listeners.
 pub struct CompletedAction {
@@ -28,6 +32,69 @@ pub struct CompletedAction {
     pub(crate) state: Arc<ActionState>,
 }
 
+/// How a `CompletedAction` is written to the `completed_actions_store`.
+#[derive(Clone, PartialEq, Message)]
+struct StoredCompletedAction {
+    /// Seconds since `UNIX_EPOCH` the action was completed at.
+    #[prost(uint64, tag = "1")]
+    completed_time_s: u64,
+    /// Nanoseconds of the second the action was completed at.
+    #[prost(uint32, tag = "2")]
+    completed_time_nanos: u32,
+    /// The `Operation` sent to clients for the action.
+    #[prost(message, optional, tag = "3")]
+    operation: Option<Operation>,
+}
+
+impl CompletedAction {
+    /// Whether the action completed less than `retain_for` before `now`.
+    /// Actions that completed after `now` are retained.
+    pub(crate) fn is_retained(&self, now: SystemTime, retain_for: Duration) -> bool {
+        now.duration_since(self.completed_time).unwrap_or_default() < retain_for
+    }
+
+    /// Encodes the action as it is written to the `completed_actions_store`.
+    pub(crate) fn encode_to_vec(&self) -> Vec<u8> {
+        let completed_time = self
+            .completed_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        StoredCompletedAction {
+            completed_time_s: completed_time.as_secs(),
+            completed_time_nanos: completed_time.subsec_nanos(),
+            operation: Some(Operation::from(ActionState::clone(&self.state))),
+        }
+        .encode_to_vec()
+    }
+
+    /// Decodes an action written by `encode_to_vec()`.
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, Error> {
+        let stored = StoredCompletedAction::decode(data)
+            .map_err(|e| make_input_err!("Failed to decode completed action: {e:?}"))?;
+        let operation = stored
+            .operation
+            .err_tip(|| "Expected operation in stored completed action")?;
+        Ok(Self {
+            completed_time: UNIX_EPOCH
+                + Duration::new(stored.completed_time_s, stored.completed_time_nanos),
+            state: Arc::new(
+                ActionState::try_from(operation)
+                    .err_tip(|| "Failed to convert operation of stored completed action")?,
+            ),
+        })
+    }
+}
+
+/// Key a completed action is written under in the `completed_actions_store`.
+pub(crate) fn completed_action_store_key(
+    unique_qualifier: &ActionInfoHashKey,
+) -> StoreKey<'static> {
+    StoreKey::from(format!(
+        "completed_action/{}",
+        unique_qualifier.action_name()
+    ))
+}
+
 impl Hash for CompletedAction {
     fn hash<H: Hasher>(&self, state: &mut H) {
         OperationId::hash(&self.state.id, state);
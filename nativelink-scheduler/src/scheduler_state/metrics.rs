@@ -19,9 +19,11 @@ pub(crate) struct Metrics {
     pub(crate) add_action_joined_running_action: CounterWithTime,
     pub(crate) add_action_joined_queued_action: CounterWithTime,
     pub(crate) add_action_new_action_created: CounterWithTime,
+    pub(crate) add_action_queue_full: CounterWithTime,
     pub(crate) update_action_missing_action_result: CounterWithTime,
     pub(crate) update_action_from_wrong_worker: CounterWithTime,
     pub(crate) update_action_no_more_listeners: CounterWithTime,
+    pub(crate) update_action_stage_regression: CounterWithTime,
     pub(crate) update_action_with_internal_error: CounterWithTime,
     pub(crate) update_action_with_internal_error_no_action: CounterWithTime,
     pub(crate) update_action_with_internal_error_backpressure: CounterWithTime,
@@ -55,6 +57,12 @@ impl Metrics {
                 "Stats about add_action().",
                 vec![("result".into(), "new_action_created".into())],
             );
+            c.publish_with_labels(
+                "add_action",
+                &self.add_action_queue_full,
+                "Stats about add_action().",
+                vec![("result".into(), "queue_full".into())],
+            );
         }
         {
             c.publish_with_labels(
@@ -75,6 +83,12 @@ impl Metrics {
                 "Stats about errors when worker sends update_action() to scheduler. These errors are not complete, just the most common.",
                 vec![("result".into(), "no_more_listeners".into())],
             );
+            c.publish_with_labels(
+                "update_action_errors",
+                &self.update_action_stage_regression,
+                "Stats about errors when worker sends update_action() to scheduler. These errors are not complete, just the most common.",
+                vec![("result".into(), "stage_regression".into())],
+            );
         }
         {
             c.publish(
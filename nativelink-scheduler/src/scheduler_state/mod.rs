@@ -13,6 +13,7 @@
 // limitations under the License.
 
 pub(crate) mod awaited_action;
+pub mod checkpoint;
 pub(crate) mod client_action_state_result;
 pub(crate) mod completed_action;
 pub(crate) mod matching_engine_action_state_result;
@@ -12,10 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cmp;
-use std::collections::BTreeMap;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{cmp, mem};
 
 use async_trait::async_trait;
 use futures::stream;
@@ -25,21 +25,38 @@ use nativelink_util::action_messages::{
     ActionInfo, ActionInfoHashKey, ActionResult, ActionStage, ActionState, ExecutionMetadata,
     OperationId, WorkerId,
 };
+use nativelink_util::background_spawn;
+use nativelink_util::store_trait::{Store, StoreLike};
 use tokio::sync::watch::error::SendError;
 use tokio::sync::{watch, Notify};
 use tracing::{event, Level};
 
 use crate::operation_state_manager::{
-    ActionStateResult, ActionStateResultStream, ClientStateManager, MatchingEngineStateManager,
-    OperationFilter, WorkerStateManager,
+    ActionEventListener, ActionStageEvent, ActionStateResult, ActionStateResultStream,
+    ClientStateManager, MatchingEngineStateManager, OperationFilter, WorkerStateManager,
 };
 use crate::scheduler_state::awaited_action::AwaitedAction;
+use crate::scheduler_state::checkpoint::{CheckpointedAction, SchedulerCheckpoint};
 use crate::scheduler_state::client_action_state_result::ClientActionStateResult;
-use crate::scheduler_state::completed_action::CompletedAction;
+use crate::scheduler_state::completed_action::{completed_action_store_key, CompletedAction};
 use crate::scheduler_state::matching_engine_action_state_result::MatchingEngineActionStateResult;
 use crate::scheduler_state::metrics::Metrics;
 use crate::scheduler_state::workers::Workers;
-use crate::worker::WorkerUpdate;
+use crate::simple_scheduler::NowFn;
+use crate::worker::{WorkerTimestamp, WorkerUpdate};
+use crate::worker_scheduler::FailedActionResult;
+
+/// Position of `stage` in the lifetime of an action. Workers may only report
+/// stages that do not go back from the current stage of an action.
+const fn stage_order(stage: &ActionStage) -> u8 {
+    match stage {
+        ActionStage::Unknown => 0,
+        ActionStage::CacheCheck => 1,
+        ActionStage::Queued => 2,
+        ActionStage::Executing => 3,
+        ActionStage::Completed(_) | ActionStage::CompletedFromCache(_) => 4,
+    }
+}
 
 #[repr(transparent)]
 pub(crate) struct StateManager {
@@ -56,7 +73,12 @@ impl StateManager {
         recently_completed_actions: HashSet<CompletedAction>,
         metrics: Arc<Metrics>,
         max_job_retries: usize,
+        max_failed_action_results: usize,
+        max_queued_actions: Option<usize>,
+        completed_actions_store: Arc<OnceLock<Store>>,
+        action_event_listener: Arc<OnceLock<Arc<dyn ActionEventListener>>>,
         tasks_or_workers_change_notify: Arc<Notify>,
+        now_fn: NowFn,
     ) -> Self {
         Self {
             inner: StateManagerImpl {
@@ -67,11 +89,164 @@ impl StateManager {
                 recently_completed_actions,
                 metrics,
                 max_job_retries,
+                failed_action_results: VecDeque::new(),
+                max_failed_action_results,
+                max_queued_actions,
+                completed_actions_store,
+                action_event_listener,
                 tasks_or_workers_change_notify,
+                now_fn,
             },
         }
     }
 
+    /// Keeps `state` in `recently_completed_actions` and, if a
+    /// `completed_actions_store` is set, writes it there in the background so
+    /// it can still be found after a restart.
+    pub(crate) fn insert_completed_action(&mut self, state: Arc<ActionState>) {
+        let completed_action = CompletedAction {
+            completed_time: (self.inner.now_fn)(),
+            state,
+        };
+        if let Some(store) = self.inner.completed_actions_store.get() {
+            let store = store.clone();
+            let key = completed_action_store_key(completed_action.state.unique_qualifier());
+            let data = completed_action.encode_to_vec();
+            background_spawn!("state_manager_write_completed_action", async move {
+                if let Err(err) = store.update_oneshot(key, data.into()).await {
+                    event!(
+                        Level::WARN,
+                        ?err,
+                        "Failed to write completed action to store"
+                    );
+                }
+            });
+        }
+        self.inner
+            .recently_completed_actions
+            .insert(completed_action);
+    }
+
+    /// Removes the completed actions of `unique_qualifiers` from the
+    /// `completed_actions_store` in the background, if one is set.
+    pub(crate) fn remove_stored_completed_actions(
+        &self,
+        unique_qualifiers: Vec<ActionInfoHashKey>,
+    ) {
+        let Some(store) = self.inner.completed_actions_store.get() else {
+            return;
+        };
+        if unique_qualifiers.is_empty() {
+            return;
+        }
+        let store = store.clone();
+        background_spawn!("state_manager_remove_completed_actions", async move {
+            for unique_qualifier in unique_qualifiers {
+                match store
+                    .remove(completed_action_store_key(&unique_qualifier))
+                    .await
+                {
+                    Ok(()) => {}
+                    // Expired entries are still ignored when they are read.
+                    Err(err) if err.code == Code::Unimplemented => return,
+                    Err(err) => event!(
+                        Level::WARN,
+                        ?err,
+                        "Failed to remove completed action from store"
+                    ),
+                }
+            }
+        });
+    }
+
+    /// Returns the stage of the action `worker_id` is running or most
+    /// recently completed for `unique_qualifier`, if any.
+    fn current_stage_of_worker_action(
+        &self,
+        unique_qualifier: &ActionInfoHashKey,
+        worker_id: WorkerId,
+    ) -> Option<&ActionStage> {
+        if let Some(running_action) = self.inner.active_actions.get(unique_qualifier) {
+            return (running_action.worker_id == Some(worker_id))
+                .then_some(&running_action.current_state.stage);
+        }
+        self.inner
+            .recently_completed_actions
+            .get(unique_qualifier)
+            .map(|completed_action| &completed_action.state.stage)
+    }
+
+    /// Starts the queued operation on `worker_id`, recording `now_timestamp`
+    /// of the worker clock as the time the action started.
+    pub(crate) async fn match_operation(
+        &mut self,
+        operation_id: OperationId,
+        worker_id: Option<WorkerId>,
+        action_stage: Result<ActionStage, Error>,
+        now_timestamp: WorkerTimestamp,
+    ) -> Result<(), Error> {
+        if let Some(action_info) = self
+            .inner
+            .queued_actions_set
+            .get(&operation_id.unique_qualifier)
+        {
+            if let Some(worker_id) = worker_id {
+                let action_info = action_info.clone();
+                self.worker_notify_run_action(worker_id, action_info.clone(), now_timestamp)
+                    .await?;
+                self.worker_set_as_active(action_info, worker_id, action_stage)
+                    .await?;
+            } else {
+                event!(
+                    Level::WARN,
+                    ?operation_id,
+                    ?worker_id,
+                    "No worker found in do_try_match()"
+                );
+            }
+        } else {
+            event!(
+                Level::WARN,
+                ?operation_id,
+                ?worker_id,
+                "No action info found in do_try_match()"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Keeps a copy of `awaited_action` in `failed_action_results` if it
+    /// completed with an error, dropping the oldest entry when full.
+    pub(crate) fn record_if_failed(&mut self, awaited_action: &AwaitedAction) {
+        let max_failed_action_results = self.inner.max_failed_action_results;
+        if max_failed_action_results == 0 {
+            return;
+        }
+        let ActionStage::Completed(action_result) = &awaited_action.current_state.stage else {
+            return;
+        };
+        let Some(err) = &action_result.error else {
+            return;
+        };
+        let failed_action_results = &mut self.inner.failed_action_results;
+        while failed_action_results.len() >= max_failed_action_results {
+            failed_action_results.pop_front();
+        }
+        failed_action_results.push_back(FailedActionResult {
+            action_name: awaited_action.action_info.unique_qualifier.action_name(),
+            operation_id: awaited_action.current_state.id.to_string(),
+            worker_id: action_result.execution_metadata.worker.clone(),
+            attempts: awaited_action.attempts,
+            code: format!("{:?}", err.code),
+            messages: err.messages.clone(),
+            failed_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+    }
+
     fn immediate_evict_worker(&mut self, worker_id: &WorkerId, err: Error) {
         if let Some(mut worker) = self.inner.workers.remove_worker(worker_id) {
             self.inner.metrics.workers_evicted.inc();
@@ -94,7 +269,7 @@ impl StateManager {
                 let mut awaited_action = running_action;
                 let send_result = if awaited_action.attempts >= self.inner.max_job_retries {
                     self.inner.metrics.retry_action_max_attempts_reached.inc();
-                    Arc::make_mut(&mut awaited_action.current_state).stage = ActionStage::Completed(ActionResult {
+                    let send_result = self.mutate_stage(&mut awaited_action, ActionStage::Completed(ActionResult {
                         execution_metadata: ExecutionMetadata {
                             worker: format!("{worker_id}"),
                             ..ExecutionMetadata::default()
@@ -104,18 +279,14 @@ impl StateManager {
                             "Job cancelled because it attempted to execute too many times and failed"
                         ))),
                         ..ActionResult::default()
-                    });
-                    awaited_action
-                        .notify_channel
-                        .send(awaited_action.current_state.clone())
+                    }));
+                    self.record_if_failed(&awaited_action);
+                    send_result
                     // Do not put the action back in the queue here, as this action attempted to run too many
                     // times.
                 } else {
                     self.inner.metrics.retry_action.inc();
-                    Arc::make_mut(&mut awaited_action.current_state).stage = ActionStage::Queued;
-                    let send_result = awaited_action
-                        .notify_channel
-                        .send(awaited_action.current_state.clone());
+                    let send_result = self.mutate_stage(&mut awaited_action, ActionStage::Queued);
                     self.inner.queued_actions_set.insert(action_info.clone());
                     self.inner
                         .queued_actions
@@ -196,8 +367,31 @@ pub(crate) struct StateManagerImpl {
     /// Default times a job can retry before failing.
     pub(crate) max_job_retries: usize,
 
+    /// The most recent actions that completed with an error, oldest first.
+    /// Kept for debugging and never used to answer clients.
+    pub(crate) failed_action_results: VecDeque<FailedActionResult>,
+
+    /// Maximum number of entries in `failed_action_results`. Zero disables it.
+    pub(crate) max_failed_action_results: usize,
+
+    /// Maximum number of actions in `queued_actions`. New actions are
+    /// rejected once it is reached. None means unlimited.
+    pub(crate) max_queued_actions: Option<usize>,
+
+    /// If set, completed actions are also written to this store so they
+    /// survive a scheduler restart. Shared with the `SimpleScheduler`, which
+    /// sets it before the scheduler is used.
+    pub(crate) completed_actions_store: Arc<OnceLock<Store>>,
+
+    /// If set, told about every stage change of an action. Shared with the
+    /// `SimpleScheduler`, which sets it before the scheduler is used.
+    pub(crate) action_event_listener: Arc<OnceLock<Arc<dyn ActionEventListener>>>,
+
     /// Notify task<->worker matching engine that work needs to be done.
     pub(crate) tasks_or_workers_change_notify: Arc<Notify>,
+
+    /// The clock actions and workers are timed with.
+    pub(crate) now_fn: NowFn,
 }
 
 impl StateManager {
@@ -215,11 +409,25 @@ impl StateManager {
     /// while another thread is operating on the data, it is acceptable, since the other thread
     /// will receive another update with the new version.
     ///
+    /// The `action_event_listener` is told about the change, if set.
+    ///
     pub(crate) fn mutate_stage(
+        &self,
         awaited_action: &mut AwaitedAction,
         action_stage: ActionStage,
     ) -> Result<(), SendError<Arc<ActionState>>> {
-        Arc::make_mut(&mut awaited_action.current_state).stage = action_stage;
+        let old_stage = mem::replace(
+            &mut Arc::make_mut(&mut awaited_action.current_state).stage,
+            action_stage,
+        );
+        if let Some(action_event_listener) = self.inner.action_event_listener.get() {
+            action_event_listener.on_stage_change(ActionStageEvent {
+                operation_id: awaited_action.current_state.id.clone(),
+                old_stage,
+                new_stage: awaited_action.current_state.stage.clone(),
+                timestamp: SystemTime::now(),
+            });
+        }
         awaited_action
             .notify_channel
             .send(awaited_action.current_state.clone())
@@ -260,10 +468,11 @@ impl StateManager {
         &mut self,
         worker_id: WorkerId,
         action_info: Arc<ActionInfo>,
+        now_timestamp: WorkerTimestamp,
     ) -> Result<(), Error> {
         if let Some(worker) = self.inner.workers.workers.get_mut(&worker_id) {
             let notify_worker_result =
-                worker.notify_update(WorkerUpdate::RunAction(action_info.clone()));
+                worker.notify_update(WorkerUpdate::RunAction(action_info.clone(), now_timestamp));
 
             if notify_worker_result.is_err() {
                 event!(
@@ -283,6 +492,9 @@ impl StateManager {
                 return Err(err);
             }
         }
+        self.inner
+            .workers
+            .record_input_root(&worker_id, action_info.input_root_digest);
         Ok(())
     }
 
@@ -299,12 +511,13 @@ impl StateManager {
     /// This function will return an error if updating the state of the `awaited_action` fails.
     ///
     async fn worker_set_action_stage(
+        &self,
         awaited_action: &mut AwaitedAction,
         action_stage: Result<ActionStage, Error>,
         worker_id: WorkerId,
     ) -> Result<(), SendError<Arc<ActionState>>> {
         match action_stage {
-            Ok(action_stage) => StateManager::mutate_stage(awaited_action, action_stage),
+            Ok(action_stage) => self.mutate_stage(awaited_action, action_stage),
             Err(e) => {
                 event!(
                     Level::WARN,
@@ -345,9 +558,9 @@ impl StateManager {
 
             awaited_action.worker_id = Some(worker_id);
 
-            let send_result =
-                StateManager::worker_set_action_stage(&mut awaited_action, action_stage, worker_id)
-                    .await;
+            let send_result = self
+                .worker_set_action_stage(&mut awaited_action, action_stage, worker_id)
+                .await;
 
             if send_result.is_err() {
                 event!(
@@ -453,6 +666,149 @@ impl StateManager {
         self.retry_action(&action_info, worker_id, err);
         self.inner.tasks_or_workers_change_notify.notify_one();
     }
+
+    /// Captures the queued and active actions so they can be restored with
+    /// `restore_checkpoint()` after the scheduler restarts.
+    pub(crate) fn checkpoint(&self) -> SchedulerCheckpoint {
+        let checkpoint_action =
+            |action_info: &Arc<ActionInfo>, awaited_action: &AwaitedAction| CheckpointedAction {
+                action_info: action_info.as_ref().clone(),
+                attempts: awaited_action.attempts,
+                worker_id: awaited_action.worker_id,
+            };
+        SchedulerCheckpoint {
+            queued_actions: self
+                .inner
+                .queued_actions
+                .iter()
+                .rev()
+                .map(|(action_info, awaited_action)| checkpoint_action(action_info, awaited_action))
+                .collect(),
+            active_actions: self
+                .inner
+                .active_actions
+                .iter()
+                .map(|(action_info, awaited_action)| checkpoint_action(action_info, awaited_action))
+                .collect(),
+        }
+    }
+
+    /// Queues the actions from a checkpoint taken with `checkpoint()`.
+    /// Actions that were active are queued again, since workers reconnect to
+    /// a restarted scheduler with new ids and do not resume the actions they
+    /// were running. Actions already known to the scheduler are skipped.
+    pub(crate) fn restore_checkpoint(&mut self, checkpoint: SchedulerCheckpoint) {
+        for checkpointed_action in checkpoint
+            .queued_actions
+            .into_iter()
+            .chain(checkpoint.active_actions)
+        {
+            let action_info = Arc::new(checkpointed_action.action_info);
+            if self.inner.queued_actions_set.contains(&action_info)
+                || self.inner.active_actions.contains_key(&action_info)
+            {
+                continue;
+            }
+            let current_state = Arc::new(ActionState {
+                stage: ActionStage::Queued,
+                id: OperationId::new(action_info.unique_qualifier.clone()),
+            });
+            let (tx, _rx) = watch::channel(current_state.clone());
+            self.inner.queued_actions_set.insert(action_info.clone());
+            self.inner.queued_actions.insert(
+                action_info.clone(),
+                AwaitedAction {
+                    action_info,
+                    current_state,
+                    notify_channel: tx,
+                    attempts: checkpointed_action.attempts,
+                    last_error: None,
+                    worker_id: None,
+                },
+            );
+        }
+        self.inner.tasks_or_workers_change_notify.notify_one();
+    }
+
+    /// Sets the priority of every queued action of `instance_name` to
+    /// `priority` and re-sorts them in the queue. Active actions are left
+    /// untouched. Returns the number of actions that were changed.
+    pub(crate) fn reprioritize_instance(&mut self, instance_name: &str, priority: i32) -> usize {
+        let matching_actions: Vec<Arc<ActionInfo>> = self
+            .inner
+            .queued_actions_set
+            .iter()
+            .filter(|action_info| {
+                action_info.unique_qualifier.instance_name == instance_name
+                    && action_info.priority != priority
+            })
+            .cloned()
+            .collect();
+        for action_info in &matching_actions {
+            self.requeue_with_priority(&action_info.unique_qualifier, priority);
+        }
+        if !matching_actions.is_empty() {
+            self.inner.tasks_or_workers_change_notify.notify_one();
+        }
+        matching_actions.len()
+    }
+
+    /// Sets the priority of the queued action `unique_qualifier` to
+    /// `priority`. Actions that are already running can not be
+    /// reprioritized.
+    pub(crate) fn set_action_priority(
+        &mut self,
+        unique_qualifier: &ActionInfoHashKey,
+        priority: i32,
+    ) -> Result<(), Error> {
+        if self.requeue_with_priority(unique_qualifier, priority) {
+            self.inner.tasks_or_workers_change_notify.notify_one();
+            return Ok(());
+        }
+        if self.inner.active_actions.contains_key(unique_qualifier) {
+            return Err(make_err!(
+                Code::FailedPrecondition,
+                "Action {} is already running, its priority can not be changed",
+                unique_qualifier.action_name()
+            ));
+        }
+        Err(make_err!(
+            Code::NotFound,
+            "Action {} is not queued",
+            unique_qualifier.action_name()
+        ))
+    }
+
+    /// Removes and re-inserts the queued action `unique_qualifier` with
+    /// `priority`, so it is placed at the right spot in `queued_actions`.
+    /// Returns false if the action is not queued.
+    fn requeue_with_priority(
+        &mut self,
+        unique_qualifier: &ActionInfoHashKey,
+        priority: i32,
+    ) -> bool {
+        let Some(mut arc_action_info) = self.inner.queued_actions_set.take(unique_qualifier) else {
+            return false;
+        };
+        let Some((original_action_info, mut queued_action)) =
+            self.inner.queued_actions.remove_entry(&arc_action_info)
+        else {
+            event!(
+                Level::ERROR,
+                ?unique_qualifier,
+                "queued_actions_set should always have same keys as queued_actions"
+            );
+            return false;
+        };
+        drop(original_action_info);
+        StateManager::mutate_priority(&mut arc_action_info, priority);
+        queued_action.action_info = arc_action_info.clone();
+        self.inner
+            .queued_actions
+            .insert(arc_action_info.clone(), queued_action);
+        self.inner.queued_actions_set.insert(arc_action_info);
+        true
+    }
 }
 
 #[async_trait]
@@ -500,6 +856,16 @@ impl ClientStateManager for StateManager {
             return Ok(result);
         }
 
+        if let Some(max_queued_actions) = self.inner.max_queued_actions {
+            if self.inner.queued_actions.len() >= max_queued_actions {
+                self.inner.metrics.add_action_queue_full.inc();
+                return Err(make_err!(
+                    Code::ResourceExhausted,
+                    "Action queue is full ({max_queued_actions} queued actions), try again later"
+                ));
+            }
+        }
+
         self.inner.metrics.add_action_new_action_created.inc();
         // Action needs to be added to queue or is not cacheable.
         let action_info = Arc::new(action_info);
@@ -567,6 +933,25 @@ impl WorkerStateManager for StateManager {
         match action_stage {
             Ok(action_stage) => {
                 let action_info_hash_key = operation_id.unique_qualifier;
+                if let Some(current_stage) =
+                    self.current_stage_of_worker_action(&action_info_hash_key, worker_id)
+                {
+                    if stage_order(&action_stage) < stage_order(current_stage) {
+                        self.inner.metrics.update_action_stage_regression.inc();
+                        event!(
+                            Level::WARN,
+                            ?action_info_hash_key,
+                            ?worker_id,
+                            ?current_stage,
+                            ?action_stage,
+                            "Worker sent a stage that goes back from the current stage of the action"
+                        );
+                        return Err(make_err!(
+                            Code::FailedPrecondition,
+                            "Worker '{worker_id}' can not move action {action_info_hash_key:?} back from {current_stage:?} to {action_stage:?}",
+                        ));
+                    }
+                }
                 if !action_stage.has_action_result() {
                     self.inner.metrics.update_action_missing_action_result.inc();
                     event!(
@@ -621,7 +1006,7 @@ impl WorkerStateManager for StateManager {
                     return Err(err);
                 }
 
-                let send_result = StateManager::mutate_stage(&mut running_action, action_stage);
+                let send_result = self.mutate_stage(&mut running_action, action_stage);
 
                 if !running_action.current_state.stage.is_finished() {
                     if send_result.is_err() {
@@ -643,13 +1028,9 @@ impl WorkerStateManager for StateManager {
                     return Ok(());
                 }
 
+                self.record_if_failed(&running_action);
                 // Keep in case this is asked for soon.
-                self.inner
-                    .recently_completed_actions
-                    .insert(CompletedAction {
-                        completed_time: SystemTime::now(),
-                        state: running_action.current_state,
-                    });
+                self.insert_completed_action(running_action.current_state);
 
                 let worker = self
                     .inner
@@ -705,38 +1086,64 @@ impl MatchingEngineStateManager for StateManager {
         worker_id: Option<WorkerId>,
         action_stage: Result<ActionStage, Error>,
     ) -> Result<(), Error> {
-        if let Some(action_info) = self
+        let now_timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.match_operation(operation_id, worker_id, action_stage, now_timestamp)
+            .await
+    }
+
+    async fn remove_operation(&mut self, operation_id: OperationId) -> Result<(), Error> {
+        let mut awaited_action = if let Some(action_info) = self
             .inner
             .queued_actions_set
-            .get(&operation_id.unique_qualifier)
+            .take(&operation_id.unique_qualifier)
         {
-            if let Some(worker_id) = worker_id {
-                let action_info = action_info.clone();
-                self.worker_notify_run_action(worker_id, action_info.clone())
-                    .await?;
-                self.worker_set_as_active(action_info, worker_id, action_stage)
-                    .await?;
-            } else {
-                event!(
-                    Level::WARN,
-                    ?operation_id,
-                    ?worker_id,
-                    "No worker found in do_try_match()"
-                );
+            self.inner
+                .queued_actions
+                .remove(&action_info)
+                .err_tip(|| "queued_actions and queued_actions_set should match")?
+        } else if let Some((action_info, awaited_action)) = self
+            .inner
+            .active_actions
+            .remove_entry(&operation_id.unique_qualifier)
+        {
+            if let Some(worker_id) = awaited_action.worker_id {
+                if let Some(worker) = self.inner.workers.workers.peek_mut(&worker_id) {
+                    // We don't care if we fail to send message to worker, this is only a best attempt.
+                    let _ = worker.kill_action(&action_info);
+                    worker.complete_action(&action_info);
+                }
             }
+            awaited_action
         } else {
+            return Err(make_err!(
+                Code::NotFound,
+                "Operation {operation_id} is neither queued nor active"
+            ));
+        };
+
+        let send_result = self.mutate_stage(
+            &mut awaited_action,
+            ActionStage::Completed(ActionResult {
+                error: Some(make_err!(
+                    Code::Cancelled,
+                    "Operation {operation_id} was removed"
+                )),
+                ..ActionResult::default()
+            }),
+        );
+        if send_result.is_err() {
             event!(
                 Level::WARN,
                 ?operation_id,
-                ?worker_id,
-                "No action info found in do_try_match()"
+                "Action has no more listeners during remove_operation()"
             );
         }
-
+        // Dropping `awaited_action` closes the channel of all listeners.
+        drop(awaited_action);
+        self.inner.tasks_or_workers_change_notify.notify_one();
         Ok(())
     }
-
-    async fn remove_operation(&self, _operation_id: OperationId) -> Result<(), Error> {
-        todo!()
-    }
 }
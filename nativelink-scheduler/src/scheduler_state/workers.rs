@@ -13,9 +13,10 @@
 // limitations under the License.
 
 use lru::LruCache;
-use nativelink_config::schedulers::WorkerAllocationStrategy;
+use nativelink_config::schedulers::{WorkerAllocationStrategy, WorkerPoolIsolation};
 use nativelink_error::{error_if, make_input_err, Error, ResultExt};
 use nativelink_util::action_messages::WorkerId;
+use nativelink_util::common::DigestInfo;
 use nativelink_util::platform_properties::PlatformProperties;
 use tracing::{event, Level};
 
@@ -27,13 +28,64 @@ pub struct Workers {
     pub(crate) workers: LruCache<WorkerId, Worker>,
     /// The allocation strategy for workers.
     pub(crate) allocation_strategy: WorkerAllocationStrategy,
+    /// If set, restricts actions to workers in the pool of their instance.
+    worker_pool_isolation: Option<WorkerPoolIsolation>,
+    /// Number of recent input roots remembered for each worker. Zero
+    /// disables preferring workers that recently used an input root.
+    input_root_history_size: usize,
 }
 
 impl Workers {
-    pub(crate) fn new(allocation_strategy: WorkerAllocationStrategy) -> Self {
+    pub(crate) fn new(
+        allocation_strategy: WorkerAllocationStrategy,
+        worker_pool_isolation: Option<WorkerPoolIsolation>,
+        input_root_history_size: usize,
+    ) -> Self {
         Self {
             workers: LruCache::unbounded(),
             allocation_strategy,
+            worker_pool_isolation,
+            input_root_history_size,
+        }
+    }
+
+    /// Remembers that an action with `input_root_digest` was sent to the
+    /// worker, so later actions with the same input root can prefer it.
+    pub(crate) fn record_input_root(
+        &mut self,
+        worker_id: &WorkerId,
+        input_root_digest: DigestInfo,
+    ) {
+        if self.input_root_history_size == 0 {
+            return;
+        }
+        let Some(worker) = self.workers.peek_mut(worker_id) else {
+            return;
+        };
+        let recent_input_roots = &mut worker.recent_input_roots;
+        recent_input_roots.retain(|digest| *digest != input_root_digest);
+        if recent_input_roots.len() >= self.input_root_history_size {
+            recent_input_roots.pop_front();
+        }
+        recent_input_roots.push_back(input_root_digest);
+    }
+
+    /// Returns true if the worker is in the same pool as the one assigned to
+    /// `instance_name`. Always true if pool isolation is not configured.
+    fn is_in_pool_for_instance(&self, worker: &Worker, instance_name: &str) -> bool {
+        let Some(isolation) = &self.worker_pool_isolation else {
+            return true;
+        };
+        let action_pool = isolation.instance_pools.get(instance_name);
+        let worker_pool = worker
+            .platform_properties
+            .properties
+            .get(&isolation.worker_property)
+            .map(|value| value.as_str());
+        match (action_pool, worker_pool) {
+            (Some(action_pool), Some(worker_pool)) => action_pool.as_str() == worker_pool,
+            (None, None) => true,
+            _ => false,
         }
     }
 
@@ -94,20 +146,51 @@ impl Workers {
     // TODO(blaise.bruer) This algorithm is not very efficient. Simple testing using a tree-like
     // structure showed worse performance on a 10_000 worker * 7 properties * 1000 queued tasks
     // simulation of worst cases in a single threaded environment.
+    // If `input_root_history_size` is set, workers that recently ran an action
+    // with the same input root are preferred.
     pub(crate) fn find_worker_for_action(
         &self,
+        instance_name: &str,
         platform_properties: &PlatformProperties,
+        input_root_digest: &DigestInfo,
+    ) -> Option<WorkerId> {
+        let is_candidate = |w: &Worker| {
+            w.can_accept_work()
+                && platform_properties.is_satisfied_by(&w.platform_properties)
+                && self.is_in_pool_for_instance(w, instance_name)
+        };
+        if self.input_root_history_size > 0 {
+            let has_input_root =
+                |w: &Worker| is_candidate(w) && w.recent_input_roots.contains(input_root_digest);
+            if let Some(worker_id) = self.find_worker_with_strategy(has_input_root) {
+                return Some(worker_id);
+            }
+        }
+        self.find_worker_with_strategy(is_candidate)
+    }
+
+    /// Picks one of the workers `is_candidate` accepts based on the
+    /// `allocation_strategy`.
+    fn find_worker_with_strategy(
+        &self,
+        is_candidate: impl Fn(&Worker) -> bool,
     ) -> Option<WorkerId> {
         let mut workers_iter = self.workers.iter();
         let workers_iter = match self.allocation_strategy {
             // Use rfind to get the least recently used that satisfies the properties.
-            WorkerAllocationStrategy::least_recently_used => workers_iter.rfind(|(_, w)| {
-                w.can_accept_work() && platform_properties.is_satisfied_by(&w.platform_properties)
-            }),
+            WorkerAllocationStrategy::least_recently_used => {
+                workers_iter.rfind(|(_, w)| is_candidate(w))
+            }
             // Use find to get the most recently used that satisfies the properties.
-            WorkerAllocationStrategy::most_recently_used => workers_iter.find(|(_, w)| {
-                w.can_accept_work() && platform_properties.is_satisfied_by(&w.platform_properties)
-            }),
+            WorkerAllocationStrategy::most_recently_used => {
+                workers_iter.find(|(_, w)| is_candidate(w))
+            }
+            // Use min_by_key on the reversed iterator so ties are broken by
+            // the least recently used worker.
+            WorkerAllocationStrategy::least_loaded => workers_iter
+                .rev()
+                .filter(|(_, w)| is_candidate(w))
+                .min_by_key(|(_, w)| w.running_action_infos.len()),
         };
         workers_iter.map(|(_, w)| &w.id).copied()
     }
@@ -12,28 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::BTreeMap;
-use std::pin::Pin;
+use std::collections::{BTreeMap, VecDeque};
+use std::pin::{pin, Pin};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::{Instant, SystemTime};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use async_lock::{Mutex, MutexGuard};
 use async_trait::async_trait;
 use futures::{Future, Stream};
 use hashbrown::{HashMap, HashSet};
+use nativelink_config::schedulers::{ActionAssignmentPolicy, ActionTimeoutMultiplier};
 use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_proto::build::bazel::remote::execution::v2::ActionResult as ProtoActionResult;
 use nativelink_util::action_messages::{
     ActionInfo, ActionInfoHashKey, ActionResult, ActionStage, ActionState, ExecutionMetadata,
     OperationId, WorkerId,
 };
 use nativelink_util::metrics_utils::{
-    AsyncCounterWrapper, Collector, CollectorState, CounterWithTime, FuncCounterWrapper,
-    MetricsComponent, Registry,
+    AsyncCounterWrapper, Collector, CollectorState, CounterWithTime, DurationHistogram,
+    FuncCounterWrapper, MetricsComponent, Registry,
 };
 use nativelink_util::platform_properties::PlatformPropertyValue;
 use nativelink_util::spawn;
+use nativelink_util::store_trait::{Store, StoreLike};
 use nativelink_util::task::JoinHandleDropGuard;
+use prost::Message;
+use serde::Serialize;
 use tokio::sync::{watch, Notify};
 use tokio::time::Duration;
 use tokio_stream::StreamExt;
@@ -41,15 +46,19 @@ use tracing::{event, Level};
 
 use crate::action_scheduler::ActionScheduler;
 use crate::operation_state_manager::{
-    ActionStateResult, ClientStateManager, MatchingEngineStateManager, OperationFilter,
-    OperationStageFlags, WorkerStateManager,
+    ActionEventListener, ActionStateResult, AdmissionController, AdmitAllController,
+    ClientStateManager, MatchingEngineStateManager, OperationFilter, OperationStageFlags,
+    WorkerStateManager,
 };
 use crate::platform_property_manager::PlatformPropertyManager;
+use crate::scheduler_state::awaited_action::AwaitedAction;
+use crate::scheduler_state::checkpoint::SchedulerCheckpoint;
+use crate::scheduler_state::completed_action::{completed_action_store_key, CompletedAction};
 use crate::scheduler_state::metrics::Metrics as SchedulerMetrics;
 use crate::scheduler_state::state_manager::StateManager;
 use crate::scheduler_state::workers::Workers;
 use crate::worker::{Worker, WorkerTimestamp, WorkerUpdate};
-use crate::worker_scheduler::WorkerScheduler;
+use crate::worker_scheduler::{FailedActionResult, WorkerScheduler};
 
 /// Default timeout for workers in seconds.
 /// If this changes, remember to change the documentation in the config.
@@ -63,6 +72,76 @@ const DEFAULT_RETAIN_COMPLETED_FOR_S: u64 = 60;
 /// If this changes, remember to change the documentation in the config.
 const DEFAULT_MAX_JOB_RETRIES: usize = 3;
 
+/// Default time the matching engine waits between runs in milliseconds.
+/// If this changes, remember to change the documentation in the config.
+const DEFAULT_MATCH_COOLDOWN_MS: u64 = 1;
+
+/// Returns the current time of the scheduler. Tests replace it to control
+/// the passing of time.
+pub type NowFn = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// A point-in-time copy of the scheduler state, used for debugging and
+/// postmortems. Only metadata is captured, never any blob contents.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerSnapshot {
+    /// Queued actions in the order they will be scheduled.
+    pub queued_actions: Vec<QueuedActionSnapshot>,
+    /// Actions that have been assigned to a worker, sorted by action name.
+    pub active_actions: Vec<ActiveActionSnapshot>,
+    /// All connected workers, sorted by worker id.
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueuedActionSnapshot {
+    /// Unique name of the action (instance, digest function, digest and salt).
+    pub action_name: String,
+    pub priority: i32,
+    /// Number of seconds since the action was inserted into the queue.
+    pub age_s: u64,
+    /// Number of times the action has been attempted on a worker.
+    pub attempts: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActiveActionSnapshot {
+    /// Unique name of the action (instance, digest function, digest and salt).
+    pub action_name: String,
+    pub priority: i32,
+    /// Number of seconds since the action was inserted into the queue.
+    pub age_s: u64,
+    /// Number of times the action has been attempted on a worker.
+    pub attempts: usize,
+    /// The worker the action is currently assigned to.
+    pub worker_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WorkerSnapshot {
+    pub worker_id: String,
+    pub platform_properties: BTreeMap<String, String>,
+    /// Names of the actions running on the worker, sorted.
+    pub running_actions: Vec<String>,
+    pub last_update_timestamp: WorkerTimestamp,
+    pub is_paused: bool,
+    pub is_draining: bool,
+}
+
+/// Actions the scheduler still had when `quiesce()` returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemainingWork {
+    /// Number of actions that are waiting for a worker.
+    pub queued_actions: usize,
+    /// Number of actions that are running on a worker.
+    pub active_actions: usize,
+}
+
+impl RemainingWork {
+    pub fn is_empty(&self) -> bool {
+        self.queued_actions == 0 && self.active_actions == 0
+    }
+}
+
 struct SimpleSchedulerImpl {
     /// The manager responsible for holding the state of actions and workers.
     state_manager: StateManager,
@@ -70,12 +149,92 @@ struct SimpleSchedulerImpl {
     retain_completed_for: Duration,
     /// Timeout of how long to evict workers if no response in this given amount of time in seconds.
     worker_timeout_s: u64,
+    /// How long newly connected workers are not given work for in seconds.
+    worker_warmup_s: u64,
+    /// Maximum number of workers in the pool. Zero means unlimited.
+    max_workers: usize,
     /// Default times a job can retry before failing.
     max_job_retries: usize,
+    /// Multipliers applied to action timeouts based on worker platform properties.
+    /// If empty, action timeouts are not enforced by the scheduler.
+    action_timeout_multipliers: Vec<ActionTimeoutMultiplier>,
+    /// Maximum seconds an action may wait in the queue before it is first
+    /// started. Zero means unlimited.
+    max_queue_wait_s: u64,
+    /// Maximum seconds an action may execute on a worker. Zero means unlimited.
+    max_execution_time_s: u64,
+    /// Worker platform properties used to group worker metrics. If empty,
+    /// worker metrics are published per worker.
+    worker_metrics_tags: Vec<String>,
+    /// If set, execution metadata timestamps further than this in the future
+    /// are clamped and out of order phase timestamps are corrected.
+    execution_metadata_max_clock_skew: Option<Duration>,
+    /// The order in which queued actions are offered to workers.
+    action_assignment_policy: ActionAssignmentPolicy,
+    /// Instance name of the last action assigned to a worker. Used to pick
+    /// which instance goes first when taking turns between instances.
+    last_assigned_instance: Option<String>,
+    /// Maximum number of active actions of each listed instance.
+    max_concurrent_actions_per_instance: HashMap<String, usize>,
+    /// If set, new actions are rejected because the scheduler is shutting
+    /// down. See `SimpleScheduler::quiesce()`.
+    is_quiescing: bool,
+    /// If set, workers that join the pool start out draining. See
+    /// `SimpleScheduler::drain_all_workers()`.
+    is_draining_all_workers: bool,
     metrics: Arc<Metrics>,
 }
 
+/// Reorders `queued_actions` so that instance names take turns, keeping the
+/// existing order of the actions of each instance. The first turn goes to the
+/// instance following `last_instance` in name order, so no instance is
+/// always first.
+fn interleave_by_instance(
+    queued_actions: Vec<(OperationId, Arc<ActionInfo>)>,
+    last_instance: Option<&str>,
+) -> Vec<(OperationId, Arc<ActionInfo>)> {
+    let total = queued_actions.len();
+    let mut by_instance: BTreeMap<String, VecDeque<(OperationId, Arc<ActionInfo>)>> =
+        BTreeMap::new();
+    for queued_action in queued_actions {
+        by_instance
+            .entry(queued_action.1.instance_name().clone())
+            .or_default()
+            .push_back(queued_action);
+    }
+    let mut instance_queues: Vec<_> = by_instance.into_iter().collect();
+    if let Some(last_instance) = last_instance {
+        let first = instance_queues
+            .iter()
+            .position(|(instance_name, _)| instance_name.as_str() > last_instance)
+            .unwrap_or(0);
+        instance_queues.rotate_left(first);
+    }
+    let mut interleaved = Vec::with_capacity(total);
+    while interleaved.len() < total {
+        for (_, queue) in &mut instance_queues {
+            if let Some(queued_action) = queue.pop_front() {
+                interleaved.push(queued_action);
+            }
+        }
+    }
+    interleaved
+}
+
 impl SimpleSchedulerImpl {
+    /// Returns the current time of the scheduler clock.
+    fn now(&self) -> SystemTime {
+        (self.state_manager.inner.now_fn)()
+    }
+
+    /// Returns the current time of the scheduler clock on the worker clock.
+    fn now_timestamp(&self) -> WorkerTimestamp {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
     /// Attempts to find a worker to execute an action and begins executing it.
     /// If an action is already running that is cacheable it may merge this action
     /// with the results and state changes of the already running action.
@@ -86,28 +245,54 @@ impl SimpleSchedulerImpl {
         &mut self,
         action_info: ActionInfo,
     ) -> Result<watch::Receiver<Arc<ActionState>>, Error> {
+        if self.is_quiescing {
+            return Err(make_err!(
+                Code::Unavailable,
+                "Scheduler is shutting down and no longer accepts new actions"
+            ));
+        }
         let add_action_result = self.state_manager.add_action(action_info).await?;
         add_action_result.as_receiver().await.cloned()
     }
 
+    fn remaining_work(&self) -> RemainingWork {
+        RemainingWork {
+            queued_actions: self.state_manager.inner.queued_actions.len(),
+            active_actions: self.state_manager.inner.active_actions.len(),
+        }
+    }
+
     fn clean_recently_completed_actions(&mut self) {
-        let expiry_time = SystemTime::now()
-            .checked_sub(self.retain_completed_for)
-            .unwrap();
+        let now = self.now();
+        let retain_completed_for = self.retain_completed_for;
+        let mut expired_actions = Vec::new();
         self.state_manager
             .inner
             .recently_completed_actions
-            .retain(|action| action.completed_time > expiry_time);
+            .retain(|action| {
+                let is_retained = action.is_retained(now, retain_completed_for);
+                if !is_retained {
+                    expired_actions.push(action.state.id.unique_qualifier.clone());
+                }
+                is_retained
+            });
+        self.state_manager
+            .remove_stored_completed_actions(expired_actions);
     }
 
+    /// Finds a completed action that is still within the retention window.
+    /// Entries older than `retain_completed_for` are treated as absent even if
+    /// `clean_recently_completed_actions` has not removed them yet.
     fn find_recently_completed_action(
         &self,
         unique_qualifier: &ActionInfoHashKey,
     ) -> Option<watch::Receiver<Arc<ActionState>>> {
+        let now = self.now();
         self.state_manager
             .inner
             .recently_completed_actions
             .get(unique_qualifier)
+            .filter(|action| action.is_retained(now, self.retain_completed_for))
             .map(|action| watch::channel(action.state.clone()).1)
     }
 
@@ -146,7 +331,7 @@ impl SimpleSchedulerImpl {
                 let send_result = if awaited_action.attempts >= self.max_job_retries {
                     self.metrics.retry_action_max_attempts_reached.inc();
 
-                    StateManager::mutate_stage(&mut awaited_action, ActionStage::Completed(ActionResult {
+                    let send_result = self.state_manager.mutate_stage(&mut awaited_action, ActionStage::Completed(ActionResult {
                         execution_metadata: ExecutionMetadata {
                             worker: format!("{worker_id}"),
                             ..ExecutionMetadata::default()
@@ -156,13 +341,16 @@ impl SimpleSchedulerImpl {
                             "Job cancelled because it attempted to execute too many times and failed"
                         ))),
                         ..ActionResult::default()
-                    }))
+                    }));
+                    self.state_manager.record_if_failed(&awaited_action);
+                    send_result
                     // Do not put the action back in the queue here, as this action attempted to run too many
                     // times.
                 } else {
                     self.metrics.retry_action.inc();
-                    let send_result =
-                        StateManager::mutate_stage(&mut awaited_action, ActionStage::Queued);
+                    let send_result = self
+                        .state_manager
+                        .mutate_stage(&mut awaited_action, ActionStage::Queued);
                     self.state_manager
                         .inner
                         .queued_actions_set
@@ -219,6 +407,256 @@ impl SimpleSchedulerImpl {
             .notify_one();
     }
 
+    /// Returns the multiplier for timeouts of actions running on `worker`.
+    fn action_timeout_multiplier(&self, worker: &Worker) -> f32 {
+        self.action_timeout_multipliers
+            .iter()
+            .find(|entry| {
+                worker
+                    .platform_properties
+                    .properties
+                    .get(&entry.property)
+                    .is_some_and(|value| value.as_str() == entry.value)
+            })
+            .map_or(1.0, |entry| entry.multiplier)
+    }
+
+    /// Kills and reschedules any running actions that have exceeded their
+    /// timeout scaled by the multiplier of the worker they are running on.
+    fn reschedule_timedout_actions(&mut self, now_timestamp: WorkerTimestamp) {
+        if self.action_timeout_multipliers.is_empty() {
+            return;
+        }
+        let mut timedout_actions = Vec::new();
+        for (worker_id, worker) in self.state_manager.inner.workers.workers.iter() {
+            let multiplier = f64::from(self.action_timeout_multiplier(worker));
+            for (action_info, start_timestamp) in &worker.running_action_start_timestamps {
+                if action_info.timeout.is_zero() {
+                    continue;
+                }
+                let timeout_s = (action_info.timeout.as_secs_f64() * multiplier).ceil() as u64;
+                if now_timestamp > start_timestamp.saturating_add(timeout_s) {
+                    timedout_actions.push((*worker_id, action_info.clone(), timeout_s));
+                }
+            }
+        }
+        if timedout_actions.is_empty() {
+            return;
+        }
+        for (worker_id, action_info, timeout_s) in timedout_actions {
+            event!(
+                Level::WARN,
+                ?worker_id,
+                ?action_info,
+                timeout_s,
+                "Action exceeded its deadline, rescheduling"
+            );
+            if let Some(worker) = self
+                .state_manager
+                .inner
+                .workers
+                .workers
+                .peek_mut(&worker_id)
+            {
+                // We don't care if we fail to send message to worker, this is only a best attempt.
+                let _ = worker.kill_action(&action_info);
+                worker.complete_action(&action_info);
+            }
+            self.retry_action(
+                &action_info,
+                &worker_id,
+                make_err!(
+                    Code::DeadlineExceeded,
+                    "Action exceeded its deadline of {timeout_s}s on worker {worker_id}"
+                ),
+            );
+        }
+        self.state_manager
+            .inner
+            .tasks_or_workers_change_notify
+            .notify_one();
+    }
+
+    /// Completes `awaited_action` with `err` and keeps it around in case its
+    /// result is asked for soon.
+    fn fail_action(
+        &mut self,
+        mut awaited_action: AwaitedAction,
+        worker_id: Option<WorkerId>,
+        err: Error,
+    ) {
+        let send_result = self.state_manager.mutate_stage(
+            &mut awaited_action,
+            ActionStage::Completed(ActionResult {
+                execution_metadata: ExecutionMetadata {
+                    worker: worker_id
+                        .map(|worker_id| format!("{worker_id}"))
+                        .unwrap_or_default(),
+                    ..ExecutionMetadata::default()
+                },
+                error: Some(err),
+                ..ActionResult::default()
+            }),
+        );
+        if send_result.is_err() {
+            event!(
+                Level::WARN,
+                action_info = ?awaited_action.action_info,
+                "Action has no more listeners during fail_action()"
+            );
+        }
+        self.state_manager.record_if_failed(&awaited_action);
+        self.state_manager
+            .insert_completed_action(awaited_action.current_state);
+    }
+
+    /// Fails queued actions that were never started and have waited longer
+    /// than `max_queue_wait_s` since they were inserted. The insert time is
+    /// a wall clock time, so the wait is measured on the scheduler clock.
+    fn fail_actions_exceeding_queue_wait(&mut self) {
+        if self.max_queue_wait_s == 0 {
+            return;
+        }
+        let max_queue_wait_s = self.max_queue_wait_s;
+        let max_queue_wait = Duration::from_secs(max_queue_wait_s);
+        let now = self.now();
+        let expired_actions: Vec<Arc<ActionInfo>> = self
+            .state_manager
+            .inner
+            .queued_actions
+            .iter()
+            .filter(|(action_info, awaited_action)| {
+                awaited_action.attempts == 0
+                    && now
+                        .duration_since(action_info.insert_timestamp)
+                        .unwrap_or_default()
+                        > max_queue_wait
+            })
+            .map(|(action_info, _)| action_info.clone())
+            .collect();
+        for action_info in expired_actions {
+            self.state_manager
+                .inner
+                .queued_actions_set
+                .remove(&action_info);
+            let Some(awaited_action) = self.state_manager.inner.queued_actions.remove(&action_info)
+            else {
+                continue;
+            };
+            event!(
+                Level::WARN,
+                ?action_info,
+                max_queue_wait_s,
+                "Action exceeded its max queue wait, failing"
+            );
+            self.fail_action(
+                awaited_action,
+                None,
+                make_err!(
+                    Code::DeadlineExceeded,
+                    "Action was not started within the max queue wait of {max_queue_wait_s}s"
+                ),
+            );
+            self.state_manager
+                .inner
+                .tasks_or_workers_change_notify
+                .notify_one();
+        }
+    }
+
+    /// Kills and fails running actions that have executed for longer than
+    /// `max_execution_time_s`. Unlike other failures they are not retried.
+    fn fail_actions_exceeding_execution_time(&mut self, now_timestamp: WorkerTimestamp) {
+        if self.max_execution_time_s == 0 {
+            return;
+        }
+        let max_execution_time_s = self.max_execution_time_s;
+        let mut expired_actions = Vec::new();
+        for (worker_id, worker) in self.state_manager.inner.workers.workers.iter() {
+            for (action_info, start_timestamp) in &worker.running_action_start_timestamps {
+                if now_timestamp > start_timestamp.saturating_add(max_execution_time_s) {
+                    expired_actions.push((*worker_id, action_info.clone()));
+                }
+            }
+        }
+        if expired_actions.is_empty() {
+            return;
+        }
+        for (worker_id, action_info) in expired_actions {
+            event!(
+                Level::WARN,
+                ?worker_id,
+                ?action_info,
+                max_execution_time_s,
+                "Action exceeded its max execution time, failing"
+            );
+            if let Some(worker) = self
+                .state_manager
+                .inner
+                .workers
+                .workers
+                .peek_mut(&worker_id)
+            {
+                // We don't care if we fail to send message to worker, this is only a best attempt.
+                let _ = worker.kill_action(&action_info);
+                worker.complete_action(&action_info);
+            }
+            let Some(awaited_action) = self.state_manager.inner.active_actions.remove(&action_info)
+            else {
+                continue;
+            };
+            self.fail_action(
+                awaited_action,
+                Some(worker_id),
+                make_err!(
+                    Code::DeadlineExceeded,
+                    "Action exceeded the max execution time of {max_execution_time_s}s on worker {worker_id}"
+                ),
+            );
+        }
+        self.state_manager
+            .inner
+            .tasks_or_workers_change_notify
+            .notify_one();
+    }
+
+    /// Ends the warmup of workers whose warmup period has elapsed.
+    fn end_elapsed_worker_warmups(&mut self, now_timestamp: WorkerTimestamp) {
+        let mut any_ready = false;
+        for (_, worker) in self.state_manager.inner.workers.workers.iter_mut() {
+            if worker
+                .warming_up_until
+                .is_some_and(|warming_up_until| warming_up_until <= now_timestamp)
+            {
+                worker.warming_up_until = None;
+                any_ready = true;
+            }
+        }
+        if any_ready {
+            self.state_manager
+                .inner
+                .tasks_or_workers_change_notify
+                .notify_one();
+        }
+    }
+
+    /// Ends the warmup of the worker, so it starts being given work.
+    fn set_worker_ready(&mut self, worker_id: WorkerId) -> Result<(), Error> {
+        let worker = self
+            .state_manager
+            .inner
+            .workers
+            .workers
+            .get_mut(&worker_id)
+            .err_tip(|| format!("Worker {worker_id} doesn't exist in the pool"))?;
+        worker.warming_up_until = None;
+        self.state_manager
+            .inner
+            .tasks_or_workers_change_notify
+            .notify_one();
+        Ok(())
+    }
+
     /// Sets if the worker is draining or not.
     fn set_drain_worker(&mut self, worker_id: WorkerId, is_draining: bool) -> Result<(), Error> {
         let worker = self
@@ -237,6 +675,21 @@ impl SimpleSchedulerImpl {
         Ok(())
     }
 
+    /// Sets every worker in the pool, and every worker that joins it later,
+    /// draining. Returns the number of workers that were not already draining.
+    fn drain_all_workers(&mut self) -> usize {
+        self.is_draining_all_workers = true;
+        let mut newly_draining = 0;
+        for (_, worker) in self.state_manager.inner.workers.workers.iter_mut() {
+            if !worker.is_draining {
+                worker.is_draining = true;
+                self.metrics.workers_drained.inc();
+                newly_draining += 1;
+            }
+        }
+        newly_draining
+    }
+
     async fn get_queued_operations(
         &self,
     ) -> Result<Pin<Box<dyn Stream<Item = Arc<dyn ActionStateResult + 'static>> + Send>>, Error>
@@ -270,60 +723,113 @@ impl SimpleSchedulerImpl {
 
         let action_state_results = self.get_queued_operations().await;
 
-        match action_state_results {
-            Ok(mut stream) => {
-                while let Some(action_state_result) = stream.next().await {
-                    let as_state_result = action_state_result.as_state().await;
-                    let Ok(state) = as_state_result else {
-                        let _ = as_state_result.inspect_err(|err| {
-                            event!(
-                                Level::ERROR,
-                                ?err,
-                                "Failed to get action_info from as_state_result stream"
-                            );
-                        });
-                        continue;
-                    };
-                    let action_state_result = action_state_result.as_action_info().await;
-                    let Ok(action_info) = action_state_result else {
-                        let _ = action_state_result.inspect_err(|err| {
-                            event!(
-                                Level::ERROR,
-                                ?err,
-                                "Failed to get action_info from action_state_results stream"
-                            );
-                        });
-                        continue;
-                    };
-
-                    let maybe_worker_id: Option<WorkerId> = {
-                        self.state_manager
-                            .inner
-                            .workers
-                            .find_worker_for_action(&action_info.platform_properties)
-                    };
-
-                    let operation_id = state.id.clone();
-                    let ret = <StateManager as MatchingEngineStateManager>::update_operation(
-                        &mut self.state_manager,
-                        operation_id.clone(),
-                        maybe_worker_id,
-                        Ok(ActionStage::Executing),
-                    )
-                    .await;
-
-                    if let Err(e) = ret {
-                        event!(
-                            Level::ERROR,
-                            ?e,
-                            "update operation failed for {}",
-                            operation_id
-                        );
-                    }
-                }
-            }
+        let mut stream = match action_state_results {
+            Ok(stream) => stream,
             Err(e) => {
                 event!(Level::ERROR, ?e, "stream error in do_try_match");
+                return;
+            }
+        };
+        let mut queued_actions = Vec::new();
+        while let Some(action_state_result) = stream.next().await {
+            let as_state_result = action_state_result.as_state().await;
+            let Ok(state) = as_state_result else {
+                let _ = as_state_result.inspect_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        ?err,
+                        "Failed to get action_info from as_state_result stream"
+                    );
+                });
+                continue;
+            };
+            let action_state_result = action_state_result.as_action_info().await;
+            let Ok(action_info) = action_state_result else {
+                let _ = action_state_result.inspect_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        ?err,
+                        "Failed to get action_info from action_state_results stream"
+                    );
+                });
+                continue;
+            };
+            queued_actions.push((state.id.clone(), action_info));
+        }
+
+        if self.action_assignment_policy == ActionAssignmentPolicy::instance_round_robin {
+            queued_actions =
+                interleave_by_instance(queued_actions, self.last_assigned_instance.as_deref());
+        }
+
+        // Number of active actions of each instance that has a concurrency limit.
+        let mut active_actions_per_instance: HashMap<String, usize> = HashMap::new();
+        if !self.max_concurrent_actions_per_instance.is_empty() {
+            for action_info in self.state_manager.inner.active_actions.keys() {
+                let instance_name = action_info.instance_name();
+                if self
+                    .max_concurrent_actions_per_instance
+                    .contains_key(instance_name)
+                {
+                    *active_actions_per_instance
+                        .entry(instance_name.clone())
+                        .or_default() += 1;
+                }
+            }
+        }
+
+        let now_timestamp = self.now_timestamp();
+        for (operation_id, action_info) in queued_actions {
+            let instance_name = action_info.instance_name();
+            if let Some(max_concurrent_actions) =
+                self.max_concurrent_actions_per_instance.get(instance_name)
+            {
+                let active_actions = active_actions_per_instance
+                    .get(instance_name)
+                    .copied()
+                    .unwrap_or(0);
+                if active_actions >= *max_concurrent_actions {
+                    // Leave the action queued until one of the instance's
+                    // actions completes.
+                    continue;
+                }
+            }
+            let maybe_worker_id: Option<WorkerId> = {
+                self.state_manager.inner.workers.find_worker_for_action(
+                    action_info.instance_name(),
+                    &action_info.platform_properties,
+                    &action_info.input_root_digest,
+                )
+            };
+            if maybe_worker_id.is_some() {
+                self.last_assigned_instance = Some(action_info.instance_name().clone());
+                if self
+                    .max_concurrent_actions_per_instance
+                    .contains_key(action_info.instance_name())
+                {
+                    *active_actions_per_instance
+                        .entry(action_info.instance_name().clone())
+                        .or_default() += 1;
+                }
+            }
+
+            let ret = self
+                .state_manager
+                .match_operation(
+                    operation_id.clone(),
+                    maybe_worker_id,
+                    Ok(ActionStage::Executing),
+                    now_timestamp,
+                )
+                .await;
+
+            if let Err(e) = ret {
+                event!(
+                    Level::ERROR,
+                    ?e,
+                    "update operation failed for {}",
+                    operation_id
+                );
             }
         }
     }
@@ -332,8 +838,25 @@ impl SimpleSchedulerImpl {
         &mut self,
         worker_id: &WorkerId,
         action_info_hash_key: ActionInfoHashKey,
-        action_stage: Result<ActionStage, Error>,
+        mut action_stage: Result<ActionStage, Error>,
     ) -> Result<(), Error> {
+        if let (Some(max_clock_skew), Ok(ActionStage::Completed(action_result))) =
+            (self.execution_metadata_max_clock_skew, &mut action_stage)
+        {
+            let corrected = action_result
+                .execution_metadata
+                .normalize_timestamps(SystemTime::now(), max_clock_skew);
+            if !corrected.is_empty() {
+                self.metrics.execution_metadata_timestamps_corrected.inc();
+                event!(
+                    Level::WARN,
+                    ?action_info_hash_key,
+                    ?worker_id,
+                    ?corrected,
+                    "Corrected invalid execution metadata timestamps reported by worker"
+                );
+            }
+        }
         let update_operation_result = <StateManager as WorkerStateManager>::update_operation(
             &mut self.state_manager,
             OperationId::new(action_info_hash_key.clone()),
@@ -360,7 +883,27 @@ impl SimpleSchedulerImpl {
 pub struct SimpleScheduler {
     inner: Arc<Mutex<SimpleSchedulerImpl>>,
     platform_property_manager: Arc<PlatformPropertyManager>,
+    /// Reject actions requesting platform properties that are not in the
+    /// supported set instead of queueing them.
+    reject_unsupported_platform_properties: bool,
+    /// Decides whether new actions are allowed to be queued. Consulted
+    /// before the scheduler lock is taken, so a slow policy does not stall
+    /// the scheduler.
+    admission_controller: Arc<dyn AdmissionController>,
     metrics: Arc<Metrics>,
+    /// If set, results reported for actions the scheduler is not tracking
+    /// are written to this action cache.
+    orphaned_result_ac_store: Option<Store>,
+    /// If set, completed actions that are not in memory are looked up in
+    /// this store. Shared with the state manager, which writes completed
+    /// actions into it.
+    completed_actions_store: Arc<OnceLock<Store>>,
+    /// Listener told about every stage change of an action. Shared with the
+    /// state manager, so it can be set without taking the scheduler lock.
+    action_event_listener: Arc<OnceLock<Arc<dyn ActionEventListener>>>,
+    /// Notified after every run of the matching engine. The matching engine
+    /// runs after every change to the queued and active actions.
+    matching_engine_run_notify: Arc<Notify>,
     // Triggers `drop()`` call if scheduler is dropped.
     _task_worker_matching_future: JoinHandleDropGuard<()>,
 }
@@ -369,16 +912,7 @@ impl SimpleScheduler {
     #[inline]
     #[must_use]
     pub fn new(scheduler_cfg: &nativelink_config::schedulers::SimpleScheduler) -> Self {
-        Self::new_with_callback(scheduler_cfg, || {
-            // The cost of running `do_try_match()` is very high, but constant
-            // in relation to the number of changes that have happened. This means
-            // that grabbing this lock to process `do_try_match()` should always
-            // yield to any other tasks that might want the lock. The easiest and
-            // most fair way to do this is to sleep for a small amount of time.
-            // Using something like tokio::task::yield_now() does not yield as
-            // aggresively as we'd like if new futures are scheduled within a future.
-            tokio::time::sleep(Duration::from_millis(1))
-        })
+        Self::new_with_callback(scheduler_cfg, || async move {})
     }
 
     pub fn new_with_callback<
@@ -387,6 +921,23 @@ impl SimpleScheduler {
     >(
         scheduler_cfg: &nativelink_config::schedulers::SimpleScheduler,
         on_matching_engine_run: F,
+    ) -> Self {
+        Self::new_with_callback_and_now_fn(
+            scheduler_cfg,
+            on_matching_engine_run,
+            Arc::new(SystemTime::now),
+        )
+    }
+
+    /// Same as `new_with_callback()`, but actions and workers are timed with
+    /// `now_fn` instead of the system clock.
+    pub fn new_with_callback_and_now_fn<
+        Fut: Future<Output = ()> + Send,
+        F: Fn() -> Fut + Send + Sync + 'static,
+    >(
+        scheduler_cfg: &nativelink_config::schedulers::SimpleScheduler,
+        on_matching_engine_run: F,
+        now_fn: NowFn,
     ) -> Self {
         let platform_property_manager = Arc::new(PlatformPropertyManager::new(
             scheduler_cfg
@@ -410,30 +961,70 @@ impl SimpleScheduler {
             max_job_retries = DEFAULT_MAX_JOB_RETRIES;
         }
 
+        let match_cooldown = Duration::from_millis(
+            scheduler_cfg
+                .match_cooldown_ms
+                .unwrap_or(DEFAULT_MATCH_COOLDOWN_MS),
+        );
+
         let tasks_or_workers_change_notify = Arc::new(Notify::new());
+        let completed_actions_store = Arc::new(OnceLock::new());
+        let action_event_listener = Arc::new(OnceLock::new());
         let state_manager = StateManager::new(
             HashSet::new(),
             BTreeMap::new(),
-            Workers::new(scheduler_cfg.allocation_strategy),
+            Workers::new(
+                scheduler_cfg.allocation_strategy,
+                scheduler_cfg.worker_pool_isolation.clone(),
+                scheduler_cfg.worker_input_root_history_size,
+            ),
             HashMap::new(),
             HashSet::new(),
             Arc::new(SchedulerMetrics::default()),
             max_job_retries,
+            scheduler_cfg.max_failed_action_results,
+            scheduler_cfg.max_queued_actions,
+            completed_actions_store.clone(),
+            action_event_listener.clone(),
             tasks_or_workers_change_notify.clone(),
+            now_fn,
         );
         let metrics = Arc::new(Metrics::default());
         let metrics_for_do_try_match = metrics.clone();
+        let matching_engine_run_notify = Arc::new(Notify::new());
+        let matching_engine_run_notify_for_do_try_match = matching_engine_run_notify.clone();
         let inner = Arc::new(Mutex::new(SimpleSchedulerImpl {
             state_manager,
             retain_completed_for: Duration::new(retain_completed_for_s, 0),
             worker_timeout_s,
+            worker_warmup_s: scheduler_cfg.worker_warmup_s,
+            max_workers: scheduler_cfg.max_workers,
             max_job_retries,
+            action_timeout_multipliers: scheduler_cfg.action_timeout_multipliers.clone(),
+            max_queue_wait_s: scheduler_cfg.max_queue_wait_s,
+            max_execution_time_s: scheduler_cfg.max_execution_time_s,
+            worker_metrics_tags: scheduler_cfg.worker_metrics_tags.clone(),
+            execution_metadata_max_clock_skew: (scheduler_cfg.execution_metadata_max_clock_skew_s
+                != 0)
+                .then(|| Duration::from_secs(scheduler_cfg.execution_metadata_max_clock_skew_s)),
+            action_assignment_policy: scheduler_cfg.action_assignment_policy,
+            last_assigned_instance: None,
+            max_concurrent_actions_per_instance: scheduler_cfg
+                .max_concurrent_actions_per_instance
+                .iter()
+                .map(|(instance_name, limit)| (instance_name.clone(), *limit))
+                .collect(),
+            is_quiescing: false,
+            is_draining_all_workers: false,
             metrics: metrics.clone(),
         }));
         let weak_inner = Arc::downgrade(&inner);
         Self {
             inner,
             platform_property_manager,
+            reject_unsupported_platform_properties: scheduler_cfg
+                .reject_unsupported_platform_properties,
+            admission_controller: Arc::new(AdmitAllController),
             _task_worker_matching_future: spawn!(
                 "simple_scheduler_task_worker_matching",
                 async move {
@@ -449,19 +1040,180 @@ impl SimpleScheduler {
                                 let mut inner = inner_mux.lock().await;
                                 let timer = metrics_for_do_try_match.do_try_match.begin_timer();
                                 inner.do_try_match().await;
+                                metrics_for_do_try_match
+                                    .do_try_match_duration
+                                    .record(timer.elapsed());
                                 timer.measure();
+                                matching_engine_run_notify_for_do_try_match.notify_waiters();
                             }
                             // If the inner went away it means the scheduler is shutting
                             // down, so we need to resolve our future.
                             None => return,
                         };
+                        // The cost of running `do_try_match()` is very high, but constant
+                        // in relation to the number of changes that have happened. This means
+                        // that grabbing this lock to process `do_try_match()` should always
+                        // yield to any other tasks that might want the lock. The easiest and
+                        // most fair way to do this is to sleep for a small amount of time.
+                        // Using something like tokio::task::yield_now() does not yield as
+                        // aggresively as we'd like if new futures are scheduled within a future.
+                        if !match_cooldown.is_zero() {
+                            tokio::time::sleep(match_cooldown).await;
+                        }
                         on_matching_engine_run().await;
                     }
                     // Unreachable.
                 }
             ),
             metrics,
+            orphaned_result_ac_store: None,
+            completed_actions_store,
+            action_event_listener,
+            matching_engine_run_notify,
+        }
+    }
+
+    /// Writes successful results that workers report for actions the
+    /// scheduler is not tracking into `ac_store`, instead of dropping them.
+    #[must_use]
+    pub fn with_orphaned_result_ac_store(mut self, ac_store: Store) -> Self {
+        self.orphaned_result_ac_store = Some(ac_store);
+        self
+    }
+
+    /// Uses `admission_controller` to decide whether new actions are queued.
+    /// By default every action is admitted.
+    #[must_use]
+    pub fn with_admission_controller(
+        mut self,
+        admission_controller: Arc<dyn AdmissionController>,
+    ) -> Self {
+        self.admission_controller = admission_controller;
+        self
+    }
+
+    /// Tells `action_event_listener` about every stage change of the actions
+    /// in this scheduler. Only the first listener is kept.
+    #[must_use]
+    pub fn with_action_event_listener(
+        self,
+        action_event_listener: Arc<dyn ActionEventListener>,
+    ) -> Self {
+        if self
+            .action_event_listener
+            .set(action_event_listener)
+            .is_err()
+        {
+            event!(
+                Level::WARN,
+                "Action event listener already set in SimpleScheduler, ignoring new listener"
+            );
         }
+        self
+    }
+
+    /// Writes the state of completed actions into `store` and looks up
+    /// actions there that are not in memory, so clients can still find
+    /// actions that completed before a restart. Only the first store is kept.
+    #[must_use]
+    pub fn with_completed_actions_store(self, store: Store) -> Self {
+        if self.completed_actions_store.set(store).is_err() {
+            event!(
+                Level::WARN,
+                "Completed actions store already set in SimpleScheduler, ignoring new store"
+            );
+        }
+        self
+    }
+
+    /// Reads a completed action written by `insert_completed_action()` from
+    /// the `completed_actions_store`, ignoring it if it is older than
+    /// `retain_completed_for`. Found actions are kept in memory again.
+    async fn find_stored_completed_action(
+        &self,
+        unique_qualifier: &ActionInfoHashKey,
+    ) -> Option<watch::Receiver<Arc<ActionState>>> {
+        let store = self.completed_actions_store.get()?;
+        let data = match store
+            .get_part_unchunked(completed_action_store_key(unique_qualifier), 0, None)
+            .await
+        {
+            Ok(data) => data,
+            Err(err) if err.code == Code::NotFound => return None,
+            Err(err) => {
+                event!(
+                    Level::WARN,
+                    ?err,
+                    "Failed to read completed action from store"
+                );
+                return None;
+            }
+        };
+        let completed_action = match CompletedAction::decode(&data) {
+            Ok(completed_action) => completed_action,
+            Err(err) => {
+                event!(Level::ERROR, ?err, "Failed to decode completed action");
+                return None;
+            }
+        };
+        let mut inner = self.get_inner_lock().await;
+        if !completed_action.is_retained(inner.now(), inner.retain_completed_for) {
+            inner
+                .state_manager
+                .remove_stored_completed_actions(vec![unique_qualifier.clone()]);
+            return None;
+        }
+        let receiver = watch::channel(completed_action.state.clone()).1;
+        inner
+            .state_manager
+            .inner
+            .recently_completed_actions
+            .insert(completed_action);
+        Some(receiver)
+    }
+
+    /// Writes `action_result` into the action cache if the action is not
+    /// tracked by the scheduler. Returns false if the result was not
+    /// salvaged and should be handled as usual.
+    async fn try_salvage_orphaned_result(
+        &self,
+        worker_id: &WorkerId,
+        action_info_hash_key: &ActionInfoHashKey,
+        action_result: &ActionResult,
+    ) -> Result<bool, Error> {
+        let Some(ac_store) = &self.orphaned_result_ac_store else {
+            return Ok(false);
+        };
+        // Only results of successful actions that may be cached are salvaged.
+        if action_info_hash_key.salt != 0 || action_result.exit_code != 0 {
+            return Ok(false);
+        }
+        let is_orphaned = !self
+            .get_inner_lock()
+            .await
+            .state_manager
+            .inner
+            .active_actions
+            .contains_key(action_info_hash_key);
+        if !is_orphaned {
+            return Ok(false);
+        }
+        event!(
+            Level::WARN,
+            ?action_info_hash_key,
+            ?worker_id,
+            "Salvaging result of an action that is not tracked by the scheduler"
+        );
+        let proto_action_result = ProtoActionResult::from(action_result.clone());
+        ac_store
+            .update_oneshot(
+                action_info_hash_key.digest,
+                proto_action_result.encode_to_vec().into(),
+            )
+            .await
+            .err_tip(|| "Failed to salvage orphaned result into the action cache")?;
+        self.metrics.orphaned_results_salvaged.inc();
+        Ok(true)
     }
 
     /// Checks to see if the worker exists in the worker pool. Should only be used in unit tests.
@@ -476,6 +1228,13 @@ impl SimpleScheduler {
             .contains(worker_id)
     }
 
+    /// Returns the number of matching engine runs recorded in each latency
+    /// bucket. Should only be used in unit tests.
+    #[must_use]
+    pub fn matching_engine_duration_buckets_for_test(&self) -> Vec<u64> {
+        self.metrics.do_try_match_duration.bucket_counts()
+    }
+
     /// A unit test function used to send the keep alive message to the worker from the server.
     pub async fn send_keep_alive_to_worker_for_test(
         &self,
@@ -494,6 +1253,172 @@ impl SimpleScheduler {
         worker.keep_alive()
     }
 
+    /// Captures a snapshot of the queued actions, active actions and workers
+    /// that can be serialized for debugging.
+    pub async fn dump_state(&self) -> SchedulerSnapshot {
+        self.dump_state_at(SystemTime::now()).await
+    }
+
+    /// Same as `dump_state()`, but computes action ages relative to `now`.
+    pub async fn dump_state_at(&self, now: SystemTime) -> SchedulerSnapshot {
+        let age_s = |action_info: &ActionInfo| {
+            now.duration_since(action_info.insert_timestamp)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let inner = self.get_inner_lock().await;
+        let state = &inner.state_manager.inner;
+
+        let queued_actions = state
+            .queued_actions
+            .iter()
+            .rev()
+            .map(|(action_info, awaited_action)| QueuedActionSnapshot {
+                action_name: action_info.unique_qualifier.action_name(),
+                priority: action_info.priority,
+                age_s: age_s(action_info),
+                attempts: awaited_action.attempts,
+            })
+            .collect();
+
+        let mut active_actions: Vec<ActiveActionSnapshot> = state
+            .active_actions
+            .iter()
+            .map(|(action_info, awaited_action)| ActiveActionSnapshot {
+                action_name: action_info.unique_qualifier.action_name(),
+                priority: action_info.priority,
+                age_s: age_s(action_info),
+                attempts: awaited_action.attempts,
+                worker_id: awaited_action.worker_id.map(|id| id.to_string()),
+            })
+            .collect();
+        active_actions.sort_unstable_by(|a, b| a.action_name.cmp(&b.action_name));
+
+        let mut workers: Vec<WorkerSnapshot> = state
+            .workers
+            .workers
+            .iter()
+            .map(|(worker_id, worker)| {
+                let mut running_actions: Vec<String> = worker
+                    .running_action_infos
+                    .iter()
+                    .map(|action_info| action_info.unique_qualifier.action_name())
+                    .collect();
+                running_actions.sort_unstable();
+                WorkerSnapshot {
+                    worker_id: worker_id.to_string(),
+                    platform_properties: worker
+                        .platform_properties
+                        .properties
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.as_str().into_owned()))
+                        .collect(),
+                    running_actions,
+                    last_update_timestamp: worker.last_update_timestamp,
+                    is_paused: worker.is_paused,
+                    is_draining: worker.is_draining,
+                }
+            })
+            .collect();
+        workers.sort_unstable_by(|a, b| a.worker_id.cmp(&b.worker_id));
+
+        SchedulerSnapshot {
+            queued_actions,
+            active_actions,
+            workers,
+        }
+    }
+
+    /// Captures the queued and active actions so they can be persisted and
+    /// restored with `restore_checkpoint()` after a restart.
+    pub async fn checkpoint(&self) -> SchedulerCheckpoint {
+        self.get_inner_lock().await.state_manager.checkpoint()
+    }
+
+    /// Queues the actions from a checkpoint taken with `checkpoint()`.
+    pub async fn restore_checkpoint(&self, checkpoint: SchedulerCheckpoint) {
+        self.get_inner_lock()
+            .await
+            .state_manager
+            .restore_checkpoint(checkpoint);
+    }
+
+    /// Sets the priority of all queued actions of `instance_name` to
+    /// `new_priority`. Actions that are already running are not affected.
+    /// Returns the number of queued actions that were reprioritized.
+    pub async fn reprioritize_instance(&self, instance_name: &str, new_priority: i32) -> usize {
+        self.get_inner_lock()
+            .await
+            .state_manager
+            .reprioritize_instance(instance_name, new_priority)
+    }
+
+    /// Cancels a queued or running action. Its listeners are sent a
+    /// `Cancelled` error and then closed, and if it was running, the worker
+    /// is asked to kill it and its resources are freed for other actions.
+    pub async fn remove_operation(&self, operation_id: OperationId) -> Result<(), Error> {
+        self.get_inner_lock()
+            .await
+            .state_manager
+            .remove_operation(operation_id)
+            .await
+    }
+
+    /// Stops all workers in the pool, including workers that join it later,
+    /// from being assigned new actions, while letting the actions they are
+    /// running complete. Returns the number of workers that were not already
+    /// draining.
+    pub async fn drain_all_workers(&self) -> usize {
+        self.get_inner_lock().await.drain_all_workers()
+    }
+
+    /// Waits until no actions are running or `timeout` elapses. Returns true
+    /// if all active actions finished. Usually used after
+    /// `drain_all_workers()` to wait for in-flight actions before shutdown.
+    pub async fn await_quiescence(&self, timeout: Duration) -> bool {
+        self.wait_until(timeout, |inner| {
+            inner.state_manager.inner.active_actions.is_empty()
+        })
+        .await
+    }
+
+    /// Prepares the scheduler for shutdown. New actions are rejected from now
+    /// on, while queued and running actions are still processed. Waits until
+    /// all of them finished or `timeout` elapses and returns the work that
+    /// is left. Use `drain_all_workers()` first to not start queued actions.
+    pub async fn quiesce(&self, timeout: Duration) -> RemainingWork {
+        self.get_inner_lock().await.is_quiescing = true;
+        // Whether we timed out is reported by the remaining work.
+        self.wait_until(timeout, |inner| inner.remaining_work().is_empty())
+            .await;
+        self.get_inner_lock().await.remaining_work()
+    }
+
+    /// Waits until `condition` holds or `timeout` elapses, checking it again
+    /// after every run of the matching engine. Returns true if the condition
+    /// holds.
+    async fn wait_until(
+        &self,
+        timeout: Duration,
+        condition: impl Fn(&SimpleSchedulerImpl) -> bool,
+    ) -> bool {
+        let wait_for_condition = async {
+            loop {
+                let mut matching_engine_ran = pin!(self.matching_engine_run_notify.notified());
+                // Register before checking, so a run that finishes in between
+                // is not missed.
+                matching_engine_ran.as_mut().enable();
+                if condition(&*self.get_inner_lock().await) {
+                    return;
+                }
+                matching_engine_ran.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait_for_condition)
+            .await
+            .is_ok()
+    }
+
     async fn get_inner_lock(&self) -> MutexGuard<'_, SimpleSchedulerImpl> {
         // We don't use one of the wrappers because we only want to capture the time spent,
         // nothing else beacuse this is a hot path.
@@ -522,6 +1447,24 @@ impl ActionScheduler for SimpleScheduler {
         &self,
         action_info: ActionInfo,
     ) -> Result<watch::Receiver<Arc<ActionState>>, Error> {
+        if self.reject_unsupported_platform_properties {
+            let known_properties = self.platform_property_manager.get_known_properties();
+            if let Some(name) = action_info
+                .platform_properties
+                .properties
+                .keys()
+                .find(|name| !known_properties.contains_key(*name))
+            {
+                return Err(make_err!(
+                    Code::FailedPrecondition,
+                    "Action requests platform property '{name}' which is not in supported_platform_properties, no worker can run it"
+                ));
+            }
+        }
+        self.admission_controller
+            .admit(&action_info)
+            .await
+            .err_tip(|| "Action rejected by admission controller")?;
         let mut inner = self.get_inner_lock().await;
         self.metrics
             .add_action
@@ -534,10 +1477,14 @@ impl ActionScheduler for SimpleScheduler {
         unique_qualifier: &ActionInfoHashKey,
     ) -> Option<watch::Receiver<Arc<ActionState>>> {
         let inner = self.get_inner_lock().await;
-        let result = inner
+        let mut result = inner
             .find_existing_action(unique_qualifier)
             .await
             .or_else(|| inner.find_recently_completed_action(unique_qualifier));
+        drop(inner);
+        if result.is_none() {
+            result = self.find_stored_completed_action(unique_qualifier).await;
+        }
         if result.is_some() {
             self.metrics.existing_actions_found.inc();
         } else {
@@ -546,6 +1493,17 @@ impl ActionScheduler for SimpleScheduler {
         result
     }
 
+    async fn set_action_priority(
+        &self,
+        unique_qualifier: &ActionInfoHashKey,
+        priority: i32,
+    ) -> Result<(), Error> {
+        self.get_inner_lock()
+            .await
+            .state_manager
+            .set_action_priority(unique_qualifier, priority)
+    }
+
     async fn clean_recently_completed_actions(&self) {
         self.get_inner_lock()
             .await
@@ -564,9 +1522,32 @@ impl WorkerScheduler for SimpleScheduler {
         self.platform_property_manager.as_ref()
     }
 
-    async fn add_worker(&self, worker: Worker) -> Result<(), Error> {
+    async fn add_worker(&self, mut worker: Worker) -> Result<(), Error> {
         let worker_id = worker.id;
         let mut inner = self.get_inner_lock().await;
+        let workers = &inner.state_manager.inner.workers.workers;
+        if inner.max_workers != 0
+            && workers.len() >= inner.max_workers
+            && !workers.contains(&worker_id)
+        {
+            event!(
+                Level::WARN,
+                ?worker_id,
+                max_workers = inner.max_workers,
+                "Rejecting worker because the worker pool is full"
+            );
+            return Err(make_err!(
+                Code::ResourceExhausted,
+                "Worker pool is full ({} workers), rejecting worker {worker_id}",
+                inner.max_workers
+            ));
+        }
+        if inner.worker_warmup_s != 0 {
+            worker.warming_up_until = Some(worker.last_update_timestamp + inner.worker_warmup_s);
+        }
+        if inner.is_draining_all_workers {
+            worker.is_draining = true;
+        }
         self.metrics.add_worker.wrap(move || {
             let res = inner
                 .state_manager
@@ -592,6 +1573,14 @@ impl WorkerScheduler for SimpleScheduler {
         action_info_hash_key: ActionInfoHashKey,
         action_stage: Result<ActionStage, Error>,
     ) -> Result<(), Error> {
+        if let Ok(ActionStage::Completed(action_result)) = &action_stage {
+            if self
+                .try_salvage_orphaned_result(worker_id, &action_info_hash_key, action_result)
+                .await?
+            {
+                return Ok(());
+            }
+        }
         let mut inner = self.get_inner_lock().await;
         self.metrics
             .update_action
@@ -655,6 +1644,10 @@ impl WorkerScheduler for SimpleScheduler {
                     ),
                 );
             }
+            inner.end_elapsed_worker_warmups(now_timestamp);
+            inner.reschedule_timedout_actions(now_timestamp);
+            inner.fail_actions_exceeding_queue_wait();
+            inner.fail_actions_exceeding_execution_time(now_timestamp);
 
             Ok(())
         })
@@ -665,12 +1658,79 @@ impl WorkerScheduler for SimpleScheduler {
         inner.set_drain_worker(worker_id, is_draining)
     }
 
+    async fn set_worker_ready(&self, worker_id: WorkerId) -> Result<(), Error> {
+        let mut inner = self.get_inner_lock().await;
+        inner.set_worker_ready(worker_id)
+    }
+
+    async fn failed_action_results(&self) -> Vec<FailedActionResult> {
+        let inner = self.get_inner_lock().await;
+        inner
+            .state_manager
+            .inner
+            .failed_action_results
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
     fn register_metrics(self: Arc<Self>, _registry: &mut Registry) {
         // We do not register anything here because we only want to register metrics
         // once and we rely on the `ActionScheduler::register_metrics()` to do that.
     }
 }
 
+/// Worker metrics summed over all workers that share the same tag values.
+#[derive(Default)]
+struct TaggedWorkersMetrics {
+    workers: u64,
+    running_actions: u64,
+    actions_completed: u64,
+    paused: u64,
+    draining: u64,
+}
+
+impl TaggedWorkersMetrics {
+    fn add_worker(&mut self, worker: &Worker) {
+        self.workers += 1;
+        self.running_actions += worker.running_action_infos.len() as u64;
+        self.actions_completed += worker.actions_completed();
+        self.paused += u64::from(worker.is_paused);
+        self.draining += u64::from(worker.is_draining);
+    }
+}
+
+impl MetricsComponent for TaggedWorkersMetrics {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "count",
+            &self.workers,
+            "The number of workers with these tags.",
+        );
+        c.publish(
+            "running_actions",
+            &self.running_actions,
+            "The number of actions running on workers with these tags.",
+        );
+        c.publish(
+            "actions_completed",
+            &self.actions_completed,
+            "The number of actions completed by workers with these tags.",
+        );
+        c.publish(
+            "paused",
+            &self.paused,
+            "The number of paused workers with these tags.",
+        );
+        c.publish(
+            "draining",
+            &self.draining,
+            "The number of draining workers with these tags.",
+        );
+    }
+}
+
 impl MetricsComponent for SimpleScheduler {
     fn gather_metrics(&self, c: &mut CollectorState) {
         self.metrics.gather_metrics(c);
@@ -714,13 +1774,21 @@ impl MetricsComponent for SimpleScheduler {
                 "The amount of times a job is allowed to retry from an internal error before it is dropped.",
             );
             let mut props = HashMap::<&String, u64>::new();
+            let mut tagged_workers = BTreeMap::<Vec<String>, TaggedWorkersMetrics>::new();
             for (_worker_id, worker) in inner.state_manager.inner.workers.workers.iter() {
-                c.publish_with_labels(
-                    "workers",
-                    worker,
-                    "",
-                    vec![("worker_id".into(), worker.id.to_string().into())],
-                );
+                if inner.worker_metrics_tags.is_empty() {
+                    c.publish_with_labels(
+                        "workers",
+                        worker,
+                        "",
+                        vec![("worker_id".into(), worker.id.to_string().into())],
+                    );
+                } else {
+                    tagged_workers
+                        .entry(worker.tag_values(&inner.worker_metrics_tags))
+                        .or_default()
+                        .add_worker(worker);
+                }
                 for (property, prop_value) in &worker.platform_properties.properties {
                     let current_value = props.get(&property).unwrap_or(&0);
                     if let PlatformPropertyValue::Minimum(worker_value) = prop_value {
@@ -728,6 +1796,15 @@ impl MetricsComponent for SimpleScheduler {
                     }
                 }
             }
+            for (tag_values, tagged_workers_metrics) in &tagged_workers {
+                let labels = inner
+                    .worker_metrics_tags
+                    .iter()
+                    .zip(tag_values)
+                    .map(|(tag, value)| (tag.clone().into(), value.clone().into()))
+                    .collect();
+                c.publish_with_labels("workers", tagged_workers_metrics, "", labels);
+            }
             for (property, prop_value) in props {
                 c.publish(
                     &format!("{property}_available_properties"),
@@ -773,6 +1850,8 @@ struct Metrics {
     update_action_with_internal_error_no_action: CounterWithTime,
     update_action_with_internal_error_backpressure: CounterWithTime,
     update_action_with_internal_error_from_wrong_worker: CounterWithTime,
+    execution_metadata_timestamps_corrected: CounterWithTime,
+    orphaned_results_salvaged: CounterWithTime,
     workers_evicted: CounterWithTime,
     workers_evicted_with_running_action: CounterWithTime,
     workers_drained: CounterWithTime,
@@ -785,6 +1864,7 @@ struct Metrics {
     lock_stall_time: AtomicU64,
     lock_stall_time_counter: AtomicU64,
     do_try_match: AsyncCounterWrapper,
+    do_try_match_duration: DurationHistogram,
 }
 
 impl Metrics {
@@ -849,6 +1929,16 @@ impl Metrics {
                 vec![("result".into(), "from_wrong_worker".into())],
             );
         }
+        c.publish(
+            "execution_metadata_timestamps_corrected",
+            &self.execution_metadata_timestamps_corrected,
+            "The number of action results with execution metadata timestamps corrected by the scheduler.",
+        );
+        c.publish(
+            "orphaned_results_salvaged",
+            &self.orphaned_results_salvaged,
+            "The number of results of untracked actions written to the action cache.",
+        );
         c.publish(
             "workers_evicted_total",
             &self.workers_evicted,
@@ -921,5 +2011,10 @@ impl Metrics {
             &self.do_try_match,
             "The job<->worker matching engine stats. This is a very expensive operation, so it is not run every time (often called do_try_match).",
         );
+        c.publish(
+            "matching_engine_duration",
+            &self.do_try_match_duration,
+            "The distribution of the time spent in each run of the job<->worker matching engine.",
+        );
     }
 }
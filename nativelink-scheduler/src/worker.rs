@@ -12,16 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_proto::com::github::trace_machina::nativelink::remote_execution::{
-    update_for_worker, ConnectionResult, StartExecute, UpdateForWorker,
+    update_for_worker, ConnectionResult, KillActionRequest, StartExecute, UpdateForWorker,
 };
 use nativelink_util::action_messages::{ActionInfo, WorkerId};
+use nativelink_util::common::DigestInfo;
 use nativelink_util::metrics_utils::{
     CollectorState, CounterWithTime, FuncCounterWrapper, MetricsComponent,
 };
@@ -32,8 +34,9 @@ pub type WorkerTimestamp = u64;
 
 /// Notifications to send worker about a requested state change.
 pub enum WorkerUpdate {
-    /// Requests that the worker begin executing this action.
-    RunAction(Arc<ActionInfo>),
+    /// Requests that the worker begin executing this action. The timestamp
+    /// is recorded as the time the action started running on the worker.
+    RunAction(Arc<ActionInfo>, WorkerTimestamp),
 
     /// Request that the worker is no longer in the pool and may discard any jobs.
     Disconnect,
@@ -54,6 +57,9 @@ pub struct Worker {
     /// The action info of the running actions on the worker
     pub running_action_infos: HashSet<Arc<ActionInfo>>,
 
+    /// Timestamp of when each running action was sent to the worker.
+    pub running_action_start_timestamps: HashMap<Arc<ActionInfo>, WorkerTimestamp>,
+
     /// Timestamp of last time this worker had been communicated with.
     // Warning: Do not update this timestamp without updating the placement of the worker in
     // the LRUCache in the Workers struct.
@@ -65,6 +71,14 @@ pub struct Worker {
     /// Whether the worker is draining.
     pub is_draining: bool,
 
+    /// If set, the worker is warming up and will not be given work until
+    /// this timestamp or until it signals that it is ready.
+    pub warming_up_until: Option<WorkerTimestamp>,
+
+    /// Input roots of the most recent actions sent to this worker, oldest
+    /// first. Only populated if `worker_input_root_history_size` is set.
+    pub recent_input_roots: VecDeque<DigestInfo>,
+
     /// Stats about the worker.
     metrics: Arc<Metrics>,
 }
@@ -109,9 +123,12 @@ impl Worker {
             platform_properties,
             tx,
             running_action_infos: HashSet::new(),
+            running_action_start_timestamps: HashMap::new(),
             last_update_timestamp: timestamp,
             is_paused: false,
             is_draining: false,
+            warming_up_until: None,
+            recent_input_roots: VecDeque::new(),
             metrics: Arc::new(Metrics {
                 connected_timestamp: SystemTime::now()
                     .duration_since(UNIX_EPOCH)
@@ -140,7 +157,9 @@ impl Worker {
     /// Notifies the worker of a requested state change.
     pub fn notify_update(&mut self, worker_update: WorkerUpdate) -> Result<(), Error> {
         match worker_update {
-            WorkerUpdate::RunAction(action_info) => self.run_action(action_info),
+            WorkerUpdate::RunAction(action_info, now_timestamp) => {
+                self.run_action(action_info, now_timestamp)
+            }
             WorkerUpdate::Disconnect => {
                 self.metrics.notify_disconnect.inc();
                 send_msg_to_worker(&mut self.tx, update_for_worker::Update::Disconnect(()))
@@ -157,13 +176,19 @@ impl Worker {
         })
     }
 
-    fn run_action(&mut self, action_info: Arc<ActionInfo>) -> Result<(), Error> {
+    fn run_action(
+        &mut self,
+        action_info: Arc<ActionInfo>,
+        now_timestamp: WorkerTimestamp,
+    ) -> Result<(), Error> {
         let tx = &mut self.tx;
         let worker_platform_properties = &mut self.platform_properties;
         let running_action_infos = &mut self.running_action_infos;
+        let running_action_start_timestamps = &mut self.running_action_start_timestamps;
         self.metrics.run_action.wrap(move || {
             let action_info_clone = action_info.as_ref().clone();
             running_action_infos.insert(action_info.clone());
+            running_action_start_timestamps.insert(action_info.clone(), now_timestamp);
             reduce_platform_properties(
                 worker_platform_properties,
                 &action_info.platform_properties,
@@ -179,8 +204,20 @@ impl Worker {
         })
     }
 
+    /// Asks the worker to kill the given action. This is only a best attempt,
+    /// the caller is still responsible for calling `complete_action()`.
+    pub fn kill_action(&mut self, action_info: &Arc<ActionInfo>) -> Result<(), Error> {
+        send_msg_to_worker(
+            &mut self.tx,
+            update_for_worker::Update::KillActionRequest(KillActionRequest {
+                action_id: hex::encode(action_info.unique_qualifier.get_hash()),
+            }),
+        )
+    }
+
     pub fn complete_action(&mut self, action_info: &Arc<ActionInfo>) {
         self.running_action_infos.remove(action_info);
+        self.running_action_start_timestamps.remove(action_info);
         self.restore_platform_properties(&action_info.platform_properties);
         self.is_paused = false;
         self.metrics.actions_completed.inc();
@@ -204,7 +241,28 @@ impl Worker {
     }
 
     pub fn can_accept_work(&self) -> bool {
-        !self.is_paused && !self.is_draining
+        !self.is_paused && !self.is_draining && self.warming_up_until.is_none()
+    }
+
+    /// Returns the number of actions this worker completed.
+    pub fn actions_completed(&self) -> u64 {
+        self.metrics
+            .actions_completed
+            .counter
+            .load(Ordering::Relaxed)
+    }
+
+    /// Returns the value of each of the `tags` platform properties of this
+    /// worker, or an empty string if the worker does not have the property.
+    pub fn tag_values(&self, tags: &[String]) -> Vec<String> {
+        tags.iter()
+            .map(|tag| {
+                self.platform_properties
+                    .properties
+                    .get(tag)
+                    .map_or_else(String::new, |value| value.as_str().into_owned())
+            })
+            .collect()
     }
 }
 
@@ -277,6 +335,12 @@ impl MetricsComponent for Worker {
             "If this worker is draining.",
             vec![("worker_id".into(), format!("{}", self.id).into())],
         );
+        c.publish_with_labels(
+            "is_warming_up",
+            &self.warming_up_until.is_some(),
+            "If this worker is warming up and not accepting work yet.",
+            vec![("worker_id".into(), format!("{}", self.id).into())],
+        );
         for action_info in self.running_action_infos.iter() {
             let action_name = action_info.unique_qualifier.action_name().to_string();
             c.publish_with_labels(
@@ -18,10 +18,29 @@ use async_trait::async_trait;
 use nativelink_error::Error;
 use nativelink_util::action_messages::{ActionInfoHashKey, ActionStage, WorkerId};
 use nativelink_util::metrics_utils::Registry;
+use serde::Serialize;
 
 use crate::platform_property_manager::PlatformPropertyManager;
 use crate::worker::{Worker, WorkerTimestamp};
 
+/// An action that completed with an error, kept for debugging when
+/// `max_failed_action_results` is set.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailedActionResult {
+    /// Unique name of the action (instance, digest function, digest and salt).
+    pub action_name: String,
+    pub operation_id: String,
+    /// The worker the action last ran on, empty if it never ran.
+    pub worker_id: String,
+    /// Number of times the action has been attempted on a worker.
+    pub attempts: usize,
+    /// Code and messages of the error the action failed with.
+    pub code: String,
+    pub messages: Vec<String>,
+    /// Seconds since the UNIX epoch when the action failed.
+    pub failed_timestamp: u64,
+}
+
 /// WorkerScheduler interface is responsible for interactions between the scheduler
 /// and worker related operations.
 #[async_trait]
@@ -57,6 +76,13 @@ pub trait WorkerScheduler: Sync + Send + Unpin {
     /// Sets if the worker is draining or not.
     async fn set_drain_worker(&self, worker_id: WorkerId, is_draining: bool) -> Result<(), Error>;
 
+    /// Ends the warmup period of a worker, so it starts being given work.
+    async fn set_worker_ready(&self, worker_id: WorkerId) -> Result<(), Error>;
+
+    /// Returns the retained results of actions that completed with an
+    /// error, newest first.
+    async fn failed_action_results(&self) -> Vec<FailedActionResult>;
+
     /// Register the metrics for the worker scheduler.
     fn register_metrics(self: Arc<Self>, _registry: &mut Registry) {}
 }
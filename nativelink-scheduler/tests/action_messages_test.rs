@@ -213,3 +213,74 @@ async fn equal_priority_earliest_first() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[nativelink_test]
+async fn normalize_timestamps_fixes_future_and_out_of_order_test() -> Result<(), Error> {
+    let now = make_system_time(100);
+    let mut execution_metadata = ExecutionMetadata {
+        worker: "foo_worker_id".to_string(),
+        queued_timestamp: make_system_time(1),
+        worker_start_timestamp: make_system_time(2),
+        input_fetch_start_timestamp: make_system_time(4),
+        // Completed before it started.
+        input_fetch_completed_timestamp: make_system_time(3),
+        execution_start_timestamp: make_system_time(5),
+        // Within the allowed clock skew.
+        execution_completed_timestamp: make_system_time(105),
+        // Far in the future.
+        output_upload_start_timestamp: make_system_time(100_000),
+        output_upload_completed_timestamp: make_system_time(100_001),
+        worker_completed_timestamp: make_system_time(100_002),
+    };
+
+    let corrected = execution_metadata.normalize_timestamps(now, Duration::from_secs(10));
+
+    assert_eq!(
+        corrected,
+        vec![
+            "input_fetch_completed_timestamp",
+            "output_upload_start_timestamp",
+            "output_upload_completed_timestamp",
+            "worker_completed_timestamp",
+        ]
+    );
+    assert_eq!(
+        execution_metadata,
+        ExecutionMetadata {
+            worker: "foo_worker_id".to_string(),
+            queued_timestamp: make_system_time(1),
+            worker_start_timestamp: make_system_time(2),
+            input_fetch_start_timestamp: make_system_time(4),
+            input_fetch_completed_timestamp: make_system_time(4),
+            execution_start_timestamp: make_system_time(5),
+            execution_completed_timestamp: make_system_time(105),
+            // Clamped to `now`, then raised to the previous phase.
+            output_upload_start_timestamp: make_system_time(105),
+            output_upload_completed_timestamp: make_system_time(105),
+            worker_completed_timestamp: make_system_time(105),
+        }
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn normalize_timestamps_skips_unset_timestamps_test() -> Result<(), Error> {
+    let now = make_system_time(100);
+    // The worker did not fetch inputs or upload outputs.
+    let mut execution_metadata = ExecutionMetadata {
+        worker: "foo_worker_id".to_string(),
+        queued_timestamp: make_system_time(1),
+        worker_start_timestamp: make_system_time(2),
+        execution_start_timestamp: make_system_time(5),
+        execution_completed_timestamp: make_system_time(6),
+        worker_completed_timestamp: make_system_time(7),
+        ..Default::default()
+    };
+    let expected = execution_metadata.clone();
+
+    let corrected = execution_metadata.normalize_timestamps(now, Duration::from_secs(10));
+
+    assert_eq!(corrected, Vec::<&str>::new());
+    assert_eq!(execution_metadata, expected);
+    Ok(())
+}
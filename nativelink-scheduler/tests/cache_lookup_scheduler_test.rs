@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::UNIX_EPOCH;
 
@@ -21,8 +22,9 @@ mod utils {
     pub(crate) mod scheduler_utils;
 }
 
+use async_trait::async_trait;
 use futures::join;
-use nativelink_error::Error;
+use nativelink_error::{make_err, Code, Error};
 use nativelink_macro::nativelink_test;
 use nativelink_proto::build::bazel::remote::execution::v2::ActionResult as ProtoActionResult;
 use nativelink_scheduler::action_scheduler::ActionScheduler;
@@ -32,9 +34,11 @@ use nativelink_store::memory_store::MemoryStore;
 use nativelink_util::action_messages::{
     ActionInfoHashKey, ActionResult, ActionStage, ActionState, OperationId,
 };
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::DigestHasherFunc;
-use nativelink_util::store_trait::{Store, StoreLike};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
 use pretty_assertions::assert_eq;
 use prost::Message;
 use tokio::sync::watch;
@@ -48,12 +52,67 @@ struct TestContext {
     cache_scheduler: CacheLookupScheduler,
 }
 
+/// An action cache that is unavailable and fails every request.
+struct UnavailableStore;
+
+#[async_trait]
+impl StoreDriver for UnavailableStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        _keys: &[StoreKey<'_>],
+        _results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        Err(make_err!(Code::Unavailable, "AC is down"))
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _reader: DropCloserReadHalf,
+        _size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        Err(make_err!(Code::Unavailable, "AC is down"))
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _writer: &mut DropCloserWriteHalf,
+        _offset: usize,
+        _length: Option<usize>,
+    ) -> Result<(), Error> {
+        Err(make_err!(Code::Unavailable, "AC is down"))
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(UnavailableStore);
+
 fn make_cache_scheduler() -> Result<TestContext, Error> {
-    let mock_scheduler = Arc::new(MockActionScheduler::new());
     let ac_store = Store::new(MemoryStore::new(
         &nativelink_config::stores::MemoryStore::default(),
     ));
-    let cache_scheduler = CacheLookupScheduler::new(ac_store.clone(), mock_scheduler.clone())?;
+    make_cache_scheduler_with_store(ac_store, false)
+}
+
+fn make_cache_scheduler_with_store(
+    ac_store: Store,
+    ac_fail_open: bool,
+) -> Result<TestContext, Error> {
+    let mock_scheduler = Arc::new(MockActionScheduler::new());
+    let cache_scheduler =
+        CacheLookupScheduler::new(ac_store.clone(), mock_scheduler.clone(), ac_fail_open)?;
     Ok(TestContext {
         mock_scheduler,
         ac_store,
@@ -124,3 +183,137 @@ async fn find_existing_action_call_passed() -> Result<(), Error> {
     assert_eq!(action_name, actual_action_name);
     Ok(())
 }
+
+#[nativelink_test]
+async fn add_action_forwards_on_ac_failure_when_fail_open() -> Result<(), Error> {
+    let context = make_cache_scheduler_with_store(Store::new(Arc::new(UnavailableStore)), true)?;
+    let action_info = make_base_action_info(UNIX_EPOCH);
+    let queued_state = Arc::new(ActionState {
+        id: OperationId::new(action_info.unique_qualifier.clone()),
+        stage: ActionStage::Queued,
+    });
+    let (_forward_watch_channel_tx, forward_watch_channel_rx) =
+        watch::channel(queued_state.clone());
+    let (maybe_rx, forwarded_action_info) = join!(
+        context.cache_scheduler.add_action(action_info.clone()),
+        context
+            .mock_scheduler
+            .expect_add_action(Ok(forward_watch_channel_rx))
+    );
+    assert_eq!(
+        action_info.unique_qualifier,
+        forwarded_action_info.unique_qualifier
+    );
+    let mut rx = maybe_rx?;
+    rx.changed()
+        .await
+        .map_err(|e| make_err!(Code::Internal, "{e:?}"))?;
+    assert_eq!(queued_state.stage, rx.borrow().stage);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn add_action_fails_on_ac_failure_when_fail_closed() -> Result<(), Error> {
+    let context = make_cache_scheduler_with_store(Store::new(Arc::new(UnavailableStore)), false)?;
+    let action_info = make_base_action_info(UNIX_EPOCH);
+    let mut rx = context.cache_scheduler.add_action(action_info).await?;
+    rx.changed()
+        .await
+        .map_err(|e| make_err!(Code::Internal, "{e:?}"))?;
+    let stage = rx.borrow().stage.clone();
+    let ActionStage::Completed(action_result) = stage else {
+        panic!("Expected action to complete with an error, got {stage:?}");
+    };
+    assert_eq!(
+        Some(Code::Unavailable),
+        action_result.error.map(|err| err.code)
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn add_action_forwards_corrupt_ac_entry_when_fail_closed() -> Result<(), Error> {
+    let ac_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let context = make_cache_scheduler_with_store(ac_store, false)?;
+    let action_info = make_base_action_info(UNIX_EPOCH);
+    context
+        .ac_store
+        .update_oneshot(*action_info.digest(), "not an action result".into())
+        .await?;
+    let (_forward_watch_channel_tx, forward_watch_channel_rx) =
+        watch::channel(Arc::new(ActionState {
+            id: OperationId::new(action_info.unique_qualifier.clone()),
+            stage: ActionStage::Queued,
+        }));
+    let (maybe_rx, forwarded_action_info) = join!(
+        context.cache_scheduler.add_action(action_info.clone()),
+        context
+            .mock_scheduler
+            .expect_add_action(Ok(forward_watch_channel_rx))
+    );
+    maybe_rx?;
+    assert_eq!(
+        action_info.unique_qualifier,
+        forwarded_action_info.unique_qualifier
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn add_action_with_changed_input_root_is_cache_miss() -> Result<(), Error> {
+    let context = make_cache_scheduler()?;
+    let cached_action_info = make_base_action_info(UNIX_EPOCH);
+    let action_result = ProtoActionResult::from(ActionResult::default());
+    context
+        .ac_store
+        .update_oneshot(
+            *cached_action_info.digest(),
+            action_result.encode_to_vec().into(),
+        )
+        .await?;
+
+    {
+        // The unchanged action is served from the cache.
+        let mut rx = context
+            .cache_scheduler
+            .add_action(cached_action_info.clone())
+            .await?;
+        rx.changed()
+            .await
+            .map_err(|e| make_err!(Code::Internal, "{e:?}"))?;
+        let stage = rx.borrow().stage.clone();
+        assert_eq!(ActionStage::CompletedFromCache(action_result), stage);
+    }
+
+    // The input root is part of the `Action` message, so a new input root
+    // always comes with a new action digest and can never hit a result that
+    // was cached for other inputs.
+    let mut changed_action_info = cached_action_info.clone();
+    changed_action_info.input_root_digest = DigestInfo::new([1u8; 32], 100);
+    changed_action_info.unique_qualifier.digest = DigestInfo::new([2u8; 32], 100);
+    let (_forward_watch_channel_tx, forward_watch_channel_rx) =
+        watch::channel(Arc::new(ActionState {
+            id: OperationId::new(changed_action_info.unique_qualifier.clone()),
+            stage: ActionStage::Queued,
+        }));
+    let (maybe_rx, forwarded_action_info) = join!(
+        context
+            .cache_scheduler
+            .add_action(changed_action_info.clone()),
+        context
+            .mock_scheduler
+            .expect_add_action(Ok(forward_watch_channel_rx))
+    );
+    maybe_rx?;
+    assert_eq!(
+        changed_action_info.input_root_digest,
+        forwarded_action_info.input_root_digest
+    );
+    assert_eq!(
+        changed_action_info.unique_qualifier,
+        forwarded_action_info.unique_qualifier
+    );
+    Ok(())
+}
@@ -47,7 +47,7 @@ fn make_modifier_scheduler(modifications: Vec<PropertyModification>) -> TestCont
     let config = nativelink_config::schedulers::PropertyModifierScheduler {
         modifications,
         scheduler: Box::new(nativelink_config::schedulers::SchedulerConfig::simple(
-            nativelink_config::schedulers::SimpleScheduler::default(),
+            Box::default(),
         )),
     };
     let modifier_scheduler = PropertyModifierScheduler::new(&config, mock_scheduler.clone());
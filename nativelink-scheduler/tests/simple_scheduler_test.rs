@@ -12,30 +12,45 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use async_trait::async_trait;
+use nativelink_config::schedulers::WorkerAllocationStrategy;
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
-use nativelink_proto::build::bazel::remote::execution::v2::{digest_function, ExecuteRequest};
+use nativelink_proto::build::bazel::remote::execution::v2::{
+    digest_function, ActionResult as ProtoActionResult, ExecuteRequest,
+};
 use nativelink_proto::com::github::trace_machina::nativelink::remote_execution::{
     update_for_worker, ConnectionResult, StartExecute, UpdateForWorker,
 };
 use nativelink_scheduler::action_scheduler::ActionScheduler;
-use nativelink_scheduler::simple_scheduler::SimpleScheduler;
+use nativelink_scheduler::operation_state_manager::AdmissionController;
+use nativelink_scheduler::scheduler_state::checkpoint::SchedulerCheckpoint;
+use nativelink_scheduler::simple_scheduler::{
+    ActiveActionSnapshot, NowFn, QueuedActionSnapshot, RemainingWork, SchedulerSnapshot,
+    SimpleScheduler, WorkerSnapshot,
+};
 use nativelink_scheduler::worker::Worker;
 use nativelink_scheduler::worker_scheduler::WorkerScheduler;
+use nativelink_store::ac_utils::get_and_decode_digest;
+use nativelink_store::memory_store::MemoryStore;
 use nativelink_util::action_messages::{
-    ActionInfoHashKey, ActionResult, ActionStage, ActionState, DirectoryInfo, ExecutionMetadata,
-    FileInfo, NameOrPath, OperationId, SymlinkInfo, WorkerId, INTERNAL_ERROR_EXIT_CODE,
+    ActionInfo, ActionInfoHashKey, ActionResult, ActionStage, ActionState, DirectoryInfo,
+    ExecutionMetadata, FileInfo, NameOrPath, OperationId, SymlinkInfo, WorkerId,
+    INTERNAL_ERROR_EXIT_CODE,
 };
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::DigestHasherFunc;
 use nativelink_util::platform_properties::{PlatformProperties, PlatformPropertyValue};
+use nativelink_util::store_trait::{Store, StoreLike};
 use pretty_assertions::assert_eq;
-use tokio::sync::{mpsc, watch};
+use prometheus_client::registry::Registry;
+use tokio::sync::{mpsc, watch, Notify};
 use utils::scheduler_utils::{make_base_action_info, INSTANCE_NAME};
 use uuid::Uuid;
 
@@ -67,6 +82,13 @@ fn make_system_time(add_time: u64) -> SystemTime {
         .unwrap()
 }
 
+/// Returns a scheduler clock that reads the seconds since `UNIX_EPOCH` from
+/// `now_s`.
+fn make_now_fn(now_s: &Arc<AtomicU64>) -> NowFn {
+    let now_s = now_s.clone();
+    Arc::new(move || UNIX_EPOCH + Duration::from_secs(now_s.load(Ordering::Acquire)))
+}
+
 async fn setup_new_worker(
     scheduler: &SimpleScheduler,
     worker_id: WorkerId,
@@ -220,6 +242,7 @@ async fn remove_worker_reschedules_multiple_running_job_test() -> Result<(), Err
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler {
             worker_timeout_s: WORKER_TIMEOUT_S,
+            match_cooldown_ms: Some(0),
             ..Default::default()
         },
         || async move {},
@@ -362,13 +385,158 @@ async fn remove_worker_reschedules_multiple_running_job_test() -> Result<(), Err
 }
 
 #[nativelink_test]
-async fn set_drain_worker_pauses_and_resumes_worker_test() -> Result<(), Error> {
+async fn drain_all_workers_waits_for_quiescence_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let mut running_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([99u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    assert_eq!(
+        running_client_rx.borrow_and_update().stage,
+        ActionStage::Executing
+    );
+
+    assert_eq!(scheduler.drain_all_workers().await, 1);
+    // Draining again does not count the worker twice.
+    assert_eq!(scheduler.drain_all_workers().await, 0);
+    assert!(!scheduler.await_quiescence(Duration::from_millis(50)).await);
+
+    // New actions are not assigned to the draining worker.
+    let mut queued_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([88u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(14),
+    )
+    .await?;
+    tokio::task::yield_now().await;
+    assert_eq!(
+        queued_client_rx.borrow_and_update().stage,
+        ActionStage::Queued
+    );
+
+    // The running action still completes normally.
+    let unique_qualifier = running_client_rx.borrow().id.unique_qualifier.clone();
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier,
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+    assert!(matches!(
+        running_client_rx.borrow_and_update().stage,
+        ActionStage::Completed(_)
+    ));
+    assert!(scheduler.await_quiescence(Duration::from_secs(5)).await);
+    assert_eq!(
+        queued_client_rx.borrow_and_update().stage,
+        ActionStage::Queued
+    );
+
+    // Workers that join after the drain started are draining too.
+    let mut rx_from_new_worker = setup_new_worker(
+        &scheduler,
+        WorkerId(Uuid::new_v4()),
+        PlatformProperties::default(),
+    )
+    .await?;
+    tokio::task::yield_now().await;
+    assert!(rx_from_new_worker.try_recv().is_err());
+    assert_eq!(
+        queued_client_rx.borrow_and_update().stage,
+        ActionStage::Queued
+    );
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn quiesce_rejects_new_actions_and_waits_for_running_test() -> Result<(), Error> {
     let worker_id: WorkerId = WorkerId(Uuid::new_v4());
 
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler::default(),
         || async move {},
     );
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let mut client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([99u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+
+    // The running action is reported if it does not finish in time.
+    assert_eq!(
+        scheduler.quiesce(Duration::from_millis(50)).await,
+        RemainingWork {
+            queued_actions: 0,
+            active_actions: 1,
+        }
+    );
+
+    let err = setup_action(
+        &scheduler,
+        DigestInfo::new([88u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(14),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.code, Code::Unavailable, "Unexpected error: {err:?}");
+
+    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier,
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+    assert!(matches!(
+        client_rx.borrow_and_update().stage,
+        ActionStage::Completed(_)
+    ));
+    assert_eq!(
+        scheduler.quiesce(Duration::from_secs(5)).await,
+        RemainingWork::default()
+    );
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn set_drain_worker_pauses_and_resumes_worker_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            match_cooldown_ms: Some(0),
+            ..Default::default()
+        },
+        || async move {},
+    );
     let action_digest = DigestInfo::new([99u8; 32], 512);
 
     let mut rx_from_worker =
@@ -518,59 +686,61 @@ async fn worker_should_not_queue_if_properties_dont_match_test() -> Result<(), E
 }
 
 #[nativelink_test]
-async fn cacheable_items_join_same_action_queued_test() -> Result<(), Error> {
-    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+async fn worker_pool_isolation_only_dispatches_to_instance_pool_test() -> Result<(), Error> {
+    const POOL_PROPERTY: &str = "pool";
+    let worker_id_a: WorkerId = WorkerId(Uuid::new_v4());
+    let worker_id_b: WorkerId = WorkerId(Uuid::new_v4());
 
     let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler::default(),
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_pool_isolation: Some(nativelink_config::schedulers::WorkerPoolIsolation {
+                worker_property: POOL_PROPERTY.to_string(),
+                instance_pools: HashMap::from([
+                    (INSTANCE_NAME.to_string(), "pool_a".to_string()),
+                    ("other_instance".to_string(), "pool_b".to_string()),
+                ]),
+            }),
+            ..Default::default()
+        },
         || async move {},
     );
     let action_digest = DigestInfo::new([99u8; 32], 512);
-
-    let unique_qualifier = ActionInfoHashKey {
-        instance_name: "".to_string(),
-        digest: DigestInfo::zero_digest(),
-        digest_function: DigestHasherFunc::Sha256,
-        salt: 0,
-    };
-    let id = OperationId::new(unique_qualifier);
-    let mut expected_action_state = ActionState {
-        id,
-        stage: ActionStage::Queued,
+    let make_pool_properties = |pool: &str| {
+        let mut properties = PlatformProperties::default();
+        properties.properties.insert(
+            POOL_PROPERTY.to_string(),
+            PlatformPropertyValue::Priority(pool.to_string()),
+        );
+        properties
     };
 
-    let insert_timestamp1 = make_system_time(1);
-    let insert_timestamp2 = make_system_time(2);
-    let mut client1_rx = setup_action(
-        &scheduler,
-        action_digest,
-        PlatformProperties::default(),
-        insert_timestamp1,
-    )
-    .await?;
-    let mut client2_rx = setup_action(
+    // Only an idle worker from the other instance's pool is available.
+    let mut rx_from_worker_b =
+        setup_new_worker(&scheduler, worker_id_b, make_pool_properties("pool_b")).await?;
+    let insert_timestamp = make_system_time(1);
+    let mut client_rx = setup_action(
         &scheduler,
         action_digest,
         PlatformProperties::default(),
-        insert_timestamp2,
+        insert_timestamp,
     )
     .await?;
 
     {
-        // Clients should get notification saying it's been queued.
-        let action_state1 = client1_rx.borrow_and_update();
-        let action_state2 = client2_rx.borrow_and_update();
-        // Name is random so we set force it to be the same.
-        expected_action_state.id = action_state1.id.clone();
-        assert_eq!(action_state1.as_ref(), &expected_action_state);
-        assert_eq!(action_state2.as_ref(), &expected_action_state);
+        // Client should get notification saying it's been queued.
+        let action_state = client_rx.borrow_and_update();
+        let expected_action_state = ActionState {
+            // Name is a random string, so we ignore it and just make it the same.
+            id: action_state.id.clone(),
+            stage: ActionStage::Queued,
+        };
+        assert_eq!(action_state.as_ref(), &expected_action_state);
     }
 
-    let mut rx_from_worker =
-        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
-
+    let mut rx_from_worker_a =
+        setup_new_worker(&scheduler, worker_id_a, make_pool_properties("pool_a")).await?;
     {
-        // Worker should have been sent an execute command.
+        // Worker in our instance's pool should have been sent an execute command.
         let expected_msg_for_worker = UpdateForWorker {
             update: Some(update_for_worker::Update::StartAction(StartExecute {
                 execute_request: Some(ExecuteRequest {
@@ -581,194 +751,1984 @@ async fn cacheable_items_join_same_action_queued_test() -> Result<(), Error> {
                     ..Default::default()
                 }),
                 salt: 0,
-                queued_timestamp: Some(insert_timestamp1.into()),
+                queued_timestamp: Some(insert_timestamp.into()),
             })),
         };
-        let msg_for_worker = rx_from_worker.recv().await.unwrap();
+        let msg_for_worker = rx_from_worker_a.recv().await.unwrap();
         assert_eq!(msg_for_worker, expected_msg_for_worker);
     }
 
-    // Action should now be executing.
-    expected_action_state.stage = ActionStage::Executing;
-    {
-        // Both client1 and client2 should be receiving the same updates.
-        // Most importantly the `name` (which is random) will be the same.
-        assert_eq!(
-            client1_rx.borrow_and_update().as_ref(),
-            &expected_action_state
-        );
-        assert_eq!(
-            client2_rx.borrow_and_update().as_ref(),
-            &expected_action_state
-        );
-    }
-
-    {
-        // Now if another action is requested it should also join with executing action.
-        let insert_timestamp3 = make_system_time(2);
-        let mut client3_rx = setup_action(
-            &scheduler,
-            action_digest,
-            PlatformProperties::default(),
-            insert_timestamp3,
-        )
-        .await?;
-        assert_eq!(
-            client3_rx.borrow_and_update().as_ref(),
-            &expected_action_state
-        );
-    }
+    // The worker from the other pool should never have been used.
+    assert_eq!(
+        rx_from_worker_b.try_recv(),
+        Err(mpsc::error::TryRecvError::Empty)
+    );
 
     Ok(())
 }
 
 #[nativelink_test]
-async fn worker_disconnects_does_not_schedule_for_execution_test() -> Result<(), Error> {
+async fn instance_round_robin_interleaves_instances_test() -> Result<(), Error> {
+    const OTHER_INSTANCE_NAME: &str = "other_instance";
     let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
     let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler::default(),
+        &nativelink_config::schedulers::SimpleScheduler {
+            action_assignment_policy:
+                nativelink_config::schedulers::ActionAssignmentPolicy::instance_round_robin,
+            ..Default::default()
+        },
         || async move {},
     );
-    let action_digest = DigestInfo::new([99u8; 32], 512);
-
-    let rx_from_worker =
-        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
-
-    // Now act like the worker disconnected.
-    drop(rx_from_worker);
+    let make_properties = |value| PlatformProperties {
+        properties: HashMap::from([("prop1".to_string(), PlatformPropertyValue::Minimum(value))]),
+    };
 
-    let insert_timestamp = make_system_time(1);
-    let mut client_rx = setup_action(
-        &scheduler,
-        action_digest,
-        PlatformProperties::default(),
-        insert_timestamp,
-    )
-    .await?;
-    {
-        // Client should get notification saying it's being queued not executed.
-        let action_state = client_rx.borrow_and_update();
-        let expected_action_state = ActionState {
-            // Name is a random string, so we ignore it and just make it the same.
-            id: action_state.id.clone(),
-            stage: ActionStage::Queued,
-        };
-        assert_eq!(action_state.as_ref(), &expected_action_state);
+    // One instance floods the queue before the other instance adds an action.
+    let mut client_rxs = Vec::new();
+    for i in 0..4 {
+        client_rxs.push(
+            setup_action(
+                &scheduler,
+                DigestInfo::new([i; 32], 512),
+                make_properties(1),
+                make_system_time(u64::from(i)),
+            )
+            .await?,
+        );
+    }
+    let mut action_info = make_base_action_info(make_system_time(10));
+    action_info.platform_properties = make_properties(1);
+    action_info.unique_qualifier.instance_name = OTHER_INSTANCE_NAME.to_string();
+    action_info.unique_qualifier.digest = DigestInfo::new([99u8; 32], 512);
+    client_rxs.push(scheduler.add_action(action_info).await?);
+
+    // The worker only has room for two actions.
+    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, make_properties(2)).await?;
+    let mut started_instance_names = Vec::new();
+    for _ in 0..2 {
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(start_execute)) => {
+                started_instance_names.push(start_execute.execute_request.unwrap().instance_name);
+            }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
     }
+    // Without taking turns both slots would go to the flooding instance.
+    assert_eq!(
+        started_instance_names,
+        vec![INSTANCE_NAME.to_string(), OTHER_INSTANCE_NAME.to_string()]
+    );
 
     Ok(())
 }
 
 #[nativelink_test]
-async fn worker_timesout_reschedules_running_job_test() -> Result<(), Error> {
-    let worker_id1: WorkerId = WorkerId(Uuid::new_v4());
-    let worker_id2: WorkerId = WorkerId(Uuid::new_v4());
+async fn max_concurrent_actions_per_instance_limits_instance_test() -> Result<(), Error> {
+    const OTHER_INSTANCE_NAME: &str = "other_instance";
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler {
-            worker_timeout_s: WORKER_TIMEOUT_S,
+            max_concurrent_actions_per_instance: HashMap::from([(INSTANCE_NAME.to_string(), 1)]),
             ..Default::default()
         },
         || async move {},
     );
-    let action_digest = DigestInfo::new([99u8; 32], 512);
-
-    // Note: This needs to stay in scope or a disconnect will trigger.
-    let mut rx_from_worker1 =
-        setup_new_worker(&scheduler, worker_id1, PlatformProperties::default()).await?;
-    let insert_timestamp = make_system_time(1);
-    let mut client_rx = setup_action(
-        &scheduler,
-        action_digest,
-        PlatformProperties::default(),
-        insert_timestamp,
-    )
-    .await?;
-
-    // Note: This needs to stay in scope or a disconnect will trigger.
-    let mut rx_from_worker2 =
-        setup_new_worker(&scheduler, worker_id2, PlatformProperties::default()).await?;
-
-    let unique_qualifier = ActionInfoHashKey {
-        instance_name: "".to_string(),
-        digest: DigestInfo::zero_digest(),
-        digest_function: DigestHasherFunc::Sha256,
-        salt: 0,
+    let make_properties = |value| PlatformProperties {
+        properties: HashMap::from([("prop1".to_string(), PlatformPropertyValue::Minimum(value))]),
     };
-    let id = OperationId::new(unique_qualifier);
-    let mut expected_action_state = ActionState {
-        id,
+
+    // The capped instance queues two actions before the other instance.
+    let mut capped_client_rxs = Vec::new();
+    for i in 0..2 {
+        capped_client_rxs.push(
+            setup_action(
+                &scheduler,
+                DigestInfo::new([i; 32], 512),
+                make_properties(1),
+                make_system_time(u64::from(i)),
+            )
+            .await?,
+        );
+    }
+    let mut action_info = make_base_action_info(make_system_time(10));
+    action_info.platform_properties = make_properties(1);
+    action_info.unique_qualifier.instance_name = OTHER_INSTANCE_NAME.to_string();
+    action_info.unique_qualifier.digest = DigestInfo::new([99u8; 32], 512);
+    let mut other_client_rx = scheduler.add_action(action_info).await?;
+
+    // The worker has room for all three actions.
+    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, make_properties(3)).await?;
+    let mut started_instance_names = Vec::new();
+    for _ in 0..2 {
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(start_execute)) => {
+                started_instance_names.push(start_execute.execute_request.unwrap().instance_name);
+            }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+    }
+    started_instance_names.sort();
+    assert_eq!(
+        started_instance_names,
+        vec![INSTANCE_NAME.to_string(), OTHER_INSTANCE_NAME.to_string()]
+    );
+    assert_eq!(
+        other_client_rx.borrow_and_update().stage,
+        ActionStage::Executing
+    );
+    assert_eq!(
+        capped_client_rxs[0].borrow_and_update().stage,
+        ActionStage::Executing
+    );
+    // The second action of the capped instance waits even though the worker
+    // has room for it.
+    assert_eq!(
+        capped_client_rxs[1].borrow_and_update().stage,
+        ActionStage::Queued
+    );
+
+    // Once the first action completes the second one is started.
+    let unique_qualifier = capped_client_rxs[0].borrow().id.unique_qualifier.clone();
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier,
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(start_execute)) => {
+            assert_eq!(
+                start_execute.execute_request.unwrap().instance_name,
+                INSTANCE_NAME
+            );
+        }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    assert_eq!(
+        capped_client_rxs[1].borrow_and_update().stage,
+        ActionStage::Executing
+    );
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn reprioritize_instance_reorders_queued_actions_test() -> Result<(), Error> {
+    const OTHER_INSTANCE_NAME: &str = "other_instance";
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let make_properties = |value| PlatformProperties {
+        properties: HashMap::from([("prop1".to_string(), PlatformPropertyValue::Minimum(value))]),
+    };
+
+    // The first instance queues its actions before the other instance.
+    let mut client_rxs = Vec::new();
+    for i in 0..2 {
+        client_rxs.push(
+            setup_action(
+                &scheduler,
+                DigestInfo::new([i; 32], 512),
+                make_properties(1),
+                make_system_time(u64::from(i)),
+            )
+            .await?,
+        );
+    }
+    let mut action_info = make_base_action_info(make_system_time(10));
+    action_info.platform_properties = make_properties(1);
+    action_info.unique_qualifier.instance_name = OTHER_INSTANCE_NAME.to_string();
+    action_info.unique_qualifier.digest = DigestInfo::new([99u8; 32], 512);
+    client_rxs.push(scheduler.add_action(action_info).await?);
+
+    assert_eq!(scheduler.reprioritize_instance(INSTANCE_NAME, -1).await, 2);
+    assert_eq!(
+        scheduler
+            .reprioritize_instance("unknown_instance", -1)
+            .await,
+        0
+    );
+
+    // The worker only has room for one action.
+    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, make_properties(1)).await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(start_execute)) => {
+            assert_eq!(
+                start_execute.execute_request.unwrap().instance_name,
+                OTHER_INSTANCE_NAME
+            );
+        }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn worker_in_warmup_is_not_matched_test() -> Result<(), Error> {
+    const WORKER_WARMUP_S: u64 = 10;
+
+    // Checks that an action waits while the worker warms up, and runs once
+    // `end_warmup` was awaited.
+    async fn check_warmup<Fut: Future<Output = Result<(), Error>>>(
+        end_warmup: impl FnOnce(Arc<SimpleScheduler>, WorkerId) -> Fut,
+    ) -> Result<(), Error> {
+        let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+        let scheduler = Arc::new(SimpleScheduler::new_with_callback(
+            &nativelink_config::schedulers::SimpleScheduler {
+                worker_timeout_s: WORKER_TIMEOUT_S,
+                worker_warmup_s: WORKER_WARMUP_S,
+                ..Default::default()
+            },
+            || async move {},
+        ));
+        let mut rx_from_worker =
+            setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+        let mut client_rx = setup_action(
+            &scheduler,
+            DigestInfo::new([99u8; 32], 512),
+            PlatformProperties::default(),
+            make_system_time(1),
+        )
+        .await?;
+
+        // The worker is warming up, so the action stays queued.
+        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Queued);
+        assert_eq!(
+            rx_from_worker.try_recv(),
+            Err(mpsc::error::TryRecvError::Empty)
+        );
+
+        end_warmup(scheduler.clone(), worker_id).await?;
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+        Ok(())
+    }
+
+    // The worker signals it is ready.
+    check_warmup(|scheduler, worker_id| async move { scheduler.set_worker_ready(worker_id).await })
+        .await?;
+
+    // The warmup period elapses.
+    check_warmup(|scheduler, _| async move {
+        // Not elapsed yet.
+        scheduler
+            .remove_timedout_workers(NOW_TIME + WORKER_WARMUP_S - 1)
+            .await?;
+        tokio::task::yield_now().await;
+        assert!(
+            scheduler.dump_state().await.active_actions.is_empty(),
+            "Action should not run before the warmup elapsed"
+        );
+        scheduler
+            .remove_timedout_workers(NOW_TIME + WORKER_WARMUP_S)
+            .await
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn add_worker_rejected_when_pool_is_full_test() -> Result<(), Error> {
+    const MAX_WORKERS: usize = 2;
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_timeout_s: WORKER_TIMEOUT_S,
+            max_workers: MAX_WORKERS,
+            ..Default::default()
+        },
+        || async move {},
+    );
+    let worker_ids: Vec<WorkerId> = (0..MAX_WORKERS).map(|_| WorkerId(Uuid::new_v4())).collect();
+    let mut worker_rxs = Vec::new();
+    for worker_id in &worker_ids {
+        worker_rxs
+            .push(setup_new_worker(&scheduler, *worker_id, PlatformProperties::default()).await?);
+    }
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let err = scheduler
+        .add_worker(Worker::new(
+            WorkerId(Uuid::new_v4()),
+            PlatformProperties::default(),
+            tx,
+            NOW_TIME,
+        ))
+        .await
+        .expect_err("Expected worker to be rejected");
+    assert_eq!(err.code, Code::ResourceExhausted);
+
+    // Existing workers are unaffected.
+    for worker_id in &worker_ids {
+        scheduler
+            .worker_keep_alive_received(worker_id, NOW_TIME + 1)
+            .await?;
+        assert!(scheduler.contains_worker_for_test(worker_id).await);
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn dump_state_snapshots_queued_active_and_workers_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let mut worker_properties = PlatformProperties::default();
+    worker_properties.properties.insert(
+        "prop".to_string(),
+        PlatformPropertyValue::Exact("1".to_string()),
+    );
+    // No worker has this property value, so this action will stay queued.
+    let mut unmatched_properties = PlatformProperties::default();
+    unmatched_properties.properties.insert(
+        "prop".to_string(),
+        PlatformPropertyValue::Exact("2".to_string()),
+    );
+
+    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, worker_properties).await?;
+    let active_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([99u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    let queued_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([88u8; 32], 512),
+        unmatched_properties,
+        make_system_time(2),
+    )
+    .await?;
+    // Wait for the first action to be dispatched to the worker.
+    rx_from_worker.recv().await.unwrap();
+
+    let active_action_name = active_client_rx.borrow().id.unique_qualifier.action_name();
+    let queued_action_name = queued_client_rx.borrow().id.unique_qualifier.action_name();
+
+    let snapshot = scheduler.dump_state_at(make_system_time(11)).await;
+    assert_eq!(
+        snapshot,
+        SchedulerSnapshot {
+            queued_actions: vec![QueuedActionSnapshot {
+                action_name: queued_action_name,
+                priority: 0,
+                age_s: 9,
+                attempts: 0,
+            }],
+            active_actions: vec![ActiveActionSnapshot {
+                action_name: active_action_name.clone(),
+                priority: 0,
+                age_s: 10,
+                attempts: 1,
+                worker_id: Some(worker_id.to_string()),
+            }],
+            workers: vec![WorkerSnapshot {
+                worker_id: worker_id.to_string(),
+                platform_properties: BTreeMap::from([("prop".to_string(), "1".to_string())]),
+                running_actions: vec![active_action_name],
+                last_update_timestamp: NOW_TIME,
+                is_paused: false,
+                is_draining: false,
+            }],
+        }
+    );
+    // Snapshot must be serializable for admin endpoints.
+    assert!(serde_json::to_string(&snapshot).is_ok());
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn restored_checkpoint_requeues_queued_and_active_actions_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let mut worker_properties = PlatformProperties::default();
+    worker_properties.properties.insert(
+        "prop".to_string(),
+        PlatformPropertyValue::Exact("1".to_string()),
+    );
+    // No worker has this property value, so this action will stay queued.
+    let mut unmatched_properties = PlatformProperties::default();
+    unmatched_properties.properties.insert(
+        "prop".to_string(),
+        PlatformPropertyValue::Exact("2".to_string()),
+    );
+
+    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, worker_properties).await?;
+    let active_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([99u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    let queued_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([88u8; 32], 512),
+        unmatched_properties,
+        make_system_time(2),
+    )
+    .await?;
+    // Wait for the first action to be dispatched to the worker.
+    rx_from_worker.recv().await.unwrap();
+
+    let checkpoint = scheduler.checkpoint().await;
+    assert_eq!(checkpoint.queued_actions.len(), 1);
+    assert_eq!(checkpoint.active_actions.len(), 1);
+    assert_eq!(checkpoint.active_actions[0].worker_id, Some(worker_id));
+    let serialized_checkpoint = serde_json::to_vec(&checkpoint).unwrap();
+    drop(scheduler);
+
+    // Simulate a restart by restoring into a new scheduler without workers.
+    let restored_scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    restored_scheduler
+        .restore_checkpoint(
+            serde_json::from_slice::<SchedulerCheckpoint>(&serialized_checkpoint).unwrap(),
+        )
+        .await;
+
+    let snapshot = restored_scheduler.dump_state_at(make_system_time(11)).await;
+    assert_eq!(
+        snapshot,
+        SchedulerSnapshot {
+            queued_actions: vec![
+                QueuedActionSnapshot {
+                    action_name: active_client_rx.borrow().id.unique_qualifier.action_name(),
+                    priority: 0,
+                    age_s: 10,
+                    attempts: 1,
+                },
+                QueuedActionSnapshot {
+                    action_name: queued_client_rx.borrow().id.unique_qualifier.action_name(),
+                    priority: 0,
+                    age_s: 9,
+                    attempts: 0,
+                },
+            ],
+            active_actions: vec![],
+            workers: vec![],
+        }
+    );
+
+    Ok(())
+}
+
+/// Runs an action with a 10s timeout on a worker of `worker_class`, that last
+/// reported in `started_after_s` seconds before the action starts, and
+/// returns whether the worker was asked to kill it when the timeout sweep
+/// runs `elapsed_s` seconds after the action started.
+async fn action_killed_after_timeout_sweep(
+    worker_class: &str,
+    started_after_s: u64,
+    elapsed_s: u64,
+) -> Result<bool, Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let now_s = Arc::new(AtomicU64::new(NOW_TIME));
+    let scheduler = SimpleScheduler::new_with_callback_and_now_fn(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_timeout_s: WORKER_TIMEOUT_S,
+            action_timeout_multipliers: vec![
+                nativelink_config::schedulers::ActionTimeoutMultiplier {
+                    property: "worker_class".to_string(),
+                    value: "slow".to_string(),
+                    multiplier: 3.0,
+                },
+            ],
+            ..Default::default()
+        },
+        || async move {},
+        make_now_fn(&now_s),
+    );
+    let mut worker_properties = PlatformProperties::default();
+    worker_properties.properties.insert(
+        "worker_class".to_string(),
+        PlatformPropertyValue::Priority(worker_class.to_string()),
+    );
+    let (tx, mut rx_from_worker) = mpsc::unbounded_channel();
+    scheduler
+        .add_worker(Worker::new(worker_id, worker_properties, tx, NOW_TIME))
+        .await?;
+    verify_initial_connection_message(worker_id, &mut rx_from_worker).await;
+
+    let started_s = NOW_TIME + started_after_s;
+    now_s.store(started_s, Ordering::Release);
+    let mut action_info = make_base_action_info(make_system_time(1));
+    action_info.timeout = Duration::from_secs(10);
+    let _client_rx = scheduler.add_action(action_info).await?;
+    tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+    let msg_for_worker = rx_from_worker.recv().await.unwrap();
+    assert!(matches!(
+        msg_for_worker.update,
+        Some(update_for_worker::Update::StartAction(_))
+    ));
+
+    scheduler
+        .remove_timedout_workers(started_s + elapsed_s)
+        .await?;
+    match rx_from_worker.try_recv() {
+        Ok(UpdateForWorker {
+            update: Some(update_for_worker::Update::KillActionRequest(_)),
+        }) => Ok(true),
+        Err(mpsc::error::TryRecvError::Empty) => Ok(false),
+        msg => Err(make_err!(
+            Code::Internal,
+            "Unexpected message for worker: {msg:?}"
+        )),
+    }
+}
+
+#[nativelink_test]
+async fn action_timeout_is_scaled_by_worker_class_test() -> Result<(), Error> {
+    // The same action gets its requested 10s deadline on an unlisted worker class...
+    assert!(!action_killed_after_timeout_sweep("fast", 0, 5).await?);
+    assert!(action_killed_after_timeout_sweep("fast", 0, 12).await?);
+    // ...but three times that on a "slow" worker.
+    assert!(!action_killed_after_timeout_sweep("slow", 0, 12).await?);
+    assert!(action_killed_after_timeout_sweep("slow", 0, 32).await?);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn action_timeout_starts_when_action_is_matched_test() -> Result<(), Error> {
+    // The deadline counts from when the action started, not from when the
+    // worker last reported in.
+    assert!(!action_killed_after_timeout_sweep("fast", 50, 5).await?);
+    assert!(action_killed_after_timeout_sweep("fast", 50, 12).await?);
+    Ok(())
+}
+
+/// Returns the error the action of `client_rx` completed with.
+fn completed_action_error(client_rx: &mut watch::Receiver<Arc<ActionState>>) -> Error {
+    match &client_rx.borrow_and_update().stage {
+        ActionStage::Completed(action_result) => action_result
+            .error
+            .clone()
+            .expect("Expected action to complete with an error"),
+        stage => panic!("Expected action to be completed, got {stage:?}"),
+    }
+}
+
+#[nativelink_test]
+async fn action_exceeding_max_queue_wait_fails_test() -> Result<(), Error> {
+    let now_s = Arc::new(AtomicU64::new(NOW_TIME));
+    let scheduler = SimpleScheduler::new_with_callback_and_now_fn(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_timeout_s: WORKER_TIMEOUT_S,
+            max_queue_wait_s: 10,
+            max_execution_time_s: 10,
+            ..Default::default()
+        },
+        || async move {},
+        make_now_fn(&now_s),
+    );
+
+    // No worker is connected, so the action is never started.
+    let mut client_rx = scheduler
+        .add_action(make_base_action_info(make_system_time(0)))
+        .await?;
+    now_s.store(NOW_TIME + 5, Ordering::Release);
+    scheduler.remove_timedout_workers(NOW_TIME + 5).await?;
+    assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Queued);
+
+    // The wait is measured on the scheduler clock, not on the timestamp
+    // workers report in with.
+    scheduler.remove_timedout_workers(NOW_TIME + 11).await?;
+    assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Queued);
+
+    now_s.store(NOW_TIME + 11, Ordering::Release);
+    scheduler.remove_timedout_workers(NOW_TIME + 5).await?;
+    let err = completed_action_error(&mut client_rx);
+    assert_eq!(err.code, Code::DeadlineExceeded, "{err:?}");
+    assert!(err.to_string().contains("max queue wait of 10s"), "{err:?}");
+    Ok(())
+}
+
+#[nativelink_test]
+async fn action_exceeding_max_execution_time_fails_test() -> Result<(), Error> {
+    let now_s = NOW_TIME;
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let clock_s = Arc::new(AtomicU64::new(now_s));
+    let scheduler = SimpleScheduler::new_with_callback_and_now_fn(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_timeout_s: WORKER_TIMEOUT_S,
+            max_queue_wait_s: 10,
+            max_execution_time_s: 10,
+            ..Default::default()
+        },
+        || async move {},
+        make_now_fn(&clock_s),
+    );
+    let (tx, mut rx_from_worker) = mpsc::unbounded_channel();
+    scheduler
+        .add_worker(Worker::new(
+            worker_id,
+            PlatformProperties::default(),
+            tx,
+            now_s,
+        ))
+        .await?;
+    verify_initial_connection_message(worker_id, &mut rx_from_worker).await;
+
+    let mut client_rx = scheduler
+        .add_action(make_base_action_info(make_system_time(0)))
+        .await?;
+    tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+    let msg_for_worker = rx_from_worker.recv().await.unwrap();
+    assert!(matches!(
+        msg_for_worker.update,
+        Some(update_for_worker::Update::StartAction(_))
+    ));
+
+    // The action was started in time, so only the execution time applies.
+    clock_s.store(now_s + 5, Ordering::Release);
+    scheduler.remove_timedout_workers(now_s + 5).await?;
+    assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
+
+    clock_s.store(now_s + 12, Ordering::Release);
+    scheduler.remove_timedout_workers(now_s + 12).await?;
+    assert!(matches!(
+        rx_from_worker.try_recv(),
+        Ok(UpdateForWorker {
+            update: Some(update_for_worker::Update::KillActionRequest(_)),
+        })
+    ));
+    let err = completed_action_error(&mut client_rx);
+    assert_eq!(err.code, Code::DeadlineExceeded, "{err:?}");
+    assert!(
+        err.to_string().contains("max execution time of 10s"),
+        "{err:?}"
+    );
+    // The action is failed rather than retried on the worker.
+    tokio::task::yield_now().await;
+    assert!(rx_from_worker.try_recv().is_err());
+    Ok(())
+}
+
+#[nativelink_test]
+async fn failed_action_results_are_retained_up_to_cap_test() -> Result<(), Error> {
+    let now_s = NOW_TIME;
+    let clock_s = Arc::new(AtomicU64::new(now_s));
+    let scheduler = SimpleScheduler::new_with_callback_and_now_fn(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_timeout_s: WORKER_TIMEOUT_S,
+            max_queue_wait_s: 10,
+            max_failed_action_results: 2,
+            ..Default::default()
+        },
+        || async move {},
+        make_now_fn(&clock_s),
+    );
+    assert_eq!(scheduler.failed_action_results().await, vec![]);
+
+    // Fail three actions one after another by letting them wait in the
+    // queue for too long, no worker is connected.
+    let mut action_names = Vec::new();
+    for i in 0..3u8 {
+        let insert_s = now_s + u64::from(i) * 20;
+        let mut action_info = make_base_action_info(UNIX_EPOCH + Duration::from_secs(insert_s));
+        action_info.unique_qualifier.digest = DigestInfo::new([i + 1; 32], 512);
+        action_names.push(action_info.unique_qualifier.action_name());
+        let mut client_rx = scheduler.add_action(action_info).await?;
+        clock_s.store(insert_s + 11, Ordering::Release);
+        scheduler.remove_timedout_workers(insert_s + 11).await?;
+        assert_eq!(
+            completed_action_error(&mut client_rx).code,
+            Code::DeadlineExceeded
+        );
+    }
+
+    // Only the two most recent failures are kept, newest first.
+    let failed_action_results = scheduler.failed_action_results().await;
+    assert_eq!(
+        failed_action_results
+            .iter()
+            .map(|result| result.action_name.clone())
+            .collect::<Vec<_>>(),
+        vec![action_names[2].clone(), action_names[1].clone()]
+    );
+    for result in &failed_action_results {
+        assert_eq!(result.code, format!("{:?}", Code::DeadlineExceeded));
+        assert!(
+            result
+                .messages
+                .iter()
+                .any(|message| message.contains("max queue wait of 10s")),
+            "{result:?}"
+        );
+        assert_eq!(result.worker_id, "");
+        assert_eq!(result.attempts, 0);
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn cacheable_items_join_same_action_queued_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let unique_qualifier = ActionInfoHashKey {
+        instance_name: "".to_string(),
+        digest: DigestInfo::zero_digest(),
+        digest_function: DigestHasherFunc::Sha256,
+        salt: 0,
+    };
+    let id = OperationId::new(unique_qualifier);
+    let mut expected_action_state = ActionState {
+        id,
+        stage: ActionStage::Queued,
+    };
+
+    let insert_timestamp1 = make_system_time(1);
+    let insert_timestamp2 = make_system_time(2);
+    let mut client1_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp1,
+    )
+    .await?;
+    let mut client2_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp2,
+    )
+    .await?;
+
+    {
+        // Clients should get notification saying it's been queued.
+        let action_state1 = client1_rx.borrow_and_update();
+        let action_state2 = client2_rx.borrow_and_update();
+        // Name is random so we set force it to be the same.
+        expected_action_state.id = action_state1.id.clone();
+        assert_eq!(action_state1.as_ref(), &expected_action_state);
+        assert_eq!(action_state2.as_ref(), &expected_action_state);
+    }
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+
+    {
+        // Worker should have been sent an execute command.
+        let expected_msg_for_worker = UpdateForWorker {
+            update: Some(update_for_worker::Update::StartAction(StartExecute {
+                execute_request: Some(ExecuteRequest {
+                    instance_name: INSTANCE_NAME.to_string(),
+                    skip_cache_lookup: true,
+                    action_digest: Some(action_digest.into()),
+                    digest_function: digest_function::Value::Sha256.into(),
+                    ..Default::default()
+                }),
+                salt: 0,
+                queued_timestamp: Some(insert_timestamp1.into()),
+            })),
+        };
+        let msg_for_worker = rx_from_worker.recv().await.unwrap();
+        assert_eq!(msg_for_worker, expected_msg_for_worker);
+    }
+
+    // Action should now be executing.
+    expected_action_state.stage = ActionStage::Executing;
+    {
+        // Both client1 and client2 should be receiving the same updates.
+        // Most importantly the `name` (which is random) will be the same.
+        assert_eq!(
+            client1_rx.borrow_and_update().as_ref(),
+            &expected_action_state
+        );
+        assert_eq!(
+            client2_rx.borrow_and_update().as_ref(),
+            &expected_action_state
+        );
+    }
+
+    {
+        // Now if another action is requested it should also join with executing action.
+        let insert_timestamp3 = make_system_time(2);
+        let mut client3_rx = setup_action(
+            &scheduler,
+            action_digest,
+            PlatformProperties::default(),
+            insert_timestamp3,
+        )
+        .await?;
+        assert_eq!(
+            client3_rx.borrow_and_update().as_ref(),
+            &expected_action_state
+        );
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn worker_disconnects_does_not_schedule_for_execution_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+
+    // Now act like the worker disconnected.
+    drop(rx_from_worker);
+
+    let insert_timestamp = make_system_time(1);
+    let mut client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp,
+    )
+    .await?;
+    {
+        // Client should get notification saying it's being queued not executed.
+        let action_state = client_rx.borrow_and_update();
+        let expected_action_state = ActionState {
+            // Name is a random string, so we ignore it and just make it the same.
+            id: action_state.id.clone(),
+            stage: ActionStage::Queued,
+        };
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn worker_timesout_reschedules_running_job_test() -> Result<(), Error> {
+    let worker_id1: WorkerId = WorkerId(Uuid::new_v4());
+    let worker_id2: WorkerId = WorkerId(Uuid::new_v4());
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_timeout_s: WORKER_TIMEOUT_S,
+            match_cooldown_ms: Some(0),
+            ..Default::default()
+        },
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    // Note: This needs to stay in scope or a disconnect will trigger.
+    let mut rx_from_worker1 =
+        setup_new_worker(&scheduler, worker_id1, PlatformProperties::default()).await?;
+    let insert_timestamp = make_system_time(1);
+    let mut client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp,
+    )
+    .await?;
+
+    // Note: This needs to stay in scope or a disconnect will trigger.
+    let mut rx_from_worker2 =
+        setup_new_worker(&scheduler, worker_id2, PlatformProperties::default()).await?;
+
+    let unique_qualifier = ActionInfoHashKey {
+        instance_name: "".to_string(),
+        digest: DigestInfo::zero_digest(),
+        digest_function: DigestHasherFunc::Sha256,
+        salt: 0,
+    };
+    let id = OperationId::new(unique_qualifier);
+    let mut expected_action_state = ActionState {
+        id,
+        stage: ActionStage::Executing,
+    };
+
+    let execution_request_for_worker = UpdateForWorker {
+        update: Some(update_for_worker::Update::StartAction(StartExecute {
+            execute_request: Some(ExecuteRequest {
+                instance_name: INSTANCE_NAME.to_string(),
+                skip_cache_lookup: true,
+                action_digest: Some(action_digest.into()),
+                digest_function: digest_function::Value::Sha256.into(),
+                ..Default::default()
+            }),
+            salt: 0,
+            queued_timestamp: Some(insert_timestamp.into()),
+        })),
+    };
+
+    {
+        // Worker1 should now see execution request.
+        let msg_for_worker = rx_from_worker1.recv().await.unwrap();
+        assert_eq!(msg_for_worker, execution_request_for_worker);
+    }
+
+    {
+        // Client should get notification saying it's being executed.
+        let action_state = client_rx.borrow_and_update();
+        // We now know the name of the action so populate it.
+        expected_action_state.id = action_state.id.clone();
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+
+    // Keep worker 2 alive.
+    scheduler
+        .worker_keep_alive_received(&worker_id2, NOW_TIME + WORKER_TIMEOUT_S)
+        .await?;
+    // This should remove worker 1 (the one executing our job).
+    scheduler
+        .remove_timedout_workers(NOW_TIME + WORKER_TIMEOUT_S)
+        .await?;
+    tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+
+    {
+        // Worker1 should have received a disconnect message.
+        let msg_for_worker = rx_from_worker1.recv().await.unwrap();
+        assert_eq!(
+            msg_for_worker,
+            UpdateForWorker {
+                update: Some(update_for_worker::Update::Disconnect(()))
+            }
+        );
+    }
+    {
+        // Client should get notification saying it's being executed.
+        let action_state = client_rx.borrow_and_update();
+        expected_action_state.stage = ActionStage::Executing;
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+    {
+        // Worker2 should now see execution request.
+        let msg_for_worker = rx_from_worker2.recv().await.unwrap();
+        assert_eq!(msg_for_worker, execution_request_for_worker);
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn update_action_sends_completed_result_to_client_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let insert_timestamp = make_system_time(1);
+    let mut client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp,
+    )
+    .await?;
+
+    {
+        // Other tests check full data. We only care if we got StartAction.
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+        // Other tests check full data. We only care if client thinks we are Executing.
+        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
+    }
+
+    let action_info_hash_key = ActionInfoHashKey {
+        instance_name: INSTANCE_NAME.to_string(),
+        digest_function: DigestHasherFunc::Sha256,
+        digest: action_digest,
+        salt: 0,
+    };
+    let action_result = ActionResult {
+        output_files: vec![FileInfo {
+            name_or_path: NameOrPath::Name("hello".to_string()),
+            digest: DigestInfo::new([5u8; 32], 18),
+            is_executable: true,
+        }],
+        output_folders: vec![DirectoryInfo {
+            path: "123".to_string(),
+            tree_digest: DigestInfo::new([9u8; 32], 100),
+        }],
+        output_file_symlinks: vec![SymlinkInfo {
+            name_or_path: NameOrPath::Name("foo".to_string()),
+            target: "bar".to_string(),
+        }],
+        output_directory_symlinks: vec![SymlinkInfo {
+            name_or_path: NameOrPath::Name("foo2".to_string()),
+            target: "bar2".to_string(),
+        }],
+        exit_code: 0,
+        stdout_digest: DigestInfo::new([6u8; 32], 19),
+        stderr_digest: DigestInfo::new([7u8; 32], 20),
+        execution_metadata: ExecutionMetadata {
+            worker: worker_id.to_string(),
+            queued_timestamp: make_system_time(5),
+            worker_start_timestamp: make_system_time(6),
+            worker_completed_timestamp: make_system_time(7),
+            input_fetch_start_timestamp: make_system_time(8),
+            input_fetch_completed_timestamp: make_system_time(9),
+            execution_start_timestamp: make_system_time(10),
+            execution_completed_timestamp: make_system_time(11),
+            output_upload_start_timestamp: make_system_time(12),
+            output_upload_completed_timestamp: make_system_time(13),
+        },
+        server_logs: HashMap::default(),
+        error: None,
+        message: String::new(),
+    };
+    scheduler
+        .update_action(
+            &worker_id,
+            action_info_hash_key,
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await?;
+
+    {
+        // Client should get notification saying it has been completed.
+        let action_state = client_rx.borrow_and_update();
+        let expected_action_state = ActionState {
+            // Name is a random string, so we ignore it and just make it the same.
+            id: action_state.id.clone(),
+            stage: ActionStage::Completed(action_result),
+        };
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+    {
+        // Update info for the action should now be closed (notification happens through Err).
+        let result = client_rx.changed().await;
+        assert!(
+            result.is_err(),
+            "Expected result to be an error : {result:?}"
+        );
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn update_action_sends_completed_result_after_disconnect() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let insert_timestamp = make_system_time(1);
+    let client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp,
+    )
+    .await?;
+
+    // Drop our receiver and don't reconnect until completed.
+    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+    drop(client_rx);
+
+    {
+        // Other tests check full data. We only care if we got StartAction.
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+    }
+
+    let action_info_hash_key = ActionInfoHashKey {
+        instance_name: INSTANCE_NAME.to_string(),
+        digest_function: DigestHasherFunc::Sha256,
+        digest: action_digest,
+        salt: 0,
+    };
+    let action_result = ActionResult {
+        output_files: vec![FileInfo {
+            name_or_path: NameOrPath::Name("hello".to_string()),
+            digest: DigestInfo::new([5u8; 32], 18),
+            is_executable: true,
+        }],
+        output_folders: vec![DirectoryInfo {
+            path: "123".to_string(),
+            tree_digest: DigestInfo::new([9u8; 32], 100),
+        }],
+        output_file_symlinks: vec![SymlinkInfo {
+            name_or_path: NameOrPath::Name("foo".to_string()),
+            target: "bar".to_string(),
+        }],
+        output_directory_symlinks: vec![SymlinkInfo {
+            name_or_path: NameOrPath::Name("foo2".to_string()),
+            target: "bar2".to_string(),
+        }],
+        exit_code: 0,
+        stdout_digest: DigestInfo::new([6u8; 32], 19),
+        stderr_digest: DigestInfo::new([7u8; 32], 20),
+        execution_metadata: ExecutionMetadata {
+            worker: worker_id.to_string(),
+            queued_timestamp: make_system_time(5),
+            worker_start_timestamp: make_system_time(6),
+            worker_completed_timestamp: make_system_time(7),
+            input_fetch_start_timestamp: make_system_time(8),
+            input_fetch_completed_timestamp: make_system_time(9),
+            execution_start_timestamp: make_system_time(10),
+            execution_completed_timestamp: make_system_time(11),
+            output_upload_start_timestamp: make_system_time(12),
+            output_upload_completed_timestamp: make_system_time(13),
+        },
+        server_logs: HashMap::default(),
+        error: None,
+        message: String::new(),
+    };
+    scheduler
+        .update_action(
+            &worker_id,
+            action_info_hash_key,
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await?;
+
+    // Now look up a channel after the action has completed.
+    let mut client_rx = scheduler
+        .find_existing_action(&unique_qualifier)
+        .await
+        .err_tip(|| "Action not found")?;
+    {
+        // Client should get notification saying it has been completed.
+        let action_state = client_rx.borrow_and_update();
+        let expected_action_state = ActionState {
+            // Name is a random string, so we ignore it and just make it the same.
+            id: action_state.id.clone(),
+            stage: ActionStage::Completed(action_result),
+        };
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn expired_completed_action_not_found_before_cleanup_test() -> Result<(), Error> {
+    const RETAIN_COMPLETED_FOR_S: u64 = 10;
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let now_s = Arc::new(AtomicU64::new(NOW_TIME));
+
+    let scheduler = SimpleScheduler::new_with_callback_and_now_fn(
+        &nativelink_config::schedulers::SimpleScheduler {
+            retain_completed_for_s: RETAIN_COMPLETED_FOR_S,
+            ..Default::default()
+        },
+        || async move {},
+        make_now_fn(&now_s),
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+    drop(client_rx);
+
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier.clone(),
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+    assert!(scheduler
+        .find_existing_action(&unique_qualifier)
+        .await
+        .is_some());
+
+    // The entry is kept until the end of the retain window...
+    now_s.store(NOW_TIME + RETAIN_COMPLETED_FOR_S - 1, Ordering::Release);
+    assert!(scheduler
+        .find_existing_action(&unique_qualifier)
+        .await
+        .is_some());
+    // ...and treated as absent after it, even without running cleanup.
+    now_s.store(NOW_TIME + RETAIN_COMPLETED_FOR_S, Ordering::Release);
+    assert!(scheduler
+        .find_existing_action(&unique_qualifier)
+        .await
+        .is_none());
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn completed_action_found_in_store_after_restart_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    )
+    .with_completed_actions_store(store.clone());
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+    drop(client_rx);
+
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    let action_result = ActionResult {
+        exit_code: 3,
+        stdout_digest: DigestInfo::new([6u8; 32], 19),
+        execution_metadata: ExecutionMetadata {
+            worker: worker_id.to_string(),
+            ..ExecutionMetadata::default()
+        },
+        ..ActionResult::default()
+    };
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier.clone(),
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await?;
+    drop(scheduler);
+
+    // A scheduler without the store does not know about the action.
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    assert!(scheduler
+        .find_existing_action(&unique_qualifier)
+        .await
+        .is_none());
+
+    // The completed action is written in the background.
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    )
+    .with_completed_actions_store(store);
+    let mut client_rx = None;
+    for _ in 0..100 {
+        client_rx = scheduler.find_existing_action(&unique_qualifier).await;
+        if client_rx.is_some() {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    let client_rx = client_rx.err_tip(|| "Completed action not found in store")?;
+    let action_state = client_rx.borrow();
+    assert_eq!(action_state.id.unique_qualifier, unique_qualifier);
+    assert_eq!(action_state.stage, ActionStage::Completed(action_result));
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn expired_completed_action_removed_from_store_test() -> Result<(), Error> {
+    const RETAIN_COMPLETED_FOR_S: u64 = 10;
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let now_s = Arc::new(AtomicU64::new(NOW_TIME));
+    let store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+
+    let scheduler = SimpleScheduler::new_with_callback_and_now_fn(
+        &nativelink_config::schedulers::SimpleScheduler {
+            retain_completed_for_s: RETAIN_COMPLETED_FOR_S,
+            ..Default::default()
+        },
+        || async move {},
+        make_now_fn(&now_s),
+    )
+    .with_completed_actions_store(store.clone());
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+    drop(client_rx);
+
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier.clone(),
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+
+    // Both writing and removing the stored action happen in the background.
+    let store_key = format!("completed_action/{}", unique_qualifier.action_name());
+    let wait_for_stored = |expect_stored: bool| {
+        let store = store.clone();
+        let store_key = store_key.clone();
+        async move {
+            for _ in 0..100 {
+                if store.has(store_key.as_str()).await?.is_some() == expect_stored {
+                    return Ok(());
+                }
+                tokio::task::yield_now().await;
+            }
+            Err(make_err!(
+                Code::Internal,
+                "Expected completed action to be stored: {expect_stored}"
+            ))
+        }
+    };
+    wait_for_stored(true).await?;
+
+    now_s.store(NOW_TIME + RETAIN_COMPLETED_FOR_S, Ordering::Release);
+    scheduler.clean_recently_completed_actions().await;
+    wait_for_stored(false).await?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn update_action_with_wrong_worker_id_errors_test() -> Result<(), Error> {
+    let good_worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let rogue_worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, good_worker_id, PlatformProperties::default()).await?;
+    let insert_timestamp = make_system_time(1);
+    let mut client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp,
+    )
+    .await?;
+
+    {
+        // Other tests check full data. We only care if we got StartAction.
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+        // Other tests check full data. We only care if client thinks we are Executing.
+        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
+    }
+
+    let action_info_hash_key = ActionInfoHashKey {
+        instance_name: INSTANCE_NAME.to_string(),
+        digest_function: DigestHasherFunc::Sha256,
+        digest: action_digest,
+        salt: 0,
+    };
+    let action_result = ActionResult {
+        output_files: Vec::default(),
+        output_folders: Vec::default(),
+        output_file_symlinks: Vec::default(),
+        output_directory_symlinks: Vec::default(),
+        exit_code: 0,
+        stdout_digest: DigestInfo::new([6u8; 32], 19),
+        stderr_digest: DigestInfo::new([7u8; 32], 20),
+        execution_metadata: ExecutionMetadata {
+            worker: good_worker_id.to_string(),
+            queued_timestamp: make_system_time(5),
+            worker_start_timestamp: make_system_time(6),
+            worker_completed_timestamp: make_system_time(7),
+            input_fetch_start_timestamp: make_system_time(8),
+            input_fetch_completed_timestamp: make_system_time(9),
+            execution_start_timestamp: make_system_time(10),
+            execution_completed_timestamp: make_system_time(11),
+            output_upload_start_timestamp: make_system_time(12),
+            output_upload_completed_timestamp: make_system_time(13),
+        },
+        server_logs: HashMap::default(),
+        error: None,
+        message: String::new(),
+    };
+    let update_action_result = scheduler
+        .update_action(
+            &rogue_worker_id,
+            action_info_hash_key,
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await;
+
+    {
+        const EXPECTED_ERR: &str =
+            "Got a result from a worker that should not be running the action";
+        // Our request should have sent an error back.
+        assert!(
+            update_action_result.is_err(),
+            "Expected error, got: {:?}",
+            &update_action_result
+        );
+        let err = update_action_result.unwrap_err();
+        assert!(
+            err.to_string().contains(EXPECTED_ERR),
+            "Error should contain '{EXPECTED_ERR}', got: {err:?}",
+        );
+    }
+    {
+        // Ensure client did not get notified.
+        assert_eq!(
+            client_rx.has_changed().unwrap(),
+            false,
+            "Client should not have been notified of event"
+        );
+    }
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn does_not_crash_if_operation_joined_then_relaunched() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            match_cooldown_ms: Some(0),
+            ..Default::default()
+        },
+        || async move {},
+    );
+    let action_digest = DigestInfo::new([99u8; 32], 512);
+
+    let unique_qualifier = ActionInfoHashKey {
+        instance_name: "".to_string(),
+        digest: DigestInfo::zero_digest(),
+        digest_function: DigestHasherFunc::Sha256,
+        salt: 0,
+    };
+    let id = OperationId::new(unique_qualifier);
+    let mut expected_action_state = ActionState {
+        id,
         stage: ActionStage::Executing,
     };
 
-    let execution_request_for_worker = UpdateForWorker {
-        update: Some(update_for_worker::Update::StartAction(StartExecute {
-            execute_request: Some(ExecuteRequest {
-                instance_name: INSTANCE_NAME.to_string(),
-                skip_cache_lookup: true,
-                action_digest: Some(action_digest.into()),
-                digest_function: digest_function::Value::Sha256.into(),
-                ..Default::default()
-            }),
-            salt: 0,
-            queued_timestamp: Some(insert_timestamp.into()),
-        })),
+    let insert_timestamp = make_system_time(1);
+    let mut client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        insert_timestamp,
+    )
+    .await?;
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+
+    {
+        // Worker should have been sent an execute command.
+        let expected_msg_for_worker = UpdateForWorker {
+            update: Some(update_for_worker::Update::StartAction(StartExecute {
+                execute_request: Some(ExecuteRequest {
+                    instance_name: INSTANCE_NAME.to_string(),
+                    skip_cache_lookup: true,
+                    action_digest: Some(action_digest.into()),
+                    digest_function: digest_function::Value::Sha256.into(),
+                    ..Default::default()
+                }),
+                salt: 0,
+                queued_timestamp: Some(insert_timestamp.into()),
+            })),
+        };
+        let msg_for_worker = rx_from_worker.recv().await.unwrap();
+        assert_eq!(msg_for_worker, expected_msg_for_worker);
+    }
+
+    {
+        // Client should get notification saying it's being executed.
+        let action_state = client_rx.borrow_and_update();
+        // We now know the name of the action so populate it.
+        expected_action_state.id = action_state.id.clone();
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+
+    let action_result = ActionResult {
+        output_files: Vec::default(),
+        output_folders: Vec::default(),
+        output_directory_symlinks: Vec::default(),
+        output_file_symlinks: Vec::default(),
+        exit_code: Default::default(),
+        stdout_digest: DigestInfo::new([1u8; 32], 512),
+        stderr_digest: DigestInfo::new([2u8; 32], 512),
+        execution_metadata: ExecutionMetadata {
+            worker: String::new(),
+            queued_timestamp: SystemTime::UNIX_EPOCH,
+            worker_start_timestamp: SystemTime::UNIX_EPOCH,
+            worker_completed_timestamp: SystemTime::UNIX_EPOCH,
+            input_fetch_start_timestamp: SystemTime::UNIX_EPOCH,
+            input_fetch_completed_timestamp: SystemTime::UNIX_EPOCH,
+            execution_start_timestamp: SystemTime::UNIX_EPOCH,
+            execution_completed_timestamp: SystemTime::UNIX_EPOCH,
+            output_upload_start_timestamp: SystemTime::UNIX_EPOCH,
+            output_upload_completed_timestamp: SystemTime::UNIX_EPOCH,
+        },
+        server_logs: HashMap::default(),
+        error: None,
+        message: String::new(),
+    };
+
+    scheduler
+        .update_action(
+            &worker_id,
+            ActionInfoHashKey {
+                instance_name: INSTANCE_NAME.to_string(),
+                digest_function: DigestHasherFunc::Sha256,
+                digest: action_digest,
+                salt: 0,
+            },
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await?;
+
+    {
+        // Action should now be executing.
+        expected_action_state.stage = ActionStage::Completed(action_result.clone());
+        assert_eq!(
+            client_rx.borrow_and_update().as_ref(),
+            &expected_action_state
+        );
+    }
+
+    // Now we need to ensure that if we schedule another execution of the same job it doesn't
+    // fail.
+
+    {
+        let insert_timestamp = make_system_time(1);
+        let mut client_rx = setup_action(
+            &scheduler,
+            action_digest,
+            PlatformProperties::default(),
+            insert_timestamp,
+        )
+        .await?;
+        // We didn't disconnect our worker, so it will have scheduled it to the worker.
+        expected_action_state.stage = ActionStage::Executing;
+        let action_state = client_rx.borrow_and_update();
+        // The name of the action changed (since it's a new action), so update it.
+        expected_action_state.id = action_state.id.clone();
+        assert_eq!(action_state.as_ref(), &expected_action_state);
+    }
+
+    Ok(())
+}
+
+/// This tests to ensure that platform property restrictions allow jobs to continue to run after
+/// a job finished on a specific worker (eg: restore platform properties).
+#[nativelink_test]
+async fn set_action_priority_reorders_queued_actions_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+
+    // The worker only has room for one action at a time.
+    let mut properties = HashMap::new();
+    properties.insert("prop1".to_string(), PlatformPropertyValue::Minimum(1));
+    let platform_properties = PlatformProperties { properties };
+
+    let make_action_info = |digest: DigestInfo, priority: i32| {
+        let mut action_info = make_base_action_info(make_system_time(1));
+        action_info.platform_properties = platform_properties.clone();
+        action_info.unique_qualifier.digest = digest;
+        action_info.priority = priority;
+        action_info
+    };
+    let high_action_info = make_action_info(DigestInfo::new([11u8; 32], 512), 5);
+    let low_action_info = make_action_info(DigestInfo::new([22u8; 32], 512), 1);
+    let low_unique_qualifier = low_action_info.unique_qualifier.clone();
+    let mut high_client_rx = scheduler.add_action(high_action_info).await?;
+    let mut low_client_rx = scheduler.add_action(low_action_info).await?;
+
+    // Promote the lower priority action above the other one.
+    scheduler
+        .set_action_priority(&low_unique_qualifier, 10)
+        .await?;
+
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, platform_properties.clone()).await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    assert_eq!(
+        low_client_rx.borrow_and_update().stage,
+        ActionStage::Executing
+    );
+    assert_eq!(
+        high_client_rx.borrow_and_update().stage,
+        ActionStage::Queued
+    );
+
+    // Running actions can not be reprioritized.
+    let err = scheduler
+        .set_action_priority(&low_unique_qualifier, 0)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, Code::FailedPrecondition, "{err:?}");
+    Ok(())
+}
+
+#[nativelink_test]
+async fn remove_operation_cancels_action_and_frees_worker_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+
+    // The worker only has room for one action at a time.
+    let mut properties = HashMap::new();
+    properties.insert("prop1".to_string(), PlatformPropertyValue::Minimum(1));
+    let platform_properties = PlatformProperties { properties };
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, platform_properties.clone()).await?;
+    let mut client1_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([11u8; 32], 512),
+        platform_properties.clone(),
+        make_system_time(1),
+    )
+    .await?;
+    let mut client2_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([22u8; 32], 512),
+        platform_properties.clone(),
+        make_system_time(2),
+    )
+    .await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    assert_eq!(client1_rx.borrow_and_update().stage, ActionStage::Executing);
+    assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Queued);
+
+    {
+        // Removing the queued action cancels it and closes its channel.
+        let operation_id = client2_rx.borrow().id.clone();
+        scheduler.remove_operation(operation_id.clone()).await?;
+        assert_eq!(
+            completed_action_error(&mut client2_rx).code,
+            Code::Cancelled
+        );
+        assert!(client2_rx.changed().await.is_err());
+        // It can only be removed once.
+        assert_eq!(
+            scheduler
+                .remove_operation(operation_id)
+                .await
+                .unwrap_err()
+                .code,
+            Code::NotFound
+        );
+    }
+    {
+        // Removing the running action kills it on the worker.
+        let operation_id = client1_rx.borrow().id.clone();
+        scheduler.remove_operation(operation_id).await?;
+        assert_eq!(
+            completed_action_error(&mut client1_rx).code,
+            Code::Cancelled
+        );
+        assert!(client1_rx.changed().await.is_err());
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::KillActionRequest(_)) => { /* Success */ }
+            v => panic!("Expected KillActionRequest, got : {v:?}"),
+        }
+    }
+    {
+        // The worker's resources are freed for a new action.
+        let mut client3_rx = setup_action(
+            &scheduler,
+            DigestInfo::new([33u8; 32], 512),
+            platform_properties,
+            make_system_time(3),
+        )
+        .await?;
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+        assert_eq!(client3_rx.borrow_and_update().stage, ActionStage::Executing);
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn run_two_jobs_on_same_worker_with_platform_properties_restrictions() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            match_cooldown_ms: Some(0),
+            ..Default::default()
+        },
+        || async move {},
+    );
+    let action_digest1 = DigestInfo::new([11u8; 32], 512);
+    let action_digest2 = DigestInfo::new([99u8; 32], 512);
+
+    let mut properties = HashMap::new();
+    properties.insert("prop1".to_string(), PlatformPropertyValue::Minimum(1));
+    let platform_properties = PlatformProperties { properties };
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, platform_properties.clone()).await?;
+    let insert_timestamp1 = make_system_time(1);
+    let mut client1_rx = setup_action(
+        &scheduler,
+        action_digest1,
+        platform_properties.clone(),
+        insert_timestamp1,
+    )
+    .await?;
+    let insert_timestamp2 = make_system_time(1);
+    let mut client2_rx = setup_action(
+        &scheduler,
+        action_digest2,
+        platform_properties,
+        insert_timestamp2,
+    )
+    .await?;
+
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    {
+        // First client should be in an Executing state.
+        assert_eq!(client1_rx.borrow_and_update().stage, ActionStage::Executing);
+        // Second client should be in a queued state.
+        assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Queued);
+    }
+
+    let action_result = ActionResult {
+        output_files: Vec::default(),
+        output_folders: Vec::default(),
+        output_file_symlinks: Vec::default(),
+        output_directory_symlinks: Vec::default(),
+        exit_code: 0,
+        stdout_digest: DigestInfo::new([6u8; 32], 19),
+        stderr_digest: DigestInfo::new([7u8; 32], 20),
+        execution_metadata: ExecutionMetadata {
+            worker: worker_id.to_string(),
+            queued_timestamp: make_system_time(5),
+            worker_start_timestamp: make_system_time(6),
+            worker_completed_timestamp: make_system_time(7),
+            input_fetch_start_timestamp: make_system_time(8),
+            input_fetch_completed_timestamp: make_system_time(9),
+            execution_start_timestamp: make_system_time(10),
+            execution_completed_timestamp: make_system_time(11),
+            output_upload_start_timestamp: make_system_time(12),
+            output_upload_completed_timestamp: make_system_time(13),
+        },
+        server_logs: HashMap::default(),
+        error: None,
+        message: String::new(),
     };
 
+    // Tell scheduler our first task is completed.
+    scheduler
+        .update_action(
+            &worker_id,
+            ActionInfoHashKey {
+                instance_name: INSTANCE_NAME.to_string(),
+                digest_function: DigestHasherFunc::Sha256,
+                digest: action_digest1,
+                salt: 0,
+            },
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await?;
+
+    // Ensure client did not get notified.
+    assert!(
+        client1_rx.changed().await.is_ok(),
+        "Client should have been notified of event"
+    );
+
     {
-        // Worker1 should now see execution request.
-        let msg_for_worker = rx_from_worker1.recv().await.unwrap();
-        assert_eq!(msg_for_worker, execution_request_for_worker);
+        // First action should now be completed.
+        let action_state = client1_rx.borrow_and_update();
+        let mut expected_action_state = ActionState {
+            // Name is a random string, so we ignore it and just make it the same.
+            id: action_state.id.clone(),
+            stage: ActionStage::Completed(action_result.clone()),
+        };
+        // We now know the name of the action so populate it.
+        expected_action_state.id.unique_qualifier = action_state.id.unique_qualifier.clone();
+        assert_eq!(action_state.as_ref(), &expected_action_state);
     }
 
+    // At this stage it should have added back any platform_properties and the next
+    // task should be executing on the same worker.
+
     {
-        // Client should get notification saying it's being executed.
-        let action_state = client_rx.borrow_and_update();
+        // Our second client should now executing.
+        match rx_from_worker.recv().await.unwrap().update {
+            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+            v => panic!("Expected StartAction, got : {v:?}"),
+        }
+        // Other tests check full data. We only care if client thinks we are Executing.
+        assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Executing);
+    }
+
+    // Tell scheduler our second task is completed.
+    scheduler
+        .update_action(
+            &worker_id,
+            ActionInfoHashKey {
+                instance_name: INSTANCE_NAME.to_string(),
+                digest_function: DigestHasherFunc::Sha256,
+                digest: action_digest2,
+                salt: 0,
+            },
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await?;
+
+    {
+        // Our second client should be notified it completed.
+        let action_state = client2_rx.borrow_and_update();
+        let mut expected_action_state = ActionState {
+            // Name is a random string, so we ignore it and just make it the same.
+            id: action_state.id.clone(),
+            stage: ActionStage::Completed(action_result.clone()),
+        };
         // We now know the name of the action so populate it.
-        expected_action_state.id = action_state.id.clone();
+        expected_action_state.id.unique_qualifier = action_state.id.unique_qualifier.clone();
         assert_eq!(action_state.as_ref(), &expected_action_state);
     }
 
-    // Keep worker 2 alive.
-    scheduler
-        .worker_keep_alive_received(&worker_id2, NOW_TIME + WORKER_TIMEOUT_S)
-        .await?;
-    // This should remove worker 1 (the one executing our job).
-    scheduler
-        .remove_timedout_workers(NOW_TIME + WORKER_TIMEOUT_S)
-        .await?;
-    tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+    Ok(())
+}
+
+/// This tests that actions are performed in the order they were queued.
+#[nativelink_test]
+async fn run_jobs_in_the_order_they_were_queued() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let action_digest1 = DigestInfo::new([11u8; 32], 512);
+    let action_digest2 = DigestInfo::new([99u8; 32], 512);
+
+    // Use property to restrict the worker to a single action at a time.
+    let mut properties = HashMap::new();
+    properties.insert("prop1".to_string(), PlatformPropertyValue::Minimum(1));
+    let platform_properties = PlatformProperties { properties };
+    // This is queued after the next one (even though it's placed in the map
+    // first), so it should execute second.
+    let insert_timestamp2 = make_system_time(2);
+    let mut client2_rx = setup_action(
+        &scheduler,
+        action_digest2,
+        platform_properties.clone(),
+        insert_timestamp2,
+    )
+    .await?;
+    let insert_timestamp1 = make_system_time(1);
+    let mut client1_rx = setup_action(
+        &scheduler,
+        action_digest1,
+        platform_properties.clone(),
+        insert_timestamp1,
+    )
+    .await?;
+
+    // Add the worker after the queue has been set up.
+    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, platform_properties).await?;
 
-    {
-        // Worker1 should have received a disconnect message.
-        let msg_for_worker = rx_from_worker1.recv().await.unwrap();
-        assert_eq!(
-            msg_for_worker,
-            UpdateForWorker {
-                update: Some(update_for_worker::Update::Disconnect(()))
-            }
-        );
-    }
-    {
-        // Client should get notification saying it's being executed.
-        let action_state = client_rx.borrow_and_update();
-        expected_action_state.stage = ActionStage::Executing;
-        assert_eq!(action_state.as_ref(), &expected_action_state);
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
     }
     {
-        // Worker2 should now see execution request.
-        let msg_for_worker = rx_from_worker2.recv().await.unwrap();
-        assert_eq!(msg_for_worker, execution_request_for_worker);
+        // First client should be in an Executing state.
+        assert_eq!(client1_rx.borrow_and_update().stage, ActionStage::Executing);
+        // Second client should be in a queued state.
+        assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Queued);
     }
 
     Ok(())
 }
 
 #[nativelink_test]
-async fn update_action_sends_completed_result_to_client_test() -> Result<(), Error> {
+async fn worker_retries_on_internal_error_and_fails_test() -> Result<(), Error> {
     let worker_id: WorkerId = WorkerId(Uuid::new_v4());
 
     let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler::default(),
+        &nativelink_config::schedulers::SimpleScheduler {
+            max_job_retries: 2,
+            ..Default::default()
+        },
         || async move {},
     );
     let action_digest = DigestInfo::new([99u8; 32], 512);
@@ -800,169 +2760,77 @@ async fn update_action_sends_completed_result_to_client_test() -> Result<(), Err
         digest: action_digest,
         salt: 0,
     };
-    let action_result = ActionResult {
-        output_files: vec![FileInfo {
-            name_or_path: NameOrPath::Name("hello".to_string()),
-            digest: DigestInfo::new([5u8; 32], 18),
-            is_executable: true,
-        }],
-        output_folders: vec![DirectoryInfo {
-            path: "123".to_string(),
-            tree_digest: DigestInfo::new([9u8; 32], 100),
-        }],
-        output_file_symlinks: vec![SymlinkInfo {
-            name_or_path: NameOrPath::Name("foo".to_string()),
-            target: "bar".to_string(),
-        }],
-        output_directory_symlinks: vec![SymlinkInfo {
-            name_or_path: NameOrPath::Name("foo2".to_string()),
-            target: "bar2".to_string(),
-        }],
-        exit_code: 0,
-        stdout_digest: DigestInfo::new([6u8; 32], 19),
-        stderr_digest: DigestInfo::new([7u8; 32], 20),
-        execution_metadata: ExecutionMetadata {
-            worker: worker_id.to_string(),
-            queued_timestamp: make_system_time(5),
-            worker_start_timestamp: make_system_time(6),
-            worker_completed_timestamp: make_system_time(7),
-            input_fetch_start_timestamp: make_system_time(8),
-            input_fetch_completed_timestamp: make_system_time(9),
-            execution_start_timestamp: make_system_time(10),
-            execution_completed_timestamp: make_system_time(11),
-            output_upload_start_timestamp: make_system_time(12),
-            output_upload_completed_timestamp: make_system_time(13),
-        },
-        server_logs: HashMap::default(),
-        error: None,
-        message: String::new(),
-    };
-    scheduler
+    let _ = scheduler
         .update_action(
             &worker_id,
-            action_info_hash_key,
-            Ok(ActionStage::Completed(action_result.clone())),
+            action_info_hash_key.clone(),
+            Err(make_err!(Code::Internal, "Some error")),
         )
-        .await?;
+        .await;
 
     {
-        // Client should get notification saying it has been completed.
+        // Client should get notification saying it has been queued again.
         let action_state = client_rx.borrow_and_update();
         let expected_action_state = ActionState {
             // Name is a random string, so we ignore it and just make it the same.
             id: action_state.id.clone(),
-            stage: ActionStage::Completed(action_result),
+            stage: ActionStage::Queued,
         };
         assert_eq!(action_state.as_ref(), &expected_action_state);
     }
-    {
-        // Update info for the action should now be closed (notification happens through Err).
-        let result = client_rx.changed().await;
-        assert!(
-            result.is_err(),
-            "Expected result to be an error : {result:?}"
-        );
-    }
-
-    Ok(())
-}
-
-#[nativelink_test]
-async fn update_action_sends_completed_result_after_disconnect() -> Result<(), Error> {
-    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
-
-    let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler::default(),
-        || async move {},
-    );
-    let action_digest = DigestInfo::new([99u8; 32], 512);
 
+    // Now connect a new worker and it should pickup the action.
     let mut rx_from_worker =
         setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
-    let insert_timestamp = make_system_time(1);
-    let client_rx = setup_action(
-        &scheduler,
-        action_digest,
-        PlatformProperties::default(),
-        insert_timestamp,
-    )
-    .await?;
-
-    // Drop our receiver and don't reconnect until completed.
-    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
-    drop(client_rx);
-
     {
         // Other tests check full data. We only care if we got StartAction.
         match rx_from_worker.recv().await.unwrap().update {
             Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
             v => panic!("Expected StartAction, got : {v:?}"),
         }
+        // Other tests check full data. We only care if client thinks we are Executing.
+        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
     }
 
-    let action_info_hash_key = ActionInfoHashKey {
-        instance_name: INSTANCE_NAME.to_string(),
-        digest_function: DigestHasherFunc::Sha256,
-        digest: action_digest,
-        salt: 0,
-    };
-    let action_result = ActionResult {
-        output_files: vec![FileInfo {
-            name_or_path: NameOrPath::Name("hello".to_string()),
-            digest: DigestInfo::new([5u8; 32], 18),
-            is_executable: true,
-        }],
-        output_folders: vec![DirectoryInfo {
-            path: "123".to_string(),
-            tree_digest: DigestInfo::new([9u8; 32], 100),
-        }],
-        output_file_symlinks: vec![SymlinkInfo {
-            name_or_path: NameOrPath::Name("foo".to_string()),
-            target: "bar".to_string(),
-        }],
-        output_directory_symlinks: vec![SymlinkInfo {
-            name_or_path: NameOrPath::Name("foo2".to_string()),
-            target: "bar2".to_string(),
-        }],
-        exit_code: 0,
-        stdout_digest: DigestInfo::new([6u8; 32], 19),
-        stderr_digest: DigestInfo::new([7u8; 32], 20),
-        execution_metadata: ExecutionMetadata {
-            worker: worker_id.to_string(),
-            queued_timestamp: make_system_time(5),
-            worker_start_timestamp: make_system_time(6),
-            worker_completed_timestamp: make_system_time(7),
-            input_fetch_start_timestamp: make_system_time(8),
-            input_fetch_completed_timestamp: make_system_time(9),
-            execution_start_timestamp: make_system_time(10),
-            execution_completed_timestamp: make_system_time(11),
-            output_upload_start_timestamp: make_system_time(12),
-            output_upload_completed_timestamp: make_system_time(13),
-        },
-        server_logs: HashMap::default(),
-        error: None,
-        message: String::new(),
-    };
-    scheduler
-        .update_action(
-            &worker_id,
-            action_info_hash_key,
-            Ok(ActionStage::Completed(action_result.clone())),
-        )
-        .await?;
+    let err = make_err!(Code::Internal, "Some error");
+    // Send internal error from worker again.
+    let _ = scheduler
+        .update_action(&worker_id, action_info_hash_key, Err(err.clone()))
+        .await;
 
-    // Now look up a channel after the action has completed.
-    let mut client_rx = scheduler
-        .find_existing_action(&unique_qualifier)
-        .await
-        .err_tip(|| "Action not found")?;
     {
-        // Client should get notification saying it has been completed.
+        // Client should get notification saying it has been queued again.
         let action_state = client_rx.borrow_and_update();
         let expected_action_state = ActionState {
             // Name is a random string, so we ignore it and just make it the same.
             id: action_state.id.clone(),
-            stage: ActionStage::Completed(action_result),
+            stage: ActionStage::Completed(ActionResult {
+                output_files: Vec::default(),
+                output_folders: Vec::default(),
+                output_file_symlinks: Vec::default(),
+                output_directory_symlinks: Vec::default(),
+                exit_code: INTERNAL_ERROR_EXIT_CODE,
+                stdout_digest: DigestInfo::zero_digest(),
+                stderr_digest: DigestInfo::zero_digest(),
+                execution_metadata: ExecutionMetadata {
+                    worker: worker_id.to_string(),
+                    queued_timestamp: SystemTime::UNIX_EPOCH,
+                    worker_start_timestamp: SystemTime::UNIX_EPOCH,
+                    worker_completed_timestamp: SystemTime::UNIX_EPOCH,
+                    input_fetch_start_timestamp: SystemTime::UNIX_EPOCH,
+                    input_fetch_completed_timestamp: SystemTime::UNIX_EPOCH,
+                    execution_start_timestamp: SystemTime::UNIX_EPOCH,
+                    execution_completed_timestamp: SystemTime::UNIX_EPOCH,
+                    output_upload_start_timestamp: SystemTime::UNIX_EPOCH,
+                    output_upload_completed_timestamp: SystemTime::UNIX_EPOCH,
+                },
+                server_logs: HashMap::default(),
+                error: Some(err.merge(make_err!(
+                    Code::Internal,
+                    "Job cancelled because it attempted to execute too many times and failed"
+                ))),
+                message: String::new(),
+            }),
         };
         assert_eq!(action_state.as_ref(), &expected_action_state);
     }
@@ -971,9 +2839,48 @@ async fn update_action_sends_completed_result_after_disconnect() -> Result<(), E
 }
 
 #[nativelink_test]
-async fn update_action_with_wrong_worker_id_errors_test() -> Result<(), Error> {
-    let good_worker_id: WorkerId = WorkerId(Uuid::new_v4());
-    let rogue_worker_id: WorkerId = WorkerId(Uuid::new_v4());
+async fn ensure_scheduler_drops_inner_spawn() -> Result<(), Error> {
+    struct DropChecker {
+        dropped: Arc<AtomicBool>,
+    }
+    impl Drop for DropChecker {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let drop_checker = Arc::new(DropChecker {
+        dropped: dropped.clone(),
+    });
+
+    // Since the inner spawn owns this callback, we can use the callback to know if the
+    // inner spawn was dropped because our callback would be dropped, which dropps our
+    // DropChecker.
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        move || {
+            // This will ensure dropping happens if this function is ever dropped.
+            let _drop_checker = drop_checker.clone();
+            async move {}
+        },
+    );
+    assert_eq!(dropped.load(Ordering::Relaxed), false);
+
+    drop(scheduler);
+    tokio::task::yield_now().await; // The drop may happen in a different task.
+
+    // Ensure our callback was dropped.
+    assert_eq!(dropped.load(Ordering::Relaxed), true);
+
+    Ok(())
+}
+
+/// Regression test for: https://github.com/TraceMachina/nativelink/issues/257.
+#[nativelink_test]
+async fn ensure_task_or_worker_change_notification_received_test() -> Result<(), Error> {
+    let worker_id1: WorkerId = WorkerId(Uuid::new_v4());
+    let worker_id2: WorkerId = WorkerId(Uuid::new_v4());
 
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler::default(),
@@ -981,20 +2888,22 @@ async fn update_action_with_wrong_worker_id_errors_test() -> Result<(), Error> {
     );
     let action_digest = DigestInfo::new([99u8; 32], 512);
 
-    let mut rx_from_worker =
-        setup_new_worker(&scheduler, good_worker_id, PlatformProperties::default()).await?;
-    let insert_timestamp = make_system_time(1);
+    let mut rx_from_worker1 =
+        setup_new_worker(&scheduler, worker_id1, PlatformProperties::default()).await?;
     let mut client_rx = setup_action(
         &scheduler,
         action_digest,
         PlatformProperties::default(),
-        insert_timestamp,
+        make_system_time(1),
     )
     .await?;
 
+    let mut rx_from_worker2 =
+        setup_new_worker(&scheduler, worker_id2, PlatformProperties::default()).await?;
+
     {
         // Other tests check full data. We only care if we got StartAction.
-        match rx_from_worker.recv().await.unwrap().update {
+        match rx_from_worker1.recv().await.unwrap().update {
             Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
             v => panic!("Expected StartAction, got : {v:?}"),
         }
@@ -1002,615 +2911,823 @@ async fn update_action_with_wrong_worker_id_errors_test() -> Result<(), Error> {
         assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
     }
 
-    let action_info_hash_key = ActionInfoHashKey {
-        instance_name: INSTANCE_NAME.to_string(),
-        digest_function: DigestHasherFunc::Sha256,
-        digest: action_digest,
-        salt: 0,
-    };
-    let action_result = ActionResult {
-        output_files: Vec::default(),
-        output_folders: Vec::default(),
-        output_file_symlinks: Vec::default(),
-        output_directory_symlinks: Vec::default(),
-        exit_code: 0,
-        stdout_digest: DigestInfo::new([6u8; 32], 19),
-        stderr_digest: DigestInfo::new([7u8; 32], 20),
-        execution_metadata: ExecutionMetadata {
-            worker: good_worker_id.to_string(),
-            queued_timestamp: make_system_time(5),
-            worker_start_timestamp: make_system_time(6),
-            worker_completed_timestamp: make_system_time(7),
-            input_fetch_start_timestamp: make_system_time(8),
-            input_fetch_completed_timestamp: make_system_time(9),
-            execution_start_timestamp: make_system_time(10),
-            execution_completed_timestamp: make_system_time(11),
-            output_upload_start_timestamp: make_system_time(12),
-            output_upload_completed_timestamp: make_system_time(13),
-        },
-        server_logs: HashMap::default(),
-        error: None,
-        message: String::new(),
-    };
-    let update_action_result = scheduler
+    let _ = scheduler
         .update_action(
-            &rogue_worker_id,
-            action_info_hash_key,
-            Ok(ActionStage::Completed(action_result.clone())),
+            &worker_id1,
+            ActionInfoHashKey {
+                instance_name: INSTANCE_NAME.to_string(),
+                digest_function: DigestHasherFunc::Sha256,
+                digest: action_digest,
+                salt: 0,
+            },
+            Err(make_err!(Code::NotFound, "Some error")),
         )
         .await;
 
+    tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+
+    // Now connect a new worker and it should pickup the action.
     {
-        const EXPECTED_ERR: &str =
-            "Got a result from a worker that should not be running the action";
-        // Our request should have sent an error back.
-        assert!(
-            update_action_result.is_err(),
-            "Expected error, got: {:?}",
-            &update_action_result
-        );
-        let err = update_action_result.unwrap_err();
-        assert!(
-            err.to_string().contains(EXPECTED_ERR),
-            "Error should contain '{EXPECTED_ERR}', got: {err:?}",
-        );
-    }
-    {
-        // Ensure client did not get notified.
-        assert_eq!(
-            client_rx.has_changed().unwrap(),
-            false,
-            "Client should not have been notified of event"
-        );
+        // Other tests check full data. We only care if we got StartAction.
+        rx_from_worker2
+            .recv()
+            .await
+            .err_tip(|| "worker went away")?;
+        // Other tests check full data. We only care if client thinks we are Executing.
+        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
     }
 
     Ok(())
 }
 
 #[nativelink_test]
-async fn does_not_crash_if_operation_joined_then_relaunched() -> Result<(), Error> {
-    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+async fn admission_controller_rejects_large_actions_test() -> Result<(), Error> {
+    const MAX_INPUT_ROOT_SIZE: i64 = 1024;
+    struct InputRootSizeLimit;
+
+    #[async_trait]
+    impl AdmissionController for InputRootSizeLimit {
+        async fn admit(&self, action_info: &ActionInfo) -> Result<(), Error> {
+            if action_info.input_root_digest.size_bytes > MAX_INPUT_ROOT_SIZE {
+                return Err(make_err!(
+                    Code::ResourceExhausted,
+                    "Input root of {} bytes exceeds limit",
+                    action_info.input_root_digest.size_bytes
+                ));
+            }
+            Ok(())
+        }
+    }
 
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler::default(),
         || async move {},
-    );
-    let action_digest = DigestInfo::new([99u8; 32], 512);
+    )
+    .with_admission_controller(Arc::new(InputRootSizeLimit));
 
-    let unique_qualifier = ActionInfoHashKey {
-        instance_name: "".to_string(),
-        digest: DigestInfo::zero_digest(),
-        digest_function: DigestHasherFunc::Sha256,
-        salt: 0,
-    };
-    let id = OperationId::new(unique_qualifier);
-    let mut expected_action_state = ActionState {
-        id,
-        stage: ActionStage::Executing,
-    };
+    let mut large_action_info = make_base_action_info(make_system_time(1));
+    large_action_info.unique_qualifier.digest = DigestInfo::new([99u8; 32], 512);
+    large_action_info.input_root_digest = DigestInfo::new([1u8; 32], MAX_INPUT_ROOT_SIZE + 1);
+    let err = scheduler
+        .add_action(large_action_info)
+        .await
+        .expect_err("Expected large action to be rejected");
+    assert_eq!(err.code, Code::ResourceExhausted);
+    assert!(
+        err.to_string()
+            .contains("Input root of 1025 bytes exceeds limit"),
+        "Unexpected error: {err:?}"
+    );
 
-    let insert_timestamp = make_system_time(1);
-    let mut client_rx = setup_action(
-        &scheduler,
-        action_digest,
-        PlatformProperties::default(),
-        insert_timestamp,
-    )
-    .await?;
-    let mut rx_from_worker =
-        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let mut small_action_info = make_base_action_info(make_system_time(2));
+    small_action_info.unique_qualifier.digest = DigestInfo::new([88u8; 32], 512);
+    small_action_info.input_root_digest = DigestInfo::new([2u8; 32], MAX_INPUT_ROOT_SIZE);
+    let client_rx = scheduler.add_action(small_action_info).await?;
+    assert_eq!(client_rx.borrow().stage, ActionStage::Queued);
 
-    {
-        // Worker should have been sent an execute command.
-        let expected_msg_for_worker = UpdateForWorker {
-            update: Some(update_for_worker::Update::StartAction(StartExecute {
-                execute_request: Some(ExecuteRequest {
-                    instance_name: INSTANCE_NAME.to_string(),
-                    skip_cache_lookup: true,
-                    action_digest: Some(action_digest.into()),
-                    digest_function: digest_function::Value::Sha256.into(),
-                    ..Default::default()
-                }),
-                salt: 0,
-                queued_timestamp: Some(insert_timestamp.into()),
-            })),
-        };
-        let msg_for_worker = rx_from_worker.recv().await.unwrap();
-        assert_eq!(msg_for_worker, expected_msg_for_worker);
-    }
+    let snapshot = scheduler.dump_state_at(make_system_time(3)).await;
+    assert_eq!(
+        snapshot.queued_actions.len(),
+        1,
+        "Only the small action should be queued"
+    );
+    Ok(())
+}
 
-    {
-        // Client should get notification saying it's being executed.
-        let action_state = client_rx.borrow_and_update();
-        // We now know the name of the action so populate it.
-        expected_action_state.id = action_state.id.clone();
-        assert_eq!(action_state.as_ref(), &expected_action_state);
+#[nativelink_test]
+async fn admission_controller_runs_without_scheduler_lock_test() -> Result<(), Error> {
+    /// Admits actions only once released.
+    struct WaitForRelease(Arc<Notify>);
+
+    #[async_trait]
+    impl AdmissionController for WaitForRelease {
+        async fn admit(&self, _action_info: &ActionInfo) -> Result<(), Error> {
+            self.0.notified().await;
+            Ok(())
+        }
     }
 
-    let action_result = ActionResult {
-        output_files: Vec::default(),
-        output_folders: Vec::default(),
-        output_directory_symlinks: Vec::default(),
-        output_file_symlinks: Vec::default(),
-        exit_code: Default::default(),
-        stdout_digest: DigestInfo::new([1u8; 32], 512),
-        stderr_digest: DigestInfo::new([2u8; 32], 512),
-        execution_metadata: ExecutionMetadata {
-            worker: String::new(),
-            queued_timestamp: SystemTime::UNIX_EPOCH,
-            worker_start_timestamp: SystemTime::UNIX_EPOCH,
-            worker_completed_timestamp: SystemTime::UNIX_EPOCH,
-            input_fetch_start_timestamp: SystemTime::UNIX_EPOCH,
-            input_fetch_completed_timestamp: SystemTime::UNIX_EPOCH,
-            execution_start_timestamp: SystemTime::UNIX_EPOCH,
-            execution_completed_timestamp: SystemTime::UNIX_EPOCH,
-            output_upload_start_timestamp: SystemTime::UNIX_EPOCH,
-            output_upload_completed_timestamp: SystemTime::UNIX_EPOCH,
+    let release = Arc::new(Notify::new());
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    )
+    .with_admission_controller(Arc::new(WaitForRelease(release.clone())));
+
+    let (add_action_result, snapshot_result) = tokio::join!(
+        scheduler.add_action(make_base_action_info(make_system_time(1))),
+        async {
+            // The scheduler stays usable while an admission decision is
+            // pending.
+            let snapshot_result = tokio::time::timeout(
+                Duration::from_secs(5),
+                scheduler.dump_state_at(make_system_time(2)),
+            )
+            .await;
+            release.notify_one();
+            snapshot_result
         },
-        server_logs: HashMap::default(),
-        error: None,
-        message: String::new(),
-    };
-
-    scheduler
-        .update_action(
-            &worker_id,
-            ActionInfoHashKey {
-                instance_name: INSTANCE_NAME.to_string(),
-                digest_function: DigestHasherFunc::Sha256,
-                digest: action_digest,
-                salt: 0,
-            },
-            Ok(ActionStage::Completed(action_result.clone())),
+    );
+    let snapshot = snapshot_result.map_err(|_| {
+        make_err!(
+            Code::DeadlineExceeded,
+            "Scheduler was locked while the action was being admitted"
         )
-        .await?;
+    })?;
+    assert_eq!(snapshot.queued_actions.len(), 0);
+    assert_eq!(add_action_result?.borrow().stage, ActionStage::Queued);
+    Ok(())
+}
 
-    {
-        // Action should now be executing.
-        expected_action_state.stage = ActionStage::Completed(action_result.clone());
-        assert_eq!(
-            client_rx.borrow_and_update().as_ref(),
-            &expected_action_state
+#[nativelink_test]
+async fn max_queued_actions_rejects_new_actions_when_full_test() -> Result<(), Error> {
+    const MAX_QUEUED_ACTIONS: usize = 2;
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            max_queued_actions: Some(MAX_QUEUED_ACTIONS),
+            ..Default::default()
+        },
+        || async move {},
+    );
+
+    // No workers are connected, so every action stays queued.
+    let mut client_rxs = Vec::new();
+    for i in 0..MAX_QUEUED_ACTIONS {
+        client_rxs.push(
+            setup_action(
+                &scheduler,
+                DigestInfo::new([i as u8; 32], 512),
+                PlatformProperties::default(),
+                make_system_time(i as u64),
+            )
+            .await?,
         );
     }
 
-    // Now we need to ensure that if we schedule another execution of the same job it doesn't
-    // fail.
+    let err = setup_action(
+        &scheduler,
+        DigestInfo::new([99u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(10),
+    )
+    .await
+    .expect_err("Expected action to be rejected while the queue is full");
+    assert_eq!(err.code, Code::ResourceExhausted);
 
-    {
-        let insert_timestamp = make_system_time(1);
-        let mut client_rx = setup_action(
-            &scheduler,
-            action_digest,
-            PlatformProperties::default(),
-            insert_timestamp,
-        )
-        .await?;
-        // We didn't disconnect our worker, so it will have scheduled it to the worker.
-        expected_action_state.stage = ActionStage::Executing;
-        let action_state = client_rx.borrow_and_update();
-        // The name of the action changed (since it's a new action), so update it.
-        expected_action_state.id = action_state.id.clone();
-        assert_eq!(action_state.as_ref(), &expected_action_state);
-    }
+    // Joining an action that is already queued is still allowed.
+    let duplicate_client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([0u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(11),
+    )
+    .await?;
+    assert_eq!(
+        duplicate_client_rx.borrow().id.unique_qualifier,
+        client_rxs[0].borrow().id.unique_qualifier
+    );
+    assert_eq!(duplicate_client_rx.borrow().stage, ActionStage::Queued);
+
+    let snapshot = scheduler.dump_state_at(make_system_time(12)).await;
+    assert_eq!(snapshot.queued_actions.len(), MAX_QUEUED_ACTIONS);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn worker_metrics_grouped_by_tags_test() -> Result<(), Error> {
+    let scheduler = Arc::new(SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_metrics_tags: vec!["region".to_string()],
+            ..Default::default()
+        },
+        || async move {},
+    ));
+    let make_properties = |region: &str| {
+        let mut properties = PlatformProperties::default();
+        properties.properties.insert(
+            "region".to_string(),
+            PlatformPropertyValue::Exact(region.to_string()),
+        );
+        properties
+    };
+    let worker_id1 = WorkerId(Uuid::new_v4());
+    let _rx1 = setup_new_worker(&scheduler, worker_id1, make_properties("us")).await?;
+    let _rx2 =
+        setup_new_worker(&scheduler, WorkerId(Uuid::new_v4()), make_properties("us")).await?;
+    let _rx3 =
+        setup_new_worker(&scheduler, WorkerId(Uuid::new_v4()), make_properties("eu")).await?;
+
+    let mut registry = <Registry>::default();
+    ActionScheduler::register_metrics(scheduler.clone(), &mut registry);
+    let mut metrics = String::new();
+    prometheus_client::encoding::text::encode(&mut metrics, &registry).unwrap();
 
+    assert!(
+        metrics.contains("workers_count{region=\"us\"} 2\n"),
+        "Expected workers grouped by region, got:\n{metrics}"
+    );
+    assert!(
+        metrics.contains("workers_count{region=\"eu\"} 1\n"),
+        "Expected workers grouped by region, got:\n{metrics}"
+    );
+    assert!(
+        !metrics.contains(&worker_id1.to_string()),
+        "Expected no per worker metrics, got:\n{metrics}"
+    );
     Ok(())
 }
 
-/// This tests to ensure that platform property restrictions allow jobs to continue to run after
-/// a job finished on a specific worker (eg: restore platform properties).
 #[nativelink_test]
-async fn run_two_jobs_on_same_worker_with_platform_properties_restrictions() -> Result<(), Error> {
+async fn update_action_normalizes_execution_metadata_timestamps_test() -> Result<(), Error> {
     let worker_id: WorkerId = WorkerId(Uuid::new_v4());
 
     let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler::default(),
+        &nativelink_config::schedulers::SimpleScheduler {
+            execution_metadata_max_clock_skew_s: 60,
+            ..Default::default()
+        },
         || async move {},
     );
-    let action_digest1 = DigestInfo::new([11u8; 32], 512);
-    let action_digest2 = DigestInfo::new([99u8; 32], 512);
+    let action_digest = DigestInfo::new([99u8; 32], 512);
 
-    let mut properties = HashMap::new();
-    properties.insert("prop1".to_string(), PlatformPropertyValue::Minimum(1));
-    let platform_properties = PlatformProperties { properties };
     let mut rx_from_worker =
-        setup_new_worker(&scheduler, worker_id, platform_properties.clone()).await?;
-    let insert_timestamp1 = make_system_time(1);
-    let mut client1_rx = setup_action(
-        &scheduler,
-        action_digest1,
-        platform_properties.clone(),
-        insert_timestamp1,
-    )
-    .await?;
-    let insert_timestamp2 = make_system_time(1);
-    let mut client2_rx = setup_action(
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let mut client_rx = setup_action(
         &scheduler,
-        action_digest2,
-        platform_properties,
-        insert_timestamp2,
+        action_digest,
+        PlatformProperties::default(),
+        make_system_time(1),
     )
     .await?;
-
     match rx_from_worker.recv().await.unwrap().update {
         Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
         v => panic!("Expected StartAction, got : {v:?}"),
     }
-    {
-        // First client should be in an Executing state.
-        assert_eq!(client1_rx.borrow_and_update().stage, ActionStage::Executing);
-        // Second client should be in a queued state.
-        assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Queued);
-    }
 
+    let action_info_hash_key = ActionInfoHashKey {
+        instance_name: INSTANCE_NAME.to_string(),
+        digest_function: DigestHasherFunc::Sha256,
+        digest: action_digest,
+        salt: 0,
+    };
     let action_result = ActionResult {
-        output_files: Vec::default(),
-        output_folders: Vec::default(),
-        output_file_symlinks: Vec::default(),
-        output_directory_symlinks: Vec::default(),
-        exit_code: 0,
-        stdout_digest: DigestInfo::new([6u8; 32], 19),
-        stderr_digest: DigestInfo::new([7u8; 32], 20),
         execution_metadata: ExecutionMetadata {
             worker: worker_id.to_string(),
             queued_timestamp: make_system_time(5),
             worker_start_timestamp: make_system_time(6),
-            worker_completed_timestamp: make_system_time(7),
             input_fetch_start_timestamp: make_system_time(8),
-            input_fetch_completed_timestamp: make_system_time(9),
+            // Out of order, the worker clock jumped backwards.
+            input_fetch_completed_timestamp: make_system_time(7),
             execution_start_timestamp: make_system_time(10),
             execution_completed_timestamp: make_system_time(11),
             output_upload_start_timestamp: make_system_time(12),
             output_upload_completed_timestamp: make_system_time(13),
+            worker_completed_timestamp: make_system_time(14),
         },
-        server_logs: HashMap::default(),
-        error: None,
-        message: String::new(),
+        ..ActionResult::default()
     };
-
-    // Tell scheduler our first task is completed.
     scheduler
         .update_action(
             &worker_id,
-            ActionInfoHashKey {
-                instance_name: INSTANCE_NAME.to_string(),
-                digest_function: DigestHasherFunc::Sha256,
-                digest: action_digest1,
-                salt: 0,
-            },
+            action_info_hash_key,
             Ok(ActionStage::Completed(action_result.clone())),
         )
         .await?;
 
-    // Ensure client did not get notified.
-    assert!(
-        client1_rx.changed().await.is_ok(),
-        "Client should have been notified of event"
+    let action_state = client_rx.borrow_and_update();
+    let ActionStage::Completed(completed_result) = &action_state.stage else {
+        panic!("Expected action to be completed, got {action_state:?}");
+    };
+    assert_eq!(
+        completed_result.execution_metadata,
+        ExecutionMetadata {
+            input_fetch_completed_timestamp: make_system_time(8),
+            ..action_result.execution_metadata
+        }
     );
+    Ok(())
+}
 
-    {
-        // First action should now be completed.
-        let action_state = client1_rx.borrow_and_update();
-        let mut expected_action_state = ActionState {
-            // Name is a random string, so we ignore it and just make it the same.
-            id: action_state.id.clone(),
-            stage: ActionStage::Completed(action_result.clone()),
-        };
-        // We now know the name of the action so populate it.
-        expected_action_state.id.unique_qualifier = action_state.id.unique_qualifier.clone();
-        assert_eq!(action_state.as_ref(), &expected_action_state);
-    }
-
-    // At this stage it should have added back any platform_properties and the next
-    // task should be executing on the same worker.
+#[nativelink_test]
+async fn orphaned_result_is_salvaged_into_ac_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
+    let action_info_hash_key = ActionInfoHashKey {
+        instance_name: INSTANCE_NAME.to_string(),
+        digest_function: DigestHasherFunc::Sha256,
+        digest: DigestInfo::new([99u8; 32], 512),
+        salt: 0,
+    };
+    let action_result = ActionResult {
+        stdout_digest: DigestInfo::new([1u8; 32], 10),
+        exit_code: 0,
+        ..ActionResult::default()
+    };
 
-    {
-        // Our second client should now executing.
-        match rx_from_worker.recv().await.unwrap().update {
-            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
-            v => panic!("Expected StartAction, got : {v:?}"),
-        }
-        // Other tests check full data. We only care if client thinks we are Executing.
-        assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Executing);
-    }
+    // By default the result of an unknown action is rejected.
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    );
+    let _rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    assert!(scheduler
+        .update_action(
+            &worker_id,
+            action_info_hash_key.clone(),
+            Ok(ActionStage::Completed(action_result.clone())),
+        )
+        .await
+        .is_err());
 
-    // Tell scheduler our second task is completed.
+    // In salvage mode the result is written to the AC.
+    let ac_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        || async move {},
+    )
+    .with_orphaned_result_ac_store(ac_store.clone());
+    let _rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
     scheduler
         .update_action(
             &worker_id,
-            ActionInfoHashKey {
-                instance_name: INSTANCE_NAME.to_string(),
-                digest_function: DigestHasherFunc::Sha256,
-                digest: action_digest2,
-                salt: 0,
-            },
+            action_info_hash_key.clone(),
             Ok(ActionStage::Completed(action_result.clone())),
         )
         .await?;
+    let stored_result =
+        get_and_decode_digest::<ProtoActionResult>(&ac_store, action_info_hash_key.digest.into())
+            .await?;
+    assert_eq!(stored_result, ProtoActionResult::from(action_result));
+    Ok(())
+}
 
-    {
-        // Our second client should be notified it completed.
-        let action_state = client2_rx.borrow_and_update();
-        let mut expected_action_state = ActionState {
-            // Name is a random string, so we ignore it and just make it the same.
-            id: action_state.id.clone(),
-            stage: ActionStage::Completed(action_result.clone()),
-        };
-        // We now know the name of the action so populate it.
-        expected_action_state.id.unique_qualifier = action_state.id.unique_qualifier.clone();
-        assert_eq!(action_state.as_ref(), &expected_action_state);
+#[nativelink_test]
+async fn matching_engine_duration_histogram_test() -> Result<(), Error> {
+    let (tx_runs, mut rx_runs) = mpsc::unbounded_channel();
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+        move || {
+            let tx_runs = tx_runs.clone();
+            async move {
+                tx_runs.send(()).unwrap();
+            }
+        },
+    );
+    assert_eq!(
+        scheduler
+            .matching_engine_duration_buckets_for_test()
+            .iter()
+            .sum::<u64>(),
+        0
+    );
+
+    // Each pass queues a different number of actions, so the matching engine
+    // has a different amount of work to do on every run.
+    let mut digest_byte = 0;
+    let mut passes = 0;
+    for num_actions in [1, 10, 50] {
+        for _ in 0..num_actions {
+            digest_byte += 1;
+            setup_action(
+                &scheduler,
+                DigestInfo::new([digest_byte; 32], 512),
+                PlatformProperties::default(),
+                make_system_time(1),
+            )
+            .await?;
+        }
+        rx_runs.recv().await.unwrap();
+        passes += 1;
     }
 
+    // The duration is recorded before the callback is run, so every run we
+    // were told about must already be in a bucket.
+    let recorded: u64 = scheduler
+        .matching_engine_duration_buckets_for_test()
+        .iter()
+        .sum();
+    assert!(
+        recorded >= passes,
+        "Expected at least {passes} recorded runs, got {recorded}"
+    );
     Ok(())
 }
 
-/// This tests that actions are performed in the order they were queued.
 #[nativelink_test]
-async fn run_jobs_in_the_order_they_were_queued() -> Result<(), Error> {
-    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
-
+async fn reject_unsupported_platform_properties_test() -> Result<(), Error> {
     let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler::default(),
+        &nativelink_config::schedulers::SimpleScheduler {
+            supported_platform_properties: Some(HashMap::from([(
+                "cpu_arch".to_string(),
+                nativelink_config::schedulers::PropertyType::exact,
+            )])),
+            reject_unsupported_platform_properties: true,
+            ..Default::default()
+        },
         || async move {},
     );
-    let action_digest1 = DigestInfo::new([11u8; 32], 512);
-    let action_digest2 = DigestInfo::new([99u8; 32], 512);
 
-    // Use property to restrict the worker to a single action at a time.
     let mut properties = HashMap::new();
-    properties.insert("prop1".to_string(), PlatformPropertyValue::Minimum(1));
-    let platform_properties = PlatformProperties { properties };
-    // This is queued after the next one (even though it's placed in the map
-    // first), so it should execute second.
-    let insert_timestamp2 = make_system_time(2);
-    let mut client2_rx = setup_action(
+    properties.insert(
+        "gpu_model".to_string(),
+        PlatformPropertyValue::Exact("h100".to_string()),
+    );
+    let err = setup_action(
         &scheduler,
-        action_digest2,
-        platform_properties.clone(),
-        insert_timestamp2,
+        DigestInfo::new([1u8; 32], 512),
+        PlatformProperties::new(properties),
+        make_system_time(1),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.code, Code::FailedPrecondition, "{err:?}");
+    assert!(scheduler.dump_state().await.queued_actions.is_empty());
+
+    let mut properties = HashMap::new();
+    properties.insert(
+        "cpu_arch".to_string(),
+        PlatformPropertyValue::Exact("arm".to_string()),
+    );
+    let mut client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([2u8; 32], 512),
+        PlatformProperties::new(properties),
+        make_system_time(1),
     )
     .await?;
-    let insert_timestamp1 = make_system_time(1);
-    let mut client1_rx = setup_action(
+    assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Queued);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn least_loaded_allocation_spreads_actions_test() -> Result<(), Error> {
+    /// Returns the digests of the actions started on each worker.
+    fn started_action_digests(
+        rx_from_workers: &mut [mpsc::UnboundedReceiver<UpdateForWorker>],
+    ) -> Vec<Vec<DigestInfo>> {
+        rx_from_workers
+            .iter_mut()
+            .map(|rx_from_worker| {
+                let mut digests = Vec::new();
+                while let Ok(update) = rx_from_worker.try_recv() {
+                    match update.update {
+                        Some(update_for_worker::Update::StartAction(start_execute)) => {
+                            let action_digest = start_execute
+                                .execute_request
+                                .unwrap()
+                                .action_digest
+                                .unwrap();
+                            digests.push(DigestInfo::try_from(action_digest).unwrap());
+                        }
+                        v => panic!("Expected StartAction, got : {v:?}"),
+                    }
+                }
+                digests
+            })
+            .collect()
+    }
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            allocation_strategy: WorkerAllocationStrategy::least_loaded,
+            match_cooldown_ms: Some(0),
+            ..Default::default()
+        },
+        || async move {},
+    );
+    let worker_ids: Vec<WorkerId> = (0..3).map(|_| WorkerId(Uuid::new_v4())).collect();
+    let mut rx_from_workers = Vec::new();
+    for worker_id in &worker_ids {
+        rx_from_workers
+            .push(setup_new_worker(&scheduler, *worker_id, PlatformProperties::default()).await?);
+    }
+
+    let mut unique_qualifiers = HashMap::new();
+    for i in 0..6 {
+        let client_rx = setup_action(
+            &scheduler,
+            DigestInfo::new([i; 32], 512),
+            PlatformProperties::default(),
+            make_system_time(u64::from(i)),
+        )
+        .await?;
+        let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+        unique_qualifiers.insert(unique_qualifier.digest, unique_qualifier);
+    }
+    let started_actions = started_action_digests(&mut rx_from_workers);
+    for digests in &started_actions {
+        assert_eq!(digests.len(), 2, "Expected two actions per worker");
+    }
+
+    // Once the last worker finishes an action it has the fewest running, so
+    // it gets the next action even though it was used most recently.
+    scheduler
+        .update_action(
+            &worker_ids[2],
+            unique_qualifiers[&started_actions[2][0]].clone(),
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+    setup_action(
         &scheduler,
-        action_digest1,
-        platform_properties.clone(),
-        insert_timestamp1,
+        DigestInfo::new([6; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(6),
     )
     .await?;
+    assert_eq!(
+        started_action_digests(&mut rx_from_workers),
+        vec![vec![], vec![], vec![DigestInfo::new([6; 32], 512)]]
+    );
 
-    // Add the worker after the queue has been set up.
-    let mut rx_from_worker = setup_new_worker(&scheduler, worker_id, platform_properties).await?;
+    Ok(())
+}
 
-    match rx_from_worker.recv().await.unwrap().update {
-        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
-        v => panic!("Expected StartAction, got : {v:?}"),
+#[nativelink_test]
+async fn worker_with_recent_input_root_is_preferred_test() -> Result<(), Error> {
+    /// Returns the index of the only worker that was sent an action.
+    fn worker_that_started_action(
+        rx_from_workers: &mut [mpsc::UnboundedReceiver<UpdateForWorker>],
+    ) -> usize {
+        let mut started_on = Vec::new();
+        for (i, rx_from_worker) in rx_from_workers.iter_mut().enumerate() {
+            while let Ok(update) = rx_from_worker.try_recv() {
+                match update.update {
+                    Some(update_for_worker::Update::StartAction(_)) => started_on.push(i),
+                    v => panic!("Expected StartAction, got : {v:?}"),
+                }
+            }
+        }
+        assert_eq!(started_on.len(), 1, "Expected exactly one action to start");
+        started_on[0]
     }
-    {
-        // First client should be in an Executing state.
-        assert_eq!(client1_rx.borrow_and_update().stage, ActionStage::Executing);
-        // Second client should be in a queued state.
-        assert_eq!(client2_rx.borrow_and_update().stage, ActionStage::Queued);
+
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            worker_input_root_history_size: 4,
+            match_cooldown_ms: Some(0),
+            ..Default::default()
+        },
+        || async move {},
+    );
+    let mut rx_from_workers = Vec::new();
+    for _ in 0..2 {
+        rx_from_workers.push(
+            setup_new_worker(
+                &scheduler,
+                WorkerId(Uuid::new_v4()),
+                PlatformProperties::default(),
+            )
+            .await?,
+        );
     }
+    let shared_input_root = DigestInfo::new([7u8; 32], 100);
+    let add_action_with_input_root = |i: u8, input_root_digest: DigestInfo| {
+        let mut action_info = make_base_action_info(make_system_time(u64::from(i)));
+        action_info.unique_qualifier.digest = DigestInfo::new([i; 32], 512);
+        action_info.input_root_digest = input_root_digest;
+        let scheduler = &scheduler;
+        async move {
+            let result = scheduler.add_action(action_info).await;
+            tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+            result
+        }
+    };
+
+    let _first_client_rx = add_action_with_input_root(1, shared_input_root).await?;
+    let first_worker = worker_that_started_action(&mut rx_from_workers);
+
+    // Without the input root the least recently used worker would be
+    // picked, which is the other worker.
+    let _second_client_rx = add_action_with_input_root(2, shared_input_root).await?;
+    assert_eq!(
+        worker_that_started_action(&mut rx_from_workers),
+        first_worker
+    );
+
+    // Actions with other input roots are still matched as usual.
+    let _third_client_rx = add_action_with_input_root(3, DigestInfo::new([8u8; 32], 100)).await?;
+    assert_ne!(
+        worker_that_started_action(&mut rx_from_workers),
+        first_worker
+    );
 
     Ok(())
 }
 
 #[nativelink_test]
-async fn worker_retries_on_internal_error_and_fails_test() -> Result<(), Error> {
+async fn update_action_rejects_stage_regression_test() -> Result<(), Error> {
     let worker_id: WorkerId = WorkerId(Uuid::new_v4());
 
     let scheduler = SimpleScheduler::new_with_callback(
-        &nativelink_config::schedulers::SimpleScheduler {
-            max_job_retries: 2,
-            ..Default::default()
-        },
+        &nativelink_config::schedulers::SimpleScheduler::default(),
         || async move {},
     );
     let action_digest = DigestInfo::new([99u8; 32], 512);
 
     let mut rx_from_worker =
         setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
-    let insert_timestamp = make_system_time(1);
     let mut client_rx = setup_action(
         &scheduler,
         action_digest,
         PlatformProperties::default(),
-        insert_timestamp,
+        make_system_time(1),
     )
     .await?;
-
-    {
-        // Other tests check full data. We only care if we got StartAction.
-        match rx_from_worker.recv().await.unwrap().update {
-            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
-            v => panic!("Expected StartAction, got : {v:?}"),
-        }
-        // Other tests check full data. We only care if client thinks we are Executing.
-        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
     }
+    let unique_qualifier = client_rx.borrow_and_update().id.unique_qualifier.clone();
+    assert_eq!(client_rx.borrow().stage, ActionStage::Executing);
 
-    let action_info_hash_key = ActionInfoHashKey {
-        instance_name: INSTANCE_NAME.to_string(),
-        digest_function: DigestHasherFunc::Sha256,
-        digest: action_digest,
-        salt: 0,
-    };
-    let _ = scheduler
+    // Going back from executing to queued is rejected without evicting the
+    // worker or changing the action.
+    let err = scheduler
         .update_action(
             &worker_id,
-            action_info_hash_key.clone(),
-            Err(make_err!(Code::Internal, "Some error")),
+            unique_qualifier.clone(),
+            Ok(ActionStage::Queued),
         )
-        .await;
-
-    {
-        // Client should get notification saying it has been queued again.
-        let action_state = client_rx.borrow_and_update();
-        let expected_action_state = ActionState {
-            // Name is a random string, so we ignore it and just make it the same.
-            id: action_state.id.clone(),
-            stage: ActionStage::Queued,
-        };
-        assert_eq!(action_state.as_ref(), &expected_action_state);
-    }
-
-    // Now connect a new worker and it should pickup the action.
-    let mut rx_from_worker =
-        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
-    {
-        // Other tests check full data. We only care if we got StartAction.
-        match rx_from_worker.recv().await.unwrap().update {
-            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
-            v => panic!("Expected StartAction, got : {v:?}"),
-        }
-        // Other tests check full data. We only care if client thinks we are Executing.
-        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
-    }
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, Code::FailedPrecondition);
+    assert!(!client_rx.has_changed().unwrap());
+    assert_eq!(client_rx.borrow().stage, ActionStage::Executing);
+    assert!(scheduler.contains_worker_for_test(&worker_id).await);
 
-    let err = make_err!(Code::Internal, "Some error");
-    // Send internal error from worker again.
-    let _ = scheduler
-        .update_action(&worker_id, action_info_hash_key, Err(err.clone()))
-        .await;
+    // Completing the action is still allowed.
+    scheduler
+        .update_action(
+            &worker_id,
+            unique_qualifier.clone(),
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
+    assert_eq!(
+        client_rx.borrow_and_update().stage,
+        ActionStage::Completed(ActionResult::default())
+    );
 
-    {
-        // Client should get notification saying it has been queued again.
-        let action_state = client_rx.borrow_and_update();
-        let expected_action_state = ActionState {
-            // Name is a random string, so we ignore it and just make it the same.
-            id: action_state.id.clone(),
-            stage: ActionStage::Completed(ActionResult {
-                output_files: Vec::default(),
-                output_folders: Vec::default(),
-                output_file_symlinks: Vec::default(),
-                output_directory_symlinks: Vec::default(),
-                exit_code: INTERNAL_ERROR_EXIT_CODE,
-                stdout_digest: DigestInfo::zero_digest(),
-                stderr_digest: DigestInfo::zero_digest(),
-                execution_metadata: ExecutionMetadata {
-                    worker: worker_id.to_string(),
-                    queued_timestamp: SystemTime::UNIX_EPOCH,
-                    worker_start_timestamp: SystemTime::UNIX_EPOCH,
-                    worker_completed_timestamp: SystemTime::UNIX_EPOCH,
-                    input_fetch_start_timestamp: SystemTime::UNIX_EPOCH,
-                    input_fetch_completed_timestamp: SystemTime::UNIX_EPOCH,
-                    execution_start_timestamp: SystemTime::UNIX_EPOCH,
-                    execution_completed_timestamp: SystemTime::UNIX_EPOCH,
-                    output_upload_start_timestamp: SystemTime::UNIX_EPOCH,
-                    output_upload_completed_timestamp: SystemTime::UNIX_EPOCH,
-                },
-                server_logs: HashMap::default(),
-                error: Some(err.merge(make_err!(
-                    Code::Internal,
-                    "Job cancelled because it attempted to execute too many times and failed"
-                ))),
-                message: String::new(),
-            }),
-        };
-        assert_eq!(action_state.as_ref(), &expected_action_state);
-    }
+    // A completed action can not be moved back to executing.
+    let err = scheduler
+        .update_action(&worker_id, unique_qualifier, Ok(ActionStage::Executing))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, Code::FailedPrecondition);
+    assert!(scheduler.contains_worker_for_test(&worker_id).await);
 
     Ok(())
 }
 
 #[nativelink_test]
-async fn ensure_scheduler_drops_inner_spawn() -> Result<(), Error> {
-    struct DropChecker {
-        dropped: Arc<AtomicBool>,
-    }
-    impl Drop for DropChecker {
-        fn drop(&mut self) {
-            self.dropped.store(true, Ordering::Relaxed);
-        }
-    }
-
-    let dropped = Arc::new(AtomicBool::new(false));
-    let drop_checker = Arc::new(DropChecker {
-        dropped: dropped.clone(),
-    });
+async fn action_event_listener_observes_stage_changes_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
 
-    // Since the inner spawn owns this callback, we can use the callback to know if the
-    // inner spawn was dropped because our callback would be dropped, which dropps our
-    // DropChecker.
+    let (event_tx, mut event_rx) = mpsc::channel(16);
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler::default(),
-        move || {
-            // This will ensure dropping happens if this function is ever dropped.
-            let _drop_checker = drop_checker.clone();
-            async move {}
-        },
-    );
-    assert_eq!(dropped.load(Ordering::Relaxed), false);
+        || async move {},
+    )
+    .with_action_event_listener(Arc::new(event_tx));
+    let action_digest = DigestInfo::new([99u8; 32], 512);
 
-    drop(scheduler);
-    tokio::task::yield_now().await; // The drop may happen in a different task.
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let client_rx = setup_action(
+        &scheduler,
+        action_digest,
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
+    }
+    let operation_id = client_rx.borrow().id.clone();
+    scheduler
+        .update_action(
+            &worker_id,
+            operation_id.unique_qualifier.clone(),
+            Ok(ActionStage::Completed(ActionResult::default())),
+        )
+        .await?;
 
-    // Ensure our callback was dropped.
-    assert_eq!(dropped.load(Ordering::Relaxed), true);
+    let mut stage_changes = Vec::new();
+    while let Ok(event) = event_rx.try_recv() {
+        assert_eq!(event.operation_id, operation_id);
+        stage_changes.push((event.old_stage, event.new_stage));
+    }
+    assert_eq!(
+        stage_changes,
+        vec![
+            (ActionStage::Queued, ActionStage::Executing),
+            (
+                ActionStage::Executing,
+                ActionStage::Completed(ActionResult::default())
+            ),
+        ]
+    );
 
     Ok(())
 }
 
-/// Regression test for: https://github.com/TraceMachina/nativelink/issues/257.
 #[nativelink_test]
-async fn ensure_task_or_worker_change_notification_received_test() -> Result<(), Error> {
-    let worker_id1: WorkerId = WorkerId(Uuid::new_v4());
-    let worker_id2: WorkerId = WorkerId(Uuid::new_v4());
+async fn action_event_listener_drops_events_when_full_test() -> Result<(), Error> {
+    let worker_id: WorkerId = WorkerId(Uuid::new_v4());
 
+    let (event_tx, mut event_rx) = mpsc::channel(1);
     let scheduler = SimpleScheduler::new_with_callback(
         &nativelink_config::schedulers::SimpleScheduler::default(),
         || async move {},
-    );
-    let action_digest = DigestInfo::new([99u8; 32], 512);
+    )
+    .with_action_event_listener(Arc::new(event_tx));
 
-    let mut rx_from_worker1 =
-        setup_new_worker(&scheduler, worker_id1, PlatformProperties::default()).await?;
-    let mut client_rx = setup_action(
+    let mut rx_from_worker =
+        setup_new_worker(&scheduler, worker_id, PlatformProperties::default()).await?;
+    let client_rx = setup_action(
         &scheduler,
-        action_digest,
+        DigestInfo::new([99u8; 32], 512),
         PlatformProperties::default(),
         make_system_time(1),
     )
     .await?;
-
-    let mut rx_from_worker2 =
-        setup_new_worker(&scheduler, worker_id2, PlatformProperties::default()).await?;
-
-    {
-        // Other tests check full data. We only care if we got StartAction.
-        match rx_from_worker1.recv().await.unwrap().update {
-            Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
-            v => panic!("Expected StartAction, got : {v:?}"),
-        }
-        // Other tests check full data. We only care if client thinks we are Executing.
-        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
     }
-
-    let _ = scheduler
+    let unique_qualifier = client_rx.borrow().id.unique_qualifier.clone();
+    // The channel is full, so completing the action must not wait for the
+    // listener.
+    scheduler
         .update_action(
-            &worker_id1,
-            ActionInfoHashKey {
-                instance_name: INSTANCE_NAME.to_string(),
-                digest_function: DigestHasherFunc::Sha256,
-                digest: action_digest,
-                salt: 0,
-            },
-            Err(make_err!(Code::NotFound, "Some error")),
+            &worker_id,
+            unique_qualifier,
+            Ok(ActionStage::Completed(ActionResult::default())),
         )
-        .await;
+        .await?;
 
-    tokio::task::yield_now().await; // Allow task<->worker matcher to run.
+    assert_eq!(
+        event_rx.try_recv().unwrap().new_stage,
+        ActionStage::Executing
+    );
+    assert!(event_rx.try_recv().is_err());
 
-    // Now connect a new worker and it should pickup the action.
-    {
-        // Other tests check full data. We only care if we got StartAction.
-        rx_from_worker2
-            .recv()
-            .await
-            .err_tip(|| "worker went away")?;
-        // Other tests check full data. We only care if client thinks we are Executing.
-        assert_eq!(client_rx.borrow_and_update().stage, ActionStage::Executing);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn match_cooldown_is_applied_between_matching_runs_test() -> Result<(), Error> {
+    const MATCH_COOLDOWN: Duration = Duration::from_millis(200);
+    let (run_tx, mut run_rx) = mpsc::unbounded_channel();
+    let scheduler = SimpleScheduler::new_with_callback(
+        &nativelink_config::schedulers::SimpleScheduler {
+            match_cooldown_ms: Some(MATCH_COOLDOWN.as_millis() as u64),
+            ..Default::default()
+        },
+        move || {
+            let run_tx = run_tx.clone();
+            async move {
+                let _ = run_tx.send(std::time::Instant::now());
+            }
+        },
+    );
+
+    let start = std::time::Instant::now();
+    let mut rx_from_worker = setup_new_worker(
+        &scheduler,
+        WorkerId(Uuid::new_v4()),
+        PlatformProperties::default(),
+    )
+    .await?;
+    let first_run = run_rx.recv().await.unwrap();
+    assert!(
+        first_run - start >= MATCH_COOLDOWN,
+        "Expected the cooldown after the first run, took {:?}",
+        first_run - start
+    );
+
+    let _client_rx = setup_action(
+        &scheduler,
+        DigestInfo::new([99u8; 32], 512),
+        PlatformProperties::default(),
+        make_system_time(1),
+    )
+    .await?;
+    let second_run = run_rx.recv().await.unwrap();
+    assert!(
+        second_run - first_run >= MATCH_COOLDOWN,
+        "Expected the cooldown between runs, took {:?}",
+        second_run - first_run
+    );
+    match rx_from_worker.recv().await.unwrap().update {
+        Some(update_for_worker::Update::StartAction(_)) => { /* Success */ }
+        v => panic!("Expected StartAction, got : {v:?}"),
     }
 
     Ok(())
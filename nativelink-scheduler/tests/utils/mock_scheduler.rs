@@ -15,7 +15,7 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use nativelink_error::{make_input_err, Error};
+use nativelink_error::{make_err, make_input_err, Code, Error};
 use nativelink_scheduler::action_scheduler::ActionScheduler;
 use nativelink_scheduler::platform_property_manager::PlatformPropertyManager;
 use nativelink_util::action_messages::{ActionInfo, ActionInfoHashKey, ActionState};
@@ -178,5 +178,16 @@ impl ActionScheduler for MockActionScheduler {
         }
     }
 
+    async fn set_action_priority(
+        &self,
+        _unique_qualifier: &ActionInfoHashKey,
+        _priority: i32,
+    ) -> Result<(), Error> {
+        Err(make_err!(
+            Code::Unimplemented,
+            "set_action_priority is not mocked"
+        ))
+    }
+
     async fn clean_recently_completed_actions(&self) {}
 }
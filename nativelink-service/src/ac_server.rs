@@ -14,10 +14,12 @@
 
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bytes::BytesMut;
 use nativelink_config::cas_server::{AcStoreConfig, InstanceName};
-use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_error::{error_if, make_err, make_input_err, Code, Error, ResultExt};
 use nativelink_proto::build::bazel::remote::execution::v2::action_cache_server::{
     ActionCache, ActionCacheServer as Server,
 };
@@ -27,21 +29,89 @@ use nativelink_proto::build::bazel::remote::execution::v2::{
 use nativelink_store::ac_utils::{get_and_decode_digest, ESTIMATED_DIGEST_SIZE};
 use nativelink_store::grpc_store::GrpcStore;
 use nativelink_store::store_manager::StoreManager;
+use nativelink_util::background_spawn;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::make_ctx_for_hash_func;
-use nativelink_util::store_trait::{Store, StoreLike};
+use nativelink_util::store_trait::{Store, StoreLike, StoreOptimizations};
+use parking_lot::Mutex;
 use prost::Message;
+use tokio::sync::RwLock;
+use tokio::time::interval;
 use tonic::{Request, Response, Status};
 use tracing::{error_span, event, instrument, Level};
 
+pub type NowFn = Box<dyn Fn() -> Result<Duration, Error> + Send + Sync>;
+
+/// Tracks when action results written to a store expire so they can be
+/// removed by a sweep.
+struct ActionResultExpirations {
+    store: Store,
+    ttl: Duration,
+    /// Time since `UNIX_EPOCH` at which each action result expires.
+    expires_at: Mutex<HashMap<DigestInfo, Duration>>,
+    /// Held for reading while an action result is written and its expiry
+    /// recorded, and for writing while an expired one is removed, so a
+    /// sweep never removes an action result that was just rewritten.
+    update_lock: RwLock<()>,
+}
+
+impl ActionResultExpirations {
+    fn record_write(&self, digest: DigestInfo, now: Duration) {
+        self.expires_at.lock().insert(digest, now + self.ttl);
+    }
+
+    fn is_expired(&self, digest: &DigestInfo, now: Duration) -> bool {
+        self.expires_at
+            .lock()
+            .get(digest)
+            .is_some_and(|expires_at| *expires_at <= now)
+    }
+
+    /// Removes all action results that expired at or before `now` and
+    /// returns how many were removed. Action results that fail to be
+    /// removed are kept and retried by the next sweep.
+    async fn sweep(&self, now: Duration) -> usize {
+        let expired: Vec<DigestInfo> = self
+            .expires_at
+            .lock()
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(digest, _)| *digest)
+            .collect();
+        let mut removed = 0;
+        for digest in &expired {
+            let _update_guard = self.update_lock.write().await;
+            // The action result may have been rewritten since it was found
+            // to be expired.
+            if !self.is_expired(digest, now) {
+                continue;
+            }
+            if let Err(err) = self.store.remove(*digest).await {
+                event!(
+                    Level::ERROR,
+                    ?digest,
+                    ?err,
+                    "Failed to remove expired action result"
+                );
+                continue;
+            }
+            removed += 1;
+            self.expires_at.lock().remove(digest);
+        }
+        removed
+    }
+}
+
 #[derive(Clone)]
 pub struct AcStoreInfo {
     store: Store,
     read_only: bool,
+    expirations: Option<Arc<ActionResultExpirations>>,
 }
 
 pub struct AcServer {
     stores: HashMap<String, AcStoreInfo>,
+    now_fn: NowFn,
 }
 
 impl Debug for AcServer {
@@ -54,23 +124,79 @@ impl AcServer {
     pub fn new(
         config: &HashMap<InstanceName, AcStoreConfig>,
         store_manager: &StoreManager,
+    ) -> Result<Self, Error> {
+        let ac_server = Self::new_with_now_fn(
+            config,
+            store_manager,
+            Box::new(move || {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|_| make_err!(Code::Internal, "System time is now behind unix epoch"))
+            }),
+        )?;
+        for store_info in ac_server.stores.values() {
+            let Some(expirations) = &store_info.expirations else {
+                continue;
+            };
+            let sweep_interval = expirations.ttl;
+            let weak_expirations = Arc::downgrade(expirations);
+            background_spawn!("ac_server_expiration_sweep", async move {
+                sweep_expired_loop(weak_expirations, sweep_interval).await;
+            });
+        }
+        Ok(ac_server)
+    }
+
+    /// Same as new(), but you can pass a custom `now_fn`, that returns a Duration since UNIX_EPOCH
+    /// representing the current time. No background sweep of expired action results is started,
+    /// use [`AcServer::sweep_expired_action_results`] instead. Used mostly in unit tests.
+    pub fn new_with_now_fn(
+        config: &HashMap<InstanceName, AcStoreConfig>,
+        store_manager: &StoreManager,
+        now_fn: NowFn,
     ) -> Result<Self, Error> {
         let mut stores = HashMap::with_capacity(config.len());
         for (instance_name, ac_cfg) in config {
             let store = store_manager.get_store(&ac_cfg.ac_store).ok_or_else(|| {
                 make_input_err!("'ac_store': '{}' does not exist", ac_cfg.ac_store)
             })?;
+            error_if!(
+                ac_cfg.action_result_ttl_s > 0 && !store.optimized_for(StoreOptimizations::Remove),
+                "'action_result_ttl_s' is set for instance '{instance_name}', but 'ac_store': '{}' can not remove action results",
+                ac_cfg.ac_store
+            );
+            let expirations = (ac_cfg.action_result_ttl_s > 0).then(|| {
+                Arc::new(ActionResultExpirations {
+                    store: store.clone(),
+                    ttl: Duration::from_secs(ac_cfg.action_result_ttl_s),
+                    expires_at: Mutex::new(HashMap::new()),
+                    update_lock: RwLock::new(()),
+                })
+            });
             stores.insert(
                 instance_name.to_string(),
                 AcStoreInfo {
                     store,
                     read_only: ac_cfg.read_only,
+                    expirations,
                 },
             );
         }
-        Ok(AcServer {
-            stores: stores.clone(),
-        })
+        Ok(AcServer { stores, now_fn })
+    }
+
+    /// Removes all action results whose TTL has passed from every instance
+    /// with `action_result_ttl_s` configured. Returns the number of action
+    /// results removed.
+    pub async fn sweep_expired_action_results(&self) -> Result<usize, Error> {
+        let now = (self.now_fn)()?;
+        let mut removed = 0;
+        for store_info in self.stores.values() {
+            if let Some(expirations) = &store_info.expirations {
+                removed += expirations.sweep(now).await;
+            }
+        }
+        Ok(removed)
     }
 
     pub fn into_service(self) -> Server<AcServer> {
@@ -147,15 +273,51 @@ impl AcServer {
             .encode(&mut store_data)
             .err_tip(|| "Provided ActionResult could not be serialized")?;
 
+        let _update_guard = match &store_info.expirations {
+            Some(expirations) => Some(expirations.update_lock.read().await),
+            None => None,
+        };
         store_info
             .store
             .update_oneshot(digest, store_data.freeze())
             .await
             .err_tip(|| "Failed to update in action cache")?;
+        if let Some(expirations) = &store_info.expirations {
+            expirations.record_write(digest, (self.now_fn)()?);
+        }
         Ok(Response::new(action_result))
     }
 }
 
+async fn sweep_expired_loop(
+    weak_expirations: Weak<ActionResultExpirations>,
+    sweep_interval: Duration,
+) {
+    let mut ticker = interval(sweep_interval);
+    loop {
+        ticker.tick().await;
+        // If we fail to upgrade, our service is probably destroyed, so return.
+        let Some(expirations) = weak_expirations.upgrade() else {
+            return;
+        };
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now,
+            Err(err) => {
+                event!(
+                    Level::ERROR,
+                    ?err,
+                    "System time is behind unix epoch, skipping expired action result sweep"
+                );
+                continue;
+            }
+        };
+        let removed = expirations.sweep(now).await;
+        if removed > 0 {
+            event!(Level::INFO, removed, "Removed expired action results");
+        }
+    }
+}
+
 #[tonic::async_trait]
 impl ActionCache for AcServer {
     #[allow(clippy::blocks_in_conditions)]
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
@@ -20,10 +21,11 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use bytes::Buf;
 use futures::future::{pending, BoxFuture};
 use futures::stream::unfold;
-use futures::{try_join, Future, Stream, TryFutureExt};
-use nativelink_config::cas_server::ByteStreamConfig;
+use futures::{join, try_join, Future, Stream, TryFutureExt};
+use nativelink_config::cas_server::{ByteStreamConfig, CasStoreConfig, InstanceName};
 use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
 use nativelink_proto::google::bytestream::byte_stream_server::{
     ByteStream, ByteStreamServer as Server,
@@ -42,7 +44,7 @@ use nativelink_util::digest_hasher::{
     default_digest_hasher_func, make_ctx_for_hash_func, DigestHasherFunc,
 };
 use nativelink_util::proto_stream_utils::WriteRequestStreamWrapper;
-use nativelink_util::resource_info::ResourceInfo;
+use nativelink_util::resource_info::{ResourceInfo, DEFAULT_MAX_RESOURCE_NAME_LENGTH};
 use nativelink_util::spawn;
 use nativelink_util::store_trait::{Store, StoreLike, UploadSizeInfo};
 use nativelink_util::task::JoinHandleDropGuard;
@@ -51,6 +53,8 @@ use tokio::time::sleep;
 use tonic::{Request, Response, Status, Streaming};
 use tracing::{enabled, error_span, event, instrument, Instrument, Level};
 
+use crate::cas_server::CasInstance;
+
 /// If this value changes update the documentation in the config definition.
 const DEFAULT_PERSIST_STREAM_ON_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 
@@ -152,16 +156,85 @@ impl IdleStream {
 type BytesWrittenAndIdleStream = (Arc<AtomicU64>, Option<IdleStream>);
 type SleepFn = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
 
+/// Reads `read_limit` bytes at `read_offset` from `store` into `writer`, but
+/// asks the store for a range that starts and ends on a multiple of
+/// `alignment`. The extra bytes are trimmed before being sent to `writer`.
+async fn aligned_get_part(
+    store: Store,
+    digest: DigestInfo,
+    mut writer: DropCloserWriteHalf,
+    read_offset: usize,
+    read_limit: Option<usize>,
+    alignment: usize,
+) -> Result<(), Error> {
+    let aligned_offset = read_offset - read_offset % alignment;
+    let mut bytes_to_skip = read_offset - aligned_offset;
+    let aligned_limit = read_limit.map(|read_limit| {
+        (bytes_to_skip + read_limit)
+            .div_ceil(alignment)
+            .saturating_mul(alignment)
+    });
+    let mut bytes_to_send = read_limit.unwrap_or(usize::MAX);
+
+    let (tx, mut rx) = make_buf_channel_pair();
+    let get_part_fut = store.get_part(digest, tx, aligned_offset, aligned_limit);
+    let trim_fut = async move {
+        loop {
+            let mut chunk = rx
+                .recv()
+                .await
+                .err_tip(|| "In ByteStreamServer::aligned_get_part")?;
+            if chunk.is_empty() {
+                break; // EOF.
+            }
+            // Keep draining after all requested bytes were sent so the
+            // store is not interrupted by a closed channel.
+            let skip = cmp::min(bytes_to_skip, chunk.len());
+            bytes_to_skip -= skip;
+            chunk.advance(skip);
+            chunk.truncate(bytes_to_send);
+            bytes_to_send -= chunk.len();
+            if !chunk.is_empty() {
+                writer
+                    .send(chunk)
+                    .await
+                    .err_tip(|| "Failed to send aligned chunk in ByteStreamServer")?;
+            }
+        }
+        writer
+            .send_eof()
+            .err_tip(|| "Failed to send EOF in ByteStreamServer::aligned_get_part")
+    };
+    let (get_part_result, trim_result) = join!(get_part_fut, trim_fut);
+    get_part_result.merge(trim_result)
+}
+
 pub struct ByteStreamServer {
-    stores: HashMap<String, Store>,
+    stores: HashMap<String, CasInstance>,
     // Max number of bytes to send on each grpc stream chunk.
     max_bytes_per_stream: usize,
+    // If non-zero, reads sent to the store are aligned to this many bytes.
+    read_alignment: usize,
+    // Resource names longer than this are rejected before parsing.
+    max_resource_name_length: usize,
+    // If non-zero, writes wait for the store to drain below this many
+    // buffered bytes before reading more data from the client.
+    write_flow_control_window: usize,
+    // Blobs larger than this are rejected by reads and writes.
+    max_blob_size: usize,
     active_uploads: Arc<Mutex<HashMap<String, BytesWrittenAndIdleStream>>>,
     sleep_fn: SleepFn,
 }
 
 impl ByteStreamServer {
-    pub fn new(config: &ByteStreamConfig, store_manager: &StoreManager) -> Result<Self, Error> {
+    /// Creates the server. Instances that are also configured in
+    /// `cas_config` route digest functions to stores the same way the CAS
+    /// service does.
+    pub fn new(
+        config: &ByteStreamConfig,
+        cas_config: Option<&HashMap<InstanceName, CasStoreConfig>>,
+        store_manager: &StoreManager,
+    ) -> Result<Self, Error> {
         let mut persist_stream_on_disconnect_timeout =
             Duration::from_secs(config.persist_stream_on_disconnect_timeout as u64);
         if config.persist_stream_on_disconnect_timeout == 0 {
@@ -169,6 +242,7 @@ impl ByteStreamServer {
         }
         Self::new_with_sleep_fn(
             config,
+            cas_config,
             store_manager,
             Arc::new(move || Box::pin(sleep(persist_stream_on_disconnect_timeout))),
         )
@@ -176,6 +250,7 @@ impl ByteStreamServer {
 
     pub fn new_with_sleep_fn(
         config: &ByteStreamConfig,
+        cas_config: Option<&HashMap<InstanceName, CasStoreConfig>>,
         store_manager: &StoreManager,
         sleep_fn: SleepFn,
     ) -> Result<Self, Error> {
@@ -184,16 +259,29 @@ impl ByteStreamServer {
             let store = store_manager
                 .get_store(store_name)
                 .ok_or_else(|| make_input_err!("'cas_store': '{}' does not exist", store_name))?;
-            stores.insert(instance_name.to_string(), store);
+            let cas_cfg = cas_config.and_then(|cas_config| cas_config.get(instance_name));
+            stores.insert(
+                instance_name.to_string(),
+                CasInstance::new(store, cas_cfg, store_manager)?,
+            );
         }
         let max_bytes_per_stream = if config.max_bytes_per_stream == 0 {
             DEFAULT_MAX_BYTES_PER_STREAM
         } else {
             config.max_bytes_per_stream
         };
+        let max_resource_name_length = if config.max_resource_name_length == 0 {
+            DEFAULT_MAX_RESOURCE_NAME_LENGTH
+        } else {
+            config.max_resource_name_length
+        };
         Ok(ByteStreamServer {
             stores,
             max_bytes_per_stream,
+            read_alignment: config.read_alignment,
+            max_resource_name_length,
+            write_flow_control_window: config.write_flow_control_window,
+            max_blob_size: config.max_blob_size.unwrap_or(usize::MAX),
             active_uploads: Arc::new(Mutex::new(HashMap::new())),
             sleep_fn,
         })
@@ -203,6 +291,29 @@ impl ByteStreamServer {
         Server::new(self)
     }
 
+    /// Resolves the digest function of a resource name and the store that
+    /// serves it, rejecting digest functions that are not allowed.
+    fn get_store_and_digest_function(
+        &self,
+        instance_name: &str,
+        digest_function: Option<&str>,
+    ) -> Result<(Store, DigestHasherFunc), Error> {
+        let digest_function = digest_function.map_or_else(
+            || Ok(default_digest_hasher_func()),
+            DigestHasherFunc::try_from,
+        )?;
+        let store = self
+            .get_instance(instance_name)?
+            .store_for(digest_function)?;
+        Ok((store.clone(), digest_function))
+    }
+
+    fn get_instance(&self, instance_name: &str) -> Result<&CasInstance, Error> {
+        self.stores
+            .get(instance_name)
+            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))
+    }
+
     fn create_or_join_upload_stream(
         &self,
         uuid: String,
@@ -282,16 +393,25 @@ impl ByteStreamServer {
             None
         };
 
+        let read_offset = usize::try_from(read_request.read_offset)
+            .err_tip(|| "read_offset has is not convertible to usize")?;
+        let read_alignment = self.read_alignment;
+        let get_part_fut: Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> =
+            if read_alignment == 0 {
+                Box::pin(async move { store.get_part(digest, tx, read_offset, read_limit).await })
+            } else {
+                Box::pin(async move {
+                    aligned_get_part(store, digest, tx, read_offset, read_limit, read_alignment)
+                        .await
+                })
+            };
+
         // This allows us to call a destructor when the the object is dropped.
         let state = Some(ReaderState {
             rx,
             max_bytes_per_stream: self.max_bytes_per_stream,
             maybe_get_part_result: None,
-            get_part_fut: Box::pin(async move {
-                store
-                    .get_part(digest, tx, read_request.read_offset as usize, read_limit)
-                    .await
-            }),
+            get_part_fut,
         });
 
         let read_stream_span = error_span!("read_stream");
@@ -401,8 +521,14 @@ impl ByteStreamServer {
             tx: &mut DropCloserWriteHalf,
             outer_bytes_received: &Arc<AtomicU64>,
             expected_size: u64,
+            write_flow_control_window: usize,
         ) -> Result<(), Error> {
             loop {
+                if write_flow_control_window != 0 {
+                    // Do not read more from the client until the store caught up.
+                    tx.wait_for_bytes_buffered_below(write_flow_control_window as u64)
+                        .await;
+                }
                 let write_request = match stream.next().await {
                     // Code path for when client tries to gracefully close the stream.
                     // If this happens it means there's a problem with the data sent,
@@ -470,6 +596,16 @@ impl ByteStreamServer {
                     return Err(make_input_err!("Received more bytes than expected"));
                 }
                 if write_request.finish_write {
+                    // Never let a short write reach the store, it would
+                    // otherwise commit a truncated blob under this digest.
+                    if tx.get_bytes_written() != expected_size {
+                        return Err(make_err!(
+                            Code::InvalidArgument,
+                            "Client finished write after sending {} bytes, but expected {} bytes",
+                            tx.get_bytes_written(),
+                            expected_size
+                        ));
+                    }
                     // Gracefully close our stream.
                     tx.send_eof()
                         .err_tip(|| "Failed to send EOF in ByteStream::write")?;
@@ -486,7 +622,8 @@ impl ByteStreamServer {
                 stream,
                 &mut active_stream.tx,
                 &active_stream_guard.bytes_received,
-                expected_size
+                expected_size,
+                self.write_flow_control_window,
             ),
             (&mut active_stream.store_update_fut)
                 .map_err(|err| { err.append("Error updating inner store") })
@@ -504,18 +641,16 @@ impl ByteStreamServer {
         &self,
         query_request: &QueryWriteStatusRequest,
     ) -> Result<Response<QueryWriteStatusResponse>, Error> {
-        let mut resource_info = ResourceInfo::new(&query_request.resource_name, true)?;
-
-        let store_clone = self
-            .stores
-            .get(resource_info.instance_name.as_ref())
-            .err_tip(|| {
-                format!(
-                    "'instance_name' not configured for '{}'",
-                    &resource_info.instance_name
-                )
-            })?
-            .clone();
+        let mut resource_info = ResourceInfo::new_with_max_length(
+            &query_request.resource_name,
+            true,
+            self.max_resource_name_length,
+        )?;
+
+        let (store_clone, _digest_function) = self.get_store_and_digest_function(
+            resource_info.instance_name.as_ref(),
+            resource_info.digest_function.as_deref(),
+        )?;
 
         let digest = DigestInfo::try_new(resource_info.hash.as_ref(), resource_info.expected_size)?;
 
@@ -571,13 +706,31 @@ impl ByteStream for ByteStreamServer {
     ) -> Result<Response<Self::ReadStream>, Status> {
         let read_request = grpc_request.into_inner();
 
-        let resource_info = ResourceInfo::new(&read_request.resource_name, false)?;
-        let instance_name = resource_info.instance_name.as_ref();
-        let store = self
-            .stores
-            .get(instance_name)
-            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))?
-            .clone();
+        let resource_info = ResourceInfo::new_with_max_length(
+            &read_request.resource_name,
+            false,
+            self.max_resource_name_length,
+        )?;
+        // Only the bytes that are served count, so small ranged reads of a
+        // large blob are allowed.
+        let read_size = resource_info
+            .expected_size
+            .saturating_sub(usize::try_from(read_request.read_offset).unwrap_or(0));
+        let read_size = match usize::try_from(read_request.read_limit) {
+            Ok(read_limit) if read_limit > 0 => cmp::min(read_limit, read_size),
+            _ => read_size,
+        };
+        if read_size > self.max_blob_size {
+            return Err(make_input_err!(
+                "Read of {read_size} bytes is larger than the maximum allowed size of {} bytes",
+                self.max_blob_size
+            )
+            .into());
+        }
+        let (store, digest_function) = self.get_store_and_digest_function(
+            resource_info.instance_name.as_ref(),
+            resource_info.digest_function.as_deref(),
+        )?;
 
         let digest = DigestInfo::try_new(resource_info.hash.as_ref(), resource_info.expected_size)?;
 
@@ -587,11 +740,6 @@ impl ByteStream for ByteStreamServer {
             return Ok(Response::new(Box::pin(stream)));
         }
 
-        let digest_function = resource_info.digest_function.as_deref().map_or_else(
-            || Ok(default_digest_hasher_func()),
-            DigestHasherFunc::try_from,
-        )?;
-
         let resp = make_ctx_for_hash_func(digest_function)
             .err_tip(|| "In BytestreamServer::read")?
             .wrap_async(
@@ -619,17 +767,22 @@ impl ByteStream for ByteStreamServer {
         &self,
         grpc_request: Request<Streaming<WriteRequest>>,
     ) -> Result<Response<WriteResponse>, Status> {
-        let stream = WriteRequestStreamWrapper::from(grpc_request.into_inner())
-            .await
-            .err_tip(|| "Could not unwrap first stream message")
-            .map_err(Into::<Status>::into)?;
+        let stream = WriteRequestStreamWrapper::from_with_limits(
+            grpc_request.into_inner(),
+            self.max_resource_name_length,
+            self.max_blob_size,
+        )
+        .await
+        .err_tip(|| "Could not unwrap first stream message")
+        .map_err(Into::<Status>::into)?;
 
+        let (store, digest_function) = self.get_store_and_digest_function(
+            stream.resource_info.instance_name.as_ref(),
+            stream.resource_info.digest_function.as_deref(),
+        )?;
         let instance_name = stream.resource_info.instance_name.as_ref();
-        let store = self
-            .stores
-            .get(instance_name)
-            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))?
-            .clone();
+        self.get_instance(instance_name)?
+            .check_writable(instance_name)?;
 
         let digest = DigestInfo::try_new(
             &stream.resource_info.hash,
@@ -642,15 +795,6 @@ impl ByteStream for ByteStreamServer {
             return grpc_store.write(stream).await.map_err(|e| e.into());
         }
 
-        let digest_function = stream
-            .resource_info
-            .digest_function
-            .as_deref()
-            .map_or_else(
-                || Ok(default_digest_hasher_func()),
-                DigestHasherFunc::try_from,
-            )?;
-
         make_ctx_for_hash_func(digest_function)
             .err_tip(|| "In BytestreamServer::write")?
             .wrap_async(
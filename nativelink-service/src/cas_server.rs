@@ -14,12 +14,13 @@
 
 use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
+use std::sync::Arc;
 
 use bytes::Bytes;
 use futures::stream::{FuturesUnordered, Stream};
 use futures::TryStreamExt;
 use nativelink_config::cas_server::{CasStoreConfig, InstanceName};
-use nativelink_error::{error_if, make_input_err, Code, Error, ResultExt};
+use nativelink_error::{error_if, make_err, make_input_err, Code, Error, ResultExt};
 use nativelink_proto::build::bazel::remote::execution::v2::content_addressable_storage_server::{
     ContentAddressableStorage, ContentAddressableStorageServer as Server,
 };
@@ -33,13 +34,129 @@ use nativelink_store::ac_utils::get_and_decode_digest;
 use nativelink_store::grpc_store::GrpcStore;
 use nativelink_store::store_manager::StoreManager;
 use nativelink_util::common::DigestInfo;
-use nativelink_util::digest_hasher::make_ctx_for_hash_func;
+use nativelink_util::digest_hasher::{make_ctx_for_hash_func, DigestHasherFunc};
 use nativelink_util::store_trait::{Store, StoreLike};
+use parking_lot::Mutex;
 use tonic::{Request, Response, Status};
 use tracing::{error_span, event, instrument, Level};
 
+/// Maximum number of directories queued in a `GetTree` page token. Larger
+/// queues are kept on the server in a `TreeCursors` and the token only names
+/// the cursor.
+const MAX_PAGE_TOKEN_DIRECTORIES: usize = 128;
+
+/// Maximum number of `GetTree` walks kept in `TreeCursors`. The oldest walk
+/// is dropped when a new one would exceed this.
+const MAX_TREE_CURSORS: usize = 1024;
+
+/// The stores that serve a single CAS instance.
+pub struct CasInstance {
+    store: Store,
+    // If not empty, only these digest functions are accepted and each of them
+    // is served from its own store.
+    digest_function_stores: HashMap<DigestHasherFunc, Store>,
+    read_only: bool,
+}
+
+impl CasInstance {
+    /// Creates an instance served by `store`, routing digest functions to
+    /// stores as configured in `cas_cfg`.
+    pub fn new(
+        store: Store,
+        cas_cfg: Option<&CasStoreConfig>,
+        store_manager: &StoreManager,
+    ) -> Result<Self, Error> {
+        let mut digest_function_stores = HashMap::new();
+        for (digest_function, store_name) in cas_cfg
+            .into_iter()
+            .flat_map(|cas_cfg| &cas_cfg.digest_function_stores)
+        {
+            let store = store_manager.get_store(store_name).ok_or_else(|| {
+                make_input_err!("'digest_function_stores': '{}' does not exist", store_name)
+            })?;
+            digest_function_stores.insert(DigestHasherFunc::from(*digest_function), store);
+        }
+        Ok(Self {
+            store,
+            digest_function_stores,
+            read_only: cas_cfg.is_some_and(|cas_cfg| cas_cfg.read_only),
+        })
+    }
+
+    /// Rejects writes if this instance is read only.
+    pub fn check_writable(&self, instance_name: &str) -> Result<(), Error> {
+        if self.read_only {
+            return Err(make_err!(
+                Code::PermissionDenied,
+                "Instance '{instance_name}' is read only"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the store that holds blobs hashed with `digest_function`,
+    /// rejecting digest functions that are not allowed.
+    pub fn store_for(&self, digest_function: DigestHasherFunc) -> Result<&Store, Error> {
+        if self.digest_function_stores.is_empty() {
+            return Ok(&self.store);
+        }
+        self.digest_function_stores
+            .get(&digest_function)
+            .ok_or_else(|| {
+                let mut allowed: Vec<String> = self
+                    .digest_function_stores
+                    .keys()
+                    .map(ToString::to_string)
+                    .collect();
+                allowed.sort_unstable();
+                make_input_err!(
+                    "Digest function {digest_function} is not allowed, expected one of: {}",
+                    allowed.join(", ")
+                )
+            })
+    }
+}
+
+/// A `GetTree` walk whose queue of directories was too large to put in its
+/// page token.
+struct TreeCursor {
+    root_digest: DigestInfo,
+    offset: usize,
+    deque: VecDeque<DigestInfo>,
+}
+
+/// The `GetTree` walks that can be resumed by a page token naming them.
+#[derive(Default)]
+struct TreeCursors {
+    cursors: HashMap<u64, TreeCursor>,
+    // Ids of `cursors` from oldest to newest.
+    order: VecDeque<u64>,
+}
+
+impl TreeCursors {
+    fn insert(&mut self, cursor: TreeCursor) -> u64 {
+        let id = rand::random::<u64>();
+        if self.cursors.insert(id, cursor).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > MAX_TREE_CURSORS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.cursors.remove(&oldest);
+            }
+        }
+        id
+    }
+
+    fn take(&mut self, id: u64) -> Option<TreeCursor> {
+        let cursor = self.cursors.remove(&id)?;
+        self.order.retain(|order_id| *order_id != id);
+        Some(cursor)
+    }
+}
+
 pub struct CasServer {
-    stores: HashMap<String, Store>,
+    stores: HashMap<String, CasInstance>,
+    tree_cursors: Arc<Mutex<TreeCursors>>,
 }
 
 type GetTreeStream = Pin<Box<dyn Stream<Item = Result<GetTreeResponse, Status>> + Send + 'static>>;
@@ -54,25 +171,40 @@ impl CasServer {
             let store = store_manager.get_store(&cas_cfg.cas_store).ok_or_else(|| {
                 make_input_err!("'cas_store': '{}' does not exist", cas_cfg.cas_store)
             })?;
-            stores.insert(instance_name.to_string(), store);
+            stores.insert(
+                instance_name.to_string(),
+                CasInstance::new(store, Some(cas_cfg), store_manager)?,
+            );
         }
-        Ok(CasServer { stores })
+        Ok(CasServer {
+            stores,
+            tree_cursors: Arc::new(Mutex::new(TreeCursors::default())),
+        })
     }
 
     pub fn into_service(self) -> Server<CasServer> {
         Server::new(self)
     }
 
+    fn get_instance(&self, instance_name: &str) -> Result<&CasInstance, Error> {
+        self.stores
+            .get(instance_name)
+            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))
+    }
+
+    fn get_store(&self, instance_name: &str, digest_function: i32) -> Result<Store, Error> {
+        let digest_function = DigestHasherFunc::try_from(digest_function)?;
+        self.get_instance(instance_name)?
+            .store_for(digest_function)
+            .cloned()
+    }
+
     async fn inner_find_missing_blobs(
         &self,
         request: FindMissingBlobsRequest,
     ) -> Result<Response<FindMissingBlobsResponse>, Error> {
         let instance_name = &request.instance_name;
-        let store = self
-            .stores
-            .get(instance_name)
-            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))?
-            .clone();
+        let store = self.get_store(instance_name, request.digest_function)?;
 
         let mut requested_blobs = Vec::with_capacity(request.blob_digests.len());
         for digest in request.blob_digests.iter() {
@@ -99,11 +231,9 @@ impl CasServer {
     ) -> Result<Response<BatchUpdateBlobsResponse>, Error> {
         let instance_name = &request.instance_name;
 
-        let store = self
-            .stores
-            .get(instance_name)
-            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))?
-            .clone();
+        self.get_instance(instance_name)?
+            .check_writable(instance_name)?;
+        let store = self.get_store(instance_name, request.digest_function)?;
 
         // If we are a GrpcStore we shortcut here, as this is a special store.
         // Note: We don't know the digests here, so we try perform a very shallow
@@ -154,11 +284,7 @@ impl CasServer {
     ) -> Result<Response<BatchReadBlobsResponse>, Error> {
         let instance_name = &request.instance_name;
 
-        let store = self
-            .stores
-            .get(instance_name)
-            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))?
-            .clone();
+        let store = self.get_store(instance_name, request.digest_function)?;
 
         // If we are a GrpcStore we shortcut here, as this is a special store.
         // Note: We don't know the digests here, so we try perform a very shallow
@@ -179,12 +305,20 @@ impl CasServer {
                     .await
                     .err_tip(|| "Error reading from store");
                 let (status, data) = result.map_or_else(
-                    |mut e| {
+                    |e| {
                         if e.code == Code::NotFound {
-                            // Trim the error code. Not Found is quite common and we don't want to send a large
-                            // error (debug) message for something that is common. We resize to just the last
-                            // message as it will be the most relevant.
-                            e.messages.resize_with(1, || "".to_string());
+                            // Not Found is quite common, so send a short message that only
+                            // contains the missing digest, so clients can easily parse it.
+                            let status = GrpcStatus {
+                                code: Code::NotFound as i32,
+                                message: format!(
+                                    "{}/{}",
+                                    digest_copy.hash_str(),
+                                    digest_copy.size_bytes
+                                ),
+                                details: vec![],
+                            };
+                            return (status, Bytes::new());
                         }
                         (e.into(), Bytes::new())
                     },
@@ -211,11 +345,7 @@ impl CasServer {
     ) -> Result<Response<GetTreeStream>, Error> {
         let instance_name = &request.instance_name;
 
-        let store = self
-            .stores
-            .get(instance_name)
-            .err_tip(|| format!("'instance_name' not configured for '{instance_name}'"))?
-            .clone();
+        let store = self.get_store(instance_name, request.digest_function)?;
 
         // If we are a GrpcStore we shortcut here, as this is a special store.
         // Note: We don't know the digests here, so we try perform a very shallow
@@ -233,65 +363,192 @@ impl CasServer {
             .try_into()
             .err_tip(|| "In GetTreeRequest::root_digest")?;
 
-        let mut deque: VecDeque<DigestInfo> = VecDeque::new();
-        let mut directories: Vec<Directory> = Vec::new();
-        // `page_token` will return the `{hash_str}-{size_bytes}` of the current request's first directory digest.
-        let mut page_token_parts = request.page_token.split('-');
-        let page_token_digest = DigestInfo::try_new(
-            page_token_parts
-                .next()
-                .err_tip(|| "Failed to parse `hash_str` in `page_token`")?,
-            page_token_parts
-                .next()
-                .err_tip(|| "Failed to parse `size_bytes` in `page_token`")?
-                .parse::<i64>()
-                .err_tip(|| "Failed to parse `size_bytes` as i64")?,
-        )
-        .err_tip(|| "Failed to parse `page_token` as `Digest` in `GetTreeRequest`")?;
-        let page_size = request.page_size;
         // If `page_size` is 0, paging is not necessary.
-        let mut page_token_matched = page_size == 0;
-        deque.push_back(root_digest);
-
-        while !deque.is_empty() {
-            let digest: DigestInfo = deque.pop_front().err_tip(|| "In VecDeque::pop_front")?;
-            let directory = get_and_decode_digest::<Directory>(&store, digest.into())
+        let page_size = match usize::try_from(request.page_size)
+            .err_tip(|| "Expected `page_size` to not be negative in GetTreeRequest")?
+        {
+            0 => usize::MAX,
+            page_size => page_size,
+        };
+        let (offset, deque) =
+            resume_tree_walk(&store, &self.tree_cursors, &request.page_token, root_digest)
                 .await
-                .err_tip(|| "Converting digest to Directory")?;
-            if digest == page_token_digest {
-                page_token_matched = true;
-            }
-            for directory in &directory.directories {
-                let digest: DigestInfo = directory
-                    .digest
-                    .clone()
-                    .err_tip(|| "Expected Digest to exist in Directory::directories::digest")?
-                    .try_into()
-                    .err_tip(|| "In Directory::file::digest")?;
-                deque.push_back(digest);
-            }
-            if page_token_matched {
-                directories.push(directory);
-                if directories.len() as i32 == page_size {
-                    break;
+                .err_tip(|| "Failed to resume from `page_token` in GetTreeRequest")?;
+
+        // Directories are only fetched when the next page is polled, so only
+        // one page of the tree is held in memory at a time.
+        let tree_cursors = self.tree_cursors.clone();
+        Ok(Response::new(Box::pin(futures::stream::unfold(
+            Some((store, offset, deque)),
+            move |state| {
+                let tree_cursors = tree_cursors.clone();
+                async move {
+                    let (store, offset, mut deque) = state?;
+                    match get_tree_page(&store, &mut deque, page_size).await {
+                        Ok(directories) => {
+                            let offset = offset + directories.len();
+                            // `next_page_token` will be an empty string when
+                            // it reached the end of the directory tree.
+                            let next_page_token =
+                                make_page_token(&tree_cursors, root_digest, offset, &deque);
+                            let next_state = (!deque.is_empty()).then_some((store, offset, deque));
+                            Some((
+                                Ok(GetTreeResponse {
+                                    directories,
+                                    next_page_token,
+                                }),
+                                next_state,
+                            ))
+                        }
+                        Err(err) => Some((Err(err.into()), None)),
+                    }
                 }
+            },
+        ))))
+    }
+}
+
+/// Resumes the breadth-first walk of the tree at `root_digest` from a
+/// `page_token`, returning how many directories were already returned and the
+/// queue of directories that still need to be visited.
+///
+/// Tokens made by `make_page_token` have the form
+/// `{offset}:{root}:{hash_str}-{size_bytes},...`, or `{offset}:{root}:@{id}`
+/// if the queue is kept in `tree_cursors`. A token is only accepted for the
+/// `root_digest` it was made for. If the cursor is no longer kept, for
+/// example because the token was already used, the walk is repeated from the
+/// root up to `offset`.
+/// Tokens of the form `{hash_str}-{size_bytes}` name the first directory of
+/// the page and are resumed by walking from the root up to that directory.
+/// An empty token starts the walk at `root_digest`.
+async fn resume_tree_walk(
+    store: &Store,
+    tree_cursors: &Mutex<TreeCursors>,
+    page_token: &str,
+    root_digest: DigestInfo,
+) -> Result<(usize, VecDeque<DigestInfo>), Error> {
+    let mut deque = VecDeque::from([root_digest]);
+    if page_token.is_empty() {
+        return Ok((0, deque));
+    }
+    let mut parts = page_token.splitn(3, ':');
+    let (Some(offset), Some(token_root), Some(queue)) = (parts.next(), parts.next(), parts.next())
+    else {
+        let page_token_digest = parse_page_token_digest(page_token)?;
+        let mut offset = 0;
+        while deque.front() != Some(&page_token_digest) {
+            error_if!(
+                deque.is_empty(),
+                "Directory {page_token} of `page_token` is not part of the tree"
+            );
+            offset += get_tree_page(store, &mut deque, 1).await?.len();
+        }
+        return Ok((offset, deque));
+    };
+    let offset = offset
+        .parse::<usize>()
+        .err_tip(|| "Failed to parse offset of `page_token`")?;
+    error_if!(
+        parse_page_token_digest(token_root)? != root_digest,
+        "`page_token` was made for root {token_root}, which is not `root_digest`"
+    );
+    if let Some(id) = queue.strip_prefix('@') {
+        let id = id
+            .parse::<u64>()
+            .err_tip(|| "Failed to parse cursor of `page_token`")?;
+        let cursor = tree_cursors.lock().take(id);
+        if let Some(cursor) = cursor {
+            if cursor.root_digest == root_digest && cursor.offset == offset {
+                return Ok((offset, cursor.deque));
             }
         }
-        // `next_page_token` will return the `{hash_str}:{size_bytes}` of the next request's first directory digest.
-        // It will be an empty string when it reached the end of the directory tree.
-        let next_page_token: String = if let Some(value) = deque.front() {
-            format!("{}-{}", value.hash_str(), value.size_bytes)
-        } else {
-            String::new()
-        };
+    } else {
+        deque = queue
+            .split(',')
+            .map(parse_page_token_digest)
+            .collect::<Result<_, _>>()?;
+        return Ok((offset, deque));
+    }
+    for _ in 0..offset {
+        error_if!(
+            deque.is_empty(),
+            "Offset {offset} of `page_token` is past the end of the tree"
+        );
+        get_tree_page(store, &mut deque, 1).await?;
+    }
+    Ok((offset, deque))
+}
 
-        Ok(Response::new(Box::pin(futures::stream::once(async {
-            Ok(GetTreeResponse {
-                directories,
-                next_page_token,
-            })
-        }))))
+fn parse_page_token_digest(digest: &str) -> Result<DigestInfo, Error> {
+    let (hash_str, size_bytes) = digest
+        .split_once('-')
+        .err_tip(|| "Failed to parse `hash_str` in `page_token`")?;
+    DigestInfo::try_new(
+        hash_str,
+        size_bytes
+            .parse::<i64>()
+            .err_tip(|| "Failed to parse `size_bytes` as i64")?,
+    )
+    .err_tip(|| "Failed to parse `page_token` as `Digest` in `GetTreeRequest`")
+}
+
+/// Encodes how many directories of the tree at `root_digest` were returned
+/// and the queue of directories that still need to be visited, so a later
+/// request can resume the walk. Queues that are too large for the token are
+/// kept in `tree_cursors`. See `resume_tree_walk` for the format.
+fn make_page_token(
+    tree_cursors: &Mutex<TreeCursors>,
+    root_digest: DigestInfo,
+    offset: usize,
+    deque: &VecDeque<DigestInfo>,
+) -> String {
+    if deque.is_empty() {
+        return String::new();
+    }
+    let root = format!("{}-{}", root_digest.hash_str(), root_digest.size_bytes);
+    if deque.len() > MAX_PAGE_TOKEN_DIRECTORIES {
+        let id = tree_cursors.lock().insert(TreeCursor {
+            root_digest,
+            offset,
+            deque: deque.clone(),
+        });
+        return format!("{offset}:{root}:@{id}");
+    }
+    let digests = deque
+        .iter()
+        .map(|digest| format!("{}-{}", digest.hash_str(), digest.size_bytes))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{offset}:{root}:{digests}")
+}
+
+/// Fetches up to `page_size` directories from the front of `deque` in
+/// breadth-first order, queueing their child directories.
+async fn get_tree_page(
+    store: &Store,
+    deque: &mut VecDeque<DigestInfo>,
+    page_size: usize,
+) -> Result<Vec<Directory>, Error> {
+    let mut directories = Vec::new();
+    while directories.len() < page_size {
+        let Some(digest) = deque.pop_front() else {
+            break;
+        };
+        let directory = get_and_decode_digest::<Directory>(store, digest.into())
+            .await
+            .err_tip(|| "Converting digest to Directory")?;
+        for directory in &directory.directories {
+            let digest: DigestInfo = directory
+                .digest
+                .clone()
+                .err_tip(|| "Expected Digest to exist in Directory::directories::digest")?
+                .try_into()
+                .err_tip(|| "In Directory::file::digest")?;
+            deque.push_back(digest);
+        }
+        directories.push(directory);
     }
+    Ok(directories)
 }
 
 #[tonic::async_trait]
@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use bytes::{Bytes, BytesMut};
 use futures::{Stream, StreamExt};
 use nativelink_config::cas_server::{ExecutionConfig, InstanceName};
 use nativelink_error::{make_input_err, Error, ResultExt};
@@ -24,31 +26,242 @@ use nativelink_proto::build::bazel::remote::execution::v2::execution_server::{
     Execution, ExecutionServer as Server,
 };
 use nativelink_proto::build::bazel::remote::execution::v2::{
-    Action, Command, ExecuteRequest, WaitExecutionRequest,
+    Action, ActionResult as ProtoActionResult, Command, ExecuteRequest, WaitExecutionRequest,
 };
 use nativelink_proto::google::longrunning::Operation;
 use nativelink_scheduler::action_scheduler::ActionScheduler;
-use nativelink_store::ac_utils::get_and_decode_digest;
+use nativelink_store::ac_utils::{get_and_decode_digest, message_to_digest};
 use nativelink_store::store_manager::StoreManager;
 use nativelink_util::action_messages::{
-    ActionInfo, ActionInfoHashKey, ActionState, OperationId, DEFAULT_EXECUTION_PRIORITY,
+    ActionInfo, ActionInfoHashKey, ActionStage, ActionState, OperationId,
+    DEFAULT_EXECUTION_PRIORITY,
 };
+use nativelink_util::background_spawn;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::{make_ctx_for_hash_func, DigestHasherFunc};
 use nativelink_util::platform_properties::PlatformProperties;
-use nativelink_util::store_trait::Store;
+use nativelink_util::store_trait::{Store, StoreLike};
+use parking_lot::Mutex;
+use prost::Message;
 use rand::{thread_rng, Rng};
 use tokio::sync::watch;
 use tokio_stream::wrappers::WatchStream;
 use tonic::{Request, Response, Status};
 use tracing::{error_span, event, instrument, Level};
 
+/// Sorts the repeated fields of `command` whose order has no meaning, so
+/// that commands that only differ in their order become identical.
+fn canonicalize_command(command: &mut Command) {
+    command
+        .environment_variables
+        .sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.value.cmp(&b.value)));
+    command.output_files.sort();
+    command.output_directories.sort();
+    command.output_paths.sort();
+    command.output_node_properties.sort();
+    if let Some(platform) = &mut command.platform {
+        platform
+            .properties
+            .sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.value.cmp(&b.value)));
+    }
+}
+
+/// Returns the digest `action` would have after canonicalizing it and the
+/// `Command` it references (see `ExecutionConfig::canonicalize_actions`).
+/// Actions that only differ in the order of fields whose order has no
+/// meaning share this digest, so it is only used as the key to merge them.
+/// The canonical messages are never stored or scheduled.
+pub async fn canonical_action_digest(
+    cas_store: &Store,
+    action: &Action,
+    digest_function: DigestHasherFunc,
+) -> Result<DigestInfo, Error> {
+    let command_digest = DigestInfo::try_from(
+        action
+            .command_digest
+            .clone()
+            .err_tip(|| "Expected command_digest to exist")?,
+    )
+    .err_tip(|| "Could not decode command digest")?;
+    let mut command = get_and_decode_digest::<Command>(cas_store, command_digest.into())
+        .await
+        .err_tip(|| "Failed to get command in canonical_action_digest")?;
+    canonicalize_command(&mut command);
+
+    let mut action = action.clone();
+    let canonical_command_digest = message_to_digest(
+        &command,
+        &mut BytesMut::with_capacity(command.encoded_len()),
+        &mut digest_function.hasher(),
+    )
+    .err_tip(|| "Failed to digest canonical command")?;
+    action.command_digest = Some(canonical_command_digest.into());
+    if let Some(platform) = &mut action.platform {
+        platform
+            .properties
+            .sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.value.cmp(&b.value)));
+    }
+    message_to_digest(
+        &action,
+        &mut BytesMut::with_capacity(action.encoded_len()),
+        &mut digest_function.hasher(),
+    )
+    .err_tip(|| "Failed to digest canonical action")
+}
+
+/// Finished merged actions are swept once at least this many are tracked.
+const MIN_MERGED_ACTIONS_SWEEP_LEN: usize = 1024;
+
+/// An action that equivalent actions can join.
+struct MergedAction {
+    /// The action as it was first scheduled. Joining requests schedule this
+    /// action with their own priority and `skip_cache_lookup`, so the
+    /// scheduler merges them into it.
+    action_info: ActionInfo,
+    /// Set once the action has been scheduled.
+    receiver: Option<watch::Receiver<Arc<ActionState>>>,
+}
+
+impl MergedAction {
+    /// Whether the action may still be joined.
+    fn is_joinable(&self) -> bool {
+        match &self.receiver {
+            // An error means the scheduler dropped the action.
+            Some(receiver) => {
+                receiver.has_changed().is_ok() && !receiver.borrow().stage.is_finished()
+            }
+            // The action is still being scheduled.
+            None => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MergedActions {
+    // Unfinished actions keyed by their canonical action digest.
+    actions: HashMap<ActionInfoHashKey, MergedAction>,
+    // Finished actions are swept when `actions` grows to this many entries.
+    sweep_at_len: usize,
+}
+
 struct InstanceInfo {
     scheduler: Arc<dyn ActionScheduler>,
     cas_store: Store,
+    // Receives the results of merged actions under the digests of the
+    // actions that joined them. Always set if `canonicalize_actions` is.
+    ac_store: Option<Store>,
+    canonicalize_actions: bool,
+    merged_actions: Mutex<MergedActions>,
 }
 
 impl InstanceInfo {
+    /// Returns the action to schedule for `action_info`: the unfinished
+    /// action `merge_key` was last scheduled as, if any, with the priority
+    /// and `skip_cache_lookup` of `action_info`. Otherwise `action_info` is
+    /// returned and equivalent actions join it from now on.
+    fn join_or_add_merged_action(
+        &self,
+        merge_key: ActionInfoHashKey,
+        action_info: ActionInfo,
+    ) -> ActionInfo {
+        let mut merged_actions = self.merged_actions.lock();
+        if let Some(merged_action) = merged_actions.actions.get(&merge_key) {
+            if merged_action.is_joinable() {
+                return ActionInfo {
+                    priority: action_info.priority,
+                    skip_cache_lookup: action_info.skip_cache_lookup,
+                    ..merged_action.action_info.clone()
+                };
+            }
+        }
+        if merged_actions.actions.len() >= merged_actions.sweep_at_len {
+            merged_actions
+                .actions
+                .retain(|_, merged_action| merged_action.is_joinable());
+            merged_actions.sweep_at_len = cmp::max(
+                merged_actions.actions.len() * 2,
+                MIN_MERGED_ACTIONS_SWEEP_LEN,
+            );
+        }
+        merged_actions.actions.insert(
+            merge_key,
+            MergedAction {
+                action_info: action_info.clone(),
+                receiver: None,
+            },
+        );
+        action_info
+    }
+
+    /// Records that the merged action of `merge_key` was scheduled as
+    /// `unique_qualifier`, unless it was replaced in the meantime. An action
+    /// that failed to be scheduled (`receiver` is `None`) is no longer joined.
+    fn merged_action_scheduled(
+        &self,
+        merge_key: &ActionInfoHashKey,
+        unique_qualifier: &ActionInfoHashKey,
+        receiver: Option<&watch::Receiver<Arc<ActionState>>>,
+    ) {
+        let mut merged_actions = self.merged_actions.lock();
+        let Some(merged_action) = merged_actions.actions.get_mut(merge_key) else {
+            return;
+        };
+        if merged_action.action_info.unique_qualifier != *unique_qualifier
+            || merged_action.receiver.is_some()
+        {
+            return;
+        }
+        match receiver {
+            Some(receiver) => merged_action.receiver = Some(receiver.clone()),
+            None => {
+                merged_actions.actions.remove(merge_key);
+            }
+        }
+    }
+
+    /// Writes the result of the action behind `receiver` to `ac_store` under
+    /// `digest` once it completed successfully, so an action that joined it
+    /// gets its own action cache entry.
+    fn cache_joined_action_result(
+        &self,
+        digest: DigestInfo,
+        mut receiver: watch::Receiver<Arc<ActionState>>,
+    ) {
+        let Some(ac_store) = self.ac_store.clone() else {
+            return;
+        };
+        background_spawn!("execution_server_cache_joined_action_result", async move {
+            let action_result = loop {
+                let action_state = receiver.borrow_and_update().clone();
+                match &action_state.stage {
+                    ActionStage::Completed(action_result) => {
+                        break ProtoActionResult::from(action_result.clone())
+                    }
+                    ActionStage::CompletedFromCache(action_result) => break action_result.clone(),
+                    _ => {}
+                }
+                // An error means the scheduler dropped the action.
+                if receiver.changed().await.is_err() {
+                    return;
+                }
+            };
+            if action_result.exit_code != 0 {
+                return;
+            }
+            if let Err(err) = ac_store
+                .update_oneshot(digest, Bytes::from(action_result.encode_to_vec()))
+                .await
+            {
+                event!(
+                    Level::WARN,
+                    ?err,
+                    ?digest,
+                    "Failed to write result of joined action to the action cache"
+                );
+            }
+        });
+    }
+
     async fn build_action_info(
         &self,
         instance_name: String,
@@ -163,12 +376,29 @@ impl ExecutionServer {
                     )
                 })?
                 .clone();
+            let ac_store = exec_cfg
+                .ac_store
+                .as_ref()
+                .map(|ac_store| {
+                    store_manager
+                        .get_store(ac_store)
+                        .ok_or_else(|| make_input_err!("'ac_store': '{}' does not exist", ac_store))
+                })
+                .transpose()?;
+            if exec_cfg.canonicalize_actions && ac_store.is_none() {
+                return Err(make_input_err!(
+                    "'ac_store' must be set when 'canonicalize_actions' is enabled"
+                ));
+            }
 
             instance_infos.insert(
                 instance_name.to_string(),
                 InstanceInfo {
                     scheduler,
                     cas_store,
+                    ac_store,
+                    canonicalize_actions: exec_cfg.canonicalize_actions,
+                    merged_actions: Mutex::new(MergedActions::default()),
                 },
             );
         }
@@ -209,8 +439,28 @@ impl ExecutionServer {
             .execution_policy
             .map_or(DEFAULT_EXECUTION_PRIORITY, |p| p.priority);
 
+        let digest_function = request
+            .digest_function
+            .try_into()
+            .err_tip(|| "Could not convert digest function in inner_execute()")?;
         let action =
             get_and_decode_digest::<Action>(&instance_info.cas_store, digest.into()).await?;
+        // Uncacheable actions are never merged, so they need no merge key.
+        let merge_key = if instance_info.canonicalize_actions && !action.do_not_cache {
+            let canonical_digest =
+                canonical_action_digest(&instance_info.cas_store, &action, digest_function)
+                    .await
+                    .err_tip(|| "Failed to canonicalize action in inner_execute()")?;
+            Some(ActionInfoHashKey {
+                instance_name: instance_name.clone(),
+                digest_function,
+                digest: canonical_digest,
+                salt: 0,
+            })
+        } else {
+            None
+        };
+
         let action_info = instance_info
             .build_action_info(
                 instance_name,
@@ -218,18 +468,28 @@ impl ExecutionServer {
                 &action,
                 priority,
                 request.skip_cache_lookup,
-                request
-                    .digest_function
-                    .try_into()
-                    .err_tip(|| "Could not convert digest function in inner_execute()")?,
+                digest_function,
             )
             .await?;
+        let Some(merge_key) = merge_key else {
+            let rx = instance_info
+                .scheduler
+                .add_action(action_info)
+                .await
+                .err_tip(|| "Failed to schedule task")?;
+            return Ok(Self::to_execute_stream(rx));
+        };
 
-        let rx = instance_info
-            .scheduler
-            .add_action(action_info)
-            .await
-            .err_tip(|| "Failed to schedule task")?;
+        // Finding or adding the merged action happens under one lock, so
+        // equivalent actions that arrive together are scheduled only once.
+        let action_info = instance_info.join_or_add_merged_action(merge_key.clone(), action_info);
+        let unique_qualifier = action_info.unique_qualifier.clone();
+        let result = instance_info.scheduler.add_action(action_info).await;
+        instance_info.merged_action_scheduled(&merge_key, &unique_qualifier, result.as_ref().ok());
+        let rx = result.err_tip(|| "Failed to schedule task")?;
+        if unique_qualifier.digest != digest {
+            instance_info.cache_joined_action_result(digest, rx.clone());
+        }
 
         Ok(Self::to_execute_stream(rx))
     }
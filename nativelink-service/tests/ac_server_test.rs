@@ -13,7 +13,9 @@
 // limitations under the License.
 
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::BytesMut;
 use maplit::hashmap;
@@ -86,6 +88,7 @@ fn make_ac_server(store_manager: &StoreManager) -> Result<AcServer, Error> {
             "foo_instance_name".to_string() => nativelink_config::cas_server::AcStoreConfig{
                 ac_store: "main_ac".to_string(),
                 read_only: false,
+                action_result_ttl_s: 0,
             }
         },
         store_manager,
@@ -231,3 +234,88 @@ async fn one_item_update_test() -> Result<(), Box<dyn std::error::Error>> {
     assert_eq!(decoded_action_result, action_result);
     Ok(())
 }
+
+#[nativelink_test]
+async fn expired_action_result_is_swept_test() -> Result<(), Box<dyn std::error::Error>> {
+    const TTL_S: u64 = 10;
+    let store_manager = make_store_manager().await?;
+    let now_s = Arc::new(AtomicU64::new(1_000));
+    let ac_server = AcServer::new_with_now_fn(
+        &hashmap! {
+            INSTANCE_NAME.to_string() => nativelink_config::cas_server::AcStoreConfig{
+                ac_store: "main_ac".to_string(),
+                read_only: false,
+                action_result_ttl_s: TTL_S,
+            }
+        },
+        &store_manager,
+        Box::new({
+            let now_s = now_s.clone();
+            move || Ok(Duration::from_secs(now_s.load(Ordering::Relaxed)))
+        }),
+    )?;
+
+    let action_result = ActionResult {
+        exit_code: 45,
+        ..Default::default()
+    };
+    let size_bytes = get_encoded_proto_size(&action_result)? as i64;
+    update_action_result(
+        &ac_server,
+        Digest {
+            hash: HASH1.to_string(),
+            size_bytes,
+        },
+        action_result.clone(),
+    )
+    .await?;
+
+    // Not expired yet, so nothing should be removed.
+    now_s.fetch_add(TTL_S - 1, Ordering::Relaxed);
+    assert_eq!(ac_server.sweep_expired_action_results().await?, 0);
+    assert_eq!(
+        get_action_result(&ac_server, HASH1, size_bytes)
+            .await?
+            .into_inner(),
+        action_result
+    );
+
+    now_s.fetch_add(1, Ordering::Relaxed);
+    assert_eq!(ac_server.sweep_expired_action_results().await?, 1);
+    let err = get_action_result(&ac_server, HASH1, size_bytes)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn ttl_requires_ac_store_that_supports_removal_test() -> Result<(), Box<dyn std::error::Error>>
+{
+    let store_manager = make_store_manager().await?;
+    store_manager.add_store(
+        "noop_ac",
+        store_factory(
+            &nativelink_config::stores::StoreConfig::noop,
+            &store_manager,
+            None,
+            None,
+        )
+        .await?,
+    );
+    let result = AcServer::new(
+        &hashmap! {
+            INSTANCE_NAME.to_string() => nativelink_config::cas_server::AcStoreConfig{
+                ac_store: "noop_ac".to_string(),
+                read_only: false,
+                action_result_ttl_s: 10,
+            }
+        },
+        &store_manager,
+    );
+    let Err(err) = result else {
+        panic!("Expected a TTL on a store without removal to be rejected");
+    };
+    assert_eq!(err.code, nativelink_error::Code::InvalidArgument);
+    Ok(())
+}
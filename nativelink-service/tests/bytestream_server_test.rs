@@ -18,6 +18,8 @@ use futures::poll;
 use futures::task::Poll;
 use hyper::body::Sender;
 use maplit::hashmap;
+use nativelink_config::cas_server::{ByteStreamConfig, CasStoreConfig};
+use nativelink_config::stores::ConfigDigestHashFunction;
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
 use nativelink_proto::google::bytestream::byte_stream_server::ByteStream;
@@ -25,11 +27,13 @@ use nativelink_proto::google::bytestream::{
     QueryWriteStatusRequest, QueryWriteStatusResponse, ReadRequest, WriteRequest, WriteResponse,
 };
 use nativelink_service::bytestream_server::ByteStreamServer;
+use nativelink_store::compression_store::CompressionStore;
 use nativelink_store::default_store_factory::store_factory;
+use nativelink_store::memory_store::MemoryStore;
 use nativelink_store::store_manager::StoreManager;
 use nativelink_util::common::{encode_stream_proto, DigestInfo};
 use nativelink_util::spawn;
-use nativelink_util::store_trait::StoreLike;
+use nativelink_util::store_trait::{Store, StoreDriver, StoreLike};
 use nativelink_util::task::JoinHandleDropGuard;
 use pretty_assertions::assert_eq;
 use prometheus_client::registry::Registry;
@@ -59,17 +63,22 @@ async fn make_store_manager() -> Result<Arc<StoreManager>, Error> {
     Ok(store_manager)
 }
 
-fn make_bytestream_server(store_manager: &StoreManager) -> Result<ByteStreamServer, Error> {
-    ByteStreamServer::new(
-        &nativelink_config::cas_server::ByteStreamConfig {
-            cas_stores: hashmap! {
-                "foo_instance_name".to_string() => "main_cas".to_string(),
-            },
-            persist_stream_on_disconnect_timeout: 0,
-            max_bytes_per_stream: 1024,
+fn make_bytestream_config() -> ByteStreamConfig {
+    ByteStreamConfig {
+        cas_stores: hashmap! {
+            INSTANCE_NAME.to_string() => "main_cas".to_string(),
         },
-        store_manager,
-    )
+        persist_stream_on_disconnect_timeout: 0,
+        max_bytes_per_stream: 1024,
+        read_alignment: 0,
+        max_resource_name_length: 0,
+        write_flow_control_window: 0,
+        max_blob_size: None,
+    }
+}
+
+fn make_bytestream_server(store_manager: &StoreManager) -> Result<ByteStreamServer, Error> {
+    ByteStreamServer::new(&make_bytestream_config(), None, store_manager)
 }
 
 #[nativelink_test]
@@ -610,6 +619,63 @@ pub async fn out_of_order_data_fails() -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+#[nativelink_test]
+pub async fn short_write_is_rejected_and_not_stored() -> Result<(), Box<dyn std::error::Error>> {
+    let store_manager = make_store_manager().await?;
+    let bs_server = make_bytestream_server(store_manager.as_ref())?;
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    // Setup stream.
+    let (mut tx, join_handle) = {
+        let (tx, body) = Body::channel();
+        let mut codec = ProstCodec::<WriteRequest, WriteRequest>::default();
+        // Note: This is an undocumented function.
+        let stream =
+            Streaming::new_request(codec.decoder(), body, Some(CompressionEncoding::Gzip), None);
+
+        let join_handle = spawn!("short_write_is_rejected_and_not_stored", async move {
+            bs_server.write(Request::new(stream)).await
+        });
+        (tx, join_handle)
+    };
+    const WRITE_DATA: &str = "12456789abcdefghijk";
+    const BYTES_SENT: usize = 8;
+
+    let resource_name = format!(
+        "{}/uploads/{}/blobs/{}/{}",
+        INSTANCE_NAME,
+        "4dcec57e-1389-4ab5-b188-4a59f22ceb4b", // Randomly generated.
+        HASH1,
+        WRITE_DATA.len()
+    );
+    // Send fewer bytes than declared and claim the write is finished.
+    let write_request = WriteRequest {
+        resource_name,
+        write_offset: 0,
+        finish_write: true,
+        data: WRITE_DATA[..BYTES_SENT].into(),
+    };
+    tx.send_data(encode_stream_proto(&write_request)?).await?;
+
+    let status = join_handle
+        .await?
+        .expect_err("Expected short write to be rejected");
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(
+        status.message().contains(&format!(
+            "after sending {BYTES_SENT} bytes, but expected {} bytes",
+            WRITE_DATA.len()
+        )),
+        "Expected error to contain both byte counts, got: {}",
+        status.message()
+    );
+
+    // The truncated data must never have been committed to the store.
+    let digest = DigestInfo::try_new(HASH1, WRITE_DATA.len())?;
+    assert_eq!(store.has(digest).await?, None);
+    Ok(())
+}
+
 #[nativelink_test]
 pub async fn upload_zero_byte_chunk() -> Result<(), Box<dyn std::error::Error>> {
     let store_manager = make_store_manager().await?;
@@ -1009,3 +1075,661 @@ pub async fn test_query_write_status_smoke_test() -> Result<(), Box<dyn std::err
         .err_tip(|| "Failed write")?;
     Ok(())
 }
+
+#[nativelink_test]
+pub async fn query_write_status_resumes_after_disconnect() -> Result<(), Box<dyn std::error::Error>>
+{
+    let store_manager = make_store_manager().await?;
+    let bs_server = Arc::new(make_bytestream_server(store_manager.as_ref())?);
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    fn setup_stream(
+        bs_server: Arc<ByteStreamServer>,
+    ) -> (
+        Sender,
+        JoinHandleDropGuard<Result<Response<WriteResponse>, tonic::Status>>,
+    ) {
+        let (tx, body) = Body::channel();
+        let mut codec = ProstCodec::<WriteRequest, WriteRequest>::default();
+        // Note: This is an undocumented function.
+        let stream =
+            Streaming::new_request(codec.decoder(), body, Some(CompressionEncoding::Gzip), None);
+
+        let join_handle = spawn!("query_write_status_resumes_write_stream", async move {
+            bs_server.write(Request::new(stream)).await
+        });
+        (tx, join_handle)
+    }
+
+    const WRITE_DATA: &str = "12456789abcdefghijk";
+    const BYTE_SPLIT_OFFSET: usize = 8;
+
+    let resource_name = format!(
+        "{}/uploads/{}/blobs/{}/{}",
+        INSTANCE_NAME,
+        "4dcec57e-1389-4ab5-b188-4a59f22ceb4b", // Randomly generated.
+        HASH1,
+        WRITE_DATA.len()
+    );
+    let mut write_request = WriteRequest {
+        resource_name: resource_name.clone(),
+        write_offset: 0,
+        finish_write: false,
+        data: WRITE_DATA[..BYTE_SPLIT_OFFSET].into(),
+    };
+    {
+        // Write the first chunk, then disconnect before finishing.
+        let (mut tx, join_handle) = setup_stream(bs_server.clone());
+        tx.send_data(encode_stream_proto(&write_request)?).await?;
+        drop(tx);
+        let result = join_handle.await?;
+        assert!(result.is_err(), "Expected interrupted write to fail");
+    }
+    let committed_size = {
+        // The partial upload is kept and reports what was committed so far.
+        let response = bs_server
+            .query_write_status(Request::new(QueryWriteStatusRequest {
+                resource_name: resource_name.clone(),
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(
+            response,
+            QueryWriteStatusResponse {
+                committed_size: BYTE_SPLIT_OFFSET as i64,
+                complete: false,
+            }
+        );
+        response.committed_size
+    };
+    {
+        // Resume from the committed size reported by the server.
+        let (mut tx, join_handle) = setup_stream(bs_server.clone());
+        write_request.write_offset = committed_size;
+        write_request.data = WRITE_DATA[committed_size as usize..].into();
+        write_request.finish_write = true;
+        tx.send_data(encode_stream_proto(&write_request)?).await?;
+        let response = join_handle.await??.into_inner();
+        assert_eq!(response.committed_size, WRITE_DATA.len() as i64);
+    }
+    {
+        let response = bs_server
+            .query_write_status(Request::new(QueryWriteStatusRequest { resource_name }))
+            .await?
+            .into_inner();
+        assert_eq!(
+            response,
+            QueryWriteStatusResponse {
+                committed_size: WRITE_DATA.len() as i64,
+                complete: true,
+            }
+        );
+        let digest = DigestInfo::try_new(HASH1, WRITE_DATA.len())?;
+        assert_eq!(
+            store.get_part_unchunked(digest, 0, None).await?,
+            WRITE_DATA,
+            "Data written to store did not match expected data",
+        );
+    }
+    Ok(())
+}
+
+/// Reads the `partial_block_reads` metric of `compression_store`.
+fn partial_block_reads(compression_store: &Arc<CompressionStore>) -> u64 {
+    let mut registry = <Registry>::default();
+    compression_store.clone().register_metrics(&mut registry);
+    let mut metrics = String::new();
+    prometheus_client::encoding::text::encode(&mut metrics, &registry).unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("partial_block_reads "))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("Expected partial_block_reads metric, got:\n{metrics}"))
+}
+
+async fn read_with_alignment(
+    store_manager: &StoreManager,
+    read_alignment: usize,
+    size: usize,
+    read_offset: usize,
+    read_limit: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let bs_server = ByteStreamServer::new(
+        &ByteStreamConfig {
+            read_alignment,
+            ..make_bytestream_config()
+        },
+        None,
+        store_manager,
+    )?;
+    let read_request = ReadRequest {
+        resource_name: format!("{INSTANCE_NAME}/blobs/{HASH1}/{size}"),
+        read_offset: read_offset as i64,
+        read_limit: read_limit as i64,
+    };
+    let mut read_stream = bs_server
+        .read(Request::new(read_request))
+        .await?
+        .into_inner();
+    let mut data = Vec::new();
+    while let Some(result_read_response) = read_stream.next().await {
+        data.extend_from_slice(&result_read_response?.data);
+    }
+    Ok(data)
+}
+
+#[nativelink_test]
+pub async fn aligned_reads_avoid_partial_block_decodes() -> Result<(), Box<dyn std::error::Error>> {
+    const BLOCK_SIZE: usize = 16;
+    const READ_OFFSET: usize = 5;
+    const READ_LIMIT: usize = 30;
+
+    let compression_store = CompressionStore::new(
+        nativelink_config::stores::CompressionStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            compression_algorithm: nativelink_config::stores::CompressionAlgorithm::lz4(
+                nativelink_config::stores::Lz4Config {
+                    block_size: BLOCK_SIZE as u32,
+                    ..Default::default()
+                },
+            ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
+        },
+        Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+    )?;
+    let store_manager = StoreManager::new();
+    store_manager.add_store("main_cas", Store::new(compression_store.clone()));
+
+    let raw_data: Vec<u8> = (0..100u8).collect();
+    let digest = DigestInfo::try_new(HASH1, raw_data.len())?;
+    store_manager
+        .get_store("main_cas")
+        .unwrap()
+        .update_oneshot(digest, raw_data.clone().into())
+        .await?;
+
+    let unaligned_data =
+        read_with_alignment(&store_manager, 0, raw_data.len(), READ_OFFSET, READ_LIMIT).await?;
+    let unaligned_partial_block_reads = partial_block_reads(&compression_store);
+    assert!(
+        unaligned_partial_block_reads > 0,
+        "Expected unaligned read to decode partial blocks"
+    );
+
+    let aligned_data = read_with_alignment(
+        &store_manager,
+        BLOCK_SIZE,
+        raw_data.len(),
+        READ_OFFSET,
+        READ_LIMIT,
+    )
+    .await?;
+    assert_eq!(
+        aligned_data,
+        &raw_data[READ_OFFSET..READ_OFFSET + READ_LIMIT],
+        "Expected aligned read to return the requested range"
+    );
+    assert_eq!(aligned_data, unaligned_data);
+    assert_eq!(
+        partial_block_reads(&compression_store),
+        unaligned_partial_block_reads,
+        "Expected aligned read to only decode whole blocks"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+pub async fn only_allowed_digest_functions_are_accepted() -> Result<(), Box<dyn std::error::Error>>
+{
+    const VALUE: &str = "12456789abcdefghijk";
+
+    let store_manager = make_store_manager().await?;
+    store_manager.add_store(
+        "legacy_cas",
+        Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+    );
+    let bs_server = ByteStreamServer::new(
+        &make_bytestream_config(),
+        Some(&hashmap! {
+            INSTANCE_NAME.to_string() => CasStoreConfig {
+                cas_store: "main_cas".to_string(),
+                read_only: false,
+                digest_function_stores: hashmap! {
+                    ConfigDigestHashFunction::sha256 => "legacy_cas".to_string(),
+                },
+            },
+        }),
+        store_manager.as_ref(),
+    )?;
+    let digest = DigestInfo::try_new(HASH1, VALUE.len())?;
+    store_manager
+        .get_store("legacy_cas")
+        .unwrap()
+        .update_oneshot(digest, VALUE.into())
+        .await?;
+
+    {
+        // The allowed digest function is served from its own store.
+        let read_request = ReadRequest {
+            resource_name: format!("{INSTANCE_NAME}/blobs/sha256/{HASH1}/{}", VALUE.len()),
+            read_offset: 0,
+            read_limit: 0,
+        };
+        let mut read_stream = bs_server
+            .read(Request::new(read_request))
+            .await?
+            .into_inner();
+        let mut data = Vec::new();
+        while let Some(result_read_response) = read_stream.next().await {
+            data.extend_from_slice(&result_read_response?.data);
+        }
+        assert_eq!(data, VALUE.as_bytes());
+    }
+    {
+        let read_request = ReadRequest {
+            resource_name: format!("{INSTANCE_NAME}/blobs/blake3/{HASH1}/{}", VALUE.len()),
+            read_offset: 0,
+            read_limit: 0,
+        };
+        let Err(status) = bs_server.read(Request::new(read_request)).await else {
+            panic!("Expected read with a disallowed digest function to fail");
+        };
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(
+            status
+                .message()
+                .contains("Digest function BLAKE3 is not allowed, expected one of: SHA256"),
+            "Unexpected error: {status:?}"
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+pub async fn oversized_blob_is_rejected_up_front() -> Result<(), Box<dyn std::error::Error>> {
+    const MAX_BLOB_SIZE: usize = 1024;
+    const OVERSIZED_BLOB_SIZE: usize = 1024 * 1024 * 1024;
+
+    let store_manager = make_store_manager().await?;
+    let bs_server = ByteStreamServer::new(
+        &ByteStreamConfig {
+            max_blob_size: Some(MAX_BLOB_SIZE),
+            ..make_bytestream_config()
+        },
+        None,
+        store_manager.as_ref(),
+    )?;
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    {
+        // The client keeps the stream open, so the write can only finish if
+        // it is rejected based on the first message alone.
+        let (mut tx, body) = Body::channel();
+        let mut codec = ProstCodec::<WriteRequest, WriteRequest>::default();
+        // Note: This is an undocumented function.
+        let stream =
+            Streaming::new_request(codec.decoder(), body, Some(CompressionEncoding::Gzip), None);
+        let write_request = WriteRequest {
+            resource_name: format!(
+                "{INSTANCE_NAME}/uploads/4dcec57e-1389-4ab5-b188-4a59f22ceb4b/blobs/{HASH1}/{OVERSIZED_BLOB_SIZE}",
+            ),
+            write_offset: 0,
+            finish_write: false,
+            data: vec![0u8; MAX_BLOB_SIZE].into(),
+        };
+        tx.send_data(encode_stream_proto(&write_request)?).await?;
+        let status = bs_server
+            .write(Request::new(stream))
+            .await
+            .expect_err("Expected write of oversized blob to fail");
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(
+            status
+                .message()
+                .contains("is larger than the maximum allowed size of 1024 bytes"),
+            "Unexpected error: {status:?}"
+        );
+        let digest = DigestInfo::try_new(HASH1, OVERSIZED_BLOB_SIZE)?;
+        assert_eq!(store.has(digest).await?, None);
+    }
+    {
+        let read_request = ReadRequest {
+            resource_name: format!("{INSTANCE_NAME}/blobs/{HASH1}/{OVERSIZED_BLOB_SIZE}"),
+            read_offset: 0,
+            read_limit: 0,
+        };
+        let Err(status) = bs_server.read(Request::new(read_request)).await else {
+            panic!("Expected read of oversized blob to fail");
+        };
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+    {
+        // Ranged reads of a blob larger than the limit are allowed as long as
+        // they return no more than the limit.
+        const BLOB_SIZE: usize = MAX_BLOB_SIZE * 2;
+        let data = vec![7u8; BLOB_SIZE];
+        store
+            .update_oneshot(DigestInfo::try_new(HASH1, BLOB_SIZE)?, data.into())
+            .await?;
+        for (read_offset, read_limit, expected_size) in [(100, 10, 10), (BLOB_SIZE - 48, 0, 48)] {
+            let read_request = ReadRequest {
+                resource_name: format!("{INSTANCE_NAME}/blobs/{HASH1}/{BLOB_SIZE}"),
+                read_offset: read_offset as i64,
+                read_limit,
+            };
+            let mut read_stream = bs_server
+                .read(Request::new(read_request))
+                .await?
+                .into_inner();
+            let mut received_size = 0;
+            while let Some(response) = read_stream.next().await {
+                received_size += response?.data.len();
+            }
+            assert_eq!(received_size, expected_size);
+        }
+        let read_request = ReadRequest {
+            resource_name: format!("{INSTANCE_NAME}/blobs/{HASH1}/{BLOB_SIZE}"),
+            read_offset: 1,
+            read_limit: 0,
+        };
+        let Err(status) = bs_server.read(Request::new(read_request)).await else {
+            panic!("Expected read of more than the maximum size to fail");
+        };
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+pub async fn write_to_read_only_instance_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    const VALUE: &str = "12456789abcdefghijk";
+
+    let store_manager = make_store_manager().await?;
+    let bs_server = ByteStreamServer::new(
+        &make_bytestream_config(),
+        Some(&hashmap! {
+            INSTANCE_NAME.to_string() => CasStoreConfig {
+                cas_store: "main_cas".to_string(),
+                read_only: true,
+                digest_function_stores: hashmap! {},
+            },
+        }),
+        store_manager.as_ref(),
+    )?;
+    let store = store_manager.get_store("main_cas").unwrap();
+    let digest = DigestInfo::try_new(HASH1, VALUE.len())?;
+
+    let (mut tx, body) = Body::channel();
+    let mut codec = ProstCodec::<WriteRequest, WriteRequest>::default();
+    // Note: This is an undocumented function.
+    let stream =
+        Streaming::new_request(codec.decoder(), body, Some(CompressionEncoding::Gzip), None);
+    let write_request = WriteRequest {
+        resource_name: format!(
+            "{INSTANCE_NAME}/uploads/4dcec57e-1389-4ab5-b188-4a59f22ceb4b/blobs/{HASH1}/{}",
+            VALUE.len()
+        ),
+        write_offset: 0,
+        finish_write: true,
+        data: VALUE.into(),
+    };
+    tx.send_data(encode_stream_proto(&write_request)?).await?;
+    let status = bs_server
+        .write(Request::new(stream))
+        .await
+        .expect_err("Expected write to read only instance to fail");
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert_eq!(store.has(digest).await?, None);
+
+    // Reads are still served.
+    store.update_oneshot(digest, VALUE.into()).await?;
+    assert_eq!(
+        read_with_alignment(&store_manager, 0, VALUE.len(), 0, VALUE.len()).await?,
+        VALUE.as_bytes()
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+pub async fn write_verifies_hash_with_requested_digest_function(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use nativelink_util::digest_hasher::{DigestHasher, DigestHasherFunc};
+
+    const VALUE: &str = "12456789abcdefghijk";
+
+    let store_manager = Arc::new(StoreManager::new());
+    store_manager.add_store(
+        "main_cas",
+        store_factory(
+            &nativelink_config::stores::StoreConfig::verify(Box::new(
+                nativelink_config::stores::VerifyStore {
+                    backend: nativelink_config::stores::StoreConfig::memory(
+                        nativelink_config::stores::MemoryStore::default(),
+                    ),
+                    verify_size: true,
+                    verify_hash: true,
+                    verified_digest_cache_size: 0,
+                    read_verification_sample_rate: 0.0,
+                    content_policy: None,
+                },
+            )),
+            &store_manager,
+            None,
+            None,
+        )
+        .await?,
+    );
+    let bs_server = make_bytestream_server(store_manager.as_ref())?;
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    let blake3_digest = {
+        let mut hasher = DigestHasherFunc::Blake3.hasher();
+        hasher.update(VALUE.as_bytes());
+        hasher.finalize_digest()
+    };
+    let write = |uuid: &str, digest_function: &str| {
+        let (mut tx, body) = Body::channel();
+        let mut codec = ProstCodec::<WriteRequest, WriteRequest>::default();
+        // Note: This is an undocumented function.
+        let stream =
+            Streaming::new_request(codec.decoder(), body, Some(CompressionEncoding::Gzip), None);
+        let write_request = WriteRequest {
+            resource_name: format!(
+                "{INSTANCE_NAME}/uploads/{uuid}/blobs/{digest_function}/{}/{}",
+                blake3_digest.hash_str(),
+                VALUE.len()
+            ),
+            write_offset: 0,
+            finish_write: true,
+            data: VALUE.into(),
+        };
+        let bs_server = &bs_server;
+        async move {
+            tx.send_data(encode_stream_proto(&write_request)?).await?;
+            drop(tx);
+            Ok::<_, Box<dyn std::error::Error>>(bs_server.write(Request::new(stream)).await)
+        }
+    };
+
+    {
+        // Hashing the upload as sha256 must not match the blake3 digest.
+        let status = write("4dcec57e-1389-4ab5-b188-4a59f22ceb4b", "sha256")
+            .await?
+            .expect_err("Expected sha256 verification of a blake3 digest to fail");
+        assert!(
+            status.message().contains(&format!(
+                "Hashes do not match, expected digest {}-{} but received {} bytes with SHA256 hash",
+                blake3_digest.hash_str(),
+                VALUE.len(),
+                VALUE.len()
+            )),
+            "Unexpected error: {status:?}"
+        );
+        assert_eq!(store.has(blake3_digest).await?, None);
+    }
+    {
+        // The digest function in the resource name selects the hasher.
+        write("d3d8cc1a-6eb4-4b67-9d32-4f8b2a1de3a7", "blake3").await??;
+        assert_eq!(store.has(blake3_digest).await?, Some(VALUE.len()));
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+pub async fn write_flow_control_applies_backpressure_to_client(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+    use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+    use nativelink_util::store_trait::{StoreDriver, StoreKey, UploadSizeInfo};
+    use tokio::sync::Semaphore;
+
+    const CHUNK_SIZE: usize = 100;
+    const CHUNK_COUNT: usize = 10;
+
+    /// Store that reads one chunk of an upload for every permit added to
+    /// `gate`, to simulate a store that stalls.
+    struct GatedStore {
+        gate: Semaphore,
+        bytes_read: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StoreDriver for GatedStore {
+        async fn has_with_results(
+            self: Pin<&Self>,
+            _keys: &[StoreKey<'_>],
+            _results: &mut [Option<usize>],
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn update(
+            self: Pin<&Self>,
+            _key: StoreKey<'_>,
+            mut reader: DropCloserReadHalf,
+            _upload_size: UploadSizeInfo,
+        ) -> Result<(), Error> {
+            loop {
+                self.gate
+                    .acquire()
+                    .await
+                    .map_err(|e| make_err!(Code::Internal, "{e:?}"))?
+                    .forget();
+                let chunk = reader.recv().await?;
+                if chunk.is_empty() {
+                    return Ok(()); // EOF.
+                }
+                self.bytes_read.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+        }
+
+        async fn get_part(
+            self: Pin<&Self>,
+            _key: StoreKey<'_>,
+            _writer: &mut DropCloserWriteHalf,
+            _offset: usize,
+            _length: Option<usize>,
+        ) -> Result<(), Error> {
+            Err(make_err!(Code::Unimplemented, "Not implemented"))
+        }
+
+        fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+            self
+        }
+
+        fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+            self
+        }
+
+        fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+            self
+        }
+
+        fn register_metrics(
+            self: Arc<Self>,
+            _registry: &mut nativelink_util::metrics_utils::Registry,
+        ) {
+        }
+    }
+
+    default_health_status_indicator!(GatedStore);
+
+    let gated_store = Arc::new(GatedStore {
+        gate: Semaphore::new(0),
+        bytes_read: AtomicUsize::new(0),
+    });
+    let store_manager = StoreManager::new();
+    store_manager.add_store("main_cas", Store::new(gated_store.clone()));
+    let bs_server = ByteStreamServer::new(
+        &ByteStreamConfig {
+            write_flow_control_window: CHUNK_SIZE,
+            ..make_bytestream_config()
+        },
+        None,
+        &store_manager,
+    )?;
+
+    let (mut tx, body) = Body::channel();
+    let mut codec = ProstCodec::<WriteRequest, WriteRequest>::default();
+    // Note: This is an undocumented function.
+    let stream =
+        Streaming::new_request(codec.decoder(), body, Some(CompressionEncoding::Gzip), None);
+    let join_handle = spawn!("write_flow_control_write_stream", async move {
+        bs_server.write(Request::new(stream)).await
+    });
+
+    let make_write_request = |i: usize| WriteRequest {
+        resource_name: format!(
+            "{INSTANCE_NAME}/uploads/4dcec57e-1389-4ab5-b188-4a59f22ceb4b/blobs/{HASH1}/{}",
+            CHUNK_SIZE * CHUNK_COUNT
+        ),
+        write_offset: (i * CHUNK_SIZE) as i64,
+        finish_write: i == CHUNK_COUNT - 1,
+        data: vec![i as u8; CHUNK_SIZE].into(),
+    };
+
+    // The store does not read anything, so the client soon can not send more.
+    let mut sent = 0;
+    while sent < CHUNK_COUNT {
+        let send_fut = tx.send_data(encode_stream_proto(&make_write_request(sent))?);
+        if tokio::time::timeout(Duration::from_millis(100), send_fut)
+            .await
+            .is_err()
+        {
+            break;
+        }
+        sent += 1;
+    }
+    assert!(
+        sent < CHUNK_COUNT / 2,
+        "Expected client to be blocked, but it sent {sent} of {CHUNK_COUNT} chunks"
+    );
+    assert_eq!(gated_store.bytes_read.load(Ordering::Relaxed), 0);
+
+    // Once the store reads again the rest of the data is accepted.
+    gated_store.gate.add_permits(CHUNK_COUNT + 1);
+    for i in sent..CHUNK_COUNT {
+        tx.send_data(encode_stream_proto(&make_write_request(i))?)
+            .await?;
+    }
+    let response = join_handle.await??.into_inner();
+    assert_eq!(response.committed_size, (CHUNK_SIZE * CHUNK_COUNT) as i64);
+    assert_eq!(
+        gated_store.bytes_read.load(Ordering::Relaxed),
+        CHUNK_SIZE * CHUNK_COUNT
+    );
+    Ok(())
+}
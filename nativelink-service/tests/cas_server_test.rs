@@ -17,6 +17,7 @@ use std::sync::Arc;
 
 use futures::StreamExt;
 use maplit::hashmap;
+use nativelink_config::stores::ConfigDigestHashFunction;
 use nativelink_error::Error;
 use nativelink_macro::nativelink_test;
 use nativelink_proto::build::bazel::remote::execution::v2::content_addressable_storage_server::ContentAddressableStorage;
@@ -33,7 +34,7 @@ use nativelink_store::default_store_factory::store_factory;
 use nativelink_store::store_manager::StoreManager;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::DigestHasherFunc;
-use nativelink_util::store_trait::{StoreKey, StoreLike};
+use nativelink_util::store_trait::StoreLike;
 use pretty_assertions::assert_eq;
 use prometheus_client::registry::Registry;
 use prost_types::Timestamp;
@@ -67,6 +68,8 @@ fn make_cas_server(store_manager: &StoreManager) -> Result<CasServer, Error> {
         &hashmap! {
             "foo_instance_name".to_string() => nativelink_config::cas_server::CasStoreConfig{
                 cas_store: "main_cas".to_string(),
+                read_only: false,
+                digest_function_stores: hashmap! {},
             }
         },
         store_manager,
@@ -289,10 +292,7 @@ async fn batch_read_blobs_read_two_blobs_success_one_fail() -> Result<(), Box<dy
                         data: vec![].into(),
                         status: Some(GrpcStatus {
                             code: Code::NotFound as i32,
-                            message: format!(
-                                "Key {:?} not found",
-                                StoreKey::from(DigestInfo::try_from(digest3)?)
-                            ),
+                            message: format!("{HASH3}/{}", digest3.size_bytes),
                             details: vec![],
                         }),
                         compressor: compressor::Value::Identity.into(),
@@ -422,6 +422,15 @@ async fn get_tree_read_directories_without_paging() -> Result<(), Box<dyn std::e
     Ok(())
 }
 
+fn make_page_token(root: DigestInfo, offset: usize, digest_infos: &[DigestInfo]) -> String {
+    let digests = digest_infos
+        .iter()
+        .map(|digest_info| format!("{}-{}", digest_info.hash_str(), digest_info.size_bytes))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{offset}:{}-{}:{digests}", root.hash_str(), root.size_bytes)
+}
+
 #[nativelink_test]
 async fn get_tree_read_directories_with_paging() -> Result<(), Box<dyn std::error::Error>> {
     let store_manager = make_store_manager().await?;
@@ -444,11 +453,7 @@ async fn get_tree_read_directories_with_paging() -> Result<(), Box<dyn std::erro
         .get_tree(Request::new(GetTreeRequest {
             instance_name: INSTANCE_NAME.to_string(),
             page_size: 2,
-            page_token: format!(
-                "{}-{}",
-                root_directory_digest_info.hash_str(),
-                root_directory_digest_info.size_bytes
-            ),
+            page_token: String::new(),
             root_digest: Some(root_directory_digest_info.into()),
             digest_function: digest_function::Value::Sha256.into(),
         }))
@@ -461,23 +466,40 @@ async fn get_tree_read_directories_with_paging() -> Result<(), Box<dyn std::erro
             .filter_map(|x| async move { Some(x.unwrap()) })
             .collect::<Vec<_>>()
             .await,
-        vec![GetTreeResponse {
-            directories: vec![root_directory.clone(), sub_directories[0].clone()],
-            next_page_token: format!(
-                "{}-{}",
-                sub_directory_digest_infos[1].hash_str(),
-                sub_directory_digest_infos[1].size_bytes
-            ),
-        }]
+        vec![
+            GetTreeResponse {
+                directories: vec![root_directory.clone(), sub_directories[0].clone()],
+                next_page_token: make_page_token(
+                    root_directory_digest_info,
+                    2,
+                    &sub_directory_digest_infos[1..]
+                ),
+            },
+            GetTreeResponse {
+                directories: vec![sub_directories[1].clone(), sub_directories[2].clone()],
+                next_page_token: make_page_token(
+                    root_directory_digest_info,
+                    4,
+                    &sub_directory_digest_infos[3..]
+                ),
+            },
+            GetTreeResponse {
+                directories: vec![sub_directories[3].clone(), sub_directories[4].clone()],
+                next_page_token: String::new(),
+            },
+        ]
     );
+
+    // Resuming from a `next_page_token` returns that page and the ones that
+    // succeed it.
     let raw_response = cas_server
         .get_tree(Request::new(GetTreeRequest {
             instance_name: INSTANCE_NAME.to_string(),
             page_size: 2,
-            page_token: format!(
-                "{}-{}",
-                sub_directory_digest_infos[1].hash_str(),
-                sub_directory_digest_infos[1].size_bytes
+            page_token: make_page_token(
+                root_directory_digest_info,
+                2,
+                &sub_directory_digest_infos[1..],
             ),
             root_digest: Some(root_directory_digest_info.into()),
             digest_function: digest_function::Value::Sha256.into(),
@@ -491,42 +513,245 @@ async fn get_tree_read_directories_with_paging() -> Result<(), Box<dyn std::erro
             .filter_map(|x| async move { Some(x.unwrap()) })
             .collect::<Vec<_>>()
             .await,
-        vec![GetTreeResponse {
-            directories: vec![sub_directories[1].clone(), sub_directories[2].clone()],
-            next_page_token: format!(
-                "{}-{}",
-                sub_directory_digest_infos[3].hash_str(),
-                sub_directory_digest_infos[3].size_bytes
-            ),
-        }]
+        vec![
+            GetTreeResponse {
+                directories: vec![sub_directories[1].clone(), sub_directories[2].clone()],
+                next_page_token: make_page_token(
+                    root_directory_digest_info,
+                    4,
+                    &sub_directory_digest_infos[3..]
+                ),
+            },
+            GetTreeResponse {
+                directories: vec![sub_directories[3].clone(), sub_directories[4].clone()],
+                next_page_token: String::new(),
+            },
+        ]
     );
-    let raw_response = cas_server
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_tree_resumes_from_bounded_page_tokens() -> Result<(), Box<dyn std::error::Error>> {
+    // More sub-directories than fit in a page token.
+    const SUB_DIRECTORIES_LENGTH: usize = 130;
+    let store_manager = make_store_manager().await?;
+    let cas_server = make_cas_server(&store_manager)?;
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    let mut sub_directories = Vec::new();
+    let mut sub_directory_digest_infos = Vec::new();
+    for i in 0..SUB_DIRECTORIES_LENGTH {
+        let sub_directory = Directory {
+            node_properties: Some(NodeProperties {
+                properties: vec![],
+                mtime: Some(Timestamp {
+                    seconds: i as i64,
+                    nanos: 0,
+                }),
+                unix_mode: None,
+            }),
+            ..Default::default()
+        };
+        sub_directory_digest_infos.push(
+            serialize_and_upload_message(
+                &sub_directory,
+                store.as_pin(),
+                &mut DigestHasherFunc::Sha256.hasher(),
+            )
+            .await?,
+        );
+        sub_directories.push(sub_directory);
+    }
+    let root_directory = Directory {
+        directories: sub_directory_digest_infos
+            .iter()
+            .enumerate()
+            .map(|(i, digest_info)| DirectoryNode {
+                name: format!("sub_directory_{i}"),
+                digest: Some((*digest_info).into()),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let root_directory_digest_info = serialize_and_upload_message(
+        &root_directory,
+        store.as_pin(),
+        &mut DigestHasherFunc::Sha256.hasher(),
+    )
+    .await?;
+    let get_page = |page_token: String| {
+        let cas_server = &cas_server;
+        async move {
+            cas_server
+                .get_tree(Request::new(GetTreeRequest {
+                    instance_name: INSTANCE_NAME.to_string(),
+                    page_size: 1,
+                    page_token,
+                    root_digest: Some(root_directory_digest_info.into()),
+                    digest_function: digest_function::Value::Sha256.into(),
+                }))
+                .await?
+                .into_inner()
+                .next()
+                .await
+                .unwrap()
+        }
+    };
+
+    // The queue of directories is too large for the token, so it is kept on
+    // the server and the token only names it.
+    let cursor_prefix = |offset: usize| {
+        format!(
+            "{offset}:{}-{}:@",
+            root_directory_digest_info.hash_str(),
+            root_directory_digest_info.size_bytes
+        )
+    };
+    let page = get_page(String::new()).await?;
+    assert_eq!(page.directories, vec![root_directory.clone()]);
+    assert!(page.next_page_token.starts_with(&cursor_prefix(1)));
+
+    // Resuming from the kept queue does not walk the tree from the root
+    // again, so it works even if the root is gone.
+    store.remove(root_directory_digest_info).await?;
+    let first_cursor_token = page.next_page_token;
+    let page = get_page(first_cursor_token.clone()).await?;
+    assert_eq!(page.directories, vec![sub_directories[0].clone()]);
+    assert!(page.next_page_token.starts_with(&cursor_prefix(2)));
+    // Once the queue is small enough, it is part of the token again.
+    let page = get_page(page.next_page_token).await?;
+    assert_eq!(page.directories, vec![sub_directories[1].clone()]);
+    assert_eq!(
+        page.next_page_token,
+        make_page_token(
+            root_directory_digest_info,
+            3,
+            &sub_directory_digest_infos[2..]
+        )
+    );
+
+    // A token whose queue is no longer kept is resumed by walking from the
+    // root again.
+    serialize_and_upload_message(
+        &root_directory,
+        store.as_pin(),
+        &mut DigestHasherFunc::Sha256.hasher(),
+    )
+    .await?;
+    let page = get_page(first_cursor_token).await?;
+    assert_eq!(page.directories, vec![sub_directories[0].clone()]);
+    assert!(page.next_page_token.starts_with(&cursor_prefix(2)));
+
+    // Tokens that only name the first directory of the page are still
+    // accepted.
+    let page = get_page(format!(
+        "{}-{}",
+        sub_directory_digest_infos[0].hash_str(),
+        sub_directory_digest_infos[0].size_bytes
+    ))
+    .await?;
+    assert_eq!(page.directories, vec![sub_directories[0].clone()]);
+    assert!(page.next_page_token.starts_with(&cursor_prefix(2)));
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_tree_rejects_page_token_of_other_root() -> Result<(), Box<dyn std::error::Error>> {
+    let store_manager = make_store_manager().await?;
+    let cas_server = make_cas_server(&store_manager)?;
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    let SetupDirectoryResult {
+        root_directory_digest_info,
+        sub_directory_digest_infos,
+        ..
+    } = setup_directory_structure(store.as_pin()).await?;
+
+    // A token made for the walk of `sub_directory[0]` must not be used to
+    // resume the walk of the root directory.
+    let result = cas_server
         .get_tree(Request::new(GetTreeRequest {
             instance_name: INSTANCE_NAME.to_string(),
             page_size: 2,
-            page_token: format!(
-                "{}-{}",
-                sub_directory_digest_infos[3].hash_str(),
-                sub_directory_digest_infos[3].size_bytes
+            page_token: make_page_token(
+                sub_directory_digest_infos[0],
+                1,
+                &sub_directory_digest_infos[1..],
             ),
             root_digest: Some(root_directory_digest_info.into()),
             digest_function: digest_function::Value::Sha256.into(),
         }))
         .await;
-    assert!(raw_response.is_ok());
+    assert_eq!(result.err().unwrap().code(), Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_tree_fetches_directories_lazily() -> Result<(), Box<dyn std::error::Error>> {
+    let store_manager = make_store_manager().await?;
+    let cas_server = make_cas_server(&store_manager)?;
+    let store = store_manager.get_store("main_cas").unwrap();
+
+    let SetupDirectoryResult {
+        root_directory,
+        root_directory_digest_info,
+        sub_directories,
+        sub_directory_digest_infos,
+    } = setup_directory_structure(store.as_pin()).await?;
+
+    let mut stream = cas_server
+        .get_tree(Request::new(GetTreeRequest {
+            instance_name: INSTANCE_NAME.to_string(),
+            page_size: 2,
+            page_token: String::new(),
+            root_digest: Some(root_directory_digest_info.into()),
+            digest_function: digest_function::Value::Sha256.into(),
+        }))
+        .await?
+        .into_inner();
     assert_eq!(
-        raw_response
-            .unwrap()
-            .into_inner()
-            .filter_map(|x| async move { Some(x.unwrap()) })
-            .collect::<Vec<_>>()
-            .await,
-        vec![GetTreeResponse {
-            directories: vec![sub_directories[3].clone(), sub_directories[4].clone()],
-            next_page_token: String::new(),
-        }]
+        stream.next().await.unwrap()?.directories,
+        vec![root_directory, sub_directories[0].clone()]
     );
 
+    // Directories of later pages are only fetched once those pages are
+    // polled, so removing one now only fails the page it belongs to.
+    store.remove(sub_directory_digest_infos[4]).await?;
+    let second_page = stream.next().await.unwrap()?;
+    assert_eq!(
+        second_page.directories,
+        vec![sub_directories[1].clone(), sub_directories[2].clone()]
+    );
+    let err = stream.next().await.unwrap().unwrap_err();
+    assert_eq!(err.code(), Code::NotFound, "{err:?}");
+    assert!(stream.next().await.is_none());
+
+    // The last `next_page_token` can be used to resume once it exists again.
+    serialize_and_upload_message(
+        &sub_directories[4],
+        store.as_pin(),
+        &mut DigestHasherFunc::Sha256.hasher(),
+    )
+    .await?;
+    let pages = cas_server
+        .get_tree(Request::new(GetTreeRequest {
+            instance_name: INSTANCE_NAME.to_string(),
+            page_size: 2,
+            page_token: second_page.next_page_token,
+            root_digest: Some(root_directory_digest_info.into()),
+            digest_function: digest_function::Value::Sha256.into(),
+        }))
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(pages.len(), 1);
+    assert_eq!(
+        pages[0].as_ref().unwrap().directories,
+        vec![sub_directories[3].clone(), sub_directories[4].clone()]
+    );
     Ok(())
 }
 
@@ -623,3 +848,265 @@ async fn batch_update_blobs_two_items_existence_with_third_missing(
     }
     Ok(())
 }
+
+#[nativelink_test]
+async fn read_only_instance_rejects_writes_and_allows_reads(
+) -> Result<(), Box<dyn std::error::Error>> {
+    const VALUE: &str = "1";
+
+    let store_manager = make_store_manager().await?;
+    let cas_server = CasServer::new(
+        &hashmap! {
+            INSTANCE_NAME.to_string() => nativelink_config::cas_server::CasStoreConfig{
+                cas_store: "main_cas".to_string(),
+                read_only: true,
+                digest_function_stores: hashmap! {},
+            }
+        },
+        &store_manager,
+    )?;
+    let digest = Digest {
+        hash: HASH1.to_string(),
+        size_bytes: VALUE.len() as i64,
+    };
+    store_manager
+        .get_store("main_cas")
+        .unwrap()
+        .update_oneshot(DigestInfo::try_new(HASH1, VALUE.len())?, VALUE.into())
+        .await?;
+
+    let status = cas_server
+        .batch_update_blobs(Request::new(BatchUpdateBlobsRequest {
+            instance_name: INSTANCE_NAME.to_string(),
+            requests: vec![batch_update_blobs_request::Request {
+                digest: Some(Digest {
+                    hash: HASH2.to_string(),
+                    size_bytes: VALUE.len() as i64,
+                }),
+                data: VALUE.into(),
+                compressor: compressor::Value::Identity.into(),
+            }],
+            digest_function: digest_function::Value::Sha256.into(),
+        }))
+        .await
+        .expect_err("Expected write to read only instance to fail");
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(
+        store_manager
+            .get_store("main_cas")
+            .unwrap()
+            .has(DigestInfo::try_new(HASH2, VALUE.len())?)
+            .await?,
+        None,
+        "Rejected blob should not be stored"
+    );
+
+    let response = cas_server
+        .batch_read_blobs(Request::new(BatchReadBlobsRequest {
+            instance_name: INSTANCE_NAME.to_string(),
+            digests: vec![digest.clone()],
+            acceptable_compressors: vec![compressor::Value::Identity.into()],
+            digest_function: digest_function::Value::Sha256.into(),
+        }))
+        .await?
+        .into_inner();
+    assert_eq!(response.responses.len(), 1);
+    assert_eq!(response.responses[0].data, VALUE.as_bytes());
+    Ok(())
+}
+
+#[nativelink_test]
+async fn find_missing_blobs_uses_existence_cache_for_repeated_queries(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use nativelink_store::existence_cache_store::ExistenceCacheStore;
+    use nativelink_store::memory_store::MemoryStore;
+    use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+    use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+    use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, UploadSizeInfo};
+
+    /// Forwards to a memory store while counting the `has` calls and the
+    /// digests they look up.
+    struct CountingHasStore {
+        inner_store: Store,
+        has_calls: AtomicUsize,
+        has_keys: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StoreDriver for CountingHasStore {
+        async fn has_with_results(
+            self: Pin<&Self>,
+            keys: &[StoreKey<'_>],
+            results: &mut [Option<usize>],
+        ) -> Result<(), Error> {
+            self.has_calls.fetch_add(1, Ordering::Relaxed);
+            self.has_keys.fetch_add(keys.len(), Ordering::Relaxed);
+            self.inner_store.has_with_results(keys, results).await
+        }
+
+        async fn update(
+            self: Pin<&Self>,
+            key: StoreKey<'_>,
+            reader: DropCloserReadHalf,
+            size_info: UploadSizeInfo,
+        ) -> Result<(), Error> {
+            self.inner_store.update(key, reader, size_info).await
+        }
+
+        async fn get_part(
+            self: Pin<&Self>,
+            key: StoreKey<'_>,
+            writer: &mut DropCloserWriteHalf,
+            offset: usize,
+            length: Option<usize>,
+        ) -> Result<(), Error> {
+            self.inner_store.get_part(key, writer, offset, length).await
+        }
+
+        fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+            self
+        }
+
+        fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+            self
+        }
+
+        fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+            self
+        }
+    }
+
+    default_health_status_indicator!(CountingHasStore);
+
+    const VALUE: &str = "1";
+    let backend = Arc::new(CountingHasStore {
+        inner_store: Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+        has_calls: AtomicUsize::new(0),
+        has_keys: AtomicUsize::new(0),
+    });
+    backend
+        .inner_store
+        .update_oneshot(DigestInfo::try_new(HASH1, VALUE.len())?, VALUE.into())
+        .await?;
+    let store_manager = Arc::new(StoreManager::new());
+    store_manager.add_store(
+        "main_cas",
+        Store::new(ExistenceCacheStore::new(
+            &nativelink_config::stores::ExistenceCacheStore {
+                backend: nativelink_config::stores::StoreConfig::noop, // Note: Not used.
+                eviction_policy: None,
+                cache_missing_for_s: 60,
+                max_backend_batch_size: 2,
+            },
+            Store::new(backend.clone()),
+        )),
+    );
+    let cas_server = make_cas_server(&store_manager)?;
+
+    let find_missing_blobs = || async {
+        let blob_digests = [HASH1, HASH2, HASH3]
+            .into_iter()
+            .map(|hash| Digest {
+                hash: hash.to_string(),
+                size_bytes: VALUE.len() as i64,
+            })
+            .collect();
+        cas_server
+            .find_missing_blobs(Request::new(FindMissingBlobsRequest {
+                instance_name: INSTANCE_NAME.to_string(),
+                blob_digests,
+                digest_function: digest_function::Value::Sha256.into(),
+            }))
+            .await
+            .map(|response| response.into_inner().missing_blob_digests)
+    };
+    let expected_missing = vec![
+        Digest {
+            hash: HASH2.to_string(),
+            size_bytes: VALUE.len() as i64,
+        },
+        Digest {
+            hash: HASH3.to_string(),
+            size_bytes: VALUE.len() as i64,
+        },
+    ];
+
+    assert_eq!(find_missing_blobs().await?, expected_missing);
+    // The three digests were looked up in batches of at most two.
+    assert_eq!(backend.has_calls.load(Ordering::Relaxed), 2);
+    assert_eq!(backend.has_keys.load(Ordering::Relaxed), 3);
+
+    // Both found and missing digests are now answered from the cache.
+    assert_eq!(find_missing_blobs().await?, expected_missing);
+    assert_eq!(backend.has_calls.load(Ordering::Relaxed), 2);
+    assert_eq!(backend.has_keys.load(Ordering::Relaxed), 3);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn digest_functions_are_routed_to_their_own_store() -> Result<(), Box<dyn std::error::Error>>
+{
+    const VALUE: &str = "1";
+
+    let store_manager = make_store_manager().await?;
+    store_manager.add_store(
+        "legacy_cas",
+        store_factory(
+            &nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            &store_manager,
+            None,
+            None,
+        )
+        .await?,
+    );
+    let cas_server = CasServer::new(
+        &hashmap! {
+            INSTANCE_NAME.to_string() => nativelink_config::cas_server::CasStoreConfig{
+                cas_store: "main_cas".to_string(),
+                read_only: false,
+                digest_function_stores: hashmap! {
+                    ConfigDigestHashFunction::sha256 => "legacy_cas".to_string(),
+                },
+            }
+        },
+        &store_manager,
+    )?;
+    let digest = DigestInfo::try_new(HASH1, VALUE.len())?;
+    store_manager
+        .get_store("legacy_cas")
+        .unwrap()
+        .update_oneshot(digest, VALUE.into())
+        .await?;
+
+    let find_missing_blobs = |digest_function: digest_function::Value| {
+        cas_server.find_missing_blobs(Request::new(FindMissingBlobsRequest {
+            instance_name: INSTANCE_NAME.to_string(),
+            blob_digests: vec![digest.into()],
+            digest_function: digest_function.into(),
+        }))
+    };
+    // The allowed digest function is served from its own store.
+    let response = find_missing_blobs(digest_function::Value::Sha256)
+        .await?
+        .into_inner();
+    assert_eq!(response.missing_blob_digests, vec![]);
+
+    let status = find_missing_blobs(digest_function::Value::Blake3)
+        .await
+        .expect_err("Expected a disallowed digest function to be rejected");
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert!(
+        status
+            .message()
+            .contains("Digest function BLAKE3 is not allowed, expected one of: SHA256"),
+        "Unexpected error: {status:?}"
+    );
+    Ok(())
+}
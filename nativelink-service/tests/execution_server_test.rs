@@ -0,0 +1,234 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use maplit::hashmap;
+use nativelink_config::cas_server::ExecutionConfig;
+use nativelink_error::{Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_proto::build::bazel::remote::execution::v2::command::EnvironmentVariable;
+use nativelink_proto::build::bazel::remote::execution::v2::execution_server::Execution;
+use nativelink_proto::build::bazel::remote::execution::v2::{Action, Command, ExecuteRequest};
+use nativelink_scheduler::action_scheduler::ActionScheduler;
+use nativelink_scheduler::simple_scheduler::SimpleScheduler;
+use nativelink_scheduler::worker::Worker;
+use nativelink_scheduler::worker_scheduler::WorkerScheduler;
+use nativelink_service::execution_server::{canonical_action_digest, ExecutionServer};
+use nativelink_store::ac_utils::serialize_and_upload_message;
+use nativelink_store::memory_store::MemoryStore;
+use nativelink_store::store_manager::StoreManager;
+use nativelink_util::action_messages::{ActionResult, ActionStage, ActionState, WorkerId};
+use nativelink_util::common::DigestInfo;
+use nativelink_util::digest_hasher::DigestHasherFunc;
+use nativelink_util::platform_properties::PlatformProperties;
+use nativelink_util::store_trait::{Store, StoreLike};
+use pretty_assertions::assert_eq;
+use tokio::sync::mpsc;
+use tonic::Request;
+use uuid::Uuid;
+
+const INSTANCE_NAME: &str = "foo_instance_name";
+const DIGEST_FUNCTION: DigestHasherFunc = DigestHasherFunc::Sha256;
+
+struct TestContext {
+    cas_store: Store,
+    ac_store: Store,
+    scheduler: Arc<SimpleScheduler>,
+    execution_server: ExecutionServer,
+}
+
+fn make_execution_server(ac_store_name: Option<&str>) -> Result<TestContext, Error> {
+    let store_manager = StoreManager::new();
+    let cas_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    store_manager.add_store("main_cas", cas_store.clone());
+    let ac_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    store_manager.add_store("main_ac", ac_store.clone());
+    let scheduler = Arc::new(SimpleScheduler::new(
+        &nativelink_config::schedulers::SimpleScheduler::default(),
+    ));
+    let execution_server = ExecutionServer::new(
+        &hashmap! {
+            INSTANCE_NAME.to_string() => ExecutionConfig {
+                cas_store: "main_cas".to_string(),
+                scheduler: "main_scheduler".to_string(),
+                canonicalize_actions: true,
+                ac_store: ac_store_name.map(str::to_string),
+            },
+        },
+        &hashmap! {
+            "main_scheduler".to_string() => scheduler.clone() as Arc<dyn ActionScheduler>,
+        },
+        &store_manager,
+    )?;
+    Ok(TestContext {
+        cas_store,
+        ac_store,
+        scheduler,
+        execution_server,
+    })
+}
+
+async fn execute(
+    execution_server: &ExecutionServer,
+    digest: DigestInfo,
+) -> Result<ActionState, Box<dyn std::error::Error>> {
+    let mut stream = execution_server
+        .execute(Request::new(ExecuteRequest {
+            instance_name: INSTANCE_NAME.to_string(),
+            action_digest: Some(digest.into()),
+            digest_function: DIGEST_FUNCTION.proto_digest_func().into(),
+            ..Default::default()
+        }))
+        .await?
+        .into_inner();
+    let operation = stream.next().await.expect("Expected an operation")?;
+    Ok(ActionState::try_from(operation)?)
+}
+
+async fn upload_action(
+    cas_store: &Store,
+    env: &[(&str, &str)],
+) -> Result<(DigestInfo, Action), Error> {
+    let command = Command {
+        arguments: vec!["echo".to_string(), "hello".to_string()],
+        environment_variables: env
+            .iter()
+            .map(|(name, value)| EnvironmentVariable {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+        output_paths: vec!["out/a".to_string(), "out/b".to_string()],
+        ..Default::default()
+    };
+    let command_digest =
+        serialize_and_upload_message(&command, cas_store.as_pin(), &mut DIGEST_FUNCTION.hasher())
+            .await?;
+    let action = Action {
+        command_digest: Some(command_digest.into()),
+        input_root_digest: Some(DigestInfo::zero_digest().into()),
+        ..Default::default()
+    };
+    let action_digest =
+        serialize_and_upload_message(&action, cas_store.as_pin(), &mut DIGEST_FUNCTION.hasher())
+            .await?;
+    Ok((action_digest, action))
+}
+
+#[nativelink_test]
+async fn env_var_order_does_not_change_merge_key_test() -> Result<(), Error> {
+    let cas_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+
+    let (digest1, action1) = upload_action(&cas_store, &[("A", "1"), ("B", "2")]).await?;
+    let (digest2, action2) = upload_action(&cas_store, &[("B", "2"), ("A", "1")]).await?;
+    assert_ne!(digest1, digest2);
+
+    let canonical_digest1 = canonical_action_digest(&cas_store, &action1, DIGEST_FUNCTION).await?;
+    let canonical_digest2 = canonical_action_digest(&cas_store, &action2, DIGEST_FUNCTION).await?;
+    // The first action already is canonical, so it is its own merge key.
+    assert_eq!(canonical_digest1, digest1);
+    assert_eq!(canonical_digest2, digest1);
+
+    Ok(())
+}
+
+#[nativelink_test]
+async fn equivalent_actions_are_merged_under_their_own_digest_test(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let TestContext {
+        cas_store,
+        ac_store,
+        scheduler,
+        execution_server,
+    } = make_execution_server(Some("main_ac"))?;
+
+    let (digest1, _) = upload_action(&cas_store, &[("B", "2"), ("A", "1")]).await?;
+    let (digest2, _) = upload_action(&cas_store, &[("A", "1"), ("B", "2")]).await?;
+
+    let state1 = execute(&execution_server, digest1).await?;
+    // The action is scheduled under the digest the client sent, not under
+    // its canonical form.
+    assert_eq!(state1.id.unique_qualifier.digest, digest1);
+    let state2 = execute(&execution_server, digest2).await?;
+    assert_eq!(state2.id, state1.id, "Expected the actions to be merged");
+
+    // Run the action to completion.
+    let worker_id = WorkerId(Uuid::new_v4());
+    let (tx, mut rx_from_worker) = mpsc::unbounded_channel();
+    let now_s = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    scheduler
+        .add_worker(Worker::new(
+            worker_id,
+            PlatformProperties::default(),
+            tx,
+            now_s,
+        ))
+        .await?;
+    // The connection message and the action.
+    rx_from_worker
+        .recv()
+        .await
+        .expect("Expected connection message");
+    rx_from_worker
+        .recv()
+        .await
+        .expect("Expected action to be started");
+    let mut client_rx = scheduler
+        .find_existing_action(&state1.id.unique_qualifier)
+        .await
+        .expect("Expected action to exist");
+    scheduler
+        .update_action(
+            &worker_id,
+            state1.id.unique_qualifier.clone(),
+            Ok(ActionStage::Completed(ActionResult {
+                exit_code: 0,
+                ..ActionResult::default()
+            })),
+        )
+        .await?;
+    client_rx.changed().await?;
+
+    // The joined action gets its own action cache entry. The merged action's
+    // own entry is written by the worker.
+    let mut has_result = false;
+    for _ in 0..100 {
+        has_result = ac_store.has(digest2).await?.is_some();
+        if has_result {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    assert!(has_result, "Expected result of joined action to be cached");
+    assert_eq!(ac_store.has(digest1).await?, None);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn canonicalize_actions_requires_ac_store_test() -> Result<(), Error> {
+    let Err(err) = make_execution_server(None) else {
+        panic!("Expected execution server without ac_store to be rejected");
+    };
+    assert_eq!(err.code, Code::InvalidArgument);
+    Ok(())
+}
@@ -0,0 +1,300 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::try_join;
+use nativelink_error::{error_if, make_err, Code, Error, ResultExt};
+use nativelink_util::buf_channel::{
+    make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
+};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::metrics_utils::{Collector, CollectorState, MetricsComponent, Registry};
+use nativelink_util::store_trait::{
+    DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+
+const DEFAULT_ALIGNMENT: usize = 4 * 1024;
+
+/// Maximum number of trailers read at the same time by `has_with_results`.
+const MAX_CONCURRENT_TRAILER_READS: usize = 64;
+
+/// Number of bytes at the end of every stored blob that hold the true size
+/// of the blob as a little endian u64.
+const TRAILER_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Size of a blob of `size` bytes once padded and the trailer is added.
+fn padded_size(size: usize, alignment: usize) -> usize {
+    (size + TRAILER_SIZE).div_ceil(alignment) * alignment
+}
+
+pub struct AlignmentStore {
+    inner_store: Store,
+    alignment: usize,
+}
+
+impl AlignmentStore {
+    pub fn new(
+        config: &nativelink_config::stores::AlignmentStore,
+        inner_store: Store,
+    ) -> Result<Arc<Self>, Error> {
+        let alignment = if config.alignment == 0 {
+            DEFAULT_ALIGNMENT
+        } else {
+            config.alignment
+        };
+        // Every padded blob ends with a block that holds the trailer.
+        error_if!(
+            alignment < TRAILER_SIZE,
+            "Alignment {alignment} must be at least {TRAILER_SIZE} bytes in AlignmentStore"
+        );
+        Ok(Arc::new(Self {
+            inner_store,
+            alignment,
+        }))
+    }
+
+    /// Reads the true size of `key` from the trailer of the stored blob,
+    /// which is `padded_size` bytes long in the inner store.
+    async fn true_size(&self, key: StoreKey<'_>, padded_size: usize) -> Result<usize, Error> {
+        error_if!(
+            padded_size < self.alignment || padded_size % self.alignment != 0,
+            "Stored size {padded_size} of {key:?} is not a multiple of the alignment {} in alignment store",
+            self.alignment
+        );
+        let last_block = self
+            .inner_store
+            .get_part_unchunked(
+                key.borrow(),
+                padded_size - self.alignment,
+                Some(self.alignment),
+            )
+            .await
+            .err_tip(|| "Failed to read trailer in alignment store")?;
+        error_if!(
+            last_block.len() != self.alignment,
+            "Expected to read {} bytes of the trailer of {key:?}, got {} in alignment store",
+            self.alignment,
+            last_block.len()
+        );
+        let mut trailer = [0u8; TRAILER_SIZE];
+        trailer.copy_from_slice(&last_block[self.alignment - TRAILER_SIZE..]);
+        let size = usize::try_from(u64::from_le_bytes(trailer))
+            .err_tip(|| "Could not convert size to usize in alignment store")?;
+        error_if!(
+            size > padded_size - TRAILER_SIZE,
+            "Trailer of {key:?} records {size} bytes, but only {padded_size} bytes are stored in alignment store"
+        );
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl StoreDriver for AlignmentStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store
+            .has_with_results(keys, results)
+            .await
+            .err_tip(|| "In AlignmentStore::has_with_results")?;
+        let true_size_futs: Vec<_> = keys
+            .iter()
+            .zip(results.iter().copied())
+            .map(|(key, padded_size)| async move {
+                match padded_size {
+                    Some(padded_size) => self.true_size(key.borrow(), padded_size).await.map(Some),
+                    None => Ok(None),
+                }
+            })
+            .collect();
+        let true_sizes: Vec<Option<usize>> = stream::iter(true_size_futs)
+            .buffered(MAX_CONCURRENT_TRAILER_READS)
+            .try_collect()
+            .await?;
+        results.copy_from_slice(&true_sizes);
+        Ok(())
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        upload_size: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        let alignment = self.alignment;
+        let padded_upload_size = match upload_size {
+            UploadSizeInfo::ExactSize(size) => {
+                UploadSizeInfo::ExactSize(padded_size(size, alignment))
+            }
+            UploadSizeInfo::MaxSize(size) => UploadSizeInfo::MaxSize(padded_size(size, alignment)),
+        };
+        let (mut tx, rx) = make_buf_channel_pair();
+        let pad_fut = async move {
+            let mut size = 0;
+            loop {
+                let chunk = reader
+                    .recv()
+                    .await
+                    .err_tip(|| "Failed to receive data in alignment store")?;
+                if chunk.is_empty() {
+                    break; // EOF.
+                }
+                size += chunk.len();
+                tx.send(chunk)
+                    .await
+                    .err_tip(|| "Failed to forward data in alignment store")?;
+            }
+            let mut padding = BytesMut::zeroed(padded_size(size, alignment) - size);
+            let trailer_start = padding.len() - TRAILER_SIZE;
+            padding[trailer_start..].copy_from_slice(&(size as u64).to_le_bytes());
+            tx.send(padding.freeze())
+                .await
+                .err_tip(|| "Failed to send padding in alignment store")?;
+            tx.send_eof()
+                .err_tip(|| "Failed to send EOF in alignment store")
+        };
+        try_join!(
+            self.inner_store.update(key, rx, padded_upload_size),
+            pad_fut
+        )
+        .err_tip(|| "In AlignmentStore::update")?;
+        Ok(())
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        let padded_size = self
+            .inner_store
+            .has(key.borrow())
+            .await
+            .err_tip(|| "In AlignmentStore::get_part")?
+            .ok_or_else(|| make_err!(Code::NotFound, "{key:?} not found in alignment store"))?;
+        let data_end = length.map_or(padded_size, |length| {
+            offset.saturating_add(length).min(padded_size)
+        });
+        if offset >= data_end {
+            // No data is requested, only the offset needs to be checked.
+            let size = self.true_size(key.borrow(), padded_size).await?;
+            error_if!(
+                offset > size,
+                "Offset {offset} is past the end of {key:?} ({size} bytes) in alignment store"
+            );
+            return writer
+                .send_eof()
+                .err_tip(|| "Failed to send EOF in alignment store get_part");
+        }
+
+        let aligned_start = offset - offset % self.alignment;
+        let aligned_end = data_end.div_ceil(self.alignment) * self.alignment;
+        // The trailer is read while the data is already being fetched, the
+        // true size is only needed to trim the data that is sent.
+        let size_fut = self.true_size(key.borrow(), padded_size);
+        let (mut tx, mut rx) = make_buf_channel_pair();
+        let trim_key = key.borrow();
+        let trim_fut = async move {
+            let size = size_fut.await?;
+            error_if!(
+                offset > size,
+                "Offset {offset} is past the end of {trim_key:?} ({size} bytes) in alignment store"
+            );
+            let end = data_end.min(size);
+            let mut position = aligned_start;
+            loop {
+                let chunk = rx
+                    .recv()
+                    .await
+                    .err_tip(|| "Failed to receive data in alignment store get_part")?;
+                if chunk.is_empty() {
+                    break; // EOF.
+                }
+                // Only forward the part of the chunk inside `offset..end`.
+                let chunk_start = offset.saturating_sub(position).min(chunk.len());
+                let chunk_end = end.saturating_sub(position).min(chunk.len());
+                position += chunk.len();
+                if chunk_start < chunk_end {
+                    writer
+                        .send(chunk.slice(chunk_start..chunk_end))
+                        .await
+                        .err_tip(|| "Failed to send data in alignment store get_part")?;
+                }
+            }
+            error_if!(
+                position < end,
+                "Inner store returned {} bytes, expected at least {} in alignment store get_part",
+                position - aligned_start,
+                end - aligned_start
+            );
+            writer
+                .send_eof()
+                .err_tip(|| "Failed to send EOF in alignment store get_part")
+        };
+        try_join!(
+            self.inner_store.get_part(
+                key.borrow(),
+                &mut tx,
+                aligned_start,
+                Some(aligned_end - aligned_start)
+            ),
+            trim_fut
+        )
+        .err_tip(|| "In AlignmentStore::get_part")?;
+        Ok(())
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().inner_store.list_digests(prefix)
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+
+    fn register_metrics(self: Arc<Self>, registry: &mut Registry) {
+        let inner_store_registry = registry.sub_registry_with_prefix("inner_store");
+        self.inner_store.register_metrics(inner_store_registry);
+        registry.register_collector(Box::new(Collector::new(&self)));
+    }
+}
+
+impl MetricsComponent for AlignmentStore {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "alignment",
+            &self.alignment,
+            "Blobs are padded to and read in multiples of this many bytes",
+        );
+    }
+}
+
+default_health_status_indicator!(AlignmentStore);
@@ -14,11 +14,13 @@
 
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{iter, mem};
 
 use async_trait::async_trait;
 use futures::stream::{FuturesUnordered, StreamExt};
 use futures::{select, FutureExt, TryFutureExt};
+use nativelink_config::stores::CompletenessCheckTimeoutBehavior;
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_proto::build::bazel::remote::execution::v2::{
     ActionResult as ProtoActionResult, OutputDirectory as ProtoOutputDirectory, Tree as ProtoTree,
@@ -29,9 +31,12 @@ use nativelink_util::health_utils::{default_health_status_indicator, HealthStatu
 use nativelink_util::metrics_utils::{
     Collector, CollectorState, CounterWithTime, MetricsComponent, Registry,
 };
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
 use parking_lot::Mutex;
-use tokio::sync::Notify;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::timeout;
 use tracing::{event, Level};
 
 use crate::ac_utils::{get_and_decode_digest, get_size_and_decode_digest};
@@ -108,21 +113,73 @@ async fn check_output_directories<'a>(
 pub struct CompletenessCheckingStore {
     cas_store: Store,
     ac_store: Store,
+    completeness_check_timeout: Option<Duration>,
+    completeness_check_timeout_behavior: CompletenessCheckTimeoutBehavior,
+    /// Bounds the number of concurrent action result fetches from `ac_store`.
+    ac_fetch_semaphore: Option<Semaphore>,
 
     incomplete_entries_counter: CounterWithTime,
     complete_entries_counter: CounterWithTime,
+    completeness_check_timeouts_counter: CounterWithTime,
 }
 
 impl CompletenessCheckingStore {
-    pub fn new(ac_store: Store, cas_store: Store) -> Arc<Self> {
+    pub fn new(
+        config: &nativelink_config::stores::CompletenessCheckingStore,
+        ac_store: Store,
+        cas_store: Store,
+    ) -> Arc<Self> {
+        let completeness_check_timeout = if config.completeness_check_timeout_millis == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(
+                config.completeness_check_timeout_millis,
+            ))
+        };
         Arc::new(CompletenessCheckingStore {
             cas_store,
             ac_store,
+            completeness_check_timeout,
+            completeness_check_timeout_behavior: config.completeness_check_timeout_behavior,
+            ac_fetch_semaphore: (config.max_concurrent_action_result_fetches != 0)
+                .then(|| Semaphore::new(config.max_concurrent_action_result_fetches)),
             incomplete_entries_counter: CounterWithTime::default(),
             complete_entries_counter: CounterWithTime::default(),
+            completeness_check_timeouts_counter: CounterWithTime::default(),
         })
     }
 
+    /// Same as `inner_has_with_results()` for a single key, but gives up after
+    /// `completeness_check_timeout` and resolves according to the configured
+    /// `CompletenessCheckTimeoutBehavior`. Returns true if the entry may be served.
+    async fn check_completeness_for_read(&self, key: StoreKey<'_>) -> Result<bool, Error> {
+        let keys = [key.borrow()];
+        let results = &mut [None];
+        let check_fut = self.inner_has_with_results(&keys, results);
+        let Some(completeness_check_timeout) = self.completeness_check_timeout else {
+            check_fut.await?;
+            return Ok(results[0].is_some());
+        };
+        match timeout(completeness_check_timeout, check_fut).await {
+            Ok(check_result) => {
+                check_result?;
+                Ok(results[0].is_some())
+            }
+            Err(_) => {
+                self.completeness_check_timeouts_counter.inc();
+                event!(
+                    Level::WARN,
+                    ?key,
+                    ?completeness_check_timeout,
+                    behavior = ?self.completeness_check_timeout_behavior,
+                    "Completeness check timed out in CompletenessCheckingStore",
+                );
+                Ok(self.completeness_check_timeout_behavior
+                    == CompletenessCheckTimeoutBehavior::fail_open)
+            }
+        }
+    }
+
     /// Check that all files and directories in action results
     /// exist in the CAS. Does this by decoding digests and
     /// checking their existence in two separate sets of futures that
@@ -160,12 +217,20 @@ impl CompletenessCheckingStore {
             .enumerate()
             .map(|(i, digest)| {
                 async move {
-                    // Note: We don't err_tip here because often have NotFound here which is ok.
-                    let (action_result, size) = get_size_and_decode_digest::<ProtoActionResult>(
-                        &self.ac_store,
-                        digest.borrow(),
-                    )
-                    .await?;
+                    let (action_result, size) = {
+                        let _permit = match &self.ac_fetch_semaphore {
+                            Some(semaphore) => Some(semaphore.acquire().await.map_err(|e| {
+                                make_err!(Code::Internal, "AC fetch semaphore closed: {e:?}")
+                            })?),
+                            None => None,
+                        };
+                        // Note: We don't err_tip here because often have NotFound here which is ok.
+                        get_size_and_decode_digest::<ProtoActionResult>(
+                            &self.ac_store,
+                            digest.borrow(),
+                        )
+                        .await?
+                    };
 
                     let (mut digest_infos, output_directories) =
                         get_digests_and_output_dirs(action_result)?;
@@ -361,11 +426,11 @@ impl StoreDriver for CompletenessCheckingStore {
         offset: usize,
         length: Option<usize>,
     ) -> Result<(), Error> {
-        let results = &mut [None];
-        self.inner_has_with_results(&[key.borrow()], results)
+        let is_complete = self
+            .check_completeness_for_read(key.borrow())
             .await
             .err_tip(|| "when calling CompletenessCheckingStore::get_part")?;
-        if results[0].is_none() {
+        if !is_complete {
             return Err(make_err!(
                 Code::NotFound,
                 "Digest found, but not all parts were found in CompletenessCheckingStore::get_part"
@@ -374,6 +439,10 @@ impl StoreDriver for CompletenessCheckingStore {
         self.ac_store.get_part(key, writer, offset, length).await
     }
 
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().ac_store.list_digests(prefix)
+    }
+
     fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
         self
     }
@@ -407,6 +476,11 @@ impl MetricsComponent for CompletenessCheckingStore {
             &self.complete_entries_counter,
             "Complete entries hit in CompletenessCheckingStore",
         );
+        c.publish(
+            "completeness_check_timeouts_counter",
+            &self.completeness_check_timeouts_counter,
+            "Reads where the completeness check timed out in CompletenessCheckingStore",
+        );
     }
 }
 
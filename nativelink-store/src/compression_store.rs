@@ -14,43 +14,82 @@
 
 use std::cmp;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use bincode::config::{FixintEncoding, WithOtherIntEncoding};
 use bincode::{DefaultOptions, Options};
 use byteorder::{ByteOrder, LittleEndian};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::future::FutureExt;
+use futures::stream::{self, StreamExt};
+use futures::try_join;
 use lz4_flex::block::{compress_into, decompress_into, get_maximum_output_size};
 use nativelink_error::{error_if, make_err, Code, Error, ResultExt};
 use nativelink_util::buf_channel::{
     make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
 };
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
-use nativelink_util::metrics_utils::Registry;
+use nativelink_util::metrics_utils::{Collector, CollectorState, MetricsComponent, Registry};
 use nativelink_util::spawn;
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    ConsistencyToken, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::cas_utils::is_zero_digest;
 
 // In the event the bytestream format changes this number should be incremented to prevent
 // backwards compatibility issues.
-pub const CURRENT_STREAM_FORMAT_VERSION: u8 = 1;
+pub const CURRENT_STREAM_FORMAT_VERSION: u8 = 2;
+
+// Version of streams written before the compression algorithm was recorded in the stream.
+// These streams are always compressed with LZ4 and can still be read.
+pub const LZ4_ONLY_STREAM_FORMAT_VERSION: u8 = 1;
 
 // Default block size that will be used to slice stream into.
 pub const DEFAULT_BLOCK_SIZE: u32 = 64 * 1024;
 
 const U32_SZ: usize = std::mem::size_of::<u8>();
 
+/// Size of the `frame_type` and size fields in front of every frame.
+const FRAME_INFO_SIZE: usize = 1 + 4;
+
+/// Size of the fields at the end of the footer that are always in the same
+/// place relative to the last byte: `index_count2`, `uncompressed_data_sz`,
+/// `block_size`, `algorithm` and `version`.
+const FOOTER_TAIL_SIZE: usize = 4 + 8 + 4 + 4 + 1;
+
+/// Same as `FOOTER_TAIL_SIZE` for streams of `LZ4_ONLY_STREAM_FORMAT_VERSION`,
+/// which have no `algorithm` field.
+const LZ4_ONLY_FOOTER_TAIL_SIZE: usize = 4 + 8 + 4 + 1;
+
+/// Header used to compute the serialized size of all headers.
+static EMPTY_HEADER: Header = Header {
+    version: CURRENT_STREAM_FORMAT_VERSION,
+    config: CompressionConfig {
+        block_size: 0,
+        algorithm: CompressionAlgorithm::Lz4,
+    },
+    upload_size: UploadSizeInfo::ExactSize(0),
+};
+
+/// Header used to compute the serialized size of all headers of
+/// `LZ4_ONLY_STREAM_FORMAT_VERSION`.
+static EMPTY_LZ4_ONLY_HEADER: Lz4OnlyHeader = Lz4OnlyHeader {
+    version: LZ4_ONLY_STREAM_FORMAT_VERSION,
+    config: Lz4Config { block_size: 0 },
+    upload_size: UploadSizeInfo::ExactSize(0),
+};
+
 type BincodeOptions = WithOtherIntEncoding<DefaultOptions, FixintEncoding>;
 
 // We use a custom frame format here because I wanted the ability in the future to:
 // * Read a random part of the data without needing to parse entire file.
 // * Compress the data on the fly without needing to know the exact input size.
 //
-// The frame format that LZ4 uses does not contain an index of where the different
+// The frame formats that LZ4 and zstd use do not contain an index of where the different
 // blocks are located. This would mean in the event we only wanted the last byte of
 // a file, we'd need to seek to the header of each block to find where the next block
 // offset is until we got to the last block then decompress it.
@@ -65,7 +104,8 @@ type BincodeOptions = WithOtherIntEncoding<DefaultOptions, FixintEncoding>;
 //
 // The frame format is as follows:
 // |----------------------------------HEADER-----------------------------------------|
-// |  version(u8) |  block_size (u32) |  upload_size_type (u32) |  upload_size (u32) |
+// |  version(u8) |  block_size (u32) |  algorithm (u32) |  upload_size_type (u32)   |
+// |  upload_size (u64) |------------------------------------------------------------|
 // |----------------------------------BLOCK------------------------------------------|
 // |  frame_type(u8) 0x00 |  compressed_data_size (u32) |        ...DATA...          |
 // |                                ...DATA...                                       |
@@ -74,7 +114,7 @@ type BincodeOptions = WithOtherIntEncoding<DefaultOptions, FixintEncoding>;
 // |  frame_type(u8) 0x01 |    footer_size (u32) |           index_count1 (u64)      |
 // |      ...[pos_from_prev_index (u32) - repeat for count {index_count*}]...        |
 // |  index_count2 (u32) |    uncompressed_data_sz (u64) |    block_size (u32)       |
-// | version (u8) |------------------------------------------------------------------|
+// |  algorithm (u32) |  version (u8) |-----------------------------------------------|
 // |---------------------------------------------------------------------------------|
 //
 // version              - A constant number used to define what version of this format is being
@@ -82,6 +122,9 @@ type BincodeOptions = WithOtherIntEncoding<DefaultOptions, FixintEncoding>;
 // block_size           - Size of each block uncompressed except for last block. This means that
 //                        every block uncompressed will be a constant size except last block may
 //                        be variable size. Block size in header and footer must match.
+// algorithm            - Algorithm every block is compressed with. Value of 0 = LZ4, 1 = zstd.
+//                        Algorithm in header and footer must match. Streams of version 1 do
+//                        not have this field in the header nor the footer and are always LZ4.
 // upload_size_type     - Value of 0 = UploadSizeInfo::ExactSize, 1 = UploadSizeInfo::MaxSize.
 //                        This is for debug reasons only.
 // upload_size          - The size of the data. WARNING: Do not rely on this being the uncompressed
@@ -106,6 +149,10 @@ type BincodeOptions = WithOtherIntEncoding<DefaultOptions, FixintEncoding>;
 // uncompressed_data_sz - Size of the original uncompressed data.
 //
 // Note: All fields fields little-endian.
+//
+// Blobs smaller than `min_compress_size` are not compressed. Instead they are stored as a
+// single `RAW_STREAM_MARKER` byte followed by the uncompressed data. The marker is never a
+// valid version, so it cannot be confused with the header of a compressed stream.
 
 /// Number representing a chunk.
 pub const CHUNK_FRAME_TYPE: u8 = 0;
@@ -113,21 +160,66 @@ pub const CHUNK_FRAME_TYPE: u8 = 0;
 /// Number representing the footer.
 pub const FOOTER_FRAME_TYPE: u8 = 1;
 
-/// This is a partial mirror of nativelink_config::stores::Lz4Config.
+/// First byte of a blob that was stored without compression.
+pub const RAW_STREAM_MARKER: u8 = 0xff;
+
+/// Algorithm the blocks of a stream are compressed with.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Lz4,
+    Zstd,
+}
+
+/// This is a partial mirror of nativelink_config::stores::CompressionAlgorithm.
 /// We cannot use that natively here because it could cause our
 /// serialized format to change if we added more configs.
 #[derive(Serialize, Deserialize, PartialEq, Debug, Default, Copy, Clone)]
+pub struct CompressionConfig {
+    pub block_size: u32,
+    pub algorithm: CompressionAlgorithm,
+}
+
+/// Config of streams of `LZ4_ONLY_STREAM_FORMAT_VERSION`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Copy, Clone)]
 pub struct Lz4Config {
     pub block_size: u32,
 }
 
+impl From<Lz4Config> for CompressionConfig {
+    fn from(config: Lz4Config) -> Self {
+        Self {
+            block_size: config.block_size,
+            algorithm: CompressionAlgorithm::Lz4,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Header {
+    pub version: u8,
+    pub config: CompressionConfig,
+    pub upload_size: UploadSizeInfo,
+}
+
+/// Header of streams of `LZ4_ONLY_STREAM_FORMAT_VERSION`.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Lz4OnlyHeader {
     pub version: u8,
     pub config: Lz4Config,
     pub upload_size: UploadSizeInfo,
 }
 
+impl From<Lz4OnlyHeader> for Header {
+    fn from(header: Lz4OnlyHeader) -> Self {
+        Self {
+            version: header.version,
+            config: header.config.into(),
+            upload_size: header.upload_size,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone, Copy)]
 pub struct SliceIndex {
     pub position_from_prev_index: u32,
@@ -135,6 +227,16 @@ pub struct SliceIndex {
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
 pub struct Footer {
+    pub indexes: Vec<SliceIndex>,
+    pub index_count: u32,
+    pub uncompressed_data_size: u64,
+    pub config: CompressionConfig,
+    pub version: u8,
+}
+
+/// Footer of streams of `LZ4_ONLY_STREAM_FORMAT_VERSION`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct Lz4OnlyFooter {
     pub indexes: Vec<SliceIndex>,
     pub index_count: u32,
     pub uncompressed_data_size: u64,
@@ -142,6 +244,55 @@ pub struct Footer {
     pub version: u8,
 }
 
+impl From<Lz4OnlyFooter> for Footer {
+    fn from(footer: Lz4OnlyFooter) -> Self {
+        Self {
+            indexes: footer.indexes,
+            index_count: footer.index_count,
+            uncompressed_data_size: footer.uncompressed_data_size,
+            config: footer.config.into(),
+            version: footer.version,
+        }
+    }
+}
+
+/// Location of the compressed data of a block in the stored stream.
+struct BlockLocation {
+    /// Offset of the compressed data, after the frame info.
+    start: usize,
+    /// Size of the compressed data.
+    len: usize,
+}
+
+/// Index of all blocks of a stored stream, built from its footer.
+struct BlockIndex {
+    config: CompressionConfig,
+    uncompressed_data_size: u64,
+    blocks: Vec<BlockLocation>,
+}
+
+/// Decompresses a single block of a stream that was compressed with `config`.
+fn decompress_block(compressed_data: &[u8], config: CompressionConfig) -> Result<Bytes, Error> {
+    if config.algorithm == CompressionAlgorithm::Zstd {
+        return zstd::bulk::decompress(compressed_data, config.block_size as usize)
+            .map(Bytes::from)
+            .map_err(|e| make_err!(Code::Internal, "Decompression error {:?}", e));
+    }
+    let max_output_size = get_maximum_output_size(config.block_size as usize);
+    let mut uncompressed_data = BytesMut::with_capacity(max_output_size);
+
+    // For efficiency reasons we do some raw slice manipulation so we can write directly
+    // into our buffer instead of having to do another allocation.
+    let raw_decompressed_data = unsafe {
+        std::slice::from_raw_parts_mut(uncompressed_data.chunk_mut().as_mut_ptr(), max_output_size)
+    };
+
+    let uncompressed_chunk_sz = decompress_into(compressed_data, raw_decompressed_data)
+        .map_err(|e| make_err!(Code::Internal, "Decompression error {:?}", e))?;
+    unsafe { uncompressed_data.advance_mut(uncompressed_chunk_sz) };
+    Ok(uncompressed_data.freeze())
+}
+
 /// lz4_flex::block::get_maximum_output_size() way over estimates, so we use the
 /// one provided here: https://github.com/torvalds/linux/blob/master/include/linux/lz4.h#L61
 /// Local testing shows this gives quite accurate worst case given random input.
@@ -149,6 +300,24 @@ fn lz4_compress_bound(input_size: usize) -> usize {
     input_size + (input_size / 255) + 16
 }
 
+/// Upper bound of the size of a block of `block_size` bytes once compressed
+/// with `algorithm`.
+fn max_compressed_block_size(algorithm: CompressionAlgorithm, block_size: u32) -> usize {
+    match algorithm {
+        CompressionAlgorithm::Lz4 => get_maximum_output_size(block_size as usize),
+        CompressionAlgorithm::Zstd => zstd::zstd_safe::compress_bound(block_size as usize),
+    }
+}
+
+/// Size of the fields at the end of the footer of streams of `version`.
+fn footer_tail_size(version: u8) -> usize {
+    if version == LZ4_ONLY_STREAM_FORMAT_VERSION {
+        LZ4_ONLY_FOOTER_TAIL_SIZE
+    } else {
+        FOOTER_TAIL_SIZE
+    }
+}
+
 struct UploadState {
     header: Header,
     footer: Footer,
@@ -157,18 +326,19 @@ struct UploadState {
 }
 
 impl UploadState {
-    pub fn new(store: &CompressionStore, upload_size: UploadSizeInfo) -> Self {
+    pub fn new(store: &CompressionStore, upload_size: UploadSizeInfo, block_size: u32) -> Self {
         let input_max_size = match upload_size {
             UploadSizeInfo::ExactSize(sz) => sz,
             UploadSizeInfo::MaxSize(sz) => sz,
         };
 
-        let max_index_count = (input_max_size / store.config.block_size as usize) + 1;
+        let max_index_count = (input_max_size / block_size as usize) + 1;
 
         let header = Header {
             version: CURRENT_STREAM_FORMAT_VERSION,
-            config: Lz4Config {
-                block_size: store.config.block_size,
+            config: CompressionConfig {
+                block_size,
+                algorithm: store.algorithm,
             },
             upload_size,
         };
@@ -185,8 +355,12 @@ impl UploadState {
             version: CURRENT_STREAM_FORMAT_VERSION,
         };
 
-        // This is more accurate of an estimate than what get_maximum_output_size calculates.
-        let max_block_size = lz4_compress_bound(store.config.block_size as usize) + U32_SZ + 1;
+        let max_compressed_size = match store.algorithm {
+            // This is more accurate of an estimate than what get_maximum_output_size calculates.
+            CompressionAlgorithm::Lz4 => lz4_compress_bound(block_size as usize),
+            CompressionAlgorithm::Zstd => max_compressed_block_size(store.algorithm, block_size),
+        };
+        let max_block_size = max_compressed_size + U32_SZ + 1;
 
         let max_output_size = {
             let header_size = store.bincode_options.serialized_size(&header).unwrap() as usize;
@@ -211,8 +385,24 @@ impl UploadState {
 /// only send the contents requested.
 pub struct CompressionStore {
     inner_store: Store,
-    config: nativelink_config::stores::Lz4Config,
+    /// Algorithm new uploads are compressed with. Data compressed with any
+    /// algorithm can be read.
+    algorithm: CompressionAlgorithm,
+    /// Compression level used when `algorithm` is zstd.
+    zstd_compression_level: i32,
+    /// Block size of uploads not matching any of `block_size_rules`.
+    block_size: u32,
+    /// Largest block size of stored data that will be decompressed.
+    max_decode_block_size: u32,
+    min_compress_size: usize,
     bincode_options: BincodeOptions,
+    partial_block_reads: AtomicU64,
+    uncompressed_bytes_total: AtomicU64,
+    compressed_bytes_total: AtomicU64,
+    read_concurrency: usize,
+    /// Pairs of minimum blob size and the block size to use for blobs of at
+    /// least that size, sorted by minimum blob size.
+    block_size_rules: Vec<(usize, u32)>,
 }
 
 impl CompressionStore {
@@ -220,23 +410,431 @@ impl CompressionStore {
         compression_config: nativelink_config::stores::CompressionStore,
         inner_store: Store,
     ) -> Result<Arc<Self>, Error> {
-        let lz4_config = match compression_config.compression_algorithm {
-            nativelink_config::stores::CompressionAlgorithm::lz4(mut lz4_config) => {
-                if lz4_config.block_size == 0 {
-                    lz4_config.block_size = DEFAULT_BLOCK_SIZE;
-                }
-                if lz4_config.max_decode_block_size == 0 {
-                    lz4_config.max_decode_block_size = lz4_config.block_size;
-                }
-                lz4_config
-            }
-        };
+        let (algorithm, zstd_compression_level, mut block_size, mut max_decode_block_size) =
+            match compression_config.compression_algorithm {
+                nativelink_config::stores::CompressionAlgorithm::lz4(lz4_config) => (
+                    CompressionAlgorithm::Lz4,
+                    0,
+                    lz4_config.block_size,
+                    lz4_config.max_decode_block_size,
+                ),
+                nativelink_config::stores::CompressionAlgorithm::zstd(zstd_config) => (
+                    CompressionAlgorithm::Zstd,
+                    zstd_config.compression_level,
+                    zstd_config.block_size,
+                    zstd_config.max_decode_block_size,
+                ),
+            };
+        error_if!(
+            !zstd::compression_level_range().contains(&zstd_compression_level),
+            "Zstd compression level {zstd_compression_level} must be in {:?} in CompressionStore",
+            zstd::compression_level_range()
+        );
+        if block_size == 0 {
+            block_size = DEFAULT_BLOCK_SIZE;
+        }
+        let mut block_size_rules: Vec<(usize, u32)> = compression_config
+            .block_size_by_blob_size
+            .iter()
+            .map(|rule| (rule.min_blob_size, rule.block_size))
+            .collect();
+        block_size_rules.sort_unstable();
+        let largest_block_size = block_size_rules
+            .iter()
+            .map(|(_, block_size)| *block_size)
+            .fold(block_size, cmp::max);
+        if max_decode_block_size == 0 {
+            max_decode_block_size = largest_block_size;
+        }
+        for (min_blob_size, rule_block_size) in &block_size_rules {
+            error_if!(
+                *rule_block_size == 0 || *rule_block_size > max_decode_block_size,
+                "Block size {rule_block_size} for blobs of at least {min_blob_size} bytes must be between 1 and max_decode_block_size ({max_decode_block_size}) in CompressionStore",
+            );
+        }
         Ok(Arc::new(CompressionStore {
             inner_store,
-            config: lz4_config,
+            algorithm,
+            zstd_compression_level,
+            block_size,
+            max_decode_block_size,
+            min_compress_size: compression_config.min_compress_size,
             bincode_options: DefaultOptions::new().with_fixint_encoding(),
+            partial_block_reads: AtomicU64::new(0),
+            uncompressed_bytes_total: AtomicU64::new(0),
+            compressed_bytes_total: AtomicU64::new(0),
+            read_concurrency: compression_config.read_concurrency,
+            block_size_rules,
+        }))
+    }
+
+    /// Picks the block size to compress an upload of `upload_size` with.
+    fn block_size_for_upload(&self, upload_size: UploadSizeInfo) -> u32 {
+        let size = match upload_size {
+            UploadSizeInfo::ExactSize(sz) | UploadSizeInfo::MaxSize(sz) => sz,
+        };
+        self.block_size_rules
+            .iter()
+            .rev()
+            .find(|(min_blob_size, _)| *min_blob_size <= size)
+            .map_or(self.block_size, |(_, block_size)| *block_size)
+    }
+
+    /// Total number of bytes received by `update()` before compression.
+    pub fn uncompressed_bytes_total(&self) -> u64 {
+        self.uncompressed_bytes_total.load(Ordering::Relaxed)
+    }
+
+    /// Total number of bytes sent to the inner store by `update()`, including
+    /// headers, frame markers and footers.
+    pub fn compressed_bytes_total(&self) -> u64 {
+        self.compressed_bytes_total.load(Ordering::Relaxed)
+    }
+
+    /// Average ratio of uncompressed to compressed bytes over all uploads.
+    /// Values above 1.0 mean the store is saving space. Returns 0.0 if
+    /// nothing has been uploaded yet.
+    pub fn compression_ratio(&self) -> f64 {
+        let compressed_bytes = self.compressed_bytes_total();
+        if compressed_bytes == 0 {
+            return 0.0;
+        }
+        self.uncompressed_bytes_total() as f64 / compressed_bytes as f64
+    }
+
+    fn record_upload(&self, uncompressed_bytes: usize, compressed_bytes: usize) {
+        self.uncompressed_bytes_total
+            .fetch_add(uncompressed_bytes as u64, Ordering::Relaxed);
+        self.compressed_bytes_total
+            .fetch_add(compressed_bytes as u64, Ordering::Relaxed);
+    }
+
+    fn check_header(&self, header: &Header) -> Result<(), Error> {
+        error_if!(
+            header.version != CURRENT_STREAM_FORMAT_VERSION
+                && header.version != LZ4_ONLY_STREAM_FORMAT_VERSION,
+            "Expected header version to match in get compression, got {}, want {} or {}",
+            header.version,
+            CURRENT_STREAM_FORMAT_VERSION,
+            LZ4_ONLY_STREAM_FORMAT_VERSION
+        );
+        error_if!(
+            header.config.block_size > self.max_decode_block_size,
+            "Block size is too large in compression, got {} > {}",
+            header.config.block_size,
+            self.max_decode_block_size
+        );
+        Ok(())
+    }
+
+    /// Size of the header of streams of `version`.
+    fn header_size(&self, version: u8) -> usize {
+        let size = if version == LZ4_ONLY_STREAM_FORMAT_VERSION {
+            self.bincode_options.serialized_size(&EMPTY_LZ4_ONLY_HEADER)
+        } else {
+            self.bincode_options.serialized_size(&EMPTY_HEADER)
+        };
+        size.unwrap() as usize
+    }
+
+    /// Deserializes and checks the header of a stream of any supported
+    /// version. The version is the first byte of `data`.
+    fn deserialize_header(&self, data: &[u8]) -> Result<Header, Error> {
+        let header = if data.first() == Some(&LZ4_ONLY_STREAM_FORMAT_VERSION) {
+            self.bincode_options
+                .deserialize::<Lz4OnlyHeader>(data)
+                .map(Header::from)
+        } else {
+            self.bincode_options.deserialize::<Header>(data)
+        }
+        .map_err(|e| make_err!(Code::Internal, "Failed to deserialize header : {:?}", e))?;
+        self.check_header(&header)?;
+        Ok(header)
+    }
+
+    /// Deserializes the footer of a stream of `version`.
+    fn deserialize_footer(&self, version: u8, data: &[u8]) -> Result<Footer, Error> {
+        if version == LZ4_ONLY_STREAM_FORMAT_VERSION {
+            self.bincode_options
+                .deserialize::<Lz4OnlyFooter>(data)
+                .map(Footer::from)
+        } else {
+            self.bincode_options.deserialize::<Footer>(data)
+        }
+        .map_err(|e| make_err!(Code::Internal, "Failed to deserialize footer : {:?}", e))
+    }
+
+    /// Compresses `chunk` into a new block frame. Returns the frame and the
+    /// size of the compressed data in it.
+    fn compress_block(&self, chunk: &[u8], block_size: u32) -> Result<(BytesMut, usize), Error> {
+        let max_output_size = max_compressed_block_size(self.algorithm, block_size);
+        let mut compressed_data_buf = BytesMut::with_capacity(FRAME_INFO_SIZE + max_output_size);
+        compressed_data_buf.put_u8(CHUNK_FRAME_TYPE);
+        compressed_data_buf.put_u32_le(0); // Filled later.
+
+        // For efficiency reasons we do some raw slice manipulation so we can write directly
+        // into our buffer instead of having to do another allocation.
+        let raw_compressed_data = unsafe {
+            std::slice::from_raw_parts_mut(
+                compressed_data_buf.chunk_mut().as_mut_ptr(),
+                max_output_size,
+            )
+        };
+
+        let compressed_data_sz = match self.algorithm {
+            CompressionAlgorithm::Lz4 => compress_into(chunk, raw_compressed_data)
+                .map_err(|e| make_err!(Code::Internal, "Compression error {:?}", e))?,
+            CompressionAlgorithm::Zstd => zstd::bulk::compress_to_buffer(
+                chunk,
+                raw_compressed_data,
+                self.zstd_compression_level,
+            )
+            .map_err(|e| make_err!(Code::Internal, "Compression error {:?}", e))?,
+        };
+        unsafe {
+            compressed_data_buf.advance_mut(compressed_data_sz);
+        }
+
+        // Now fill the size in our slice.
+        LittleEndian::write_u32(&mut compressed_data_buf[1..5], compressed_data_sz as u32);
+        Ok((compressed_data_buf, compressed_data_sz))
+    }
+
+    /// Reads the header and footer of `key` from the inner store and builds
+    /// the index of its blocks. Returns `None` if the data was stored raw.
+    async fn read_block_index(&self, key: StoreKey<'_>) -> Result<Option<BlockIndex>, Error> {
+        let stored_size = self
+            .inner_store
+            .has(key.borrow())
+            .await
+            .err_tip(|| "Failed to get stored size in compression store")?
+            .ok_or_else(|| make_err!(Code::NotFound, "{key:?} not found in compression store"))?;
+        let chunk = self
+            .inner_store
+            .get_part_unchunked(
+                key.borrow(),
+                0,
+                Some(self.header_size(CURRENT_STREAM_FORMAT_VERSION)),
+            )
+            .await
+            .err_tip(|| "Failed to read header in compression store")?;
+        let Some(&version) = chunk.first() else {
+            return Err(make_err!(
+                Code::Internal,
+                "Stored data of {key:?} is empty in compression store"
+            ));
+        };
+        if version == RAW_STREAM_MARKER {
+            return Ok(None);
+        }
+        let header_size = self.header_size(version);
+        error_if!(
+            chunk.len() < header_size,
+            "Expected inner store to return the proper amount of data in compression store {} != {}",
+            chunk.len(),
+            header_size,
+        );
+        let header = self.deserialize_header(&chunk[..header_size])?;
+        let footer_tail_size = footer_tail_size(header.version);
+
+        error_if!(
+            stored_size < header_size + FRAME_INFO_SIZE + footer_tail_size,
+            "Stored data of {key:?} is too small to hold a footer in compression store"
+        );
+        let tail = self
+            .inner_store
+            .get_part_unchunked(
+                key.borrow(),
+                stored_size - footer_tail_size,
+                Some(footer_tail_size),
+            )
+            .await
+            .err_tip(|| "Failed to read footer tail in compression store")?;
+        error_if!(
+            tail.len() != footer_tail_size,
+            "Unexpected EOF when reading footer tail in compression store"
+        );
+        // The footer is `index_count1` (u64), the indexes (u32 each) and the tail.
+        let index_count = LittleEndian::read_u32(&tail[..4]) as usize;
+        let footer_size = 8 + index_count * 4 + footer_tail_size;
+        error_if!(
+            header_size + FRAME_INFO_SIZE + footer_size > stored_size,
+            "Footer of {key:?} is larger than the stored data in compression store"
+        );
+        let footer_frame_start = stored_size - FRAME_INFO_SIZE - footer_size;
+        let mut chunk = self
+            .inner_store
+            .get_part_unchunked(
+                key.borrow(),
+                footer_frame_start,
+                Some(FRAME_INFO_SIZE + footer_size),
+            )
+            .await
+            .err_tip(|| "Failed to read footer in compression store")?;
+        error_if!(
+            chunk.len() != FRAME_INFO_SIZE + footer_size,
+            "Unexpected EOF when reading footer in compression store"
+        );
+        let frame_type = chunk.get_u8();
+        let frame_sz = chunk.get_u32_le();
+        error_if!(
+            frame_type != FOOTER_FRAME_TYPE || frame_sz as usize != footer_size,
+            "Expected footer frame of {footer_size} bytes in compression store, got type {frame_type} of {frame_sz} bytes"
+        );
+        let footer = self.deserialize_footer(header.version, &chunk)?;
+        error_if!(
+            header.version != footer.version || header.config != footer.config,
+            "Expected header and footer to match in compression store, {:?} != {:?}",
+            (header.version, header.config),
+            (footer.version, footer.config)
+        );
+        error_if!(
+            footer.indexes.len() != index_count,
+            "Expected index counts to match in compression store footer, {} != {}",
+            footer.indexes.len(),
+            index_count
+        );
+
+        // Every index holds the compressed size of its block. There is no
+        // index for the last block, it ends where the footer starts.
+        let mut blocks = Vec::with_capacity(index_count + 1);
+        let mut position = header_size;
+        for index in &footer.indexes {
+            let len = index.position_from_prev_index as usize;
+            blocks.push(BlockLocation {
+                start: position + FRAME_INFO_SIZE,
+                len,
+            });
+            position += FRAME_INFO_SIZE + len;
+        }
+        if footer.uncompressed_data_size != 0 {
+            error_if!(
+                position + FRAME_INFO_SIZE > footer_frame_start,
+                "Block index of {key:?} does not match the stored data in compression store"
+            );
+            blocks.push(BlockLocation {
+                start: position + FRAME_INFO_SIZE,
+                len: footer_frame_start - position - FRAME_INFO_SIZE,
+            });
+        }
+        let block_size = u64::from(header.config.block_size);
+        error_if!(
+            block_size == 0
+                || footer.uncompressed_data_size.div_ceil(block_size) != blocks.len() as u64,
+            "Expected {} bytes to be stored in {} blocks of {block_size} bytes in compression store",
+            footer.uncompressed_data_size,
+            blocks.len()
+        );
+        Ok(Some(BlockIndex {
+            config: header.config,
+            uncompressed_data_size: footer.uncompressed_data_size,
+            blocks,
         }))
     }
+
+    /// Fetches and decompresses the blocks of `key` overlapping the
+    /// requested range, `read_concurrency` at a time, and sends them in
+    /// order.
+    async fn get_part_concurrent(
+        &self,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: u64,
+        length: Option<usize>,
+        index: &BlockIndex,
+    ) -> Result<(), Error> {
+        let block_size = u64::from(index.config.block_size);
+        let total_size = index.uncompressed_data_size;
+        let end = length.map_or(total_size, |length| {
+            offset.saturating_add(length as u64).min(total_size)
+        });
+        if offset < end {
+            let first_block = offset / block_size;
+            let last_block = (end - 1) / block_size;
+            let mut blocks = stream::iter(first_block..=last_block)
+                .map(|block| {
+                    let key = key.borrow();
+                    async move {
+                        let location = &index.blocks[block as usize];
+                        let compressed_data = self
+                            .inner_store
+                            .get_part_unchunked(key, location.start, Some(location.len))
+                            .await
+                            .err_tip(|| "Failed to read block in compression store")?;
+                        error_if!(
+                            compressed_data.len() != location.len,
+                            "Got EOF earlier than expected when reading block {block} in compression store"
+                        );
+                        let data = decompress_block(&compressed_data, index.config)?;
+                        let expected_size = cmp::min(block_size, total_size - block * block_size);
+                        error_if!(
+                            data.len() as u64 != expected_size,
+                            "Expected block {block} to decompress to {expected_size} bytes in compression store, got {}",
+                            data.len()
+                        );
+                        Ok::<_, Error>(data)
+                    }
+                })
+                .buffered(self.read_concurrency);
+            let mut position = first_block * block_size;
+            while let Some(data) = blocks.next().await {
+                let data = data?;
+                let start_pos = offset.saturating_sub(position) as usize;
+                let end_pos = cmp::min(end - position, data.len() as u64) as usize;
+                if start_pos != 0 || end_pos != data.len() {
+                    self.partial_block_reads.fetch_add(1, Ordering::Relaxed);
+                }
+                position += data.len() as u64;
+                writer
+                    .send(data.slice(start_pos..end_pos))
+                    .await
+                    .err_tip(|| "Failed sending chunk in compression store")?;
+            }
+        }
+        writer
+            .send_eof()
+            .err_tip(|| "Failed to send eof in compression store write")
+    }
+
+    /// Stores the data behind a `RAW_STREAM_MARKER` without compressing it.
+    async fn update_raw(
+        &self,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        size: usize,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        let data = reader
+            .consume(None)
+            .await
+            .err_tip(|| "Failed to read data in compression store raw update")?;
+        error_if!(
+            data.len() > size,
+            "Got more data than stated in compression store upload request"
+        );
+        let mut raw_data = BytesMut::with_capacity(1 + data.len());
+        raw_data.put_u8(RAW_STREAM_MARKER);
+        raw_data.extend_from_slice(&data);
+        let raw_data_len = raw_data.len();
+        let (mut tx, rx) = make_buf_channel_pair();
+        let send_fut = async move {
+            tx.send(raw_data.freeze())
+                .await
+                .err_tip(|| "Failed to write data in compression store raw update")?;
+            tx.send_eof()
+                .err_tip(|| "Failed to write EOF in compression store raw update")
+        };
+        let ((), token) = try_join!(
+            send_fut,
+            self.inner_store.update_with_consistency_token(
+                key,
+                rx,
+                UploadSizeInfo::ExactSize(raw_data_len)
+            ),
+        )
+        .err_tip(|| "Inner store update in compression store failed")?;
+        self.record_upload(data.len(), raw_data_len);
+        Ok(token)
+    }
 }
 
 #[async_trait]
@@ -252,10 +850,27 @@ impl StoreDriver for CompressionStore {
     async fn update(
         self: Pin<&Self>,
         key: StoreKey<'_>,
-        mut reader: DropCloserReadHalf,
+        reader: DropCloserReadHalf,
         upload_size: UploadSizeInfo,
     ) -> Result<(), Error> {
-        let mut output_state = UploadState::new(&self, upload_size);
+        self.update_with_consistency_token(key, reader, upload_size)
+            .await
+            .map(|_| ())
+    }
+
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        upload_size: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        if let UploadSizeInfo::ExactSize(size) = upload_size {
+            if size < self.min_compress_size {
+                return self.update_raw(key, reader, size).await;
+            }
+        }
+        let block_size = self.block_size_for_upload(upload_size);
+        let mut output_state = UploadState::new(&self, upload_size, block_size);
 
         let (mut tx, rx) = make_buf_channel_pair();
 
@@ -263,7 +878,7 @@ impl StoreDriver for CompressionStore {
         let key = key.into_owned();
         let update_fut = spawn!("compression_store_update_spawn", async move {
             inner_store
-                .update(
+                .update_with_consistency_token(
                     key,
                     rx,
                     UploadSizeInfo::MaxSize(output_state.max_output_size),
@@ -281,6 +896,7 @@ impl StoreDriver for CompressionStore {
         );
 
         let write_fut = async move {
+            let mut sent_amt = 0;
             {
                 // Write Header.
                 let serialized_header = self
@@ -289,6 +905,7 @@ impl StoreDriver for CompressionStore {
                     .map_err(|e| {
                         make_err!(Code::Internal, "Failed to serialize header : {:?}", e)
                     })?;
+                sent_amt += serialized_header.len();
                 tx.send(serialized_header.into())
                     .await
                     .err_tip(|| "Failed to write compression header on upload")?;
@@ -298,7 +915,7 @@ impl StoreDriver for CompressionStore {
             let mut index_count: u32 = 0;
             for index in &mut output_state.footer.indexes {
                 let chunk = reader
-                    .consume(Some(self.config.block_size as usize))
+                    .consume(Some(block_size as usize))
                     .await
                     .err_tip(|| "Failed to read take in update in compression store")?;
                 if chunk.is_empty() {
@@ -311,30 +928,11 @@ impl StoreDriver for CompressionStore {
                     "Got more data than stated in compression store upload request"
                 );
 
-                let max_output_size = get_maximum_output_size(self.config.block_size as usize);
-                let mut compressed_data_buf = BytesMut::with_capacity(max_output_size);
-                compressed_data_buf.put_u8(CHUNK_FRAME_TYPE);
-                compressed_data_buf.put_u32_le(0); // Filled later.
-
-                // For efficiency reasons we do some raw slice manipulation so we can write directly
-                // into our buffer instead of having to do another allocation.
-                let raw_compressed_data = unsafe {
-                    std::slice::from_raw_parts_mut(
-                        compressed_data_buf.chunk_mut().as_mut_ptr(),
-                        max_output_size,
-                    )
-                };
-
-                let compressed_data_sz = compress_into(&chunk, raw_compressed_data)
-                    .map_err(|e| make_err!(Code::Internal, "Compression error {:?}", e))?;
-                unsafe {
-                    compressed_data_buf.advance_mut(compressed_data_sz);
-                }
-
-                // Now fill the size in our slice.
-                LittleEndian::write_u32(&mut compressed_data_buf[1..5], compressed_data_sz as u32);
+                let (compressed_data_buf, compressed_data_sz) =
+                    self.compress_block(&chunk, block_size)?;
 
                 // Now send our chunk.
+                sent_amt += compressed_data_buf.len();
                 tx.send(compressed_data_buf.freeze())
                     .await
                     .err_tip(|| "Failed to write chunk to inner store in compression store")?;
@@ -373,6 +971,7 @@ impl StoreDriver for CompressionStore {
                 footer.put_u32_le(serialized_footer.len() as u32);
                 footer.extend_from_slice(&serialized_footer);
 
+                sent_amt += footer.len();
                 tx.send(footer.freeze())
                     .await
                     .err_tip(|| "Failed to write footer to inner store in compression store")?;
@@ -380,12 +979,59 @@ impl StoreDriver for CompressionStore {
                     .err_tip(|| "Failed writing EOF in compression store update")?;
             }
 
-            Result::<(), Error>::Ok(())
+            Result::<_, Error>::Ok((received_amt, sent_amt))
         };
         let (write_result, update_result) = tokio::join!(write_fut, update_fut);
+        if let (Ok((received_amt, sent_amt)), Ok(_)) = (&write_result, &update_result) {
+            self.record_upload(*received_amt, *sent_amt);
+        }
         write_result.merge(update_result)
     }
 
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        self.inner_store
+            .as_store_driver_pin()
+            .wait_for_consistency(key, token)
+            .await
+    }
+
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        // The inner store only knows the size of the stored data, which is
+        // not the size of the data for string keys.
+        let size = match key {
+            StoreKey::Digest(digest) => digest.size_bytes as usize,
+            StoreKey::Str(_) => match self
+                .read_block_index(key.borrow())
+                .await
+                .err_tip(|| "In CompressionStore::get_tail")?
+            {
+                Some(index) => index.uncompressed_data_size as usize,
+                // Raw data is stored after a single marker byte.
+                None => self
+                    .inner_store
+                    .has(key.borrow())
+                    .await
+                    .err_tip(|| "In CompressionStore::get_tail")?
+                    .ok_or_else(|| {
+                        make_err!(Code::NotFound, "{key:?} not found in compression store")
+                    })?
+                    .saturating_sub(1),
+            },
+        };
+        let offset = size.saturating_sub(length);
+        self.get_part(key, writer, offset, Some(size - offset))
+            .await
+    }
+
     async fn get_part(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -401,6 +1047,18 @@ impl StoreDriver for CompressionStore {
         }
 
         let offset = offset as u64;
+        if self.read_concurrency > 1 {
+            if let Some(index) = self
+                .read_block_index(key.borrow())
+                .await
+                .err_tip(|| "In CompressionStore::get_part")?
+            {
+                return self
+                    .get_part_concurrent(key, writer, offset, length, &index)
+                    .await
+                    .err_tip(|| "In CompressionStore::get_part");
+            }
+        }
         let (tx, mut rx) = make_buf_channel_pair();
 
         let inner_store = self.inner_store.clone();
@@ -420,53 +1078,50 @@ impl StoreDriver for CompressionStore {
             },
         );
         let read_fut = async move {
+            let version = match rx.peek().await {
+                Ok(chunk) => chunk.first().copied(),
+                Err(err) => return Err(err.clone()),
+            };
+            if version == Some(RAW_STREAM_MARKER) {
+                let data = rx
+                    .consume(None)
+                    .await
+                    .err_tip(|| "Failed to read raw data in get_part compression store")?;
+                // Skip the marker.
+                let data = data.slice(1..);
+                let start_pos = cmp::min(offset as usize, data.len());
+                let end_pos = cmp::min(
+                    start_pos.saturating_add(length.unwrap_or(usize::MAX)),
+                    data.len(),
+                );
+                if end_pos != start_pos {
+                    // Make sure we don't send an EOF by accident.
+                    writer
+                        .send(data.slice(start_pos..end_pos))
+                        .await
+                        .err_tip(|| "Failed sending raw data in compression store")?;
+                }
+                writer
+                    .send_eof()
+                    .err_tip(|| "Failed to send eof in compression store raw read")?;
+                return Ok(());
+            }
             let header = {
                 // Read header.
-                static EMPTY_HEADER: Header = Header {
-                    version: CURRENT_STREAM_FORMAT_VERSION,
-                    config: Lz4Config { block_size: 0 },
-                    upload_size: UploadSizeInfo::ExactSize(0),
-                };
-                let header_size = self.bincode_options.serialized_size(&EMPTY_HEADER).unwrap();
+                let header_size =
+                    self.header_size(version.unwrap_or(CURRENT_STREAM_FORMAT_VERSION));
                 let chunk = rx
-                    .consume(Some(header_size as usize))
+                    .take_exact(header_size)
                     .await
                     .err_tip(|| "Failed to read header in get_part compression store")?;
-                error_if!(
-                    chunk.len() as u64 != header_size,
-                    "Expected inner store to return the proper amount of data in compression store {} != {}",
-                    chunk.len(),
-                    header_size,
-                );
 
-                self.bincode_options
-                    .deserialize::<Header>(&chunk)
-                    .map_err(|e| {
-                        make_err!(Code::Internal, "Failed to deserialize header : {:?}", e)
-                    })?
+                self.deserialize_header(&chunk)?
             };
 
-            error_if!(
-                header.version != CURRENT_STREAM_FORMAT_VERSION,
-                "Expected header version to match in get compression, got {}, want {}",
-                header.version,
-                CURRENT_STREAM_FORMAT_VERSION
-            );
-            error_if!(
-                header.config.block_size > self.config.max_decode_block_size,
-                "Block size is too large in compression, got {} > {}",
-                header.config.block_size,
-                self.config.max_decode_block_size
-            );
-
             let mut chunk = rx
-                .consume(Some(1 + 4))
+                .take_exact(1 + 4)
                 .await
                 .err_tip(|| "Failed to read init frame info in compression store")?;
-            error_if!(
-                chunk.len() < 1 + 4,
-                "Received EOF too early while reading init frame info in compression store"
-            );
 
             let mut frame_type = chunk.get_u8();
             let mut frame_sz = chunk.get_u32_le();
@@ -482,33 +1137,12 @@ impl StoreDriver for CompressionStore {
                     chunks_count
                 );
 
-                let chunk = rx
-                    .consume(Some(frame_sz as usize))
-                    .await
-                    .err_tip(|| "Failed to read chunk in get_part compression store")?;
-                if chunk.len() < frame_sz as usize {
-                    return Err(make_err!(
-                        Code::Internal,
-                        "Got EOF earlier than expected. Maybe the data is not compressed or different format?"
-                    ));
-                }
+                let chunk = rx.take_exact(frame_sz as usize).await.err_tip(|| {
+                    "Failed to read chunk in get_part compression store. Maybe the data is not compressed or different format?"
+                })?;
                 {
-                    let max_output_size =
-                        get_maximum_output_size(header.config.block_size as usize);
-                    let mut uncompressed_data = BytesMut::with_capacity(max_output_size);
-
-                    // For efficiency reasons we do some raw slice manipulation so we can write directly
-                    // into our buffer instead of having to do another allocation.
-                    let raw_decompressed_data = unsafe {
-                        std::slice::from_raw_parts_mut(
-                            uncompressed_data.chunk_mut().as_mut_ptr(),
-                            max_output_size,
-                        )
-                    };
-
-                    let uncompressed_chunk_sz = decompress_into(&chunk, raw_decompressed_data)
-                        .map_err(|e| make_err!(Code::Internal, "Decompression error {:?}", e))?;
-                    unsafe { uncompressed_data.advance_mut(uncompressed_chunk_sz) };
+                    let uncompressed_data = decompress_block(&chunk, header.config)?;
+                    let uncompressed_chunk_sz = uncompressed_data.len();
                     let new_uncompressed_data_sz =
                         uncompressed_data_sz + uncompressed_chunk_sz as u64;
                     if new_uncompressed_data_sz >= offset && remaining_bytes_to_send > 0 {
@@ -518,13 +1152,16 @@ impl StoreDriver for CompressionStore {
                             offset - uncompressed_data_sz
                         } as usize;
                         let end_pos = cmp::min(
-                            start_pos + remaining_bytes_to_send as usize,
+                            start_pos.saturating_add(remaining_bytes_to_send as usize),
                             uncompressed_chunk_sz,
                         );
+                        if start_pos != 0 || end_pos != uncompressed_chunk_sz {
+                            self.partial_block_reads.fetch_add(1, Ordering::Relaxed);
+                        }
                         if end_pos != start_pos {
                             // Make sure we don't send an EOF by accident.
                             writer
-                                .send(uncompressed_data.freeze().slice(start_pos..end_pos))
+                                .send(uncompressed_data.slice(start_pos..end_pos))
                                 .await
                                 .err_tip(|| "Failed sending chunk in compression store")?;
                         }
@@ -535,13 +1172,9 @@ impl StoreDriver for CompressionStore {
                 chunks_count += 1;
 
                 let mut chunk = rx
-                    .consume(Some(1 + 4))
+                    .take_exact(1 + 4)
                     .await
                     .err_tip(|| "Failed to read frame info in compression store")?;
-                error_if!(
-                    chunk.len() < 1 + 4,
-                    "Received EOF too early while reading frame info in compression store"
-                );
 
                 frame_type = chunk.get_u8();
                 frame_sz = chunk.get_u32_le();
@@ -551,20 +1184,11 @@ impl StoreDriver for CompressionStore {
             {
                 // Read and validate footer.
                 let chunk = rx
-                    .consume(Some(frame_sz as usize))
+                    .take_exact(frame_sz as usize)
                     .await
-                    .err_tip(|| "Failed to read chunk in get_part compression store")?;
-                error_if!(
-                    chunk.len() < frame_sz as usize,
-                    "Unexpected EOF when reading footer in compression store get_part"
-                );
+                    .err_tip(|| "Failed to read footer in get_part compression store")?;
 
-                let footer = self
-                    .bincode_options
-                    .deserialize::<Footer>(&chunk)
-                    .map_err(|e| {
-                        make_err!(Code::Internal, "Failed to deserialize footer : {:?}", e)
-                    })?;
+                let footer = self.deserialize_footer(header.version, &chunk)?;
 
                 error_if!(
                     header.version != footer.version,
@@ -627,6 +1251,32 @@ impl StoreDriver for CompressionStore {
     fn register_metrics(self: Arc<Self>, registry: &mut Registry) {
         let inner_store_registry = registry.sub_registry_with_prefix("inner_store");
         self.inner_store.register_metrics(inner_store_registry);
+        registry.register_collector(Box::new(Collector::new(&self)));
+    }
+}
+
+impl MetricsComponent for CompressionStore {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "partial_block_reads",
+            &self.partial_block_reads,
+            "Number of blocks that were decompressed but only partially read",
+        );
+        c.publish(
+            "uncompressed_bytes_total",
+            &self.uncompressed_bytes_total,
+            "Total number of bytes uploaded to the store before compression",
+        );
+        c.publish(
+            "compressed_bytes_total",
+            &self.compressed_bytes_total,
+            "Total number of bytes written to the inner store after compression",
+        );
+        c.publish(
+            "compression_ratio",
+            &self.compression_ratio(),
+            "Average ratio of uncompressed to compressed bytes over all uploads",
+        );
     }
 }
 
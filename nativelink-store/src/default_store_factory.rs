@@ -12,30 +12,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use futures::stream::FuturesOrdered;
 use futures::{Future, TryStreamExt};
 use nativelink_config::stores::StoreConfig;
-use nativelink_error::Error;
-use nativelink_util::health_utils::HealthRegistryBuilder;
+use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_util::health_utils::{HealthRegistryBuilder, HealthStatus};
 use nativelink_util::metrics_utils::Registry;
-use nativelink_util::store_trait::{Store, StoreDriver};
+use nativelink_util::store_trait::{health_check_data, Store, StoreDriver, StoreLike};
 
+use crate::alignment_store::AlignmentStore;
 use crate::completeness_checking_store::CompletenessCheckingStore;
 use crate::compression_store::CompressionStore;
 use crate::dedup_store::DedupStore;
 use crate::existence_cache_store::ExistenceCacheStore;
 use crate::fast_slow_store::FastSlowStore;
 use crate::filesystem_store::FilesystemStore;
+use crate::gcs_store::GcsStore;
 use crate::grpc_store::GrpcStore;
+use crate::key_limit_store::KeyLimitStore;
 use crate::memory_store::MemoryStore;
 use crate::noop_store::NoopStore;
+use crate::prefetch_store::PrefetchStore;
 use crate::redis_store::RedisStore;
 use crate::ref_store::RefStore;
 use crate::s3_store::S3Store;
 use crate::shard_store::ShardStore;
+use crate::single_flight_store::SingleFlightStore;
 use crate::size_partitioning_store::SizePartitioningStore;
 use crate::store_manager::StoreManager;
 use crate::verify_store::VerifyStore;
@@ -49,14 +55,26 @@ pub fn store_factory<'a>(
     maybe_health_registry_builder: Option<&'a mut HealthRegistryBuilder>,
 ) -> Pin<FutureMaybeStore<'a>> {
     Box::pin(async move {
+        check_store_config(backend)?;
         let store: Arc<dyn StoreDriver> = match backend {
             StoreConfig::memory(config) => MemoryStore::new(config),
             StoreConfig::experimental_s3_store(config) => S3Store::new(config).await?,
+            StoreConfig::gcs_store(config) => GcsStore::new(config).await?,
             StoreConfig::redis_store(config) => RedisStore::new(config)?,
-            StoreConfig::verify(config) => VerifyStore::new(
-                config,
-                store_factory(&config.backend, store_manager, None, None).await?,
-            ),
+            StoreConfig::verify(config) => {
+                let store = VerifyStore::new(
+                    config,
+                    store_factory(&config.backend, store_manager, None, None).await?,
+                );
+                if let Some(name) = &config.content_policy {
+                    let content_policy =
+                        store_manager.get_content_policy(name).ok_or_else(|| {
+                            make_input_err!("'content_policy': '{name}' is not registered")
+                        })?;
+                    store.set_content_policy(content_policy);
+                }
+                store
+            }
             StoreConfig::compression(config) => CompressionStore::new(
                 *config.clone(),
                 store_factory(&config.backend, store_manager, None, None).await?,
@@ -70,7 +88,24 @@ pub fn store_factory<'a>(
                 config,
                 store_factory(&config.backend, store_manager, None, None).await?,
             ),
+            StoreConfig::single_flight(config) => SingleFlightStore::new(
+                config,
+                store_factory(&config.backend, store_manager, None, None).await?,
+            ),
+            StoreConfig::key_limit(config) => KeyLimitStore::new(
+                config,
+                store_factory(&config.backend, store_manager, None, None).await?,
+            ),
+            StoreConfig::prefetch(config) => PrefetchStore::new(
+                config,
+                store_factory(&config.backend, store_manager, None, None).await?,
+            ),
+            StoreConfig::alignment(config) => AlignmentStore::new(
+                config,
+                store_factory(&config.backend, store_manager, None, None).await?,
+            )?,
             StoreConfig::completeness_checking(config) => CompletenessCheckingStore::new(
+                config,
                 store_factory(&config.backend, store_manager, None, None).await?,
                 store_factory(&config.cas_store, store_manager, None, None).await?,
             ),
@@ -112,3 +147,165 @@ pub fn store_factory<'a>(
         Ok(Store::new(store))
     })
 }
+
+/// Returns the stores directly nested under `config`.
+fn child_store_configs(config: &StoreConfig) -> Vec<&StoreConfig> {
+    match config {
+        StoreConfig::verify(config) => vec![&config.backend],
+        StoreConfig::compression(config) => vec![&config.backend],
+        StoreConfig::dedup(config) => vec![&config.index_store, &config.content_store],
+        StoreConfig::existence_cache(config) => vec![&config.backend],
+        StoreConfig::single_flight(config) => vec![&config.backend],
+        StoreConfig::key_limit(config) => vec![&config.backend],
+        StoreConfig::prefetch(config) => vec![&config.backend],
+        StoreConfig::alignment(config) => vec![&config.backend],
+        StoreConfig::completeness_checking(config) => vec![&config.backend, &config.cas_store],
+        StoreConfig::fast_slow(config) => vec![&config.fast, &config.slow],
+        StoreConfig::size_partitioning(config) => {
+            vec![&config.lower_store, &config.upper_store]
+        }
+        StoreConfig::shard(config) => config.stores.iter().map(|shard| &shard.store).collect(),
+        StoreConfig::memory(_)
+        | StoreConfig::experimental_s3_store(_)
+        | StoreConfig::gcs_store(_)
+        | StoreConfig::redis_store(_)
+        | StoreConfig::filesystem(_)
+        | StoreConfig::ref_store(_)
+        | StoreConfig::grpc(_)
+        | StoreConfig::noop => vec![],
+    }
+}
+
+/// Returns true if both configs will end up pointing at the same
+/// underlying storage. Memory and noop stores are never considered the
+/// same, since every config creates an independent instance.
+fn is_same_store(a: &StoreConfig, b: &StoreConfig) -> bool {
+    match (a, b) {
+        (StoreConfig::ref_store(a), StoreConfig::ref_store(b)) => a.name == b.name,
+        (StoreConfig::memory(_) | StoreConfig::noop, _) => false,
+        (a, b) => match (serde_json::to_value(a), serde_json::to_value(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        },
+    }
+}
+
+/// Rejects obvious misconfigurations of a single store level.
+fn check_store_config(config: &StoreConfig) -> Result<(), Error> {
+    match config {
+        StoreConfig::fast_slow(config) if is_same_store(&config.fast, &config.slow) => Err(
+            make_input_err!("FastSlowStore 'fast' and 'slow' must not be the same store"),
+        ),
+        StoreConfig::fast_slow(config)
+            if config.fast_store_max_populate_size != 0
+                && config.fast_store_max_populate_bytes.is_some() =>
+        {
+            Err(make_input_err!(
+                "FastSlowStore 'fast_store_max_populate_size' and 'fast_store_max_populate_bytes' must not both be set"
+            ))
+        }
+        StoreConfig::size_partitioning(config)
+            if is_same_store(&config.lower_store, &config.upper_store) =>
+        {
+            Err(make_input_err!(
+                "SizePartitioningStore 'lower_store' and 'upper_store' must not be the same store"
+            ))
+        }
+        StoreConfig::compression(config) if matches!(config.backend, StoreConfig::dedup(_)) => {
+            Err(make_input_err!(
+                "CompressionStore must not use a DedupStore as its backend, use the CompressionStore as the DedupStore's 'content_store' instead"
+            ))
+        }
+        StoreConfig::memory(config)
+            if config
+                .memory_pressure_eviction
+                .as_ref()
+                .is_some_and(|eviction| eviction.check_interval_s == Some(0)) =>
+        {
+            Err(make_input_err!(
+                "MemoryStore 'memory_pressure_eviction.check_interval_s' must be greater than 0"
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks every store in `stores` through all of its nested stores,
+/// following `ref_store`s to the store they name. This catches the
+/// misconfigurations `check_store_config` rejects when they are hidden
+/// behind other stores, and stores that reference themselves through a
+/// chain of `ref_store`s, which would cause every request to loop forever.
+pub fn check_store_configs(stores: &HashMap<String, StoreConfig>) -> Result<(), Error> {
+    for (name, config) in stores {
+        check_store_config_tree(config, stores, &mut vec![name.as_str()], false)
+            .err_tip(|| format!("Failed to create store '{name}'"))?;
+    }
+    Ok(())
+}
+
+/// Checks `config` and everything nested under it. `path` holds the names
+/// of the stores entered so far and `under_compression` is set once a
+/// `CompressionStore` backend has been entered.
+fn check_store_config_tree<'a>(
+    config: &'a StoreConfig,
+    stores: &'a HashMap<String, StoreConfig>,
+    path: &mut Vec<&'a str>,
+    under_compression: bool,
+) -> Result<(), Error> {
+    check_store_config(config)?;
+    match config {
+        StoreConfig::dedup(_) if under_compression => {
+            return Err(make_input_err!(
+                "CompressionStore must not use a DedupStore as its backend, use the CompressionStore as the DedupStore's 'content_store' instead"
+            ));
+        }
+        StoreConfig::compression(config) => {
+            return check_store_config_tree(&config.backend, stores, path, true);
+        }
+        StoreConfig::ref_store(ref_config) => {
+            let name = ref_config.name.as_str();
+            if path.contains(&name) {
+                return Err(make_input_err!(
+                    "Store '{name}' references itself through '{}'",
+                    path.join("' -> '")
+                ));
+            }
+            // Unknown names are reported when the ref_store is first used.
+            let Some(config) = stores.get(name) else {
+                return Ok(());
+            };
+            path.push(name);
+            let result = check_store_config_tree(config, stores, path, under_compression);
+            path.pop();
+            return result;
+        }
+        _ => {}
+    }
+    for child in child_store_configs(config) {
+        check_store_config_tree(child, stores, path, under_compression)?;
+    }
+    Ok(())
+}
+
+/// Writes a small sentinel blob to the store registered as `name`, then
+/// reads it back and verifies it. This is the same round trip as the store
+/// health check. The sentinel is derived from the store name and removed
+/// again afterwards, stores that can not remove keys keep it and reuse it on
+/// the next self-test.
+pub async fn self_test_store(name: &str, store: &Store) -> Result<(), Error> {
+    let namespace = format!("self_test/{name}");
+    if let HealthStatus::Failed { message, .. } = store.check_health(namespace.clone().into()).await
+    {
+        return Err(make_err!(
+            Code::FailedPrecondition,
+            "Startup self-test of store '{name}' failed: {message}"
+        ));
+    }
+    let (sentinel, _) = health_check_data(&namespace, store.as_store_driver().get_name());
+    match store.remove(sentinel).await {
+        Err(err) if err.code != Code::Unimplemented => {
+            Err(err).err_tip(|| format!("Removing self-test sentinel of store '{name}'"))
+        }
+        _ => Ok(()),
+    }
+}
@@ -12,19 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::SystemTime;
 
 use async_trait::async_trait;
+use futures::future::try_join_all;
 use nativelink_config::stores::{EvictionPolicy, ExistenceCacheStore as ExistenceCacheStoreConfig};
-use nativelink_error::{error_if, Error, ResultExt};
+use nativelink_error::{Error, ResultExt};
 use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::common::DigestInfo;
 use nativelink_util::evicting_map::{EvictingMap, LenEntry};
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
 use nativelink_util::metrics_utils::{CollectorState, MetricsComponent, Registry};
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    ConsistencyToken, DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+use parking_lot::Mutex;
 
 #[derive(Clone, Debug)]
 struct ExistanceItem(usize);
@@ -41,18 +46,102 @@ impl LenEntry for ExistanceItem {
     }
 }
 
+/// Tracks the backend lookups of a digest that are in flight.
+#[derive(Default)]
+struct InFlightLookup {
+    /// Number of lookups of the digest that are in flight.
+    lookups: usize,
+    /// Incremented whenever the digest is uploaded or read while a lookup
+    /// is in flight, so a lookup can tell its "missing" answer is stale.
+    generation: u64,
+}
+
+type InFlightLookups = Mutex<HashMap<DigestInfo, InFlightLookup>>;
+
+/// Registers lookups of `digests` in `in_flight_lookups` for as long as it
+/// lives.
+struct InFlightLookupGuard<'a> {
+    in_flight_lookups: &'a InFlightLookups,
+    digests: Vec<(DigestInfo, u64)>,
+}
+
+impl<'a> InFlightLookupGuard<'a> {
+    fn new(in_flight_lookups: &'a InFlightLookups, digests: &[DigestInfo]) -> Self {
+        let mut lookups = in_flight_lookups.lock();
+        let digests = digests
+            .iter()
+            .map(|digest| {
+                let lookup = lookups.entry(*digest).or_default();
+                lookup.lookups += 1;
+                (*digest, lookup.generation)
+            })
+            .collect();
+        Self {
+            in_flight_lookups,
+            digests,
+        }
+    }
+
+    /// Returns the digests that were not uploaded or read since the lookup
+    /// started.
+    fn unchanged(&self) -> Vec<DigestInfo> {
+        let lookups = self.in_flight_lookups.lock();
+        self.digests
+            .iter()
+            .filter(|(digest, generation)| {
+                lookups
+                    .get(digest)
+                    .is_some_and(|lookup| lookup.generation == *generation)
+            })
+            .map(|(digest, _)| *digest)
+            .collect()
+    }
+}
+
+impl Drop for InFlightLookupGuard<'_> {
+    fn drop(&mut self) {
+        let mut lookups = self.in_flight_lookups.lock();
+        for (digest, _) in &self.digests {
+            if let Some(lookup) = lookups.get_mut(digest) {
+                lookup.lookups -= 1;
+                if lookup.lookups == 0 {
+                    lookups.remove(digest);
+                }
+            }
+        }
+    }
+}
+
 pub struct ExistenceCacheStore {
     inner_store: Store,
     existence_cache: EvictingMap<DigestInfo, ExistanceItem, SystemTime>,
+    /// Digests the backend recently reported as missing. `None` if
+    /// `cache_missing_for_s` is zero.
+    missing_cache: Option<EvictingMap<DigestInfo, ExistanceItem, SystemTime>>,
+    /// Backend lookups in flight, used to keep a lookup that raced with an
+    /// upload from remembering the digest as missing.
+    in_flight_lookups: InFlightLookups,
+    max_backend_batch_size: usize,
 }
 
 impl ExistenceCacheStore {
     pub fn new(config: &ExistenceCacheStoreConfig, inner_store: Store) -> Arc<Self> {
         let empty_policy = EvictionPolicy::default();
         let eviction_policy = config.eviction_policy.as_ref().unwrap_or(&empty_policy);
+        let missing_cache = (config.cache_missing_for_s != 0).then(|| {
+            let missing_policy = EvictionPolicy {
+                max_seconds: config.cache_missing_for_s,
+                max_count: eviction_policy.max_count,
+                ..Default::default()
+            };
+            EvictingMap::new(&missing_policy, SystemTime::now())
+        });
         Arc::new(Self {
             inner_store,
             existence_cache: EvictingMap::new(eviction_policy, SystemTime::now()),
+            missing_cache,
+            in_flight_lookups: Mutex::new(HashMap::new()),
+            max_backend_batch_size: config.max_backend_batch_size,
         })
     }
 
@@ -64,6 +153,15 @@ impl ExistenceCacheStore {
         self.existence_cache.remove(digest).await;
     }
 
+    async fn forget_missing(&self, digest: &DigestInfo) {
+        if let Some(missing_cache) = &self.missing_cache {
+            if let Some(lookup) = self.in_flight_lookups.lock().get_mut(digest) {
+                lookup.generation += 1;
+            }
+            missing_cache.remove(digest).await;
+        }
+    }
+
     async fn inner_has_with_results(
         self: Pin<&Self>,
         keys: &[DigestInfo],
@@ -71,55 +169,83 @@ impl ExistenceCacheStore {
     ) -> Result<(), Error> {
         self.existence_cache.sizes_for_keys(keys, results).await;
 
-        let not_cached_keys: Vec<_> = keys
+        let mut known_missing = vec![None; keys.len()];
+        if let Some(missing_cache) = &self.missing_cache {
+            missing_cache.sizes_for_keys(keys, &mut known_missing).await;
+        }
+        let query_indexes: Vec<_> = results
             .iter()
-            .zip(results.iter())
-            .filter_map(|(digest, result)| result.map_or_else(|| Some(digest.into()), |_| None))
+            .zip(known_missing.iter())
+            .enumerate()
+            .filter_map(|(index, (result, missing))| {
+                (result.is_none() && missing.is_none()).then_some(index)
+            })
             .collect();
 
         // Hot path optimization when all keys are cached.
-        if not_cached_keys.is_empty() {
+        if query_indexes.is_empty() {
             return Ok(());
         }
 
         // Now query only the items not found in the cache.
-        let mut inner_results = vec![None; not_cached_keys.len()];
-        self.inner_store
-            .has_with_results(&not_cached_keys, &mut inner_results)
-            .await
-            .err_tip(|| "In ExistenceCacheStore::inner_has_with_results")?;
+        let query_keys: Vec<StoreKey> = query_indexes
+            .iter()
+            .map(|&index| keys[index].into())
+            .collect();
+        let mut inner_results = vec![None; query_keys.len()];
+        let in_flight_lookup = self.missing_cache.as_ref().map(|_| {
+            let digests: Vec<_> = query_indexes.iter().map(|&index| keys[index]).collect();
+            InFlightLookupGuard::new(&self.in_flight_lookups, &digests)
+        });
+        let batch_size = match self.max_backend_batch_size {
+            0 => query_keys.len(),
+            max_backend_batch_size => max_backend_batch_size,
+        };
+        try_join_all(
+            query_keys
+                .chunks(batch_size)
+                .zip(inner_results.chunks_mut(batch_size))
+                .map(|(keys, results)| self.inner_store.has_with_results(keys, results)),
+        )
+        .await
+        .err_tip(|| "In ExistenceCacheStore::inner_has_with_results")?;
 
-        // Insert found from previous query into our cache.
+        // Insert the results of the query into our caches.
         {
-            // Note: Sadly due to some weird lifetime issues we need to collect here, but
-            // in theory we don't actually need to collect.
-            let inserts = not_cached_keys
+            let (found, missing): (Vec<_>, Vec<_>) = query_indexes
                 .iter()
                 .zip(inner_results.iter())
-                .filter_map(|(key, result)| {
-                    result.map(|size| (key.borrow().into_digest(), ExistanceItem(size)))
-                })
+                .map(|(&index, result)| (keys[index], *result))
+                .partition(|(_, result)| result.is_some());
+            let inserts = found
+                .into_iter()
+                .filter_map(|(digest, result)| Some((digest, ExistanceItem(result?))))
                 .collect::<Vec<_>>();
             let _ = self.existence_cache.insert_many(inserts).await;
+            if let (Some(missing_cache), Some(in_flight_lookup)) =
+                (&self.missing_cache, in_flight_lookup)
+            {
+                let missing: Vec<_> = missing.into_iter().map(|(digest, _)| digest).collect();
+                let inserts = missing
+                    .iter()
+                    .map(|digest| (*digest, ExistanceItem(0)))
+                    .collect::<Vec<_>>();
+                let _ = missing_cache.insert_many(inserts).await;
+                // A digest uploaded or read while it was looked up may have
+                // been forgotten before the insert above, so it is forgotten
+                // again.
+                let unchanged = in_flight_lookup.unchanged();
+                for digest in missing {
+                    if !unchanged.contains(&digest) {
+                        missing_cache.remove(&digest).await;
+                    }
+                }
+            }
         }
 
         // Merge the results from the cache and the query.
-        {
-            let mut inner_results_iter = inner_results.into_iter();
-            // We know at this point that any None in results was queried and will have
-            // a result in inner_results_iter, so use this knowledge to fill in the results.
-            for result in results.iter_mut() {
-                if result.is_none() {
-                    *result = inner_results_iter
-                        .next()
-                        .expect("has_with_results returned less results than expected");
-                }
-            }
-            // Ensure that there was no logic error by ensuring our iterator is not empty.
-            error_if!(
-                inner_results_iter.next().is_some(),
-                "has_with_results returned more results than expected"
-            );
+        for (index, result) in query_indexes.into_iter().zip(inner_results) {
+            results[index] = result;
         }
 
         Ok(())
@@ -147,9 +273,20 @@ impl StoreDriver for ExistenceCacheStore {
     async fn update(
         self: Pin<&Self>,
         key: StoreKey<'_>,
-        mut reader: DropCloserReadHalf,
+        reader: DropCloserReadHalf,
         size_info: UploadSizeInfo,
     ) -> Result<(), Error> {
+        self.update_with_consistency_token(key, reader, size_info)
+            .await
+            .map(|_| ())
+    }
+
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
         let digest = key.into_digest();
         let mut exists = [None];
         self.inner_has_with_results(&[digest], &mut exists)
@@ -162,10 +299,14 @@ impl StoreDriver for ExistenceCacheStore {
                 .drain()
                 .await
                 .err_tip(|| "In ExistenceCacheStore::update")?;
-            return Ok(());
+            return Ok(None);
         }
-        let result = self.inner_store.update(digest, reader, size_info).await;
+        let result = self
+            .inner_store
+            .update_with_consistency_token(digest, reader, size_info)
+            .await;
         if result.is_ok() {
+            self.forget_missing(&digest).await;
             if let UploadSizeInfo::ExactSize(size) = size_info {
                 let _ = self
                     .existence_cache
@@ -176,6 +317,22 @@ impl StoreDriver for ExistenceCacheStore {
         result
     }
 
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        let digest = key.borrow().into_digest();
+        self.inner_store
+            .as_store_driver_pin()
+            .wait_for_consistency(key, token)
+            .await?;
+        // A lookup made before the write became visible may have remembered
+        // the digest as missing.
+        self.forget_missing(&digest).await;
+        Ok(())
+    }
+
     async fn get_part(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -189,6 +346,7 @@ impl StoreDriver for ExistenceCacheStore {
             .get_part(digest, writer, offset, length)
             .await;
         if result.is_ok() {
+            self.forget_missing(&digest).await;
             let size = usize::try_from(digest.size_bytes)
                 .err_tip(|| "Could not convert size_bytes in ExistenceCacheStore::get_part")?;
             let _ = self
@@ -199,6 +357,10 @@ impl StoreDriver for ExistenceCacheStore {
         result
     }
 
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().inner_store.list_digests(prefix)
+    }
+
     fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
         self
     }
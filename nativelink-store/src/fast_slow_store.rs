@@ -29,10 +29,39 @@ use nativelink_util::fs;
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
 use nativelink_util::metrics_utils::{CollectorState, MetricsComponent, Registry};
 use nativelink_util::store_trait::{
-    slow_update_store_with_file, Store, StoreDriver, StoreKey, StoreLike, StoreOptimizations,
-    UploadSizeInfo,
+    slow_update_store_with_file, ConsistencyToken, DigestStream, Store, StoreDriver, StoreKey,
+    StoreLike, StoreOptimizations, UploadSizeInfo,
 };
 
+/// Error returned when `FastSlowStore::populate_fast_store` fails.
+#[derive(Debug)]
+pub struct PopulateFastStoreError {
+    /// Number of bytes sent to the fast store before the failure. Objects
+    /// that were only partially sent are not committed by the fast store.
+    pub bytes_populated: u64,
+    /// The underlying error.
+    pub err: Error,
+}
+
+impl From<PopulateFastStoreError> for Error {
+    fn from(err: PopulateFastStoreError) -> Self {
+        err.err.append(format!(
+            "After populating {} bytes into the fast store",
+            err.bytes_populated
+        ))
+    }
+}
+
+/// Whether a read that misses the fast store should copy the object into
+/// the fast store.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PopulateMode {
+    /// Copy the object into the fast store regardless of its size.
+    Always,
+    /// Only copy objects up to `fast_store_max_populate_bytes` bytes.
+    UpToMaxSize,
+}
+
 // TODO(blaise.bruer) This store needs to be evaluated for more efficient memory usage,
 // there are many copies happening internally.
 
@@ -43,19 +72,24 @@ use nativelink_util::store_trait::{
 pub struct FastSlowStore {
     fast_store: Store,
     slow_store: Store,
+    fast_store_max_populate_bytes: Option<u64>,
     weak_self: Weak<Self>,
     metrics: FastSlowStoreMetrics,
 }
 
 impl FastSlowStore {
     pub fn new(
-        _config: &nativelink_config::stores::FastSlowStore,
+        config: &nativelink_config::stores::FastSlowStore,
         fast_store: Store,
         slow_store: Store,
     ) -> Arc<Self> {
         Arc::new_cyclic(|weak_self| Self {
             fast_store,
             slow_store,
+            fast_store_max_populate_bytes: config
+                .fast_store_max_populate_bytes
+                .or((config.fast_store_max_populate_size != 0)
+                    .then_some(config.fast_store_max_populate_size as u64)),
             weak_self: weak_self.clone(),
             metrics: FastSlowStoreMetrics::default(),
         })
@@ -77,12 +111,21 @@ impl FastSlowStore {
     /// cost function. Since the data itself is shared and not copied it should be fairly
     /// low cost to just discard the data, but does cost a few mutex locks while
     /// streaming.
-    pub async fn populate_fast_store(&self, key: StoreKey<'_>) -> Result<(), Error> {
+    /// On failure the number of bytes that were sent to the fast store is
+    /// reported so callers can decide whether to retry.
+    pub async fn populate_fast_store(
+        &self,
+        key: StoreKey<'_>,
+    ) -> Result<(), PopulateFastStoreError> {
         let maybe_size_info = self
             .fast_store
             .has(key.borrow())
             .await
-            .err_tip(|| "While querying in populate_fast_store")?;
+            .err_tip(|| "While querying in populate_fast_store")
+            .map_err(|err| PopulateFastStoreError {
+                bytes_populated: 0,
+                err,
+            })?;
         if maybe_size_info.is_some() {
             return Ok(());
         }
@@ -94,8 +137,162 @@ impl FastSlowStore {
             while !rx.recv().await?.is_empty() {}
             Ok(())
         };
-        let (drain_res, get_res) = join!(drain_fut, StoreDriver::get(Pin::new(self), key, tx));
-        get_res.err_tip(|| "Failed to populate()").merge(drain_res)
+        let fast_store_bytes = AtomicU64::new(0);
+        let get_fut = async {
+            let mut tx = tx;
+            // Populating the fast store is the point of this call, so never
+            // skip it, even for large objects.
+            Pin::new(self)
+                .get_part_impl(
+                    key,
+                    &mut tx,
+                    0,
+                    None,
+                    PopulateMode::Always,
+                    &fast_store_bytes,
+                )
+                .await
+        };
+        let (drain_res, get_res) = join!(drain_fut, get_fut);
+        let Err(err) = get_res.err_tip(|| "Failed to populate()").merge(drain_res) else {
+            return Ok(());
+        };
+        Err(PopulateFastStoreError {
+            bytes_populated: fast_store_bytes.load(Ordering::Acquire),
+            err,
+        })
+    }
+
+    async fn get_part_impl(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+        populate_mode: PopulateMode,
+        fast_store_bytes: &AtomicU64,
+    ) -> Result<(), Error> {
+        // TODO(blaise.bruer) Investigate if we should maybe ignore errors here instead of
+        // forwarding the up.
+        if self.fast_store.has(key.borrow()).await?.is_some() {
+            self.metrics
+                .fast_store_hit_count
+                .fetch_add(1, Ordering::Acquire);
+            self.fast_store
+                .get_part(key, writer.borrow_mut(), offset, length)
+                .await?;
+            self.metrics
+                .fast_store_downloaded_bytes
+                .fetch_add(writer.get_bytes_written(), Ordering::Acquire);
+            return Ok(());
+        }
+
+        // If our slow store is a noop store it can never have the data, so
+        // the fast store miss is final.
+        if self
+            .slow_store
+            .inner_store::<StoreKey<'_>>(None)
+            .optimized_for(StoreOptimizations::NoopDownloads)
+        {
+            return Err(make_err!(
+                Code::NotFound,
+                "Object {} not found in fast store and slow store is a noop store",
+                key.as_str()
+            ));
+        }
+
+        let sz = self
+            .slow_store
+            .has(key.borrow())
+            .await
+            .err_tip(|| "Failed to run has() on slow store")?
+            .ok_or_else(|| {
+                make_err!(
+                    Code::NotFound,
+                    "Object {} not found in either fast or slow store",
+                    key.as_str()
+                )
+            })?;
+        self.metrics
+            .slow_store_hit_count
+            .fetch_add(1, Ordering::Acquire);
+
+        if populate_mode == PopulateMode::UpToMaxSize
+            && self
+                .fast_store_max_populate_bytes
+                .is_some_and(|max_populate_bytes| sz as u64 > max_populate_bytes)
+        {
+            // Large objects are served directly from the slow store so they
+            // do not evict smaller objects from the fast store.
+            self.slow_store
+                .get_part(key, writer.borrow_mut(), offset, length)
+                .await?;
+            self.metrics
+                .slow_store_downloaded_bytes
+                .fetch_add(writer.get_bytes_written(), Ordering::Acquire);
+            return Ok(());
+        }
+
+        let send_range = offset..length.map_or(usize::MAX, |length| length + offset);
+        let mut bytes_received: usize = 0;
+
+        let (mut fast_tx, fast_rx) = make_buf_channel_pair();
+        let (slow_tx, mut slow_rx) = make_buf_channel_pair();
+        let data_stream_fut = async move {
+            let mut writer_pin = Pin::new(writer);
+            loop {
+                let output_buf = slow_rx
+                    .recv()
+                    .await
+                    .err_tip(|| "Failed to read data data buffer from slow store")?;
+                if output_buf.is_empty() {
+                    // Write out our EOF.
+                    // We are dropped as soon as we send_eof to writer_pin, so
+                    // we wait until we've finished all of our joins to do that.
+                    let fast_res = fast_tx.send_eof();
+                    return Ok::<_, Error>((fast_res, writer_pin));
+                }
+                self.metrics
+                    .slow_store_downloaded_bytes
+                    .fetch_add(output_buf.len() as u64, Ordering::Acquire);
+
+                let writer_fut = if let Some(range) = Self::calculate_range(
+                    &(bytes_received..bytes_received + output_buf.len()),
+                    &send_range,
+                ) {
+                    writer_pin.send(output_buf.slice(range)).right_future()
+                } else {
+                    futures::future::ready(Ok(())).left_future()
+                };
+                bytes_received += output_buf.len();
+
+                let output_len = output_buf.len() as u64;
+                let (fast_tx_res, writer_res) = join!(fast_tx.send(output_buf), writer_fut);
+                fast_tx_res.err_tip(|| "Failed to write to fast store in fast_slow store")?;
+                fast_store_bytes.fetch_add(output_len, Ordering::Release);
+                writer_res.err_tip(|| "Failed to write result to writer in fast_slow store")?;
+            }
+        };
+
+        let slow_store_fut = self.slow_store.get(key.borrow(), slow_tx);
+        let fast_store_fut =
+            self.fast_store
+                .update(key.borrow(), fast_rx, UploadSizeInfo::ExactSize(sz));
+
+        let (data_stream_res, slow_res, fast_res) =
+            join!(data_stream_fut, slow_store_fut, fast_store_fut);
+        match data_stream_res {
+            Ok((fast_eof_res, mut writer_pin)) =>
+            // Sending the EOF will drop us almost immediately in bytestream_server
+            // so we perform it as the very last action in this method.
+            {
+                fast_eof_res
+                    .merge(fast_res)
+                    .merge(slow_res)
+                    .merge(writer_pin.send_eof())
+            }
+            Err(err) => fast_res.merge(slow_res).merge(Err(err)),
+        }
     }
 
     /// Returns the range of bytes that should be sent given a slice bounds
@@ -145,67 +342,69 @@ impl StoreDriver for FastSlowStore {
     async fn update(
         self: Pin<&Self>,
         key: StoreKey<'_>,
-        mut reader: DropCloserReadHalf,
+        reader: DropCloserReadHalf,
         size_info: UploadSizeInfo,
     ) -> Result<(), Error> {
+        self.update_with_consistency_token(key, reader, size_info)
+            .await
+            .map(|_| ())
+    }
+
+    /// The token is the one of the `slow` store, since reads that miss the
+    /// `fast` store are served from it. If the `slow` store ignores updates,
+    /// the token of the `fast` store is returned instead.
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
         // If either one of our stores is a noop store, bypass the multiplexing
         // and just use the store that is not a noop store.
         let slow_store = self.slow_store.inner_store(Some(key.borrow()));
         if slow_store.optimized_for(StoreOptimizations::NoopUpdates) {
-            return self.fast_store.update(key, reader, size_info).await;
+            return self
+                .fast_store
+                .update_with_consistency_token(key, reader, size_info)
+                .await;
         }
         let fast_store = self.fast_store.inner_store(Some(key.borrow()));
         if fast_store.optimized_for(StoreOptimizations::NoopUpdates) {
-            return self.slow_store.update(key, reader, size_info).await;
+            return self
+                .slow_store
+                .update_with_consistency_token(key, reader, size_info)
+                .await;
         }
 
-        let (mut fast_tx, fast_rx) = make_buf_channel_pair();
-        let (mut slow_tx, slow_rx) = make_buf_channel_pair();
-
-        let data_stream_fut = async move {
-            loop {
-                let buffer = reader
-                    .recv()
-                    .await
-                    .err_tip(|| "Failed to read buffer in fastslow store")?;
-                if buffer.is_empty() {
-                    // EOF received.
-                    fast_tx.send_eof().err_tip(|| {
-                        "Failed to write eof to fast store in fast_slow store update"
-                    })?;
-                    slow_tx
-                        .send_eof()
-                        .err_tip(|| "Failed to write eof to writer in fast_slow store update")?;
-                    return Result::<(), Error>::Ok(());
-                }
-
-                let (fast_result, slow_result) =
-                    join!(fast_tx.send(buffer.clone()), slow_tx.send(buffer));
-                fast_result
-                    .map_err(|e| {
-                        make_err!(
-                            Code::Internal,
-                            "Failed to send message to fast_store in fast_slow_store {:?}",
-                            e
-                        )
-                    })
-                    .merge(slow_result.map_err(|e| {
-                        make_err!(
-                            Code::Internal,
-                            "Failed to send message to slow_store in fast_slow store {:?}",
-                            e
-                        )
-                    }))?;
-            }
-        };
+        let (fast_rx, slow_rx, data_stream_fut) = reader.tee();
 
         let fast_store_fut = self.fast_store.update(key.borrow(), fast_rx, size_info);
-        let slow_store_fut = self.slow_store.update(key.borrow(), slow_rx, size_info);
+        let slow_store_fut =
+            self.slow_store
+                .update_with_consistency_token(key.borrow(), slow_rx, size_info);
 
         let (data_stream_res, fast_res, slow_res) =
             join!(data_stream_fut, fast_store_fut, slow_store_fut);
-        data_stream_res.merge(fast_res).merge(slow_res)?;
-        Ok(())
+        data_stream_res.merge(fast_res).merge(slow_res)
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        // Wait on the store that issued the token, see
+        // `update_with_consistency_token()`.
+        let slow_store = self.slow_store.inner_store(Some(key.borrow()));
+        let store = if slow_store.optimized_for(StoreOptimizations::NoopUpdates) {
+            &self.fast_store
+        } else {
+            &self.slow_store
+        };
+        store
+            .as_store_driver_pin()
+            .wait_for_consistency(key, token)
+            .await
     }
 
     /// FastSlowStore has optimiations for dealing with files.
@@ -281,95 +480,21 @@ impl StoreDriver for FastSlowStore {
         offset: usize,
         length: Option<usize>,
     ) -> Result<(), Error> {
-        // TODO(blaise.bruer) Investigate if we should maybe ignore errors here instead of
-        // forwarding the up.
-        if self.fast_store.has(key.borrow()).await?.is_some() {
-            self.metrics
-                .fast_store_hit_count
-                .fetch_add(1, Ordering::Acquire);
-            self.fast_store
-                .get_part(key, writer.borrow_mut(), offset, length)
-                .await?;
-            self.metrics
-                .fast_store_downloaded_bytes
-                .fetch_add(writer.get_bytes_written(), Ordering::Acquire);
-            return Ok(());
-        }
-
-        let sz = self
-            .slow_store
-            .has(key.borrow())
-            .await
-            .err_tip(|| "Failed to run has() on slow store")?
-            .ok_or_else(|| {
-                make_err!(
-                    Code::NotFound,
-                    "Object {} not found in either fast or slow store",
-                    key.as_str()
-                )
-            })?;
-        self.metrics
-            .slow_store_hit_count
-            .fetch_add(1, Ordering::Acquire);
-
-        let send_range = offset..length.map_or(usize::MAX, |length| length + offset);
-        let mut bytes_received: usize = 0;
-
-        let (mut fast_tx, fast_rx) = make_buf_channel_pair();
-        let (slow_tx, mut slow_rx) = make_buf_channel_pair();
-        let data_stream_fut = async move {
-            let mut writer_pin = Pin::new(writer);
-            loop {
-                let output_buf = slow_rx
-                    .recv()
-                    .await
-                    .err_tip(|| "Failed to read data data buffer from slow store")?;
-                if output_buf.is_empty() {
-                    // Write out our EOF.
-                    // We are dropped as soon as we send_eof to writer_pin, so
-                    // we wait until we've finished all of our joins to do that.
-                    let fast_res = fast_tx.send_eof();
-                    return Ok::<_, Error>((fast_res, writer_pin));
-                }
-                self.metrics
-                    .slow_store_downloaded_bytes
-                    .fetch_add(output_buf.len() as u64, Ordering::Acquire);
-
-                let writer_fut = if let Some(range) = Self::calculate_range(
-                    &(bytes_received..bytes_received + output_buf.len()),
-                    &send_range,
-                ) {
-                    writer_pin.send(output_buf.slice(range)).right_future()
-                } else {
-                    futures::future::ready(Ok(())).left_future()
-                };
-                bytes_received += output_buf.len();
-
-                let (fast_tx_res, writer_res) = join!(fast_tx.send(output_buf), writer_fut);
-                fast_tx_res.err_tip(|| "Failed to write to fast store in fast_slow store")?;
-                writer_res.err_tip(|| "Failed to write result to writer in fast_slow store")?;
-            }
-        };
-
-        let slow_store_fut = self.slow_store.get(key.borrow(), slow_tx);
-        let fast_store_fut =
-            self.fast_store
-                .update(key.borrow(), fast_rx, UploadSizeInfo::ExactSize(sz));
+        self.get_part_impl(
+            key,
+            writer,
+            offset,
+            length,
+            PopulateMode::UpToMaxSize,
+            &AtomicU64::new(0),
+        )
+        .await
+    }
 
-        let (data_stream_res, slow_res, fast_res) =
-            join!(data_stream_fut, slow_store_fut, fast_store_fut);
-        match data_stream_res {
-            Ok((fast_eof_res, mut writer_pin)) =>
-            // Sending the EOF will drop us almost immediately in bytestream_server
-            // so we perform it as the very last action in this method.
-            {
-                fast_eof_res
-                    .merge(fast_res)
-                    .merge(slow_res)
-                    .merge(writer_pin.send_eof())
-            }
-            Err(err) => fast_res.merge(slow_res).merge(Err(err)),
-        }
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        // Every object is written to the slow store, the fast store only
+        // holds a subset of them.
+        self.get_ref().slow_store.list_digests(prefix)
     }
 
     fn inner_store(&self, _key: Option<StoreKey>) -> &dyn StoreDriver {
@@ -34,14 +34,16 @@ use nativelink_util::common::{fs, DigestInfo};
 use nativelink_util::evicting_map::{EvictingMap, LenEntry};
 use nativelink_util::health_utils::{HealthRegistryBuilder, HealthStatus, HealthStatusIndicator};
 use nativelink_util::metrics_utils::{Collector, CollectorState, MetricsComponent, Registry};
-use nativelink_util::store_trait::{StoreDriver, StoreKey, StoreOptimizations, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    DigestStream, StoreDriver, StoreKey, StoreOptimizations, UploadSizeInfo,
+};
 use nativelink_util::{background_spawn, spawn_blocking};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use tokio::time::{sleep, timeout, Sleep};
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::{event, Level};
 
-use crate::cas_utils::is_zero_digest;
+use crate::cas_utils::{digest_from_key, is_zero_digest};
 
 // Default size to allocate memory of the buffer when reading files.
 const DEFAULT_BUFF_SIZE: usize = 32 * 1024;
@@ -386,13 +388,6 @@ impl LenEntry for FileEntryImpl {
     }
 }
 
-#[inline]
-pub fn digest_from_filename(file_name: &str) -> Result<DigestInfo, Error> {
-    let (hash, size) = file_name.split_once('-').err_tip(|| "")?;
-    let size = size.parse::<i64>()?;
-    DigestInfo::try_new(hash, size)
-}
-
 /// The number of files to read the metadata for at the same time when running
 /// add_files_to_cache.
 const SIMULTANEOUS_METADATA_READS: usize = 200;
@@ -412,7 +407,7 @@ async fn add_files_to_cache<Fe: FileEntry>(
         anchor_time: &SystemTime,
         shared_context: &Arc<SharedContext>,
     ) -> Result<(), Error> {
-        let digest = digest_from_filename(file_name)?;
+        let digest = digest_from_key(file_name)?;
 
         let file_entry = Fe::create(
             data_size,
@@ -585,6 +580,9 @@ impl<Fe: FileEntry> FilesystemStore<Fe> {
     }
 
     pub async fn get_file_entry_for_digest(&self, digest: &DigestInfo) -> Result<Arc<Fe>, Error> {
+        if is_zero_digest(digest) {
+            Pin::new(self).create_zero_file_if_missing(*digest).await?;
+        }
         self.evicting_map.get(digest).await.ok_or_else(|| {
             make_err!(
                 Code::NotFound,
@@ -594,6 +592,19 @@ impl<Fe: FileEntry> FilesystemStore<Fe> {
         })
     }
 
+    /// `StoreLike` answers for the empty digest without calling the store,
+    /// so nothing ever uploads it. The empty file is created on demand
+    /// instead, which `has_with_results` does when it is missing.
+    async fn create_zero_file_if_missing(
+        self: Pin<&Self>,
+        digest: DigestInfo,
+    ) -> Result<(), Error> {
+        StoreDriver::has(self, digest.into())
+            .await
+            .err_tip(|| "Failed to check if zero digest exists in filesystem store")?;
+        Ok(())
+    }
+
     async fn update_file<'a>(
         self: Pin<&'a Self>,
         mut entry: Fe,
@@ -715,6 +726,61 @@ impl<Fe: FileEntry> FilesystemStore<Fe> {
         .await
         .err_tip(|| "Failed to create spawn in filesystem store update_file")?
     }
+
+    /// Sends the rest of the file to the writer followed by EOF. The file
+    /// is closed while waiting on a slow receiver and re-opened on demand.
+    async fn send_file_to_writer(
+        &self,
+        mut resumeable_temp_file: fs::ResumeableFileSlot,
+        writer: &mut DropCloserWriteHalf,
+    ) -> Result<(), Error> {
+        loop {
+            let mut buf = BytesMut::with_capacity(self.read_buffer_size);
+            resumeable_temp_file
+                .as_reader()
+                .await
+                .err_tip(|| "In FileSystemStore::send_file_to_writer()")?
+                .read_buf(&mut buf)
+                .await
+                .err_tip(|| "Failed to read data in filesystem store")?;
+            if buf.is_empty() {
+                break; // EOF.
+            }
+            // In the event it takes a while to send the data to the client, we want to close the
+            // reading file, to prevent the file descriptor left open for long periods of time.
+            // Failing to do so might cause deadlocks if the receiver is unable to receive data
+            // because it is waiting for a file descriptor to open before receiving data.
+            // Using `ResumeableFileSlot` will re-open the file in the event it gets closed on the
+            // next iteration.
+            let buf_content = buf.freeze();
+            loop {
+                let sleep_fn = (self.sleep_fn)(fs::idle_file_descriptor_timeout());
+                tokio::pin!(sleep_fn);
+                tokio::select! {
+                    _ = & mut (sleep_fn) => {
+                        resumeable_temp_file
+                            .close_file()
+                            .await
+                            .err_tip(|| "Could not close file due to timeout in FileSystemStore::send_file_to_writer")?;
+                        continue;
+                    }
+                    res = writer.send(buf_content.clone()) => {
+                        match res {
+                            Ok(()) => break,
+                            Err(err) => {
+                                return Err(err).err_tip(|| "Failed to send chunk in filesystem store send_file_to_writer");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        writer
+            .send_eof()
+            .err_tip(|| "Filed to send EOF in filesystem store send_file_to_writer")?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -739,7 +805,7 @@ impl<Fe: FileEntry> StoreDriver for FilesystemStore<Fe> {
             }
             let (mut tx, rx) = make_buf_channel_pair();
             let send_eof_result = tx.send_eof();
-            self.update(digest.into(), rx, UploadSizeInfo::ExactSize(0))
+            StoreDriver::update(self, digest.into(), rx, UploadSizeInfo::ExactSize(0))
                 .await
                 .err_tip(|| format!("Failed to create zero file for key {digest:?}"))
                 .merge(
@@ -752,6 +818,31 @@ impl<Fe: FileEntry> StoreDriver for FilesystemStore<Fe> {
         Ok(())
     }
 
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        let content_path = format!("{}/", self.shared_context.content_path);
+        Box::pin(
+            async move {
+                let (permit, dir_handle) = fs::read_dir(content_path)
+                    .await
+                    .err_tip(|| "Failed opening content directory for listing in filesystem store")?
+                    .into_inner();
+                let dir_stream = ReadDirStream::new(dir_handle).map_err(Error::from);
+                Ok::<_, Error>(dir_stream.try_filter_map(move |dir_entry| {
+                    // Hold the permit until the whole directory was read.
+                    let _permit = &permit;
+                    let file_name = dir_entry.file_name();
+                    let digest = file_name
+                        .to_str()
+                        .filter(|file_name| file_name.starts_with(prefix))
+                        .and_then(|file_name| digest_from_key(file_name).ok());
+                    futures::future::ready(Ok(digest))
+                }))
+            }
+            .try_flatten_stream()
+            .map_err(|e| e.append("While listing digests in filesystem store")),
+        )
+    }
+
     async fn update(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -831,9 +922,7 @@ impl<Fe: FileEntry> StoreDriver for FilesystemStore<Fe> {
     ) -> Result<(), Error> {
         let digest = key.into_digest();
         if is_zero_digest(digest) {
-            self.has(digest.into())
-                .await
-                .err_tip(|| "Failed to check if zero digest exists in filesystem store")?;
+            self.create_zero_file_if_missing(digest).await?;
             writer
                 .send_eof()
                 .err_tip(|| "Failed to send zero EOF in filesystem store get_part")?;
@@ -848,54 +937,55 @@ impl<Fe: FileEntry> StoreDriver for FilesystemStore<Fe> {
             )
         })?;
         let read_limit = length.unwrap_or(usize::MAX) as u64;
-        let mut resumeable_temp_file = entry.read_file_part(offset as u64, read_limit).await?;
+        let resumeable_temp_file = entry.read_file_part(offset as u64, read_limit).await?;
 
-        loop {
-            let mut buf = BytesMut::with_capacity(self.read_buffer_size);
-            resumeable_temp_file
+        self.send_file_to_writer(resumeable_temp_file, writer)
+            .await
+            .err_tip(|| "In FileSystemStore::get_part()")
+    }
+
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        let digest = key.into_digest();
+        if is_zero_digest(digest) {
+            self.create_zero_file_if_missing(digest).await?;
+            writer
+                .send_eof()
+                .err_tip(|| "Failed to send zero EOF in filesystem store get_tail")?;
+            return Ok(());
+        }
+
+        let entry = self.evicting_map.get(&digest).await.ok_or_else(|| {
+            make_err!(
+                Code::NotFound,
+                "{} not found in filesystem store",
+                digest.hash_str()
+            )
+        })?;
+        let mut resumeable_temp_file = entry.read_file_part(0, length as u64).await?;
+        {
+            let file = resumeable_temp_file
                 .as_reader()
                 .await
-                .err_tip(|| "In FileSystemStore::get_part()")?
-                .read_buf(&mut buf)
+                .err_tip(|| "In FileSystemStore::get_tail()")?
+                .get_mut();
+            // Files smaller than `length` can't seek to `End(-length)`, so we
+            // clamp to the start of the file.
+            let file_size = file
+                .seek(SeekFrom::End(0))
                 .await
-                .err_tip(|| "Failed to read data in filesystem store")?;
-            if buf.is_empty() {
-                break; // EOF.
-            }
-            // In the event it takes a while to send the data to the client, we want to close the
-            // reading file, to prevent the file descriptor left open for long periods of time.
-            // Failing to do so might cause deadlocks if the receiver is unable to receive data
-            // because it is waiting for a file descriptor to open before receiving data.
-            // Using `ResumeableFileSlot` will re-open the file in the event it gets closed on the
-            // next iteration.
-            let buf_content = buf.freeze();
-            loop {
-                let sleep_fn = (self.sleep_fn)(fs::idle_file_descriptor_timeout());
-                tokio::pin!(sleep_fn);
-                tokio::select! {
-                    _ = & mut (sleep_fn) => {
-                        resumeable_temp_file
-                            .close_file()
-                            .await
-                            .err_tip(|| "Could not close file due to timeout in FileSystemStore::get_part")?;
-                        continue;
-                    }
-                    res = writer.send(buf_content.clone()) => {
-                        match res {
-                            Ok(()) => break,
-                            Err(err) => {
-                                return Err(err).err_tip(|| "Failed to send chunk in filesystem store get_part");
-                            }
-                        }
-                    }
-                }
-            }
+                .err_tip(|| "Failed to seek to end of file in FileSystemStore::get_tail()")?;
+            file.seek(SeekFrom::Start(file_size.saturating_sub(length as u64)))
+                .await
+                .err_tip(|| "Failed to seek file in FileSystemStore::get_tail()")?;
         }
-        writer
-            .send_eof()
-            .err_tip(|| "Filed to send EOF in filesystem store get_part")?;
-
-        Ok(())
+        self.send_file_to_writer(resumeable_temp_file, writer)
+            .await
+            .err_tip(|| "In FileSystemStore::get_tail()")
     }
 
     fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
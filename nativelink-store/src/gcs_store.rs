@@ -0,0 +1,668 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{unfold, FuturesUnordered};
+use futures::{StreamExt, TryStreamExt};
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::retry::{Retrier, RetryResult};
+use nativelink_util::store_trait::{StoreDriver, StoreKey, UploadSizeInfo};
+use rand::rngs::OsRng;
+use rand::Rng;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tracing::{event, Level};
+
+use crate::cas_utils::is_zero_digest;
+
+// Default base URL of the GCS JSON API.
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+// Chunks of a resumable upload must be a multiple of this size. See:
+// https://cloud.google.com/storage/docs/performing-resumable-uploads
+const RESUMABLE_CHUNK_ALIGNMENT: usize = 256 * 1024; // 256KiB.
+
+// Default size of the chunks of a resumable upload.
+// Note: If you change this, adjust the docs in the config.
+const DEFAULT_RESUMABLE_CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8MiB.
+
+// Default host of the GCE metadata server that hands out access tokens.
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+
+// Access tokens are refreshed this long before they expire.
+const ACCESS_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// GCS answers with this status while a resumable upload is incomplete.
+const RESUME_INCOMPLETE: u16 = 308;
+
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+/// Percent-encodes `value` so it can be used as a single path segment or
+/// query parameter value.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{byte:02X}").expect("Writing to a String cannot fail");
+        }
+    }
+    encoded
+}
+
+/// Maps an unexpected response status to a retry decision. Throttling and
+/// server errors are retried, everything else fails right away.
+fn retry_result_for_status<T>(status: StatusCode, context: &str) -> RetryResult<T> {
+    let err = make_err!(
+        Code::Unavailable,
+        "Unexpected status {status} from GCS while {context}"
+    );
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        RetryResult::Retry(err)
+    } else {
+        RetryResult::Err(err)
+    }
+}
+
+/// Returns how many bytes of a resumable upload GCS has persisted, taken
+/// from the `Range` header of a resume incomplete response. See:
+/// https://cloud.google.com/storage/docs/performing-resumable-uploads#status-check
+fn persisted_size(response: &Response<Body>) -> Result<usize, Error> {
+    let Some(range) = response.headers().get(RANGE) else {
+        return Ok(0);
+    };
+    range
+        .to_str()
+        .ok()
+        .and_then(|range| range.strip_prefix("bytes=0-"))
+        .and_then(|last_byte| last_byte.parse::<usize>().ok())
+        .map(|last_byte| last_byte + 1)
+        .ok_or_else(|| make_err!(Code::Internal, "Invalid Range header {range:?} from GCS"))
+}
+
+pub struct GcsStore {
+    http_client: Client<HttpsConnector<HttpConnector>, Body>,
+    // The metadata server is only reachable over plain HTTP, so it gets its
+    // own client that is not restricted to HTTPS.
+    metadata_client: Client<HttpConnector, Body>,
+    metadata_host: String,
+    endpoint: String,
+    bucket: String,
+    key_prefix: String,
+    retrier: Retrier,
+    resumable_chunk_size: usize,
+    anonymous_access: bool,
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+impl GcsStore {
+    pub async fn new(config: &nativelink_config::stores::GcsStore) -> Result<Arc<Self>, Error> {
+        let jitter_amt = config.retry.jitter;
+        let jitter_fn = Arc::new(move |delay: Duration| {
+            if jitter_amt == 0. {
+                return delay;
+            }
+            let min = 1. - (jitter_amt / 2.);
+            let max = 1. + (jitter_amt / 2.);
+            delay.mul_f32(OsRng.gen_range(min..max))
+        });
+        Self::new_with_jitter(config, jitter_fn)
+    }
+
+    pub fn new_with_jitter(
+        config: &nativelink_config::stores::GcsStore,
+        jitter_fn: Arc<dyn Fn(Duration) -> Duration + Send + Sync>,
+    ) -> Result<Arc<Self>, Error> {
+        let metadata_host =
+            env::var("GCE_METADATA_HOST").unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_string());
+        Self::new_with_jitter_and_metadata_host(config, jitter_fn, metadata_host)
+    }
+
+    pub fn new_with_jitter_and_metadata_host(
+        config: &nativelink_config::stores::GcsStore,
+        jitter_fn: Arc<dyn Fn(Duration) -> Duration + Send + Sync>,
+        metadata_host: String,
+    ) -> Result<Arc<Self>, Error> {
+        let resumable_chunk_size = if config.resumable_chunk_size == 0 {
+            DEFAULT_RESUMABLE_CHUNK_SIZE
+        } else {
+            config.resumable_chunk_size
+        };
+        if resumable_chunk_size % RESUMABLE_CHUNK_ALIGNMENT != 0 {
+            return Err(make_input_err!(
+                "resumable_chunk_size must be a multiple of {RESUMABLE_CHUNK_ALIGNMENT} in GcsStore, got {resumable_chunk_size}"
+            ));
+        }
+
+        let connector_with_roots = hyper_rustls::HttpsConnectorBuilder::new().with_webpki_roots();
+        let connector_with_schemes = if config.insecure_allow_http {
+            connector_with_roots.https_or_http()
+        } else {
+            connector_with_roots.https_only()
+        };
+        let connector = connector_with_schemes.enable_http1().enable_http2().build();
+
+        Ok(Arc::new(Self {
+            http_client: Client::builder().build(connector),
+            metadata_client: Client::builder().build_http(),
+            metadata_host,
+            endpoint: config
+                .endpoint
+                .as_deref()
+                .unwrap_or(DEFAULT_ENDPOINT)
+                .trim_end_matches('/')
+                .to_string(),
+            bucket: config.bucket.to_string(),
+            key_prefix: config.key_prefix.as_ref().unwrap_or(&String::new()).clone(),
+            retrier: Retrier::new(
+                Arc::new(|duration| Box::pin(sleep(duration))),
+                jitter_fn,
+                config.retry.to_owned(),
+            ),
+            resumable_chunk_size,
+            anonymous_access: config.anonymous_access,
+            access_token: Mutex::new(None),
+        }))
+    }
+
+    fn make_gcs_path(&self, key: StoreKey<'_>) -> String {
+        format!("{}{}", self.key_prefix, key.as_str(),)
+    }
+
+    fn object_url(&self, gcs_path: &str) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.endpoint,
+            percent_encode(&self.bucket),
+            percent_encode(gcs_path)
+        )
+    }
+
+    fn upload_url(&self, gcs_path: &str, upload_type: &str) -> String {
+        format!(
+            "{}/upload/storage/v1/b/{}/o?uploadType={upload_type}&name={}",
+            self.endpoint,
+            percent_encode(&self.bucket),
+            percent_encode(gcs_path)
+        )
+    }
+
+    /// Returns an access token of the default service account, fetching a
+    /// new one from the GCE metadata server if the cached one is expiring.
+    /// The lock is held while fetching, so concurrent requests wait for a
+    /// single refresh instead of each asking the metadata server.
+    async fn get_access_token(&self) -> Result<String, Error> {
+        let mut access_token = self.access_token.lock().await;
+        if let Some(access_token) = access_token.as_ref() {
+            if access_token.expires_at > Instant::now() + ACCESS_TOKEN_REFRESH_MARGIN {
+                return Ok(access_token.token.clone());
+            }
+        }
+        let request = Request::get(format!(
+            "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+            self.metadata_host
+        ))
+        .header("Metadata-Flavor", "Google")
+        .body(Body::empty())
+        .map_err(|e| make_err!(Code::Internal, "Failed to build GCS token request : {e:?}"))?;
+        let response = self.metadata_client.request(request).await.map_err(|e| {
+            make_err!(
+                Code::Unavailable,
+                "Failed to fetch access token from metadata server : {e:?}"
+            )
+        })?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| make_err!(Code::Unavailable, "Failed to read access token : {e:?}"))?;
+        if status != StatusCode::OK {
+            return Err(make_err!(
+                Code::Unauthenticated,
+                "Metadata server returned {status} when fetching access token"
+            ));
+        }
+        let json: serde_json::Value = serde_json::from_slice(&body)
+            .map_err(|e| make_err!(Code::Internal, "Failed to parse access token : {e:?}"))?;
+        let token = json["access_token"]
+            .as_str()
+            .err_tip(|| "Expected access_token in metadata server response")?
+            .to_string();
+        let expires_in = json["expires_in"].as_u64().unwrap_or(0);
+        *access_token = Some(AccessToken {
+            token: token.clone(),
+            expires_at: Instant::now() + Duration::from_secs(expires_in),
+        });
+        Ok(token)
+    }
+
+    /// Sends a request to GCS, adding credentials unless anonymous access
+    /// is configured.
+    async fn send_request(
+        &self,
+        request: hyper::http::request::Builder,
+        body: Body,
+    ) -> Result<Response<Body>, Error> {
+        let request = if self.anonymous_access {
+            request
+        } else {
+            let token = self
+                .get_access_token()
+                .await
+                .err_tip(|| "In GcsStore::send_request")?;
+            request.header(AUTHORIZATION, format!("Bearer {token}"))
+        };
+        let request = request
+            .body(body)
+            .map_err(|e| make_err!(Code::Internal, "Failed to build GCS request : {e:?}"))?;
+        self.http_client
+            .request(request)
+            .await
+            .map_err(|e| make_err!(Code::Unavailable, "GCS request failed : {e:?}"))
+    }
+
+    async fn has(self: Pin<&Self>, key: &StoreKey<'_>) -> Result<Option<usize>, Error> {
+        let gcs_path = &self.make_gcs_path(key.borrow());
+        self.retrier
+            .retry(unfold((), move |state| async move {
+                let response = match self
+                    .send_request(Request::get(self.object_url(gcs_path)), Body::empty())
+                    .await
+                {
+                    Ok(response) => response,
+                    Err(e) => return Some((RetryResult::Retry(e), state)),
+                };
+                let status = response.status();
+                if status == StatusCode::NOT_FOUND {
+                    return Some((RetryResult::Ok(None), state));
+                }
+                if status != StatusCode::OK {
+                    return Some((retry_result_for_status(status, "reading metadata"), state));
+                }
+                let retry_result = match hyper::body::to_bytes(response.into_body()).await {
+                    Ok(body) => serde_json::from_slice::<serde_json::Value>(&body)
+                        .ok()
+                        .and_then(|json| json["size"].as_str()?.parse::<usize>().ok())
+                        .map_or_else(
+                            || {
+                                RetryResult::Err(make_err!(
+                                    Code::InvalidArgument,
+                                    "Expected a valid size in GCS object metadata"
+                                ))
+                            },
+                            |size| RetryResult::Ok(Some(size)),
+                        ),
+                    Err(e) => RetryResult::Retry(make_err!(
+                        Code::Unavailable,
+                        "Failed to read GCS object metadata : {e:?}"
+                    )),
+                };
+                Some((retry_result, state))
+            }))
+            .await
+    }
+
+    /// Uploads `data` in a single request.
+    async fn simple_upload(&self, gcs_path: &str, data: Bytes) -> Result<(), Error> {
+        self.retrier
+            .retry(unfold(data, move |data| async move {
+                let request = Request::post(self.upload_url(gcs_path, "media"))
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .header(CONTENT_LENGTH, data.len());
+                let retry_result = match self.send_request(request, Body::from(data.clone())).await
+                {
+                    Ok(response) if response.status().is_success() => RetryResult::Ok(()),
+                    Ok(response) => retry_result_for_status(response.status(), "uploading"),
+                    Err(e) => RetryResult::Retry(e),
+                };
+                Some((retry_result, data))
+            }))
+            .await
+    }
+
+    /// Starts a resumable upload session and returns its session URI.
+    async fn start_resumable_upload(
+        &self,
+        gcs_path: &str,
+        upload_size: UploadSizeInfo,
+    ) -> Result<String, Error> {
+        self.retrier
+            .retry(unfold((), move |state| async move {
+                let mut request =
+                    Request::post(self.upload_url(gcs_path, "resumable")).header(CONTENT_LENGTH, 0);
+                if let UploadSizeInfo::ExactSize(size) = upload_size {
+                    request = request.header("X-Upload-Content-Length", size);
+                }
+                let retry_result = match self.send_request(request, Body::empty()).await {
+                    Ok(response) if response.status().is_success() => response
+                        .headers()
+                        .get(LOCATION)
+                        .and_then(|location| location.to_str().ok())
+                        .map_or_else(
+                            || {
+                                RetryResult::Err(make_err!(
+                                    Code::Internal,
+                                    "Expected a session URI from GCS resumable upload"
+                                ))
+                            },
+                            |location| RetryResult::Ok(location.to_string()),
+                        ),
+                    Ok(response) => {
+                        retry_result_for_status(response.status(), "starting resumable upload")
+                    }
+                    Err(e) => RetryResult::Retry(e),
+                };
+                Some((retry_result, state))
+            }))
+            .await
+    }
+
+    /// Sends the data of `reader` to a resumable upload session, one chunk
+    /// of `resumable_chunk_size` bytes at a time.
+    async fn upload_chunks(
+        &self,
+        session_uri: &str,
+        reader: &mut DropCloserReadHalf,
+    ) -> Result<(), Error> {
+        let mut position: usize = 0;
+        loop {
+            let chunk = reader
+                .consume(Some(self.resumable_chunk_size))
+                .await
+                .err_tip(|| "Failed to read chunk in gcs_store")?;
+            // A full chunk may still be the last one, so peek for the EOF.
+            let is_last_chunk = chunk.len() < self.resumable_chunk_size
+                || reader
+                    .peek()
+                    .await
+                    .as_ref()
+                    .map_err(Clone::clone)
+                    .err_tip(|| "Failed to peek next chunk in gcs_store")?
+                    .is_empty();
+            let end = position + chunk.len();
+            // GCS may persist only part of a chunk, in which case the rest of
+            // it is sent again from the offset the server reports.
+            let mut committed = position;
+            loop {
+                let remaining = chunk.slice(committed - position..);
+                let content_range = match (remaining.is_empty(), is_last_chunk) {
+                    (true, _) => format!("bytes */{end}"),
+                    (false, true) => format!("bytes {committed}-{}/{end}", end - 1),
+                    (false, false) => format!("bytes {committed}-{}/*", end - 1),
+                };
+                let content_range = &content_range;
+                let persisted = self
+                    .retrier
+                    .retry(unfold(remaining, move |remaining| async move {
+                        let request = Request::put(session_uri)
+                            .header(CONTENT_LENGTH, remaining.len())
+                            .header(CONTENT_RANGE, content_range);
+                        let retry_result = match self
+                            .send_request(request, Body::from(remaining.clone()))
+                            .await
+                        {
+                            Ok(response) if response.status().is_success() => RetryResult::Ok(end),
+                            Ok(response) if response.status().as_u16() == RESUME_INCOMPLETE => {
+                                match persisted_size(&response) {
+                                    Ok(persisted) => RetryResult::Ok(persisted),
+                                    Err(err) => RetryResult::Err(err),
+                                }
+                            }
+                            Ok(response) => {
+                                retry_result_for_status(response.status(), "uploading chunk")
+                            }
+                            Err(e) => RetryResult::Retry(e),
+                        };
+                        Some((retry_result, remaining))
+                    }))
+                    .await?;
+                if persisted == end {
+                    break;
+                }
+                // Without progress the same bytes would be sent forever.
+                if persisted <= committed || persisted > end {
+                    return Err(make_err!(
+                        Code::Internal,
+                        "GCS reported {persisted} bytes persisted after sending bytes {committed}-{end} in gcs_store"
+                    ));
+                }
+                committed = persisted;
+            }
+            if is_last_chunk {
+                return Ok(());
+            }
+            position = end;
+        }
+    }
+
+    /// Cancels a resumable upload session so GCS discards its data. This is
+    /// a best effort, GCS also expires unfinished sessions after a week.
+    async fn cancel_resumable_upload(
+        &self,
+        gcs_path: &str,
+        session_uri: &str,
+    ) -> Result<(), Error> {
+        let result = match self
+            .send_request(Request::delete(session_uri), Body::empty())
+            .await
+        {
+            // GCS answers a cancelled session with the non-standard 499.
+            Ok(response) if response.status().as_u16() == 499 || response.status().is_success() => {
+                Ok(())
+            }
+            Ok(response) => Err(make_err!(
+                Code::Aborted,
+                "Failed to cancel resumable upload in GCS store, got status {}",
+                response.status()
+            )),
+            Err(e) => Err(e),
+        };
+        if let Err(err) = &result {
+            event!(
+                Level::WARN,
+                ?err,
+                gcs_path,
+                "Failed to cancel resumable upload in GCS store"
+            );
+        }
+        result
+    }
+
+    async fn get_object_range(
+        self: Pin<&Self>,
+        gcs_path: &str,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        end_read_byte: Option<usize>,
+    ) -> Result<(), Error> {
+        self.retrier
+            .retry(unfold(writer, move |writer| async move {
+                let start = offset + writer.get_bytes_written() as usize;
+                let range = end_read_byte.map_or_else(
+                    || format!("bytes={start}-"),
+                    |end| format!("bytes={start}-{end}"),
+                );
+                let request = Request::get(format!("{}?alt=media", self.object_url(gcs_path)))
+                    .header(RANGE, range);
+                let response = match self.send_request(request, Body::empty()).await {
+                    Ok(response) => response,
+                    Err(e) => return Some((RetryResult::Retry(e), writer)),
+                };
+                match response.status() {
+                    StatusCode::OK | StatusCode::PARTIAL_CONTENT => {}
+                    StatusCode::NOT_FOUND => {
+                        return Some((
+                            RetryResult::Err(make_err!(
+                                Code::NotFound,
+                                "No such key in GCS: {gcs_path}"
+                            )),
+                            writer,
+                        ));
+                    }
+                    status => {
+                        return Some((retry_result_for_status(status, "downloading"), writer));
+                    }
+                }
+
+                // Copy data from the GCS response to the writer stream.
+                let mut gcs_in_stream = response.into_body();
+                while let Some(maybe_bytes) = gcs_in_stream.next().await {
+                    match maybe_bytes {
+                        Ok(bytes) => {
+                            if bytes.is_empty() {
+                                continue;
+                            }
+                            if let Err(e) = writer.send(bytes).await {
+                                return Some((
+                                    RetryResult::Err(make_input_err!(
+                                        "Error sending bytes to consumer in GCS: {e}"
+                                    )),
+                                    writer,
+                                ));
+                            }
+                        }
+                        Err(e) => {
+                            return Some((
+                                RetryResult::Retry(make_err!(
+                                    Code::Unavailable,
+                                    "Bad bytestream element in GCS: {e}"
+                                )),
+                                writer,
+                            ));
+                        }
+                    }
+                }
+                if let Err(e) = writer.send_eof() {
+                    return Some((
+                        RetryResult::Err(make_input_err!(
+                            "Failed to send EOF to consumer in GCS: {e}"
+                        )),
+                        writer,
+                    ));
+                }
+                Some((RetryResult::Ok(()), writer))
+            }))
+            .await
+    }
+}
+
+#[async_trait]
+impl StoreDriver for GcsStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        keys.iter()
+            .zip(results.iter_mut())
+            .map(|(key, result)| async move {
+                if is_zero_digest(key.borrow()) {
+                    *result = Some(0);
+                    return Ok::<_, Error>(());
+                }
+                *result = self.has(key).await?;
+                Ok::<_, Error>(())
+            })
+            .collect::<FuturesUnordered<_>>()
+            .try_collect()
+            .await?;
+        Ok(())
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        digest: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        upload_size: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        let gcs_path = &self.make_gcs_path(digest.borrow());
+
+        // Small uploads of a known size are sent in a single request.
+        if let UploadSizeInfo::ExactSize(size) = upload_size {
+            if size < self.resumable_chunk_size {
+                let data = reader
+                    .consume(Some(size))
+                    .await
+                    .err_tip(|| "Failed to read data in gcs_store")?;
+                return self.simple_upload(gcs_path, data).await;
+            }
+        }
+
+        let session_uri = &self
+            .start_resumable_upload(gcs_path, upload_size)
+            .await
+            .err_tip(|| "In GcsStore::update")?;
+        match self.upload_chunks(session_uri, &mut reader).await {
+            Ok(()) => Ok(()),
+            Err(e) => Result::<(), _>::Err(e)
+                .merge(self.cancel_resumable_upload(gcs_path, session_uri).await),
+        }
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        if is_zero_digest(key.borrow()) || length == Some(0) {
+            writer
+                .send_eof()
+                .err_tip(|| "Failed to send zero EOF in GCS store get_part")?;
+            return Ok(());
+        }
+
+        let gcs_path = &self.make_gcs_path(key);
+        // GCS ranges are inclusive of the last byte.
+        let end_read_byte = length
+            .map_or(Some(None), |length| {
+                offset.checked_add(length - 1).map(Some)
+            })
+            .err_tip(|| "Integer overflow protection triggered")?;
+
+        self.get_object_range(gcs_path, writer, offset, end_read_byte)
+            .await
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(GcsStore);
@@ -0,0 +1,161 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use nativelink_error::{make_err, Code, Error, ResultExt};
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::metrics_utils::{
+    Collector, CollectorState, CounterWithTime, MetricsComponent, Registry,
+};
+use nativelink_util::store_trait::{
+    DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+use parking_lot::Mutex;
+use tracing::{event, Level};
+
+pub struct KeyLimitStore {
+    inner_store: Store,
+    max_keys: usize,
+    written_keys: Mutex<HashSet<StoreKey<'static>>>,
+    rejected_uploads: CounterWithTime,
+}
+
+impl KeyLimitStore {
+    pub fn new(config: &nativelink_config::stores::KeyLimitStore, inner_store: Store) -> Arc<Self> {
+        Arc::new(Self {
+            inner_store,
+            max_keys: config.max_keys,
+            written_keys: Mutex::new(HashSet::new()),
+            rejected_uploads: CounterWithTime::default(),
+        })
+    }
+
+    /// Records `key` as written. Returns `Ok(true)` if the key was not
+    /// written before, `Ok(false)` if it was, or an error if recording it
+    /// would exceed the key limit.
+    fn reserve_key(&self, key: &StoreKey<'static>) -> Result<bool, Error> {
+        let mut written_keys = self.written_keys.lock();
+        if written_keys.contains(key) {
+            return Ok(false);
+        }
+        if written_keys.len() >= self.max_keys {
+            self.rejected_uploads.inc();
+            event!(
+                Level::WARN,
+                ?key,
+                max_keys = self.max_keys,
+                "Rejecting upload of new key because the key limit was reached",
+            );
+            return Err(make_err!(
+                Code::ResourceExhausted,
+                "Key limit of {} reached, refusing to store new key {key:?} in KeyLimitStore",
+                self.max_keys
+            ));
+        }
+        written_keys.insert(key.clone());
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl StoreDriver for KeyLimitStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store.has_with_results(keys, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        let key = key.into_owned();
+        let is_new_key = self
+            .reserve_key(&key)
+            .err_tip(|| "In KeyLimitStore::update")?;
+        let result = self
+            .inner_store
+            .update(key.borrow(), reader, size_info)
+            .await;
+        if result.is_err() && is_new_key {
+            // The key never made it to the backend, so it should not use up
+            // any of the limit.
+            self.written_keys.lock().remove(&key);
+        }
+        result
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        self.inner_store.get_part(key, writer, offset, length).await
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().inner_store.list_digests(prefix)
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+
+    fn register_metrics(self: Arc<Self>, registry: &mut Registry) {
+        let inner_store_registry = registry.sub_registry_with_prefix("inner_store");
+        self.inner_store.register_metrics(inner_store_registry);
+        registry.register_collector(Box::new(Collector::new(&self)));
+    }
+}
+
+impl MetricsComponent for KeyLimitStore {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "max_keys",
+            &self.max_keys,
+            "Maximum number of distinct keys that may be written",
+        );
+        c.publish(
+            "written_keys",
+            &self.written_keys.lock().len(),
+            "Number of distinct keys written through this store",
+        );
+        c.publish(
+            "rejected_uploads",
+            &self.rejected_uploads,
+            "Uploads rejected because the key limit was reached",
+        );
+    }
+}
+
+default_health_status_indicator!(KeyLimitStore);
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 pub mod ac_utils;
+pub mod alignment_store;
 pub mod cas_utils;
 pub mod completeness_checking_store;
 pub mod compression_store;
@@ -21,13 +22,17 @@ pub mod default_store_factory;
 pub mod existence_cache_store;
 pub mod fast_slow_store;
 pub mod filesystem_store;
+pub mod gcs_store;
 pub mod grpc_store;
+pub mod key_limit_store;
 pub mod memory_store;
 pub mod noop_store;
+pub mod prefetch_store;
 pub mod redis_store;
 pub mod ref_store;
 pub mod s3_store;
 pub mod shard_store;
+pub mod single_flight_store;
 pub mod size_partitioning_store;
 pub mod store_manager;
 pub mod verify_store;
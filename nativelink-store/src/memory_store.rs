@@ -17,25 +17,53 @@ use std::fmt::Debug;
 use std::ops::Bound;
 use std::pin::Pin;
 use std::sync::{Arc, Weak};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use nativelink_error::{make_err, Code, Error, ResultExt};
+use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_util::background_spawn;
 use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::evicting_map::{EvictingMap, LenEntry};
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
-use nativelink_util::metrics_utils::{Collector, CollectorState, MetricsComponent, Registry};
+use nativelink_util::metrics_utils::{
+    Collector, CollectorState, Counter, MetricsComponent, Registry,
+};
 use nativelink_util::store_trait::{
     StoreDriver, StoreKey, StoreOptimizations, StoreSubscription, StoreSubscriptionItem,
     UploadSizeInfo,
 };
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
 use tokio::sync::watch;
+use tokio::time::{interval_at, Instant};
 use tracing::{event, Level};
 
 use crate::cas_utils::is_zero_digest;
 
+const DEFAULT_PSI_PATH: &str = "/proc/pressure/memory";
+const DEFAULT_PRESSURE_EVICT_PERCENT: u8 = 10;
+const DEFAULT_PRESSURE_CHECK_INTERVAL_S: u64 = 1;
+
+/// Parses the "some avg10" value out of the contents of a Linux PSI file,
+/// which looks like:
+/// ```text
+/// some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+/// full avg10=0.00 avg60=0.00 avg300=0.00 total=0
+/// ```
+fn parse_psi_some_avg10(contents: &str) -> Result<f32, Error> {
+    let some_line = contents
+        .lines()
+        .find(|line| line.starts_with("some "))
+        .err_tip(|| "Missing 'some' line in memory pressure file")?;
+    let avg10 = some_line
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))
+        .err_tip(|| format!("Missing avg10 in memory pressure line '{some_line}'"))?;
+    avg10
+        .parse::<f32>()
+        .map_err(|e| make_input_err!("Could not parse avg10 value '{avg10}' : {e:?}"))
+}
+
 #[derive(Clone)]
 pub struct BytesWrapper(Bytes);
 
@@ -126,22 +154,109 @@ impl StoreSubscriptionItem for MemoryStoreSubscriptionItem {
     }
 }
 
+/// `MemoryPressureEviction` config with the defaults filled in.
+struct MemoryPressureMonitor {
+    psi_path: String,
+    threshold: f32,
+    evict_percent: u8,
+    check_interval: Duration,
+}
+
 type SubscriptionSender = watch::Sender<Result<Arc<dyn StoreSubscriptionItem>, Error>>;
 pub struct MemoryStore {
     weak_self: Weak<Self>,
     evicting_map: EvictingMap<StoreKey<'static>, BytesWrapper, SystemTime>,
     subscriptions: RwLock<HashMap<StoreKey<'static>, SubscriptionSender>>,
+    memory_pressure_eviction: Option<MemoryPressureMonitor>,
+    memory_pressure_evicted_bytes: Counter,
 }
 
 impl MemoryStore {
     pub fn new(config: &nativelink_config::stores::MemoryStore) -> Arc<Self> {
         let empty_policy = nativelink_config::stores::EvictionPolicy::default();
         let eviction_policy = config.eviction_policy.as_ref().unwrap_or(&empty_policy);
-        Arc::new_cyclic(|weak_self| MemoryStore {
+        let memory_pressure_eviction =
+            config
+                .memory_pressure_eviction
+                .as_ref()
+                .map(|memory_pressure_eviction| {
+                    let check_interval_s = memory_pressure_eviction
+                        .check_interval_s
+                        .unwrap_or(DEFAULT_PRESSURE_CHECK_INTERVAL_S);
+                    MemoryPressureMonitor {
+                        psi_path: memory_pressure_eviction
+                            .psi_path
+                            .clone()
+                            .unwrap_or_else(|| DEFAULT_PSI_PATH.to_string()),
+                        threshold: memory_pressure_eviction.threshold,
+                        evict_percent: memory_pressure_eviction
+                            .evict_percent
+                            .unwrap_or(DEFAULT_PRESSURE_EVICT_PERCENT),
+                        check_interval: Duration::from_secs(check_interval_s),
+                    }
+                });
+        let store = Arc::new_cyclic(|weak_self| MemoryStore {
             weak_self: weak_self.clone(),
             evicting_map: EvictingMap::new(eviction_policy, SystemTime::now()),
             subscriptions: RwLock::new(HashMap::new()),
-        })
+            memory_pressure_eviction,
+            memory_pressure_evicted_bytes: Counter::default(),
+        });
+        if let Some(memory_pressure_eviction) = &store.memory_pressure_eviction {
+            Self::start_memory_pressure_monitor(
+                Arc::downgrade(&store),
+                memory_pressure_eviction.check_interval,
+            );
+        }
+        store
+    }
+
+    fn start_memory_pressure_monitor(weak_self: Weak<Self>, check_interval: Duration) {
+        background_spawn!("memory_store_memory_pressure_monitor", async move {
+            // The first check happens one interval after startup; a new
+            // store has nothing to evict yet.
+            let mut ticker = interval_at(Instant::now() + check_interval, check_interval);
+            loop {
+                ticker.tick().await;
+                // If we fail to upgrade, the store was dropped, so stop monitoring.
+                let Some(store) = weak_self.upgrade() else {
+                    return;
+                };
+                if let Err(err) = store.check_memory_pressure().await {
+                    event!(Level::WARN, ?err, "Failed to check memory pressure");
+                }
+            }
+        });
+    }
+
+    /// Reads the configured memory pressure signal and, if it is at or over
+    /// the threshold, evicts the least recently used entries. Returns the
+    /// number of bytes evicted.
+    pub async fn check_memory_pressure(&self) -> Result<u64, Error> {
+        let Some(memory_pressure_eviction) = &self.memory_pressure_eviction else {
+            return Ok(0);
+        };
+        let contents = nativelink_util::fs::read(&memory_pressure_eviction.psi_path)
+            .await
+            .err_tip(|| "Failed to read memory pressure file in MemoryStore")?;
+        let pressure = parse_psi_some_avg10(&String::from_utf8_lossy(&contents))
+            .err_tip(|| format!("In {}", memory_pressure_eviction.psi_path))?;
+        if pressure < memory_pressure_eviction.threshold {
+            return Ok(0);
+        }
+        let evicted_bytes = self
+            .evicting_map
+            .evict_lru_percent(memory_pressure_eviction.evict_percent)
+            .await;
+        event!(
+            Level::WARN,
+            pressure,
+            threshold = memory_pressure_eviction.threshold,
+            evicted_bytes,
+            "Evicted items from MemoryStore due to memory pressure",
+        );
+        self.memory_pressure_evicted_bytes.add(evicted_bytes);
+        Ok(evicted_bytes)
     }
 
     /// Returns the number of key-value pairs that are currently in the the cache.
@@ -212,19 +327,35 @@ impl StoreDriver for MemoryStore {
         self: Pin<&Self>,
         key: StoreKey<'_>,
         mut reader: DropCloserReadHalf,
-        _size_info: UploadSizeInfo,
+        size_info: UploadSizeInfo,
     ) -> Result<(), Error> {
         // Internally Bytes might hold a reference to more data than just our data. To prevent
         // this potential case, we make a full copy of our data for long-term storage.
-        let final_buffer = {
-            let buffer = reader
-                .consume(None)
-                .await
-                .err_tip(|| "Failed to collect all bytes from reader in memory_store::update")?;
-            let mut new_buffer = BytesMut::with_capacity(buffer.len());
-            new_buffer.extend_from_slice(&buffer[..]);
-            new_buffer.freeze()
-        };
+        let final_buffer =
+            if let UploadSizeInfo::ExactSize(size) = size_info {
+                // The size is known, so the copy is made into a buffer allocated once.
+                let buffer = reader.consume_with_size_hint(size).await.err_tip(|| {
+                    "Failed to collect all bytes from reader in memory_store::update"
+                })?;
+                if buffer.len() != size {
+                    return Err(make_input_err!(
+                        "Expected {size} bytes but received {} bytes in memory_store::update",
+                        buffer.len()
+                    ));
+                }
+                if buffer.len() == buffer.capacity() {
+                    buffer.freeze()
+                } else {
+                    Bytes::copy_from_slice(&buffer[..])
+                }
+            } else {
+                let buffer = reader.consume(None).await.err_tip(|| {
+                    "Failed to collect all bytes from reader in memory_store::update"
+                })?;
+                let mut new_buffer = BytesMut::with_capacity(buffer.len());
+                new_buffer.extend_from_slice(&buffer[..]);
+                new_buffer.freeze()
+            };
 
         self.evicting_map
             .insert(key.borrow().into_owned(), BytesWrapper(final_buffer))
@@ -239,6 +370,11 @@ impl StoreDriver for MemoryStore {
         Ok(())
     }
 
+    async fn remove(self: Pin<&Self>, key: StoreKey<'_>) -> Result<(), Error> {
+        self.evicting_map.remove(&key.into_owned()).await;
+        Ok(())
+    }
+
     async fn get_part(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -273,7 +409,10 @@ impl StoreDriver for MemoryStore {
     }
 
     fn optimized_for(&self, optimization: StoreOptimizations) -> bool {
-        optimization == StoreOptimizations::SubscribeChanges
+        matches!(
+            optimization,
+            StoreOptimizations::SubscribeChanges | StoreOptimizations::Remove
+        )
     }
 
     async fn subscribe(self: Arc<Self>, key: StoreKey<'_>) -> Box<dyn StoreSubscription> {
@@ -326,6 +465,11 @@ impl StoreDriver for MemoryStore {
 impl MetricsComponent for MemoryStore {
     fn gather_metrics(&self, c: &mut CollectorState) {
         c.publish("evicting_map", &self.evicting_map, "");
+        c.publish(
+            "memory_pressure_evicted_bytes",
+            &self.memory_pressure_evicted_bytes,
+            "Bytes evicted because of memory pressure",
+        );
     }
 }
 
@@ -0,0 +1,249 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use nativelink_error::{Error, ResultExt};
+use nativelink_util::background_spawn;
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::metrics_utils::{
+    Collector, CollectorState, CounterWithTime, MetricsComponent, Registry,
+};
+use nativelink_util::store_trait::{
+    DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+use parking_lot::Mutex;
+
+const DEFAULT_PREFETCH_WINDOW: usize = 1024 * 1024;
+const DEFAULT_MAX_PREFETCHED_KEYS: usize = 16;
+
+type PrefetchFuture = Shared<BoxFuture<'static, Result<Bytes, Error>>>;
+
+/// Data of a key that was (or is being) read ahead of the client.
+struct PrefetchedRange {
+    key: StoreKey<'static>,
+    /// Offset in the key of the first prefetched byte.
+    start: usize,
+    data: PrefetchFuture,
+}
+
+pub struct PrefetchStore {
+    inner_store: Store,
+    prefetch_window: usize,
+    max_prefetched_keys: usize,
+    /// At most one range per key, oldest first.
+    prefetched: Mutex<VecDeque<PrefetchedRange>>,
+    prefetches: CounterWithTime,
+    prefetch_hits: CounterWithTime,
+    prefetch_misses: CounterWithTime,
+}
+
+impl PrefetchStore {
+    pub fn new(config: &nativelink_config::stores::PrefetchStore, inner_store: Store) -> Arc<Self> {
+        let prefetch_window = if config.prefetch_window == 0 {
+            DEFAULT_PREFETCH_WINDOW
+        } else {
+            config.prefetch_window
+        };
+        let max_prefetched_keys = if config.max_prefetched_keys == 0 {
+            DEFAULT_MAX_PREFETCHED_KEYS
+        } else {
+            config.max_prefetched_keys
+        };
+        Arc::new(Self {
+            inner_store,
+            prefetch_window,
+            max_prefetched_keys,
+            prefetched: Mutex::new(VecDeque::new()),
+            prefetches: CounterWithTime::default(),
+            prefetch_hits: CounterWithTime::default(),
+            prefetch_misses: CounterWithTime::default(),
+        })
+    }
+
+    /// Returns the bytes in `offset..end` of `key` if they were prefetched.
+    /// Waits for the prefetch if it is still in flight.
+    async fn get_prefetched(&self, key: &StoreKey<'_>, offset: usize, end: usize) -> Option<Bytes> {
+        let (start, data_fut) = {
+            let prefetched = self.prefetched.lock();
+            let range = prefetched.iter().find(|range| {
+                &range.key == key
+                    && range.start <= offset
+                    && end <= range.start.saturating_add(self.prefetch_window)
+            })?;
+            (range.start, range.data.clone())
+        };
+        // Errors are ignored here, the read will be retried on the backend.
+        let data = data_fut.await.ok()?;
+        let relative_offset = offset - start;
+        if relative_offset > data.len() {
+            // Reading past the end of the data, let the backend decide
+            // how to handle it.
+            return None;
+        }
+        // If the data is shorter than requested the prefetch reached the end
+        // of the key, so the read is truncated just like the backend would.
+        Some(data.slice(relative_offset..(end - start).min(data.len())))
+    }
+
+    /// Starts reading `start..start + prefetch_window` of `key` unless the
+    /// range `start..start + length` is already prefetched or `start` is
+    /// known to be past the end of the data.
+    fn maybe_prefetch(&self, key: StoreKey<'static>, start: usize, length: usize) {
+        let mut prefetched = self.prefetched.lock();
+        if let Some(idx) = prefetched.iter().position(|range| range.key == key) {
+            let range = &prefetched[idx];
+            if range.start <= start
+                && start.saturating_add(length) <= range.start.saturating_add(self.prefetch_window)
+            {
+                return;
+            }
+            if let Some(Ok(data)) = range.data.peek() {
+                if data.len() < self.prefetch_window && start >= range.start + data.len() {
+                    // The previous prefetch reached the end of the data.
+                    return;
+                }
+            }
+            prefetched.remove(idx);
+        }
+        while prefetched.len() >= self.max_prefetched_keys {
+            prefetched.pop_front();
+        }
+        self.prefetches.inc();
+        let inner_store = self.inner_store.clone();
+        let prefetch_window = self.prefetch_window;
+        let prefetch_key = key.clone();
+        let data = background_spawn!("prefetch_store_prefetch", async move {
+            inner_store
+                .get_part_unchunked(prefetch_key, start, Some(prefetch_window))
+                .await
+        })
+        .map(|res| res.err_tip(|| "Prefetch task failed in PrefetchStore")?)
+        .boxed()
+        .shared();
+        prefetched.push_back(PrefetchedRange { key, start, data });
+    }
+}
+
+#[async_trait]
+impl StoreDriver for PrefetchStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store.has_with_results(keys, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        // Prefetched data of this key may no longer match what is stored.
+        self.prefetched.lock().retain(|range| range.key != key);
+        self.inner_store.update(key, reader, size_info).await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        // Only bounded reads have a "next" range worth reading ahead.
+        let Some(length) = length else {
+            return self.inner_store.get_part(key, writer, offset, None).await;
+        };
+        let key = key.into_owned();
+        let end = offset.saturating_add(length);
+        if let Some(data) = self.get_prefetched(&key, offset, end).await {
+            self.prefetch_hits.inc();
+            if !data.is_empty() {
+                writer
+                    .send(data)
+                    .await
+                    .err_tip(|| "Failed to send prefetched data in PrefetchStore")?;
+            }
+            writer
+                .send_eof()
+                .err_tip(|| "Failed to send EOF in PrefetchStore")?;
+        } else {
+            self.prefetch_misses.inc();
+            self.inner_store
+                .get_part(key.borrow(), writer, offset, Some(length))
+                .await?;
+        }
+        self.maybe_prefetch(key, end, length);
+        Ok(())
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().inner_store.list_digests(prefix)
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+
+    fn register_metrics(self: Arc<Self>, registry: &mut Registry) {
+        let inner_store_registry = registry.sub_registry_with_prefix("inner_store");
+        self.inner_store.register_metrics(inner_store_registry);
+        registry.register_collector(Box::new(Collector::new(&self)));
+    }
+}
+
+impl MetricsComponent for PrefetchStore {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "prefetch_window",
+            &self.prefetch_window,
+            "Number of bytes read ahead after each read",
+        );
+        c.publish(
+            "prefetches",
+            &self.prefetches,
+            "Number of prefetches started",
+        );
+        c.publish(
+            "prefetch_hits",
+            &self.prefetch_hits,
+            "Reads served from prefetched data",
+        );
+        c.publish(
+            "prefetch_misses",
+            &self.prefetch_misses,
+            "Bounded reads that were not prefetched and went to the backend",
+        );
+    }
+}
+
+default_health_status_indicator!(PrefetchStore);
@@ -17,11 +17,12 @@ use std::cell::{OnceCell, UnsafeCell};
 use std::fmt::Display;
 use std::pin::Pin;
 use std::sync::{Arc, Once};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::future::{ErrInto, FutureExt, Shared};
-use futures::stream::FuturesOrdered;
+use futures::stream::{unfold, FuturesOrdered};
 use futures::{Future, TryFutureExt, TryStreamExt};
 use nativelink_config::stores::RedisMode;
 use nativelink_error::{error_if, make_err, Code, Error, ResultExt};
@@ -29,11 +30,16 @@ use nativelink_util::background_spawn;
 use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::health_utils::{HealthRegistryBuilder, HealthStatus, HealthStatusIndicator};
 use nativelink_util::metrics_utils::{Collector, CollectorState, MetricsComponent, Registry};
+use nativelink_util::retry::{Retrier, RetryResult};
 use nativelink_util::store_trait::{StoreDriver, StoreKey, UploadSizeInfo};
+use rand::rngs::OsRng;
+use rand::Rng;
 use redis::aio::{ConnectionLike, ConnectionManager};
 use redis::cluster_async::ClusterConnection;
 use redis::{AsyncCommands, ToRedisArgs};
 use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{event, Level};
 
 use crate::cas_utils::is_zero_digest;
 
@@ -135,10 +141,18 @@ impl BackgroundConnection<ConnectionKind> {
     ///
     /// Some cursory checks are performed on the given connection info that can fail before a connection is established.
     /// Errors that occur during the connection process are surfaced when the connection is first used.
-    pub fn single<T: redis::IntoConnectionInfo>(params: T) -> Result<Self, Error> {
+    pub fn single<T: redis::IntoConnectionInfo>(
+        params: T,
+        retrier: Retrier,
+    ) -> Result<Self, Error> {
         let client = redis::Client::open(params).map_err(from_redis_err)?;
-        let init = async move { client.get_connection_manager().await };
-        Ok(Self::with_initializer(init))
+        Ok(Self::with_retrying_initializer(
+            move || {
+                let client = client.clone();
+                async move { client.get_connection_manager().await }
+            },
+            retrier,
+        ))
     }
 
     /// Connect to multiple Redis instances configured in cluster mode
@@ -149,10 +163,16 @@ impl BackgroundConnection<ConnectionKind> {
     /// Errors that occur during the connection are surfaced when the connection is first used.
     pub fn cluster<T: redis::IntoConnectionInfo>(
         params: impl IntoIterator<Item = T>,
+        retrier: Retrier,
     ) -> Result<Self, Error> {
         let client = redis::cluster::ClusterClient::new(params).map_err(from_redis_err)?;
-        let init = async move { client.get_async_connection().await };
-        Ok(Self::with_initializer(init))
+        Ok(Self::with_retrying_initializer(
+            move || {
+                let client = client.clone();
+                async move { client.get_async_connection().await }
+            },
+            retrier,
+        ))
     }
 }
 
@@ -176,6 +196,35 @@ impl<C: Clone + Send + 'static> BackgroundConnection<C> {
         }
     }
 
+    /// Same as [`BackgroundConnection::with_initializer`], but calls `init_fn` again according to `retrier`
+    /// if connecting fails, so a Redis instance that is briefly unavailable does not leave the connection
+    /// permanently errored.
+    pub fn with_retrying_initializer<F, Fut, T, E>(init_fn: F, retrier: Retrier) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+        C: From<T>,
+        T: Send + 'static,
+        Error: From<E>,
+        E: Send + 'static,
+    {
+        let init = async move {
+            retrier
+                .retry(unfold((), |()| async {
+                    match init_fn().await {
+                        Ok(connection) => Some((RetryResult::Ok(connection), ())),
+                        Err(err) => {
+                            let err = Error::from(err);
+                            event!(Level::WARN, ?err, "Failed to connect to Redis");
+                            Some((RetryResult::Retry(err), ()))
+                        }
+                    }
+                }))
+                .await
+        };
+        Self::with_initializer::<_, T, Error>(init)
+    }
+
     /// Retrieve the underlying connection. If the connection hasn't been established yet, the current task will
     /// wait until the connection has been made.
     ///
@@ -261,11 +310,24 @@ impl RedisStore<ConnectionKind> {
                 "At least one address must be specified to connect to Redis".to_string(),
             ));
         };
+        let jitter_amt = config.connection_retry.jitter;
+        let retrier = Retrier::new(
+            Arc::new(|duration| Box::pin(sleep(duration))),
+            Arc::new(move |delay: Duration| {
+                if jitter_amt == 0. {
+                    return delay;
+                }
+                let min = 1. - (jitter_amt / 2.);
+                let max = 1. + (jitter_amt / 2.);
+                delay.mul_f32(OsRng.gen_range(min..max))
+            }),
+            config.connection_retry.clone(),
+        );
         let connection =
             match config.mode {
                 RedisMode::Cluster => {
                     let addrs = config.addresses.iter().map(String::as_str);
-                    BackgroundConnection::cluster(addrs)?
+                    BackgroundConnection::cluster(addrs, retrier)?
                 }
                 RedisMode::Standard if config.addresses.len() > 1 => return Err(Error::new(
                     Code::InvalidArgument,
@@ -274,7 +336,7 @@ impl RedisStore<ConnectionKind> {
                 )),
                 RedisMode::Standard => {
                     let addr = config.addresses[0].as_str();
-                    BackgroundConnection::single(addr)?
+                    BackgroundConnection::single(addr, retrier)?
                 }
                 RedisMode::Sentinel => {
                     return Err(Error::new(
@@ -17,10 +17,13 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex, Weak};
 
 use async_trait::async_trait;
+use futures::stream;
 use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
 use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
 use tracing::{event, Level};
 
 use crate::store_manager::StoreManager;
@@ -128,6 +131,22 @@ impl StoreDriver for RefStore {
             .await
     }
 
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        self.get_store()?.get_tail(key, writer, length).await
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        match self.get_ref().get_store() {
+            Ok(store) => store.list_digests(prefix),
+            Err(err) => Box::pin(stream::once(async move { Err(err) })),
+        }
+    }
+
     fn inner_store(&self, key: Option<StoreKey>) -> &'_ dyn StoreDriver {
         match self.get_store() {
             Ok(store) => store.inner_store(key),
@@ -13,27 +13,30 @@
 // limitations under the License.
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{cmp, env};
 
 use async_trait::async_trait;
 use aws_config::default_provider::credentials;
 use aws_config::{AppName, BehaviorVersion};
 use aws_sdk_s3::config::Region;
+use aws_sdk_s3::operation::abort_multipart_upload::AbortMultipartUploadError;
 use aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadOutput;
 use aws_sdk_s3::operation::get_object::GetObjectError;
 use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::presigning::PresigningConfig;
 use aws_sdk_s3::primitives::{ByteStream, SdkBody};
 use aws_sdk_s3::types::builders::{CompletedMultipartUploadBuilder, CompletedPartBuilder};
 use aws_sdk_s3::Client;
 use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
 use bytes::Bytes;
 use futures::future::FusedFuture;
-use futures::stream::{unfold, FuturesUnordered};
+use futures::stream::{self, try_unfold, unfold, FuturesUnordered};
 use futures::{FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt};
 use http_body::{Frame, SizeHint};
 use hyper::client::connect::{Connected, Connection, HttpConnector};
@@ -41,22 +44,27 @@ use hyper::service::Service;
 use hyper::Uri;
 use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
 use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_util::background_spawn;
 use nativelink_util::buf_channel::{
     make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
 };
+use nativelink_util::common::DigestInfo;
 use nativelink_util::fs;
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
-use nativelink_util::retry::{Retrier, RetryResult};
-use nativelink_util::store_trait::{StoreDriver, StoreKey, UploadSizeInfo};
+use nativelink_util::retry::{Retrier, RetryBudget, RetryResult};
+use nativelink_util::store_trait::{
+    ConsistencyToken, DigestStream, StoreDriver, StoreKey, UploadSizeInfo,
+};
+use parking_lot::Mutex;
 use rand::rngs::OsRng;
 use rand::Rng;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, SemaphorePermit};
+use tokio::sync::{mpsc, Semaphore, SemaphorePermit};
 use tokio::time::sleep;
 use tracing::{event, Level};
 
-use crate::cas_utils::is_zero_digest;
+use crate::cas_utils::{digest_from_key, is_zero_digest};
 
 // S3 parts cannot be smaller than this number. See:
 // https://docs.aws.amazon.com/AmazonS3/latest/userguide/qfacts.html
@@ -127,6 +135,56 @@ impl<T: Connection + AsyncWrite + AsyncRead + Unpin> AsyncWrite for ConnectionWi
     }
 }
 
+type RetryBudgetMap = HashMap<(u64, u64), Arc<RetryBudget>>;
+
+/// Retry budgets shared by every S3 store with the same
+/// (`retry_budget_tokens`, `retry_budget_refill_per_s`) configuration.
+static SHARED_RETRY_BUDGETS: OnceLock<Mutex<RetryBudgetMap>> = OnceLock::new();
+
+fn make_retrier(
+    config: &nativelink_config::stores::S3Store,
+    jitter_fn: Arc<dyn Fn(Duration) -> Duration + Send + Sync>,
+) -> Retrier {
+    let retrier = Retrier::new(
+        Arc::new(|duration| Box::pin(sleep(duration))),
+        jitter_fn,
+        config.retry.to_owned(),
+    );
+    if config.retry_budget_tokens == 0 {
+        return retrier;
+    }
+    let budget_key = (config.retry_budget_tokens, config.retry_budget_refill_per_s);
+    let retry_budget = SHARED_RETRY_BUDGETS
+        .get_or_init(Mutex::default)
+        .lock()
+        .entry(budget_key)
+        .or_insert_with(|| Arc::new(RetryBudget::new(budget_key.0, budget_key.1)))
+        .clone();
+    retrier.with_retry_budget(retry_budget)
+}
+
+/// Upload part semaphore shared by every S3 store in the process, with the
+/// `global_max_concurrent_uploads` of the first store that set it.
+static GLOBAL_UPLOAD_SEMAPHORE: OnceLock<(usize, Arc<Semaphore>)> = OnceLock::new();
+
+fn make_upload_semaphore(config: &nativelink_config::stores::S3Store) -> Option<Arc<Semaphore>> {
+    let max_uploads = config.global_max_concurrent_uploads;
+    if max_uploads == 0 {
+        return None;
+    }
+    let (global_max_uploads, semaphore) = GLOBAL_UPLOAD_SEMAPHORE
+        .get_or_init(|| (max_uploads, Arc::new(Semaphore::new(max_uploads))));
+    if *global_max_uploads != max_uploads {
+        event!(
+            Level::WARN,
+            max_uploads,
+            global_max_uploads,
+            "S3 stores configure different global_max_concurrent_uploads, the first configured limit is used"
+        );
+    }
+    Some(semaphore.clone())
+}
+
 #[derive(Clone)]
 pub struct TlsConnector {
     connector: HttpsConnector<HttpConnector>,
@@ -155,11 +213,7 @@ impl TlsConnector {
 
         Self {
             connector,
-            retrier: Retrier::new(
-                Arc::new(|duration| Box::pin(sleep(duration))),
-                jitter_fn,
-                config.retry.to_owned(),
-            ),
+            retrier: make_retrier(config, jitter_fn),
         }
     }
 
@@ -239,6 +293,10 @@ pub struct S3Store {
     retrier: Retrier,
     max_retry_buffer_per_request: usize,
     multipart_max_concurrent_uploads: usize,
+    multipart_part_size: Option<usize>,
+    upload_semaphore: Option<Arc<Semaphore>>,
+    multipart_upload_semaphore: Option<Semaphore>,
+    head_before_get: bool,
 }
 
 impl S3Store {
@@ -274,7 +332,23 @@ impl S3Store {
             }
             aws_sdk_s3::Client::new(&config_builder.load().await)
         };
-        Self::new_with_client_and_jitter(config, s3_client, jitter_fn)
+        let store = Self::new_with_client_and_jitter(config, s3_client, jitter_fn)?;
+        if config.abort_orphaned_multipart_uploads_after_s != 0 {
+            let max_age = Duration::from_secs(config.abort_orphaned_multipart_uploads_after_s);
+            let store = store.clone();
+            // Failing to clean up is not fatal, the uploads are retried on
+            // the next start.
+            background_spawn!("s3_store_abort_orphaned_multipart_uploads", async move {
+                if let Err(err) = store.abort_orphaned_multipart_uploads(max_age).await {
+                    event!(
+                        Level::WARN,
+                        ?err,
+                        "Failed to abort orphaned multipart uploads in S3 store"
+                    );
+                }
+            });
+        }
+        Ok(store)
     }
 
     pub fn new_with_client_and_jitter(
@@ -282,24 +356,170 @@ impl S3Store {
         s3_client: Client,
         jitter_fn: Arc<dyn Fn(Duration) -> Duration + Send + Sync>,
     ) -> Result<Arc<Self>, Error> {
+        if let Some(multipart_part_size) = config.multipart_part_size {
+            if multipart_part_size > MAX_MULTIPART_SIZE {
+                return Err(make_input_err!(
+                    "multipart_part_size must not be larger than {MAX_MULTIPART_SIZE} in S3Store, got {multipart_part_size}"
+                ));
+            }
+        }
         Ok(Arc::new(Self {
             s3_client: Arc::new(s3_client),
             bucket: config.bucket.to_string(),
             key_prefix: config.key_prefix.as_ref().unwrap_or(&String::new()).clone(),
-            retrier: Retrier::new(
-                Arc::new(|duration| Box::pin(sleep(duration))),
-                jitter_fn,
-                config.retry.to_owned(),
-            ),
+            retrier: make_retrier(config, jitter_fn),
             max_retry_buffer_per_request: config
                 .max_retry_buffer_per_request
                 .unwrap_or(DEFAULT_MAX_RETRY_BUFFER_PER_REQUEST),
             multipart_max_concurrent_uploads: config
                 .multipart_max_concurrent_uploads
                 .map_or(DEFAULT_MULTIPART_MAX_CONCURRENT_UPLOADS, |v| v),
+            multipart_part_size: config.multipart_part_size,
+            upload_semaphore: make_upload_semaphore(config),
+            multipart_upload_semaphore: (config.max_concurrent_multipart_uploads != 0)
+                .then(|| Semaphore::new(config.max_concurrent_multipart_uploads)),
+            head_before_get: config.head_before_get,
         }))
     }
 
+    /// Aborts all multipart uploads under `key_prefix` that were started more
+    /// than `max_age` ago. Returns the number of uploads that were aborted.
+    /// An upload that fails to abort is logged and skipped; only a failure to
+    /// list the uploads is returned as an error.
+    pub async fn abort_orphaned_multipart_uploads(
+        &self,
+        max_age: Duration,
+    ) -> Result<usize, Error> {
+        let cutoff_secs = SystemTime::now()
+            .checked_sub(max_age)
+            .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |cutoff| cutoff.as_secs() as i64);
+        let mut aborted = 0;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let mut request = self
+                .s3_client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .set_key_marker(key_marker.take())
+                .set_upload_id_marker(upload_id_marker.take());
+            if !self.key_prefix.is_empty() {
+                request = request.prefix(&self.key_prefix);
+            }
+            let output = request.send().await.map_err(|e| {
+                make_err!(
+                    Code::Unavailable,
+                    "Failed to list multipart uploads in S3 store : {e:?}"
+                )
+            })?;
+            for upload in output.uploads() {
+                let (Some(key), Some(upload_id), Some(initiated)) =
+                    (upload.key(), upload.upload_id(), upload.initiated())
+                else {
+                    continue;
+                };
+                if initiated.secs() >= cutoff_secs {
+                    continue;
+                }
+                // The failure is already logged, keep going with the rest.
+                if self.abort_multipart_upload(key, upload_id).await.is_ok() {
+                    aborted += 1;
+                }
+            }
+            if output.is_truncated() != Some(true) {
+                break;
+            }
+            key_marker = output.next_key_marker().map(str::to_string);
+            upload_id_marker = output.next_upload_id_marker().map(str::to_string);
+        }
+        if aborted > 0 {
+            event!(
+                Level::INFO,
+                aborted,
+                "Aborted orphaned multipart uploads in S3 store"
+            );
+        }
+        Ok(aborted)
+    }
+
+    /// Returns a pre-signed URL that can be used to download `digest`
+    /// directly from S3 for `expiry`, without going through this server.
+    /// The URL is signed with the credentials of the store and is not
+    /// checked for the object to exist.
+    pub async fn presign_get(&self, digest: DigestInfo, expiry: Duration) -> Result<String, Error> {
+        let presigning_config = PresigningConfig::expires_in(expiry).map_err(|e| {
+            make_input_err!("Invalid expiry for pre-signed URL in S3 store : {e:?}")
+        })?;
+        let presigned_request = self
+            .s3_client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.make_s3_path(StoreKey::Digest(digest)))
+            .presigned(presigning_config)
+            .await
+            .map_err(|e| {
+                make_err!(
+                    Code::Internal,
+                    "Failed to pre-sign GET request in S3 store : {e:?}"
+                )
+            })?;
+        Ok(presigned_request.uri().to_string())
+    }
+
+    /// Aborts a multipart upload and logs its upload id. An upload that no
+    /// longer exists counts as aborted, so racing aborts of the same upload
+    /// do not fail.
+    async fn abort_multipart_upload(&self, s3_path: &str, upload_id: &str) -> Result<(), Error> {
+        let result = self
+            .s3_client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(s3_path)
+            .upload_id(upload_id)
+            .send()
+            .await;
+        match result {
+            Ok(_) => {
+                event!(
+                    Level::INFO,
+                    s3_path,
+                    upload_id,
+                    "Aborted multipart upload in S3 store"
+                );
+                Ok(())
+            }
+            Err(e)
+                if matches!(
+                    e.as_service_error(),
+                    Some(AbortMultipartUploadError::NoSuchUpload(_))
+                ) =>
+            {
+                event!(
+                    Level::INFO,
+                    s3_path,
+                    upload_id,
+                    "Multipart upload in S3 store was already aborted"
+                );
+                Ok(())
+            }
+            Err(e) => {
+                let err = make_err!(
+                    Code::Aborted,
+                    "Failed to abort multipart upload {upload_id} in S3 store : {e:?}"
+                );
+                event!(
+                    Level::WARN,
+                    ?err,
+                    s3_path,
+                    upload_id,
+                    "Multipart upload error"
+                );
+                Err(err)
+            }
+        }
+    }
+
     fn make_s3_path(&self, key: StoreKey<'_>) -> String {
         format!("{}{}", self.key_prefix, key.as_str(),)
     }
@@ -345,6 +565,105 @@ impl S3Store {
             }))
             .await
     }
+
+    /// Streams the object at `s3_path` into `writer`. `make_range` is called
+    /// with the number of bytes already written on every attempt to build the
+    /// http `Range` header, so retries resume where the last attempt stopped.
+    /// If `make_range` returns `None` the download cannot be cleanly resumed
+    /// from that point and no further attempt is made.
+    async fn get_object_range(
+        self: Pin<&Self>,
+        s3_path: &str,
+        writer: &mut DropCloserWriteHalf,
+        make_range: impl Fn(usize) -> Option<String> + Send + Sync,
+    ) -> Result<(), Error> {
+        let make_range = &make_range;
+        self.retrier
+            .retry(unfold(writer, move |writer| async move {
+                let bytes_written = writer.get_bytes_written();
+                let Some(range) = make_range(bytes_written as usize) else {
+                    return Some((
+                        RetryResult::Err(make_err!(
+                            Code::Unavailable,
+                            "Cannot resume download of {s3_path} in S3 after {bytes_written} bytes were sent"
+                        )),
+                        writer,
+                    ));
+                };
+                let result = self
+                    .s3_client
+                    .get_object()
+                    .bucket(&self.bucket)
+                    .key(s3_path)
+                    .range(range)
+                    .send()
+                    .await;
+
+                let mut s3_in_stream = match result {
+                    Ok(head_object_output) => head_object_output.body,
+                    Err(sdk_error) => match sdk_error.into_service_error() {
+                        GetObjectError::NoSuchKey(e) => {
+                            return Some((
+                                RetryResult::Err(make_err!(
+                                    Code::NotFound,
+                                    "No such key in S3: {e}"
+                                )),
+                                writer,
+                            ));
+                        }
+                        other => {
+                            return Some((
+                                RetryResult::Retry(make_err!(
+                                    Code::Unavailable,
+                                    "Unhandled GetObjectError in S3: {other:?}",
+                                )),
+                                writer,
+                            ));
+                        }
+                    },
+                };
+
+                // Copy data from s3 input stream to the writer stream.
+                while let Some(maybe_bytes) = s3_in_stream.next().await {
+                    match maybe_bytes {
+                        Ok(bytes) => {
+                            if bytes.is_empty() {
+                                // Ignore possible EOF. Different implimentations of S3 may or may not
+                                // send EOF this way.
+                                continue;
+                            }
+                            if let Err(e) = writer.send(bytes).await {
+                                return Some((
+                                    RetryResult::Err(make_input_err!(
+                                        "Error sending bytes to consumer in S3: {e}"
+                                    )),
+                                    writer,
+                                ));
+                            }
+                        }
+                        Err(e) => {
+                            return Some((
+                                RetryResult::Retry(make_err!(
+                                    Code::Unavailable,
+                                    "Bad bytestream element in S3: {e}"
+                                )),
+                                writer,
+                            ));
+                        }
+                    }
+                }
+                if let Err(e) = writer.send_eof() {
+                    return Some((
+                        RetryResult::Err(make_input_err!(
+                            "Failed to send EOF to consumer in S3: {e}"
+                        )),
+                        writer,
+                    ));
+                }
+                Some((RetryResult::Ok(()), writer))
+            }))
+            .await
+    }
 }
 
 #[async_trait]
@@ -371,6 +690,49 @@ impl StoreDriver for S3Store {
         Ok(())
     }
 
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        let s3_prefix = format!("{}{prefix}", self.key_prefix);
+        // The state is the continuation token of the next page, or `None`
+        // once the last page was read.
+        let pages = try_unfold(Some(None), move |continuation_token| {
+            let s3_prefix = s3_prefix.clone();
+            async move {
+                let Some(continuation_token) = continuation_token else {
+                    return Ok::<_, Error>(None);
+                };
+                let mut request = self
+                    .s3_client
+                    .list_objects_v2()
+                    .bucket(&self.bucket)
+                    .set_continuation_token(continuation_token);
+                if !s3_prefix.is_empty() {
+                    request = request.prefix(s3_prefix);
+                }
+                let output = request.send().await.map_err(|e| {
+                    make_err!(
+                        Code::Unavailable,
+                        "Failed to list objects in S3 store : {e:?}"
+                    )
+                })?;
+                let digests: Vec<Result<DigestInfo, Error>> = output
+                    .contents()
+                    .iter()
+                    .filter_map(|object| {
+                        let file_name = object.key()?.strip_prefix(&self.key_prefix)?;
+                        digest_from_key(file_name).ok().map(Ok)
+                    })
+                    .collect();
+                let next_continuation_token = if output.is_truncated() == Some(true) {
+                    Some(output.next_continuation_token().map(str::to_string))
+                } else {
+                    None
+                };
+                Ok(Some((stream::iter(digests), next_continuation_token)))
+            }
+        });
+        Box::pin(pages.try_flatten())
+    }
+
     async fn update(
         self: Pin<&Self>,
         digest: StoreKey<'_>,
@@ -456,6 +818,18 @@ impl StoreDriver for S3Store {
                 .await;
         }
 
+        // Held until the multipart upload is completed or aborted, to limit
+        // the number of multipart uploads in progress in this store.
+        let _multipart_permit = match &self.multipart_upload_semaphore {
+            Some(semaphore) => Some(semaphore.acquire().await.map_err(|e| {
+                make_err!(
+                    Code::Internal,
+                    "Multipart upload semaphore closed in s3_store: {e:?}"
+                )
+            })?),
+            None => None,
+        };
+
         let upload_id = &self
             .retrier
             .retry(unfold((), move |()| async move {
@@ -491,8 +865,13 @@ impl StoreDriver for S3Store {
 
         // S3 requires us to upload in parts if the size is greater than 5GB. The part size must be at least
         // 5mb (except last part) and can have up to 10,000 parts.
-        let bytes_per_upload_part =
-            (max_size / (MIN_MULTIPART_SIZE - 1)).clamp(MIN_MULTIPART_SIZE, MAX_MULTIPART_SIZE);
+        let bytes_per_upload_part = self
+            .multipart_part_size
+            .map_or_else(
+                || max_size / (MIN_MULTIPART_SIZE - 1),
+                |part_size| part_size.max(max_size.div_ceil(MAX_UPLOAD_PARTS)),
+            )
+            .clamp(MIN_MULTIPART_SIZE, MAX_MULTIPART_SIZE);
 
         let upload_parts = move || async move {
             // This will ensure we only have `multipart_max_concurrent_uploads` * `bytes_per_upload_part`
@@ -501,6 +880,7 @@ impl StoreDriver for S3Store {
 
             let read_stream_fut = async move {
                 let retrier = &Pin::get_ref(self).retrier;
+                let upload_semaphore = Pin::get_ref(self).upload_semaphore.as_deref();
                 // Note: Our break condition is when we reach EOF.
                 for part_number in 1..i32::MAX {
                     let write_buf = reader
@@ -511,43 +891,63 @@ impl StoreDriver for S3Store {
                         break; // Reached EOF.
                     }
 
-                    tx.send(retrier.retry(unfold(
-                        write_buf,
-                        move |write_buf| {
-                            async move {
-                                let retry_result = self
-                                    .s3_client
-                                    .upload_part()
-                                    .bucket(&self.bucket)
-                                    .key(s3_path)
-                                    .upload_id(upload_id)
-                                    .body(ByteStream::new(SdkBody::from(write_buf.clone())))
-                                    .part_number(part_number)
-                                    .send()
-                                    .await
-                                    .map_or_else(
-                                        |e| {
-                                            RetryResult::Retry(make_err!(
-                                                Code::Aborted,
-                                                "Failed to upload part {part_number} in S3 store: {e:?}"
-                                            ))
+                    tx.send(async move {
+                        retrier.retry(unfold(
+                            write_buf,
+                            move |write_buf| {
+                                async move {
+                                    // Held while the part is sent, to limit the number of
+                                    // concurrent uploads across all S3 stores. It is released
+                                    // before the retry backoff.
+                                    let _permit = match upload_semaphore {
+                                        Some(semaphore) => match semaphore.acquire().await {
+                                            Ok(permit) => Some(permit),
+                                            Err(e) => {
+                                                return Some((
+                                                    RetryResult::Err(make_err!(
+                                                        Code::Internal,
+                                                        "Upload semaphore closed in s3_store: {e:?}"
+                                                    )),
+                                                    write_buf,
+                                                ));
+                                            }
                                         },
-                                        |mut response| {
-                                            RetryResult::Ok(
-                                                CompletedPartBuilder::default()
-                                                    // Only set an entity tag if it exists. This saves
-                                                    // 13 bytes per part on the final request if it can
-                                                    // omit the `<ETAG><ETAG/>` string.
-                                                    .set_e_tag(response.e_tag.take())
-                                                    .part_number(part_number)
-                                                    .build(),
-                                            )
-                                        },
-                                    );
-                                Some((retry_result, write_buf))
+                                        None => None,
+                                    };
+                                    let retry_result = self
+                                        .s3_client
+                                        .upload_part()
+                                        .bucket(&self.bucket)
+                                        .key(s3_path)
+                                        .upload_id(upload_id)
+                                        .body(ByteStream::new(SdkBody::from(write_buf.clone())))
+                                        .part_number(part_number)
+                                        .send()
+                                        .await
+                                        .map_or_else(
+                                            |e| {
+                                                RetryResult::Retry(make_err!(
+                                                    Code::Aborted,
+                                                    "Failed to upload part {part_number} in S3 store: {e:?}"
+                                                ))
+                                            },
+                                            |mut response| {
+                                                RetryResult::Ok(
+                                                    CompletedPartBuilder::default()
+                                                        // Only set an entity tag if it exists. This saves
+                                                        // 13 bytes per part on the final request if it can
+                                                        // omit the `<ETAG><ETAG/>` string.
+                                                        .set_e_tag(response.e_tag.take())
+                                                        .part_number(part_number)
+                                                        .build(),
+                                                )
+                                            },
+                                        );
+                                    Some((retry_result, write_buf))
+                                }
                             }
-                        }
-                    ))).await.map_err(|_| make_err!(Code::Internal, "Failed to send part to channel in s3_store"))?;
+                        )).await
+                    }).await.map_err(|_| make_err!(Code::Internal, "Failed to send part to channel in s3_store"))?;
                 }
                 Result::<_, Error>::Ok(())
             }.fuse();
@@ -607,31 +1007,53 @@ impl StoreDriver for S3Store {
         // If we fail attempt to abort the multipart upload (cleanup).
         upload_parts()
             .or_else(move |e| async move {
-                Result::<(), _>::Err(e).merge(
-                    // Note: We don't retry here because this is just a best attempt.
-                    self.s3_client
-                        .abort_multipart_upload()
-                        .bucket(&self.bucket)
-                        .key(s3_path)
-                        .upload_id(upload_id)
-                        .send()
-                        .await
-                        .map_or_else(
-                            |e| {
-                                let err = make_err!(
-                                    Code::Aborted,
-                                    "Failed to abort multipart upload in S3 store : {e:?}"
-                                );
-                                event!(Level::INFO, ?err, "Multipart upload error");
-                                Err(err)
-                            },
-                            |_| Ok(()),
-                        ),
-                )
+                // Note: We don't retry here because this is just a best attempt.
+                Result::<(), _>::Err(e).merge(self.abort_multipart_upload(s3_path, upload_id).await)
             })
             .await
     }
 
+    /// S3 compatible backends are not all strongly consistent, so the token
+    /// holds the number of bytes written. Reads with the token wait until
+    /// the object is reported with that size.
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        upload_size: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        let (mut tx, rx) = make_buf_channel_pair();
+        let (bind_res, update_res) =
+            tokio::join!(tx.bind(&mut reader), self.update(key, rx, upload_size));
+        bind_res
+            .merge(update_res)
+            .err_tip(|| "In S3Store::update_with_consistency_token")?;
+        Ok(Some(ConsistencyToken::new(reader.get_bytes_received())))
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        let expected_size = usize::try_from(token.value())
+            .err_tip(|| "Could not convert token to size in S3Store::wait_for_consistency")?;
+        let key = &key;
+        self.retrier
+            .retry(unfold((), move |()| async move {
+                let retry_result = match self.has(key).await {
+                    Ok(Some(size)) if size == expected_size => RetryResult::Ok(()),
+                    Ok(size) => RetryResult::Retry(make_err!(
+                        Code::Unavailable,
+                        "Write of {key:?} is not visible in S3 yet, expected {expected_size} bytes but found {size:?}"
+                    )),
+                    Err(err) => RetryResult::Err(err),
+                };
+                Some((retry_result, ()))
+            }))
+            .await
+    }
+
     async fn get_part(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -646,89 +1068,83 @@ impl StoreDriver for S3Store {
             return Ok(());
         }
 
-        let s3_path = &self.make_s3_path(key);
-        let end_read_byte = length
+        let s3_path = &self.make_s3_path(key.borrow());
+        // The end is exclusive.
+        let mut end = length
             .map_or(Some(None), |length| Some(offset.checked_add(length)))
             .err_tip(|| "Integer overflow protection triggered")?;
 
-        self.retrier
-            .retry(unfold(writer, move |writer| async move {
-                let result = self
-                    .s3_client
-                    .get_object()
-                    .bucket(&self.bucket)
-                    .key(s3_path)
-                    .range(format!(
-                        "bytes={}-{}",
-                        offset + writer.get_bytes_written() as usize,
-                        end_read_byte.map_or_else(String::new, |v| v.to_string())
-                    ))
-                    .send()
-                    .await;
+        if self.head_before_get {
+            let size = self
+                .has(&key)
+                .await
+                .err_tip(|| "Failed to get object size in S3 store get_part")?
+                .ok_or_else(|| make_err!(Code::NotFound, "No such key in S3: {s3_path}"))?;
+            if offset > size {
+                return Err(make_err!(
+                    Code::OutOfRange,
+                    "Offset {offset} is past the end of {s3_path} of {size} bytes in S3"
+                ));
+            }
+            end = Some(end.map_or(size, |end| end.min(size)));
+        }
+        // S3 rejects ranges that contain no bytes, so we only check for
+        // existence in that case.
+        if end == Some(offset) {
+            if !self.head_before_get && self.has(&key).await?.is_none() {
+                return Err(make_err!(Code::NotFound, "No such key in S3: {s3_path}"));
+            }
+            return writer
+                .send_eof()
+                .err_tip(|| "Failed to send EOF in S3 store get_part");
+        }
+        // The end of http ranges is inclusive.
+        let end_read_byte = end.map(|end| end - 1);
 
-                let mut s3_in_stream = match result {
-                    Ok(head_object_output) => head_object_output.body,
-                    Err(sdk_error) => match sdk_error.into_service_error() {
-                        GetObjectError::NoSuchKey(e) => {
-                            return Some((
-                                RetryResult::Err(make_err!(
-                                    Code::NotFound,
-                                    "No such key in S3: {e}"
-                                )),
-                                writer,
-                            ));
-                        }
-                        other => {
-                            return Some((
-                                RetryResult::Retry(make_err!(
-                                    Code::Unavailable,
-                                    "Unhandled GetObjectError in S3: {other:?}",
-                                )),
-                                writer,
-                            ));
-                        }
-                    },
-                };
+        self.get_object_range(s3_path, writer, |bytes_written| {
+            Some(format!(
+                "bytes={}-{}",
+                offset + bytes_written,
+                end_read_byte.map_or_else(String::new, |v| v.to_string())
+            ))
+        })
+        .await
+    }
 
-                // Copy data from s3 input stream to the writer stream.
-                while let Some(maybe_bytes) = s3_in_stream.next().await {
-                    match maybe_bytes {
-                        Ok(bytes) => {
-                            if bytes.is_empty() {
-                                // Ignore possible EOF. Different implimentations of S3 may or may not
-                                // send EOF this way.
-                                continue;
-                            }
-                            if let Err(e) = writer.send(bytes).await {
-                                return Some((
-                                    RetryResult::Err(make_input_err!(
-                                        "Error sending bytes to consumer in S3: {e}"
-                                    )),
-                                    writer,
-                                ));
-                            }
-                        }
-                        Err(e) => {
-                            return Some((
-                                RetryResult::Retry(make_input_err!(
-                                    "Bad bytestream element in S3: {e}"
-                                )),
-                                writer,
-                            ));
-                        }
-                    }
-                }
-                if let Err(e) = writer.send_eof() {
-                    return Some((
-                        RetryResult::Err(make_input_err!(
-                            "Failed to send EOF to consumer in S3: {e}"
-                        )),
-                        writer,
-                    ));
-                }
-                Some((RetryResult::Ok(()), writer))
-            }))
-            .await
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        if is_zero_digest(key.borrow()) {
+            return writer
+                .send_eof()
+                .err_tip(|| "Failed to send zero EOF in S3 store get_tail");
+        }
+        // S3 does not allow a suffix range of zero bytes, so we only
+        // check for existence in that case.
+        if length == 0 {
+            if self.has(&key).await?.is_none() {
+                return Err(make_err!(
+                    Code::NotFound,
+                    "No such key in S3: {}",
+                    key.as_str()
+                ));
+            }
+            return writer
+                .send_eof()
+                .err_tip(|| "Failed to send EOF in S3 store get_tail");
+        }
+
+        let s3_path = &self.make_s3_path(key);
+        // Note: A negative range will return the last `length` bytes, or the
+        // whole object if it is smaller than `length`. Since we don't know
+        // which, a partially streamed tail cannot be resumed.
+        self.get_object_range(s3_path, writer, |bytes_written| {
+            (bytes_written == 0).then(|| format!("bytes=-{length}"))
+        })
+        .await
     }
 
     fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
@@ -18,12 +18,14 @@ use std::pin::Pin;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use futures::stream::{FuturesUnordered, TryStreamExt};
+use futures::stream::{self, FuturesUnordered, StreamExt, TryStreamExt};
 use nativelink_error::{error_if, Error, ResultExt};
 use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
 use nativelink_util::metrics_utils::Registry;
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    ConsistencyToken, DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
 
 pub struct ShardStore {
     // The weights will always be in ascending order a specific store is choosen based on the
@@ -191,6 +193,32 @@ impl StoreDriver for ShardStore {
             .err_tip(|| "In ShardStore::update()")
     }
 
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        let store = self.get_store(&key);
+        store
+            .update_with_consistency_token(key, reader, size_info)
+            .await
+            .err_tip(|| "In ShardStore::update_with_consistency_token()")
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        let store = self.get_store(&key);
+        store
+            .as_store_driver_pin()
+            .wait_for_consistency(key, token)
+            .await
+            .err_tip(|| "In ShardStore::wait_for_consistency()")
+    }
+
     async fn get_part(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -205,6 +233,26 @@ impl StoreDriver for ShardStore {
             .err_tip(|| "In ShardStore::get_part()")
     }
 
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        let store = self.get_store(&key);
+        store
+            .get_tail(key, writer, length)
+            .await
+            .err_tip(|| "In ShardStore::get_tail()")
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        Box::pin(
+            stream::iter(&self.get_ref().weights_and_stores)
+                .flat_map(move |(_, store)| store.list_digests(prefix)),
+        )
+    }
+
     fn inner_store(&self, key: Option<StoreKey>) -> &'_ dyn StoreDriver {
         let Some(key) = key else {
             return self;
@@ -0,0 +1,500 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::join;
+use nativelink_error::{Error, ResultExt};
+use nativelink_util::background_spawn;
+use nativelink_util::buf_channel::{
+    make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
+};
+use nativelink_util::common::DigestInfo;
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::metrics_utils::{
+    Collector, CollectorState, CounterWithTime, MetricsComponent, Registry,
+};
+use nativelink_util::store_trait::{
+    ConsistencyToken, DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+/// Result of an in flight upload. `None` until the upload finishes.
+type UploadResult = Option<Result<Option<ConsistencyToken>, Error>>;
+
+enum UploadRole {
+    /// This upload is the first for the digest and will write to the backend.
+    Leader(watch::Sender<UploadResult>),
+    /// Another upload of the same digest is in flight, wait for its result.
+    Follower(watch::Receiver<UploadResult>),
+}
+
+/// Result of an in flight read of a whole blob. `None` until the read
+/// finishes. `Some(Ok(None))` if the leading read did not keep the data,
+/// because nobody had joined it yet when it streamed part of the blob.
+type ReadResult = Option<Result<Option<Bytes>, Error>>;
+
+/// Reads of blobs larger than this are never collapsed, because the whole
+/// blob is held in memory to be shared with all readers.
+const MAX_COALESCED_READ_SIZE: i64 = 16 * 1024 * 1024;
+
+/// Maximum number of bytes held by finished reads kept for the coalescing
+/// window. Reads finishing while this is exceeded are not kept.
+const MAX_RETAINED_READ_BYTES: u64 = 256 * 1024 * 1024;
+
+type InFlightReads = Arc<Mutex<InFlightReadsState>>;
+
+#[derive(Default)]
+struct InFlightReadsState {
+    reads: HashMap<DigestInfo, InFlightRead>,
+    /// Sum of `retained_bytes` of all reads.
+    retained_bytes: u64,
+}
+
+struct InFlightRead {
+    rx: watch::Receiver<ReadResult>,
+    /// Reads arriving before this point are served from `rx`, even if the
+    /// read already finished.
+    window_end: Instant,
+    /// Size of the data kept for the coalescing window after the read
+    /// finished.
+    retained_bytes: u64,
+}
+
+enum ReadRole {
+    /// This read is the first for the digest and will read from the backend.
+    Leader(InFlightReadGuard),
+    /// Another read of the same digest is in flight or finished within the
+    /// coalescing window, use its result.
+    Follower(watch::Receiver<ReadResult>),
+}
+
+/// Removes the in flight read entry once the leading read fails or is
+/// dropped, or once the coalescing window is over if the read succeeded.
+struct InFlightReadGuard {
+    in_flight_reads: InFlightReads,
+    digest: DigestInfo,
+    tx: watch::Sender<ReadResult>,
+    window_end: Instant,
+}
+
+impl Drop for InFlightReadGuard {
+    fn drop(&mut self) {
+        let remaining = self.window_end.saturating_duration_since(Instant::now());
+        let rx = self.tx.subscribe();
+        // Only complete blobs are kept for later reads. A size that does not
+        // match the digest means the key does not address its content (for
+        // example an action cache entry), which may change at any time.
+        let retained_bytes = match self.tx.borrow().as_ref() {
+            Some(Ok(Some(data))) if data.len() as i64 == self.digest.size_bytes => {
+                Some(data.len() as u64)
+            }
+            _ => None,
+        };
+        let retained = retained_bytes.is_some_and(|retained_bytes| {
+            !remaining.is_zero()
+                && retain_in_flight_read(&self.in_flight_reads, self.digest, &rx, retained_bytes)
+        });
+        if !retained {
+            remove_in_flight_read(&self.in_flight_reads, self.digest, &rx);
+            return;
+        }
+        let in_flight_reads = self.in_flight_reads.clone();
+        let digest = self.digest;
+        background_spawn!("single_flight_store_read_window", async move {
+            tokio::time::sleep(remaining).await;
+            remove_in_flight_read(&in_flight_reads, digest, &rx);
+        });
+    }
+}
+
+/// Accounts the data of the read of `digest` as retained, unless that
+/// would exceed `MAX_RETAINED_READ_BYTES`. Returns whether it was retained.
+fn retain_in_flight_read(
+    in_flight_reads: &Mutex<InFlightReadsState>,
+    digest: DigestInfo,
+    rx: &watch::Receiver<ReadResult>,
+    retained_bytes: u64,
+) -> bool {
+    let mut state = in_flight_reads.lock();
+    if state.retained_bytes + retained_bytes > MAX_RETAINED_READ_BYTES {
+        return false;
+    }
+    let Some(read) = state.reads.get_mut(&digest) else {
+        return false;
+    };
+    if !read.rx.same_channel(rx) {
+        return false;
+    }
+    read.retained_bytes = retained_bytes;
+    state.retained_bytes += retained_bytes;
+    true
+}
+
+/// Removes the read of `digest` unless it was already replaced by a newer read.
+fn remove_in_flight_read(
+    in_flight_reads: &Mutex<InFlightReadsState>,
+    digest: DigestInfo,
+    rx: &watch::Receiver<ReadResult>,
+) {
+    let mut state = in_flight_reads.lock();
+    if let Entry::Occupied(entry) = state.reads.entry(digest) {
+        if entry.get().rx.same_channel(rx) {
+            let read = entry.remove();
+            state.retained_bytes -= read.retained_bytes;
+        }
+    }
+}
+
+/// Sends the requested range of `data` followed by an EOF.
+async fn send_range(
+    writer: &mut DropCloserWriteHalf,
+    data: Bytes,
+    offset: usize,
+    length: Option<usize>,
+) -> Result<(), Error> {
+    let start = offset.min(data.len());
+    let end = length.map_or(data.len(), |length| {
+        start.saturating_add(length).min(data.len())
+    });
+    if start != end {
+        writer
+            .send(data.slice(start..end))
+            .await
+            .err_tip(|| "Failed to send data in SingleFlightStore::get_part")?;
+    }
+    writer
+        .send_eof()
+        .err_tip(|| "Failed to send EOF in SingleFlightStore::get_part")
+}
+
+/// Joins `chunks` into a single buffer, without copying if there is only one.
+fn concat_chunks(chunks: Vec<Bytes>) -> Bytes {
+    if chunks.len() == 1 {
+        return chunks.into_iter().next().unwrap_or_default();
+    }
+    let mut data = BytesMut::with_capacity(chunks.iter().map(Bytes::len).sum());
+    for chunk in chunks {
+        data.extend_from_slice(&chunk);
+    }
+    data.freeze()
+}
+
+/// Removes the in flight entry once the leading upload finishes or is dropped.
+struct InFlightGuard<'a> {
+    store: &'a SingleFlightStore,
+    digest: DigestInfo,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.store.in_flight_uploads.lock().remove(&self.digest);
+    }
+}
+
+pub struct SingleFlightStore {
+    inner_store: Store,
+    in_flight_uploads: Mutex<HashMap<DigestInfo, watch::Receiver<UploadResult>>>,
+    in_flight_reads: InFlightReads,
+    read_coalescing_window: Duration,
+    deduplicated_uploads: CounterWithTime,
+    coalesced_reads: CounterWithTime,
+}
+
+impl SingleFlightStore {
+    pub fn new(
+        config: &nativelink_config::stores::SingleFlightStore,
+        inner_store: Store,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            inner_store,
+            in_flight_uploads: Mutex::new(HashMap::new()),
+            in_flight_reads: Arc::new(Mutex::new(InFlightReadsState::default())),
+            read_coalescing_window: Duration::from_millis(config.read_coalescing_window_millis),
+            deduplicated_uploads: CounterWithTime::default(),
+            coalesced_reads: CounterWithTime::default(),
+        })
+    }
+
+    fn read_role(&self, digest: DigestInfo) -> ReadRole {
+        let mut state = self.in_flight_reads.lock();
+        if let Some(read) = state.reads.get(&digest) {
+            if read.rx.borrow().is_none() || Instant::now() < read.window_end {
+                return ReadRole::Follower(read.rx.clone());
+            }
+        }
+        let (tx, rx) = watch::channel(None);
+        let window_end = Instant::now() + self.read_coalescing_window;
+        state.reads.insert(
+            digest,
+            InFlightRead {
+                rx,
+                window_end,
+                retained_bytes: 0,
+            },
+        );
+        ReadRole::Leader(InFlightReadGuard {
+            in_flight_reads: self.in_flight_reads.clone(),
+            digest,
+            tx,
+            window_end,
+        })
+    }
+
+    /// Streams the whole blob from the backend to `writer`. The data is only
+    /// kept for other reads once a follower joined or if there is a
+    /// coalescing window, so a lone read is not buffered.
+    async fn lead_read(
+        &self,
+        guard: InFlightReadGuard,
+        writer: &mut DropCloserWriteHalf,
+    ) -> Result<(), Error> {
+        let digest = guard.digest;
+        let retain_all = !self.read_coalescing_window.is_zero();
+        let (tx, mut rx) = make_buf_channel_pair();
+        let read_fut = self.inner_store.get_part(digest, tx, 0, None);
+        let forward_fut = async move {
+            // `None` once a chunk was sent that nobody kept.
+            let mut chunks = Some(Vec::new());
+            loop {
+                let chunk = rx
+                    .recv()
+                    .await
+                    .err_tip(|| "Failed to read data in SingleFlightStore::get_part")?;
+                if chunk.is_empty() {
+                    break;
+                }
+                if let Some(kept) = chunks.as_mut() {
+                    // The entry in `in_flight_reads` holds one receiver, any
+                    // other receiver belongs to a follower.
+                    if retain_all || guard.tx.receiver_count() > 1 {
+                        kept.push(chunk.clone());
+                    } else {
+                        chunks = None;
+                    }
+                }
+                writer
+                    .send(chunk)
+                    .await
+                    .err_tip(|| "Failed to send data in SingleFlightStore::get_part")?;
+            }
+            writer
+                .send_eof()
+                .err_tip(|| "Failed to send EOF in SingleFlightStore::get_part")?;
+            Ok::<_, Error>((guard, chunks.map(concat_chunks)))
+        };
+        let (read_result, forward_result) = join!(read_fut, forward_fut);
+        match (read_result, forward_result) {
+            (Ok(()), Ok((guard, data))) => {
+                guard.tx.send_replace(Some(Ok(data)));
+                Ok(())
+            }
+            // If only our writer failed, the followers start over.
+            (Ok(()), Err(e)) => Err(e),
+            (Err(e), forward_result) => {
+                // The guard was dropped with `forward_fut` in this case, so
+                // the followers start over as well.
+                Err::<(), _>(e).merge(forward_result.map(|_| ()))
+            }
+        }
+    }
+
+    fn upload_role(&self, digest: DigestInfo) -> UploadRole {
+        match self.in_flight_uploads.lock().entry(digest) {
+            Entry::Occupied(entry) => UploadRole::Follower(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let (tx, rx) = watch::channel(None);
+                entry.insert(rx);
+                UploadRole::Leader(tx)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StoreDriver for SingleFlightStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store.has_with_results(keys, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.update_with_consistency_token(key, reader, size_info)
+            .await
+            .map(|_| ())
+    }
+
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        // Only digests identify their content, other keys are passed through.
+        let StoreKey::Digest(digest) = key else {
+            return self
+                .inner_store
+                .update_with_consistency_token(key, reader, size_info)
+                .await;
+        };
+        // If the leading upload fails or is dropped, its followers still
+        // hold their own data, so one of them becomes the new leader.
+        loop {
+            match self.upload_role(digest) {
+                UploadRole::Leader(tx) => {
+                    let guard = InFlightGuard {
+                        store: self.get_ref(),
+                        digest,
+                    };
+                    let result = self
+                        .inner_store
+                        .update_with_consistency_token(digest, reader, size_info)
+                        .await;
+                    // Uploads arriving after this point start a new upload.
+                    drop(guard);
+                    tx.send_replace(Some(result.clone()));
+                    return result;
+                }
+                UploadRole::Follower(mut rx) => {
+                    let result = match rx.wait_for(Option::is_some).await {
+                        Ok(result) => result.clone(),
+                        Err(_) => continue,
+                    };
+                    let Some(Ok(token)) = result else {
+                        continue;
+                    };
+                    // We need to drain the reader to avoid the writer complaining that we dropped
+                    // the connection prematurely.
+                    reader
+                        .drain()
+                        .await
+                        .err_tip(|| "In SingleFlightStore::update")?;
+                    self.deduplicated_uploads.inc();
+                    return Ok(token);
+                }
+            }
+        }
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        self.inner_store
+            .as_store_driver_pin()
+            .wait_for_consistency(key, token)
+            .await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        // Only reads of whole blobs small enough to be held in memory are
+        // collapsed, other reads are passed through.
+        let digest = match key {
+            StoreKey::Digest(digest)
+                if offset == 0
+                    && length.map_or(true, |length| length as i64 >= digest.size_bytes)
+                    && digest.size_bytes <= MAX_COALESCED_READ_SIZE =>
+            {
+                digest
+            }
+            key => return self.inner_store.get_part(key, writer, offset, length).await,
+        };
+        // If the leading read is dropped before it finishes, its followers
+        // start over and one of them becomes the new leader.
+        loop {
+            match self.read_role(digest) {
+                ReadRole::Leader(guard) => return self.lead_read(guard, writer).await,
+                ReadRole::Follower(mut rx) => {
+                    let result = match rx.wait_for(Option::is_some).await {
+                        Ok(result) => result.clone(),
+                        Err(_) => continue,
+                    };
+                    let result = result
+                        .err_tip(|| "Read result missing in SingleFlightStore::get_part")?
+                        .err_tip(|| "Concurrent read of same digest failed in SingleFlightStore")?;
+                    let Some(data) = result else {
+                        // The leader did not keep the data, read it ourselves.
+                        return self
+                            .inner_store
+                            .get_part(digest, writer, offset, length)
+                            .await;
+                    };
+                    self.coalesced_reads.inc();
+                    return send_range(writer, data, offset, length).await;
+                }
+            }
+        }
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().inner_store.list_digests(prefix)
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+
+    fn register_metrics(self: Arc<Self>, registry: &mut Registry) {
+        let inner_store_registry = registry.sub_registry_with_prefix("inner_store");
+        self.inner_store.register_metrics(inner_store_registry);
+        registry.register_collector(Box::new(Collector::new(&self)));
+    }
+}
+
+impl MetricsComponent for SingleFlightStore {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "deduplicated_uploads",
+            &self.deduplicated_uploads,
+            "Uploads that skipped the backend because the same digest was already being uploaded",
+        );
+        c.publish(
+            "coalesced_reads",
+            &self.coalesced_reads,
+            "Reads that skipped the backend because the same digest was read concurrently or within the coalescing window",
+        );
+    }
+}
+
+default_health_status_indicator!(SingleFlightStore);
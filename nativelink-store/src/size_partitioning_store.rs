@@ -16,11 +16,14 @@ use std::pin::Pin;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use futures::StreamExt;
 use nativelink_error::{make_input_err, Error, ResultExt};
 use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
 use nativelink_util::metrics_utils::{Collector, CollectorState, MetricsComponent, Registry};
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    ConsistencyToken, DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
 use tokio::join;
 
 pub struct SizePartitioningStore {
@@ -112,6 +115,55 @@ impl StoreDriver for SizePartitioningStore {
         self.upper_store.update(digest, reader, size_info).await
     }
 
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        let digest = match key {
+            StoreKey::Digest(digest) => digest,
+            other => {
+                return Err(make_input_err!(
+                    "SizePartitioningStore only supports Digest keys, got {other:?}"
+                ))
+            }
+        };
+        if digest.size_bytes < self.partition_size {
+            return self
+                .lower_store
+                .update_with_consistency_token(digest, reader, size_info)
+                .await;
+        }
+        self.upper_store
+            .update_with_consistency_token(digest, reader, size_info)
+            .await
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        let digest = match key {
+            StoreKey::Digest(digest) => digest,
+            other => {
+                return Err(make_input_err!(
+                    "SizePartitioningStore only supports Digest keys, got {other:?}"
+                ))
+            }
+        };
+        let store = if digest.size_bytes < self.partition_size {
+            &self.lower_store
+        } else {
+            &self.upper_store
+        };
+        store
+            .as_store_driver_pin()
+            .wait_for_consistency(digest.into(), token)
+            .await
+    }
+
     async fn get_part(
         self: Pin<&Self>,
         key: StoreKey<'_>,
@@ -138,6 +190,15 @@ impl StoreDriver for SizePartitioningStore {
             .await
     }
 
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        let this = self.get_ref();
+        Box::pin(
+            this.lower_store
+                .list_digests(prefix)
+                .chain(this.upper_store.list_digests(prefix)),
+        )
+    }
+
     fn inner_store(&self, key: Option<StoreKey>) -> &'_ dyn StoreDriver {
         let Some(key) = key else {
             return self;
@@ -13,21 +13,43 @@
 // limitations under the License.
 
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 use nativelink_util::store_trait::Store;
 
+use crate::verify_store::ContentPolicy;
+
 pub struct StoreManager {
     stores: RwLock<HashMap<String, Store>>,
+    content_policies: RwLock<HashMap<String, Arc<dyn ContentPolicy>>>,
 }
 
 impl StoreManager {
     pub fn new() -> StoreManager {
         StoreManager {
             stores: RwLock::new(HashMap::new()),
+            content_policies: RwLock::new(HashMap::new()),
         }
     }
 
+    /// Registers `content_policy` under `name`, so `VerifyStore` configs can
+    /// refer to it with `content_policy`.
+    pub fn add_content_policy(&self, name: &str, content_policy: Arc<dyn ContentPolicy>) {
+        let mut content_policies = self
+            .content_policies
+            .write()
+            .expect("Failed to lock mutex in add_content_policy()");
+        content_policies.insert(name.to_string(), content_policy);
+    }
+
+    pub fn get_content_policy(&self, name: &str) -> Option<Arc<dyn ContentPolicy>> {
+        let content_policies = self
+            .content_policies
+            .read()
+            .expect("Failed to lock read mutex in get_content_policy()");
+        content_policies.get(name).cloned()
+    }
+
     pub fn add_store(&self, name: &str, store: Store) {
         let mut stores = self
             .stores
@@ -12,52 +12,215 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::num::NonZeroUsize;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use nativelink_error::{make_input_err, Error, ResultExt};
+use lru::LruCache;
+use nativelink_error::{make_input_err, Code, Error, ResultExt};
 use nativelink_util::buf_channel::{
     make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
 };
+use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::{
-    default_digest_hasher_func, DigestHasher, ACTIVE_HASHER_FUNC,
+    default_digest_hasher_func, DigestHasherFunc, StreamingHasher, ACTIVE_HASHER_FUNC,
 };
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
 use nativelink_util::metrics_utils::{
     Collector, CollectorState, CounterWithTime, MetricsComponent, Registry,
 };
 use nativelink_util::origin_context::ActiveOriginContext;
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{
+    ConsistencyToken, DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use tracing::{event, Level};
+
+/// Policy hook that decides whether data may be stored, e.g. to scan for
+/// secrets. Register it with `StoreManager::add_content_policy()` and name it
+/// in the `content_policy` config of a `VerifyStore`, or set it on a
+/// `VerifyStore` with `VerifyStore::set_content_policy()`.
+pub trait ContentPolicy: Send + Sync + 'static {
+    /// Called when an upload of `digest` starts. The returned inspector is
+    /// shown the data as it is uploaded. Returning `None` accepts the upload
+    /// without looking at its data.
+    fn new_inspector(&self, digest: DigestInfo) -> Option<Box<dyn ContentInspector>>;
+}
+
+/// Inspects the data of a single upload for a `ContentPolicy`. Returning an
+/// error, usually with `Code::PermissionDenied`, aborts the upload before
+/// the inner store completes it. The error is returned to the client.
+pub trait ContentInspector: Send {
+    /// Called with each chunk of data in order, before it is forwarded to
+    /// the inner store.
+    fn inspect(&mut self, chunk: &[u8]) -> Result<(), Error>;
+
+    /// Called once all data was received.
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The default `ContentPolicy`, which accepts all data.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AcceptAllContentPolicy;
+
+impl ContentPolicy for AcceptAllContentPolicy {
+    fn new_inspector(&self, _digest: DigestInfo) -> Option<Box<dyn ContentInspector>> {
+        None
+    }
+}
 
 pub struct VerifyStore {
     inner_store: Store,
     verify_size: bool,
     verify_hash: bool,
+    /// Digests whose hash was recently verified on upload. `None` if
+    /// `verified_digest_cache_size` is zero.
+    verified_digests: Option<Mutex<LruCache<DigestInfo, ()>>>,
+    read_verification_sample_rate: f64,
+    /// Decides which reads are verified.
+    read_sample_rng: Mutex<Box<dyn RngCore + Send>>,
+    content_policy: Mutex<Arc<dyn ContentPolicy>>,
 
     // Metrics.
     size_verification_failures: CounterWithTime,
     hash_verification_failures: CounterWithTime,
+    hash_verification_cache_hits: CounterWithTime,
+    read_verifications: CounterWithTime,
+    read_verification_failures: CounterWithTime,
+    content_policy_rejections: CounterWithTime,
 }
 
 impl VerifyStore {
     pub fn new(config: &nativelink_config::stores::VerifyStore, inner_store: Store) -> Arc<Self> {
+        Self::new_with_rng(config, inner_store, Box::new(StdRng::from_entropy()))
+    }
+
+    /// Same as `new()`, but uses `read_sample_rng` to pick the reads that
+    /// are verified.
+    pub fn new_with_rng(
+        config: &nativelink_config::stores::VerifyStore,
+        inner_store: Store,
+        read_sample_rng: Box<dyn RngCore + Send>,
+    ) -> Arc<Self> {
         Arc::new(VerifyStore {
             inner_store,
             verify_size: config.verify_size,
             verify_hash: config.verify_hash,
+            verified_digests: NonZeroUsize::new(config.verified_digest_cache_size)
+                .map(|size| Mutex::new(LruCache::new(size))),
+            read_verification_sample_rate: f64::from(config.read_verification_sample_rate)
+                .clamp(0., 1.),
+            read_sample_rng: Mutex::new(read_sample_rng),
+            content_policy: Mutex::new(Arc::new(AcceptAllContentPolicy)),
             size_verification_failures: CounterWithTime::default(),
             hash_verification_failures: CounterWithTime::default(),
+            hash_verification_cache_hits: CounterWithTime::default(),
+            read_verifications: CounterWithTime::default(),
+            read_verification_failures: CounterWithTime::default(),
+            content_policy_rejections: CounterWithTime::default(),
         })
     }
 
-    async fn inner_check_update<D: DigestHasher>(
+    /// Replaces the policy that decides whether uploaded data may be stored.
+    /// By default all data is accepted.
+    pub fn set_content_policy(&self, content_policy: Arc<dyn ContentPolicy>) {
+        *self.content_policy.lock() = content_policy;
+    }
+
+    fn check_content_policy(&self, result: Result<(), Error>) -> Result<(), Error> {
+        if result.is_err() {
+            self.content_policy_rejections.inc();
+        }
+        result.err_tip(|| "Upload rejected by content policy in verify store")
+    }
+
+    /// Returns the digest to check the read against if this read was
+    /// sampled for verification.
+    fn sample_read(
+        &self,
+        key: &StoreKey<'_>,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Option<DigestInfo> {
+        let StoreKey::Digest(digest) = key else {
+            return None;
+        };
+        // Only a read of the whole blob can be checked against its digest.
+        let is_full_read = offset == 0
+            && length.map_or(true, |length| {
+                i64::try_from(length).map_or(true, |length| length >= digest.size_bytes)
+            });
+        if !is_full_read || self.read_verification_sample_rate == 0. {
+            return None;
+        }
+        self.read_sample_rng
+            .lock()
+            .gen_bool(self.read_verification_sample_rate)
+            .then_some(*digest)
+    }
+
+    fn is_verified(&self, key: &StoreKey<'_>) -> bool {
+        match (key, self.verified_digests.as_ref()) {
+            (StoreKey::Digest(digest), Some(verified_digests)) => {
+                verified_digests.lock().get(digest).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets a verified digest, so the next upload of it is hashed again.
+    fn forget_verified(&self, key: &StoreKey<'_>) {
+        if let (StoreKey::Digest(digest), Some(verified_digests)) =
+            (key, self.verified_digests.as_ref())
+        {
+            verified_digests.lock().pop(digest);
+        }
+    }
+
+    /// Reads an upload to the end without storing it, verifying its size
+    /// when `verify_size` is set.
+    async fn drain_update(
+        &self,
+        mut reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        reader
+            .drain()
+            .await
+            .err_tip(|| "Failed to drain upload in verify store")?;
+        if let UploadSizeInfo::ExactSize(expected_size) = size_info {
+            let received_size = reader.get_bytes_received();
+            if self.verify_size && received_size != expected_size as u64 {
+                self.size_verification_failures.inc();
+                return Err(make_input_err!(
+                    "Expected size {} but got size {} on insert",
+                    expected_size,
+                    received_size
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn forget_verified_if_not_found(&self, key: &StoreKey<'_>, result: &Result<(), Error>) {
+        if matches!(result, Err(err) if err.code == Code::NotFound) {
+            self.forget_verified(key);
+        }
+    }
+
+    async fn inner_check_update(
         &self,
         mut tx: DropCloserWriteHalf,
         mut rx: DropCloserReadHalf,
         size_info: UploadSizeInfo,
-        original_hash: [u8; 32],
-        mut maybe_hasher: Option<&mut D>,
+        digest: DigestInfo,
+        mut maybe_hasher: Option<(DigestHasherFunc, StreamingHasher)>,
+        mut maybe_inspector: Option<Box<dyn ContentInspector>>,
     ) -> Result<(), Error> {
         let mut sum_size: u64 = 0;
         loop {
@@ -79,26 +242,38 @@ impl VerifyStore {
                         ));
                     }
                 }
-                if let Some(hasher) = maybe_hasher {
-                    let hash_result: [u8; 32] = hasher.finalize_digest().packed_hash;
-                    if original_hash != hash_result {
+                if let Some((digest_function, hasher)) = maybe_hasher {
+                    let hash_result = hasher.finalize();
+                    if digest.packed_hash != hash_result.packed_hash {
                         self.hash_verification_failures.inc();
+                        // Returning the error drops `tx` before EOF, so the
+                        // inner store rejects the write.
                         return Err(make_input_err!(
-                            "Hashes do not match, got: {} but digest hash was {}",
-                            hex::encode(original_hash),
-                            hex::encode(hash_result),
+                            "Hashes do not match, expected digest {}-{} but received {} bytes with {digest_function} hash {}",
+                            digest.hash_str(),
+                            digest.size_bytes,
+                            hash_result.size_bytes,
+                            hash_result.hash_str(),
                         ));
                     }
                 }
+                if let Some(inspector) = maybe_inspector.as_mut() {
+                    self.check_content_policy(inspector.finish())?;
+                }
                 tx.send_eof().err_tip(|| "In verify_store::check_update")?;
                 break;
             }
 
+            // Rejected data must never reach the inner store.
+            if let Some(inspector) = maybe_inspector.as_mut() {
+                self.check_content_policy(inspector.inspect(&chunk))?;
+            }
+
             // This will allows us to hash while sending data to another thread.
             let write_future = tx.send(chunk.clone());
 
-            if let Some(hasher) = maybe_hasher.as_mut() {
-                hasher.update(chunk.as_ref());
+            if let Some((_, hasher)) = maybe_hasher.as_mut() {
+                hasher.update(&chunk);
             }
 
             write_future
@@ -116,7 +291,13 @@ impl StoreDriver for VerifyStore {
         digests: &[StoreKey<'_>],
         results: &mut [Option<usize>],
     ) -> Result<(), Error> {
-        self.inner_store.has_with_results(digests, results).await
+        self.inner_store.has_with_results(digests, results).await?;
+        for (digest, result) in digests.iter().zip(results.iter()) {
+            if result.is_none() {
+                self.forget_verified(digest);
+            }
+        }
+        Ok(())
     }
 
     async fn update(
@@ -125,6 +306,17 @@ impl StoreDriver for VerifyStore {
         reader: DropCloserReadHalf,
         size_info: UploadSizeInfo,
     ) -> Result<(), Error> {
+        self.update_with_consistency_token(key, reader, size_info)
+            .await
+            .map(|_| ())
+    }
+
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
         let digest = match key {
             StoreKey::Digest(digest) => digest,
             _ => {
@@ -146,26 +338,67 @@ impl StoreDriver for VerifyStore {
             }
         }
 
-        let mut hasher = if self.verify_hash {
-            Some(
-                ActiveOriginContext::get_value(&ACTIVE_HASHER_FUNC)
-                    .err_tip(|| "In verify_store::update")?
-                    .map_or_else(default_digest_hasher_func, |v| *v)
-                    .hasher(),
-            )
+        if self.verify_hash && self.is_verified(&key) {
+            // The stored copy was already hashed, so the uploaded bytes are
+            // discarded instead of being written over it unverified.
+            let has_result = self
+                .inner_store
+                .has(digest)
+                .await
+                .err_tip(|| "In verify_store::update")?;
+            if has_result.is_some() {
+                self.hash_verification_cache_hits.inc();
+                // The stored copy is already visible, so there is nothing
+                // for a reader to wait for.
+                return self.drain_update(reader, size_info).await.map(|()| None);
+            }
+            self.forget_verified(&key);
+        }
+
+        let hasher = if self.verify_hash {
+            let digest_function = ActiveOriginContext::get_value(&ACTIVE_HASHER_FUNC)
+                .err_tip(|| "In verify_store::update")?
+                .map_or_else(default_digest_hasher_func, |v| *v);
+            Some((digest_function, StreamingHasher::new(digest_function)))
         } else {
             None
         };
 
+        let needs_caching = hasher.is_some() && self.verified_digests.is_some();
         let (tx, rx) = make_buf_channel_pair();
 
-        let update_fut = self.inner_store.update(digest, rx, size_info);
-        let check_fut =
-            self.inner_check_update(tx, reader, size_info, digest.packed_hash, hasher.as_mut());
+        let update_fut = self
+            .inner_store
+            .update_with_consistency_token(digest, rx, size_info);
+        let inspector = self.content_policy.lock().new_inspector(digest);
+        let check_fut = self.inner_check_update(tx, reader, size_info, digest, hasher, inspector);
 
         let (update_res, check_res) = tokio::join!(update_fut, check_fut);
 
-        update_res.merge(check_res)
+        // A failed check is the cause of the inner store's error, so its
+        // code is the one returned to the client.
+        let result = if check_res.is_err() {
+            check_res.merge(update_res)
+        } else {
+            update_res
+        };
+        if needs_caching && result.is_ok() {
+            if let Some(verified_digests) = &self.verified_digests {
+                verified_digests.lock().put(digest, ());
+            }
+        }
+        result
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        self.inner_store
+            .as_store_driver_pin()
+            .wait_for_consistency(key, token)
+            .await
     }
 
     async fn get_part(
@@ -175,7 +408,46 @@ impl StoreDriver for VerifyStore {
         offset: usize,
         length: Option<usize>,
     ) -> Result<(), Error> {
-        self.inner_store.get_part(key, writer, offset, length).await
+        let Some(digest) = self.sample_read(&key, offset, length) else {
+            let result = self
+                .inner_store
+                .get_part(key.borrow(), writer, offset, length)
+                .await;
+            self.forget_verified_if_not_found(&key, &result);
+            return result;
+        };
+        self.read_verifications.inc();
+        let result = self
+            .inner_store
+            .get_part_verified(digest, writer, offset, length)
+            .await;
+        match &result {
+            Err(err) if err.code == Code::DataLoss => {
+                self.read_verification_failures.inc();
+                self.forget_verified(&key);
+                event!(Level::ERROR, ?digest, ?err, "Read verification failed");
+            }
+            _ => self.forget_verified_if_not_found(&key, &result),
+        }
+        result
+    }
+
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        let result = self
+            .inner_store
+            .get_tail(key.borrow(), writer, length)
+            .await;
+        self.forget_verified_if_not_found(&key, &result);
+        result
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        self.get_ref().inner_store.list_digests(prefix)
     }
 
     fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
@@ -219,6 +491,31 @@ impl MetricsComponent for VerifyStore {
             &self.hash_verification_failures,
             "Number of failures the verification store had due to hash mismatches",
         );
+        c.publish(
+            "hash_verification_cache_hits_total",
+            &self.hash_verification_cache_hits,
+            "Number of uploads that skipped hashing because the digest was recently verified",
+        );
+        c.publish(
+            "read_verification_sample_rate",
+            &self.read_verification_sample_rate,
+            "Fraction of reads the verification store verifies",
+        );
+        c.publish(
+            "read_verifications_total",
+            &self.read_verifications,
+            "Number of reads the verification store sampled and verified",
+        );
+        c.publish(
+            "read_verification_failures_total",
+            &self.read_verification_failures,
+            "Number of sampled reads whose data did not match the digest",
+        );
+        c.publish(
+            "content_policy_rejections_total",
+            &self.content_policy_rejections,
+            "Number of uploads the content policy rejected",
+        );
     }
 }
 
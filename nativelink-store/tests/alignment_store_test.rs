@@ -0,0 +1,132 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use nativelink_config::stores::{MemoryStore as MemoryStoreConfig, StoreConfig};
+use nativelink_error::{Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_store::alignment_store::AlignmentStore;
+use nativelink_store::memory_store::MemoryStore;
+use nativelink_util::store_trait::{Store, StoreLike};
+use pretty_assertions::assert_eq;
+
+const ALIGNMENT: usize = 16;
+
+fn make_stores() -> Result<(Store, Arc<MemoryStore>), Error> {
+    let inner_store = MemoryStore::new(&MemoryStoreConfig::default());
+    let store = Store::new(AlignmentStore::new(
+        &nativelink_config::stores::AlignmentStore {
+            backend: StoreConfig::noop,
+            alignment: ALIGNMENT,
+        },
+        Store::new(inner_store.clone()),
+    )?);
+    Ok((store, inner_store))
+}
+
+#[nativelink_test]
+async fn round_trip_various_sizes_test() -> Result<(), Error> {
+    let (store, inner_store) = make_stores()?;
+    for size in [1, 7, 8, 9, 15, 16, 17, 100] {
+        let key = format!("blob_{size}");
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        store
+            .update_oneshot(key.as_str(), data.clone().into())
+            .await?;
+
+        // The inner store holds padded data.
+        let stored_size = inner_store.has(key.as_str()).await?.unwrap();
+        assert_eq!(stored_size % ALIGNMENT, 0, "size {size}");
+        assert!(stored_size > size, "size {size}");
+
+        assert_eq!(store.has(key.as_str()).await?, Some(size), "size {size}");
+        assert_eq!(
+            store.get_part_unchunked(key.as_str(), 0, None).await?,
+            data,
+            "size {size}"
+        );
+        // Unaligned ranges are trimmed to exactly the requested bytes.
+        let offset = size / 3;
+        let length = size / 2;
+        assert_eq!(
+            store
+                .get_part_unchunked(key.as_str(), offset, Some(length))
+                .await?,
+            data[offset..offset + length],
+            "size {size}"
+        );
+        // Reads past the end are truncated to the true size.
+        assert_eq!(
+            store
+                .get_part_unchunked(key.as_str(), offset, Some(size * 2))
+                .await?,
+            data[offset..],
+            "size {size}"
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn missing_key_test() -> Result<(), Error> {
+    let (store, _) = make_stores()?;
+    assert_eq!(store.has("missing").await?, None);
+    assert!(store.get_part_unchunked("missing", 0, None).await.is_err());
+    Ok(())
+}
+
+#[nativelink_test]
+async fn alignment_smaller_than_trailer_is_rejected_test() -> Result<(), Error> {
+    for alignment in [1, 7] {
+        let result = AlignmentStore::new(
+            &nativelink_config::stores::AlignmentStore {
+                backend: StoreConfig::noop,
+                alignment,
+            },
+            Store::new(MemoryStore::new(&MemoryStoreConfig::default())),
+        );
+        let Err(err) = result else {
+            panic!("Expected alignment {alignment} to be rejected");
+        };
+        assert_eq!(err.code, Code::InvalidArgument);
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn has_many_returns_true_sizes_test() -> Result<(), Error> {
+    let (store, _) = make_stores()?;
+    store.update_oneshot("blob_a", vec![1u8; 5].into()).await?;
+    store.update_oneshot("blob_b", vec![2u8; 40].into()).await?;
+
+    let keys = ["blob_a".into(), "missing".into(), "blob_b".into()];
+    assert_eq!(store.has_many(&keys).await?, vec![Some(5), None, Some(40)]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn offset_past_end_is_rejected_test() -> Result<(), Error> {
+    let (store, _) = make_stores()?;
+    store.update_oneshot("blob", vec![1u8; 5].into()).await?;
+
+    assert_eq!(
+        store.get_part_unchunked("blob", 5, None).await?,
+        Vec::<u8>::new()
+    );
+    // Inside the padding, but past the true size.
+    let err = store.get_part_unchunked("blob", 6, None).await.unwrap_err();
+    assert_eq!(err.code, Code::InvalidArgument, "{err:?}");
+    Ok(())
+}
@@ -12,11 +12,79 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
 use blake3::Hasher as Blake3;
-use nativelink_store::cas_utils::is_zero_digest;
+use nativelink_error::{make_err, Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_store::cas_utils::{is_zero_digest, ZERO_BYTE_DIGESTS};
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::common::DigestInfo;
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use pretty_assertions::assert_eq;
 use sha2::{Digest, Sha256};
 
+/// Store that fails and counts every request that reaches it.
+#[derive(Default)]
+struct UnreachableStore {
+    calls: AtomicUsize,
+}
+
+impl UnreachableStore {
+    fn call(&self) -> Error {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        make_err!(Code::Internal, "UnreachableStore was called")
+    }
+}
+
+#[async_trait]
+impl StoreDriver for UnreachableStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        _keys: &[StoreKey<'_>],
+        _results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        Err(self.call())
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _reader: DropCloserReadHalf,
+        _size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        Err(self.call())
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _writer: &mut DropCloserWriteHalf,
+        _offset: usize,
+        _length: Option<usize>,
+    ) -> Result<(), Error> {
+        Err(self.call())
+    }
+
+    fn inner_store(&self, _key: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(UnreachableStore);
+
 #[test]
 fn sha256_is_zero_digest() {
     let digest = DigestInfo {
@@ -56,3 +124,22 @@ fn blake_is_non_zero_digest() {
     };
     assert!(!is_zero_digest(&digest));
 }
+
+#[nativelink_test]
+async fn empty_digest_does_not_reach_store_test() -> Result<(), Error> {
+    let inner_store = Arc::new(UnreachableStore::default());
+    let store = Store::new(inner_store.clone());
+    let digest = ZERO_BYTE_DIGESTS[0];
+
+    assert_eq!(store.has(digest).await?, Some(0));
+    assert_eq!(store.has_many(&[digest.into()]).await?, vec![Some(0)]);
+    store.update_oneshot(digest, "".into()).await?;
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, "");
+    assert!(store.update_oneshot(digest, "foo".into()).await.is_err());
+    assert_eq!(inner_store.calls.load(Ordering::Relaxed), 0);
+
+    // Other digests are still sent to the store.
+    assert!(store.has(DigestInfo::new([1u8; 32], 0)).await.is_err());
+    assert_eq!(inner_store.calls.load(Ordering::Relaxed), 1);
+    Ok(())
+}
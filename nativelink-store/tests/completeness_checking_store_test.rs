@@ -12,21 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use nativelink_config::stores::MemoryStore as MemoryStoreConfig;
-use nativelink_error::Error;
+use async_trait::async_trait;
+use nativelink_config::stores::{
+    CompletenessCheckTimeoutBehavior, CompletenessCheckingStore as CompletenessCheckingStoreConfig,
+    MemoryStore as MemoryStoreConfig, StoreConfig,
+};
+use nativelink_error::{Code, Error};
 use nativelink_macro::nativelink_test;
 use nativelink_proto::build::bazel::remote::execution::v2::{
     ActionResult as ProtoActionResult, Directory, DirectoryNode, FileNode, OutputDirectory,
-    OutputFile, Tree,
+    OutputFile, OutputSymlink, Tree,
 };
 use nativelink_store::ac_utils::serialize_and_upload_message;
 use nativelink_store::completeness_checking_store::CompletenessCheckingStore;
 use nativelink_store::memory_store::MemoryStore;
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::DigestHasherFunc;
-use nativelink_util::store_trait::{Store, StoreLike};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use pretty_assertions::assert_eq;
+use tokio::task::yield_now;
 
 const ROOT_FILE: DigestInfo = DigestInfo::new([0u8; 32], 0);
 const ROOT_DIRECTORY: DigestInfo = DigestInfo::new([1u8; 32], 0);
@@ -35,11 +45,27 @@ const OUTPUT_FILE: DigestInfo = DigestInfo::new([4u8; 32], 0);
 const STDOUT: DigestInfo = DigestInfo::new([5u8; 32], 0);
 const STDERR: DigestInfo = DigestInfo::new([6u8; 32], 0);
 
+fn make_config(
+    completeness_check_timeout_millis: u64,
+    completeness_check_timeout_behavior: CompletenessCheckTimeoutBehavior,
+) -> CompletenessCheckingStoreConfig {
+    CompletenessCheckingStoreConfig {
+        backend: StoreConfig::noop,
+        cas_store: StoreConfig::noop,
+        completeness_check_timeout_millis,
+        completeness_check_timeout_behavior,
+        max_concurrent_action_result_fetches: 0,
+    }
+}
+
 async fn setup() -> Result<(Arc<CompletenessCheckingStore>, Arc<MemoryStore>, DigestInfo), Error> {
     let backend_store = Store::new(MemoryStore::new(&MemoryStoreConfig::default()));
     let cas_store = MemoryStore::new(&MemoryStoreConfig::default());
-    let ac_store =
-        CompletenessCheckingStore::new(backend_store.clone(), Store::new(cas_store.clone()));
+    let ac_store = CompletenessCheckingStore::new(
+        &make_config(0, CompletenessCheckTimeoutBehavior::default()),
+        backend_store.clone(),
+        Store::new(cas_store.clone()),
+    );
 
     cas_store.update_oneshot(ROOT_FILE, "".into()).await?;
     // Note: Explicitly not uploading `ROOT_DIRECTORY`. See: TraceMachina/nativelink#747.
@@ -211,6 +237,59 @@ async fn verify_has_function_call_checks_cas() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn missing_symlink_target_is_reported_incomplete() -> Result<(), Error> {
+    const SYMLINK_TARGET_FILE: DigestInfo = DigestInfo::new([7u8; 32], 0);
+
+    let backend_store = Store::new(MemoryStore::new(&MemoryStoreConfig::default()));
+    let cas_store = MemoryStore::new(&MemoryStoreConfig::default());
+    let ac_store = CompletenessCheckingStore::new(
+        &make_config(0, CompletenessCheckTimeoutBehavior::default()),
+        backend_store,
+        Store::new(cas_store.clone()),
+    );
+
+    // Symlink targets are paths, not digests. A target that is produced by
+    // the action is itself an output file, so its blob is what must exist.
+    let symlink = OutputSymlink {
+        path: "out/link".to_string(),
+        target: "target".to_string(),
+        ..Default::default()
+    };
+    let action_result = ProtoActionResult {
+        output_files: vec![OutputFile {
+            path: "out/target".to_string(),
+            digest: Some(SYMLINK_TARGET_FILE.into()),
+            ..Default::default()
+        }],
+        output_file_symlinks: vec![symlink.clone()],
+        output_symlinks: vec![symlink],
+        ..Default::default()
+    };
+    let action_result_digest = serialize_and_upload_message(
+        &action_result,
+        ac_store.as_pin(),
+        &mut DigestHasherFunc::Blake3.hasher(),
+    )
+    .await?;
+
+    let res = ac_store.has_many(&[action_result_digest.into()]).await?;
+    assert!(
+        res[0].is_none(),
+        "Results should be none with missing symlink target."
+    );
+
+    cas_store
+        .update_oneshot(SYMLINK_TARGET_FILE, "".into())
+        .await?;
+    let res = ac_store.has_many(&[action_result_digest.into()]).await?;
+    assert!(
+        res[0].is_some(),
+        "Results should be some once the symlink target exists."
+    );
+    Ok(())
+}
+
 #[nativelink_test]
 async fn verify_completeness_get() -> Result<(), Error> {
     {
@@ -245,3 +324,219 @@ async fn verify_completeness_get() -> Result<(), Error> {
 
     Ok(())
 }
+
+/// CAS store whose existence checks never complete, but otherwise
+/// forwards to the inner store.
+struct StalledHasStore {
+    inner: Store,
+}
+
+#[async_trait]
+impl StoreDriver for StalledHasStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        _digests: &[StoreKey<'_>],
+        _results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        futures::future::pending().await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.inner.update(key, reader, size_info).await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        self.inner.get_part(key, writer, offset, length).await
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+        self
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(StalledHasStore);
+
+async fn setup_stalled_cas(
+    completeness_check_timeout_behavior: CompletenessCheckTimeoutBehavior,
+) -> Result<(Arc<CompletenessCheckingStore>, DigestInfo), Error> {
+    let backend_store = Store::new(MemoryStore::new(&MemoryStoreConfig::default()));
+    let cas_store = Store::new(Arc::new(StalledHasStore {
+        inner: Store::new(MemoryStore::new(&MemoryStoreConfig::default())),
+    }));
+    let ac_store = CompletenessCheckingStore::new(
+        &make_config(10, completeness_check_timeout_behavior),
+        backend_store,
+        cas_store,
+    );
+    let action_result = ProtoActionResult {
+        stdout_digest: Some(STDOUT.into()),
+        ..Default::default()
+    };
+    let action_result_digest = serialize_and_upload_message(
+        &action_result,
+        ac_store.as_pin(),
+        &mut DigestHasherFunc::Blake3.hasher(),
+    )
+    .await?;
+    Ok((ac_store, action_result_digest))
+}
+
+#[nativelink_test]
+async fn completeness_check_timeout_fail_open_serves_result() -> Result<(), Error> {
+    let (ac_store, action_result_digest) =
+        setup_stalled_cas(CompletenessCheckTimeoutBehavior::fail_open).await?;
+
+    let data = ac_store
+        .get_part_unchunked(action_result_digest, 0, None)
+        .await?;
+    assert_eq!(
+        data.len() as i64,
+        action_result_digest.size_bytes,
+        ".get() should serve the action result when failing open",
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn completeness_check_timeout_fail_closed_returns_not_found() -> Result<(), Error> {
+    let (ac_store, action_result_digest) =
+        setup_stalled_cas(CompletenessCheckTimeoutBehavior::fail_closed).await?;
+
+    let err = ac_store
+        .get_part_unchunked(action_result_digest, 0, None)
+        .await
+        .expect_err(".get() should fail when failing closed");
+    assert_eq!(err.code, Code::NotFound);
+    Ok(())
+}
+
+/// AC store that tracks how many reads are in flight at the same time.
+struct ConcurrencyTrackingStore {
+    inner: Store,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+#[async_trait]
+impl StoreDriver for ConcurrencyTrackingStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        digests: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner.has_with_results(digests, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.inner.update(key, reader, size_info).await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::AcqRel);
+        // Give the other fetches a chance to start while this one is in flight.
+        for _ in 0..10 {
+            yield_now().await;
+        }
+        let result = self.inner.get_part(key, writer, offset, length).await;
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        result
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+        self
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(ConcurrencyTrackingStore);
+
+#[nativelink_test]
+async fn action_result_fetch_concurrency_is_bounded() -> Result<(), Error> {
+    const MAX_CONCURRENT_FETCHES: usize = 3;
+    const NUM_ACTION_RESULTS: i32 = 20;
+    let backend_store = Arc::new(ConcurrencyTrackingStore {
+        inner: Store::new(MemoryStore::new(&MemoryStoreConfig::default())),
+        in_flight: AtomicUsize::new(0),
+        max_in_flight: AtomicUsize::new(0),
+    });
+    let cas_store = MemoryStore::new(&MemoryStoreConfig::default());
+    cas_store.update_oneshot(STDOUT, "".into()).await?;
+    let ac_store = CompletenessCheckingStore::new(
+        &CompletenessCheckingStoreConfig {
+            max_concurrent_action_result_fetches: MAX_CONCURRENT_FETCHES,
+            ..make_config(0, CompletenessCheckTimeoutBehavior::default())
+        },
+        Store::new(backend_store.clone()),
+        Store::new(cas_store),
+    );
+
+    let mut action_result_digests = Vec::new();
+    for exit_code in 0..NUM_ACTION_RESULTS {
+        let action_result = ProtoActionResult {
+            exit_code,
+            stdout_digest: Some(STDOUT.into()),
+            ..Default::default()
+        };
+        let digest = serialize_and_upload_message(
+            &action_result,
+            ac_store.as_pin(),
+            &mut DigestHasherFunc::Blake3.hasher(),
+        )
+        .await?;
+        action_result_digests.push(StoreKey::from(digest));
+    }
+    // A missing action result must still be reported as missing.
+    action_result_digests.push(StoreKey::from(OUTPUT_FILE));
+
+    let results = ac_store.has_many(&action_result_digests).await?;
+    let (missing, found) = results.split_last().unwrap();
+    assert!(
+        found.iter().all(Option::is_some),
+        "Expected all action results to be complete, got {results:?}"
+    );
+    assert_eq!(*missing, None);
+    assert_eq!(
+        backend_store.max_in_flight.load(Ordering::Acquire),
+        MAX_CONCURRENT_FETCHES,
+        "Expected AC fetches to be bounded by max_concurrent_action_result_fetches"
+    );
+    Ok(())
+}
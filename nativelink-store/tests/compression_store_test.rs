@@ -17,14 +17,16 @@ use std::io::Cursor;
 use std::pin::Pin;
 use std::str::from_utf8;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bincode::{DefaultOptions, Options};
 use bytes::Bytes;
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
 use nativelink_store::compression_store::{
-    CompressionStore, Footer, Lz4Config, SliceIndex, CURRENT_STREAM_FORMAT_VERSION,
-    DEFAULT_BLOCK_SIZE, FOOTER_FRAME_TYPE,
+    CompressionAlgorithm, CompressionConfig, CompressionStore, Footer, Lz4Config, Lz4OnlyFooter,
+    Lz4OnlyHeader, SliceIndex, CHUNK_FRAME_TYPE, CURRENT_STREAM_FORMAT_VERSION, DEFAULT_BLOCK_SIZE,
+    FOOTER_FRAME_TYPE, LZ4_ONLY_STREAM_FORMAT_VERSION, RAW_STREAM_MARKER,
 };
 use nativelink_store::memory_store::MemoryStore;
 use nativelink_util::buf_channel::make_buf_channel_pair;
@@ -40,6 +42,7 @@ use tokio::io::AsyncReadExt;
 /// Utility function that will build a Footer object from the input.
 fn extract_footer(data: &[u8]) -> Result<Footer, Error> {
     let mut pos = data.len() - 1; // Skip version byte(u8)
+    pos -= 4; // Skip algorithm(u32).
     pos -= 4; // Skip block_size(u32).
     pos -= 8; // Skip uncompressed_data_size(u64).
     let index_count = u32::from_le_bytes(data[pos - 4..pos].try_into().unwrap());
@@ -80,6 +83,9 @@ async fn simple_smoke_test() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(MemoryStore::new(
             &nativelink_config::stores::MemoryStore::default(),
@@ -117,6 +123,9 @@ async fn partial_reads_test() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(MemoryStore::new(
             &nativelink_config::stores::MemoryStore::default(),
@@ -175,6 +184,9 @@ async fn rand_5mb_smoke_test() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(MemoryStore::new(
             &nativelink_config::stores::MemoryStore::default(),
@@ -199,6 +211,156 @@ async fn rand_5mb_smoke_test() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn rand_5mb_zstd_smoke_test() -> Result<(), Error> {
+    for read_concurrency in [0, 4] {
+        let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+        let store_owned = CompressionStore::new(
+            nativelink_config::stores::CompressionStore {
+                backend: nativelink_config::stores::StoreConfig::memory(
+                    nativelink_config::stores::MemoryStore::default(),
+                ),
+                compression_algorithm: nativelink_config::stores::CompressionAlgorithm::zstd(
+                    nativelink_config::stores::ZstdConfig {
+                        compression_level: 3,
+                        ..Default::default()
+                    },
+                ),
+                min_compress_size: 0,
+                read_concurrency,
+                block_size_by_blob_size: vec![],
+            },
+            Store::new(inner_store.clone()),
+        )
+        .err_tip(|| "Failed to create compression store")?;
+        let store = Pin::new(&store_owned);
+
+        let mut value = vec![0u8; 5 * MEGABYTE_SZ];
+        let mut rng = SmallRng::seed_from_u64(1);
+        rng.fill(&mut value[..]);
+
+        let digest = DigestInfo::try_new(VALID_HASH, DUMMY_DATA_SIZE).unwrap();
+        store.update_oneshot(digest, value.clone().into()).await?;
+
+        let store_data = store
+            .get_part_unchunked(digest, 0, None)
+            .await
+            .err_tip(|| "Failed to get from inner store")?;
+        assert_eq!(&store_data, &value, "Expected data to match");
+
+        let offset = DEFAULT_BLOCK_SIZE as usize + 10;
+        let store_data = store
+            .get_part_unchunked(digest, offset, Some(MEGABYTE_SZ))
+            .await
+            .err_tip(|| "Failed to get part from inner store")?;
+        assert_eq!(
+            &store_data,
+            &value[offset..offset + MEGABYTE_SZ],
+            "Expected partial data to match"
+        );
+
+        let compressed_data = inner_store.get_part_unchunked(digest, 0, None).await?;
+        assert_eq!(
+            extract_footer(&compressed_data)?.config,
+            CompressionConfig {
+                block_size: DEFAULT_BLOCK_SIZE,
+                algorithm: CompressionAlgorithm::Zstd,
+            },
+            "Expected footer to record zstd"
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn reads_lz4_only_stream_format_test() -> Result<(), Error> {
+    const BLOCK_SIZE: u32 = 1024;
+    let bincode_options = DefaultOptions::new().with_fixint_encoding();
+    let mut value = vec![0u8; 3 * BLOCK_SIZE as usize + 100];
+    let mut rng = SmallRng::seed_from_u64(1);
+    rng.fill(&mut value[..]);
+
+    // Build a stream in the format used before the algorithm was recorded.
+    let mut stream = bincode_options
+        .serialize(&Lz4OnlyHeader {
+            version: LZ4_ONLY_STREAM_FORMAT_VERSION,
+            config: Lz4Config {
+                block_size: BLOCK_SIZE,
+            },
+            upload_size: UploadSizeInfo::ExactSize(value.len()),
+        })
+        .unwrap();
+    let mut indexes = Vec::new();
+    for chunk in value.chunks(BLOCK_SIZE as usize) {
+        let compressed_data = lz4_flex::block::compress(chunk);
+        stream.push(CHUNK_FRAME_TYPE);
+        stream.extend_from_slice(&(compressed_data.len() as u32).to_le_bytes());
+        stream.extend_from_slice(&compressed_data);
+        indexes.push(SliceIndex {
+            position_from_prev_index: compressed_data.len() as u32,
+        });
+    }
+    // There is no index for the last block.
+    indexes.pop();
+    let footer = bincode_options
+        .serialize(&Lz4OnlyFooter {
+            index_count: indexes.len() as u32,
+            indexes,
+            uncompressed_data_size: value.len() as u64,
+            config: Lz4Config {
+                block_size: BLOCK_SIZE,
+            },
+            version: LZ4_ONLY_STREAM_FORMAT_VERSION,
+        })
+        .unwrap();
+    stream.push(FOOTER_FRAME_TYPE);
+    stream.extend_from_slice(&(footer.len() as u32).to_le_bytes());
+    stream.extend_from_slice(&footer);
+
+    // Streams in the old format are readable regardless of the configured
+    // algorithm.
+    for read_concurrency in [0, 4] {
+        let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+        let store = CompressionStore::new(
+            nativelink_config::stores::CompressionStore {
+                backend: nativelink_config::stores::StoreConfig::memory(
+                    nativelink_config::stores::MemoryStore::default(),
+                ),
+                compression_algorithm: nativelink_config::stores::CompressionAlgorithm::zstd(
+                    nativelink_config::stores::ZstdConfig {
+                        block_size: BLOCK_SIZE,
+                        ..Default::default()
+                    },
+                ),
+                min_compress_size: 0,
+                read_concurrency,
+                block_size_by_blob_size: vec![],
+            },
+            Store::new(inner_store.clone()),
+        )
+        .err_tip(|| "Failed to create compression store")?;
+
+        let digest = DigestInfo::try_new(VALID_HASH, DUMMY_DATA_SIZE).unwrap();
+        inner_store
+            .update_oneshot(digest, stream.clone().into())
+            .await?;
+
+        assert_eq!(
+            store.get_part_unchunked(digest, 0, None).await?,
+            value,
+            "Expected data to match with read_concurrency {read_concurrency}"
+        );
+        assert_eq!(
+            store
+                .get_part_unchunked(digest, BLOCK_SIZE as usize - 1, Some(2))
+                .await?,
+            value[BLOCK_SIZE as usize - 1..BLOCK_SIZE as usize + 1],
+            "Expected partial data to match with read_concurrency {read_concurrency}"
+        );
+    }
+    Ok(())
+}
+
 #[nativelink_test]
 async fn sanity_check_zero_bytes_test() -> Result<(), Error> {
     let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
@@ -212,6 +374,9 @@ async fn sanity_check_zero_bytes_test() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(inner_store.clone()),
     )
@@ -242,8 +407,9 @@ async fn sanity_check_zero_bytes_test() -> Result<(), Error> {
             indexes: vec![],
             index_count: 0,
             uncompressed_data_size: 0,
-            config: Lz4Config {
+            config: CompressionConfig {
                 block_size: DEFAULT_BLOCK_SIZE,
+                algorithm: CompressionAlgorithm::Lz4,
             },
             version: CURRENT_STREAM_FORMAT_VERSION,
         },
@@ -268,6 +434,9 @@ async fn check_header_test() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(inner_store.clone()),
     )
@@ -307,6 +476,12 @@ async fn check_header_test() -> Result<(), Error> {
         let block_size = reader.read_u32_le().await?;
         assert_eq!(block_size, BLOCK_SIZE, "Expected block size to match");
     }
+    {
+        // Check algorithm.
+        const LZ4_OPT_CODE: u32 = 0;
+        let algorithm = reader.read_u32_le().await?;
+        assert_eq!(algorithm, LZ4_OPT_CODE, "Expected algorithm to match");
+    }
     {
         // Check upload_type and upload_size.
         const MAX_SIZE_OPT_CODE: u32 = 1;
@@ -329,8 +504,9 @@ async fn check_header_test() -> Result<(), Error> {
             indexes: vec![],
             index_count: 0,
             uncompressed_data_size: RAW_INPUT.len() as u64,
-            config: Lz4Config {
-                block_size: BLOCK_SIZE
+            config: CompressionConfig {
+                block_size: BLOCK_SIZE,
+                algorithm: CompressionAlgorithm::Lz4,
             },
             version: CURRENT_STREAM_FORMAT_VERSION,
         },
@@ -355,6 +531,9 @@ async fn check_footer_test() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(inner_store.clone()),
     )
@@ -386,6 +565,13 @@ async fn check_footer_test() -> Result<(), Error> {
             "Expected footer version to match current version"
         );
     }
+    {
+        // Check algorithm in footer.
+        const LZ4_OPT_CODE: u32 = 0;
+        let algorithm = u32::from_le_bytes(compressed_data[pos - 4..pos].try_into().unwrap());
+        pos -= 4;
+        assert_eq!(algorithm, LZ4_OPT_CODE, "Expected algorithm to match");
+    }
     {
         // Check block size in footer.
         let block_size = u32::from_le_bytes(compressed_data[pos - 4..pos].try_into().unwrap());
@@ -450,7 +636,7 @@ async fn check_footer_test() -> Result<(), Error> {
         pos -= 4;
         assert_eq!(
             footer_len,
-            1 + 4 + 8 + 4 + (index_count * 4) + 8,
+            1 + 4 + 4 + 8 + 4 + (index_count * 4) + 8,
             "Expected frame type to be footer"
         );
     }
@@ -474,8 +660,9 @@ async fn check_footer_test() -> Result<(), Error> {
                 .to_vec(),
             index_count: EXPECTED_INDEXES.len() as u32,
             uncompressed_data_size: data_len as u64,
-            config: Lz4Config {
-                block_size: BLOCK_SIZE
+            config: CompressionConfig {
+                block_size: BLOCK_SIZE,
+                algorithm: CompressionAlgorithm::Lz4,
             },
             version: CURRENT_STREAM_FORMAT_VERSION,
         },
@@ -505,6 +692,9 @@ async fn get_part_is_zero_digest() -> Result<(), Error> {
                     ..Default::default()
                 },
             ),
+            min_compress_size: 0,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
         },
         Store::new(inner_store.clone()),
     )
@@ -531,3 +721,395 @@ async fn get_part_is_zero_digest() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[nativelink_test]
+async fn blobs_below_min_compress_size_are_stored_raw_test() -> Result<(), Error> {
+    const MIN_COMPRESS_SIZE: usize = 100;
+    let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let store = CompressionStore::new(
+        nativelink_config::stores::CompressionStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            compression_algorithm: nativelink_config::stores::CompressionAlgorithm::lz4(
+                nativelink_config::stores::Lz4Config {
+                    ..Default::default()
+                },
+            ),
+            min_compress_size: MIN_COMPRESS_SIZE,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
+        },
+        Store::new(inner_store.clone()),
+    )
+    .err_tip(|| "Failed to create compression store")?;
+
+    let small_value = vec![1u8; MIN_COMPRESS_SIZE - 1];
+    let small_digest = DigestInfo::try_new(VALID_HASH, small_value.len())?;
+    store
+        .update_oneshot(small_digest, small_value.clone().into())
+        .await?;
+    let stored_data = inner_store
+        .get_part_unchunked(small_digest, 0, None)
+        .await?;
+    assert_eq!(stored_data[0], RAW_STREAM_MARKER);
+    assert_eq!(&stored_data[1..], &small_value[..], "Expected raw data");
+    assert_eq!(
+        store.get_part_unchunked(small_digest, 0, None).await?,
+        small_value
+    );
+    assert_eq!(
+        store.get_part_unchunked(small_digest, 10, Some(5)).await?,
+        small_value[10..15]
+    );
+
+    let large_value = vec![2u8; MIN_COMPRESS_SIZE];
+    let large_digest = DigestInfo::try_new(VALID_HASH, large_value.len())?;
+    store
+        .update_oneshot(large_digest, large_value.clone().into())
+        .await?;
+    let stored_data = inner_store
+        .get_part_unchunked(large_digest, 0, None)
+        .await?;
+    assert_eq!(
+        stored_data[0], CURRENT_STREAM_FORMAT_VERSION,
+        "Expected compressed stream header"
+    );
+    assert!(
+        stored_data.len() < large_value.len(),
+        "Expected data to be compressed"
+    );
+    assert_eq!(
+        store.get_part_unchunked(large_digest, 0, None).await?,
+        large_value
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_tail_returns_last_uncompressed_bytes_test() -> Result<(), Error> {
+    const MIN_COMPRESS_SIZE: usize = 100;
+    let store = CompressionStore::new(
+        nativelink_config::stores::CompressionStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            compression_algorithm: nativelink_config::stores::CompressionAlgorithm::lz4(
+                nativelink_config::stores::Lz4Config {
+                    block_size: 10,
+                    ..Default::default()
+                },
+            ),
+            min_compress_size: MIN_COMPRESS_SIZE,
+            read_concurrency: 0,
+            block_size_by_blob_size: vec![],
+        },
+        Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+    )
+    .err_tip(|| "Failed to create compression store")?;
+
+    // The stored size differs from the size of the data both for compressed
+    // data and for data stored raw behind a marker byte.
+    let raw_value: Vec<u8> = (0..MIN_COMPRESS_SIZE as u8 - 1).collect();
+    let compressed_value: Vec<u8> = (0..=255).cycle().take(MIN_COMPRESS_SIZE * 3).collect();
+    for value in [raw_value, compressed_value] {
+        let digest = DigestInfo::try_new(VALID_HASH, value.len())?;
+        store.update_oneshot(digest, value.clone().into()).await?;
+        let str_key = format!("key-{}", value.len());
+        store
+            .update_oneshot(str_key.as_str(), value.clone().into())
+            .await?;
+        for length in [0, 7, 25, value.len(), value.len() + 1] {
+            let expected = &value[value.len().saturating_sub(length)..];
+            let (tx, mut rx) = make_buf_channel_pair();
+            let (get_res, data_res) =
+                futures::join!(store.get_tail(digest, tx, length), rx.consume(None));
+            get_res?;
+            assert_eq!(data_res?, expected, "Wrong tail of digest for {length}");
+            let (tx, mut rx) = make_buf_channel_pair();
+            let (get_res, data_res) = futures::join!(
+                store.get_tail(str_key.as_str(), tx, length),
+                rx.consume(None)
+            );
+            get_res?;
+            assert_eq!(data_res?, expected, "Wrong tail of key for {length}");
+        }
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn compression_ratio_metrics_test() -> Result<(), Error> {
+    fn make_store() -> Result<Arc<CompressionStore>, Error> {
+        CompressionStore::new(
+            nativelink_config::stores::CompressionStore {
+                backend: nativelink_config::stores::StoreConfig::memory(
+                    nativelink_config::stores::MemoryStore::default(),
+                ),
+                compression_algorithm: nativelink_config::stores::CompressionAlgorithm::lz4(
+                    nativelink_config::stores::Lz4Config {
+                        ..Default::default()
+                    },
+                ),
+                min_compress_size: 0,
+                read_concurrency: 0,
+                block_size_by_blob_size: vec![],
+            },
+            Store::new(MemoryStore::new(
+                &nativelink_config::stores::MemoryStore::default(),
+            )),
+        )
+        .err_tip(|| "Failed to create compression store")
+    }
+
+    let compressible_store = make_store()?;
+    assert_eq!(compressible_store.compression_ratio(), 0.0);
+    let compressible_value = vec![0u8; MEGABYTE_SZ];
+    let digest = DigestInfo::try_new(VALID_HASH, compressible_value.len())?;
+    compressible_store
+        .update_oneshot(digest, compressible_value.into())
+        .await?;
+    assert_eq!(
+        compressible_store.uncompressed_bytes_total(),
+        MEGABYTE_SZ as u64
+    );
+    assert!(compressible_store.compressed_bytes_total() < MEGABYTE_SZ as u64 / 10);
+    assert!(compressible_store.compression_ratio() > 10.0);
+
+    let incompressible_store = make_store()?;
+    let mut incompressible_value = vec![0u8; MEGABYTE_SZ];
+    let mut rng = SmallRng::seed_from_u64(1);
+    rng.fill(&mut incompressible_value[..]);
+    incompressible_store
+        .update_oneshot(digest, incompressible_value.into())
+        .await?;
+    assert_eq!(
+        incompressible_store.uncompressed_bytes_total(),
+        MEGABYTE_SZ as u64
+    );
+    assert!(incompressible_store.compressed_bytes_total() >= MEGABYTE_SZ as u64);
+    assert!(incompressible_store.compression_ratio() <= 1.0);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn concurrent_block_reads_test() -> Result<(), Error> {
+    use async_trait::async_trait;
+    use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+    use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+    use nativelink_util::store_trait::{StoreDriver, StoreKey};
+
+    const BLOCK_SIZE: u32 = 16 * 1024;
+    const BLOCK_COUNT: usize = 32;
+    const REQUEST_LATENCY: Duration = Duration::from_millis(5);
+    const BYTES_PER_MS: usize = 1024;
+
+    /// Store that simulates a backend with a per request latency and a
+    /// limited bandwidth per request.
+    struct LatencyStore {
+        inner_store: Store,
+    }
+
+    #[async_trait]
+    impl StoreDriver for LatencyStore {
+        async fn has_with_results(
+            self: Pin<&Self>,
+            keys: &[StoreKey<'_>],
+            results: &mut [Option<usize>],
+        ) -> Result<(), Error> {
+            tokio::time::sleep(REQUEST_LATENCY).await;
+            self.inner_store.has_with_results(keys, results).await
+        }
+
+        async fn update(
+            self: Pin<&Self>,
+            key: StoreKey<'_>,
+            reader: DropCloserReadHalf,
+            upload_size: UploadSizeInfo,
+        ) -> Result<(), Error> {
+            self.inner_store.update(key, reader, upload_size).await
+        }
+
+        async fn get_part(
+            self: Pin<&Self>,
+            key: StoreKey<'_>,
+            writer: &mut DropCloserWriteHalf,
+            offset: usize,
+            length: Option<usize>,
+        ) -> Result<(), Error> {
+            let data = self
+                .inner_store
+                .get_part_unchunked(key, offset, length)
+                .await?;
+            let transfer_ms = (data.len() / BYTES_PER_MS) as u64;
+            tokio::time::sleep(REQUEST_LATENCY + Duration::from_millis(transfer_ms)).await;
+            if !data.is_empty() {
+                writer.send(data).await?;
+            }
+            writer.send_eof()
+        }
+
+        fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+            self
+        }
+
+        fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+            self
+        }
+
+        fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+            self
+        }
+
+        fn register_metrics(
+            self: Arc<Self>,
+            _registry: &mut nativelink_util::metrics_utils::Registry,
+        ) {
+        }
+    }
+
+    default_health_status_indicator!(LatencyStore);
+
+    let backend = Store::new(Arc::new(LatencyStore {
+        inner_store: Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+    }));
+    let make_store = |read_concurrency| {
+        CompressionStore::new(
+            nativelink_config::stores::CompressionStore {
+                backend: nativelink_config::stores::StoreConfig::memory(
+                    nativelink_config::stores::MemoryStore::default(),
+                ),
+                compression_algorithm: nativelink_config::stores::CompressionAlgorithm::lz4(
+                    nativelink_config::stores::Lz4Config {
+                        block_size: BLOCK_SIZE,
+                        ..Default::default()
+                    },
+                ),
+                min_compress_size: 0,
+                read_concurrency,
+                block_size_by_blob_size: vec![],
+            },
+            backend.clone(),
+        )
+        .err_tip(|| "Failed to create compression store")
+    };
+    let sequential_store = make_store(0)?;
+    let concurrent_store = make_store(8)?;
+
+    // Random data does not compress, so the stored data is as large as the
+    // original and every read is dominated by the transfer time.
+    let mut value = vec![0u8; BLOCK_SIZE as usize * BLOCK_COUNT - 123];
+    let mut rng = SmallRng::seed_from_u64(1);
+    rng.fill(&mut value[..]);
+    let digest = DigestInfo::try_new(VALID_HASH, value.len())?;
+    sequential_store
+        .update_oneshot(digest, value.clone().into())
+        .await?;
+
+    let start = Instant::now();
+    let sequential_data = sequential_store.get_part_unchunked(digest, 0, None).await?;
+    let sequential_elapsed = start.elapsed();
+    let start = Instant::now();
+    let concurrent_data = concurrent_store.get_part_unchunked(digest, 0, None).await?;
+    let concurrent_elapsed = start.elapsed();
+    assert_eq!(sequential_data, value);
+    assert_eq!(concurrent_data, value);
+    assert!(
+        concurrent_elapsed * 2 < sequential_elapsed,
+        "Expected concurrent read ({concurrent_elapsed:?}) to be faster than sequential read ({sequential_elapsed:?})"
+    );
+
+    // Ranged reads that start and end inside of blocks.
+    for (offset, length) in [
+        (0, Some(1)),
+        (BLOCK_SIZE as usize - 1, Some(2)),
+        (12_345, Some(BLOCK_SIZE as usize * 5)),
+        (value.len() - 10, None),
+        (value.len() - 10, Some(100)),
+        (value.len(), None),
+        (value.len() + 10, Some(5)),
+    ] {
+        let start_pos = cmp::min(value.len(), offset);
+        let end_pos = cmp::min(
+            value.len(),
+            offset.saturating_add(length.unwrap_or(usize::MAX)),
+        );
+        assert_eq!(
+            concurrent_store
+                .get_part_unchunked(digest, offset, length)
+                .await?,
+            value[start_pos..end_pos],
+            "Expected data to match at {offset} - {length:?}"
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn block_size_picked_by_blob_size_test() -> Result<(), Error> {
+    const DEFAULT_TEST_BLOCK_SIZE: u32 = 64;
+    let block_size_by_blob_size = vec![
+        nativelink_config::stores::CompressionBlockSizeRule {
+            min_blob_size: 10_000,
+            block_size: 1024,
+        },
+        nativelink_config::stores::CompressionBlockSizeRule {
+            min_blob_size: 1_000,
+            block_size: 256,
+        },
+    ];
+    let expected_block_sizes = [(100, DEFAULT_TEST_BLOCK_SIZE), (5_000, 256), (20_000, 1024)];
+
+    for read_concurrency in [0, 4] {
+        let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+        let store = CompressionStore::new(
+            nativelink_config::stores::CompressionStore {
+                backend: nativelink_config::stores::StoreConfig::memory(
+                    nativelink_config::stores::MemoryStore::default(),
+                ),
+                compression_algorithm: nativelink_config::stores::CompressionAlgorithm::lz4(
+                    nativelink_config::stores::Lz4Config {
+                        block_size: DEFAULT_TEST_BLOCK_SIZE,
+                        ..Default::default()
+                    },
+                ),
+                min_compress_size: 0,
+                read_concurrency,
+                block_size_by_blob_size: block_size_by_blob_size.clone(),
+            },
+            Store::new(inner_store.clone()),
+        )
+        .err_tip(|| "Failed to create compression store")?;
+
+        let mut rng = SmallRng::seed_from_u64(1);
+        for (blob_size, expected_block_size) in expected_block_sizes {
+            let mut value = vec![0u8; blob_size];
+            rng.fill(&mut value[..]);
+            let digest = DigestInfo::new([blob_size as u8; 32], blob_size as i64);
+            store.update_oneshot(digest, value.clone().into()).await?;
+
+            let compressed_data = inner_store.get_part_unchunked(digest, 0, None).await?;
+            assert_eq!(
+                extract_footer(&compressed_data)?.config.block_size,
+                expected_block_size,
+                "Expected block size for a {blob_size} byte blob to match"
+            );
+
+            assert_eq!(store.get_part_unchunked(digest, 0, None).await?, value);
+            let offset = blob_size / 3;
+            let length = blob_size / 2;
+            assert_eq!(
+                store
+                    .get_part_unchunked(digest, offset, Some(length))
+                    .await?,
+                &value[offset..offset + length],
+                "Expected partial read of a {blob_size} byte blob to match"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,257 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{poll, try_join};
+use nativelink_error::{make_err, Code, Error, ResultExt};
+use nativelink_macro::nativelink_test;
+use nativelink_store::existence_cache_store::ExistenceCacheStore;
+use nativelink_store::memory_store::MemoryStore;
+use nativelink_store::verify_store::VerifyStore;
+use nativelink_util::buf_channel::{
+    make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
+};
+use nativelink_util::common::DigestInfo;
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::store_trait::{
+    ConsistencyToken, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
+use parking_lot::Mutex;
+use pretty_assertions::assert_eq;
+use tokio::sync::watch;
+
+const KEY: &str = "foo";
+const VALUE: &str = "bar";
+const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
+
+/// Simulates an eventually consistent backend. Writes are only visible to
+/// reads once `replicate()` is called.
+struct LaggingStore {
+    inner_store: Store,
+    last_token: AtomicU64,
+    pending: Mutex<Vec<(StoreKey<'static>, Bytes)>>,
+    visible_tx: watch::Sender<u64>,
+}
+
+impl LaggingStore {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner_store: Store::new(MemoryStore::new(
+                &nativelink_config::stores::MemoryStore::default(),
+            )),
+            last_token: AtomicU64::new(0),
+            pending: Mutex::new(Vec::new()),
+            visible_tx: watch::channel(0).0,
+        })
+    }
+
+    /// Makes all pending writes visible to reads.
+    async fn replicate(&self) -> Result<(), Error> {
+        let last_token = self.last_token.load(Ordering::Acquire);
+        let pending = std::mem::take(&mut *self.pending.lock());
+        for (key, data) in pending {
+            self.inner_store.update_oneshot(key, data).await?;
+        }
+        self.visible_tx.send_replace(last_token);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StoreDriver for LaggingStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store.has_with_results(keys, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.update_with_consistency_token(key, reader, size_info)
+            .await
+            .map(|_| ())
+    }
+
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        _size_info: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        let data = reader.consume(None).await?;
+        let mut pending = self.pending.lock();
+        pending.push((key.into_owned(), data));
+        let token = self.last_token.fetch_add(1, Ordering::AcqRel) + 1;
+        Ok(Some(ConsistencyToken::new(token)))
+    }
+
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        self.visible_tx
+            .subscribe()
+            .wait_for(|visible| *visible >= token.value())
+            .await
+            .map_err(|e| make_err!(Code::Internal, "Visibility channel closed: {e:?}"))?;
+        Ok(())
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        self.inner_store.get_part(key, writer, offset, length).await
+    }
+
+    fn inner_store(&self, _key: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(LaggingStore);
+
+async fn update_with_token(
+    store: &Store,
+    key: impl Into<StoreKey<'static>>,
+    value: &'static str,
+) -> Result<Option<ConsistencyToken>, Error> {
+    let (mut tx, rx) = make_buf_channel_pair();
+    let send_fut = async move {
+        tx.send(value.into()).await?;
+        tx.send_eof()
+    };
+    let (token, ()) = try_join!(
+        store.update_with_consistency_token(key.into(), rx, UploadSizeInfo::ExactSize(value.len())),
+        send_fut,
+    )
+    .err_tip(|| "In update_with_token")?;
+    Ok(token)
+}
+
+#[nativelink_test]
+async fn read_with_token_waits_for_write_test() -> Result<(), Error> {
+    let lagging_store = LaggingStore::new();
+    let store = Store::new(lagging_store.clone());
+
+    let token = update_with_token(&store, KEY, VALUE)
+        .await?
+        .err_tip(|| "Expected lagging store to return a consistency token")?;
+    assert_eq!(
+        store.has(KEY).await?,
+        None,
+        "Write should not be visible without replication"
+    );
+
+    let has_fut = store.has_with_consistency_token(KEY, Some(token));
+    tokio::pin!(has_fut);
+    assert!(
+        poll!(&mut has_fut).is_pending(),
+        "Read with token should wait for the write to be visible"
+    );
+
+    lagging_store.replicate().await?;
+    assert_eq!(has_fut.await?, Some(VALUE.len()));
+
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    store
+        .get_part_with_consistency_token(KEY, &mut tx, 0, None, Some(token))
+        .await?;
+    assert_eq!(rx.consume(None).await?, VALUE.as_bytes());
+    Ok(())
+}
+
+#[nativelink_test]
+async fn wrapped_store_waits_for_write_test() -> Result<(), Error> {
+    let lagging_store = LaggingStore::new();
+    let verify_store = VerifyStore::new(
+        &nativelink_config::stores::VerifyStore {
+            backend: nativelink_config::stores::StoreConfig::noop, // Note: Not used.
+            verify_size: true,
+            verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
+        },
+        Store::new(lagging_store.clone()),
+    );
+    let store = Store::new(ExistenceCacheStore::new(
+        &nativelink_config::stores::ExistenceCacheStore {
+            backend: nativelink_config::stores::StoreConfig::noop, // Note: Not used.
+            eviction_policy: None,
+            cache_missing_for_s: 60,
+            max_backend_batch_size: 0,
+        },
+        Store::new(verify_store),
+    ));
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+
+    let token = update_with_token(&store, digest, VALUE)
+        .await?
+        .err_tip(|| "Expected wrapped store to forward the consistency token")?;
+    assert_eq!(
+        Store::new(lagging_store.clone()).has(digest).await?,
+        None,
+        "Write should not be visible without replication"
+    );
+
+    let has_fut = store.has_with_consistency_token(digest, Some(token));
+    tokio::pin!(has_fut);
+    assert!(
+        poll!(&mut has_fut).is_pending(),
+        "Read with token should wait for the write to be visible"
+    );
+
+    lagging_store.replicate().await?;
+    assert_eq!(has_fut.await?, Some(VALUE.len()));
+    Ok(())
+}
+
+#[nativelink_test]
+async fn strongly_consistent_store_ignores_token_test() -> Result<(), Error> {
+    let store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    assert_eq!(update_with_token(&store, KEY, VALUE).await?, None);
+    assert_eq!(
+        store
+            .has_with_consistency_token(KEY, Some(ConsistencyToken::new(u64::MAX)))
+            .await?,
+        Some(VALUE.len())
+    );
+    Ok(())
+}
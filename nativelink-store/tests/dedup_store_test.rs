@@ -12,9 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nativelink_error::{Code, Error, ResultExt};
+use bincode::{DefaultOptions, Options};
+use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
-use nativelink_store::dedup_store::DedupStore;
+use nativelink_store::dedup_store::{DedupIndex, DedupStore};
 use nativelink_store::memory_store::MemoryStore;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::store_trait::{Store, StoreLike};
@@ -303,6 +304,7 @@ async fn has_checks_content_store() -> Result<(), Error> {
             max_count: 10,
             ..Default::default()
         }),
+        ..Default::default()
     });
 
     let store = DedupStore::new(
@@ -354,6 +356,54 @@ async fn has_checks_content_store() -> Result<(), Error> {
     Ok(())
 }
 
+/// Ensure that checking several entries at once reports only the entries with a missing
+/// chunk as not found.
+#[nativelink_test]
+async fn has_many_reports_entries_with_missing_chunks_test() -> Result<(), Error> {
+    let index_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let content_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+
+    let store = DedupStore::new(
+        &make_default_config(),
+        Store::new(index_store.clone()),
+        Store::new(content_store.clone()),
+    );
+
+    const DATA_SIZE: usize = MEGABYTE_SZ / 4;
+    let data1 = make_random_data(DATA_SIZE);
+    let data2: Vec<u8> = data1.iter().rev().copied().collect();
+    let digest1 = DigestInfo::try_new(VALID_HASH1, DATA_SIZE).unwrap();
+    let digest2 = DigestInfo::try_new(VALID_HASH2, DATA_SIZE).unwrap();
+    store
+        .update_oneshot(digest1, data1.into())
+        .await
+        .err_tip(|| "Failed to write data1 to dedup store")?;
+    store
+        .update_oneshot(digest2, data2.into())
+        .await
+        .err_tip(|| "Failed to write data2 to dedup store")?;
+
+    let index_data = index_store
+        .get_part_unchunked(digest1, 0, None)
+        .await
+        .err_tip(|| "Failed to read index of data1")?;
+    let index = DefaultOptions::new()
+        .with_fixint_encoding()
+        .deserialize::<DedupIndex>(&index_data)
+        .map_err(|e| make_err!(Code::Internal, "Failed to decode index: {e:?}"))?;
+    assert!(
+        content_store.remove_entry(index.entries[0].into()).await,
+        "Expected first chunk of data1 to exist in content store"
+    );
+
+    let size_infos = store
+        .has_many(&[digest1.into(), digest2.into()])
+        .await
+        .err_tip(|| "Failed to run .has_many")?;
+    assert_eq!(size_infos, vec![None, Some(DATA_SIZE)]);
+    Ok(())
+}
+
 /// Ensure that when we run a `.has()` on a dedup store and the index does not exist it will
 /// properly return None.
 #[nativelink_test]
@@ -364,6 +414,7 @@ async fn has_with_no_existing_index_returns_none_test() -> Result<(), Error> {
             max_count: 10,
             ..Default::default()
         }),
+        ..Default::default()
     });
 
     let store = DedupStore::new(
@@ -0,0 +1,355 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nativelink_config::stores::StoreConfig;
+use nativelink_error::{make_err, Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_store::default_store_factory::{
+    check_store_configs, self_test_store, store_factory,
+};
+use nativelink_store::store_manager::StoreManager;
+use nativelink_store::verify_store::{ContentInspector, ContentPolicy};
+use nativelink_util::common::DigestInfo;
+use nativelink_util::store_trait::{health_check_data, StoreLike};
+use pretty_assertions::assert_eq;
+
+fn parse_config(json: &str) -> StoreConfig {
+    serde_json::from_str(json).unwrap()
+}
+
+async fn create_store(json: &str) -> Result<(), Error> {
+    let store_manager = Arc::new(StoreManager::new());
+    store_factory(&parse_config(json), &store_manager, None, None)
+        .await
+        .map(|_| ())
+}
+
+#[nativelink_test]
+async fn fast_slow_with_same_ref_store_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "fast_slow": {
+            "fast": { "ref_store": { "name": "CAS" } },
+            "slow": { "ref_store": { "name": "CAS" } }
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn fast_slow_with_same_filesystem_store_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "fast_slow": {
+            "fast": { "filesystem": {
+                "content_path": "/tmp/content",
+                "temp_path": "/tmp/tmp"
+            } },
+            "slow": { "filesystem": {
+                "content_path": "/tmp/content",
+                "temp_path": "/tmp/tmp"
+            } }
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn fast_slow_with_separate_memory_stores_test() -> Result<(), Error> {
+    // Every memory config creates its own instance, so identical configs
+    // are not the same store.
+    create_store(
+        r#"{ "fast_slow": {
+            "fast": { "memory": {} },
+            "slow": { "memory": {} }
+        } }"#,
+    )
+    .await
+}
+
+#[nativelink_test]
+async fn size_partitioning_with_same_ref_store_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "size_partitioning": {
+            "size": "128",
+            "lower_store": { "ref_store": { "name": "CAS" } },
+            "upper_store": { "ref_store": { "name": "CAS" } }
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn nested_misconfiguration_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "verify": {
+            "backend": { "fast_slow": {
+                "fast": { "ref_store": { "name": "CAS" } },
+                "slow": { "ref_store": { "name": "CAS" } }
+            } },
+            "verify_size": true
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn compression_with_dedup_backend_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "compression": {
+            "compression_algorithm": { "lz4": {} },
+            "backend": { "dedup": {
+                "index_store": { "memory": {} },
+                "content_store": { "memory": {} }
+            } }
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn dedup_with_compression_content_store_test() -> Result<(), Error> {
+    create_store(
+        r#"{ "dedup": {
+            "index_store": { "memory": {} },
+            "content_store": { "compression": {
+                "compression_algorithm": { "lz4": {} },
+                "backend": { "memory": {} }
+            } }
+        } }"#,
+    )
+    .await
+}
+
+#[nativelink_test]
+async fn memory_pressure_check_interval_of_zero_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "memory": {
+            "memory_pressure_eviction": { "threshold": 10.0, "check_interval_s": "0" }
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn fast_slow_both_max_populate_limits_set_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "fast_slow": {
+            "fast": { "memory": {} },
+            "slow": { "memory": {} },
+            "fast_store_max_populate_size": "1kb",
+            "fast_store_max_populate_bytes": "1000"
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+/// Rejects all data.
+struct RejectAllContentPolicy;
+
+struct RejectAllInspector;
+
+impl ContentPolicy for RejectAllContentPolicy {
+    fn new_inspector(&self, _digest: DigestInfo) -> Option<Box<dyn ContentInspector>> {
+        Some(Box::new(RejectAllInspector))
+    }
+}
+
+impl ContentInspector for RejectAllInspector {
+    fn inspect(&mut self, _chunk: &[u8]) -> Result<(), Error> {
+        Err(make_err!(Code::PermissionDenied, "All data is rejected"))
+    }
+}
+
+#[nativelink_test]
+async fn verify_store_uses_registered_content_policy_test() -> Result<(), Error> {
+    const VALUE: &str = "123";
+    let store_manager = Arc::new(StoreManager::new());
+    store_manager.add_content_policy("reject_all", Arc::new(RejectAllContentPolicy));
+    let store = store_factory(
+        &parse_config(
+            r#"{ "verify": {
+                "backend": { "memory": {} },
+                "content_policy": "reject_all"
+            } }"#,
+        ),
+        &store_manager,
+        None,
+        None,
+    )
+    .await?;
+    let digest = DigestInfo::try_new(
+        "0123456789abcdef000000000000000000010000000000000123456789abcdef",
+        VALUE.len(),
+    )?;
+    let err = store
+        .update_oneshot(digest, VALUE.into())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.code,
+        Code::PermissionDenied,
+        "Unexpected error: {err:?}"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn verify_store_with_unknown_content_policy_test() -> Result<(), Error> {
+    let result = create_store(
+        r#"{ "verify": {
+            "backend": { "memory": {} },
+            "content_policy": "unknown"
+        } }"#,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().code, Code::InvalidArgument);
+    Ok(())
+}
+
+fn parse_stores(json: &str) -> HashMap<String, StoreConfig> {
+    serde_json::from_str(json).unwrap()
+}
+
+#[nativelink_test]
+async fn store_referencing_itself_test() -> Result<(), Error> {
+    let config = r#"{ "compression": {
+        "compression_algorithm": { "lz4": {} },
+        "backend": { "ref_store": { "name": "CAS" } }
+    } }"#;
+    assert_eq!(
+        check_store_configs(&parse_stores(&format!(r#"{{ "CAS": {config} }}"#)))
+            .unwrap_err()
+            .code,
+        Code::InvalidArgument
+    );
+    check_store_configs(&parse_stores(&format!(
+        r#"{{ "CAS": {config}, "OTHER": {{ "memory": {{}} }} }}"#
+    )))
+    .unwrap_err();
+    check_store_configs(&parse_stores(&format!(
+        r#"{{ "OTHER": {config}, "CAS": {{ "memory": {{}} }} }}"#
+    )))
+}
+
+#[nativelink_test]
+async fn stores_referencing_each_other_test() -> Result<(), Error> {
+    let stores = parse_stores(
+        r#"{
+            "A": { "existence_cache": {
+                "backend": { "ref_store": { "name": "B" } }
+            } },
+            "B": { "fast_slow": {
+                "fast": { "memory": {} },
+                "slow": { "ref_store": { "name": "A" } }
+            } }
+        }"#,
+    );
+    assert_eq!(
+        check_store_configs(&stores).unwrap_err().code,
+        Code::InvalidArgument
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn compression_with_nested_dedup_backend_test() -> Result<(), Error> {
+    let stores = parse_stores(
+        r#"{
+            "COMPRESSED": { "compression": {
+                "compression_algorithm": { "lz4": {} },
+                "backend": { "existence_cache": {
+                    "backend": { "ref_store": { "name": "DEDUP" } }
+                } }
+            } },
+            "DEDUP": { "dedup": {
+                "index_store": { "memory": {} },
+                "content_store": { "memory": {} }
+            } }
+        }"#,
+    );
+    assert_eq!(
+        check_store_configs(&stores).unwrap_err().code,
+        Code::InvalidArgument
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn dedup_referencing_compression_content_store_test() -> Result<(), Error> {
+    check_store_configs(&parse_stores(
+        r#"{
+            "COMPRESSED": { "compression": {
+                "compression_algorithm": { "lz4": {} },
+                "backend": { "memory": {} }
+            } },
+            "DEDUP": { "dedup": {
+                "index_store": { "memory": {} },
+                "content_store": { "ref_store": { "name": "COMPRESSED" } }
+            } }
+        }"#,
+    ))
+}
+
+#[nativelink_test]
+async fn self_test_passes_for_healthy_store_test() -> Result<(), Error> {
+    let store_manager = Arc::new(StoreManager::new());
+    let store = store_factory(
+        &parse_config(r#"{ "memory": {} }"#),
+        &store_manager,
+        None,
+        None,
+    )
+    .await?;
+    self_test_store("CAS", &store).await?;
+    let (sentinel, _) = health_check_data("self_test/CAS", store.as_store_driver().get_name());
+    assert_eq!(
+        store.has(sentinel).await?,
+        None,
+        "Expected self-test sentinel to be removed"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn self_test_fails_for_store_that_drops_writes_test() -> Result<(), Error> {
+    // A noop store accepts writes but never stores anything, like a store
+    // without write permissions that does not report errors.
+    let store_manager = Arc::new(StoreManager::new());
+    let store = store_factory(&parse_config(r#""noop""#), &store_manager, None, None).await?;
+    let err = self_test_store("CAS", &store)
+        .await
+        .expect_err("Expected self-test of noop store to fail");
+    assert_eq!(err.code, Code::FailedPrecondition);
+    assert_eq!(
+        err.messages,
+        vec!["Startup self-test of store 'CAS' failed: Store.has() size not found".to_string()]
+    );
+    Ok(())
+}
@@ -12,14 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
 use nativelink_config::stores::{ExistenceCacheStore as ExistenceCacheStoreConfig, StoreConfig};
 use nativelink_error::{Error, ResultExt};
 use nativelink_macro::nativelink_test;
 use nativelink_store::existence_cache_store::ExistenceCacheStore;
 use nativelink_store::memory_store::MemoryStore;
+use nativelink_util::buf_channel::{
+    make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
+};
 use nativelink_util::common::DigestInfo;
-use nativelink_util::store_trait::{Store, StoreLike};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::spawn;
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
 use pretty_assertions::assert_eq;
+use tokio::sync::Notify;
 
 const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
 
@@ -29,6 +40,8 @@ async fn simple_exist_cache_test() -> Result<(), Error> {
     let config = ExistenceCacheStoreConfig {
         backend: StoreConfig::noop, // Note: Not used.
         eviction_policy: Default::default(),
+        cache_missing_for_s: 0,
+        max_backend_batch_size: 0,
     };
     let inner_store = Store::new(MemoryStore::new(
         &nativelink_config::stores::MemoryStore::default(),
@@ -69,6 +82,8 @@ async fn update_flags_existance_cache_test() -> Result<(), Error> {
     let config = ExistenceCacheStoreConfig {
         backend: StoreConfig::noop,
         eviction_policy: Default::default(),
+        cache_missing_for_s: 0,
+        max_backend_batch_size: 0,
     };
     let inner_store = Store::new(MemoryStore::new(
         &nativelink_config::stores::MemoryStore::default(),
@@ -94,6 +109,8 @@ async fn get_part_caches_if_exact_size_set() -> Result<(), Error> {
     let config = ExistenceCacheStoreConfig {
         backend: StoreConfig::noop,
         eviction_policy: Default::default(),
+        cache_missing_for_s: 0,
+        max_backend_batch_size: 0,
     };
     let inner_store = Store::new(MemoryStore::new(
         &nativelink_config::stores::MemoryStore::default(),
@@ -116,3 +133,141 @@ async fn get_part_caches_if_exact_size_set() -> Result<(), Error> {
     );
     Ok(())
 }
+
+#[nativelink_test]
+async fn missing_digest_is_forgotten_after_upload_test() -> Result<(), Error> {
+    const VALUE: &str = "123";
+    let config = ExistenceCacheStoreConfig {
+        backend: StoreConfig::noop, // Note: Not used.
+        eviction_policy: Default::default(),
+        cache_missing_for_s: 60,
+        max_backend_batch_size: 0,
+    };
+    let inner_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let store = ExistenceCacheStore::new(&config, inner_store.clone());
+    let digest = DigestInfo::try_new(VALID_HASH1, 3).unwrap();
+
+    assert_eq!(store.has(digest).await?, None);
+    // Uploads that bypass the cache are not seen until the entry expires.
+    inner_store.update_oneshot(digest, VALUE.into()).await?;
+    assert_eq!(store.has(digest).await?, None);
+
+    store
+        .update_oneshot(digest, VALUE.into())
+        .await
+        .err_tip(|| "Failed to update store")?;
+    assert_eq!(store.has(digest).await?, Some(VALUE.len()));
+    Ok(())
+}
+
+/// Forwards to a memory store, but can hold the answer of a lookup back
+/// until released, like a slow backend.
+struct SlowHasStore {
+    inner_store: Store,
+    hold_next_has: AtomicBool,
+    has_calls: AtomicUsize,
+    release_has: Notify,
+}
+
+#[async_trait]
+impl StoreDriver for SlowHasStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store.has_with_results(keys, results).await?;
+        self.has_calls.fetch_add(1, Ordering::Relaxed);
+        if self.hold_next_has.swap(false, Ordering::Relaxed) {
+            self.release_has.notified().await;
+        }
+        Ok(())
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.inner_store.update(key, reader, size_info).await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        self.inner_store.get_part(key, writer, offset, length).await
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(SlowHasStore);
+
+#[nativelink_test]
+async fn lookup_racing_upload_does_not_remember_missing_test() -> Result<(), Error> {
+    const VALUE: &str = "123";
+    let config = ExistenceCacheStoreConfig {
+        backend: StoreConfig::noop, // Note: Not used.
+        eviction_policy: Default::default(),
+        cache_missing_for_s: 60,
+        max_backend_batch_size: 0,
+    };
+    let slow_store = Arc::new(SlowHasStore {
+        inner_store: Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+        hold_next_has: AtomicBool::new(true),
+        has_calls: AtomicUsize::new(0),
+        release_has: Notify::new(),
+    });
+    let store = Store::new(ExistenceCacheStore::new(
+        &config,
+        Store::new(slow_store.clone()),
+    ));
+    let digest = DigestInfo::try_new(VALID_HASH1, 3).unwrap();
+
+    // The backend answers the lookup with "missing", but the answer only
+    // arrives after the digest was uploaded.
+    let lookup = spawn!("existence_store_test_lookup", {
+        let store = store.clone();
+        async move { store.has(digest).await }
+    });
+    while slow_store.has_calls.load(Ordering::Relaxed) == 0 {
+        tokio::task::yield_now().await;
+    }
+    // The size is not known up front, so the upload does not fill the
+    // existence cache.
+    let (mut tx, rx) = make_buf_channel_pair();
+    tx.send(VALUE.into()).await?;
+    tx.send_eof()?;
+    store
+        .update(digest, rx, UploadSizeInfo::MaxSize(VALUE.len()))
+        .await
+        .err_tip(|| "Failed to update store")?;
+    slow_store.release_has.notify_one();
+    assert_eq!(lookup.await.err_tip(|| "Lookup panicked")??, None);
+
+    assert_eq!(
+        store.has(digest).await?,
+        Some(VALUE.len()),
+        "Expected the stale lookup to not be remembered as missing"
+    );
+    Ok(())
+}
@@ -26,7 +26,7 @@ use nativelink_store::noop_store::NoopStore;
 use nativelink_util::buf_channel::make_buf_channel_pair;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
-use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, StoreOptimizations};
 use pretty_assertions::assert_eq;
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
@@ -48,6 +48,8 @@ fn make_stores() -> (Store, Store, Store) {
             slow: nativelink_config::stores::StoreConfig::memory(
                 nativelink_config::stores::MemoryStore::default(),
             ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         fast_store.clone(),
         slow_store.clone(),
@@ -343,6 +345,8 @@ async fn drop_on_eof_completes_store_futures() -> Result<(), Error> {
             slow: nativelink_config::stores::StoreConfig::memory(
                 nativelink_config::stores::MemoryStore::default(),
             ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         fast_store,
         slow_store,
@@ -392,6 +396,8 @@ async fn ignore_value_in_fast_store() -> Result<(), Error> {
             slow: nativelink_config::stores::StoreConfig::memory(
                 nativelink_config::stores::MemoryStore::default(),
             ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         fast_store.clone(),
         slow_store,
@@ -419,6 +425,8 @@ async fn has_checks_fast_store_when_noop() -> Result<(), Error> {
             nativelink_config::stores::MemoryStore::default(),
         ),
         slow: nativelink_config::stores::StoreConfig::noop,
+        fast_store_max_populate_size: 0,
+        fast_store_max_populate_bytes: None,
     };
     let fast_slow_store = Arc::new(FastSlowStore::new(
         &fast_slow_store_config,
@@ -453,3 +461,326 @@ async fn has_checks_fast_store_when_noop() -> Result<(), Error> {
     );
     Ok(())
 }
+
+#[nativelink_test]
+async fn get_part_skips_noop_slow_store_on_fast_miss_test() -> Result<(), Error> {
+    struct CountingNoopStore {
+        calls: AtomicBool,
+    }
+
+    #[async_trait]
+    impl StoreDriver for CountingNoopStore {
+        async fn has_with_results(
+            self: Pin<&Self>,
+            _digests: &[StoreKey<'_>],
+            _results: &mut [Option<usize>],
+        ) -> Result<(), Error> {
+            self.calls.store(true, Ordering::Release);
+            Ok(())
+        }
+
+        async fn update(
+            self: Pin<&Self>,
+            _digest: StoreKey<'_>,
+            mut reader: nativelink_util::buf_channel::DropCloserReadHalf,
+            _size_info: nativelink_util::store_trait::UploadSizeInfo,
+        ) -> Result<(), Error> {
+            reader.drain().await
+        }
+
+        fn optimized_for(&self, optimization: StoreOptimizations) -> bool {
+            optimization == StoreOptimizations::NoopDownloads
+        }
+
+        async fn get_part(
+            self: Pin<&Self>,
+            _key: StoreKey<'_>,
+            _writer: &mut nativelink_util::buf_channel::DropCloserWriteHalf,
+            _offset: usize,
+            _length: Option<usize>,
+        ) -> Result<(), Error> {
+            self.calls.store(true, Ordering::Release);
+            Err(make_err!(Code::NotFound, "Not found in noop store"))
+        }
+
+        fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+            self
+        }
+
+        fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+            self
+        }
+
+        fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+            self
+        }
+
+        fn register_metrics(
+            self: Arc<Self>,
+            _registry: &mut nativelink_util::metrics_utils::Registry,
+        ) {
+        }
+    }
+
+    default_health_status_indicator!(CountingNoopStore);
+
+    let slow_store = Arc::new(CountingNoopStore {
+        calls: AtomicBool::new(false),
+    });
+    let fast_slow_store = FastSlowStore::new(
+        &nativelink_config::stores::FastSlowStore {
+            fast: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            slow: nativelink_config::stores::StoreConfig::noop,
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
+        },
+        Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        )),
+        Store::new(slow_store.clone()),
+    );
+
+    let digest = DigestInfo::try_new(VALID_HASH, 100).unwrap();
+    let err = fast_slow_store
+        .get_part_unchunked(digest, 0, None)
+        .await
+        .expect_err("Expected fast store miss to fail");
+    assert_eq!(err.code, Code::NotFound);
+    assert!(
+        !slow_store.calls.load(Ordering::Acquire),
+        "Expected slow store to not be called"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn populate_fast_store_reports_partial_bytes_on_failure_test() -> Result<(), Error> {
+    /// Slow store that sends the first `sent_before_failure` bytes of the
+    /// requested data and then fails. If all of the data was sent, the EOF is
+    /// sent too, so the fast store commits the object before the failure.
+    struct PartialFailureStore {
+        sent_before_failure: usize,
+    }
+
+    #[async_trait]
+    impl StoreDriver for PartialFailureStore {
+        async fn has_with_results(
+            self: Pin<&Self>,
+            digests: &[StoreKey<'_>],
+            results: &mut [Option<usize>],
+        ) -> Result<(), Error> {
+            for (digest, result) in digests.iter().zip(results.iter_mut()) {
+                *result = Some(digest.borrow().into_digest().size_bytes as usize);
+            }
+            Ok(())
+        }
+
+        async fn update(
+            self: Pin<&Self>,
+            _digest: StoreKey<'_>,
+            mut reader: nativelink_util::buf_channel::DropCloserReadHalf,
+            _size_info: nativelink_util::store_trait::UploadSizeInfo,
+        ) -> Result<(), Error> {
+            reader.drain().await
+        }
+
+        async fn get_part(
+            self: Pin<&Self>,
+            key: StoreKey<'_>,
+            writer: &mut nativelink_util::buf_channel::DropCloserWriteHalf,
+            _offset: usize,
+            _length: Option<usize>,
+        ) -> Result<(), Error> {
+            writer
+                .send(Bytes::from(vec![0_u8; self.sent_before_failure]))
+                .await?;
+            if self.sent_before_failure == key.into_digest().size_bytes as usize {
+                writer.send_eof()?;
+            }
+            Err(make_err!(Code::Unavailable, "Slow store went away"))
+        }
+
+        fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+            self
+        }
+
+        fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+            self
+        }
+
+        fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+            self
+        }
+    }
+
+    default_health_status_indicator!(PartialFailureStore);
+
+    async fn populate_with_failure(
+        sent_before_failure: usize,
+        expect_committed: bool,
+    ) -> Result<(), Error> {
+        let fast_store = Store::new(MemoryStore::new(
+            &nativelink_config::stores::MemoryStore::default(),
+        ));
+        let fast_slow_store = FastSlowStore::new(
+            &nativelink_config::stores::FastSlowStore {
+                fast: nativelink_config::stores::StoreConfig::memory(
+                    nativelink_config::stores::MemoryStore::default(),
+                ),
+                slow: nativelink_config::stores::StoreConfig::noop,
+                fast_store_max_populate_size: 0,
+                fast_store_max_populate_bytes: None,
+            },
+            fast_store.clone(),
+            Store::new(Arc::new(PartialFailureStore {
+                sent_before_failure,
+            })),
+        );
+        let digest = DigestInfo::try_new(VALID_HASH, 100)?;
+
+        let err = fast_slow_store
+            .populate_fast_store(digest.into())
+            .await
+            .expect_err("Expected populate_fast_store to fail");
+        assert_eq!(err.bytes_populated, sent_before_failure as u64);
+        assert_eq!(fast_store.has(digest).await?.is_some(), expect_committed);
+        let expected_message = format!("After populating {sent_before_failure} bytes");
+        assert!(
+            Error::from(err)
+                .message_string()
+                .contains(&expected_message),
+            "Expected converted error to mention the populated bytes"
+        );
+        Ok(())
+    }
+
+    // Partial data is counted, but never committed to the fast store...
+    populate_with_failure(40, false).await?;
+    // ...unless the whole object was sent before the failure.
+    populate_with_failure(100, true).await?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn large_reads_skip_populating_fast_store_test() -> Result<(), Error> {
+    const MAX_POPULATE_SIZE: usize = 100;
+    let fast_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let slow_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let fast_slow_store = Store::new(FastSlowStore::new(
+        &nativelink_config::stores::FastSlowStore {
+            fast: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            slow: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            fast_store_max_populate_size: MAX_POPULATE_SIZE,
+            fast_store_max_populate_bytes: None,
+        },
+        fast_store.clone(),
+        slow_store.clone(),
+    ));
+
+    let small_data = make_random_data(MAX_POPULATE_SIZE);
+    let small_digest = DigestInfo::try_new(VALID_HASH, small_data.len())?;
+    slow_store
+        .update_oneshot(small_digest, small_data.clone().into())
+        .await?;
+    let large_data = make_random_data(MAX_POPULATE_SIZE + 1);
+    let large_digest = DigestInfo::try_new(VALID_HASH, large_data.len())?;
+    slow_store
+        .update_oneshot(large_digest, large_data.clone().into())
+        .await?;
+
+    assert_eq!(
+        fast_slow_store
+            .get_part_unchunked(small_digest, 0, None)
+            .await?,
+        small_data
+    );
+    assert_eq!(
+        fast_store.has(small_digest).await?,
+        Some(small_data.len()),
+        "Expected small object to be copied into the fast store"
+    );
+
+    assert_eq!(
+        fast_slow_store
+            .get_part_unchunked(large_digest, 0, None)
+            .await?,
+        large_data
+    );
+    assert_eq!(
+        fast_store.has(large_digest).await?,
+        None,
+        "Expected large object to not be copied into the fast store"
+    );
+
+    // Ranged reads of large objects are also served directly from the slow store.
+    for (offset, length) in [(1, Some(10)), (MAX_POPULATE_SIZE - 5, None), (3, Some(0))] {
+        let end = length.map_or(large_data.len(), |length| offset + length);
+        assert_eq!(
+            fast_slow_store
+                .get_part_unchunked(large_digest, offset, length)
+                .await?,
+            large_data[offset..end],
+            "Expected ranged read at {offset} - {length:?} to match"
+        );
+    }
+    assert_eq!(
+        fast_store.has(large_digest).await?,
+        None,
+        "Expected ranged reads to not copy the large object into the fast store"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn max_populate_bytes_skips_populating_fast_store_test() -> Result<(), Error> {
+    const MAX_POPULATE_BYTES: u64 = 100;
+    let fast_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let slow_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let fast_slow_store = Store::new(FastSlowStore::new(
+        &nativelink_config::stores::FastSlowStore {
+            fast: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            slow: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: Some(MAX_POPULATE_BYTES),
+        },
+        fast_store.clone(),
+        slow_store.clone(),
+    ));
+
+    let large_data = make_random_data(MAX_POPULATE_BYTES as usize + 1);
+    let large_digest = DigestInfo::try_new(VALID_HASH, large_data.len())?;
+    slow_store
+        .update_oneshot(large_digest, large_data.clone().into())
+        .await?;
+
+    assert_eq!(
+        fast_slow_store
+            .get_part_unchunked(large_digest, 1, Some(10))
+            .await?,
+        large_data[1..11]
+    );
+    assert_eq!(
+        fast_store.has(large_digest).await?,
+        None,
+        "Expected large object to not be copied into the fast store"
+    );
+    Ok(())
+}
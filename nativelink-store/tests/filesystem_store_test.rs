@@ -18,6 +18,7 @@ use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
 use std::ops::DerefMut;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
@@ -27,18 +28,19 @@ use bytes::Bytes;
 use filetime::{set_file_atime, FileTime};
 use futures::executor::block_on;
 use futures::task::Poll;
-use futures::{poll, Future, FutureExt};
+use futures::{join, poll, Future, FutureExt};
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
+use nativelink_store::cas_utils::digest_from_key;
 use nativelink_store::fast_slow_store::FastSlowStore;
 use nativelink_store::filesystem_store::{
-    digest_from_filename, EncodedFilePath, FileEntry, FileEntryImpl, FilesystemStore,
+    EncodedFilePath, FileEntry, FileEntryImpl, FilesystemStore,
 };
 use nativelink_util::buf_channel::make_buf_channel_pair;
 use nativelink_util::common::{fs, DigestInfo};
 use nativelink_util::evicting_map::LenEntry;
 use nativelink_util::origin_context::ContextAwareFuture;
-use nativelink_util::store_trait::{Store, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreLike, UploadSizeInfo};
 use nativelink_util::{background_spawn, spawn};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
@@ -824,8 +826,7 @@ async fn eviction_on_insert_calls_unref_once() -> Result<(), Error> {
         fn on_unref<Fe: FileEntry>(file_entry: &Fe) {
             block_on(file_entry.get_file_path_locked(move |path_str| async move {
                 let path = Path::new(&path_str);
-                let digest =
-                    digest_from_filename(path.file_name().unwrap().to_str().unwrap()).unwrap();
+                let digest = digest_from_key(path.file_name().unwrap().to_str().unwrap()).unwrap();
                 UNREFED_DIGESTS.lock().push(digest);
                 Ok(())
             }))
@@ -1112,23 +1113,23 @@ async fn has_with_results_on_zero_digests() -> Result<(), Error> {
     let content_path = make_temp_path("content_path");
     let temp_path = make_temp_path("temp_path");
 
-    let store = Arc::new(
-        FilesystemStore::<FileEntryImpl>::new_with_timeout_and_rename_fn(
-            &nativelink_config::stores::FilesystemStore {
-                content_path: content_path.clone(),
-                temp_path: temp_path.clone(),
-                read_buffer_size: 1,
-                ..Default::default()
-            },
-            |_| sleep(Duration::ZERO),
-            |from, to| std::fs::rename(from, to),
-        )
-        .await?,
-    );
+    let store = FilesystemStore::<FileEntryImpl>::new_with_timeout_and_rename_fn(
+        &nativelink_config::stores::FilesystemStore {
+            content_path: content_path.clone(),
+            temp_path: temp_path.clone(),
+            read_buffer_size: 1,
+            ..Default::default()
+        },
+        |_| sleep(Duration::ZERO),
+        |from, to| std::fs::rename(from, to),
+    )
+    .await?;
 
     let keys = vec![digest.into()];
     let mut results = vec![None];
-    let _ = store
+    // Calls the driver directly, `StoreLike` would answer for the empty
+    // digest without reaching the filesystem.
+    let _ = Pin::new(store.as_ref())
         .has_with_results(&keys, &mut results)
         .await
         .err_tip(|| "Failed to get_part");
@@ -1307,6 +1308,77 @@ async fn deleted_file_removed_from_store() -> Result<(), Error> {
     Ok(())
 }
 
+#[serial]
+#[nativelink_test]
+async fn get_tail_returns_last_bytes() -> Result<(), Error> {
+    const VALUE1: &str = "123456789";
+    let digest = DigestInfo::try_new(HASH1, VALUE1.len())?;
+    let store =
+        FilesystemStore::<FileEntryImpl>::new(&nativelink_config::stores::FilesystemStore {
+            content_path: make_temp_path("content_path"),
+            temp_path: make_temp_path("temp_path"),
+            read_buffer_size: 1,
+            ..Default::default()
+        })
+        .await?;
+    store.update_oneshot(digest, VALUE1.into()).await?;
+
+    for (length, expected) in [(3, "789"), (0, ""), (VALUE1.len(), VALUE1), (100, VALUE1)] {
+        let (tx, mut rx) = make_buf_channel_pair();
+        let (get_res, data_res) = join!(store.get_tail(digest, tx, length), rx.consume(None));
+        get_res?;
+        assert_eq!(
+            data_res?,
+            expected.as_bytes(),
+            "Wrong tail for length {length}"
+        );
+    }
+
+    let missing_digest = DigestInfo::try_new(HASH2, VALUE1.len())?;
+    let (tx, _rx) = make_buf_channel_pair();
+    let err = store
+        .get_tail(missing_digest, tx, 3)
+        .await
+        .expect_err("Expected get_tail to fail on missing digest");
+    assert_eq!(err.code, Code::NotFound);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn list_digests_with_prefix_test() -> Result<(), Error> {
+    const VALUE: &str = "123";
+    let store =
+        FilesystemStore::<FileEntryImpl>::new(&nativelink_config::stores::FilesystemStore {
+            content_path: make_temp_path("content_path"),
+            temp_path: make_temp_path("temp_path"),
+            ..Default::default()
+        })
+        .await?;
+    let digests = [
+        DigestInfo::try_new(&"aa".repeat(32), VALUE.len())?,
+        DigestInfo::try_new(&format!("ab{}", "00".repeat(31)), VALUE.len())?,
+        DigestInfo::try_new(&"bb".repeat(32), VALUE.len())?,
+    ];
+    for digest in digests {
+        store.update_oneshot(digest, VALUE.into()).await?;
+    }
+
+    let store = &store;
+    let list = |prefix| async move {
+        let mut digests = store
+            .list_digests(prefix)
+            .collect::<Result<Vec<_>, Error>>()
+            .await?;
+        digests.sort_unstable();
+        Result::<Vec<DigestInfo>, Error>::Ok(digests)
+    };
+    assert_eq!(list("").await?, digests.to_vec());
+    assert_eq!(list("a").await?, digests[..2].to_vec());
+    assert_eq!(list("aa").await?, digests[..1].to_vec());
+    assert_eq!(list("c").await?, vec![]);
+    Ok(())
+}
+
 // Ensure that get_file_size() returns the correct number
 // ceil(content length / block_size) * block_size
 // assume block size 4K
@@ -1426,6 +1498,8 @@ async fn update_with_whole_file_slow_path_when_low_file_descriptors() -> Result<
             slow: nativelink_config::stores::StoreConfig::memory(
                 nativelink_config::stores::MemoryStore::default(),
             ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         Store::new(
             FilesystemStore::<FileEntryImpl>::new(&nativelink_config::stores::FilesystemStore {
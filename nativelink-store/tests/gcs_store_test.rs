@@ -0,0 +1,493 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::join_all;
+use futures::join;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use nativelink_error::{Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_store::gcs_store::GcsStore;
+use nativelink_util::background_spawn;
+use nativelink_util::buf_channel::make_buf_channel_pair;
+use nativelink_util::common::DigestInfo;
+use nativelink_util::store_trait::{StoreLike, UploadSizeInfo};
+use parking_lot::Mutex;
+use pretty_assertions::assert_eq;
+use tokio::time::sleep;
+
+const BUCKET_NAME: &str = "dummy-bucket-name";
+const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
+const CHUNK_SIZE: usize = 256 * 1024;
+const TOKEN_PATH: &str = "/computeMetadata/v1/instance/service-accounts/default/token";
+const ACCESS_TOKEN: &str = "dummy-access-token";
+
+/// An in-memory stand-in for the subset of the GCS JSON API used by
+/// `GcsStore`.
+#[derive(Default)]
+struct FakeGcs {
+    objects: HashMap<String, Vec<u8>>,
+    sessions: HashMap<String, (String, Vec<u8>)>,
+    requests: Vec<String>,
+    // Number of upload requests of which only the first half is persisted.
+    partially_persisted_chunks: usize,
+    // Number of access tokens handed out by the metadata server.
+    token_requests: usize,
+    // `Authorization` header of every request to the GCS API.
+    authorizations: Vec<Option<String>>,
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            decoded.push(u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| percent_decode(value))
+    })
+}
+
+fn header(req: &Request<Body>, name: &str) -> Option<String> {
+    Some(req.headers().get(name)?.to_str().unwrap().to_string())
+}
+
+fn respond(status: u16, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+async fn handle(state: Arc<Mutex<FakeGcs>>, req: Request<Body>) -> Response<Body> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    state
+        .lock()
+        .requests
+        .push(format!("{method} {}", req.uri()));
+    let object_prefix = format!("/storage/v1/b/{BUCKET_NAME}/o/");
+    let upload_path = format!("/upload/storage/v1/b/{BUCKET_NAME}/o");
+
+    if path == TOKEN_PATH {
+        assert_eq!(header(&req, "metadata-flavor").as_deref(), Some("Google"));
+        state.lock().token_requests += 1;
+        // Give concurrent requests a chance to ask for a token as well.
+        sleep(Duration::from_millis(10)).await;
+        return respond(
+            200,
+            format!(r#"{{"access_token":"{ACCESS_TOKEN}","expires_in":3600}}"#),
+        );
+    }
+    state
+        .lock()
+        .authorizations
+        .push(header(&req, "authorization"));
+
+    if method == Method::GET && path.starts_with(&object_prefix) {
+        let name = percent_decode(&path[object_prefix.len()..]);
+        let Some(data) = state.lock().objects.get(&name).cloned() else {
+            return respond(404, "");
+        };
+        if query_param(&req, "alt").as_deref() != Some("media") {
+            return respond(
+                200,
+                format!(r#"{{"name":"{name}","size":"{}"}}"#, data.len()),
+            );
+        }
+        let range = header(&req, "range").unwrap();
+        let (start, end) = range
+            .strip_prefix("bytes=")
+            .unwrap()
+            .split_once('-')
+            .unwrap();
+        let start: usize = start.parse().unwrap();
+        let end = if end.is_empty() {
+            data.len()
+        } else {
+            end.parse::<usize>().unwrap() + 1
+        };
+        return respond(206, data[start..end.min(data.len())].to_vec());
+    }
+
+    if method == Method::POST && path == upload_path {
+        let name = query_param(&req, "name").unwrap();
+        match query_param(&req, "uploadType").as_deref() {
+            Some("media") => {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                state.lock().objects.insert(name, body.to_vec());
+                return respond(200, "{}");
+            }
+            Some("resumable") => {
+                let host = header(&req, "host").unwrap();
+                let mut state = state.lock();
+                let session_id = format!("session-{}", state.sessions.len());
+                state
+                    .sessions
+                    .insert(session_id.clone(), (name, Vec::new()));
+                return Response::builder()
+                    .status(200)
+                    .header("location", format!("http://{host}/upload/{session_id}"))
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            _ => return respond(400, ""),
+        }
+    }
+
+    if let Some(session_id) = path.strip_prefix("/upload/session-") {
+        let session_id = format!("session-{session_id}");
+        if method == Method::DELETE {
+            state.lock().sessions.remove(&session_id);
+            return respond(499, "");
+        }
+        let content_range = header(&req, "content-range").unwrap();
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let mut state = state.lock();
+        let partially_persisted = state.partially_persisted_chunks > 0 && !body.is_empty();
+        if partially_persisted {
+            state.partially_persisted_chunks -= 1;
+        }
+        let Some((_, data)) = state.sessions.get_mut(&session_id) else {
+            return respond(404, "");
+        };
+        if let Some((start, _)) = content_range
+            .strip_prefix("bytes ")
+            .and_then(|range| range.split_once('-'))
+        {
+            assert_eq!(
+                start.parse::<usize>().unwrap(),
+                data.len(),
+                "Expected upload to resume where the persisted data ends"
+            );
+        }
+        if partially_persisted {
+            data.extend_from_slice(&body[..body.len() / 2]);
+        } else {
+            data.extend_from_slice(&body);
+        }
+        if partially_persisted || content_range.ends_with("/*") {
+            let mut response = Response::builder().status(308);
+            if !data.is_empty() {
+                response = response.header("range", format!("bytes=0-{}", data.len() - 1));
+            }
+            return response.body(Body::empty()).unwrap();
+        }
+        let (name, data) = state.sessions.remove(&session_id).unwrap();
+        assert_eq!(
+            content_range.rsplit('/').next().unwrap(),
+            data.len().to_string()
+        );
+        state.objects.insert(name, data);
+        return respond(200, "{}");
+    }
+
+    respond(400, "")
+}
+
+async fn start_fake_gcs() -> (SocketAddr, Arc<Mutex<FakeGcs>>) {
+    let state = Arc::new(Mutex::new(FakeGcs::default()));
+    let service_state = state.clone();
+    let make_service = make_service_fn(move |_| {
+        let state = service_state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(state, req).await) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    background_spawn!("fake_gcs_server", async move {
+        server.await.unwrap();
+    });
+    (addr, state)
+}
+
+fn make_store(addr: SocketAddr, key_prefix: Option<String>) -> Result<Arc<GcsStore>, Error> {
+    GcsStore::new_with_jitter(
+        &nativelink_config::stores::GcsStore {
+            bucket: BUCKET_NAME.to_string(),
+            key_prefix,
+            endpoint: Some(format!("http://{addr}")),
+            resumable_chunk_size: CHUNK_SIZE,
+            anonymous_access: true,
+            insecure_allow_http: true,
+            ..Default::default()
+        },
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )
+}
+
+#[nativelink_test]
+async fn simple_upload_and_has_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    let store = make_store(addr, None)?;
+    let digest = DigestInfo::try_new(VALID_HASH1, 11)?;
+
+    assert_eq!(store.has(digest).await, Ok(None));
+    store
+        .update_oneshot(digest, Bytes::from_static(b"hello world"))
+        .await?;
+    assert_eq!(store.has(digest).await, Ok(Some(11)));
+    assert_eq!(
+        state.lock().objects.get(&format!("{VALID_HASH1}-11")),
+        Some(&b"hello world".to_vec())
+    );
+    assert!(
+        state
+            .lock()
+            .requests
+            .iter()
+            .any(|request| request.contains("uploadType=media")),
+        "Expected a single request upload"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn resumable_upload_of_large_object_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    let store = make_store(addr, None)?;
+    let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 1000)).map(|i| i as u8).collect();
+    let digest = DigestInfo::try_new(VALID_HASH1, data.len())?;
+
+    store
+        .update_oneshot(digest, Bytes::from(data.clone()))
+        .await?;
+
+    let state = state.lock();
+    assert_eq!(
+        state.objects.get(&format!("{VALID_HASH1}-{}", data.len())),
+        Some(&data)
+    );
+    let chunk_requests = state
+        .requests
+        .iter()
+        .filter(|request| request.starts_with("PUT"))
+        .count();
+    assert_eq!(chunk_requests, 3, "Expected one request per chunk");
+    Ok(())
+}
+
+#[nativelink_test]
+async fn resumable_upload_resumes_from_persisted_offset_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    let store = make_store(addr, None)?;
+    let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 1000))
+        .map(|i| (i % 13) as u8)
+        .collect();
+    let digest = DigestInfo::try_new(VALID_HASH1, data.len())?;
+    // GCS only persists part of the first chunk and the retry of its rest.
+    state.lock().partially_persisted_chunks = 2;
+
+    store
+        .update_oneshot(digest, Bytes::from(data.clone()))
+        .await?;
+
+    let state = state.lock();
+    assert_eq!(
+        state.objects.get(&format!("{VALID_HASH1}-{}", data.len())),
+        Some(&data)
+    );
+    let chunk_requests = state
+        .requests
+        .iter()
+        .filter(|request| request.starts_with("PUT"))
+        .count();
+    assert_eq!(
+        chunk_requests, 5,
+        "Expected the rest of the first chunk to be sent twice more"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn resumable_upload_of_unknown_size_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    let store = make_store(addr, None)?;
+    // Exactly two chunks, so the end of the upload is only found by
+    // peeking for the EOF.
+    let data: Vec<u8> = (0..(CHUNK_SIZE * 2)).map(|i| (i % 7) as u8).collect();
+    let digest = DigestInfo::try_new(VALID_HASH1, data.len())?;
+
+    let (mut tx, rx) = make_buf_channel_pair();
+    let send_data = data.clone();
+    let (upload_result, send_result) = join!(
+        store.update(digest, rx, UploadSizeInfo::MaxSize(CHUNK_SIZE * 4)),
+        async move {
+            for chunk in send_data.chunks(100_000) {
+                tx.send(Bytes::copy_from_slice(chunk)).await?;
+            }
+            tx.send_eof()
+        }
+    );
+    upload_result?;
+    send_result?;
+
+    let state = state.lock();
+    assert_eq!(
+        state.objects.get(&format!("{VALID_HASH1}-{}", data.len())),
+        Some(&data)
+    );
+    assert!(
+        state.sessions.is_empty(),
+        "Expected upload session to finish"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_part_reads_requested_range_test() -> Result<(), Error> {
+    let (addr, _state) = start_fake_gcs().await;
+    let store = make_store(addr, None)?;
+    let digest = DigestInfo::try_new(VALID_HASH1, 10)?;
+    store
+        .update_oneshot(digest, Bytes::from_static(b"0123456789"))
+        .await?;
+
+    assert_eq!(
+        store.get_part_unchunked(digest, 2, Some(5)).await?,
+        Bytes::from_static(b"23456")
+    );
+    assert_eq!(
+        store.get_part_unchunked(digest, 7, None).await?,
+        Bytes::from_static(b"789")
+    );
+    assert_eq!(
+        store.get_part_unchunked(digest, 0, Some(0)).await?,
+        Bytes::new()
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn key_prefix_and_missing_object_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    let store = make_store(addr, Some("cas/".to_string()))?;
+    let digest = DigestInfo::try_new(VALID_HASH1, 3)?;
+
+    let err = store.get_part_unchunked(digest, 0, None).await.unwrap_err();
+    assert_eq!(err.code, Code::NotFound, "{err:?}");
+
+    store
+        .update_oneshot(digest, Bytes::from_static(b"abc"))
+        .await?;
+    assert!(state
+        .lock()
+        .objects
+        .contains_key(&format!("cas/{VALID_HASH1}-3")));
+    assert_eq!(
+        store.get_part_unchunked(digest, 0, None).await?,
+        Bytes::from_static(b"abc")
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn rejects_unaligned_chunk_size_test() -> Result<(), Error> {
+    let result = GcsStore::new_with_jitter(
+        &nativelink_config::stores::GcsStore {
+            bucket: BUCKET_NAME.to_string(),
+            resumable_chunk_size: CHUNK_SIZE + 1,
+            ..Default::default()
+        },
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    );
+    assert_eq!(result.err().map(|e| e.code), Some(Code::InvalidArgument));
+    Ok(())
+}
+
+fn make_authenticated_store(
+    addr: SocketAddr,
+    insecure_allow_http: bool,
+) -> Result<Arc<GcsStore>, Error> {
+    GcsStore::new_with_jitter_and_metadata_host(
+        &nativelink_config::stores::GcsStore {
+            bucket: BUCKET_NAME.to_string(),
+            endpoint: Some(format!("http://{addr}")),
+            insecure_allow_http,
+            ..Default::default()
+        },
+        Arc::new(move |_delay| Duration::from_secs(0)),
+        addr.to_string(),
+    )
+}
+
+#[nativelink_test]
+async fn authenticated_requests_share_one_access_token_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    let store = make_authenticated_store(addr, true)?;
+    let digest = DigestInfo::try_new(VALID_HASH1, 11)?;
+
+    let results = join_all((0..10).map(|_| store.has(digest))).await;
+    for result in results {
+        assert_eq!(result, Ok(None));
+    }
+
+    let state = state.lock();
+    assert_eq!(
+        state.token_requests, 1,
+        "Expected concurrent requests to share a single token refresh"
+    );
+    assert_eq!(state.authorizations.len(), 10);
+    for authorization in &state.authorizations {
+        assert_eq!(
+            authorization.as_deref(),
+            Some(format!("Bearer {ACCESS_TOKEN}").as_str())
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn access_token_is_fetched_over_http_when_https_only_test() -> Result<(), Error> {
+    let (addr, state) = start_fake_gcs().await;
+    // The fake GCS API is only served over HTTP, so the request itself is
+    // rejected, but only after the token was fetched from the metadata
+    // server over plain HTTP.
+    let store = make_authenticated_store(addr, false)?;
+    let digest = DigestInfo::try_new(VALID_HASH1, 11)?;
+
+    let err = store.has(digest).await.unwrap_err();
+    assert!(
+        err.messages
+            .iter()
+            .all(|message| !message.contains("access token")),
+        "Expected the access token fetch to succeed, got {err:?}"
+    );
+    assert_eq!(state.lock().token_requests, 1);
+    assert!(state.lock().authorizations.is_empty());
+    Ok(())
+}
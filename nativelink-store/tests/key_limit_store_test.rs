@@ -0,0 +1,62 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use nativelink_config::stores::{KeyLimitStore as KeyLimitStoreConfig, StoreConfig};
+use nativelink_error::{Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_store::key_limit_store::KeyLimitStore;
+use nativelink_store::memory_store::MemoryStore;
+use nativelink_util::common::DigestInfo;
+use nativelink_util::store_trait::{Store, StoreLike};
+use pretty_assertions::assert_eq;
+
+const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
+const VALID_HASH2: &str = "0123456789abcdef000000000000000000020000000000000123456789abcdef";
+const VALID_HASH3: &str = "0123456789abcdef000000000000000000030000000000000123456789abcdef";
+const VALUE: &str = "123";
+
+#[nativelink_test]
+async fn new_keys_rejected_once_limit_reached_test() -> Result<(), Error> {
+    let config = KeyLimitStoreConfig {
+        backend: StoreConfig::memory(nativelink_config::stores::MemoryStore::default()),
+        max_keys: 2,
+    };
+    let inner_store = Store::new(MemoryStore::new(
+        &nativelink_config::stores::MemoryStore::default(),
+    ));
+    let store = Store::new(KeyLimitStore::new(&config, inner_store.clone()));
+    let digest1 = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    let digest2 = DigestInfo::try_new(VALID_HASH2, VALUE.len())?;
+    let digest3 = DigestInfo::try_new(VALID_HASH3, VALUE.len())?;
+
+    store.update_oneshot(digest1, VALUE.into()).await?;
+    store.update_oneshot(digest2, VALUE.into()).await?;
+
+    let err = store
+        .update_oneshot(digest3, VALUE.into())
+        .await
+        .expect_err("Expected upload of a new key past the limit to fail");
+    assert_eq!(err.code, Code::ResourceExhausted);
+    assert_eq!(
+        inner_store.has(digest3).await?,
+        None,
+        "Rejected key should not reach the backend"
+    );
+
+    // Keys that were already written can still be overwritten.
+    store.update_oneshot(digest1, VALUE.into()).await?;
+    store.update_oneshot(digest2, VALUE.into()).await?;
+    assert_eq!(store.has(digest1).await?, Some(VALUE.len()));
+    Ok(())
+}
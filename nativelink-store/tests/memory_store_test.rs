@@ -12,11 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::ops::RangeBounds;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::{env, fs};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use futures::poll;
+use futures::{join, poll};
 use memory_stats::memory_stats;
 use nativelink_error::{Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
@@ -24,9 +27,16 @@ use nativelink_store::memory_store::MemoryStore;
 use nativelink_util::buf_channel::make_buf_channel_pair;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::spawn;
-use nativelink_util::store_trait::{Store, StoreKey, StoreLike};
+use nativelink_util::store_trait::{move_digest, Store, StoreKey, StoreLike, UploadSizeInfo};
+use parking_lot::Mutex;
 use pretty_assertions::assert_eq;
+use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
 
 const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
 const VALID_HASH2: &str = "0123456789abcdef000000000000000000020000000000000123456789abcdef";
@@ -128,6 +138,29 @@ async fn ensure_full_copy_of_bytes_is_made_test() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn update_rejects_size_mismatch_test() -> Result<(), Error> {
+    const VALUE: &str = "123";
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+
+    for declared_size in [VALUE.len() - 1, VALUE.len() + 1] {
+        let (mut tx, rx) = make_buf_channel_pair();
+        let (update_result, send_result) = join!(
+            store.update(digest, rx, UploadSizeInfo::ExactSize(declared_size)),
+            async move {
+                tx.send(VALUE.into()).await?;
+                tx.send_eof()
+            }
+        );
+        send_result?;
+        let err = update_result.expect_err("Expected size mismatch to be rejected");
+        assert_eq!(err.code, Code::InvalidArgument);
+        assert_eq!(store.has(digest).await?, None);
+    }
+    Ok(())
+}
+
 #[nativelink_test]
 async fn read_partial() -> Result<(), Error> {
     const VALUE1: &str = "1234";
@@ -149,6 +182,77 @@ async fn read_partial() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn get_tail_returns_last_bytes() -> Result<(), Error> {
+    const VALUE1: &str = "123456789";
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE1.len())?;
+    store.update_oneshot(digest, VALUE1.into()).await?;
+
+    for (length, expected) in [(3, "789"), (0, ""), (VALUE1.len(), VALUE1), (100, VALUE1)] {
+        let (tx, mut rx) = make_buf_channel_pair();
+        let (get_res, data_res) = join!(store.get_tail(digest, tx, length), rx.consume(None));
+        get_res?;
+        assert_eq!(
+            data_res?,
+            expected.as_bytes(),
+            "Wrong tail for length {length}"
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_part_verified_rejects_corrupt_data() -> Result<(), Error> {
+    const VALUE1: &str = "123456789";
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+
+    // Data that matches its digest is served as usual.
+    let valid_digest = DigestInfo::new(Sha256::digest(VALUE1).into(), VALUE1.len() as i64);
+    store.update_oneshot(valid_digest, VALUE1.into()).await?;
+    let (tx, mut rx) = make_buf_channel_pair();
+    let (get_res, data_res) = join!(
+        store.get_part_verified(valid_digest, tx, 0, None),
+        rx.consume(None)
+    );
+    get_res?;
+    assert_eq!(data_res?, VALUE1.as_bytes());
+
+    // VALID_HASH1 is not the hash of VALUE1, so this blob is corrupt.
+    let corrupt_digest = DigestInfo::try_new(VALID_HASH1, VALUE1.len())?;
+    store.update_oneshot(corrupt_digest, VALUE1.into()).await?;
+    let (tx, mut rx) = make_buf_channel_pair();
+    let (get_res, data_res) = join!(
+        store.get_part_verified(corrupt_digest, tx, 0, None),
+        async move {
+            let mut received = BytesMut::new();
+            loop {
+                match rx.recv().await {
+                    Ok(chunk) if chunk.is_empty() => return (received, Ok(())),
+                    Ok(chunk) => received.extend_from_slice(&chunk),
+                    Err(err) => return (received, Err(err)),
+                }
+            }
+        }
+    );
+    assert_eq!(get_res.map_err(|err| err.code), Err(Code::DataLoss));
+    let (received, stream_end) = data_res;
+    // All of the data is streamed before the error.
+    assert_eq!(received, VALUE1.as_bytes());
+    assert_eq!(stream_end.map_err(|err| err.code), Err(Code::DataLoss));
+
+    // Partial reads can not be checked, so they are served as usual.
+    let (tx, mut rx) = make_buf_channel_pair();
+    let (get_res, data_res) = join!(
+        store.get_part_verified(corrupt_digest, tx, 1, Some(3)),
+        rx.consume(None)
+    );
+    get_res?;
+    assert_eq!(data_res?, "234".as_bytes());
+    Ok(())
+}
+
 // A bug was found where reading an empty value from memory store would result in an error
 // due to internal EOF handling. This is an edge case test.
 #[nativelink_test]
@@ -462,3 +566,206 @@ async fn list_test() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[nativelink_test]
+async fn evicts_lru_entries_under_memory_pressure_test() -> Result<(), Error> {
+    const VALUE: &str = "1234";
+    let psi_dir = format!(
+        "{}/{}",
+        env::var("TEST_TMPDIR").unwrap_or(env::temp_dir().to_str().unwrap().to_string()),
+        thread_rng().gen::<u64>(),
+    );
+    fs::create_dir_all(&psi_dir)?;
+    let psi_path = format!("{psi_dir}/memory");
+    fs::write(
+        &psi_path,
+        "some avg10=1.00 avg60=0.00 avg300=0.00 total=0\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+    )?;
+
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore {
+        memory_pressure_eviction: Some(nativelink_config::stores::MemoryPressureEviction {
+            psi_path: Some(psi_path.clone()),
+            threshold: 50.0,
+            evict_percent: Some(50),
+            // Only checked manually by the test.
+            check_interval_s: Some(3600),
+        }),
+        ..Default::default()
+    });
+    let digests = [VALID_HASH1, VALID_HASH2, VALID_HASH3, VALID_HASH4]
+        .into_iter()
+        .map(|hash| DigestInfo::try_new(hash, VALUE.len()))
+        .collect::<Result<Vec<_>, _>>()?;
+    for digest in &digests {
+        store.update_oneshot(*digest, VALUE.into()).await?;
+    }
+
+    assert_eq!(
+        store.check_memory_pressure().await?,
+        0,
+        "Expected no eviction below the threshold"
+    );
+    assert_eq!(store.len_for_test().await, 4);
+
+    // Simulate the system coming under memory pressure.
+    fs::write(
+        &psi_path,
+        "some avg10=75.00 avg60=20.00 avg300=5.00 total=1000\nfull avg10=10.00 avg60=0.00 avg300=0.00 total=10\n",
+    )?;
+    assert_eq!(store.check_memory_pressure().await?, 2 * VALUE.len() as u64);
+    assert_eq!(store.len_for_test().await, 2);
+    assert_eq!(
+        store.has(digests[0]).await?,
+        None,
+        "Expected least recently used entry to be evicted"
+    );
+    assert_eq!(store.has(digests[3]).await?, Some(VALUE.len()));
+    Ok(())
+}
+
+#[nativelink_test]
+async fn evicts_lru_entries_over_max_count_test() -> Result<(), Error> {
+    const VALUE: &str = "1234";
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore {
+        eviction_policy: Some(nativelink_config::stores::EvictionPolicy {
+            // Far more than the entries need, so only `max_count` evicts.
+            max_bytes: 1024,
+            max_seconds: 3600,
+            max_count: 2,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let digests = [VALID_HASH1, VALID_HASH2, VALID_HASH3, VALID_HASH4]
+        .into_iter()
+        .map(|hash| DigestInfo::try_new(hash, VALUE.len()))
+        .collect::<Result<Vec<_>, _>>()?;
+    for digest in &digests {
+        store.update_oneshot(*digest, VALUE.into()).await?;
+    }
+
+    assert_eq!(store.len_for_test().await, 2);
+    assert_eq!(store.has(digests[0]).await?, None);
+    assert_eq!(store.has(digests[1]).await?, None);
+    assert_eq!(store.has(digests[2]).await?, Some(VALUE.len()));
+    assert_eq!(store.has(digests[3]).await?, Some(VALUE.len()));
+    Ok(())
+}
+
+#[nativelink_test]
+async fn move_digest_between_stores_test() -> Result<(), Error> {
+    const VALUE: &str = "promote me";
+    let src_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let dst_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    src_store.update_oneshot(digest, VALUE.into()).await?;
+
+    move_digest(src_store.as_ref(), dst_store.as_ref(), digest).await?;
+
+    assert_eq!(
+        src_store.has(digest).await?,
+        None,
+        "Expected source store to no longer have the digest"
+    );
+    assert_eq!(
+        dst_store.get_part_unchunked(digest, 0, None).await?,
+        VALUE.as_bytes(),
+        "Expected destination store to have the digest"
+    );
+
+    let err = move_digest(src_store.as_ref(), dst_store.as_ref(), digest)
+        .await
+        .expect_err("Expected moving a missing digest to fail");
+    assert_eq!(err.code, Code::NotFound);
+    assert!(dst_store.has(digest).await?.is_some());
+    Ok(())
+}
+
+#[nativelink_test]
+async fn exact_size_chunked_upload_test() -> Result<(), Error> {
+    const VALUE: &str = "chunked exact size upload";
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+
+    let (mut tx, rx) = make_buf_channel_pair();
+    let (update_result, send_result) = join!(
+        store.update(digest, rx, UploadSizeInfo::ExactSize(VALUE.len())),
+        async move {
+            for chunk in VALUE.as_bytes().chunks(4) {
+                tx.send(Bytes::copy_from_slice(chunk)).await?;
+            }
+            tx.send_eof()
+        }
+    );
+    update_result.merge(send_result)?;
+
+    assert_eq!(
+        store.get_part_unchunked(digest, 0, None).await?,
+        VALUE.as_bytes()
+    );
+    Ok(())
+}
+
+/// Captures the fields of every span, the same data an OpenTelemetry exporter
+/// receives from its tracing layer.
+#[derive(Clone, Default)]
+struct CapturingLayer {
+    spans: Arc<Mutex<HashMap<Id, HashMap<String, String>>>>,
+}
+
+struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for CapturingLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: Context<'_, S>) {
+        let mut fields = HashMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        self.spans.lock().insert(id.clone(), fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        if let Some(fields) = self.spans.lock().get_mut(id) {
+            values.record(&mut FieldVisitor(fields));
+        }
+    }
+}
+
+#[nativelink_test]
+async fn get_part_emits_store_operation_span_test() -> Result<(), Error> {
+    const VALUE: &str = "traced";
+    let layer = CapturingLayer::default();
+    let _subscriber_guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(layer.clone()));
+
+    let store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    store.update_oneshot(digest, VALUE.into()).await?;
+    layer.spans.lock().clear();
+
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let (get_result, data) = join!(store.get_part(digest, &mut tx, 0, None), rx.consume(None));
+    get_result?;
+    assert_eq!(data?, VALUE.as_bytes());
+
+    let spans = layer.spans.lock();
+    let get_part_span = spans
+        .values()
+        .find(|fields| fields.get("otel.name").map(String::as_str) == Some("store.get_part"))
+        .err_tip(|| "Expected a store.get_part span")?;
+    assert_eq!(
+        get_part_span.get("digest"),
+        Some(&format!("{VALID_HASH1}-{}", VALUE.len()))
+    );
+    assert_eq!(get_part_span.get("size"), Some(&VALUE.len().to_string()));
+    Ok(())
+}
@@ -0,0 +1,135 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use nativelink_config::stores::{MemoryStore as MemoryStoreConfig, StoreConfig};
+use nativelink_error::Error;
+use nativelink_macro::nativelink_test;
+use nativelink_store::memory_store::MemoryStore;
+use nativelink_store::prefetch_store::PrefetchStore;
+use nativelink_util::buf_channel::{DropCloserReadHalf, DropCloserWriteHalf};
+use nativelink_util::common::DigestInfo;
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use pretty_assertions::assert_eq;
+
+const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
+
+/// Backend that counts how many reads reach it.
+struct ReadCountingStore {
+    inner: Store,
+    reads: AtomicUsize,
+}
+
+#[async_trait]
+impl StoreDriver for ReadCountingStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        digests: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner.has_with_results(digests, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.inner.update(key, reader, size_info).await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.get_part(key, writer, offset, length).await
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &'_ dyn StoreDriver {
+        self
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(ReadCountingStore);
+
+#[nativelink_test]
+async fn sequential_reads_are_served_from_prefetched_data_test() -> Result<(), Error> {
+    const DATA_SIZE: usize = 100;
+    const CHUNK_SIZE: usize = 10;
+    let data: Vec<u8> = (0..DATA_SIZE as u8).collect();
+    let digest = DigestInfo::try_new(VALID_HASH1, DATA_SIZE)?;
+
+    let backend = Arc::new(ReadCountingStore {
+        inner: Store::new(MemoryStore::new(&MemoryStoreConfig::default())),
+        reads: AtomicUsize::new(0),
+    });
+    let store = Store::new(PrefetchStore::new(
+        &nativelink_config::stores::PrefetchStore {
+            backend: StoreConfig::noop,
+            prefetch_window: 40,
+            max_prefetched_keys: 0,
+        },
+        Store::new(backend.clone()),
+    ));
+    store.update_oneshot(digest, data.clone().into()).await?;
+
+    for offset in (0..DATA_SIZE).step_by(CHUNK_SIZE) {
+        let chunk = store
+            .get_part_unchunked(digest, offset, Some(CHUNK_SIZE))
+            .await?;
+        assert_eq!(chunk, data[offset..offset + CHUNK_SIZE]);
+    }
+
+    // One read for the first chunk, then prefetches of 10..50, 50..90 and
+    // 90..100. Without prefetching there would be one read per chunk.
+    assert_eq!(backend.reads.load(Ordering::Relaxed), 4);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn update_discards_prefetched_data_test() -> Result<(), Error> {
+    const KEY: &str = "foo";
+    let store = Store::new(PrefetchStore::new(
+        &nativelink_config::stores::PrefetchStore {
+            backend: StoreConfig::noop,
+            prefetch_window: 40,
+            max_prefetched_keys: 0,
+        },
+        Store::new(MemoryStore::new(&MemoryStoreConfig::default())),
+    ));
+    store.update_oneshot(KEY, "aaaabbbb".into()).await?;
+    assert_eq!(store.get_part_unchunked(KEY, 0, Some(4)).await?, "aaaa");
+
+    store.update_oneshot(KEY, "aaaacccc".into()).await?;
+    assert_eq!(store.get_part_unchunked(KEY, 4, Some(4)).await?, "cccc");
+    Ok(())
+}
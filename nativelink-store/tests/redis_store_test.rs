@@ -12,13 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use bytes::Bytes;
-use nativelink_error::Error;
+use futures::future;
+use nativelink_config::stores::Retry;
+use nativelink_error::{make_err, Code, Error};
 use nativelink_macro::nativelink_test;
 use nativelink_store::cas_utils::ZERO_BYTE_DIGESTS;
 use nativelink_store::redis_store::{BackgroundConnection, RedisStore};
 use nativelink_util::buf_channel::make_buf_channel_pair;
 use nativelink_util::common::DigestInfo;
+use nativelink_util::retry::Retrier;
 use nativelink_util::store_trait::{StoreLike, UploadSizeInfo};
 use pretty_assertions::assert_eq;
 use redis::{Pipeline, RedisError};
@@ -360,3 +366,43 @@ async fn yield_between_sending_packets_in_update() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[nativelink_test]
+async fn initial_connection_is_retried() -> Result<(), Error> {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let retrier = Retrier::new(
+        Arc::new(|_delay| Box::pin(future::ready(()))),
+        Arc::new(|delay| delay),
+        Retry {
+            max_retries: 3,
+            ..Default::default()
+        },
+    );
+
+    let store = MockRedisStore::new_with_conn_and_name_generator(
+        BackgroundConnection::with_retrying_initializer(
+            {
+                let attempts = attempts.clone();
+                move || {
+                    let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                    async move {
+                        if attempt == 0 {
+                            return Err(make_err!(Code::Unavailable, "Redis not ready yet"));
+                        }
+                        Ok::<_, Error>(MockRedisConnectionBuilder::new().build())
+                    }
+                }
+            },
+            retrier,
+        ),
+        mock_uuid_generator,
+    );
+
+    assert!(
+        store.get_conn().await.is_ok(),
+        "Expected connection to succeed after retrying"
+    );
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+
+    Ok(())
+}
@@ -12,26 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use aws_sdk_s3::config::{BehaviorVersion, Builder, Region};
+use aws_sdk_s3::config::{BehaviorVersion, Builder, Credentials, Region};
 use aws_sdk_s3::primitives::ByteStream;
 use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+use aws_smithy_runtime_api::client::http::{
+    http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector,
+};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
 use aws_smithy_types::body::SdkBody;
 use bytes::{BufMut, Bytes, BytesMut};
-use futures::join;
 use futures::task::Poll;
+use futures::{join, TryStreamExt};
 use http::header;
 use http::status::StatusCode;
 use hyper::Body;
-use nativelink_error::{make_input_err, Error, ResultExt};
+use nativelink_error::{make_input_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
 use nativelink_store::s3_store::S3Store;
 use nativelink_util::buf_channel::make_buf_channel_pair;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::spawn;
-use nativelink_util::store_trait::{StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{ConsistencyToken, StoreLike, UploadSizeInfo};
 use pretty_assertions::assert_eq;
 use sha2::{Digest, Sha256};
 
@@ -174,6 +179,76 @@ async fn simple_has_retries() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn has_with_consistency_token_waits_for_size() -> Result<(), Error> {
+    const CONTENT_LENGTH: u64 = 100;
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            http::Request::builder().body(SdkBody::empty()).unwrap(),
+            http::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            http::Request::builder().body(SdkBody::empty()).unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, "50")
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            http::Request::builder().body(SdkBody::empty()).unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, CONTENT_LENGTH.to_string())
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            http::Request::builder().body(SdkBody::empty()).unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, CONTENT_LENGTH.to_string())
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client)
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            retry: nativelink_config::stores::Retry {
+                max_retries: 1024,
+                delay: 0.,
+                jitter: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let digest = DigestInfo::try_new(VALID_HASH1, CONTENT_LENGTH)?;
+    let result = store
+        .has_with_consistency_token(digest, Some(ConsistencyToken::new(CONTENT_LENGTH)))
+        .await;
+    assert_eq!(
+        result,
+        Ok(Some(CONTENT_LENGTH as usize)),
+        "Expected to find item once it has the written size, got: {result:?}"
+    );
+    Ok(())
+}
+
 #[nativelink_test]
 async fn simple_update_ac() -> Result<(), Error> {
     const AC_ENTRY_SIZE: u64 = 199;
@@ -316,7 +391,7 @@ async fn smoke_test_get_part() -> Result<(), Error> {
                 .uri(format!(
                     "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{AC_ENTRY_SIZE}?x-id=GetObject",
                 ))
-                .header("range", format!("bytes={}-{}", OFFSET, OFFSET + LENGTH))
+                .header("range", format!("bytes={}-{}", OFFSET, OFFSET + LENGTH - 1))
                 .body(SdkBody::empty())
                 .unwrap(),
             http::Response::builder()
@@ -351,6 +426,127 @@ async fn smoke_test_get_part() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn head_before_get_validates_range_test() -> Result<(), Error> {
+    const OBJECT_SIZE: usize = 100;
+    const OFFSET: usize = 10;
+    let digest = DigestInfo::try_new(VALID_HASH1, OBJECT_SIZE)?;
+    let object_uri =
+        format!("https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{OBJECT_SIZE}");
+    let head_event = || {
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(object_uri.clone())
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .header(header::CONTENT_LENGTH, OBJECT_SIZE.to_string())
+                .body(SdkBody::empty())
+                .unwrap(),
+        )
+    };
+    let send_data = vec![7u8; OBJECT_SIZE - OFFSET];
+    let mock_client = StaticReplayClient::new(vec![
+        head_event(),
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!("{object_uri}?x-id=GetObject"))
+                // The requested length is clamped to the size of the object.
+                .header("range", format!("bytes={OFFSET}-{}", OBJECT_SIZE - 1))
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .body(SdkBody::from(send_data.clone()))
+                .unwrap(),
+        ),
+        head_event(),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            head_before_get: true,
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let store_data = store
+        .get_part_unchunked(digest, OFFSET, Some(OBJECT_SIZE * 5))
+        .await?;
+    assert_eq!(store_data, send_data);
+
+    // Reads past the end of the object fail without issuing a GET.
+    let err = store
+        .get_part_unchunked(digest, OBJECT_SIZE + 1, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, Code::OutOfRange, "{err:?}");
+
+    mock_client.assert_requests_match(&[]);
+    assert_eq!(
+        mock_client
+            .actual_requests()
+            .map(|request| request.method().to_string())
+            .collect::<Vec<_>>(),
+        vec!["HEAD", "GET", "HEAD"],
+        "Expected the HEAD request to precede the GET request"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_tail_uses_suffix_range() -> Result<(), Error> {
+    const AC_ENTRY_SIZE: u64 = 1000;
+    const LENGTH: usize = 3;
+    const TAIL_DATA: &str = "xyz";
+    let mock_client = StaticReplayClient::new(vec![ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{AC_ENTRY_SIZE}?x-id=GetObject",
+                ))
+                .header("range", format!("bytes=-{LENGTH}"))
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(SdkBody::from(TAIL_DATA))
+                .unwrap(),
+        )]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let (tx, mut rx) = make_buf_channel_pair();
+    let (get_res, data_res) = join!(
+        store.get_tail(DigestInfo::try_new(VALID_HASH1, AC_ENTRY_SIZE)?, tx, LENGTH),
+        rx.consume(None)
+    );
+    get_res?;
+    assert_eq!(data_res?, TAIL_DATA.as_bytes());
+
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
 #[nativelink_test]
 async fn get_part_simple_retries() -> Result<(), Error> {
     let mock_client = StaticReplayClient::new(vec![
@@ -411,6 +607,149 @@ async fn get_part_simple_retries() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn get_part_retries_use_requested_range() -> Result<(), Error> {
+    const CAS_ENTRY_SIZE: usize = 10;
+    const OFFSET: usize = 2;
+    const LENGTH: usize = 5;
+    let make_request = || {
+        http::Request::builder()
+            .uri(format!(
+                "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{CAS_ENTRY_SIZE}?x-id=GetObject",
+            ))
+            .header("range", format!("bytes={}-{}", OFFSET, OFFSET + LENGTH - 1))
+            .body(SdkBody::empty())
+            .unwrap()
+    };
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            make_request(),
+            http::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            make_request(),
+            http::Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            make_request(),
+            http::Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(SdkBody::from("23456"))
+                .unwrap(),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            retry: nativelink_config::stores::Retry {
+                max_retries: 3,
+                delay: 0.,
+                jitter: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let data = store
+        .get_part_unchunked(
+            DigestInfo::try_new(VALID_HASH1, CAS_ENTRY_SIZE)?,
+            OFFSET,
+            Some(LENGTH),
+        )
+        .await?;
+    assert_eq!(data, "23456".as_bytes());
+
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn get_part_resumes_interrupted_download() -> Result<(), Error> {
+    const CAS_ENTRY_SIZE: usize = 10; // Length of "helloworld".
+    let (mut tx, channel_body) = Body::channel();
+    let make_request = |start: usize| {
+        http::Request::builder()
+            .uri(format!(
+                "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{CAS_ENTRY_SIZE}?x-id=GetObject",
+            ))
+            .header("range", format!("bytes={}-{}", start, CAS_ENTRY_SIZE - 1))
+            .body(SdkBody::empty())
+            .unwrap()
+    };
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            make_request(0),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .body(SdkBody::from_body_0_4(channel_body))
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            make_request(5),
+            http::Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(SdkBody::from("world"))
+                .unwrap(),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            retry: nativelink_config::stores::Retry {
+                max_retries: 1,
+                delay: 0.,
+                jitter: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let (_, get_part_result) = join!(
+        async move {
+            tx.send_data(Bytes::from_static(b"hello")).await?;
+            // Simulate the connection dropping mid-download.
+            tx.abort();
+            Result::<(), hyper::Error>::Ok(())
+        },
+        store.get_part_unchunked(
+            DigestInfo::try_new(VALID_HASH1, CAS_ENTRY_SIZE)?,
+            0,
+            Some(CAS_ENTRY_SIZE),
+        )
+    );
+    assert_eq!(
+        get_part_result.err_tip(|| "Expected get_part_result to pass")?,
+        "helloworld".as_bytes()
+    );
+
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
 #[nativelink_test]
 async fn multipart_update_large_cas() -> Result<(), Error> {
     // Same as in s3_store.
@@ -531,18 +870,277 @@ async fn multipart_update_large_cas() -> Result<(), Error> {
 }
 
 #[nativelink_test]
-async fn ensure_empty_string_in_stream_works_test() -> Result<(), Error> {
-    const CAS_ENTRY_SIZE: usize = 10; // Length of "helloworld".
-    let (mut tx, channel_body) = Body::channel();
-    let mock_client = StaticReplayClient::new(vec![ReplayEvent::new(
-            http::Request::builder()
-                .uri(format!(
-                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{CAS_ENTRY_SIZE}?x-id=GetObject",
-                ))
-                .header("range", format!("bytes={}-{}", 0, CAS_ENTRY_SIZE))
-                .body(SdkBody::empty())
-                .unwrap(),
-            http::Response::builder()
+async fn multipart_update_uses_configured_part_size() -> Result<(), Error> {
+    const PART_SIZE: usize = 6 * 1024 * 1024; // 6mb.
+    const AC_ENTRY_SIZE: usize = PART_SIZE + 50;
+
+    let mut send_data = Vec::with_capacity(AC_ENTRY_SIZE);
+    for i in 0..send_data.capacity() {
+        send_data.push(((i * 3) % 256) as u8);
+    }
+    let digest = DigestInfo::try_new(VALID_HASH1, send_data.len())?;
+
+    let mock_client = StaticReplayClient::new(vec![
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{AC_ENTRY_SIZE}?uploads",
+                    ))
+                    .method("POST")
+                    .body(SdkBody::empty())
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::from(
+                        r#"
+                        <InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                          <UploadId>Dummy-uploadid</UploadId>
+                        </InitiateMultipartUploadResult>"#
+                            .as_bytes(),
+                    ))
+                    .unwrap(),
+            ),
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{AC_ENTRY_SIZE}?x-id=UploadPart&partNumber=1&uploadId=Dummy-uploadid",
+                    ))
+                    .method("PUT")
+                    .header("content-type", "application/octet-stream")
+                    .header("content-length", PART_SIZE.to_string())
+                    .body(SdkBody::from(&send_data[0..PART_SIZE]))
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::empty())
+                    .unwrap(),
+            ),
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{AC_ENTRY_SIZE}?x-id=UploadPart&partNumber=2&uploadId=Dummy-uploadid",
+                    ))
+                    .method("PUT")
+                    .header("content-type", "application/octet-stream")
+                    .header("content-length", "50")
+                    .body(SdkBody::from(&send_data[PART_SIZE..AC_ENTRY_SIZE]))
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::empty())
+                    .unwrap(),
+            ),
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{AC_ENTRY_SIZE}?uploadId=Dummy-uploadid",
+                    ))
+                    .method("POST")
+                    .body(SdkBody::from(concat!(
+                        r#"<CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">"#,
+                        "<Part><PartNumber>1</PartNumber></Part>",
+                        "<Part><PartNumber>2</PartNumber></Part>",
+                        "</CompleteMultipartUpload>",
+                    )))
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::from(
+                        r#"<CompleteMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"></CompleteMultipartUploadResult>"#,
+                    ))
+                    .unwrap(),
+            ),
+        ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            multipart_part_size: Some(PART_SIZE),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+    store
+        .update_oneshot(digest, send_data.clone().into())
+        .await?;
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn rejects_too_large_multipart_part_size() -> Result<(), Error> {
+    let mock_client = StaticReplayClient::new(vec![]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client)
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let result = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            multipart_part_size: Some(5 * 1024 * 1024 * 1024 + 1),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    );
+    assert!(
+        result.is_err(),
+        "Expected part size above 5GB to be rejected"
+    );
+    Ok(())
+}
+
+/// Answers multipart upload requests and records the highest number of
+/// `UploadPart` requests and of multipart uploads that were in flight at once.
+#[derive(Clone, Debug, Default)]
+struct ConcurrencyTrackingConnector {
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: Arc<AtomicUsize>,
+    uploads_in_flight: Arc<AtomicUsize>,
+    max_uploads_in_flight: Arc<AtomicUsize>,
+}
+
+impl HttpConnector for ConcurrencyTrackingConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let connector = self.clone();
+        let uri = request.uri().to_string();
+        HttpConnectorFuture::new(async move {
+            let body = if uri.contains("x-id=UploadPart") {
+                let in_flight = connector.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                connector
+                    .max_in_flight
+                    .fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                connector.in_flight.fetch_sub(1, Ordering::SeqCst);
+                ""
+            } else if uri.ends_with("?uploads") {
+                let uploads_in_flight =
+                    connector.uploads_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                connector
+                    .max_uploads_in_flight
+                    .fetch_max(uploads_in_flight, Ordering::SeqCst);
+                r#"<InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><UploadId>Dummy-uploadid</UploadId></InitiateMultipartUploadResult>"#
+            } else {
+                connector.uploads_in_flight.fetch_sub(1, Ordering::SeqCst);
+                r#"<CompleteMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"></CompleteMultipartUploadResult>"#
+            };
+            Ok(HttpResponse::new(
+                StatusCode::OK.into(),
+                SdkBody::from(body),
+            ))
+        })
+    }
+}
+
+#[nativelink_test]
+async fn global_max_concurrent_uploads_limits_upload_parts() -> Result<(), Error> {
+    const MIN_MULTIPART_SIZE: usize = 5 * 1024 * 1024; // 5mb.
+    const GLOBAL_MAX_CONCURRENT_UPLOADS: usize = 2;
+    const AC_ENTRY_SIZE: usize = MIN_MULTIPART_SIZE * 6;
+
+    let connector = ConcurrencyTrackingConnector::default();
+    let shared_connector = SharedHttpConnector::new(connector.clone());
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(http_client_fn(move |_, _| shared_connector.clone()))
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            global_max_concurrent_uploads: GLOBAL_MAX_CONCURRENT_UPLOADS,
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let digest = DigestInfo::try_new(VALID_HASH1, AC_ENTRY_SIZE)?;
+    store
+        .update_oneshot(digest, vec![0u8; AC_ENTRY_SIZE].into())
+        .await?;
+
+    let max_in_flight = connector.max_in_flight.load(Ordering::SeqCst);
+    assert!(
+        max_in_flight > 0 && max_in_flight <= GLOBAL_MAX_CONCURRENT_UPLOADS,
+        "Expected at most {GLOBAL_MAX_CONCURRENT_UPLOADS} concurrent uploads, got {max_in_flight}"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn max_concurrent_multipart_uploads_serializes_uploads() -> Result<(), Error> {
+    const MIN_MULTIPART_SIZE: usize = 5 * 1024 * 1024; // 5mb.
+    const MAX_CONCURRENT_MULTIPART_UPLOADS: usize = 1;
+    const AC_ENTRY_SIZE: usize = MIN_MULTIPART_SIZE * 2;
+    const VALID_HASH2: &str = "0123456789abcdef000000000000000000020000000000000123456789abcdef";
+    const VALID_HASH3: &str = "0123456789abcdef000000000000000000030000000000000123456789abcdef";
+
+    let connector = ConcurrencyTrackingConnector::default();
+    let shared_connector = SharedHttpConnector::new(connector.clone());
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(http_client_fn(move |_, _| shared_connector.clone()))
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            max_concurrent_multipart_uploads: MAX_CONCURRENT_MULTIPART_UPLOADS,
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let upload = |hash: &'static str| {
+        let store = store.clone();
+        async move {
+            let digest = DigestInfo::try_new(hash, AC_ENTRY_SIZE)?;
+            store
+                .update_oneshot(digest, vec![0u8; AC_ENTRY_SIZE].into())
+                .await
+        }
+    };
+    let (res1, res2, res3) = join!(
+        upload(VALID_HASH1),
+        upload(VALID_HASH2),
+        upload(VALID_HASH3)
+    );
+    res1.merge(res2).merge(res3)?;
+
+    assert_eq!(
+        connector.max_uploads_in_flight.load(Ordering::SeqCst),
+        MAX_CONCURRENT_MULTIPART_UPLOADS,
+        "Expected multipart uploads to run one at a time"
+    );
+    assert_eq!(connector.uploads_in_flight.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn ensure_empty_string_in_stream_works_test() -> Result<(), Error> {
+    const CAS_ENTRY_SIZE: usize = 10; // Length of "helloworld".
+    let (mut tx, channel_body) = Body::channel();
+    let mock_client = StaticReplayClient::new(vec![ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{CAS_ENTRY_SIZE}?x-id=GetObject",
+                ))
+                .header("range", format!("bytes={}-{}", 0, CAS_ENTRY_SIZE - 1))
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
                 .status(StatusCode::OK)
                 .body(SdkBody::from_body_0_4(channel_body))
                 .unwrap(),
@@ -659,3 +1257,517 @@ async fn has_with_results_on_zero_digests() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[nativelink_test]
+async fn under_declared_max_size_upload_uses_multipart() -> Result<(), Error> {
+    // Same as in s3_store.
+    const MIN_MULTIPART_SIZE: usize = 5 * 1024 * 1024; // 5mb.
+    const DECLARED_SIZE: usize = 100;
+    const SEND_SIZE: usize = MIN_MULTIPART_SIZE + 50;
+
+    let send_data: Vec<u8> = (0..SEND_SIZE).map(|i| ((i * 3) % 256) as u8).collect();
+    let digest = DigestInfo::try_new(VALID_HASH1, SEND_SIZE)?;
+
+    // Only uploads with a known exact size below `MIN_MULTIPART_SIZE` use a
+    // single `PutObject`. An upload that only declares a maximum size always
+    // uses a multipart upload, so it can grow past the single put limit
+    // without being rejected by S3.
+    let mock_client = StaticReplayClient::new(vec![
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{SEND_SIZE}?uploads",
+                    ))
+                    .method("POST")
+                    .body(SdkBody::empty())
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::from(
+                        r#"
+                        <InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                          <UploadId>Dummy-uploadid</UploadId>
+                        </InitiateMultipartUploadResult>"#
+                            .as_bytes(),
+                    ))
+                    .unwrap(),
+            ),
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{SEND_SIZE}?x-id=UploadPart&partNumber=1&uploadId=Dummy-uploadid",
+                    ))
+                    .method("PUT")
+                    .header("content-type", "application/octet-stream")
+                    .header("content-length", "5242880")
+                    .body(SdkBody::from(&send_data[0..MIN_MULTIPART_SIZE]))
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::empty())
+                    .unwrap(),
+            ),
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{SEND_SIZE}?x-id=UploadPart&partNumber=2&uploadId=Dummy-uploadid",
+                    ))
+                    .method("PUT")
+                    .header("content-type", "application/octet-stream")
+                    .header("content-length", "50")
+                    .body(SdkBody::from(&send_data[MIN_MULTIPART_SIZE..]))
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::empty())
+                    .unwrap(),
+            ),
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri(format!(
+                        "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{SEND_SIZE}?uploadId=Dummy-uploadid",
+                    ))
+                    .method("POST")
+                    .header("content-length", "177")
+                    .body(SdkBody::from(concat!(
+                        r#"<CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">"#,
+                        "<Part><PartNumber>1</PartNumber></Part>",
+                        "<Part><PartNumber>2</PartNumber></Part>",
+                        "</CompleteMultipartUpload>",
+                    )))
+                    .unwrap(),
+                http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(SdkBody::from(
+                        r#"<CompleteMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"></CompleteMultipartUploadResult>"#,
+                    ))
+                    .unwrap(),
+            ),
+        ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let (mut tx, rx) = make_buf_channel_pair();
+    let (update_result, send_result) = join!(
+        store.update(digest, rx, UploadSizeInfo::MaxSize(DECLARED_SIZE)),
+        async move {
+            tx.send(send_data.into()).await?;
+            tx.send_eof()
+        }
+    );
+    update_result.merge(send_result)?;
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn client_disconnect_aborts_multipart_upload_test() -> Result<(), Error> {
+    const MAX_UPLOAD_SIZE: usize = 20 * 1024 * 1024;
+    let digest = DigestInfo::try_new(VALID_HASH1, MAX_UPLOAD_SIZE)?;
+
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{MAX_UPLOAD_SIZE}?uploads",
+                ))
+                .method("POST")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .body(SdkBody::from(
+                    r#"
+                    <InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                      <UploadId>Dummy-uploadid</UploadId>
+                    </InitiateMultipartUploadResult>"#
+                        .as_bytes(),
+                ))
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{VALID_HASH1}-{MAX_UPLOAD_SIZE}?x-id=AbortMultipartUpload&uploadId=Dummy-uploadid",
+                ))
+                .method("DELETE")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+        // Reconciliation finds no uploads left behind.
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/?uploads",
+                ))
+                .method("GET")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .body(SdkBody::from(
+                    r#"
+                    <ListMultipartUploadsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                      <Bucket>dummy-bucket-name</Bucket>
+                      <IsTruncated>false</IsTruncated>
+                    </ListMultipartUploadsResult>"#
+                        .as_bytes(),
+                ))
+                .unwrap(),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let (mut tx, rx) = make_buf_channel_pair();
+    let (update_result, ()) = join!(
+        store.update(digest, rx, UploadSizeInfo::MaxSize(MAX_UPLOAD_SIZE)),
+        async move {
+            tx.send(Bytes::from_static(b"partial data")).await.unwrap();
+            // The client disconnects without sending EOF.
+            drop(tx);
+        }
+    );
+    assert!(
+        update_result.is_err(),
+        "Expected update to fail when the client disconnects"
+    );
+
+    assert_eq!(
+        store
+            .abort_orphaned_multipart_uploads(Duration::from_secs(0))
+            .await?,
+        0,
+        "Expected no multipart uploads to be left behind"
+    );
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn abort_orphaned_multipart_uploads_test() -> Result<(), Error> {
+    const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+    let recent_initiated = aws_smithy_types::DateTime::from(std::time::SystemTime::now())
+        .fmt(aws_smithy_types::date_time::Format::DateTime)
+        .unwrap();
+
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/?uploads",
+                ))
+                .method("GET")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .body(SdkBody::from(format!(
+                    r#"
+                    <ListMultipartUploadsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                      <Bucket>dummy-bucket-name</Bucket>
+                      <IsTruncated>false</IsTruncated>
+                      <Upload>
+                        <Key>old-key</Key>
+                        <UploadId>old-uploadid</UploadId>
+                        <Initiated>2020-01-01T00:00:00.000Z</Initiated>
+                      </Upload>
+                      <Upload>
+                        <Key>recent-key</Key>
+                        <UploadId>recent-uploadid</UploadId>
+                        <Initiated>{recent_initiated}</Initiated>
+                      </Upload>
+                    </ListMultipartUploadsResult>"#
+                )))
+                .unwrap(),
+        ),
+        // A concurrent abort already removed the upload, which is not an error.
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/old-key?x-id=AbortMultipartUpload&uploadId=old-uploadid",
+                ))
+                .method("DELETE")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(SdkBody::from(
+                    r#"
+                    <Error>
+                      <Code>NoSuchUpload</Code>
+                      <Message>The specified upload does not exist.</Message>
+                    </Error>"#
+                        .as_bytes(),
+                ))
+                .unwrap(),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    assert_eq!(store.abort_orphaned_multipart_uploads(MAX_AGE).await?, 1);
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn abort_orphaned_multipart_uploads_continues_after_failure_test() -> Result<(), Error> {
+    let abort_request = |key: &str| {
+        http::Request::builder()
+            .uri(format!(
+                "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/{key}?x-id=AbortMultipartUpload&uploadId={key}-uploadid",
+            ))
+            .method("DELETE")
+            .body(SdkBody::empty())
+            .unwrap()
+    };
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/?uploads",
+                ))
+                .method("GET")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .body(SdkBody::from(
+                    r#"
+                    <ListMultipartUploadsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                      <Bucket>dummy-bucket-name</Bucket>
+                      <IsTruncated>false</IsTruncated>
+                      <Upload>
+                        <Key>first-key</Key>
+                        <UploadId>first-key-uploadid</UploadId>
+                        <Initiated>2020-01-01T00:00:00.000Z</Initiated>
+                      </Upload>
+                      <Upload>
+                        <Key>second-key</Key>
+                        <UploadId>second-key-uploadid</UploadId>
+                        <Initiated>2020-01-01T00:00:00.000Z</Initiated>
+                      </Upload>
+                    </ListMultipartUploadsResult>"#
+                        .as_bytes(),
+                ))
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            abort_request("first-key"),
+            http::Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(SdkBody::from(
+                    r#"
+                    <Error>
+                      <Code>AccessDenied</Code>
+                      <Message>Access Denied</Message>
+                    </Error>"#
+                        .as_bytes(),
+                ))
+                .unwrap(),
+        ),
+        ReplayEvent::new(
+            abort_request("second-key"),
+            http::Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(SdkBody::empty())
+                .unwrap(),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    // The first upload fails to abort, the second is still aborted.
+    assert_eq!(
+        store
+            .abort_orphaned_multipart_uploads(Duration::from_secs(60))
+            .await?,
+        1
+    );
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn list_digests_follows_continuation_tokens() -> Result<(), Error> {
+    const VALID_HASH2: &str = "0123456789abcdef000000000000000000020000000000000123456789abcdef";
+    let list_response = |keys: &[String], next_token: Option<&str>| {
+        let contents: String = keys
+            .iter()
+            .map(|key| format!("<Contents><Key>{key}</Key></Contents>"))
+            .collect();
+        let truncation = match next_token {
+            Some(token) => format!(
+                "<IsTruncated>true</IsTruncated><NextContinuationToken>{token}</NextContinuationToken>"
+            ),
+            None => "<IsTruncated>false</IsTruncated>".to_string(),
+        };
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .body(SdkBody::from(format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                 <Name>{BUCKET_NAME}</Name>{truncation}{contents}</ListBucketResult>"
+            )))
+            .unwrap()
+    };
+    let mock_client = StaticReplayClient::new(vec![
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/?list-type=2&prefix=prefix%2F0123"
+                ))
+                .body(SdkBody::empty())
+                .unwrap(),
+            list_response(&[format!("prefix/{VALID_HASH1}-100")], Some("page2")),
+        ),
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri(format!(
+                    "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/?list-type=2&prefix=prefix%2F0123&continuation-token=page2"
+                ))
+                .body(SdkBody::empty())
+                .unwrap(),
+            list_response(&[format!("prefix/{VALID_HASH2}-200")], None),
+        ),
+    ]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            key_prefix: Some("prefix/".to_string()),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let digests = store.list_digests("0123").try_collect::<Vec<_>>().await?;
+    assert_eq!(
+        digests,
+        vec![
+            DigestInfo::try_new(VALID_HASH1, 100)?,
+            DigestInfo::try_new(VALID_HASH2, 200)?,
+        ]
+    );
+
+    mock_client.assert_requests_match(&[]);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn presign_get_returns_signed_url_for_digest() -> Result<(), Error> {
+    const CONTENT_SIZE: usize = 100;
+    const EXPIRY_S: u64 = 300;
+    let mock_client = StaticReplayClient::new(vec![]);
+    let test_config = Builder::new()
+        .behavior_version(BehaviorVersion::v2024_03_28())
+        .region(Region::from_static(REGION))
+        .credentials_provider(Credentials::for_tests())
+        .http_client(mock_client.clone())
+        .build();
+    let s3_client = aws_sdk_s3::Client::from_conf(test_config);
+    let store = S3Store::new_with_client_and_jitter(
+        &nativelink_config::stores::S3Store {
+            bucket: BUCKET_NAME.to_string(),
+            key_prefix: Some("prefix/".to_string()),
+            ..Default::default()
+        },
+        s3_client,
+        Arc::new(move |_delay| Duration::from_secs(0)),
+    )?;
+
+    let url = store
+        .presign_get(
+            DigestInfo::try_new(VALID_HASH1, CONTENT_SIZE)?,
+            Duration::from_secs(EXPIRY_S),
+        )
+        .await?;
+    assert!(
+        url.starts_with(&format!(
+            "https://{BUCKET_NAME}.s3.{REGION}.amazonaws.com/prefix/{VALID_HASH1}-{CONTENT_SIZE}?"
+        )),
+        "Unexpected pre-signed URL: {url}"
+    );
+    for expected_param in [
+        "x-id=GetObject".to_string(),
+        format!("X-Amz-Expires={EXPIRY_S}"),
+        "X-Amz-Signature=".to_string(),
+    ] {
+        assert!(
+            url.contains(&expected_param),
+            "Expected {expected_param} in pre-signed URL: {url}"
+        );
+    }
+    // Pre-signing does not send any request.
+    mock_client.assert_requests_match(&[]);
+
+    // Expiries longer than a week can not be signed.
+    let err = store
+        .presign_get(
+            DigestInfo::try_new(VALID_HASH1, CONTENT_SIZE)?,
+            Duration::from_secs(8 * 24 * 60 * 60),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, Code::InvalidArgument);
+    Ok(())
+}
@@ -273,6 +273,35 @@ async fn weights_send_to_proper_store() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn same_digest_routes_to_same_shard_across_instances() -> Result<(), Error> {
+    const WEIGHTS: &[u32] = &[1, 2, 3];
+    let (shard_store1, stores1) = make_stores(WEIGHTS);
+    let (shard_store2, stores2) = make_stores(WEIGHTS);
+
+    for counter in 0u64..100 {
+        let mut hasher = DigestHasherFunc::Blake3.hasher();
+        hasher.update(&counter.to_le_bytes());
+        let digest = hasher.finalize_digest();
+        shard_store1.update_oneshot(digest, "data".into()).await?;
+        shard_store2.update_oneshot(digest, "data".into()).await?;
+
+        let mut shards1 = Vec::new();
+        let mut shards2 = Vec::new();
+        for index in 0..WEIGHTS.len() {
+            if stores1[index].has(digest).await?.is_some() {
+                shards1.push(index);
+            }
+            if stores2[index].has(digest).await?.is_some() {
+                shards2.push(index);
+            }
+        }
+        assert_eq!(shards1.len(), 1, "Expected digest in exactly one shard");
+        assert_eq!(shards1, shards2, "Expected same shard for {digest:?}");
+    }
+    Ok(())
+}
+
 #[nativelink_test]
 async fn verify_weights_even_weights() -> Result<(), Error> {
     verify_weights(
@@ -0,0 +1,377 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::join;
+use nativelink_error::{make_err, Code, Error, ResultExt};
+use nativelink_macro::nativelink_test;
+use nativelink_store::memory_store::MemoryStore;
+use nativelink_store::single_flight_store::SingleFlightStore;
+use nativelink_util::buf_channel::{
+    make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
+};
+use nativelink_util::common::DigestInfo;
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::spawn;
+use nativelink_util::store_trait::{Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo};
+use pretty_assertions::assert_eq;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
+const VALUE: &str = "123";
+
+/// Forwards to a memory store while counting how many uploads and reads
+/// reach it.
+struct CountingStore {
+    inner_store: Store,
+    update_count: AtomicUsize,
+    get_part_count: AtomicUsize,
+    // If set, the next read never completes.
+    stall_next_read: AtomicBool,
+    // If set, the next upload fails after receiving its data.
+    fail_next_update: AtomicBool,
+    // If set, the next read sends its first byte and then waits for
+    // `read_gate` before sending the rest.
+    gate_next_read: AtomicBool,
+    read_gate: Notify,
+}
+
+impl CountingStore {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner_store: Store::new(MemoryStore::new(
+                &nativelink_config::stores::MemoryStore::default(),
+            )),
+            update_count: AtomicUsize::new(0),
+            get_part_count: AtomicUsize::new(0),
+            stall_next_read: AtomicBool::new(false),
+            fail_next_update: AtomicBool::new(false),
+            gate_next_read: AtomicBool::new(false),
+            read_gate: Notify::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl StoreDriver for CountingStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        keys: &[StoreKey<'_>],
+        results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        self.inner_store.has_with_results(keys, results).await
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        mut reader: DropCloserReadHalf,
+        size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        self.update_count.fetch_add(1, Ordering::Relaxed);
+        if self.fail_next_update.swap(false, Ordering::Relaxed) {
+            reader.consume(None).await?;
+            return Err(make_err!(Code::Unavailable, "Injected upload failure"));
+        }
+        self.inner_store.update(key, reader, size_info).await
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Error> {
+        self.get_part_count.fetch_add(1, Ordering::Relaxed);
+        if self.stall_next_read.swap(false, Ordering::Relaxed) {
+            return std::future::pending().await;
+        }
+        if self.gate_next_read.swap(false, Ordering::Relaxed) {
+            let data = self
+                .inner_store
+                .get_part_unchunked(key, offset, length)
+                .await?;
+            writer.send(data.slice(..1)).await?;
+            self.read_gate.notified().await;
+            writer.send(data.slice(1..)).await?;
+            return writer.send_eof();
+        }
+        self.inner_store.get_part(key, writer, offset, length).await
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(CountingStore);
+
+fn make_single_flight_store(
+    counting_store: &Arc<CountingStore>,
+    read_coalescing_window_millis: u64,
+) -> Store {
+    Store::new(SingleFlightStore::new(
+        &nativelink_config::stores::SingleFlightStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            read_coalescing_window_millis,
+        },
+        Store::new(counting_store.clone()),
+    ))
+}
+
+#[nativelink_test]
+async fn concurrent_uploads_of_same_digest_write_once_test() -> Result<(), Error> {
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, 0);
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+
+    let (mut tx1, rx1) = make_buf_channel_pair();
+    let (mut tx2, rx2) = make_buf_channel_pair();
+    // Both uploads are started before any data is sent, so the second one
+    // arrives while the first is still in flight.
+    let (upload1_result, upload2_result, send_result) = join!(
+        store.update(digest, rx1, UploadSizeInfo::ExactSize(VALUE.len())),
+        store.update(digest, rx2, UploadSizeInfo::ExactSize(VALUE.len())),
+        async move {
+            tx2.send(VALUE.into()).await?;
+            tx2.send_eof()?;
+            tx1.send(VALUE.into()).await?;
+            tx1.send_eof()
+        },
+    );
+    send_result.err_tip(|| "Failed to send data")?;
+    upload1_result.err_tip(|| "First upload failed")?;
+    upload2_result.err_tip(|| "Second upload failed")?;
+
+    assert_eq!(
+        counting_store.update_count.load(Ordering::Relaxed),
+        1,
+        "Expected only one upload to reach the backend"
+    );
+    assert_eq!(
+        store.get_part_unchunked(digest, 0, None).await?,
+        VALUE.as_bytes()
+    );
+
+    // Once the first upload finished, a new upload is written again.
+    store.update_oneshot(digest, VALUE.into()).await?;
+    assert_eq!(counting_store.update_count.load(Ordering::Relaxed), 2);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn follower_uploads_own_data_when_leading_upload_fails_test() -> Result<(), Error> {
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, 0);
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    counting_store
+        .fail_next_update
+        .store(true, Ordering::Relaxed);
+
+    let (mut tx1, rx1) = make_buf_channel_pair();
+    let (mut tx2, rx2) = make_buf_channel_pair();
+    // The first upload leads and fails only after the second one joined it.
+    let (upload1_result, upload2_result, send_result) = join!(
+        store.update(digest, rx1, UploadSizeInfo::ExactSize(VALUE.len())),
+        store.update(digest, rx2, UploadSizeInfo::ExactSize(VALUE.len())),
+        async move {
+            tx2.send(VALUE.into()).await?;
+            tx2.send_eof()?;
+            tx1.send(VALUE.into()).await?;
+            tx1.send_eof()
+        },
+    );
+    send_result.err_tip(|| "Failed to send data")?;
+    assert_eq!(
+        upload1_result.map_err(|e| e.code),
+        Err(Code::Unavailable),
+        "Expected the leading upload to fail"
+    );
+    upload2_result.err_tip(|| "Expected the follower to upload its own data")?;
+
+    assert_eq!(counting_store.update_count.load(Ordering::Relaxed), 2);
+    assert_eq!(
+        store.get_part_unchunked(digest, 0, None).await?,
+        VALUE.as_bytes()
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn staggered_reads_within_window_are_coalesced_test() -> Result<(), Error> {
+    const WINDOW_MILLIS: u64 = 500;
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+
+    // Without a window, reads that are not concurrent each reach the backend.
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, 0);
+    store.update_oneshot(digest, VALUE.into()).await?;
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    sleep(Duration::from_millis(10)).await;
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    assert_eq!(counting_store.get_part_count.load(Ordering::Relaxed), 2);
+
+    // With a window, the staggered reads share the first backend read.
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, WINDOW_MILLIS);
+    store.update_oneshot(digest, VALUE.into()).await?;
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    sleep(Duration::from_millis(10)).await;
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    sleep(Duration::from_millis(10)).await;
+    assert_eq!(
+        store
+            .get_part_unchunked(digest, 0, Some(VALUE.len()))
+            .await?,
+        VALUE
+    );
+    assert_eq!(
+        counting_store.get_part_count.load(Ordering::Relaxed),
+        1,
+        "Expected reads within the window to coalesce into one backend read"
+    );
+
+    // Once the window is over, the backend is read again.
+    sleep(Duration::from_millis(WINDOW_MILLIS + 100)).await;
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    assert_eq!(counting_store.get_part_count.load(Ordering::Relaxed), 2);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn follower_retries_when_leading_read_is_dropped_test() -> Result<(), Error> {
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, 0);
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    store.update_oneshot(digest, VALUE.into()).await?;
+
+    counting_store
+        .stall_next_read
+        .store(true, Ordering::Relaxed);
+    let leader = spawn!("single_flight_store_test_leader", {
+        let store = store.clone();
+        async move { store.get_part_unchunked(digest, 0, None).await }
+    });
+    while counting_store.get_part_count.load(Ordering::Relaxed) == 0 {
+        tokio::task::yield_now().await;
+    }
+    let follower = spawn!("single_flight_store_test_follower", {
+        let store = store.clone();
+        async move { store.get_part_unchunked(digest, 0, None).await }
+    });
+    // Give the follower time to join the stalled read before cancelling it.
+    sleep(Duration::from_millis(10)).await;
+    drop(leader);
+
+    assert_eq!(
+        follower.await.err_tip(|| "Follower panicked")??,
+        VALUE.as_bytes()
+    );
+    assert_eq!(
+        counting_store.get_part_count.load(Ordering::Relaxed),
+        2,
+        "Expected the follower to read the backend itself"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn lone_read_is_streamed_test() -> Result<(), Error> {
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, 0);
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    store.update_oneshot(digest, VALUE.into()).await?;
+    counting_store.gate_next_read.store(true, Ordering::Relaxed);
+
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let (read_result, receive_result) = join!(store.get_part(digest, &mut tx, 0, None), async {
+        // The first byte arrives while the backend is still reading.
+        let first_chunk = timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .map_err(|_| make_err!(Code::DeadlineExceeded, "Read was not streamed"))??;
+        counting_store.read_gate.notify_one();
+        let rest = rx.consume(None).await?;
+        Ok::<_, Error>([first_chunk, rest].concat())
+    });
+    read_result.err_tip(|| "Read failed")?;
+    assert_eq!(receive_result?, VALUE.as_bytes());
+    Ok(())
+}
+
+#[nativelink_test]
+async fn follower_joining_after_data_was_streamed_reads_backend_test() -> Result<(), Error> {
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, 0);
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len())?;
+    store.update_oneshot(digest, VALUE.into()).await?;
+    counting_store.gate_next_read.store(true, Ordering::Relaxed);
+
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let (read_result, follower_result) = join!(store.get_part(digest, &mut tx, 0, None), async {
+        // The leader already sent its first byte without anyone to keep it
+        // for, so the follower cannot be served from it.
+        let first_chunk = rx.recv().await?;
+        let follower = store.get_part_unchunked(digest, 0, None);
+        counting_store.read_gate.notify_one();
+        let follower_data = follower.await?;
+        let rest = rx.consume(None).await?;
+        Ok::<_, Error>(([first_chunk, rest].concat(), follower_data))
+    });
+    read_result.err_tip(|| "Leading read failed")?;
+    let (leader_data, follower_data) = follower_result?;
+    assert_eq!(leader_data, VALUE.as_bytes());
+    assert_eq!(follower_data, VALUE.as_bytes());
+    assert_eq!(
+        counting_store.get_part_count.load(Ordering::Relaxed),
+        2,
+        "Expected the late follower to read the backend itself"
+    );
+    Ok(())
+}
+
+#[nativelink_test]
+async fn reads_not_matching_digest_size_are_not_kept_test() -> Result<(), Error> {
+    const WINDOW_MILLIS: u64 = 60_000;
+    let counting_store = CountingStore::new();
+    let store = make_single_flight_store(&counting_store, WINDOW_MILLIS);
+    // Like an action cache entry, the value is not addressed by its digest.
+    let digest = DigestInfo::try_new(VALID_HASH1, VALUE.len() + 1)?;
+    store.update_oneshot(digest, VALUE.into()).await?;
+
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    assert_eq!(store.get_part_unchunked(digest, 0, None).await?, VALUE);
+    assert_eq!(
+        counting_store.get_part_count.load(Ordering::Relaxed),
+        2,
+        "Expected each read to reach the backend"
+    );
+    Ok(())
+}
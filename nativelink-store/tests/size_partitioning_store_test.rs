@@ -12,15 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::pin::Pin;
 use std::sync::Arc;
 
-use nativelink_error::Error;
+use async_trait::async_trait;
+use futures::{stream, TryStreamExt};
+use nativelink_error::{make_err, Code, Error};
 use nativelink_macro::nativelink_test;
 use nativelink_store::memory_store::MemoryStore;
 use nativelink_store::size_partitioning_store::SizePartitioningStore;
+use nativelink_util::buf_channel::{
+    make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf,
+};
 use nativelink_util::common::DigestInfo;
-use nativelink_util::store_trait::{Store, StoreLike};
+use nativelink_util::health_utils::{default_health_status_indicator, HealthStatusIndicator};
+use nativelink_util::store_trait::{
+    DigestStream, Store, StoreDriver, StoreKey, StoreLike, UploadSizeInfo,
+};
 use pretty_assertions::assert_eq;
+use tokio::try_join;
 
 const BASE_SIZE_PART: u64 = 5;
 
@@ -203,3 +213,143 @@ async fn update_test() -> Result<(), Error> {
     }
     Ok(())
 }
+
+#[nativelink_test]
+async fn update_routes_by_digest_size_at_boundary_test() -> Result<(), Error> {
+    let (size_part_store, lower_memory_store, upper_memory_store) = setup_stores(BASE_SIZE_PART);
+
+    // Exactly at the partition size goes to the upper store.
+    const AT_SIZE_VALUE: &str = "12345";
+    let at_size_digest = DigestInfo::try_new(BIG_HASH, AT_SIZE_VALUE.len())?;
+    size_part_store
+        .update_oneshot(at_size_digest, AT_SIZE_VALUE.into())
+        .await?;
+    assert_eq!(upper_memory_store.has(at_size_digest).await?, Some(5));
+    assert_eq!(lower_memory_store.has(at_size_digest).await?, None);
+
+    // Just below the partition size goes to the lower store.
+    const BELOW_SIZE_VALUE: &str = "1234";
+    let below_size_digest = DigestInfo::try_new(SMALL_HASH, BELOW_SIZE_VALUE.len())?;
+    size_part_store
+        .update_oneshot(below_size_digest, BELOW_SIZE_VALUE.into())
+        .await?;
+    assert_eq!(lower_memory_store.has(below_size_digest).await?, Some(4));
+    assert_eq!(upper_memory_store.has(below_size_digest).await?, None);
+
+    // Uploads of unknown size are routed by the digest size too, otherwise
+    // `has()` and `get_part()` would look in the wrong store.
+    const MAX_SIZE_VALUE: &str = "99";
+    let max_size_digest = DigestInfo::try_new(
+        "0123456789abcdef000000000000000000020000000000000123456789abcdef",
+        MAX_SIZE_VALUE.len(),
+    )?;
+    let (mut tx, rx) = make_buf_channel_pair();
+    try_join!(
+        size_part_store.update(max_size_digest, rx, UploadSizeInfo::MaxSize(1024)),
+        async move {
+            tx.send(MAX_SIZE_VALUE.into()).await?;
+            tx.send_eof()
+        },
+    )?;
+    assert_eq!(lower_memory_store.has(max_size_digest).await?, Some(2));
+    assert_eq!(upper_memory_store.has(max_size_digest).await?, None);
+    assert_eq!(
+        size_part_store
+            .get_part_unchunked(max_size_digest, 0, None)
+            .await?,
+        MAX_SIZE_VALUE.as_bytes()
+    );
+    Ok(())
+}
+
+/// Store that can only list a fixed set of digests.
+struct ListingStore {
+    digests: Vec<DigestInfo>,
+}
+
+#[async_trait]
+impl StoreDriver for ListingStore {
+    async fn has_with_results(
+        self: Pin<&Self>,
+        _keys: &[StoreKey<'_>],
+        _results: &mut [Option<usize>],
+    ) -> Result<(), Error> {
+        Err(make_err!(Code::Unimplemented, "Not implemented"))
+    }
+
+    async fn update(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _reader: DropCloserReadHalf,
+        _size_info: UploadSizeInfo,
+    ) -> Result<(), Error> {
+        Err(make_err!(Code::Unimplemented, "Not implemented"))
+    }
+
+    async fn get_part(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _writer: &mut DropCloserWriteHalf,
+        _offset: usize,
+        _length: Option<usize>,
+    ) -> Result<(), Error> {
+        Err(make_err!(Code::Unimplemented, "Not implemented"))
+    }
+
+    fn list_digests<'a>(self: Pin<&'a Self>, prefix: &'a str) -> DigestStream<'a> {
+        Box::pin(stream::iter(
+            self.get_ref()
+                .digests
+                .iter()
+                .filter(move |digest| digest.hash_str().starts_with(prefix))
+                .map(|digest| Ok(*digest)),
+        ))
+    }
+
+    fn inner_store(&self, _digest: Option<StoreKey>) -> &dyn StoreDriver {
+        self
+    }
+
+    fn as_any<'a>(&'a self) -> &'a (dyn std::any::Any + Sync + Send + 'static) {
+        self
+    }
+
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn std::any::Any + Sync + Send + 'static> {
+        self
+    }
+}
+
+default_health_status_indicator!(ListingStore);
+
+#[nativelink_test]
+async fn list_digests_lists_both_stores_test() -> Result<(), Error> {
+    let lower_digest = DigestInfo::try_new(SMALL_HASH, 2)?;
+    let upper_digest = DigestInfo::try_new(
+        "0123456789abcdef000000000000000000020000000000000123456789abcdef",
+        9,
+    )?;
+    let other_digest = DigestInfo::try_new(
+        "fedcba9876543210000000000000000000020000000000000123456789abcdef",
+        9,
+    )?;
+    let size_part_store = SizePartitioningStore::new(
+        &nativelink_config::stores::SizePartitioningStore {
+            size: BASE_SIZE_PART,
+            lower_store: nativelink_config::stores::StoreConfig::noop,
+            upper_store: nativelink_config::stores::StoreConfig::noop,
+        },
+        Store::new(Arc::new(ListingStore {
+            digests: vec![lower_digest],
+        })),
+        Store::new(Arc::new(ListingStore {
+            digests: vec![upper_digest, other_digest],
+        })),
+    );
+
+    let digests = size_part_store
+        .list_digests("0123")
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(digests, vec![lower_digest, upper_digest]);
+    Ok(())
+}
@@ -13,22 +13,38 @@
 // limitations under the License.
 
 use std::pin::Pin;
+use std::sync::Arc;
 
 use futures::try_join;
-use nativelink_error::{Error, ResultExt};
+use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
 use nativelink_store::memory_store::MemoryStore;
-use nativelink_store::verify_store::VerifyStore;
+use nativelink_store::verify_store::{ContentInspector, ContentPolicy, VerifyStore};
 use nativelink_util::buf_channel::make_buf_channel_pair;
 use nativelink_util::common::DigestInfo;
 use nativelink_util::digest_hasher::{make_ctx_for_hash_func, DigestHasherFunc};
+use nativelink_util::metrics_utils::Registry;
 use nativelink_util::spawn;
-use nativelink_util::store_trait::{Store, StoreLike, UploadSizeInfo};
+use nativelink_util::store_trait::{Store, StoreDriver, StoreLike, UploadSizeInfo};
 use pretty_assertions::assert_eq;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use tracing::info_span;
 
 const VALID_HASH1: &str = "0123456789abcdef000000000000000000010000000000000123456789abcdef";
 
+fn metric_value(store: &Arc<VerifyStore>, name: &str) -> String {
+    let mut registry = <Registry>::default();
+    store.clone().register_metrics(&mut registry);
+    let mut metrics = String::new();
+    prometheus_client::encoding::text::encode(&mut metrics, &registry).unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{name} ")))
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| panic!("Expected {name} metric, got:\n{metrics}"))
+}
+
 #[nativelink_test]
 async fn verify_size_false_passes_on_update() -> Result<(), Error> {
     let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
@@ -39,6 +55,9 @@ async fn verify_size_false_passes_on_update() -> Result<(), Error> {
             ),
             verify_size: false,
             verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -70,6 +89,9 @@ async fn verify_size_true_fails_on_update() -> Result<(), Error> {
             ),
             verify_size: true,
             verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -110,6 +132,9 @@ async fn verify_size_true_suceeds_on_update() -> Result<(), Error> {
             ),
             verify_size: true,
             verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -136,6 +161,9 @@ async fn verify_size_true_suceeds_on_multi_chunk_stream_update() -> Result<(), E
             ),
             verify_size: true,
             verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -175,6 +203,9 @@ async fn verify_sha256_hash_true_suceeds_on_update() -> Result<(), Error> {
             ),
             verify_size: false,
             verify_hash: true,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -203,6 +234,9 @@ async fn verify_sha256_hash_true_fails_on_update() -> Result<(), Error> {
             ),
             verify_size: false,
             verify_hash: true,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -214,8 +248,9 @@ async fn verify_sha256_hash_true_fails_on_update() -> Result<(), Error> {
     let result = store.update_oneshot(digest, VALUE.into()).await;
     let err = result.unwrap_err().to_string();
     const ACTUAL_HASH: &str = "a665a45920422f9d417e4867efdc4fb8a04a1f3fff1fa07e998e86f7f7a27ae3";
-    let expected_err =
-        format!("Hashes do not match, got: {HASH} but digest hash was {ACTUAL_HASH}");
+    let expected_err = format!(
+        "Hashes do not match, expected digest {HASH}-3 but received 3 bytes with SHA256 hash {ACTUAL_HASH}"
+    );
     assert!(
         err.contains(&expected_err),
         "Error should contain '{expected_err}', got: {err:?}"
@@ -238,6 +273,9 @@ async fn verify_blake3_hash_true_suceeds_on_update() -> Result<(), Error> {
             ),
             verify_size: false,
             verify_hash: true,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -272,6 +310,9 @@ async fn verify_blake3_hash_true_fails_on_update() -> Result<(), Error> {
             ),
             verify_size: false,
             verify_hash: true,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
         },
         Store::new(inner_store.clone()),
     );
@@ -291,8 +332,9 @@ async fn verify_blake3_hash_true_fails_on_update() -> Result<(), Error> {
     // let result = store.update_oneshot(digest, VALUE.into()).await;
     let err = result.unwrap_err().to_string();
     const ACTUAL_HASH: &str = "b3d4f8803f7e24b8f389b072e75477cdbcfbe074080fb5e500e53e26e054158e";
-    let expected_err =
-        format!("Hashes do not match, got: {HASH} but digest hash was {ACTUAL_HASH}");
+    let expected_err = format!(
+        "Hashes do not match, expected digest {HASH}-3 but received 3 bytes with BLAKE3 hash {ACTUAL_HASH}"
+    );
     assert!(
         err.contains(&expected_err),
         "Error should contain '{expected_err}', got: {err:?}"
@@ -304,3 +346,232 @@ async fn verify_blake3_hash_true_fails_on_update() -> Result<(), Error> {
     );
     Ok(())
 }
+
+#[nativelink_test]
+async fn verified_digest_cache_skips_rehashing_test() -> Result<(), Error> {
+    let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let store = VerifyStore::new(
+        &nativelink_config::stores::VerifyStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            verify_size: true,
+            verify_hash: true,
+            verified_digest_cache_size: 10,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
+        },
+        Store::new(inner_store.clone()),
+    );
+
+    /// This value is blake3("123").
+    const HASH: &str = "b3d4f8803f7e24b8f389b072e75477cdbcfbe074080fb5e500e53e26e054158e";
+    let digest = || DigestInfo::try_new(HASH, 3).unwrap();
+    let cache_hits = || metric_value(&store, "hash_verification_cache_hits_total");
+    let update = |value: &'static str| {
+        make_ctx_for_hash_func(DigestHasherFunc::Blake3)
+            .unwrap()
+            .wrap_async(
+                info_span!("update_oneshot"),
+                store.update_oneshot(digest(), value.into()),
+            )
+    };
+
+    update("123").await?;
+    assert_eq!(cache_hits(), "0", "First upload should be hashed");
+    update("123").await?;
+    assert_eq!(cache_hits(), "1", "Second upload should skip hashing");
+
+    // The size is still verified for remembered digests.
+    let result = update("1234").await;
+    assert!(result.is_err(), "Expected size mismatch, got: {result:?}");
+
+    // Unhashed data must never replace the verified copy.
+    update("456").await?;
+    assert_eq!(
+        inner_store.get_part_unchunked(digest(), 0, None).await?,
+        "123",
+        "Skipped upload should not be written to the backend"
+    );
+
+    // Once the backend loses the digest it must be hashed again.
+    inner_store.remove_entry(digest().into()).await;
+    let result = update("456").await;
+    assert!(result.is_err(), "Expected hash mismatch, got: {result:?}");
+    update("123").await?;
+    assert_eq!(cache_hits(), "2", "Upload after eviction should be hashed");
+    update("123").await?;
+    assert_eq!(cache_hits(), "3", "Re-verified digest should be remembered");
+    Ok(())
+}
+
+#[nativelink_test]
+async fn read_verification_samples_reads_and_catches_corruption_test() -> Result<(), Error> {
+    let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let store = VerifyStore::new_with_rng(
+        &nativelink_config::stores::VerifyStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            verify_size: false,
+            verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.1,
+            content_policy: None,
+        },
+        Store::new(inner_store.clone()),
+        Box::new(StdRng::seed_from_u64(0)),
+    );
+
+    /// This value is blake3("123").
+    const HASH: &str = "b3d4f8803f7e24b8f389b072e75477cdbcfbe074080fb5e500e53e26e054158e";
+    let digest = DigestInfo::try_new(HASH, 3)?;
+    store.update_oneshot(digest, "123".into()).await?;
+    // Data that does not match its digest, stored around the VerifyStore.
+    let corrupt_digest = DigestInfo::try_new(VALID_HASH1, 3)?;
+    inner_store
+        .update_oneshot(corrupt_digest, "123".into())
+        .await?;
+
+    let ctx = make_ctx_for_hash_func(DigestHasherFunc::Blake3)?;
+    const READS: usize = 1000;
+    for _ in 0..READS {
+        let data = ctx
+            .clone()
+            .wrap_async(
+                info_span!("get_part_unchunked"),
+                store.get_part_unchunked(digest, 0, None),
+            )
+            .await?;
+        assert_eq!(data, "123");
+    }
+    let read_verifications: usize = metric_value(&store, "read_verifications_total")
+        .parse()
+        .unwrap();
+    assert!(
+        (READS / 20..READS / 5).contains(&read_verifications),
+        "Expected roughly 10% of {READS} reads to be verified, got {read_verifications}"
+    );
+    assert_eq!(
+        metric_value(&store, "read_verification_failures_total"),
+        "0"
+    );
+
+    // Unsampled reads serve the corrupt data, the first sampled one fails.
+    let err = loop {
+        let result = ctx
+            .clone()
+            .wrap_async(
+                info_span!("get_part_unchunked"),
+                store.get_part_unchunked(corrupt_digest, 0, None),
+            )
+            .await;
+        match result {
+            Ok(data) => assert_eq!(data, "123"),
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.code, Code::DataLoss, "Unexpected error: {err:?}");
+    assert_eq!(
+        metric_value(&store, "read_verifications_total"),
+        (read_verifications + 1).to_string()
+    );
+    assert_eq!(
+        metric_value(&store, "read_verification_failures_total"),
+        "1"
+    );
+    Ok(())
+}
+
+/// Rejects blobs that contain `MARKER`, even if it spans chunks.
+struct MarkerContentPolicy;
+
+const MARKER: &[u8] = b"SECRET";
+
+struct MarkerInspector {
+    /// End of the data seen so far, long enough to find a marker that
+    /// continues in the next chunk.
+    tail: Vec<u8>,
+}
+
+impl ContentPolicy for MarkerContentPolicy {
+    fn new_inspector(&self, _digest: DigestInfo) -> Option<Box<dyn ContentInspector>> {
+        Some(Box::new(MarkerInspector { tail: Vec::new() }))
+    }
+}
+
+impl ContentInspector for MarkerInspector {
+    fn inspect(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.tail.extend_from_slice(chunk);
+        if self
+            .tail
+            .windows(MARKER.len())
+            .any(|window| window == MARKER)
+        {
+            return Err(make_err!(Code::PermissionDenied, "Blob contains a secret"));
+        }
+        let keep_from = self.tail.len().saturating_sub(MARKER.len() - 1);
+        self.tail.drain(..keep_from);
+        Ok(())
+    }
+}
+
+#[nativelink_test]
+async fn content_policy_rejects_marked_blobs_test() -> Result<(), Error> {
+    let inner_store = MemoryStore::new(&nativelink_config::stores::MemoryStore::default());
+    let store = VerifyStore::new(
+        &nativelink_config::stores::VerifyStore {
+            backend: nativelink_config::stores::StoreConfig::memory(
+                nativelink_config::stores::MemoryStore::default(),
+            ),
+            verify_size: true,
+            verify_hash: false,
+            verified_digest_cache_size: 0,
+            read_verification_sample_rate: 0.0,
+            content_policy: None,
+        },
+        Store::new(inner_store.clone()),
+    );
+    store.set_content_policy(Arc::new(MarkerContentPolicy));
+
+    const COMPLIANT_VALUE: &str = "nothing to see here";
+    let compliant_digest = DigestInfo::try_new(VALID_HASH1, COMPLIANT_VALUE.len())?;
+    store
+        .update_oneshot(compliant_digest, COMPLIANT_VALUE.into())
+        .await?;
+    assert_eq!(
+        inner_store.has(compliant_digest).await,
+        Ok(Some(COMPLIANT_VALUE.len()))
+    );
+
+    // The marker is split across two chunks.
+    const CHUNK1: &str = "my SEC";
+    const CHUNK2: &str = "RET value";
+    let marked_digest = DigestInfo::try_new(
+        "0123456789abcdef000000000000000000020000000000000123456789abcdef",
+        CHUNK1.len() + CHUNK2.len(),
+    )?;
+    let (mut tx, rx) = make_buf_channel_pair();
+    let send_fut = async move {
+        tx.send(CHUNK1.into()).await?;
+        tx.send(CHUNK2.into()).await?;
+        tx.send_eof()
+    };
+    let (update_result, _) = futures::join!(
+        store.update(
+            marked_digest,
+            rx,
+            UploadSizeInfo::ExactSize(CHUNK1.len() + CHUNK2.len())
+        ),
+        send_fut,
+    );
+    let err = update_result.unwrap_err();
+    assert_eq!(
+        err.code,
+        Code::PermissionDenied,
+        "Unexpected error: {err:?}"
+    );
+    assert_eq!(inner_store.has(marked_digest).await, Ok(None));
+    assert_eq!(metric_value(&store, "content_policy_rejections_total"), "1");
+    Ok(())
+}
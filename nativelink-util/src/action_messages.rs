@@ -645,6 +645,73 @@ impl Default for ExecutionMetadata {
     }
 }
 
+impl ExecutionMetadata {
+    /// Fixes timestamps that cannot be correct, which usually happens when
+    /// the clock of the worker is wrong. Timestamps more than
+    /// `max_clock_skew` after `now` are clamped to `now`, then each phase
+    /// timestamp is raised to at least the timestamp of the phase before it,
+    /// in the order the phases happen on a worker. Unset timestamps
+    /// (`UNIX_EPOCH`) are left unset and skipped when ordering. Returns the
+    /// names of the timestamps that were changed.
+    pub fn normalize_timestamps(
+        &mut self,
+        now: SystemTime,
+        max_clock_skew: Duration,
+    ) -> Vec<&'static str> {
+        let latest_allowed = now + max_clock_skew;
+        let mut corrected = Vec::new();
+        let mut previous = SystemTime::UNIX_EPOCH;
+        for (name, timestamp) in [
+            ("queued_timestamp", &mut self.queued_timestamp),
+            ("worker_start_timestamp", &mut self.worker_start_timestamp),
+            (
+                "input_fetch_start_timestamp",
+                &mut self.input_fetch_start_timestamp,
+            ),
+            (
+                "input_fetch_completed_timestamp",
+                &mut self.input_fetch_completed_timestamp,
+            ),
+            (
+                "execution_start_timestamp",
+                &mut self.execution_start_timestamp,
+            ),
+            (
+                "execution_completed_timestamp",
+                &mut self.execution_completed_timestamp,
+            ),
+            (
+                "output_upload_start_timestamp",
+                &mut self.output_upload_start_timestamp,
+            ),
+            (
+                "output_upload_completed_timestamp",
+                &mut self.output_upload_completed_timestamp,
+            ),
+            (
+                "worker_completed_timestamp",
+                &mut self.worker_completed_timestamp,
+            ),
+        ] {
+            if *timestamp == SystemTime::UNIX_EPOCH {
+                continue;
+            }
+            let original = *timestamp;
+            if *timestamp > latest_allowed {
+                *timestamp = now;
+            }
+            if *timestamp < previous {
+                *timestamp = previous;
+            }
+            if *timestamp != original {
+                corrected.push(name);
+            }
+            previous = *timestamp;
+        }
+        corrected
+    }
+}
+
 impl From<ExecutionMetadata> for ExecutedActionMetadata {
     fn from(val: ExecutionMetadata) -> Self {
         Self {
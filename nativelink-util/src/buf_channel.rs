@@ -12,20 +12,101 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::VecDeque;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::task::Poll;
 
 use bytes::{Bytes, BytesMut};
 use futures::task::Context;
 use futures::{Future, Stream, TryFutureExt};
 use nativelink_error::{error_if, make_err, make_input_err, Code, Error, ResultExt};
-use tokio::sync::mpsc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Notify};
+
+use crate::metrics_utils::{metrics_enabled, CollectorState, MetricsComponent};
 
 const ZERO_DATA: Bytes = Bytes::new();
 
+/// Most bytes `consume_with_size_hint()` allocates before any data arrives,
+/// so a stream that claims a huge size can not make us allocate it up front.
+pub const MAX_SIZE_HINT_PREALLOCATION: usize = 4 * 1024 * 1024;
+
+static BUF_CHANNEL_METRICS: OnceLock<Arc<BufChannelMetrics>> = OnceLock::new();
+
+/// Process wide counters of how buf_channel streams were terminated.
+#[derive(Default)]
+pub struct BufChannelMetrics {
+    dropped_before_eof: AtomicU64,
+    eof_without_data: AtomicU64,
+    size_hint_exceeded: AtomicU64,
+    size_hint_reallocations: AtomicU64,
+}
+
+impl BufChannelMetrics {
+    /// Returns the global metrics shared by all buf_channel pairs.
+    pub fn global() -> &'static Arc<Self> {
+        BUF_CHANNEL_METRICS.get_or_init(Arc::default)
+    }
+
+    /// Number of writers that were dropped before sending an EOF, for
+    /// example because a client disconnected or a task panicked.
+    pub fn dropped_before_eof(&self) -> u64 {
+        self.dropped_before_eof.load(Ordering::Relaxed)
+    }
+
+    /// Number of streams that sent an EOF without sending any data.
+    pub fn eof_without_data(&self) -> u64 {
+        self.eof_without_data.load(Ordering::Relaxed)
+    }
+
+    /// Number of streams collected with `consume_with_size_hint()` that sent
+    /// more data than the size hint.
+    pub fn size_hint_exceeded(&self) -> u64 {
+        self.size_hint_exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Number of times `consume_with_size_hint()` had to grow its buffer
+    /// past the size hint.
+    pub fn size_hint_reallocations(&self) -> u64 {
+        self.size_hint_reallocations.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn inc(counter: &AtomicU64) {
+        if metrics_enabled() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl MetricsComponent for BufChannelMetrics {
+    fn gather_metrics(&self, c: &mut CollectorState) {
+        c.publish(
+            "dropped_before_eof",
+            &self.dropped_before_eof,
+            "Number of stream writers dropped before sending an EOF",
+        );
+        c.publish(
+            "eof_without_data",
+            &self.eof_without_data,
+            "Number of streams that sent an EOF without any data",
+        );
+        c.publish(
+            "size_hint_exceeded",
+            &self.size_hint_exceeded,
+            "Number of streams that sent more data than their size hint",
+        );
+        c.publish(
+            "size_hint_reallocations",
+            &self.size_hint_reallocations,
+            "Number of buffer reallocations past the size hint of a stream",
+        );
+    }
+}
+
 /// Create a channel pair that can be used to transport buffer objects around to
 /// different components. This wrapper is used because the streams give some
 /// utility like managing EOF in a more friendly way, ensure if no EOF is received
@@ -39,16 +120,19 @@ pub fn make_buf_channel_pair() -> (DropCloserWriteHalf, DropCloserReadHalf) {
     // thread is pumping large amounts of data into the channel.
     let (tx, rx) = mpsc::channel(2);
     let eof_sent = Arc::new(AtomicBool::new(false));
+    let read_progress = Arc::new(ReadProgress::default());
     (
         DropCloserWriteHalf {
             tx: Some(tx),
             bytes_written: 0,
             eof_sent: eof_sent.clone(),
+            read_progress: read_progress.clone(),
         },
         DropCloserReadHalf {
             rx,
             queued_data: VecDeque::new(),
             eof_sent,
+            read_progress,
             bytes_received: 0,
             recent_data: Vec::new(),
             max_recent_data_size: 0,
@@ -56,11 +140,20 @@ pub fn make_buf_channel_pair() -> (DropCloserWriteHalf, DropCloserReadHalf) {
     )
 }
 
+/// Number of bytes the reader took out of the channel, so the writer can
+/// wait for the reader to catch up.
+#[derive(Default)]
+struct ReadProgress {
+    bytes_read: AtomicU64,
+    notify: Notify,
+}
+
 /// Writer half of the pair.
 pub struct DropCloserWriteHalf {
     tx: Option<mpsc::Sender<Result<Bytes, Error>>>,
     bytes_written: u64,
     eof_sent: Arc<AtomicBool>,
+    read_progress: Arc<ReadProgress>,
 }
 
 impl DropCloserWriteHalf {
@@ -124,7 +217,7 @@ impl DropCloserWriteHalf {
             match self.send_get_bytes_on_error(chunk).await {
                 Ok(()) => {}
                 Err(e) => {
-                    reader.queued_data.push_front(Ok(e.1));
+                    reader.unread(Ok(e.1));
                     return Err(e.0).err_tip(|| "In DropCloserWriteHalf::bind::send");
                 }
             }
@@ -141,12 +234,29 @@ impl DropCloserWriteHalf {
         );
         // Flag that we have sent the EOF.
         self.eof_sent.store(true, Ordering::Release);
+        if self.bytes_written == 0 {
+            BufChannelMetrics::inc(&BufChannelMetrics::global().eof_without_data);
+        }
 
         // Now close our stream.
         self.tx = None;
         Ok(())
     }
 
+    /// Sends `err` to the receiver in place of an EOF and closes the stream.
+    /// The receiver gets `err` even if it already received all of the data.
+    pub async fn send_err(&mut self, err: Error) -> Result<(), Error> {
+        let tx = self.tx.take().ok_or_else(|| {
+            make_err!(Code::Internal, "Tried to send an error when pipe is broken")
+        })?;
+        tx.send(Err(err)).await.map_err(|_| {
+            make_err!(
+                Code::Internal,
+                "Failed to send error, receiver disconnected"
+            )
+        })
+    }
+
     /// Returns the number of bytes written so far. This does not mean the receiver received
     /// all of the bytes written to the stream so far.
     #[must_use]
@@ -154,6 +264,29 @@ impl DropCloserWriteHalf {
         self.bytes_written
     }
 
+    /// Returns the number of bytes written that the receiver has not taken
+    /// out of the channel yet.
+    #[must_use]
+    pub fn get_bytes_buffered(&self) -> u64 {
+        self.bytes_written
+            .saturating_sub(self.read_progress.bytes_read.load(Ordering::Acquire))
+    }
+
+    /// Waits until fewer than `watermark` bytes are buffered in the channel
+    /// (see `get_bytes_buffered()`), or the receiver went away. This lets a
+    /// writer only produce more data once the receiver has caught up.
+    pub async fn wait_for_bytes_buffered_below(&self, watermark: u64) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        while self.get_bytes_buffered() >= watermark {
+            tokio::select! {
+                () = self.read_progress.notify.notified() => {}
+                () = tx.closed() => return,
+            }
+        }
+    }
+
     /// Returns if the pipe was broken. This is good for determining if the reader broke the
     /// pipe or the writer broke the pipe, since this will only return true if the pipe was
     /// broken by the writer.
@@ -163,12 +296,23 @@ impl DropCloserWriteHalf {
     }
 }
 
+impl Drop for DropCloserWriteHalf {
+    fn drop(&mut self) {
+        // If `tx` is None either the EOF was sent or the receiver went away,
+        // neither of which is an abnormal termination of the writer.
+        if self.tx.is_some() && !self.eof_sent.load(Ordering::Acquire) {
+            BufChannelMetrics::inc(&BufChannelMetrics::global().dropped_before_eof);
+        }
+    }
+}
+
 /// Reader half of the pair.
 pub struct DropCloserReadHalf {
     rx: mpsc::Receiver<Result<Bytes, Error>>,
     /// Number of bytes received over the stream.
     bytes_received: u64,
     eof_sent: Arc<AtomicBool>,
+    read_progress: Arc<ReadProgress>,
     /// If not empty, this is the data that needs to be sent out before
     /// data from the underlying channel can should be sent.
     queued_data: VecDeque<Result<Bytes, Error>>,
@@ -199,7 +343,16 @@ impl DropCloserReadHalf {
             // then pass None to simulate the stream's version of EOF.
             Some(Ok(result_bytes)) => (!result_bytes.is_empty()).then(|| Ok(result_bytes)),
             Some(Err(cached_error)) => Some(Err(cached_error)),
-            None => self.rx.recv().await,
+            None => {
+                let maybe_chunk = self.rx.recv().await;
+                if let Some(Ok(chunk)) = &maybe_chunk {
+                    self.read_progress
+                        .bytes_read
+                        .fetch_add(chunk.len() as u64, Ordering::Release);
+                    self.read_progress.notify.notify_one();
+                }
+                maybe_chunk
+            }
         };
         match maybe_chunk {
             Some(Ok(chunk)) => {
@@ -213,10 +366,7 @@ impl DropCloserReadHalf {
                 Ok(chunk)
             }
 
-            Some(Err(e)) => Err(make_err!(
-                Code::Internal,
-                "Received erroneous queued_data chunk: {e}"
-            )),
+            Some(Err(e)) => Err(e).err_tip(|| "Received erroneous queued_data chunk"),
 
             // None is a safe EOF received.
             None => {
@@ -294,11 +444,56 @@ impl DropCloserReadHalf {
         Ok(())
     }
 
+    /// Copies all data in the stream into `writer` until an EOF is received,
+    /// then flushes `writer`. Returns the number of bytes written.
+    pub async fn write_to(&mut self, writer: &mut (dyn AsyncWrite + Unpin)) -> Result<u64, Error> {
+        let mut bytes_written = 0;
+        loop {
+            let chunk = self
+                .recv()
+                .await
+                .err_tip(|| "Failed to receive data in buf_channel::write_to")?;
+            if chunk.is_empty() {
+                break; // EOF.
+            }
+            bytes_written += chunk.len() as u64;
+            writer
+                .write_all(&chunk)
+                .await
+                .err_tip(|| "Failed to write data in buf_channel::write_to")?;
+        }
+        writer
+            .flush()
+            .await
+            .err_tip(|| "Failed to flush writer in buf_channel::write_to")?;
+        Ok(bytes_written)
+    }
+
+    /// Puts a chunk taken out by `recv()` back to the front of the stream.
+    /// The chunk is taken out of the received byte count and `recent_data`,
+    /// so it is only counted once when it is received again.
+    fn unread(&mut self, chunk: Result<Bytes, Error>) {
+        if let Ok(data) = &chunk {
+            self.bytes_received = self.bytes_received.saturating_sub(data.len() as u64);
+            // `recv()` put the chunk at the end of `recent_data`, if it is
+            // still being populated.
+            if let Some(last) = self.recent_data.pop() {
+                let received_len = last.len().saturating_sub(data.len());
+                if received_len > 0 {
+                    self.recent_data.push(last.slice(..received_len));
+                }
+            }
+        }
+        self.queued_data.push_front(chunk);
+    }
+
     /// Peek the next set of bytes in the stream without consuming them.
+    /// The bytes are only counted in `get_bytes_received()` once they are
+    /// received.
     pub async fn peek(&mut self) -> &Result<Bytes, Error> {
         if self.queued_data.is_empty() {
             let chunk = self.recv().await;
-            self.queued_data.push_front(chunk);
+            self.unread(chunk);
         }
         self.queued_data
             .front()
@@ -327,7 +522,7 @@ impl DropCloserReadHalf {
             }
             if chunk.len() > size {
                 let remaining = chunk.split_off(size);
-                self.queued_data.push_front(Ok(remaining));
+                self.unread(Ok(remaining));
                 // No need to read EOF if we are a partial chunk.
                 return Ok(chunk);
             }
@@ -358,7 +553,7 @@ impl DropCloserReadHalf {
             if output.len() + chunk.len() > size {
                 // Slice off the extra data and put it back into the queue. We are done.
                 let remaining = chunk.split_off(size - output.len());
-                self.queued_data.push_front(Ok(remaining));
+                self.unread(Ok(remaining));
             }
             output.extend_from_slice(&chunk);
             if output.len() == size {
@@ -367,6 +562,117 @@ impl DropCloserReadHalf {
         }
         Ok(output.freeze())
     }
+
+    /// Same as `consume(Some(size))`, but returns an error if the stream
+    /// reaches EOF before `size` bytes were received. Data past `size` is
+    /// left in the stream for the next read.
+    pub async fn take_exact(&mut self, size: usize) -> Result<Bytes, Error> {
+        if size == 0 {
+            return Ok(Bytes::new());
+        }
+        let data = self
+            .consume(Some(size))
+            .await
+            .err_tip(|| "In buf_channel::take_exact()")?;
+        if data.len() != size {
+            return Err(make_err!(
+                Code::Internal,
+                "Expected {size} bytes in buf_channel::take_exact(), but EOF was received after {} bytes",
+                data.len()
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Splits the stream into two readers that both receive every chunk,
+    /// the EOF and any error of this stream. The returned future forwards
+    /// the data and must be polled together with the readers (eg: with
+    /// `join!`). It only reads the next chunk once both readers have room
+    /// for it, so the slowest reader sets the pace. If a reader goes away
+    /// the future fails and the other reader gets an error instead of EOF.
+    pub fn tee(
+        mut self,
+    ) -> (
+        DropCloserReadHalf,
+        DropCloserReadHalf,
+        impl Future<Output = Result<(), Error>> + Send,
+    ) {
+        let (mut first_tx, first_rx) = make_buf_channel_pair();
+        let (mut second_tx, second_rx) = make_buf_channel_pair();
+        let forward_fut = async move {
+            loop {
+                let chunk = match self.recv().await {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        // Readers that already went away do not need the error.
+                        let _ = first_tx.send_err(err.clone()).await;
+                        let _ = second_tx.send_err(err.clone()).await;
+                        return Err(err).err_tip(|| "Failed to read data in buf_channel::tee");
+                    }
+                };
+                if chunk.is_empty() {
+                    first_tx
+                        .send_eof()
+                        .err_tip(|| "Failed to send EOF to first reader in buf_channel::tee")?;
+                    second_tx
+                        .send_eof()
+                        .err_tip(|| "Failed to send EOF to second reader in buf_channel::tee")?;
+                    return Ok(());
+                }
+                let (first_res, second_res) =
+                    futures::join!(first_tx.send(chunk.clone()), second_tx.send(chunk));
+                first_res
+                    .err_tip(|| "Failed to send to first reader in buf_channel::tee")
+                    .merge(
+                        second_res
+                            .err_tip(|| "Failed to send to second reader in buf_channel::tee"),
+                    )?;
+            }
+        };
+        (first_rx, second_rx, forward_fut)
+    }
+
+    /// Takes all the bytes in the stream into a buffer allocated up front
+    /// with `size_hint` bytes of capacity, or `MAX_SIZE_HINT_PREALLOCATION`
+    /// if that is less. Unlike `consume()` the data is always copied, so the
+    /// returned buffer owns exactly its own data. Up to `size_hint` bytes the
+    /// buffer grows towards the hint, past the hint it at least doubles every
+    /// time it grows, so a stream much larger than its hint is only
+    /// reallocated a logarithmic number of times.
+    pub async fn consume_with_size_hint(&mut self, size_hint: usize) -> Result<BytesMut, Error> {
+        let mut output = BytesMut::with_capacity(cmp::min(size_hint, MAX_SIZE_HINT_PREALLOCATION));
+        let mut reallocations = 0;
+        loop {
+            let chunk = self
+                .recv()
+                .await
+                .err_tip(|| "During read of buf_channel::consume_with_size_hint()")?;
+            if chunk.is_empty() {
+                break; // EOF.
+            }
+            let needed = output.len() + chunk.len();
+            if output.capacity() < needed {
+                let mut new_capacity = cmp::max(needed, output.capacity() * 2);
+                if needed <= size_hint {
+                    new_capacity = cmp::min(new_capacity, size_hint);
+                } else {
+                    reallocations += 1;
+                }
+                output.reserve(new_capacity - output.len());
+            }
+            output.extend_from_slice(&chunk);
+        }
+        if reallocations > 0 {
+            let metrics = BufChannelMetrics::global();
+            BufChannelMetrics::inc(&metrics.size_hint_exceeded);
+            if metrics_enabled() {
+                metrics
+                    .size_hint_reallocations
+                    .fetch_add(reallocations, Ordering::Relaxed);
+            }
+        }
+        Ok(output)
+    }
 }
 
 impl Stream for DropCloserReadHalf {
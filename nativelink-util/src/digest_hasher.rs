@@ -24,6 +24,7 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::buf_channel::DropCloserReadHalf;
 use crate::common::DigestInfo;
 use crate::origin_context::{ActiveOriginContext, OriginContext};
 use crate::{fs, make_symbol, spawn_blocking};
@@ -62,6 +63,12 @@ pub fn set_default_digest_hasher_func(hasher: DigestHasherFunc) -> Result<(), Er
 }
 
 /// Supported digest hash functions.
+///
+/// Only hash functions with 32 byte digests are supported, because
+/// `DigestInfo` stores the hash as a fixed 32 byte array that every store
+/// keys on. Legacy functions such as sha1 (20 bytes) and md5 (16 bytes)
+/// would need variable length digests across all stores, so they are
+/// rejected when a client requests them.
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum DigestHasherFunc {
     Sha256,
@@ -139,6 +146,12 @@ impl TryFrom<i32> for DigestHasherFunc {
         match ProtoDigestFunction::try_from(value) {
             Ok(ProtoDigestFunction::Sha256) => Ok(Self::Sha256),
             Ok(ProtoDigestFunction::Blake3) => Ok(Self::Blake3),
+            Ok(value @ (ProtoDigestFunction::Sha1 | ProtoDigestFunction::Md5)) => {
+                Err(make_input_err!(
+                    "Digest function {} is not supported, only digest functions with 32 byte hashes are",
+                    value.as_str_name()
+                ))
+            }
             value => Err(make_input_err!(
                 "Unknown or unsupported digest function for int conversion: {:?}",
                 value.map(|v| v.as_str_name())
@@ -201,6 +214,59 @@ pub trait DigestHasher {
     }
 }
 
+/// Computes a digest from data that arrives in chunks, for example while it
+/// is being streamed into or out of a store. The result is the same as
+/// hashing the concatenation of all chunks at once.
+pub struct StreamingHasher {
+    hasher: DigestHasherImpl,
+}
+
+impl StreamingHasher {
+    #[must_use]
+    pub fn new(digest_function: DigestHasherFunc) -> Self {
+        Self {
+            hasher: digest_function.hasher(),
+        }
+    }
+
+    /// Adds the next chunk of data to the hash.
+    #[inline]
+    pub fn update(&mut self, chunk: &[u8]) {
+        DigestHasher::update(&mut self.hasher, chunk);
+    }
+
+    /// Returns the number of bytes hashed so far.
+    #[must_use]
+    pub const fn bytes_hashed(&self) -> u64 {
+        self.hasher.hashed_size as u64
+    }
+
+    /// Returns the digest of all data passed to `update()`.
+    #[must_use]
+    pub fn finalize(mut self) -> DigestInfo {
+        self.hasher.finalize_digest()
+    }
+
+    /// Hashes all data received from `reader` until EOF.
+    pub async fn hash_reader(
+        digest_function: DigestHasherFunc,
+        reader: &mut DropCloserReadHalf,
+    ) -> Result<DigestInfo, Error> {
+        let mut hasher = Self::new(digest_function);
+        loop {
+            let chunk = reader
+                .recv()
+                .await
+                .err_tip(|| "In StreamingHasher::hash_reader")?;
+            if chunk.is_empty() {
+                break; // EOF.
+            }
+            hasher.update(&chunk);
+        }
+        Ok(hasher.finalize())
+    }
+}
+
 pub enum DigestHasherFuncImpl {
     Sha256(Sha256),
     Blake3(Box<Blake3Hasher>), // Box because Blake3Hasher is 1.3kb in size.
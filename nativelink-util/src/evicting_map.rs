@@ -314,6 +314,32 @@ where
         }
     }
 
+    /// Evicts the least recently used items until at least `percent` percent
+    /// of the currently stored bytes have been evicted, regardless of the
+    /// configured eviction policy. Returns the number of bytes evicted.
+    pub async fn evict_lru_percent(&self, percent: u8) -> u64 {
+        let mut state = self.state.lock().await;
+        let bytes_to_evict = (state.sum_store_size * u64::from(percent.min(100))).div_ceil(100);
+        let mut evicted_bytes = 0;
+        let mut evicted_items: u64 = 0;
+        while evicted_bytes < bytes_to_evict {
+            let Some((key, eviction_item)) = state.lru.pop_lru() else {
+                break;
+            };
+            evicted_bytes += eviction_item.data.len() as u64;
+            evicted_items += 1;
+            state.remove(&key, &eviction_item, false).await;
+        }
+        event!(
+            Level::DEBUG,
+            percent,
+            evicted_items,
+            evicted_bytes,
+            "Evicted least recently used items",
+        );
+        evicted_bytes
+    }
+
     /// Return the size of a `key`, if not found `None` is returned.
     pub async fn size_for_key<Q>(&self, key: &Q) -> Option<usize>
     where
@@ -324,6 +324,12 @@ pub struct AsyncTimer<'a> {
 }
 
 impl<'a> AsyncTimer<'a> {
+    /// Time passed since the timer was started.
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
     #[inline]
     pub fn measure(self) {
         if !metrics_enabled() {
@@ -475,6 +481,111 @@ impl MetricPublisher for &AsyncCounterWrapper {
     }
 }
 
+/// Upper bounds in nanos of the buckets of a `DurationHistogram`. Durations
+/// larger than the last bound are counted in an extra overflow bucket.
+const DURATION_HISTOGRAM_BOUNDS_NS: [u64; 13] = [
+    10_000,         // 10us.
+    50_000,         // 50us.
+    100_000,        // 100us.
+    500_000,        // 500us.
+    1_000_000,      // 1ms.
+    5_000_000,      // 5ms.
+    10_000_000,     // 10ms.
+    50_000_000,     // 50ms.
+    100_000_000,    // 100ms.
+    500_000_000,    // 500ms.
+    1_000_000_000,  // 1s.
+    5_000_000_000,  // 5s.
+    10_000_000_000, // 10s.
+];
+
+/// Tracks the distribution of durations in fixed buckets, so percentiles can
+/// be published without keeping every sample around.
+#[derive(Default)]
+pub struct DurationHistogram {
+    buckets: [AtomicU64; DURATION_HISTOGRAM_BOUNDS_NS.len() + 1],
+}
+
+impl DurationHistogram {
+    #[inline]
+    pub fn record(&self, duration: Duration) {
+        if !metrics_enabled() {
+            return;
+        }
+        let duration_ns = duration.as_nanos();
+        let index = DURATION_HISTOGRAM_BOUNDS_NS
+            .iter()
+            .position(|bound| duration_ns <= u128::from(*bound))
+            .unwrap_or(DURATION_HISTOGRAM_BOUNDS_NS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of recorded durations in each bucket. The last entry is the
+    /// overflow bucket.
+    pub fn bucket_counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Estimates the given quantile (0.0 - 1.0) as the upper bound of the
+    /// bucket it falls in. Durations in the overflow bucket are reported as
+    /// the largest bound. Returns `None` if nothing was recorded.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let counts = self.bucket_counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = ((quantile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                let index = index.min(DURATION_HISTOGRAM_BOUNDS_NS.len() - 1);
+                return Some(Duration::from_nanos(DURATION_HISTOGRAM_BOUNDS_NS[index]));
+            }
+        }
+        None
+    }
+}
+
+impl MetricPublisher for &DurationHistogram {
+    #[inline]
+    fn publish(&self, state: &mut CollectorState, name: String, help: String, labels: Labels) {
+        let counts = self.bucket_counts();
+        let mut cumulative = 0;
+        for (index, count) in counts.iter().enumerate() {
+            cumulative += count;
+            let bound = DURATION_HISTOGRAM_BOUNDS_NS
+                .get(index)
+                .map_or_else(|| "+Inf".to_string(), u64::to_string);
+            let mut labels = labels.clone();
+            labels.extend_from_slice(&[("le".into(), bound.into())]);
+            state.publish_number(
+                format!("{name}_bucket_ns"),
+                cumulative,
+                format!("{help} The number of durations less than or equal to the bucket bound in nanos."),
+                labels,
+            );
+        }
+        for quantile in [0.50, 0.90, 0.99] {
+            let Some(value) = self.percentile(quantile) else {
+                return;
+            };
+            let mut labels = labels.clone();
+            labels.extend_from_slice(&[("quantile".into(), format!("{quantile:.2}").into())]);
+            state.publish_number(
+                format!("{name}_ns"),
+                value.as_nanos() as u64,
+                format!("{help} The estimated duration percentile in nanos."),
+                labels,
+            );
+        }
+    }
+}
+
 /// Tracks an number.
 #[derive(Default)]
 pub struct Counter(AtomicU64);
@@ -23,7 +23,7 @@ use nativelink_proto::google::bytestream::{ReadResponse, WriteRequest};
 use parking_lot::Mutex;
 use tonic::{Status, Streaming};
 
-use crate::resource_info::ResourceInfo;
+use crate::resource_info::{check_resource_name_length, ResourceInfo};
 
 #[derive(Debug)]
 pub struct WriteRequestStreamWrapper<T, E>
@@ -43,13 +43,37 @@ where
     E: Into<Error>,
     T: Stream<Item = Result<WriteRequest, E>> + Unpin,
 {
-    pub async fn from(mut stream: T) -> Result<WriteRequestStreamWrapper<T, E>, Error> {
+    pub async fn from(stream: T) -> Result<WriteRequestStreamWrapper<T, E>, Error> {
+        Self::from_with_max_resource_name_length(stream, usize::MAX).await
+    }
+
+    /// Same as [`WriteRequestStreamWrapper::from`], but rejects the stream if
+    /// the resource name of the first message is longer than
+    /// `max_resource_name_length` bytes.
+    pub async fn from_with_max_resource_name_length(
+        stream: T,
+        max_resource_name_length: usize,
+    ) -> Result<WriteRequestStreamWrapper<T, E>, Error> {
+        Self::from_with_limits(stream, max_resource_name_length, usize::MAX).await
+    }
+
+    /// Same as [`WriteRequestStreamWrapper::from_with_max_resource_name_length`],
+    /// but also rejects the stream with `InvalidArgument` if the size of the
+    /// blob declared in the resource name is larger than `max_blob_size`.
+    /// This happens before any data is read past the first message.
+    pub async fn from_with_limits(
+        mut stream: T,
+        max_resource_name_length: usize,
+        max_blob_size: usize,
+    ) -> Result<WriteRequestStreamWrapper<T, E>, Error> {
         let first_msg = stream
             .next()
             .await
             .err_tip(|| "Error receiving first message in stream")?
             .err_tip(|| "Expected WriteRequest struct in stream")?;
 
+        check_resource_name_length(&first_msg.resource_name, max_resource_name_length)
+            .err_tip(|| "In WriteRequestStreamWrapper::from")?;
         let resource_info = ResourceInfo::new(&first_msg.resource_name, true)
             .err_tip(|| {
                 format!(
@@ -58,6 +82,13 @@ where
                 )
             })?
             .to_owned();
+        if resource_info.expected_size > max_blob_size {
+            return Err(make_input_err!(
+                "Blob size {} is larger than the maximum allowed size of {max_blob_size} bytes",
+                resource_info.expected_size
+            ))
+            .err_tip(|| "In WriteRequestStreamWrapper::from");
+        }
 
         Ok(WriteRequestStreamWrapper {
             resource_info,
@@ -14,7 +14,7 @@
 
 use std::borrow::Cow;
 
-use nativelink_error::{error_if, make_input_err, Error, ResultExt};
+use nativelink_error::{error_if, make_err, make_input_err, Code, Error, ResultExt};
 
 const ERROR_MSG: &str = concat!(
     "Expected resource_name to be of pattern ",
@@ -34,6 +34,24 @@ const DIGEST_FUNCTIONS: [&str; 9] = [
     "blake3",
 ];
 
+/// Default maximum length of a resource name accepted by
+/// [`ResourceInfo::new_with_max_length`].
+pub const DEFAULT_MAX_RESOURCE_NAME_LENGTH: usize = 4096;
+
+/// Returns `Code::InvalidArgument` if `resource_name` is longer than
+/// `max_length` bytes. The resource name is not included in the error to
+/// keep it small.
+pub fn check_resource_name_length(resource_name: &str, max_length: usize) -> Result<(), Error> {
+    if resource_name.len() > max_length {
+        return Err(make_err!(
+            Code::InvalidArgument,
+            "resource_name is {} bytes, which exceeds the maximum of {max_length} bytes",
+            resource_name.len()
+        ));
+    }
+    Ok(())
+}
+
 // Named struct to make the code easier to read when adding the slash size.
 const SLASH_SIZE: usize = 1;
 
@@ -92,6 +110,17 @@ pub struct ResourceInfo<'a> {
 }
 
 impl<'a> ResourceInfo<'a> {
+    /// Same as [`ResourceInfo::new`], but rejects resource names longer
+    /// than `max_length` bytes with `Code::InvalidArgument` before parsing.
+    pub fn new_with_max_length(
+        resource_name: &'a str,
+        is_upload: bool,
+        max_length: usize,
+    ) -> Result<ResourceInfo<'a>, Error> {
+        check_resource_name_length(resource_name, max_length)?;
+        Self::new(resource_name, is_upload)
+    }
+
     pub fn new(resource_name: &'a str, is_upload: bool) -> Result<ResourceInfo<'a>, Error> {
         // The most amount of slashes there can be to get to "(compressed-)blobs" section is 7.
         let mut rparts = resource_name.rsplitn(7, '/');
@@ -14,12 +14,13 @@
 
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::future::Future;
 use futures::stream::StreamExt;
 use nativelink_config::stores::{ErrorCode, Retry};
 use nativelink_error::{make_err, Code, Error};
+use parking_lot::Mutex;
 use tracing::{event, Level};
 
 struct ExponentialBackoff {
@@ -51,12 +52,66 @@ pub enum RetryResult<T> {
     Err(Error),
 }
 
+type NowFn = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+struct RetryBudgetState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket that limits how many retries may happen across every
+/// `Retrier` sharing it. Each retry withdraws one token and tokens refill at
+/// a constant rate, so during an outage retries are suppressed once the
+/// bucket is empty instead of multiplying the load on the backend.
+pub struct RetryBudget {
+    max_tokens: u64,
+    refill_tokens_per_s: u64,
+    now_fn: NowFn,
+    state: Mutex<RetryBudgetState>,
+}
+
+impl RetryBudget {
+    pub fn new(max_tokens: u64, refill_tokens_per_s: u64) -> Self {
+        Self::new_with_now_fn(max_tokens, refill_tokens_per_s, Arc::new(Instant::now))
+    }
+
+    pub fn new_with_now_fn(max_tokens: u64, refill_tokens_per_s: u64, now_fn: NowFn) -> Self {
+        let last_refill = (now_fn)();
+        Self {
+            max_tokens,
+            refill_tokens_per_s,
+            now_fn,
+            state: Mutex::new(RetryBudgetState {
+                tokens: max_tokens as f64,
+                last_refill,
+            }),
+        }
+    }
+
+    /// Attempts to take a token for a single retry. Returns false if the
+    /// budget is depleted and the retry should not happen.
+    pub fn try_acquire(&self) -> bool {
+        let now = (self.now_fn)();
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.refill_tokens_per_s as f64)
+            .min(self.max_tokens as f64);
+        state.last_refill = now;
+        if state.tokens < 1. {
+            return false;
+        }
+        state.tokens -= 1.;
+        true
+    }
+}
+
 /// Class used to retry a job with a sleep function in between each retry.
 #[derive(Clone)]
 pub struct Retrier {
     sleep_fn: SleepFn,
     jitter_fn: JitterFn,
     config: Retry,
+    retry_budget: Option<Arc<RetryBudget>>,
 }
 
 fn to_error_code(code: &Code) -> ErrorCode {
@@ -87,9 +142,18 @@ impl Retrier {
             sleep_fn,
             jitter_fn,
             config,
+            retry_budget: None,
         }
     }
 
+    /// Limits retries by `retry_budget`, which may be shared with other
+    /// `Retrier`s.
+    #[must_use]
+    pub fn with_retry_budget(mut self, retry_budget: Arc<RetryBudget>) -> Self {
+        self.retry_budget = Some(retry_budget);
+        self
+    }
+
     /// This should only return true if the error code should be interpreted as
     /// temporary.
     fn should_retry(&self, code: &Code) -> bool {
@@ -159,11 +223,18 @@ impl Retrier {
                             event!(Level::ERROR, ?attempt, ?err, "Not retrying permanent error");
                             return Err(err);
                         }
-                        (self.sleep_fn)(
-                            iter.next()
-                                .ok_or(err.append(format!("On attempt {attempt}")))?,
-                        )
-                        .await
+                        let delay = iter
+                            .next()
+                            .ok_or_else(|| err.clone().append(format!("On attempt {attempt}")))?;
+                        if let Some(retry_budget) = &self.retry_budget {
+                            if !retry_budget.try_acquire() {
+                                event!(Level::WARN, ?attempt, ?err, "Retry budget exhausted");
+                                return Err(err.append(format!(
+                                    "Retry budget exhausted on attempt {attempt}"
+                                )));
+                            }
+                        }
+                        (self.sleep_fn)(delay).await
                     }
                 }
             }
@@ -23,21 +23,28 @@ use std::sync::{Arc, OnceLock};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use futures::future::{select, Either};
-use futures::{join, try_join, Future, FutureExt};
+use futures::{join, stream, try_join, Future, FutureExt, Stream};
 use nativelink_error::{error_if, make_err, Code, Error, ResultExt};
 use rand::rngs::StdRng;
 use rand::{RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncSeekExt;
 use tokio::time::timeout;
+use tracing::{field, info_span, Instrument, Span};
 
 use crate::buf_channel::{make_buf_channel_pair, DropCloserReadHalf, DropCloserWriteHalf};
 use crate::common::DigestInfo;
 use crate::default_store_key_subscribe::default_store_key_subscribe;
-use crate::digest_hasher::{default_digest_hasher_func, DigestHasher, DigestHasherFunc};
+use crate::digest_hasher::{
+    default_digest_hasher_func, DigestHasher, DigestHasherFunc, StreamingHasher, ACTIVE_HASHER_FUNC,
+};
 use crate::fs::{self, idle_file_descriptor_timeout};
 use crate::health_utils::{HealthRegistryBuilder, HealthStatus, HealthStatusIndicator};
 use crate::metrics_utils::Registry;
+use crate::origin_context::ActiveOriginContext;
+
+/// Stream of digests returned by [`StoreLike::list_digests`].
+pub type DigestStream<'a> = Pin<Box<dyn Stream<Item = Result<DigestInfo, Error>> + Send + 'a>>;
 
 static DEFAULT_DIGEST_SIZE_HEALTH_CHECK: OnceLock<usize> = OnceLock::new();
 /// Default digest size for health check data. Any change in this value
@@ -60,6 +67,77 @@ pub fn set_default_digest_size_health_check(size: usize) -> Result<(), Error> {
     })
 }
 
+/// Returns the key and data that the health check of the store driver named
+/// `store_name` writes for `namespace`.
+pub fn health_check_data(namespace: &str, store_name: &str) -> (StoreKey<'static>, Bytes) {
+    let mut digest_data = vec![0u8; default_digest_size_health_check()];
+
+    let mut namespace_hasher = StdHasher::new();
+    namespace.hash(&mut namespace_hasher);
+    store_name.hash(&mut namespace_hasher);
+    let hash_seed = namespace_hasher.finish();
+
+    // Fill the digest data with random data based on a stable
+    // hash of the namespace and store name. Intention is to
+    // have randomly filled data that is unique per store and
+    // does not change between health checks. This is to ensure
+    // we are not adding more data to store on each health check.
+    let mut rng: StdRng = StdRng::seed_from_u64(hash_seed);
+    rng.fill_bytes(&mut digest_data);
+
+    let mut digest_hasher = default_digest_hasher_func().hasher();
+    digest_hasher.update(&digest_data);
+    (
+        StoreKey::from(digest_hasher.finalize_digest()),
+        Bytes::from(digest_data),
+    )
+}
+
+static SHORT_CIRCUIT_EMPTY_DIGEST: OnceLock<bool> = OnceLock::new();
+
+/// Whether `StoreLike` answers requests for the empty digest itself, without
+/// calling the store. Enabled unless disabled with
+/// `set_short_circuit_empty_digest()`.
+pub fn short_circuit_empty_digest() -> bool {
+    *SHORT_CIRCUIT_EMPTY_DIGEST.get_or_init(|| true)
+}
+
+/// Set whether the empty digest is short circuited, this should be called once.
+pub fn set_short_circuit_empty_digest(enabled: bool) -> Result<(), Error> {
+    SHORT_CIRCUIT_EMPTY_DIGEST
+        .set(enabled)
+        .map_err(|_| make_err!(Code::Internal, "set_short_circuit_empty_digest already set"))
+}
+
+pub const ZERO_BYTE_DIGESTS: [DigestInfo; 2] = [
+    // Sha256 hash of zero bytes.
+    DigestInfo::new(
+        [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+            0x78, 0x52, 0xb8, 0x55,
+        ],
+        0,
+    ),
+    // Blake3 hash of zero bytes.
+    DigestInfo::new(
+        [
+            0xaf, 0x13, 0x49, 0xb9, 0xf5, 0xf9, 0xa1, 0xa6, 0xa0, 0x40, 0x4d, 0xea, 0x36, 0xdc,
+            0xc9, 0x49, 0x9b, 0xcb, 0x25, 0xc9, 0xad, 0xc1, 0x12, 0xb7, 0xcc, 0x9a, 0x93, 0xca,
+            0xe4, 0x1f, 0x32, 0x62,
+        ],
+        0,
+    ),
+];
+
+#[inline]
+pub fn is_zero_digest<'a>(digest: impl Into<StoreKey<'a>>) -> bool {
+    match digest.into() {
+        StoreKey::Digest(digest) => digest.size_bytes == 0 && ZERO_BYTE_DIGESTS.contains(&digest),
+        _ => false,
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum UploadSizeInfo {
     /// When the data transfer amount is known to be exact size, this enum should be used.
@@ -130,6 +208,79 @@ pub async fn slow_update_store_with_file<S: StoreDriver + ?Sized>(
     }
 }
 
+/// Copies the data stored under `key` in `src_store` into `dst_store`.
+pub async fn copy_digest<'a>(
+    src_store: &'a impl StoreLike,
+    dst_store: &'a impl StoreLike,
+    key: impl Into<StoreKey<'a>>,
+) -> Result<(), Error> {
+    let key = key.into();
+    let size = src_store
+        .has(key.borrow())
+        .await
+        .err_tip(|| "In copy_digest::has")?
+        .err_tip_with_code(|_| (Code::NotFound, format!("{key:?} not found in source store")))?;
+    let (tx, rx) = make_buf_channel_pair();
+    try_join!(
+        src_store
+            .get(key.borrow(), tx)
+            .map(|r| r.err_tip(|| "Failed to read from source store in copy_digest")),
+        dst_store
+            .update(key.borrow(), rx, UploadSizeInfo::ExactSize(size))
+            .map(|r| r.err_tip(|| "Failed to write to destination store in copy_digest")),
+    )?;
+    Ok(())
+}
+
+/// Moves the data stored under `key` from `src_store` to `dst_store`.
+/// The source is only removed once the destination reports having the
+/// full object, so a failure part way through never loses the data.
+/// `src_store` must support [`StoreLike::remove`].
+pub async fn move_digest<'a>(
+    src_store: &'a impl StoreLike,
+    dst_store: &'a impl StoreLike,
+    key: impl Into<StoreKey<'a>>,
+) -> Result<(), Error> {
+    let key = key.into();
+    copy_digest(src_store, dst_store, key.borrow())
+        .await
+        .err_tip(|| "In move_digest")?;
+    let src_size = src_store
+        .has(key.borrow())
+        .await
+        .err_tip(|| "Failed to check source store in move_digest")?;
+    let dst_size = dst_store
+        .has(key.borrow())
+        .await
+        .err_tip(|| "Failed to check destination store in move_digest")?;
+    error_if!(
+        dst_size.is_none() || dst_size != src_size,
+        "Destination store has {dst_size:?} bytes for {key:?} but source has {src_size:?}, not removing source"
+    );
+    src_store
+        .remove(key)
+        .await
+        .err_tip(|| "Failed to remove source in move_digest")
+}
+
+/// Opaque token returned by [`StoreLike::update_with_consistency_token`].
+/// Passing it to the `*_with_consistency_token` read methods guarantees the
+/// read observes at least the write that produced it, even on eventually
+/// consistent backends. The meaning of the value is private to the store that
+/// issued it, so tokens must not be passed to a different store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConsistencyToken(u64);
+
+impl ConsistencyToken {
+    pub const fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub const fn value(&self) -> u64 {
+        self.0
+    }
+}
+
 /// Optimizations that stores may want to expose to the callers.
 /// This is useful for specific cases when the store can optimize the processing
 /// of the data being processed.
@@ -146,6 +297,9 @@ pub enum StoreOptimizations {
 
     /// If the store is optimized for serving subscriptions to keys.
     SubscribeChanges,
+
+    /// If the store can remove individual keys with `remove()`.
+    Remove,
 }
 
 /// A key that has been subscribed to in the store. This can be used
@@ -396,6 +550,22 @@ impl Store {
     }
 }
 
+/// Creates the span a store operation runs in. The span carries the key and
+/// the object size, and sets `otel.name` so an OpenTelemetry layer exports it
+/// as `store.<operation>`.
+fn store_operation_span(operation: &'static str, key: &StoreKey<'_>) -> Span {
+    let span = info_span!(
+        "store_operation",
+        otel.name = operation,
+        digest = %key.as_str(),
+        size = field::Empty,
+    );
+    if let StoreKey::Digest(digest) = key {
+        span.record("size", digest.size_bytes);
+    }
+    span
+}
+
 impl StoreLike for Store {
     #[inline]
     fn as_store_driver(&self) -> &'_ dyn StoreDriver {
@@ -442,7 +612,19 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         &'a self,
         digest: impl Into<StoreKey<'a>>,
     ) -> impl Future<Output = Result<Option<usize>, Error>> + 'a {
-        self.as_store_driver_pin().has(digest.into())
+        let key = digest.into();
+        let span = store_operation_span("store.has", &key);
+        async move {
+            if short_circuit_empty_digest() && is_zero_digest(key.borrow()) {
+                return Ok(Some(0));
+            }
+            let result = self.as_store_driver_pin().has(key).await;
+            if let Ok(Some(size)) = result {
+                Span::current().record("size", size);
+            }
+            result
+        }
+        .instrument(span)
     }
 
     /// Look up a list of digests in the store and return a result for each in
@@ -454,7 +636,11 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         &'a self,
         digests: &'a [StoreKey<'a>],
     ) -> impl Future<Output = Result<Vec<Option<usize>>, Error>> + Send + 'a {
-        self.as_store_driver_pin().has_many(digests)
+        async move {
+            let mut results = vec![None; digests.len()];
+            self.has_with_results(digests, &mut results).await?;
+            Ok(results)
+        }
     }
 
     /// The implementation of the above has and has_many functions.  See their
@@ -465,8 +651,34 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         digests: &'a [StoreKey<'a>],
         results: &'a mut [Option<usize>],
     ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
-        self.as_store_driver_pin()
-            .has_with_results(digests, results)
+        async move {
+            if !short_circuit_empty_digest()
+                || !digests.iter().any(|key| is_zero_digest(key.borrow()))
+            {
+                return self
+                    .as_store_driver_pin()
+                    .has_with_results(digests, results)
+                    .await;
+            }
+            // Only ask the store about the digests that are not empty.
+            let (indexes, keys): (Vec<usize>, Vec<StoreKey<'_>>) = digests
+                .iter()
+                .enumerate()
+                .filter(|(_, key)| !is_zero_digest(key.borrow()))
+                .map(|(i, key)| (i, key.borrow()))
+                .unzip();
+            let mut store_results = vec![None; keys.len()];
+            if !keys.is_empty() {
+                self.as_store_driver_pin()
+                    .has_with_results(&keys, &mut store_results)
+                    .await?;
+            }
+            results.fill(Some(0));
+            for (i, result) in indexes.into_iter().zip(store_results) {
+                results[i] = result;
+            }
+            Ok(())
+        }
     }
 
     /// List all the keys in the store that are within the given range.
@@ -507,8 +719,48 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         reader: DropCloserReadHalf,
         upload_size: UploadSizeInfo,
     ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
+        let key = digest.into();
+        let span = store_operation_span("store.update", &key);
+        if let UploadSizeInfo::ExactSize(size) = upload_size {
+            span.record("size", size);
+        }
+        // Boxed so the returned future stays `Unpin` like the driver's.
+        Box::pin(
+            async move {
+                if short_circuit_empty_digest() && is_zero_digest(key.borrow()) {
+                    let mut reader = reader;
+                    let data = reader
+                        .consume(None)
+                        .await
+                        .err_tip(|| "Failed to drain empty digest upload in StoreLike::update")?;
+                    error_if!(
+                        !data.is_empty(),
+                        "Received {} bytes for empty digest {key:?}",
+                        data.len()
+                    );
+                    return Ok(());
+                }
+                self.as_store_driver_pin()
+                    .update(key, reader, upload_size)
+                    .await
+            }
+            .instrument(span),
+        )
+    }
+
+    /// Same as `.update()`, but also returns a [`ConsistencyToken`] that can
+    /// be given to later reads to make sure they observe this write. Strongly
+    /// consistent stores return `None`, since every read already observes
+    /// every completed write.
+    #[inline]
+    fn update_with_consistency_token<'a>(
+        &'a self,
+        digest: impl Into<StoreKey<'a>>,
+        reader: DropCloserReadHalf,
+        upload_size: UploadSizeInfo,
+    ) -> impl Future<Output = Result<Option<ConsistencyToken>, Error>> + Send + 'a {
         self.as_store_driver_pin()
-            .update(digest.into(), reader, upload_size)
+            .update_with_consistency_token(digest.into(), reader, upload_size)
     }
 
     /// Any optimizations the store might want to expose to the callers.
@@ -532,6 +784,27 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
             .update_with_whole_file(digest.into(), file, upload_size)
     }
 
+    /// Removes the key from the store. Removing a key that does not exist
+    /// is not an error. Stores that cannot remove individual keys return
+    /// `Code::Unimplemented`.
+    #[inline]
+    fn remove<'a>(
+        &'a self,
+        key: impl Into<StoreKey<'a>>,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
+        self.as_store_driver_pin().remove(key.into())
+    }
+
+    /// Lists the digests of all the objects in the store whose key, in the
+    /// `{hash}-{size}` form, starts with `prefix`. The order is unspecified
+    /// and keys that are not digests are skipped. This is meant for tooling
+    /// like an external garbage collector and may be slow on large stores.
+    /// Stores that cannot list their contents return `Code::Unimplemented`.
+    #[inline]
+    fn list_digests<'a>(&'a self, prefix: &'a str) -> DigestStream<'a> {
+        self.as_store_driver_pin().list_digests(prefix)
+    }
+
     /// Utility to send all the data to the store when you have all the bytes.
     #[inline]
     fn update_oneshot<'a>(
@@ -539,8 +812,18 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         digest: impl Into<StoreKey<'a>>,
         data: Bytes,
     ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
-        self.as_store_driver_pin()
-            .update_oneshot(digest.into(), data)
+        let key = digest.into();
+        async move {
+            if short_circuit_empty_digest() && is_zero_digest(key.borrow()) {
+                error_if!(
+                    !data.is_empty(),
+                    "Received {} bytes for empty digest {key:?}",
+                    data.len()
+                );
+                return Ok(());
+            }
+            self.as_store_driver_pin().update_oneshot(key, data).await
+        }
     }
 
     /// Retreives part of the data from the store and writes it to the given writer.
@@ -557,11 +840,158 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         // expects the drop() method to be called on it when the future
         // is done due to the complex interaction between the DropCloserWriteHalf
         // and the DropCloserReadHalf during drop().
+        let span = store_operation_span("store.get_part", &key);
         async move {
+            if short_circuit_empty_digest() && is_zero_digest(key.borrow()) {
+                return writer
+                    .borrow_mut()
+                    .send_eof()
+                    .err_tip(|| "Failed to send EOF for empty digest in StoreLike::get_part");
+            }
             self.as_store_driver_pin()
                 .get_part(key, writer.borrow_mut(), offset, length)
                 .await
         }
+        .instrument(span)
+    }
+
+    /// Same as `.has()`, but if `token` is given, waits until the write that
+    /// produced it is visible before looking up the digest.
+    #[inline]
+    fn has_with_consistency_token<'a>(
+        &'a self,
+        digest: impl Into<StoreKey<'a>>,
+        token: Option<ConsistencyToken>,
+    ) -> impl Future<Output = Result<Option<usize>, Error>> + Send + 'a {
+        let key = digest.into();
+        async move {
+            if let Some(token) = token {
+                self.as_store_driver_pin()
+                    .wait_for_consistency(key.borrow(), token)
+                    .await
+                    .err_tip(|| "In StoreLike::has_with_consistency_token")?;
+            }
+            self.as_store_driver_pin().has(key).await
+        }
+    }
+
+    /// Same as `.get_part()`, but if `token` is given, waits until the write
+    /// that produced it is visible before reading the data.
+    #[inline]
+    fn get_part_with_consistency_token<'a>(
+        &'a self,
+        digest: impl Into<StoreKey<'a>>,
+        mut writer: impl BorrowMut<DropCloserWriteHalf> + Send + 'a,
+        offset: usize,
+        length: Option<usize>,
+        token: Option<ConsistencyToken>,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
+        let key = digest.into();
+        // Note: We need to capture `writer` for the same reason as `.get_part()`.
+        async move {
+            if let Some(token) = token {
+                self.as_store_driver_pin()
+                    .wait_for_consistency(key.borrow(), token)
+                    .await
+                    .err_tip(|| "In StoreLike::get_part_with_consistency_token")?;
+            }
+            self.as_store_driver_pin()
+                .get_part(key, writer.borrow_mut(), offset, length)
+                .await
+        }
+    }
+
+    /// Same as `.get_part()`, but hashes the data as it is streamed to
+    /// `writer`. If the whole blob was read and it does not match `digest`,
+    /// a `DataLoss` error is sent to `writer` in place of the EOF. Unlike
+    /// `VerifyStore`, which checks data as it is uploaded, this checks the
+    /// bytes that are served, so corruption is caught in every store.
+    /// The data is hashed with the digest function of the active origin
+    /// context, or the default one if none is set.
+    #[inline]
+    fn get_part_verified<'a>(
+        &'a self,
+        digest: DigestInfo,
+        mut writer: impl BorrowMut<DropCloserWriteHalf> + Send + 'a,
+        offset: usize,
+        length: Option<usize>,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
+        // Note: We need to capture `writer` for the same reason as `.get_part()`.
+        async move {
+            let digest_function = ActiveOriginContext::get_value(&ACTIVE_HASHER_FUNC)
+                .err_tip(|| "In StoreLike::get_part_verified")?
+                .map_or_else(default_digest_hasher_func, |v| *v);
+            // Only a read of the whole blob can be checked against its digest.
+            let is_full_read = offset == 0
+                && length.map_or(true, |length| {
+                    i64::try_from(length).map_or(true, |length| length >= digest.size_bytes)
+                });
+            let (tx, mut rx) = make_buf_channel_pair();
+            let writer = writer.borrow_mut();
+            let forward_fut = async move {
+                let mut hasher = StreamingHasher::new(digest_function);
+                loop {
+                    let chunk = rx
+                        .recv()
+                        .await
+                        .err_tip(|| "Failed to read data in StoreLike::get_part_verified")?;
+                    if chunk.is_empty() {
+                        break; // EOF.
+                    }
+                    if is_full_read {
+                        hasher.update(&chunk);
+                    }
+                    writer
+                        .send(chunk)
+                        .await
+                        .err_tip(|| "Failed to write data in StoreLike::get_part_verified")?;
+                }
+                if is_full_read {
+                    let actual_digest = hasher.finalize();
+                    if actual_digest != digest {
+                        let err = make_err!(
+                            Code::DataLoss,
+                            "Data served for {digest:?} does not match its digest, got {actual_digest:?}"
+                        );
+                        // The receiver may already be gone, in which case
+                        // there is nobody left to tell.
+                        let _ = writer.send_err(err.clone()).await;
+                        return Err(err);
+                    }
+                }
+                writer
+                    .send_eof()
+                    .err_tip(|| "Failed to send EOF in StoreLike::get_part_verified")
+            };
+            let (get_res, forward_res) =
+                join!(self.get_part(digest, tx, offset, length), forward_fut);
+            get_res.merge(forward_res)
+        }
+    }
+
+    /// Retrieves the last `length` bytes of the data from the store and writes
+    /// them to the given writer. If the data is smaller than `length`, all of
+    /// the data is written.
+    #[inline]
+    fn get_tail<'a>(
+        &'a self,
+        digest: impl Into<StoreKey<'a>>,
+        mut writer: impl BorrowMut<DropCloserWriteHalf> + Send + 'a,
+        length: usize,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
+        let key = digest.into();
+        // Note: We need to capture `writer` for the same reason as `.get_part()`.
+        async move {
+            if short_circuit_empty_digest() && is_zero_digest(key.borrow()) {
+                return writer
+                    .borrow_mut()
+                    .send_eof()
+                    .err_tip(|| "Failed to send EOF for empty digest in StoreLike::get_tail");
+            }
+            self.as_store_driver_pin()
+                .get_tail(key, writer.borrow_mut(), length)
+                .await
+        }
     }
 
     /// Utility that works the same as `.get_part()`, but writes all the data.
@@ -571,7 +1001,7 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         key: impl Into<StoreKey<'a>>,
         writer: DropCloserWriteHalf,
     ) -> impl Future<Output = Result<(), Error>> + Send + 'a {
-        self.as_store_driver_pin().get(key.into(), writer)
+        self.get_part(key, writer, 0, None)
     }
 
     /// Utility that will return all the bytes at once instead of in a streaming manner.
@@ -582,8 +1012,17 @@ pub trait StoreLike: Send + Sync + Sized + Unpin + 'static {
         offset: usize,
         length: Option<usize>,
     ) -> impl Future<Output = Result<Bytes, Error>> + Send + 'a {
-        self.as_store_driver_pin()
-            .get_part_unchunked(key.into(), offset, length)
+        let key = key.into();
+        let span = store_operation_span("store.get_part", &key);
+        async move {
+            if short_circuit_empty_digest() && is_zero_digest(key.borrow()) {
+                return Ok(Bytes::new());
+            }
+            self.as_store_driver_pin()
+                .get_part_unchunked(key, offset, length)
+                .await
+        }
+        .instrument(span)
     }
 
     /// Default implementation of the health check. Some stores may want to override this
@@ -647,6 +1086,28 @@ pub trait StoreDriver: Sync + Send + Unpin + HealthStatusIndicator + 'static {
         upload_size: UploadSizeInfo,
     ) -> Result<(), Error>;
 
+    /// See: [`StoreLike::update_with_consistency_token`] for details.
+    async fn update_with_consistency_token(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        reader: DropCloserReadHalf,
+        upload_size: UploadSizeInfo,
+    ) -> Result<Option<ConsistencyToken>, Error> {
+        self.update(key, reader, upload_size).await?;
+        Ok(None)
+    }
+
+    /// Waits until the write that produced `token` is visible to reads of
+    /// `key`. Strongly consistent stores return immediately. Stores that
+    /// wrap other stores must forward the token to the store that issued it.
+    async fn wait_for_consistency(
+        self: Pin<&Self>,
+        _key: StoreKey<'_>,
+        _token: ConsistencyToken,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// See: [`StoreLike::optimized_for`] for details.
     fn optimized_for(&self, _optimization: StoreOptimizations) -> bool {
         false
@@ -673,6 +1134,24 @@ pub trait StoreDriver: Sync + Send + Unpin + HealthStatusIndicator + 'static {
         Ok(Some(file))
     }
 
+    /// See: [`StoreLike::remove`] for details.
+    async fn remove(self: Pin<&Self>, _key: StoreKey<'_>) -> Result<(), Error> {
+        Err(make_err!(
+            Code::Unimplemented,
+            "Store::remove() not implemented for this store"
+        ))
+    }
+
+    /// See: [`StoreLike::list_digests`] for details.
+    fn list_digests<'a>(self: Pin<&'a Self>, _prefix: &'a str) -> DigestStream<'a> {
+        Box::pin(stream::once(async {
+            Err(make_err!(
+                Code::Unimplemented,
+                "Store::list_digests() not implemented for this store"
+            ))
+        }))
+    }
+
     /// See: [`StoreLike::update_oneshot`] for details.
     async fn update_oneshot(self: Pin<&Self>, key: StoreKey<'_>, data: Bytes) -> Result<(), Error> {
         // TODO(blaise.bruer) This is extremely inefficient, since we have exactly
@@ -708,6 +1187,31 @@ pub trait StoreDriver: Sync + Send + Unpin + HealthStatusIndicator + 'static {
         length: Option<usize>,
     ) -> Result<(), Error>;
 
+    /// See: [`StoreLike::get_tail`] for details.
+    /// The default implementation takes the size of the data from the digest,
+    /// or looks it up with `.has()` for string keys, and then calls
+    /// `.get_part()` with the computed offset. Stores that can read from the
+    /// end of the data directly, or whose `.has()` does not return the size
+    /// of the data `.get_part()` returns, should override this.
+    async fn get_tail(
+        self: Pin<&Self>,
+        key: StoreKey<'_>,
+        writer: &mut DropCloserWriteHalf,
+        length: usize,
+    ) -> Result<(), Error> {
+        let size = match key {
+            StoreKey::Digest(digest) => digest.size_bytes as usize,
+            StoreKey::Str(_) => self
+                .has(key.borrow())
+                .await
+                .err_tip(|| "Failed to get size in Store::get_tail")?
+                .ok_or_else(|| make_err!(Code::NotFound, "Key not found in Store::get_tail"))?,
+        };
+        let offset = size.saturating_sub(length);
+        self.get_part(key, writer, offset, Some(size - offset))
+            .await
+    }
+
     /// See: [`StoreLike::get`] for details.
     #[inline]
     async fn get(
@@ -748,28 +1252,8 @@ pub trait StoreDriver: Sync + Send + Unpin + HealthStatusIndicator + 'static {
 
     /// See: [`StoreLike::check_health`] for details.
     async fn check_health(self: Pin<&Self>, namespace: Cow<'static, str>) -> HealthStatus {
-        let digest_data_size = default_digest_size_health_check();
-        let mut digest_data = vec![0u8; digest_data_size];
-
-        let mut namespace_hasher = StdHasher::new();
-        namespace.hash(&mut namespace_hasher);
-        self.get_name().hash(&mut namespace_hasher);
-        let hash_seed = namespace_hasher.finish();
-
-        // Fill the digest data with random data based on a stable
-        // hash of the namespace and store name. Intention is to
-        // have randomly filled data that is unique per store and
-        // does not change between health checks. This is to ensure
-        // we are not adding more data to store on each health check.
-        let mut rng: StdRng = StdRng::seed_from_u64(hash_seed);
-        rng.fill_bytes(&mut digest_data);
-
-        let mut digest_hasher = default_digest_hasher_func().hasher();
-        digest_hasher.update(&digest_data);
-        let digest_data_len = digest_data.len();
-        let digest_info = StoreKey::from(digest_hasher.finalize_digest());
-
-        let digest_bytes = bytes::Bytes::copy_from_slice(&digest_data);
+        let (digest_info, digest_bytes) = health_check_data(&namespace, self.get_name());
+        let digest_data_len = digest_bytes.len();
 
         if let Err(e) = self
             .update_oneshot(digest_info.borrow(), digest_bytes.clone())
@@ -0,0 +1,85 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::join;
+use nativelink_error::Error;
+use nativelink_macro::nativelink_test;
+use nativelink_util::buf_channel::{make_buf_channel_pair, BufChannelMetrics};
+use pretty_assertions::assert_eq;
+
+const DATA: &str = "foo";
+
+// The metrics are process wide, so this is the only test in this binary to
+// avoid other tests changing the counters concurrently.
+#[nativelink_test]
+async fn counts_writers_dropped_before_eof_test() -> Result<(), Error> {
+    let metrics = BufChannelMetrics::global();
+
+    {
+        // A clean EOF does not count as a premature drop.
+        let (mut tx, mut rx) = make_buf_channel_pair();
+        tx.send(DATA.into()).await?;
+        tx.send_eof()?;
+        drop(tx);
+        assert_eq!(rx.consume(None).await?, DATA);
+        assert_eq!(metrics.dropped_before_eof(), 0);
+        assert_eq!(metrics.eof_without_data(), 0);
+    }
+    {
+        let (mut tx, mut rx) = make_buf_channel_pair();
+        tx.send(DATA.into()).await?;
+        drop(tx);
+        assert_eq!(rx.recv().await?, DATA);
+        assert!(
+            rx.recv().await.is_err(),
+            "Expected error after premature drop"
+        );
+        assert_eq!(metrics.dropped_before_eof(), 1);
+    }
+    {
+        let (mut tx, mut rx) = make_buf_channel_pair();
+        tx.send_eof()?;
+        assert_eq!(rx.recv().await?, "");
+        assert_eq!(metrics.eof_without_data(), 1);
+        assert_eq!(metrics.dropped_before_eof(), 1);
+    }
+    {
+        // Collecting a stream much larger than its size hint.
+        const CHUNK_SIZE: usize = 1024;
+        const CHUNK_COUNT: usize = 64;
+        let (mut tx, mut rx) = make_buf_channel_pair();
+        let tx_fut = async move {
+            for i in 0..CHUNK_COUNT {
+                tx.send(vec![i as u8; CHUNK_SIZE].into()).await?;
+            }
+            tx.send_eof()
+        };
+        let (buffer, send_result) = join!(rx.consume_with_size_hint(10), tx_fut);
+        send_result?;
+        let buffer = buffer?;
+        let expected: Vec<u8> = (0..CHUNK_COUNT)
+            .flat_map(|i| vec![i as u8; CHUNK_SIZE])
+            .collect();
+        assert_eq!(buffer, expected);
+        assert!(buffer.capacity() <= 2 * buffer.len());
+        assert_eq!(metrics.size_hint_exceeded(), 1);
+        // The buffer grows geometrically instead of once per chunk.
+        let reallocations = metrics.size_hint_reallocations();
+        assert!(
+            reallocations > 0 && reallocations <= 8,
+            "Expected at most 8 reallocations, got {reallocations}"
+        );
+    }
+    Ok(())
+}
@@ -18,9 +18,9 @@ use bytes::{Bytes, BytesMut};
 use futures::poll;
 use nativelink_error::{make_err, Code, Error, ResultExt};
 use nativelink_macro::nativelink_test;
-use nativelink_util::buf_channel::make_buf_channel_pair;
+use nativelink_util::buf_channel::{make_buf_channel_pair, MAX_SIZE_HINT_PREALLOCATION};
 use pretty_assertions::assert_eq;
-use tokio::try_join;
+use tokio::{join, try_join};
 
 const DATA1: &str = "foo";
 const DATA2: &str = "bar";
@@ -87,6 +87,69 @@ async fn consume_all_test() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn consume_with_size_hint_allocates_once_test() -> Result<(), Error> {
+    let expected = format!("{DATA1}{DATA2}{DATA3}");
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send(DATA3.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        let buffer = rx.consume_with_size_hint(expected.len()).await?;
+        assert_eq!(buffer, expected.as_bytes());
+        // The buffer was allocated once with the hinted size and never grew.
+        assert_eq!(buffer.capacity(), expected.len());
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn consume_with_size_hint_caps_preallocation_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        // A stream claiming to be huge must not be allocated up front.
+        let buffer = rx.consume_with_size_hint(usize::MAX).await?;
+        assert_eq!(buffer, DATA1.as_bytes());
+        assert_eq!(buffer.capacity(), MAX_SIZE_HINT_PREALLOCATION);
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn write_to_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send(DATA3.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        let mut output = Vec::new();
+        let bytes_written = rx.write_to(&mut output).await?;
+        let expected = format!("{DATA1}{DATA2}{DATA3}");
+        assert_eq!(bytes_written, expected.len() as u64);
+        assert_eq!(output, expected.as_bytes());
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
 /// Test to ensure data is optimized so that the exact same pointer is received
 /// when calling `collect_all_with_size_hint` when a copy is not needed.
 #[nativelink_test]
@@ -175,6 +238,186 @@ async fn consume_some_reads_eof() -> Result<(), Error> {
     Ok(())
 }
 
+#[nativelink_test]
+async fn take_exact_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        assert_eq!(
+            rx.take_exact(DATA1.len() + DATA2.len()).await?,
+            Bytes::from(format!("{DATA1}{DATA2}"))
+        );
+        assert_eq!(rx.recv().await?, Bytes::new(), "Expected EOF");
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn take_exact_over_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        let all_data = Bytes::from(format!("{DATA1}{DATA2}"));
+        // Ends in the middle of the second chunk.
+        assert_eq!(rx.take_exact(4).await?, all_data.slice(0..4));
+        // The rest of the partial chunk is still readable.
+        assert_eq!(rx.take_exact(2).await?, all_data.slice(4..6));
+        assert_eq!(rx.consume(None).await?, Bytes::new(), "Expected EOF");
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn take_exact_under_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        let err = rx
+            .take_exact(100)
+            .await
+            .expect_err("Expected take_exact to fail on early EOF");
+        assert_eq!(err.code, Code::Internal);
+        assert!(
+            err.to_string().contains("EOF was received after 6 bytes"),
+            "Unexpected error: {err:?}"
+        );
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn peek_does_not_consume_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        assert_eq!(rx.peek().await, &Ok(Bytes::from(DATA1)));
+        // Peeking again returns the same chunk.
+        assert_eq!(rx.peek().await, &Ok(Bytes::from(DATA1)));
+        assert_eq!(rx.get_bytes_received(), 0);
+        assert_eq!(rx.recv().await?, Bytes::from(DATA1));
+        assert_eq!(rx.get_bytes_received(), DATA1.len() as u64);
+        assert_eq!(rx.recv().await?, Bytes::from(DATA2));
+        assert_eq!(rx.recv().await?, Bytes::new(), "Expected EOF");
+        assert_eq!(rx.get_bytes_received(), (DATA1.len() + DATA2.len()) as u64);
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn peek_then_reset_stream_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    rx.set_max_recent_data_size(100);
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let rx_fut = async move {
+        assert_eq!(rx.recv().await?, Bytes::from(DATA1));
+        assert_eq!(rx.peek().await, &Ok(Bytes::from(DATA2)));
+        rx.try_reset_stream()?;
+        // The peeked chunk is replayed exactly once.
+        assert_eq!(
+            rx.consume(None).await?,
+            Bytes::from(format!("{DATA1}{DATA2}"))
+        );
+        Result::<(), Error>::Ok(())
+    };
+    try_join!(tx_fut, rx_fut)?;
+    Ok(())
+}
+
+#[nativelink_test]
+async fn tee_sends_same_data_to_both_readers_test() -> Result<(), Error> {
+    let (mut tx, rx) = make_buf_channel_pair();
+    let (mut first_rx, mut second_rx, tee_fut) = rx.tee();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        tx.send(DATA2.into()).await?;
+        tx.send(DATA3.into()).await?;
+        tx.send_eof()?;
+        Result::<(), Error>::Ok(())
+    };
+    let (tx_res, tee_res, first_res, second_res) = join!(
+        tx_fut,
+        tee_fut,
+        first_rx.consume(None),
+        second_rx.consume(None)
+    );
+    tx_res?;
+    tee_res?;
+    let expected = Bytes::from(format!("{DATA1}{DATA2}{DATA3}"));
+    assert_eq!(first_res?, expected);
+    assert_eq!(second_res?, expected);
+    Ok(())
+}
+
+#[nativelink_test]
+async fn tee_sends_error_to_both_readers_test() -> Result<(), Error> {
+    let (mut tx, rx) = make_buf_channel_pair();
+    let (first_rx, second_rx, tee_fut) = rx.tee();
+    let tx_fut = async move {
+        tx.send(DATA1.into()).await?;
+        // Dropped without sending EOF.
+        Result::<(), Error>::Ok(())
+    };
+    let collect = |mut rx: nativelink_util::buf_channel::DropCloserReadHalf| async move {
+        let mut received = BytesMut::new();
+        loop {
+            match rx.recv().await {
+                Ok(chunk) if chunk.is_empty() => return (received, Ok(())),
+                Ok(chunk) => received.extend_from_slice(&chunk),
+                Err(err) => return (received, Err(err)),
+            }
+        }
+    };
+    let (tx_res, tee_res, (first_data, first_end), (second_data, second_end)) =
+        join!(tx_fut, tee_fut, collect(first_rx), collect(second_rx));
+    tx_res?;
+    assert_eq!(tee_res.map_err(|err| err.code), Err(Code::Internal));
+    assert_eq!(first_data, DATA1.as_bytes());
+    assert_eq!(second_data, DATA1.as_bytes());
+    let first_err = first_end.expect_err("Expected first reader to get an error");
+    let second_err = second_end.expect_err("Expected second reader to get an error");
+    assert_eq!(first_err, second_err);
+    assert!(
+        first_err
+            .to_string()
+            .contains("Sender dropped before sending EOF"),
+        "Unexpected error: {first_err:?}"
+    );
+    Ok(())
+}
+
 #[nativelink_test]
 async fn simple_stream_test() -> Result<(), Error> {
     use futures::StreamExt;
@@ -0,0 +1,79 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use nativelink_error::{Code, Error};
+use nativelink_macro::nativelink_test;
+use nativelink_proto::build::bazel::remote::execution::v2::digest_function::Value as ProtoDigestFunction;
+use nativelink_util::buf_channel::make_buf_channel_pair;
+use nativelink_util::digest_hasher::{DigestHasher, DigestHasherFunc, StreamingHasher};
+use pretty_assertions::assert_eq;
+
+const DATA: &str = "The quick brown fox jumps over the lazy dog";
+
+fn one_shot_digest(digest_function: DigestHasherFunc) -> nativelink_util::common::DigestInfo {
+    let mut hasher = digest_function.hasher();
+    hasher.update(DATA.as_bytes());
+    hasher.finalize_digest()
+}
+
+#[nativelink_test]
+async fn chunked_hash_matches_one_shot_hash_test() -> Result<(), Error> {
+    for digest_function in [DigestHasherFunc::Sha256, DigestHasherFunc::Blake3] {
+        let mut hasher = StreamingHasher::new(digest_function);
+        for chunk in Bytes::from(DATA).chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.bytes_hashed(), DATA.len() as u64);
+        assert_eq!(
+            hasher.finalize(),
+            one_shot_digest(digest_function),
+            "Digest mismatch for {digest_function}"
+        );
+    }
+    Ok(())
+}
+
+#[nativelink_test]
+async fn hash_reader_matches_one_shot_hash_test() -> Result<(), Error> {
+    let (mut tx, mut rx) = make_buf_channel_pair();
+    let send_fut = async move {
+        for chunk in Bytes::from(DATA).chunks(5) {
+            tx.send(Bytes::copy_from_slice(chunk)).await?;
+        }
+        tx.send_eof()
+    };
+    let (send_result, digest) = tokio::join!(
+        send_fut,
+        StreamingHasher::hash_reader(DigestHasherFunc::Blake3, &mut rx)
+    );
+    send_result?;
+    assert_eq!(digest?, one_shot_digest(DigestHasherFunc::Blake3));
+    Ok(())
+}
+
+#[nativelink_test]
+async fn legacy_digest_functions_are_rejected_test() -> Result<(), Error> {
+    for digest_function in [ProtoDigestFunction::Sha1, ProtoDigestFunction::Md5] {
+        let err = DigestHasherFunc::try_from(digest_function as i32)
+            .expect_err("Expected legacy digest function to be rejected");
+        assert_eq!(err.code, Code::InvalidArgument);
+        assert!(
+            err.to_string()
+                .contains("only digest functions with 32 byte hashes"),
+            "Unexpected error: {err}"
+        );
+    }
+    Ok(())
+}
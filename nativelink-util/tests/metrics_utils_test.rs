@@ -0,0 +1,43 @@
+// Copyright 2024 The NativeLink Authors. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use nativelink_error::Error;
+use nativelink_macro::nativelink_test;
+use nativelink_util::metrics_utils::DurationHistogram;
+use pretty_assertions::assert_eq;
+
+#[nativelink_test]
+async fn duration_histogram_percentiles_test() -> Result<(), Error> {
+    let histogram = DurationHistogram::default();
+    assert_eq!(histogram.percentile(0.5), None);
+
+    for _ in 0..98 {
+        histogram.record(Duration::from_micros(5));
+    }
+    histogram.record(Duration::from_millis(3));
+    histogram.record(Duration::from_secs(60));
+
+    let counts = histogram.bucket_counts();
+    assert_eq!(counts.iter().sum::<u64>(), 100);
+    assert_eq!(counts[0], 98);
+    assert_eq!(counts[counts.len() - 1], 1, "Expected one overflow sample");
+
+    assert_eq!(histogram.percentile(0.50), Some(Duration::from_micros(10)));
+    assert_eq!(histogram.percentile(0.99), Some(Duration::from_millis(5)));
+    // Overflowing durations are reported as the largest bound.
+    assert_eq!(histogram.percentile(1.0), Some(Duration::from_secs(10)));
+    Ok(())
+}
@@ -14,6 +14,7 @@
 
 use std::borrow::Cow;
 
+use nativelink_error::Code;
 use nativelink_macro::nativelink_test;
 use nativelink_util::resource_info::ResourceInfo;
 use pretty_assertions::assert_eq;
@@ -707,3 +708,24 @@ async fn write_invalid_size_test() -> Result<(), Box<dyn std::error::Error>> {
     assert!(ResourceInfo::new(RESOURCE_NAME, true).is_err());
     Ok(())
 }
+
+#[nativelink_test]
+async fn resource_name_over_max_length_is_rejected_test() -> Result<(), Box<dyn std::error::Error>>
+{
+    const RESOURCE_NAME: &str = "instance_name/blobs/hash/12345";
+    let resource_info =
+        ResourceInfo::new_with_max_length(RESOURCE_NAME, false, RESOURCE_NAME.len())?;
+    assert_eq!(resource_info.instance_name, "instance_name");
+    assert_eq!(resource_info.hash, "hash");
+    assert_eq!(resource_info.expected_size, 12345);
+
+    let long_resource_name = format!("{}/blobs/hash/12345", "a/".repeat(10_000));
+    let err = ResourceInfo::new_with_max_length(&long_resource_name, false, 4096)
+        .expect_err("Expected over length resource name to be rejected");
+    assert_eq!(err.code, Code::InvalidArgument);
+    assert!(
+        !err.to_string().contains(&long_resource_name),
+        "Expected error to not contain the resource name, got {err}"
+    );
+    Ok(())
+}
@@ -13,15 +13,16 @@
 // limitations under the License.
 
 use std::pin::Pin;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use futures::future::ready;
 use futures::stream::repeat_with;
 use nativelink_config::stores::Retry;
 use nativelink_error::{make_err, Code, Error};
 use nativelink_macro::nativelink_test;
-use nativelink_util::retry::{Retrier, RetryResult};
+use nativelink_util::retry::{Retrier, RetryBudget, RetryResult};
 use pretty_assertions::assert_eq;
 use tokio::time::Duration;
 
@@ -178,3 +179,48 @@ async fn retry_calls_sleep_fn() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[nativelink_test]
+async fn retry_budget_stops_retries_until_refilled() -> Result<(), Error> {
+    let start = Instant::now();
+    let elapsed_s = Arc::new(AtomicU64::new(0));
+    let retry_budget = Arc::new(RetryBudget::new_with_now_fn(2, 1, {
+        let elapsed_s = elapsed_s.clone();
+        Arc::new(move || start + Duration::from_secs(elapsed_s.load(Ordering::Relaxed)))
+    }));
+    let retrier = Retrier::new(
+        Arc::new(|_duration| Box::pin(ready(()))),
+        Arc::new(move |_delay| Duration::from_millis(1)),
+        Retry {
+            max_retries: 5,
+            ..Default::default()
+        },
+    )
+    .with_retry_budget(retry_budget);
+    let run_count = Arc::new(AtomicI32::new(0));
+    let run_failing = || {
+        retrier.retry(repeat_with(|| {
+            run_count.fetch_add(1, Ordering::Relaxed);
+            RetryResult::<bool>::Retry(make_err!(Code::Unavailable, "Dummy failure",))
+        }))
+    };
+
+    // The two tokens in the budget allow two retries, then retrying stops.
+    let result = run_failing().await;
+    assert_eq!(run_count.swap(0, Ordering::Relaxed), 3);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Error { code: Unavailable, messages: [\"Dummy failure\", \"Retry budget exhausted on attempt 3\"] }"
+    );
+
+    // Budget is depleted, so no retries happen at all.
+    assert!(run_failing().await.is_err());
+    assert_eq!(run_count.swap(0, Ordering::Relaxed), 1);
+
+    // After one second, a single token is refilled allowing one retry.
+    elapsed_s.store(1, Ordering::Relaxed);
+    assert!(run_failing().await.is_err());
+    assert_eq!(run_count.swap(0, Ordering::Relaxed), 2);
+
+    Ok(())
+}
@@ -39,7 +39,7 @@ use futures::stream::{FuturesUnordered, StreamExt, TryStreamExt};
 use nativelink_config::cas_server::{
     EnvironmentSource, UploadActionResultConfig, UploadCacheResultsStrategy,
 };
-use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
+use nativelink_error::{error_if, make_err, make_input_err, Code, Error, ResultExt};
 use nativelink_proto::build::bazel::remote::execution::v2::{
     Action, ActionResult as ProtoActionResult, Command as ProtoCommand,
     Directory as ProtoDirectory, Directory, DirectoryNode, ExecuteResponse, FileNode, SymlinkNode,
@@ -149,6 +149,7 @@ pub fn download_to_directory<'a>(
             futures.push(
                 cas_store
                     .populate_fast_store(digest.into())
+                    .map_err(Error::from)
                     .and_then(move |_| async move {
                         let file_entry = filesystem_store
                             .get_file_entry_for_digest(&digest)
@@ -1051,15 +1052,43 @@ impl RunningActionImpl {
             output_paths.append(&mut command_proto.output_files);
             output_paths.append(&mut command_proto.output_directories);
         }
-        for entry in output_paths {
-            let full_path = OsString::from(if command_proto.working_directory.is_empty() {
-                format!("{}/{}", self.work_directory, entry)
-            } else {
-                format!(
-                    "{}/{}/{}",
-                    self.work_directory, command_proto.working_directory, entry
-                )
-            });
+        let output_paths: Vec<(String, OsString)> = output_paths
+            .into_iter()
+            .map(|entry| {
+                let full_path = OsString::from(if command_proto.working_directory.is_empty() {
+                    format!("{}/{}", self.work_directory, entry)
+                } else {
+                    format!(
+                        "{}/{}/{}",
+                        self.work_directory, command_proto.working_directory, entry
+                    )
+                });
+                (entry, full_path)
+            })
+            .collect();
+        // Outputs are counted before anything is uploaded, so an action with
+        // too many outputs does not fill the CAS with them.
+        let max_output_count = self
+            .running_actions_manager
+            .upload_action_results
+            .max_output_count;
+        if max_output_count != 0 {
+            let mut output_count = 0;
+            for (_, full_path) in &output_paths {
+                match fs::symlink_metadata(full_path).await {
+                    Ok(_) => output_count += 1,
+                    Err(e) if e.code == Code::NotFound => {}
+                    Err(e) => {
+                        return Err(e).err_tip(|| format!("Could not open file {full_path:?}"))
+                    }
+                }
+            }
+            error_if!(
+                output_count > max_output_count,
+                "Action produced {output_count} outputs, which is more than the maximum of {max_output_count} outputs allowed"
+            );
+        }
+        for (entry, full_path) in output_paths {
             let work_directory = &self.work_directory;
             output_path_futures.push(async move {
                 let metadata = {
@@ -1395,6 +1424,8 @@ struct UploadActionResults {
     historical_store: Store,
     success_message_template: Template,
     failure_message_template: Template,
+    /// Maximum number of outputs an action may produce, 0 means no limit.
+    max_output_count: usize,
 }
 
 impl UploadActionResults {
@@ -1436,6 +1467,7 @@ impl UploadActionResults {
                     )
                 },
             )?,
+            max_output_count: config.max_output_count,
         })
     }
 
@@ -423,6 +423,8 @@ async fn new_local_worker_creates_work_directory_test() -> Result<(), Box<dyn st
             slow: nativelink_config::stores::StoreConfig::memory(
                 nativelink_config::stores::MemoryStore::default(),
             ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         Store::new(
             <FilesystemStore>::new(&nativelink_config::stores::FilesystemStore {
@@ -471,6 +473,8 @@ async fn new_local_worker_removes_work_directory_before_start_test(
             slow: nativelink_config::stores::StoreConfig::memory(
                 nativelink_config::stores::MemoryStore::default(),
             ),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         Store::new(
             <FilesystemStore>::new(&nativelink_config::stores::FilesystemStore {
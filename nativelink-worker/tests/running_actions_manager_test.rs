@@ -48,7 +48,7 @@ use nativelink_util::action_messages::{
 };
 use nativelink_util::common::{fs, DigestInfo};
 use nativelink_util::digest_hasher::{DigestHasher, DigestHasherFunc};
-use nativelink_util::store_trait::{Store, StoreLike};
+use nativelink_util::store_trait::{Store, StoreLike, ZERO_BYTE_DIGESTS};
 use nativelink_worker::running_actions_manager::{
     download_to_directory, Callbacks, ExecutionConfiguration, RunningAction, RunningActionImpl,
     RunningActionsManager, RunningActionsManagerArgs, RunningActionsManagerImpl,
@@ -101,6 +101,8 @@ async fn setup_stores() -> Result<
         &nativelink_config::stores::FastSlowStore {
             fast: nativelink_config::stores::StoreConfig::filesystem(fast_config),
             slow: nativelink_config::stores::StoreConfig::memory(slow_config),
+            fast_store_max_populate_size: 0,
+            fast_store_max_populate_bytes: None,
         },
         Store::new(fast_store.clone()),
         Store::new(slow_store.clone()),
@@ -408,6 +410,56 @@ async fn download_to_directory_symlink_download_test() -> Result<(), Box<dyn std
     Ok(())
 }
 
+#[nativelink_test]
+async fn download_to_directory_empty_file_download_test() -> Result<(), Box<dyn std::error::Error>>
+{
+    const FILE_NAME: &str = "empty_file.txt";
+
+    let (fast_store, slow_store, cas_store, _ac_store) = setup_stores().await?;
+
+    let root_directory_digest = {
+        // The empty file is never uploaded, stores answer for it themselves.
+        let root_directory_digest = DigestInfo::new([1u8; 32], 32);
+        let root_directory = Directory {
+            files: vec![FileNode {
+                name: FILE_NAME.to_string(),
+                digest: Some(ZERO_BYTE_DIGESTS[0].into()),
+                is_executable: false,
+                node_properties: None,
+            }],
+            ..Default::default()
+        };
+
+        slow_store
+            .as_ref()
+            .update_oneshot(root_directory_digest, root_directory.encode_to_vec().into())
+            .await?;
+        root_directory_digest
+    };
+
+    let download_dir = {
+        // Tell it to download the digest info to a directory.
+        let download_dir = make_temp_path("download_dir");
+        fs::create_dir_all(&download_dir)
+            .await
+            .err_tip(|| format!("Could not make download_dir : {download_dir}"))?;
+        download_to_directory(
+            cas_store.as_ref(),
+            fast_store.as_pin(),
+            &root_directory_digest,
+            &download_dir,
+        )
+        .await?;
+        download_dir
+    };
+    {
+        // Now ensure that our download_dir has the empty file.
+        let file_content = fs::read(format!("{download_dir}/{FILE_NAME}")).await?;
+        assert_eq!(file_content, Vec::<u8>::new());
+    }
+    Ok(())
+}
+
 #[nativelink_test]
 async fn ensure_output_files_full_directories_are_created_no_working_directory_test(
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -3306,3 +3358,126 @@ async fn running_actions_manager_respects_action_timeout() -> Result<(), Box<dyn
     assert_eq!(result.exit_code, 1, "Action process should be been killed");
     Ok(())
 }
+
+#[nativelink_test]
+async fn max_output_count_fails_action_test() -> Result<(), Box<dyn std::error::Error>> {
+    const WORKER_ID: &str = "foo_worker_id";
+
+    fn test_monotonic_clock() -> SystemTime {
+        static CLOCK: AtomicU64 = AtomicU64::new(0);
+        monotonic_clock(&CLOCK)
+    }
+
+    let (_, _, cas_store, ac_store) = setup_stores().await?;
+
+    #[cfg(target_family = "unix")]
+    let arguments = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        "printf 'a' > ./a.txt; printf 'b' > ./b.txt".to_string(),
+    ];
+    #[cfg(target_family = "windows")]
+    let arguments = vec![
+        "cmd".to_string(),
+        "/C".to_string(),
+        "echo | set /p=a> ./a.txt & echo | set /p=b> ./b.txt".to_string(),
+    ];
+    let command = Command {
+        arguments,
+        output_paths: vec!["a.txt".to_string(), "b.txt".to_string()],
+        working_directory: ".".to_string(),
+        ..Default::default()
+    };
+    let command_digest = serialize_and_upload_message(
+        &command,
+        cas_store.as_pin(),
+        &mut DigestHasherFunc::Sha256.hasher(),
+    )
+    .await?;
+    let input_root_digest = serialize_and_upload_message(
+        &Directory::default(),
+        cas_store.as_pin(),
+        &mut DigestHasherFunc::Sha256.hasher(),
+    )
+    .await?;
+    let action = Action {
+        command_digest: Some(command_digest.into()),
+        input_root_digest: Some(input_root_digest.into()),
+        ..Default::default()
+    };
+    let action_digest = serialize_and_upload_message(
+        &action,
+        cas_store.as_pin(),
+        &mut DigestHasherFunc::Sha256.hasher(),
+    )
+    .await?;
+
+    let run_with_max_output_count = |max_output_count| {
+        let cas_store = cas_store.clone();
+        let ac_store = ac_store.clone();
+        async move {
+            let root_action_directory = make_temp_path("root_action_directory");
+            fs::create_dir_all(&root_action_directory).await?;
+            let running_actions_manager = Arc::new(RunningActionsManagerImpl::new_with_callbacks(
+                RunningActionsManagerArgs {
+                    root_action_directory,
+                    execution_configuration: ExecutionConfiguration::default(),
+                    cas_store: cas_store.clone(),
+                    ac_store: Some(Store::new(ac_store)),
+                    historical_store: Store::new(cas_store),
+                    upload_action_result_config:
+                        &nativelink_config::cas_server::UploadActionResultConfig {
+                            upload_ac_results_strategy:
+                                nativelink_config::cas_server::UploadCacheResultsStrategy::never,
+                            max_output_count,
+                            ..Default::default()
+                        },
+                    max_action_timeout: Duration::MAX,
+                    timeout_handled_externally: false,
+                },
+                Callbacks {
+                    now_fn: test_monotonic_clock,
+                    sleep_fn: |_duration| Box::pin(futures::future::pending()),
+                },
+            )?);
+            let running_action_impl = running_actions_manager
+                .create_and_add_action(
+                    WORKER_ID.to_string(),
+                    StartExecute {
+                        execute_request: Some(ExecuteRequest {
+                            action_digest: Some(action_digest.into()),
+                            digest_function: ProtoDigestFunction::Sha256.into(),
+                            ..Default::default()
+                        }),
+                        salt: 0,
+                        queued_timestamp: None,
+                    },
+                )
+                .await?;
+            run_action(running_action_impl).await
+        }
+    };
+
+    let err = run_with_max_output_count(1)
+        .await
+        .expect_err("Action with too many outputs should fail");
+    assert_eq!(err.code, Code::InvalidArgument);
+    assert!(
+        err.to_string()
+            .contains("Action produced 2 outputs, which is more than the maximum of 1"),
+        "Unexpected error: {err:?}"
+    );
+    let output_digest = DigestHasherFunc::Sha256
+        .hasher()
+        .compute_from_reader(Cursor::new("a"))
+        .await?;
+    assert_eq!(
+        cas_store.has(output_digest).await?,
+        None,
+        "Expected no outputs to be uploaded"
+    );
+
+    let action_result = run_with_max_output_count(2).await?;
+    assert_eq!(action_result.output_files.len(), 2);
+    Ok(())
+}
@@ -30,7 +30,7 @@ use nativelink_config::cas_server::{
     CasConfig, GlobalConfig, HttpCompressionAlgorithm, ListenerConfig, ServerConfig, WorkerConfig,
 };
 use nativelink_config::stores::ConfigDigestHashFunction;
-use nativelink_error::{make_err, Code, Error, ResultExt};
+use nativelink_error::{make_err, make_input_err, Code, Error, ResultExt};
 use nativelink_scheduler::default_scheduler_factory::scheduler_factory;
 use nativelink_service::ac_server::AcServer;
 use nativelink_service::bep_server::BepServer;
@@ -40,9 +40,12 @@ use nativelink_service::cas_server::CasServer;
 use nativelink_service::execution_server::ExecutionServer;
 use nativelink_service::health_server::HealthServer;
 use nativelink_service::worker_api_server::WorkerApiServer;
-use nativelink_store::default_store_factory::store_factory;
+use nativelink_store::default_store_factory::{
+    check_store_configs, self_test_store, store_factory,
+};
 use nativelink_store::store_manager::StoreManager;
 use nativelink_util::action_messages::WorkerId;
+use nativelink_util::buf_channel::BufChannelMetrics;
 use nativelink_util::common::fs::{set_idle_file_descriptor_timeout, set_open_file_limit};
 use nativelink_util::digest_hasher::{set_default_digest_hasher_func, DigestHasherFunc};
 use nativelink_util::health_utils::HealthRegistryBuilder;
@@ -52,7 +55,8 @@ use nativelink_util::metrics_utils::{
 };
 use nativelink_util::origin_context::OriginContext;
 use nativelink_util::store_trait::{
-    set_default_digest_size_health_check, DEFAULT_DIGEST_SIZE_HEALTH_CHECK_CFG,
+    set_default_digest_size_health_check, set_short_circuit_empty_digest,
+    DEFAULT_DIGEST_SIZE_HEALTH_CHECK_CFG,
 };
 use nativelink_util::task::TaskExecutor;
 use nativelink_util::{background_spawn, init_tracing, spawn, spawn_blocking};
@@ -110,6 +114,7 @@ async fn inner_main(
         "nativelink".into(),
     )));
 
+    check_store_configs(&cfg.stores)?;
     let store_manager = Arc::new(StoreManager::new());
     {
         let mut health_registry_lock = health_registry_builder.lock().await;
@@ -133,6 +138,16 @@ async fn inner_main(
             );
         }
     }
+    for name in &cfg.startup_self_test_stores {
+        let store = store_manager.get_store(name).ok_or_else(|| {
+            make_input_err!("Store '{name}' in startup_self_test_stores does not exist")
+        })?;
+        self_test_store(name, &store).await?;
+    }
+
+    root_metrics_registry
+        .sub_registry_with_prefix("buf_channel")
+        .register_collector(Box::new(Collector::new(BufChannelMetrics::global())));
 
     let mut action_schedulers = HashMap::new();
     let mut worker_schedulers = HashMap::new();
@@ -224,6 +239,7 @@ async fn inner_main(
     let root_metrics_registry = Arc::new(AsyncMutex::new(root_metrics_registry));
     for (server_cfg, connected_clients_mux) in servers_and_clients {
         let services = server_cfg.services.ok_or("'services' must be configured")?;
+        let cas_cfg = services.cas.as_ref();
 
         // Currently we only support http as our socket type.
         let ListenerConfig::http(http_config) = server_cfg.listener;
@@ -258,8 +274,9 @@ async fn inner_main(
             .add_optional_service(
                 services
                     .cas
+                    .as_ref()
                     .map_or(Ok(None), |cfg| {
-                        CasServer::new(&cfg, &store_manager).map(|v| {
+                        CasServer::new(cfg, &store_manager).map(|v| {
                             let mut service = v.into_service();
                             let send_algo = &http_config.compression.send_compression_algorithm;
                             if let Some(encoding) =
@@ -311,7 +328,7 @@ async fn inner_main(
                 services
                     .bytestream
                     .map_or(Ok(None), |cfg| {
-                        ByteStreamServer::new(&cfg, &store_manager).map(|v| {
+                        ByteStreamServer::new(&cfg, cas_cfg, &store_manager).map(|v| {
                             let mut service = v.into_service();
                             let send_algo = &http_config.compression.send_compression_algorithm;
                             if let Some(encoding) =
@@ -502,51 +519,111 @@ async fn inner_main(
                 &admin_config.path
             };
             let worker_schedulers = Arc::new(worker_schedulers.clone());
+            let worker_schedulers_for_ready = worker_schedulers.clone();
+            let worker_schedulers_for_failed_actions = worker_schedulers.clone();
             svc = svc.nest_service(
                 path,
-                Router::new().route(
-                    "/scheduler/:instance_name/set_drain_worker/:worker_id/:is_draining",
-                    axum::routing::post(
-                        move |params: axum::extract::Path<(String, String, String)>| async move {
-                            let (instance_name, worker_id, is_draining) = params.0;
-                            (async move {
-                                let is_draining = match is_draining.as_str() {
-                                    "0" => false,
-                                    "1" => true,
-                                    _ => {
-                                        return Err(make_err!(
-                                            Code::Internal,
-                                            "{} is neither 0 nor 1",
-                                            is_draining
-                                        ))
-                                    }
-                                };
-                                worker_schedulers
-                                    .get(&instance_name)
-                                    .err_tip(|| {
-                                        format!(
-                                            "Can not get an instance with the name of '{}'",
-                                            &instance_name
+                Router::new()
+                    .route(
+                        "/scheduler/:instance_name/set_drain_worker/:worker_id/:is_draining",
+                        axum::routing::post(
+                            move |params: axum::extract::Path<(String, String, String)>| async move {
+                                let (instance_name, worker_id, is_draining) = params.0;
+                                (async move {
+                                    let is_draining = match is_draining.as_str() {
+                                        "0" => false,
+                                        "1" => true,
+                                        _ => {
+                                            return Err(make_err!(
+                                                Code::Internal,
+                                                "{} is neither 0 nor 1",
+                                                is_draining
+                                            ))
+                                        }
+                                    };
+                                    worker_schedulers
+                                        .get(&instance_name)
+                                        .err_tip(|| {
+                                            format!(
+                                                "Can not get an instance with the name of '{}'",
+                                                &instance_name
+                                            )
+                                        })?
+                                        .clone()
+                                        .set_drain_worker(
+                                            WorkerId::try_from(worker_id.clone())?,
+                                            is_draining,
                                         )
-                                    })?
-                                    .clone()
-                                    .set_drain_worker(
-                                        WorkerId::try_from(worker_id.clone())?,
-                                        is_draining,
+                                        .await?;
+                                    Ok::<_, Error>(format!("Draining worker {worker_id}"))
+                                })
+                                .await
+                                .map_err(|e| {
+                                    Err::<String, _>((
+                                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                                        format!("Error: {e:?}"),
+                                    ))
+                                })
+                            },
+                        ),
+                    )
+                    .route(
+                        "/scheduler/:instance_name/set_worker_ready/:worker_id",
+                        axum::routing::post(
+                            move |params: axum::extract::Path<(String, String)>| async move {
+                                let (instance_name, worker_id) = params.0;
+                                (async move {
+                                    worker_schedulers_for_ready
+                                        .get(&instance_name)
+                                        .err_tip(|| {
+                                            format!(
+                                                "Can not get an instance with the name of '{}'",
+                                                &instance_name
+                                            )
+                                        })?
+                                        .clone()
+                                        .set_worker_ready(WorkerId::try_from(worker_id.clone())?)
+                                        .await?;
+                                    Ok::<_, Error>(format!("Worker {worker_id} is ready"))
+                                })
+                                .await
+                                .map_err(|e| {
+                                    Err::<String, _>((
+                                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                                        format!("Error: {e:?}"),
+                                    ))
+                                })
+                            },
+                        ),
+                    )
+                    .route(
+                        "/scheduler/:instance_name/failed_actions",
+                        axum::routing::get(
+                            move |params: axum::extract::Path<String>| async move {
+                                let instance_name = params.0;
+                                (async move {
+                                    let failed_action_results = worker_schedulers_for_failed_actions
+                                        .get(&instance_name)
+                                        .err_tip(|| {
+                                            format!(
+                                                "Can not get an instance with the name of '{}'",
+                                                &instance_name
+                                            )
+                                        })?
+                                        .failed_action_results()
+                                        .await;
+                                    Ok::<_, Error>(axum::Json(failed_action_results))
+                                })
+                                .await
+                                .map_err(|e| {
+                                    (
+                                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                                        format!("Error: {e:?}"),
                                     )
-                                    .await?;
-                                Ok::<_, Error>(format!("Draining worker {worker_id}"))
-                            })
-                            .await
-                            .map_err(|e| {
-                                Err::<String, _>((
-                                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Error: {e:?}"),
-                                ))
-                            })
-                        },
+                                })
+                            },
+                        ),
                     ),
-                ),
             )
         }
 
@@ -915,6 +992,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }),
                 default_digest_hash_function: None,
                 default_digest_size_health_check: DEFAULT_DIGEST_SIZE_HEALTH_CHECK_CFG,
+                disable_empty_digest_short_circuit: false,
             }
         };
         set_open_file_limit(global_cfg.max_open_files);
@@ -927,6 +1005,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .unwrap_or(ConfigDigestHashFunction::sha256),
         ))?;
         set_default_digest_size_health_check(global_cfg.default_digest_size_health_check)?;
+        set_short_circuit_empty_digest(!global_cfg.disable_empty_digest_short_circuit)?;
         // TODO (#513): prevent deadlocks by assigning max blocking threads number of open files * ten
         (!global_cfg.disable_metrics, global_cfg.max_open_files * 10)
     };